@@ -0,0 +1,48 @@
+//! Throughput and latencies of the Redis queue under the usual load profiles, see
+//! [`rappel::longrunning::bench`]. Runs against `REDIS_URL`, a local Redis by default:
+//!
+//! ```sh
+//! REDIS_URL=redis://127.0.0.1/ cargo bench --bench queue
+//! ```
+
+use rappel::longrunning::bench;
+use rappel::longrunning::bench::LoadProfile;
+use rappel::longrunning::AckMode;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+  let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+  let client = redis::Client::open(url)?;
+
+  let profiles = [
+    ("single worker", LoadProfile::new(2_000)),
+    (
+      "contended",
+      LoadProfile::new(10_000).with_producers(4).with_workers(16),
+    ),
+    (
+      "large payloads",
+      LoadProfile::new(2_000)
+        .with_workers(4)
+        .with_payload_bytes(64 * 1024),
+    ),
+    (
+      "steady 500/s",
+      LoadProfile::new(2_500).with_workers(4).with_rate(500),
+    ),
+    (
+      "auto ack",
+      LoadProfile::new(10_000)
+        .with_producers(4)
+        .with_workers(16)
+        .with_ack_mode(AckMode::Auto),
+    ),
+  ];
+
+  for (name, profile) in profiles {
+    let report = bench::run(client.clone(), &profile).await?;
+    println!("{}: {}\n", name, report);
+  }
+
+  Ok(())
+}
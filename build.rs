@@ -12,6 +12,7 @@ fn main() {
       &[
         "proto/rappel/system/clusters.proto",
         "proto/rappel/system/location.proto",
+        "proto/rappel/system/discovery.proto",
         "proto/rappel/cluster/workspaces.proto",
         "proto/rappel/account/billing.proto",
         "proto/rappel/account/organization.proto",
@@ -20,6 +21,7 @@ fn main() {
         "proto/rappel/process/process.proto",
         "proto/rappel/rpc/packet.proto",
         "proto/google/rpc/code.proto",
+        "proto/google/rpc/error_details.proto",
       ],
       &["proto"],
     )
@@ -29,9 +31,11 @@ fn main() {
   println!("cargo:rerun-if-changed=migrations");
   println!("cargo:rerun-if-changed=build.rs");
   println!("cargo:rerun-if-changed=proto/google/protobuf/any.proto");
+  println!("cargo:rerun-if-changed=proto/longrunning/operations.proto");
   println!("cargo:rerun-if-changed=proto/rappel/cluster/workspaces.proto");
   println!("cargo:rerun-if-changed=proto/rappel/workspace/ides.proto");
   println!("cargo:rerun-if-changed=proto/rappel/process/process.proto");
+  println!("cargo:rerun-if-changed=proto/rappel/system/discovery.proto");
   println!("cargo:rerun-if-changed=proto/rappel/rpc");
 
   std::fs::copy(&descriptor_path, "./descriptors.pb").unwrap();
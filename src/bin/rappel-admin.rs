@@ -0,0 +1,577 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
+use futures::StreamExt;
+use prost::Message;
+use serde_json::Value;
+
+use rappel::grpc::dynamic::DescriptorPool;
+use rappel::longrunning::admin::RedisAdmin;
+use rappel::longrunning::maintenance::EnqueuePolicy;
+use rappel::longrunning::maintenance::Maintenance;
+use rappel::longrunning::store::RedisTaskStore;
+use rappel::longrunning::Context;
+use rappel::longrunning::OperationFilter;
+use rappel::longrunning::OperationState;
+use rappel::longrunning::UnknownOperationState;
+use rappel::proto::longrunning::operations_client::OperationsClient;
+use rappel::proto::longrunning::OperationEvent;
+use rappel::proto::longrunning::StreamOperationsRequest;
+use rappel::redis::Keys;
+use rappel::service::resumable_watch;
+use rappel::service::ShardMap;
+use rappel::service::WatchRetry;
+
+/// Operator tooling for the rappel queues stored in Redis.
+#[derive(Parser, Debug)]
+#[command(name = "rappel-admin", version)]
+struct Cli {
+  /// Redis holding the queues and operations.
+  #[arg(long, env = "RAPPEL_REDIS_URL", default_value = "redis://127.0.0.1/")]
+  redis_url: String,
+
+  /// Prefix of the Redis keys, e.g. `rappel:production:`.
+  #[arg(long, env = "RAPPEL_KEY_PREFIX", default_value = "")]
+  key_prefix: String,
+
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// List the queues with their pending, in-flight, delayed, invalid and quarantined counts.
+  Queues,
+
+  /// Print an operation as JSON.
+  Get { operation_id: String },
+
+  /// Print an operation and the operations it enqueued, recursively, as JSON.
+  Tree {
+    operation_id: String,
+
+    /// Levels of descendants printed, every level when 0.
+    #[arg(long, default_value_t = 0)]
+    max_depth: u32,
+  },
+
+  /// Print the enqueue to completion latency percentiles of task types, or of every task type.
+  Latency { task_types: Vec<String> },
+
+  /// Print the depth, throughput, failure rate and latencies of a queue as JSON.
+  Metrics {
+    queue: String,
+
+    /// Windows in seconds the rates and latencies are computed over, the last 5 minutes, hour and
+    /// day when none.
+    #[arg(long = "window")]
+    window_secs: Vec<u64>,
+  },
+
+  /// Print the payload and output schemas published for task types, or for every task type.
+  Schemas { task_types: Vec<String> },
+
+  /// Enqueue a task from its JSON payload, validated against the schema of its task type.
+  Submit {
+    task_type: String,
+
+    json_payload: String,
+
+    #[arg(long)]
+    queue: String,
+
+    /// User the operation is enqueued for.
+    #[arg(long, env = "USER", default_value = "rappel-admin")]
+    user_id: String,
+  },
+
+  /// Cancel an operation that did not complete yet.
+  Cancel {
+    operation_id: String,
+
+    #[arg(long, default_value = "Cancelled by an operator")]
+    reason: String,
+  },
+
+  /// Put an operation back on its queue, e.g. after it was quarantined.
+  Requeue { operation_id: String },
+
+  /// Attach a note to an operation, e.g. a link to an incident, or remove it with an empty value.
+  Annotate {
+    operation_id: String,
+    key: String,
+    #[arg(default_value = "")]
+    value: String,
+  },
+
+  /// Delete a completed operation, restorable until it is purged.
+  Delete { operation_id: String },
+
+  /// Restore a deleted operation that was not purged yet.
+  Restore { operation_id: String },
+
+  /// Permanently remove the operations deleted before the grace period.
+  Purge {
+    #[arg(long, default_value_t = 30)]
+    grace_days: u64,
+  },
+
+  /// Stop the workers of a queue from pulling operations.
+  Pause { queue: String },
+
+  /// Let the workers of a paused queue pull operations again.
+  Resume { queue: String },
+
+  /// Start, end or show the cluster-wide maintenance.
+  Maintenance {
+    #[command(subcommand)]
+    command: MaintenanceCommand,
+  },
+
+  /// Print the lifecycle events as they happen, one JSON object per line.
+  Tail {
+    /// Only print the events of these queues.
+    #[arg(long = "queue")]
+    queues: Vec<String>,
+
+    #[arg(long)]
+    user_id: Option<String>,
+
+    #[arg(long)]
+    operation_id: Option<String>,
+  },
+
+  /// Write the stored operations to stdout, one JSON object per line.
+  Export {
+    /// Only export the operations of these queues.
+    #[arg(long = "queue")]
+    queues: Vec<String>,
+
+    #[arg(long = "task-type")]
+    task_types: Vec<String>,
+
+    /// Only export the operations with these statuses, e.g. `Failed`.
+    #[arg(long = "status")]
+    statuses: Vec<String>,
+
+    #[arg(long)]
+    user_id: Option<String>,
+  },
+
+  /// Inspect and edit the key to instance assignments of a sharded service.
+  Shards {
+    /// Service name, as in the locator configuration.
+    service: String,
+
+    #[command(subcommand)]
+    command: ShardsCommand,
+  },
+
+  /// Follow the operations through the Operations service.
+  Operations {
+    /// Address of the Operations service.
+    #[arg(
+      long,
+      env = "RAPPEL_OPERATIONS_ADDRESS",
+      default_value = "http://127.0.0.1:50051"
+    )]
+    address: String,
+
+    #[command(subcommand)]
+    command: OperationsCommand,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+enum OperationsCommand {
+  /// Show the operations as their events happen, in a table redrawn on every event or as a
+  /// stream of JSON events.
+  Watch {
+    /// Only show the operations of these queues.
+    #[arg(long = "queue")]
+    queues: Vec<String>,
+
+    /// Only show the operations in these states, e.g. `running`.
+    #[arg(long = "status", value_parser = parse_state)]
+    statuses: Vec<OperationState>,
+
+    #[arg(long)]
+    user_id: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+  },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Output {
+  /// A table of the operations, redrawn on every event.
+  Table,
+  /// The events, one JSON object per line.
+  Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum MaintenanceCommand {
+  /// Start a maintenance, or change the one in progress.
+  Start {
+    /// What happens to the operations enqueued meanwhile: `reject`, `defer` or `accept`.
+    #[arg(long, default_value_t = EnqueuePolicy::Reject)]
+    enqueue: EnqueuePolicy,
+
+    /// Stop the workers of every queue from pulling operations.
+    #[arg(long)]
+    pause_pulls: bool,
+
+    #[arg(long, default_value = "Maintenance in progress")]
+    reason: String,
+  },
+
+  /// End the maintenance, delivering the operations deferred meanwhile.
+  End,
+
+  /// Show the maintenance in progress.
+  Status,
+}
+
+#[derive(Subcommand, Debug)]
+enum ShardsCommand {
+  /// List the pinned keys with their instance address.
+  List,
+
+  /// Pin a key to an instance.
+  Assign { key: String, address: String },
+
+  /// Remove the assignment of a key.
+  Unassign { key: String },
+
+  /// Move the keys pinned to instances missing from the given addresses.
+  Rebalance {
+    /// Address of a live instance, repeat for every instance.
+    #[arg(long = "address", required = true)]
+    addresses: Vec<String>,
+  },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+  let cli = Cli::parse();
+  let client = redis::Client::open(cli.redis_url.as_str())?;
+  let keys = Keys::new(&cli.key_prefix);
+  let admin = RedisAdmin::new(client.clone()).with_keys(keys.clone());
+  let pool = DescriptorPool::rappel()?;
+
+  match cli.command {
+    Command::Queues => {
+      println!(
+        "{:<40} {:>10} {:>10} {:>10} {:>10} {:>12} {:>7}",
+        "QUEUE", "PENDING", "IN-FLIGHT", "DELAYED", "INVALID", "QUARANTINED", "PAUSED"
+      );
+
+      for queue in admin.queues().await? {
+        println!(
+          "{:<40} {:>10} {:>10} {:>10} {:>10} {:>12} {:>7}",
+          queue.name,
+          queue.pending,
+          queue.in_flight,
+          queue.delayed,
+          queue.invalid,
+          queue.quarantined,
+          queue.paused
+        );
+      }
+    }
+    Command::Get { operation_id } => {
+      let operation = admin.operation(&operation_id).await?;
+      print_json(&pool, "longrunning.Operation", &operation)?;
+    }
+    Command::Tree {
+      operation_id,
+      max_depth,
+    } => {
+      let tree = admin.tree(&operation_id, max_depth).await?;
+      print_json(&pool, "longrunning.OperationTree", &tree)?;
+    }
+    Command::Latency { task_types } => {
+      let summary = admin.latency(&task_types).await?;
+      print_json(&pool, "longrunning.LatencySummary", &summary)?;
+    }
+    Command::Metrics { queue, window_secs } => {
+      let windows: Vec<Duration> = window_secs.into_iter().map(Duration::from_secs).collect();
+      let metrics = admin.queue_metrics(&queue, &windows).await?;
+      print_json(&pool, "longrunning.QueueMetrics", &metrics)?;
+    }
+    Command::Schemas { task_types } => {
+      let schemas = admin.schemas(&task_types).await?;
+      print_json(&pool, "longrunning.ListTaskSchemasResponse", &schemas)?;
+    }
+    Command::Submit {
+      task_type,
+      json_payload,
+      queue,
+      user_id,
+    } => {
+      let ctx = Context::new(user_id, "rappel-admin".to_string());
+      let operation = admin
+        .submit(&task_type, &queue, &json_payload, &ctx)
+        .await?;
+      print_json(&pool, "longrunning.Operation", &operation)?;
+    }
+    Command::Cancel {
+      operation_id,
+      reason,
+    } => {
+      let operation = admin.cancel(&operation_id, &reason).await?;
+      print_json(&pool, "longrunning.Operation", &operation)?;
+    }
+    Command::Requeue { operation_id } => {
+      let operation = admin.requeue(&operation_id).await?;
+      print_json(&pool, "longrunning.Operation", &operation)?;
+    }
+    Command::Annotate {
+      operation_id,
+      key,
+      value,
+    } => {
+      let operation = admin.annotate(&operation_id, &key, &value).await?;
+      print_json(&pool, "longrunning.Operation", &operation)?;
+    }
+    Command::Delete { operation_id } => admin.delete(&operation_id).await?,
+    Command::Restore { operation_id } => {
+      let operation = admin.restore(&operation_id).await?;
+      print_json(&pool, "longrunning.Operation", &operation)?;
+    }
+    Command::Purge { grace_days } => {
+      let purged = admin
+        .purge_deleted(Duration::from_secs(grace_days * 24 * 60 * 60))
+        .await?;
+      println!("Purged {} operations", purged.len());
+    }
+    Command::Pause { queue } => admin.pause(&queue).await?,
+    Command::Maintenance { command } => match command {
+      MaintenanceCommand::Start {
+        enqueue,
+        pause_pulls,
+        reason,
+      } => print_maintenance(Some(
+        admin
+          .start_maintenance(enqueue, pause_pulls, &reason)
+          .await?,
+      )),
+      MaintenanceCommand::End => {
+        let deferred = admin.end_maintenance().await?;
+        eprintln!(
+          "Ended maintenance, delivering {} deferred operations",
+          deferred
+        );
+      }
+      MaintenanceCommand::Status => print_maintenance(admin.maintenance().await?),
+    },
+    Command::Resume { queue } => admin.resume(&queue).await?,
+    Command::Tail {
+      queues,
+      user_id,
+      operation_id,
+    } => {
+      let filter = StreamOperationsRequest {
+        queues,
+        event_types: Vec::default(),
+        user_id: user_id.unwrap_or_default(),
+        operation_id: operation_id.unwrap_or_default(),
+        after_ts: None,
+      };
+
+      let mut events = Box::pin(admin.tail(filter).await?);
+      while let Some(event) = events.next().await {
+        let value = pool.decode_message("longrunning.OperationEvent", &event.encode_to_vec())?;
+        println!("{}", value);
+      }
+    }
+    Command::Export {
+      queues,
+      task_types,
+      statuses,
+      user_id,
+    } => {
+      let filter = OperationFilter {
+        queues,
+        task_types,
+        user_id,
+        statuses,
+        ..Default::default()
+      };
+
+      let store = RedisTaskStore::new(client).with_keys(keys);
+      let exported = store.export(&filter, &mut tokio::io::stdout()).await?;
+      eprintln!("Exported {} operations", exported);
+    }
+    Command::Shards { service, command } => {
+      let shard_map = ShardMap::new(client, &service).with_prefix(keys.prefix());
+
+      match command {
+        ShardsCommand::List => {
+          let mut entries: Vec<_> = shard_map.entries().await?.into_iter().collect();
+          entries.sort();
+
+          for (key, address) in entries {
+            println!("{:<40} {}", key, address);
+          }
+        }
+        ShardsCommand::Assign { key, address } => shard_map.assign(&key, &address).await?,
+        ShardsCommand::Unassign { key } => shard_map.unassign(&key).await?,
+        ShardsCommand::Rebalance { addresses } => {
+          for moved in shard_map.rebalance(&addresses).await? {
+            println!("{:<40} {} -> {}", moved.key, moved.from, moved.to);
+          }
+        }
+      }
+    }
+    Command::Operations { address, command } => match command {
+      OperationsCommand::Watch {
+        queues,
+        statuses,
+        user_id,
+        output,
+      } => {
+        let client = OperationsClient::connect(address).await?;
+        let filter = StreamOperationsRequest {
+          queues,
+          event_types: Vec::default(),
+          user_id: user_id.unwrap_or_default(),
+          operation_id: String::default(),
+          after_ts: None,
+        };
+
+        let events = resumable_watch(filter, WatchRetry::default(), |request| {
+          let mut client = client.clone();
+          async move { client.stream_operations(request).await }
+        });
+        watch(&pool, events, &statuses, output).await?;
+      }
+    },
+  }
+
+  Ok(())
+}
+
+#[derive(Debug)]
+struct OperationRow {
+  queue: String,
+  user_id: String,
+  state: OperationState,
+  updated: String,
+}
+
+async fn watch(
+  pool: &DescriptorPool,
+  events: impl futures::Stream<Item = Result<OperationEvent, tonic::Status>>,
+  statuses: &[OperationState],
+  output: Output,
+) -> anyhow::Result<()> {
+  let mut rows = BTreeMap::new();
+  let mut events = Box::pin(events);
+
+  if let Output::Table = output {
+    draw(&rows);
+  }
+
+  while let Some(event) = events.next().await {
+    let event = event?;
+    let state = match event.state() {
+      Some(state) => state,
+      None => continue,
+    };
+    let shown = statuses.is_empty() || statuses.contains(&state);
+
+    match output {
+      Output::Json if shown => {
+        let value = pool.decode_message("longrunning.OperationEvent", &event.encode_to_vec())?;
+        println!("{}", value);
+      }
+      Output::Json => {}
+      Output::Table => {
+        // An operation leaving the filtered states leaves the table.
+        if shown {
+          let updated = event
+            .event_ts
+            .as_ref()
+            .and_then(|ts| chrono::DateTime::from_timestamp(ts.seconds, ts.nanos as u32))
+            .map(|ts| ts.format("%H:%M:%S").to_string())
+            .unwrap_or_default();
+          rows.insert(
+            event.operation_id,
+            OperationRow {
+              queue: event.queue,
+              user_id: event.user_id,
+              state,
+              updated,
+            },
+          );
+        } else {
+          rows.remove(&event.operation_id);
+        }
+        draw(&rows);
+      }
+    }
+  }
+
+  Ok(())
+}
+
+fn draw(rows: &BTreeMap<String, OperationRow>) {
+  // Clears the terminal and moves the cursor home.
+  print!("\x1b[2J\x1b[H");
+  println!(
+    "{:<38} {:<24} {:<12} {:<16} {:>8}",
+    "OPERATION", "QUEUE", "STATE", "USER", "UPDATED"
+  );
+  for (operation_id, row) in rows {
+    println!(
+      "{:<38} {:<24} {:<12} {:<16} {:>8}",
+      operation_id,
+      row.queue,
+      row.state.as_str(),
+      row.user_id,
+      row.updated
+    );
+  }
+}
+
+/// Parses the states regardless of case, e.g. `running`.
+fn parse_state(value: &str) -> Result<OperationState, UnknownOperationState> {
+  let mut chars = value.chars();
+  let capitalized = chars
+    .next()
+    .map(|first| {
+      first
+        .to_uppercase()
+        .chain(chars.flat_map(char::to_lowercase))
+    })
+    .map(String::from_iter)
+    .unwrap_or_default();
+  capitalized.parse()
+}
+
+fn print_maintenance(maintenance: Option<Maintenance>) {
+  match maintenance {
+    Some(maintenance) => println!(
+      "{}",
+      serde_json::json!({
+        "enqueue": maintenance.enqueue.as_str(),
+        "pause_pulls": maintenance.pause_pulls,
+        "reason": maintenance.reason,
+        "since_ts": maintenance.since_ts,
+      })
+    ),
+    None => println!("No maintenance in progress"),
+  }
+}
+
+fn print_json<M: Message>(pool: &DescriptorPool, name: &str, message: &M) -> anyhow::Result<()> {
+  let value: Value = pool.decode_message(name, &message.encode_to_vec())?;
+  println!("{}", serde_json::to_string_pretty(&value)?);
+  Ok(())
+}
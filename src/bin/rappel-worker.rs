@@ -0,0 +1,37 @@
+use clap::Parser;
+use config::Config;
+use config::Environment;
+use tracing::Level;
+
+use rappel::longrunning::registry::TaskRegistry;
+use rappel::longrunning::runner::Runner;
+use rappel::longrunning::runner::RunnerConfig;
+
+/// Performs the tasks of rappel queues with the command handlers of its configuration.
+#[derive(Parser, Debug)]
+#[command(name = "rappel-worker", version)]
+struct Cli {
+  /// Configuration file, see `RunnerConfig`. Values can be overridden by `RAPPEL_*` variables.
+  #[arg(long, short, default_value = "config/worker")]
+  config: String,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+  let cli = Cli::parse();
+
+  tracing_subscriber::fmt()
+    .json()
+    .flatten_event(true)
+    .with_max_level(Level::INFO)
+    .init();
+
+  let config: RunnerConfig = Config::builder()
+    .add_source(config::File::with_name(&cli.config))
+    .add_source(Environment::with_prefix("RAPPEL").separator("_"))
+    .build()?
+    .try_deserialize()?;
+
+  Runner::new(config, TaskRegistry::new())?.run().await?;
+  Ok(())
+}
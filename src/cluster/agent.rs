@@ -0,0 +1,199 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::Code;
+
+use crate::proto::prelude::ProstTimestamp;
+use crate::proto::system::Cluster;
+use crate::proto::system::ClusterPhase;
+use crate::proto::system::ClusterStatus;
+use crate::proto::system::CreateClusterRequest;
+use crate::proto::system::UpdateClusterRequest;
+use crate::service::ClusterSvcClient;
+use crate::service::ShardedClient;
+
+use super::placement::Resources;
+
+/// Interval between heartbeats by default.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Capacity and health of a node, as probed before every heartbeat.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeReport {
+  pub ready: bool,
+  pub phase: ClusterPhase,
+  pub capacity: Resources,
+  /// Resources allocated to workspaces.
+  pub allocated: Resources,
+}
+
+/// Probes the node an agent runs on.
+#[async_trait::async_trait]
+pub trait NodeProbe: Send + Sync {
+  async fn probe(&self) -> NodeReport;
+}
+
+/// Called with the cluster when the system service no longer knows it, e.g. after it was deleted
+/// or its state was lost. The agent registers the cluster again afterwards.
+pub type RegistrationLost = Arc<dyn Fn(&Cluster) + Send + Sync>;
+
+/// Registers a cluster with the system service and reports its capacity and health, see
+/// [`ClusterAgent::run`]. Shared by the node agents.
+///
+/// ```rust,ignore
+/// let agent = ClusterAgent::new(locator.get().await?, cluster, probe)
+///   .on_registration_lost(Arc::new(|cluster| workspaces.reconcile(&cluster.cluster_id)));
+/// agent.run().await?;
+/// ```
+#[derive(Clone)]
+pub struct ClusterAgent<P> {
+  clients: ShardedClient<ClusterSvcClient>,
+  cluster: Cluster,
+  probe: P,
+  interval: Duration,
+  on_lost: Vec<RegistrationLost>,
+}
+
+impl<P: NodeProbe> ClusterAgent<P> {
+  pub fn new(clients: ShardedClient<ClusterSvcClient>, cluster: Cluster, probe: P) -> Self {
+    Self {
+      clients,
+      cluster,
+      probe,
+      interval: DEFAULT_HEARTBEAT_INTERVAL,
+      on_lost: Vec::default(),
+    }
+  }
+
+  /// Sends a heartbeat every `interval`, [`DEFAULT_HEARTBEAT_INTERVAL`] by default.
+  pub fn with_interval(mut self, interval: Duration) -> Self {
+    self.interval = interval;
+    self
+  }
+
+  pub fn on_registration_lost(mut self, hook: RegistrationLost) -> Self {
+    self.on_lost.push(hook);
+    self
+  }
+
+  pub fn cluster_id(&self) -> &str {
+    &self.cluster.cluster_id
+  }
+
+  /// Creates the cluster in the system service. A cluster registered already is kept.
+  pub async fn register(&self) -> Result<(), tonic::Status> {
+    let cluster = reported(&self.cluster, self.probe.probe().await);
+    let mut client = self.clients.acquire(self.cluster_id()).await?;
+
+    match client
+      .create(CreateClusterRequest {
+        cluster: Some(cluster),
+      })
+      .await
+    {
+      Ok(_) => {
+        tracing::info!(message = "Registered cluster", cluster_id = %self.cluster_id());
+        Ok(())
+      }
+      Err(status) if status.code() == Code::AlreadyExists => Ok(()),
+      Err(status) => Err(status),
+    }
+  }
+
+  /// Reports the current capacity and health of the cluster.
+  pub async fn heartbeat(&self) -> Result<Cluster, tonic::Status> {
+    let cluster = reported(&self.cluster, self.probe.probe().await);
+    let mut client = self.clients.acquire(self.cluster_id()).await?;
+
+    let response = client
+      .update(UpdateClusterRequest {
+        cluster: Some(cluster),
+      })
+      .await?;
+
+    Ok(response.into_inner())
+  }
+
+  /// Registers the cluster, then sends heartbeats until the surrounding future is dropped. Failed
+  /// heartbeats are retried at the next tick. When the system service no longer knows the
+  /// cluster, the registration lost hooks are called and the cluster is registered again. Fails
+  /// only if the first registration does.
+  pub async fn run(&self) -> Result<(), tonic::Status> {
+    self.register().await?;
+
+    let mut ticks = tokio::time::interval(self.interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+      ticks.tick().await;
+
+      match self.heartbeat().await {
+        Ok(_) => {}
+        Err(status) if status.code() == Code::NotFound => {
+          tracing::warn!(message = "Cluster registration lost", cluster_id = %self.cluster_id());
+          for hook in &self.on_lost {
+            hook(&self.cluster);
+          }
+
+          if let Err(status) = self.register().await {
+            tracing::warn!(message = "Failed to register cluster again", cluster_id = %self.cluster_id(), %status);
+          }
+        }
+        Err(status) => {
+          tracing::warn!(message = "Cluster heartbeat failed", cluster_id = %self.cluster_id(), %status);
+        }
+      }
+    }
+  }
+}
+
+/// Returns `cluster` with the capacity and status of `report`.
+fn reported(cluster: &Cluster, report: NodeReport) -> Cluster {
+  let start_ts = cluster
+    .cluster_status
+    .as_ref()
+    .and_then(|status| status.start_ts.clone());
+
+  Cluster {
+    cluster_capacity: report.capacity,
+    cluster_status: Some(ClusterStatus {
+      phase: report.phase as i32,
+      ready: report.ready,
+      cluster_size: report.allocated,
+      start_ts,
+      last_probe_ts: Some(ProstTimestamp::now().into_inner()),
+    }),
+    ..cluster.clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cluster::placement::NodeCapacity;
+
+  #[test]
+  fn reported_should_carry_capacity_and_health() {
+    let report = NodeReport {
+      ready: true,
+      phase: ClusterPhase::Running,
+      capacity: Resources::from([("cpu".to_string(), 8)]),
+      allocated: Resources::from([("cpu".to_string(), 2)]),
+    };
+    let cluster = Cluster {
+      cluster_id: "eu-1-a".to_string(),
+      location: "eu-1".to_string(),
+      ..Default::default()
+    };
+
+    let reported = reported(&cluster, report.clone());
+    let capacity = NodeCapacity::from(&reported);
+
+    assert_eq!(capacity.node_id, "eu-1-a");
+    assert_eq!(capacity.location, "eu-1");
+    assert!(capacity.ready);
+    assert_eq!(capacity.capacity, report.capacity);
+    assert_eq!(capacity.allocated, report.allocated);
+    assert!(reported.cluster_status.unwrap().last_probe_ts.is_some());
+  }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::proto::system::Cluster;
+use crate::proto::system::ClusterPhase;
+use crate::proto::system::Location;
+
+/// Resource amounts keyed by resource name, e.g. `cpu` or `memory`, as in
+/// [`Cluster::cluster_capacity`].
+pub type Resources = HashMap<String, i64>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlacementError {
+  #[error("No node in {0} can fit the requested resources")]
+  NoCapacity(String),
+
+  #[error("No ready node in {0}")]
+  NoNode(String),
+}
+
+impl From<PlacementError> for tonic::Status {
+  fn from(error: PlacementError) -> Self {
+    tonic::Status::resource_exhausted(error.to_string())
+  }
+}
+
+/// Capacity of a node workspaces can be placed on.
+#[derive(Clone, Debug, Default)]
+pub struct NodeCapacity {
+  pub node_id: String,
+  /// Name of the [`Location`] of the node.
+  pub location: String,
+  pub ready: bool,
+  pub capacity: Resources,
+  pub allocated: Resources,
+  /// Workspaces already placed on the node.
+  pub workspaces: u32,
+}
+
+impl NodeCapacity {
+  /// Fraction of `resource` in use once `request` is placed, `None` if it does not fit.
+  fn utilization(&self, resource: &str, request: &Resources) -> Option<f64> {
+    let capacity = self.capacity.get(resource).copied().unwrap_or_default();
+    let allocated = self.allocated.get(resource).copied().unwrap_or_default();
+    let requested = request.get(resource).copied().unwrap_or_default();
+
+    match capacity {
+      0 if requested == 0 => Some(0.0),
+      0 => None,
+      _ if allocated + requested > capacity => None,
+      _ => Some((allocated + requested) as f64 / capacity as f64),
+    }
+  }
+
+  fn utilizations(&self, request: &Resources) -> Option<Vec<f64>> {
+    self
+      .capacity
+      .keys()
+      .chain(request.keys())
+      .map(|resource| self.utilization(resource, request))
+      .collect()
+  }
+}
+
+/// Reads the capacity of a cluster: `cluster_capacity` is the capacity and the `cluster_size` of
+/// its status the allocated amount. Only running and ready clusters accept workspaces.
+impl From<&Cluster> for NodeCapacity {
+  fn from(cluster: &Cluster) -> Self {
+    let status = cluster.cluster_status.clone().unwrap_or_default();
+
+    Self {
+      node_id: cluster.cluster_id.clone(),
+      location: cluster.location.clone(),
+      ready: status.ready && status.phase == ClusterPhase::Running as i32,
+      capacity: cluster.cluster_capacity.clone(),
+      allocated: status.cluster_size,
+      workspaces: 0,
+    }
+  }
+}
+
+/// The locations a workspace may be placed in.
+#[derive(Clone, Debug, Default)]
+pub struct LocationConstraint {
+  /// Accepted location names, every location when empty.
+  names: Vec<String>,
+}
+
+impl LocationConstraint {
+  pub fn any() -> Self {
+    Self::default()
+  }
+
+  pub fn exact(location: &Location) -> Self {
+    Self {
+      names: vec![location.name.clone()],
+    }
+  }
+
+  /// Every location of `locations` in `region`.
+  pub fn region(locations: &[Location], region: &str) -> Self {
+    Self::matching(locations, |location| location.region == region)
+  }
+
+  /// Every location of `locations` in `zone`.
+  pub fn zone(locations: &[Location], zone: &str) -> Self {
+    Self::matching(locations, |location| location.zone == zone)
+  }
+
+  pub fn accepts(&self, location: &str) -> bool {
+    self.names.is_empty() || self.names.iter().any(|name| name == location)
+  }
+
+  fn matching<F: Fn(&Location) -> bool>(locations: &[Location], predicate: F) -> Self {
+    let names: Vec<String> = locations
+      .iter()
+      .filter(|location| predicate(location))
+      .map(|location| location.name.clone())
+      .collect();
+
+    // Keeps an unknown region or zone from turning into "any location".
+    match names.is_empty() {
+      true => Self {
+        names: vec![String::default()],
+      },
+      false => Self { names },
+    }
+  }
+}
+
+impl std::fmt::Display for LocationConstraint {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.names.is_empty() {
+      true => write!(f, "any location"),
+      false => write!(f, "[{}]", self.names.join(", ")),
+    }
+  }
+}
+
+/// Ranks the nodes a request fits on; the node with the highest score is selected.
+pub trait PlacementStrategy: Send + Sync + std::fmt::Debug {
+  fn score(&self, node: &NodeCapacity, utilizations: &[f64]) -> f64;
+}
+
+/// Fills the busiest nodes first, keeping the others free for large requests or scale down.
+#[derive(Clone, Debug, Default)]
+pub struct BinPacking;
+
+impl PlacementStrategy for BinPacking {
+  fn score(&self, _: &NodeCapacity, utilizations: &[f64]) -> f64 {
+    utilizations.iter().sum::<f64>() / utilizations.len().max(1) as f64
+  }
+}
+
+/// Places workspaces on the nodes holding the fewest workspaces.
+#[derive(Clone, Debug, Default)]
+pub struct Spread;
+
+impl PlacementStrategy for Spread {
+  fn score(&self, node: &NodeCapacity, _: &[f64]) -> f64 {
+    -(node.workspaces as f64)
+  }
+}
+
+/// Places workspaces on the nodes whose most used resource is the least used.
+#[derive(Clone, Debug, Default)]
+pub struct LeastLoaded;
+
+impl PlacementStrategy for LeastLoaded {
+  fn score(&self, _: &NodeCapacity, utilizations: &[f64]) -> f64 {
+    -utilizations.iter().copied().fold(0.0, f64::max)
+  }
+}
+
+/// Selects the node a workspace is placed on.
+///
+/// ```rust,ignore
+/// let scheduler = Scheduler::new(Arc::new(LeastLoaded));
+/// let nodes: Vec<NodeCapacity> = clusters.iter().map(NodeCapacity::from).collect();
+/// let node = scheduler.select(&nodes, &request, &LocationConstraint::region(&locations, "eu"))?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+  strategy: Arc<dyn PlacementStrategy>,
+}
+
+impl Default for Scheduler {
+  fn default() -> Self {
+    Self::new(Arc::new(LeastLoaded))
+  }
+}
+
+impl Scheduler {
+  pub fn new(strategy: Arc<dyn PlacementStrategy>) -> Self {
+    Self { strategy }
+  }
+
+  /// Returns the ready node accepted by `constraint` that fits `request` with the highest score.
+  /// Ties go to the lowest node id, so the selection is deterministic.
+  pub fn select<'a>(
+    &self,
+    nodes: &'a [NodeCapacity],
+    request: &Resources,
+    constraint: &LocationConstraint,
+  ) -> Result<&'a NodeCapacity, PlacementError> {
+    let candidates: Vec<&NodeCapacity> = nodes
+      .iter()
+      .filter(|node| node.ready && constraint.accepts(&node.location))
+      .collect();
+
+    if candidates.is_empty() {
+      return Err(PlacementError::NoNode(constraint.to_string()));
+    }
+
+    candidates
+      .into_iter()
+      .filter_map(|node| {
+        let utilizations = node.utilizations(request)?;
+        Some((self.strategy.score(node, &utilizations), node))
+      })
+      .max_by(|(a, a_node), (b, b_node)| {
+        a.total_cmp(b)
+          .then_with(|| b_node.node_id.cmp(&a_node.node_id))
+      })
+      .map(|(_, node)| node)
+      .ok_or_else(|| PlacementError::NoCapacity(constraint.to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn resources(cpu: i64) -> Resources {
+    HashMap::from([("cpu".to_string(), cpu)])
+  }
+
+  fn node(node_id: &str, location: &str, allocated: i64, workspaces: u32) -> NodeCapacity {
+    NodeCapacity {
+      node_id: node_id.to_string(),
+      location: location.to_string(),
+      ready: true,
+      capacity: resources(10),
+      allocated: resources(allocated),
+      workspaces,
+    }
+  }
+
+  fn location(name: &str, region: &str) -> Location {
+    Location {
+      name: name.to_string(),
+      region: region.to_string(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn strategies_should_select_expected_node() {
+    let nodes = vec![
+      node("busy", "eu-1", 8, 1),
+      node("idle", "eu-1", 1, 5),
+      node("full", "eu-1", 10, 0),
+    ];
+    let any = LocationConstraint::any();
+
+    let select = |strategy: Arc<dyn PlacementStrategy>| {
+      Scheduler::new(strategy)
+        .select(&nodes, &resources(2), &any)
+        .unwrap()
+        .node_id
+        .clone()
+    };
+
+    assert_eq!(select(Arc::new(BinPacking)), "busy");
+    assert_eq!(select(Arc::new(LeastLoaded)), "idle");
+    assert_eq!(select(Arc::new(Spread)), "busy");
+  }
+
+  #[test]
+  fn select_should_honor_location_and_capacity() {
+    let locations = vec![location("eu-1", "eu"), location("us-1", "us")];
+    let nodes = vec![node("eu", "eu-1", 9, 0), node("us", "us-1", 0, 0)];
+    let scheduler = Scheduler::default();
+
+    let us = LocationConstraint::region(&locations, "us");
+    assert_eq!(
+      scheduler
+        .select(&nodes, &resources(5), &us)
+        .unwrap()
+        .node_id,
+      "us"
+    );
+
+    let eu = LocationConstraint::exact(&locations[0]);
+    assert!(matches!(
+      scheduler.select(&nodes, &resources(5), &eu),
+      Err(PlacementError::NoCapacity(_))
+    ));
+
+    let unknown = LocationConstraint::region(&locations, "ap");
+    assert!(matches!(
+      scheduler.select(&nodes, &resources(1), &unknown),
+      Err(PlacementError::NoNode(_))
+    ));
+  }
+}
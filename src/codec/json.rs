@@ -9,6 +9,8 @@ use super::DecoderRead;
 use super::Encoder;
 use super::EncoderWrite;
 
+pub const CONTENT_TYPE: &str = "application/json";
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
   #[error("Serde failed {0}")]
@@ -49,9 +51,20 @@ impl<U: DeserializeOwned> Decoder for SerdeJsonDecoder<U> {
   }
 }
 
-#[derive(Clone, Debug)]
 pub struct JsonCodec<T, U>(PhantomData<(T, U)>);
 
+impl<T, U> Clone for JsonCodec<T, U> {
+  fn clone(&self) -> Self {
+    Self(PhantomData)
+  }
+}
+
+impl<T, U> std::fmt::Debug for JsonCodec<T, U> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("JsonCodec")
+  }
+}
+
 impl<T: Serialize, U: DeserializeOwned> JsonCodec<T, U> {
   pub fn new() -> Self {
     Self::default()
@@ -76,6 +89,10 @@ where
   type Encoder = SerdeJsonEncoder<T>;
   type Decoder = SerdeJsonDecoder<U>;
 
+  fn content_type(&self) -> &'static str {
+    CONTENT_TYPE
+  }
+
   fn encoder(&self) -> Self::Encoder {
     SerdeJsonEncoder(PhantomData)
   }
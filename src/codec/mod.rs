@@ -1,4 +1,23 @@
+use std::any::Any;
+
 pub mod json;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+pub mod protobuf;
+#[cfg(feature = "encryption")]
+pub mod sensitive;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("Failed to encode: {0}")]
+  Encode(Box<dyn std::error::Error + Send + Sync>),
+
+  #[error("Failed to decode: {0}")]
+  Decode(Box<dyn std::error::Error + Send + Sync>),
+
+  #[error("Codec cannot encode values of type {0}")]
+  UnexpectedType(&'static str),
+}
 
 pub trait EncoderWrite {
   fn write(&mut self, arg: &[u8]);
@@ -12,6 +31,8 @@ pub trait Encoder {
   type Item;
   type Error;
 
+  /// Appends the encoded `item` to `buf` and returns the number of bytes written. Callers must
+  /// not store `buf` when encoding fails, it may hold a partially encoded item.
   fn encode<T: EncoderWrite>(
     &mut self,
     item: &Self::Item,
@@ -34,6 +55,10 @@ pub trait Codec {
   type Encoder: Encoder<Item = Self::Encodable, Error = Self::EncodingError>;
   type Decoder: Decoder<Item = Self::Decodable, Error = Self::DecodingError>;
 
+  /// Media type of the encoded items, stored next to payloads so they can be decoded after the
+  /// codec of a queue changed.
+  fn content_type(&self) -> &'static str;
+
   fn encoder(&self) -> Self::Encoder;
 
   fn decoder(&self) -> Self::Decoder;
@@ -56,3 +81,129 @@ impl DecoderRead for &str {
     self.as_bytes()
   }
 }
+
+/// Decodes without copying the buffer, e.g. a payload fetched from Redis.
+impl DecoderRead for bytes::Bytes {
+  fn as_slice(&self) -> &[u8] {
+    self
+  }
+}
+
+impl DecoderRead for &[u8] {
+  fn as_slice(&self) -> &[u8] {
+    self
+  }
+}
+
+/// Object safe version of [`Codec`], so codecs of different item types can be stored together,
+/// e.g. in a [`crate::longrunning::registry::TaskRegistry`]. Items are passed as [`Any`] and
+/// must be of the type of the underlying codec.
+pub trait DynCodec: Send + Sync + std::fmt::Debug {
+  fn content_type(&self) -> &'static str;
+
+  fn encode_any(&self, item: &dyn Any, buf: &mut Vec<u8>) -> Result<usize, Error>;
+
+  fn decode_any(&self, buf: &[u8]) -> Result<Option<Box<dyn Any + Send>>, Error>;
+}
+
+impl<C> DynCodec for C
+where
+  C: Codec + Send + Sync + std::fmt::Debug,
+  C::Encodable: 'static,
+  C::Decodable: Send + 'static,
+  C::EncodingError: std::error::Error + Send + Sync + 'static,
+  C::DecodingError: std::error::Error + Send + Sync + 'static,
+{
+  fn content_type(&self) -> &'static str {
+    Codec::content_type(self)
+  }
+
+  fn encode_any(&self, item: &dyn Any, buf: &mut Vec<u8>) -> Result<usize, Error> {
+    let item = item
+      .downcast_ref::<C::Encodable>()
+      .ok_or(Error::UnexpectedType(std::any::type_name::<C::Encodable>()))?;
+
+    self
+      .encoder()
+      .encode(item, buf)
+      .map_err(|error| Error::Encode(Box::new(error)))
+  }
+
+  fn decode_any(&self, mut buf: &[u8]) -> Result<Option<Box<dyn Any + Send>>, Error> {
+    let item = self
+      .decoder()
+      .decode(&mut buf)
+      .map_err(|error| Error::Decode(Box::new(error)))?;
+
+    Ok(item.map(|item| Box::new(item) as Box<dyn Any + Send>))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use serde::Deserialize;
+  use serde::Serialize;
+
+  use super::json::JsonCodec;
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+  struct Task {
+    item: i32,
+  }
+
+  #[test]
+  fn dyn_codec_should_round_trip_and_reject_other_types() {
+    let codec: Box<dyn DynCodec> = Box::new(JsonCodec::<Task, Task>::new());
+    let mut buf = Vec::default();
+
+    codec.encode_any(&Task { item: 10 }, &mut buf).unwrap();
+    let decoded = codec.decode_any(&buf).unwrap().unwrap();
+
+    assert_eq!(decoded.downcast_ref::<Task>(), Some(&Task { item: 10 }));
+    assert!(matches!(
+      codec.encode_any(&10, &mut buf),
+      Err(Error::UnexpectedType(_))
+    ));
+    assert!(codec.decode_any(b"not json").is_err());
+  }
+
+  #[test]
+  fn protobuf_codec_should_round_trip() {
+    use crate::proto::longrunning::GetOperationRequest;
+
+    let codec = protobuf::ProtobufCodec::<GetOperationRequest>::new();
+    let request = GetOperationRequest {
+      operation_id: "42".to_string(),
+    };
+    let mut buf = Vec::default();
+
+    codec.encoder().encode(&request, &mut buf).unwrap();
+
+    assert_eq!(Codec::content_type(&codec), "application/protobuf");
+    assert_eq!(codec.decoder().decode(&mut buf).unwrap(), Some(request));
+  }
+
+  #[cfg(feature = "msgpack")]
+  #[test]
+  fn msgpack_codec_should_round_trip() {
+    let codec = msgpack::MsgpackCodec::<Task, Task>::new();
+    let mut buf = Vec::default();
+
+    codec.encoder().encode(&Task { item: 7 }, &mut buf).unwrap();
+
+    assert_eq!(
+      codec.decoder().decode(&mut buf).unwrap(),
+      Some(Task { item: 7 })
+    );
+  }
+
+  #[test]
+  fn decoder_should_read_bytes() {
+    let mut decoder = JsonCodec::<Task, Task>::new().decoder();
+    let mut buf = Bytes::from_static(b"{\"item\":1}");
+
+    assert_eq!(decoder.decode(&mut buf).unwrap(), Some(Task { item: 1 }));
+  }
+}
@@ -0,0 +1,110 @@
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::Codec;
+use super::Decoder;
+use super::DecoderRead;
+use super::Encoder;
+use super::EncoderWrite;
+
+pub const CONTENT_TYPE: &str = "application/msgpack+zstd";
+
+/// Compression level used by [`MsgpackCodec`], zstd's default.
+const LEVEL: i32 = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("MessagePack encoding failed {0}")]
+  Encode(#[from] rmp_serde::encode::Error),
+
+  #[error("MessagePack decoding failed {0}")]
+  Decode(#[from] rmp_serde::decode::Error),
+
+  #[error("Compression failed {0}")]
+  Zstd(#[from] std::io::Error),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MsgpackEncoder<T>(PhantomData<T>);
+
+#[derive(Clone, Debug, Default)]
+pub struct MsgpackDecoder<T>(PhantomData<T>);
+
+impl<U: Serialize> Encoder for MsgpackEncoder<U> {
+  type Item = U;
+
+  type Error = Error;
+
+  fn encode<T: EncoderWrite>(
+    &mut self,
+    item: &Self::Item,
+    buf: &mut T,
+  ) -> Result<usize, Self::Error> {
+    let packed = rmp_serde::to_vec_named(item)?;
+    let temp = zstd::encode_all(packed.as_slice(), LEVEL)?;
+    buf.write(&temp);
+
+    Ok(temp.len())
+  }
+}
+
+impl<U: DeserializeOwned> Decoder for MsgpackDecoder<U> {
+  type Item = U;
+
+  type Error = Error;
+
+  fn decode<T: DecoderRead>(&mut self, buf: &mut T) -> Result<Option<Self::Item>, Self::Error> {
+    let packed = zstd::decode_all(buf.as_slice())?;
+    let res = rmp_serde::from_slice(&packed)?;
+    Ok(Some(res))
+  }
+}
+
+/// Encodes items as zstd compressed MessagePack, for large payloads.
+#[derive(Clone)]
+pub struct MsgpackCodec<T, U>(PhantomData<(T, U)>);
+
+impl<T, U> std::fmt::Debug for MsgpackCodec<T, U> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("MsgpackCodec")
+  }
+}
+
+impl<T: Serialize, U: DeserializeOwned> MsgpackCodec<T, U> {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl<T: Serialize, U: DeserializeOwned> Default for MsgpackCodec<T, U> {
+  fn default() -> Self {
+    Self(PhantomData)
+  }
+}
+
+impl<T, U> Codec for MsgpackCodec<T, U>
+where
+  T: Serialize,
+  U: DeserializeOwned,
+{
+  type Encodable = T;
+  type Decodable = U;
+  type EncodingError = Error;
+  type DecodingError = Error;
+  type Encoder = MsgpackEncoder<T>;
+  type Decoder = MsgpackDecoder<U>;
+
+  fn content_type(&self) -> &'static str {
+    CONTENT_TYPE
+  }
+
+  fn encoder(&self) -> Self::Encoder {
+    MsgpackEncoder(PhantomData)
+  }
+
+  fn decoder(&self) -> Self::Decoder {
+    MsgpackDecoder(PhantomData)
+  }
+}
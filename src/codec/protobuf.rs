@@ -0,0 +1,94 @@
+use std::marker::PhantomData;
+
+use prost::Message;
+
+use super::Codec;
+use super::Decoder;
+use super::DecoderRead;
+use super::Encoder;
+use super::EncoderWrite;
+
+pub const CONTENT_TYPE: &str = "application/protobuf";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("Protobuf decoding failed {0}")]
+  Decode(#[from] prost::DecodeError),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProstEncoder<T>(PhantomData<T>);
+
+#[derive(Clone, Debug, Default)]
+pub struct ProstDecoder<T>(PhantomData<T>);
+
+impl<U: Message> Encoder for ProstEncoder<U> {
+  type Item = U;
+
+  type Error = Error;
+
+  fn encode<T: EncoderWrite>(
+    &mut self,
+    item: &Self::Item,
+    buf: &mut T,
+  ) -> Result<usize, Self::Error> {
+    let temp = item.encode_to_vec();
+    buf.write(&temp);
+
+    Ok(temp.len())
+  }
+}
+
+impl<U: Message + Default> Decoder for ProstDecoder<U> {
+  type Item = U;
+
+  type Error = Error;
+
+  fn decode<T: DecoderRead>(&mut self, buf: &mut T) -> Result<Option<Self::Item>, Self::Error> {
+    let res = U::decode(buf.as_slice())?;
+    Ok(Some(res))
+  }
+}
+
+/// Encodes items with their protobuf wire format.
+#[derive(Clone)]
+pub struct ProtobufCodec<T>(PhantomData<T>);
+
+impl<T> std::fmt::Debug for ProtobufCodec<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("ProtobufCodec")
+  }
+}
+
+impl<T: Message + Default> ProtobufCodec<T> {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl<T: Message + Default> Default for ProtobufCodec<T> {
+  fn default() -> Self {
+    Self(PhantomData)
+  }
+}
+
+impl<T: Message + Default> Codec for ProtobufCodec<T> {
+  type Encodable = T;
+  type Decodable = T;
+  type EncodingError = Error;
+  type DecodingError = Error;
+  type Encoder = ProstEncoder<T>;
+  type Decoder = ProstDecoder<T>;
+
+  fn content_type(&self) -> &'static str {
+    CONTENT_TYPE
+  }
+
+  fn encoder(&self) -> Self::Encoder {
+    ProstEncoder(PhantomData)
+  }
+
+  fn decoder(&self) -> Self::Decoder {
+    ProstDecoder(PhantomData)
+  }
+}
@@ -0,0 +1,328 @@
+//! Field-level encryption of the personal data of a payload, so the rest of it stays readable in
+//! Redis for searching and debugging.
+//!
+//! Task types wrap their sensitive fields in [`Sensitive`], and queues encode them with a
+//! [`SensitiveCodec`] wrapping their usual codec:
+//!
+//! ```rust,ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Export {
+//!   account_id: String,
+//!   email: Sensitive<String>,
+//! }
+//!
+//! let codec = SensitiveCodec::new(JsonCodec::<Export, Export>::new(), FieldKey::new(&secret)?);
+//! // {"account_id":"42","email":"enc:v1:3q2+7w..."}
+//! ```
+//!
+//! Fields are sealed with AES-256-GCM under a random nonce. A [`Sensitive`] field only encodes
+//! within a [`SensitiveCodec`], so it is never written in clear by mistake.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use ring::aead;
+use ring::rand::SecureRandom;
+use ring::rand::SystemRandom;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+use super::Codec;
+use super::Decoder;
+use super::DecoderRead;
+use super::Encoder;
+use super::EncoderWrite;
+
+/// Prefix of the encrypted fields, versioning their layout.
+const PREFIX: &str = "enc:v1:";
+
+thread_local! {
+  /// Key of the codec encoding or decoding on this thread, see [`scoped`].
+  static KEY: RefCell<Option<Arc<FieldKey>>> = const { RefCell::new(None) };
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("Field keys must be 32 bytes long")]
+  InvalidKey,
+
+  #[error("Sensitive field encoded or decoded outside of a SensitiveCodec")]
+  MissingKey,
+
+  #[error("Failed to encrypt a sensitive field")]
+  Seal,
+
+  #[error("Failed to decrypt a sensitive field, it is malformed or was sealed with another key")]
+  Open,
+}
+
+/// AES-256-GCM key sealing the [`Sensitive`] fields.
+pub struct FieldKey {
+  key: aead::LessSafeKey,
+  rng: SystemRandom,
+}
+
+impl fmt::Debug for FieldKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("FieldKey(***)")
+  }
+}
+
+impl FieldKey {
+  /// Key of the 32 bytes of `secret`.
+  pub fn new(secret: &[u8]) -> Result<Self, Error> {
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, secret).map_err(|_| Error::InvalidKey)?;
+
+    Ok(Self {
+      key: aead::LessSafeKey::new(key),
+      rng: SystemRandom::new(),
+    })
+  }
+
+  /// `plaintext` sealed under a random nonce, as `enc:v1:` followed by the base64 encoded nonce and
+  /// ciphertext.
+  fn seal(&self, plaintext: &[u8]) -> Result<String, Error> {
+    let mut nonce = [0; aead::NONCE_LEN];
+    self.rng.fill(&mut nonce).map_err(|_| Error::Seal)?;
+
+    let mut sealed = plaintext.to_vec();
+    self
+      .key
+      .seal_in_place_append_tag(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::empty(),
+        &mut sealed,
+      )
+      .map_err(|_| Error::Seal)?;
+
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&sealed);
+    Ok(format!("{}{}", PREFIX, base64::encode(out)))
+  }
+
+  fn open(&self, sealed: &str) -> Result<Vec<u8>, Error> {
+    let encoded = sealed.strip_prefix(PREFIX).ok_or(Error::Open)?;
+    let mut sealed = base64::decode(encoded).map_err(|_| Error::Open)?;
+    if sealed.len() < aead::NONCE_LEN {
+      return Err(Error::Open);
+    }
+
+    let mut ciphertext = sealed.split_off(aead::NONCE_LEN);
+    let nonce = aead::Nonce::try_assume_unique_for_key(&sealed).map_err(|_| Error::Open)?;
+    let plaintext = self
+      .key
+      .open_in_place(nonce, aead::Aad::empty(), &mut ciphertext)
+      .map_err(|_| Error::Open)?;
+
+    Ok(plaintext.to_vec())
+  }
+}
+
+/// Runs `f` with `key` sealing and opening the [`Sensitive`] fields of this thread.
+fn scoped<R>(key: &Arc<FieldKey>, f: impl FnOnce() -> R) -> R {
+  struct Restore(Option<Arc<FieldKey>>);
+
+  impl Drop for Restore {
+    fn drop(&mut self) {
+      KEY.with(|current| *current.borrow_mut() = self.0.take());
+    }
+  }
+
+  let _restore = Restore(KEY.with(|current| current.borrow_mut().replace(key.clone())));
+  f()
+}
+
+fn with_key<R>(f: impl FnOnce(&FieldKey) -> Result<R, Error>) -> Result<R, Error> {
+  KEY.with(|current| match current.borrow().as_deref() {
+    Some(key) => f(key),
+    None => Err(Error::MissingKey),
+  })
+}
+
+/// A field encrypted in the encoded payload, see the [module](self) documentation. Its debug
+/// output is redacted.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Sensitive<T>(T);
+
+impl<T> Sensitive<T> {
+  pub fn new(value: T) -> Self {
+    Self(value)
+  }
+
+  pub fn into_inner(self) -> T {
+    self.0
+  }
+}
+
+impl<T> From<T> for Sensitive<T> {
+  fn from(value: T) -> Self {
+    Self(value)
+  }
+}
+
+impl<T> Deref for Sensitive<T> {
+  type Target = T;
+
+  fn deref(&self) -> &Self::Target {
+    &self.0
+  }
+}
+
+impl<T> fmt::Debug for Sensitive<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Sensitive(***)")
+  }
+}
+
+impl<T: Serialize> Serialize for Sensitive<T> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let plaintext = serde_json::to_vec(&self.0).map_err(serde::ser::Error::custom)?;
+    let sealed = with_key(|key| key.seal(&plaintext)).map_err(serde::ser::Error::custom)?;
+    serializer.serialize_str(&sealed)
+  }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Sensitive<T> {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let sealed = String::deserialize(deserializer)?;
+    let plaintext = with_key(|key| key.open(&sealed)).map_err(serde::de::Error::custom)?;
+    let value = serde_json::from_slice(&plaintext).map_err(serde::de::Error::custom)?;
+    Ok(Self(value))
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct SensitiveEncoder<E> {
+  inner: E,
+  key: Arc<FieldKey>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SensitiveDecoder<D> {
+  inner: D,
+  key: Arc<FieldKey>,
+}
+
+impl<E: Encoder> Encoder for SensitiveEncoder<E> {
+  type Item = E::Item;
+
+  type Error = E::Error;
+
+  fn encode<T: EncoderWrite>(
+    &mut self,
+    item: &Self::Item,
+    buf: &mut T,
+  ) -> Result<usize, Self::Error> {
+    let inner = &mut self.inner;
+    scoped(&self.key, || inner.encode(item, buf))
+  }
+}
+
+impl<D: Decoder> Decoder for SensitiveDecoder<D> {
+  type Item = D::Item;
+
+  type Error = D::Error;
+
+  fn decode<T: DecoderRead>(&mut self, buf: &mut T) -> Result<Option<Self::Item>, Self::Error> {
+    let inner = &mut self.inner;
+    scoped(&self.key, || inner.decode(buf))
+  }
+}
+
+/// Encodes items with the codec it wraps, encrypting their [`Sensitive`] fields with its key.
+/// Payloads keep the content type of the wrapped codec.
+#[derive(Clone, Debug)]
+pub struct SensitiveCodec<C> {
+  inner: C,
+  key: Arc<FieldKey>,
+}
+
+impl<C: Codec> SensitiveCodec<C> {
+  pub fn new(inner: C, key: FieldKey) -> Self {
+    Self {
+      inner,
+      key: Arc::new(key),
+    }
+  }
+}
+
+impl<C: Codec> Codec for SensitiveCodec<C> {
+  type Encodable = C::Encodable;
+  type Decodable = C::Decodable;
+  type EncodingError = C::EncodingError;
+  type DecodingError = C::DecodingError;
+  type Encoder = SensitiveEncoder<C::Encoder>;
+  type Decoder = SensitiveDecoder<C::Decoder>;
+
+  fn content_type(&self) -> &'static str {
+    self.inner.content_type()
+  }
+
+  fn encoder(&self) -> Self::Encoder {
+    SensitiveEncoder {
+      inner: self.inner.encoder(),
+      key: self.key.clone(),
+    }
+  }
+
+  fn decoder(&self) -> Self::Decoder {
+    SensitiveDecoder {
+      inner: self.inner.decoder(),
+      key: self.key.clone(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde::Deserialize;
+  use serde::Serialize;
+
+  use super::*;
+  use crate::codec::json::JsonCodec;
+
+  #[derive(Serialize, Deserialize, Debug, PartialEq)]
+  struct Export {
+    account_id: String,
+    email: Sensitive<String>,
+  }
+
+  fn codec(secret: u8) -> SensitiveCodec<JsonCodec<Export, Export>> {
+    SensitiveCodec::new(JsonCodec::new(), FieldKey::new(&[secret; 32]).unwrap())
+  }
+
+  #[test]
+  fn codec_should_only_encrypt_sensitive_fields() {
+    let export = Export {
+      account_id: "42".to_string(),
+      email: "jane@example.com".to_string().into(),
+    };
+
+    let mut buf = Vec::new();
+    codec(1).encoder().encode(&export, &mut buf).unwrap();
+    let encoded: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(encoded["account_id"], "42");
+    assert!(encoded["email"].as_str().unwrap().starts_with(PREFIX));
+    assert!(!String::from_utf8_lossy(&buf).contains("jane@example.com"));
+
+    assert_eq!(codec(1).decoder().decode(&mut buf).unwrap(), Some(export));
+    assert!(codec(2).decoder().decode(&mut buf).is_err());
+    assert_eq!(format!("{:?}", Sensitive::new("jane")), "Sensitive(***)");
+  }
+
+  #[test]
+  fn sensitive_fields_should_not_encode_without_a_key() {
+    let export = Export {
+      account_id: "42".to_string(),
+      email: "jane@example.com".to_string().into(),
+    };
+
+    assert!(serde_json::to_vec(&export).is_err());
+    assert!(matches!(FieldKey::new(b"short"), Err(Error::InvalidKey)));
+  }
+}
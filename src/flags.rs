@@ -0,0 +1,396 @@
+//! Feature flags shared by the rappel services.
+//!
+//! A [`Flag`] holds a JSON value per target: a default, overridden per organization, overridden
+//! per user. Flags are read from a [`FlagSource`], either the Redis hash `flags` (see
+//! [`RedisFlagSource`]) or a JSON file (see [`FileFlagSource`]), and cached by a [`FlagClient`].
+//! Changes made through [`RedisFlagSource::set`] are announced on [`FLAG_EVENTS_CHANNEL`], which
+//! [`FlagClient::watch`] follows to drop its cache right away.
+//!
+//! ```rust,ignore
+//! let flags = FlagClient::new(Arc::new(RedisFlagSource::new(client.clone())));
+//! tokio::spawn({
+//!   let flags = flags.clone();
+//!   async move { flags.watch(client, FLAG_EVENTS_CHANNEL).await }
+//! });
+//!
+//! let target = Target::default().with_organization_id("acme");
+//! if flags.is_enabled("workspaces.snapshots", &target).await {
+//!   // ...
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::StreamExt;
+use redis::AsyncCommands;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use tracing_futures::Instrument;
+
+use crate::redis::Keys;
+use crate::util::redis_exec;
+
+/// Redis pub/sub channel the names of the changed flags are published to.
+pub const FLAG_EVENTS_CHANNEL: &str = "events:flags";
+
+/// Time the flags are cached for when no invalidation is received.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum FlagError {
+  #[error("Redis command failed: {0}")]
+  Redis(#[from] redis::RedisError),
+
+  #[error("Failed to read the flags file: {0}")]
+  Io(#[from] std::io::Error),
+
+  #[error("Invalid flag {name}: {source}")]
+  Invalid {
+    name: String,
+    source: serde_json::Error,
+  },
+}
+
+/// Who a flag is evaluated for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Target {
+  pub user_id: Option<String>,
+  pub organization_id: Option<String>,
+}
+
+impl Target {
+  pub fn with_user_id(mut self, user_id: &str) -> Self {
+    self.user_id = Some(user_id.to_string());
+    self
+  }
+
+  pub fn with_organization_id(mut self, organization_id: &str) -> Self {
+    self.organization_id = Some(organization_id.to_string());
+    self
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Flag {
+  pub default: Value,
+
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub organizations: HashMap<String, Value>,
+
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub users: HashMap<String, Value>,
+}
+
+impl Flag {
+  pub fn new(default: Value) -> Self {
+    Self {
+      default,
+      ..Default::default()
+    }
+  }
+
+  pub fn with_organization(mut self, organization_id: &str, value: Value) -> Self {
+    self
+      .organizations
+      .insert(organization_id.to_string(), value);
+    self
+  }
+
+  pub fn with_user(mut self, user_id: &str, value: Value) -> Self {
+    self.users.insert(user_id.to_string(), value);
+    self
+  }
+
+  /// Returns the value of the user of `target`, else of its organization, else the default.
+  pub fn evaluate(&self, target: &Target) -> &Value {
+    let user = target.user_id.as_ref().and_then(|id| self.users.get(id));
+    let organization = || {
+      let id = target.organization_id.as_ref()?;
+      self.organizations.get(id)
+    };
+
+    user.or_else(organization).unwrap_or(&self.default)
+  }
+}
+
+/// Loads every flag at once.
+#[async_trait::async_trait]
+pub trait FlagSource: Send + Sync + fmt::Debug {
+  async fn load(&self) -> Result<HashMap<String, Flag>, FlagError>;
+}
+
+/// Flags stored as JSON in the Redis hash `flags`, keyed by flag name.
+#[derive(Clone, Debug)]
+pub struct RedisFlagSource {
+  client: redis::Client,
+  keys: Keys,
+}
+
+impl RedisFlagSource {
+  pub fn new(client: redis::Client) -> Self {
+    Self {
+      client,
+      keys: Keys::default(),
+    }
+  }
+
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Creates or replaces the flag `name` and announces the change.
+  pub async fn set(&self, name: &str, flag: &Flag) -> Result<(), FlagError> {
+    let value = serde_json::to_string(flag).map_err(|source| FlagError::Invalid {
+      name: name.to_string(),
+      source,
+    })?;
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = redis::pipe()
+      .hset(self.keys.flags(), name, value)
+      .ignore()
+      .publish(self.keys.channel(FLAG_EVENTS_CHANNEL), name)
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-flags-set", %name))
+      .await?;
+
+    Ok(())
+  }
+
+  /// Deletes the flag `name` and announces the change.
+  pub async fn remove(&self, name: &str) -> Result<(), FlagError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = redis::pipe()
+      .hdel(self.keys.flags(), name)
+      .ignore()
+      .publish(self.keys.channel(FLAG_EVENTS_CHANNEL), name)
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-flags-remove", %name))
+      .await?;
+
+    Ok(())
+  }
+}
+
+#[async_trait::async_trait]
+impl FlagSource for RedisFlagSource {
+  async fn load(&self) -> Result<HashMap<String, Flag>, FlagError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let values: HashMap<String, String> = conn
+      .hgetall(self.keys.flags())
+      .instrument(tracing::info_span!("redis-flags-load"))
+      .await?;
+
+    values
+      .into_iter()
+      .map(|(name, value)| match serde_json::from_str(&value) {
+        Ok(flag) => Ok((name, flag)),
+        Err(source) => Err(FlagError::Invalid { name, source }),
+      })
+      .collect()
+  }
+}
+
+/// Flags read from a JSON file holding an object keyed by flag name, e.g.
+/// `{"workspaces.snapshots": {"default": false, "organizations": {"acme": true}}}`.
+#[derive(Clone, Debug)]
+pub struct FileFlagSource {
+  path: PathBuf,
+}
+
+impl FileFlagSource {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self { path: path.into() }
+  }
+}
+
+#[async_trait::async_trait]
+impl FlagSource for FileFlagSource {
+  async fn load(&self) -> Result<HashMap<String, Flag>, FlagError> {
+    let content = tokio::fs::read(&self.path).await?;
+
+    serde_json::from_slice(&content).map_err(|source| FlagError::Invalid {
+      name: self.path.display().to_string(),
+      source,
+    })
+  }
+}
+
+type Cached = (Instant, Arc<HashMap<String, Flag>>);
+
+/// Evaluates flags from a cached [`FlagSource`]. When the source fails, the last flags loaded are
+/// used until it recovers. Clones share the cache.
+#[derive(Clone, Debug)]
+pub struct FlagClient {
+  source: Arc<dyn FlagSource>,
+  ttl: Duration,
+  cache: Arc<Mutex<Option<Cached>>>,
+}
+
+impl FlagClient {
+  pub fn new(source: Arc<dyn FlagSource>) -> Self {
+    Self {
+      source,
+      ttl: DEFAULT_TTL,
+      cache: Arc::default(),
+    }
+  }
+
+  /// Reloads the flags at least every `ttl`, [`DEFAULT_TTL`] by default.
+  pub fn with_ttl(mut self, ttl: Duration) -> Self {
+    self.ttl = ttl;
+    self
+  }
+
+  /// Returns the value of the flag `name` for `target`, `None` if there is no such flag.
+  pub async fn value(&self, name: &str, target: &Target) -> Result<Option<Value>, FlagError> {
+    let flags = self.flags().await?;
+    Ok(flags.get(name).map(|flag| flag.evaluate(target).clone()))
+  }
+
+  /// Returns the value of the flag `name` for `target` as a `T`.
+  pub async fn get<T: DeserializeOwned>(
+    &self,
+    name: &str,
+    target: &Target,
+  ) -> Result<Option<T>, FlagError> {
+    self
+      .value(name, target)
+      .await?
+      .map(serde_json::from_value)
+      .transpose()
+      .map_err(|source| FlagError::Invalid {
+        name: name.to_string(),
+        source,
+      })
+  }
+
+  /// Whether the boolean flag `name` is on for `target`. Missing, invalid or unavailable flags
+  /// are off.
+  pub async fn is_enabled(&self, name: &str, target: &Target) -> bool {
+    self.get_or(name, target, false).await
+  }
+
+  /// Returns the value of the flag `name` for `target`, or `default` if the flag is missing,
+  /// invalid or unavailable.
+  pub async fn get_or<T: DeserializeOwned>(&self, name: &str, target: &Target, default: T) -> T {
+    match self.get(name, target).await {
+      Ok(Some(value)) => value,
+      Ok(None) => default,
+      Err(error) => {
+        tracing::warn!(message = "Failed to evaluate flag", %name, %error);
+        default
+      }
+    }
+  }
+
+  /// Drops the cached flags, the next evaluation reloads them.
+  pub fn invalidate(&self) {
+    self.cache.lock().unwrap().take();
+  }
+
+  /// Drops the cached flags whenever a change is published on `channel`, usually the prefixed
+  /// [`FLAG_EVENTS_CHANNEL`], until the subscription ends.
+  pub async fn watch(&self, client: redis::Client, channel: &str) -> Result<(), FlagError> {
+    let mut pubsub = redis_exec::connect(&client).await?.into_pubsub();
+    pubsub.subscribe(channel).await?;
+
+    let mut messages = pubsub.into_on_message();
+    while let Some(message) = messages.next().await {
+      let name: String = message.get_payload().unwrap_or_default();
+      tracing::debug!(message = "Flag changed", %name);
+      self.invalidate();
+    }
+
+    Ok(())
+  }
+
+  async fn flags(&self) -> Result<Arc<HashMap<String, Flag>>, FlagError> {
+    let stale = match &*self.cache.lock().unwrap() {
+      Some((loaded, flags)) if loaded.elapsed() < self.ttl => return Ok(flags.clone()),
+      Some((_, flags)) => Some(flags.clone()),
+      None => None,
+    };
+
+    match (self.source.load().await, stale) {
+      (Ok(flags), _) => {
+        let flags = Arc::new(flags);
+        *self.cache.lock().unwrap() = Some((Instant::now(), flags.clone()));
+        Ok(flags)
+      }
+      (Err(error), Some(stale)) => {
+        tracing::warn!(message = "Failed to reload the flags, using the cached ones", %error);
+        Ok(stale)
+      }
+      (Err(error), None) => Err(error),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+
+  use serde_json::json;
+
+  use super::*;
+
+  #[derive(Debug, Default)]
+  struct CountingSource {
+    loads: AtomicUsize,
+  }
+
+  #[async_trait::async_trait]
+  impl FlagSource for CountingSource {
+    async fn load(&self) -> Result<HashMap<String, Flag>, FlagError> {
+      self.loads.fetch_add(1, Ordering::SeqCst);
+
+      let snapshots = Flag::new(json!(false))
+        .with_organization("acme", json!(true))
+        .with_user("42", json!(false));
+      let limit = Flag::new(json!(10)).with_organization("acme", json!("many"));
+
+      Ok(HashMap::from([
+        ("snapshots".to_string(), snapshots),
+        ("limit".to_string(), limit),
+      ]))
+    }
+  }
+
+  #[tokio::test]
+  async fn client_should_evaluate_targets_from_cache() {
+    let source = Arc::new(CountingSource::default());
+    let flags = FlagClient::new(source.clone());
+    let acme = Target::default().with_organization_id("acme");
+
+    assert!(flags.is_enabled("snapshots", &acme).await);
+    assert!(
+      !flags
+        .is_enabled("snapshots", &acme.clone().with_user_id("42"))
+        .await
+    );
+    assert!(!flags.is_enabled("snapshots", &Target::default()).await);
+    assert!(!flags.is_enabled("missing", &acme).await);
+    assert_eq!(flags.get_or("limit", &Target::default(), 0).await, 10);
+    assert!(flags.get::<i64>("limit", &acme).await.is_err());
+    assert_eq!(source.loads.load(Ordering::SeqCst), 1);
+
+    flags.invalidate();
+    assert_eq!(flags.get_or("limit", &acme, 0).await, 0);
+    assert_eq!(source.loads.load(Ordering::SeqCst), 2);
+  }
+}
@@ -0,0 +1,208 @@
+//! Entry points for fuzzers, e.g. the `cargo fuzz` targets in `fuzz/`. Every function takes
+//! arbitrary bytes, as they could be found in Redis, and panics only when a reader panics or a
+//! decoded value does not survive a round trip through its codec.
+//!
+//! ```text
+//! cargo +nightly fuzz run json_codec
+//! ```
+
+use crate::codec::json::JsonCodec;
+use crate::codec::Codec;
+use crate::codec::Decoder;
+use crate::codec::Encoder;
+
+/// Decodes `data` with `codec`, and checks that whatever decodes is encoded and decoded again to
+/// the same value.
+pub fn round_trip<C>(codec: &C, mut data: &[u8])
+where
+  C: Codec,
+  C::Decodable: PartialEq + std::fmt::Debug + std::borrow::Borrow<C::Encodable>,
+  C::EncodingError: std::fmt::Debug,
+  C::DecodingError: std::fmt::Debug,
+{
+  use std::borrow::Borrow;
+
+  let decoded = match codec.decoder().decode(&mut data) {
+    Ok(Some(decoded)) => decoded,
+    Ok(None) | Err(_) => return,
+  };
+
+  let mut encoded = Vec::default();
+  codec
+    .encoder()
+    .encode(decoded.borrow(), &mut encoded)
+    .expect("a decoded value should encode");
+  let decoded_again = codec
+    .decoder()
+    .decode(&mut encoded)
+    .expect("an encoded value should decode");
+
+  assert_eq!(decoded_again, Some(decoded));
+}
+
+/// Round trip of any JSON document through [`JsonCodec`].
+pub fn json_codec(data: &[u8]) {
+  round_trip(
+    &JsonCodec::<serde_json::Value, serde_json::Value>::new(),
+    data,
+  );
+}
+
+/// Round trip of operations through [`crate::codec::protobuf::ProtobufCodec`].
+#[cfg(feature = "longrunning")]
+pub fn protobuf_codec(data: &[u8]) {
+  round_trip(
+    &crate::codec::protobuf::ProtobufCodec::<crate::proto::longrunning::Operation>::new(),
+    data,
+  );
+}
+
+/// Round trip of any document through [`crate::codec::msgpack::MsgpackCodec`].
+#[cfg(feature = "msgpack")]
+pub fn msgpack_codec(data: &[u8]) {
+  round_trip(
+    &crate::codec::msgpack::MsgpackCodec::<serde_json::Value, serde_json::Value>::new(),
+    data,
+  );
+}
+
+/// Reads an operation hash from `data`, whose lines are alternately field names and values, as
+/// [`crate::longrunning::redis::RedisQueue`] readers do with `HGETALL` replies.
+#[cfg(all(feature = "longrunning", feature = "redis"))]
+pub fn operation_hash(data: &[u8]) {
+  use redis::FromRedisValue;
+
+  let hash = redis::Value::Bulk(
+    data
+      .split(|byte| *byte == b'\n')
+      .map(|item| redis::Value::Data(item.to_vec()))
+      .collect(),
+  );
+
+  let _ = crate::proto::longrunning::Operation::from_redis_value(&hash);
+}
+
+#[cfg(test)]
+mod tests {
+  use rand::rngs::StdRng;
+  use rand::Rng;
+  use rand::SeedableRng;
+  use serde_json::Value;
+
+  use super::*;
+
+  const CASES: usize = 500;
+
+  fn bytes(rng: &mut StdRng) -> Vec<u8> {
+    let len = rng.gen_range(0..64);
+    (0..len).map(|_| rng.gen()).collect()
+  }
+
+  fn text(rng: &mut StdRng) -> String {
+    let len = rng.gen_range(0..12);
+    (0..len).map(|_| rng.gen::<char>()).collect()
+  }
+
+  fn json(rng: &mut StdRng, depth: u32) -> Value {
+    match rng.gen_range(0..if depth == 0 { 4 } else { 6 }) {
+      0 => Value::Null,
+      1 => Value::Bool(rng.gen()),
+      2 => match rng.gen_range(0..3) {
+        0 => Value::from(rng.gen::<i64>()),
+        1 => Value::from(rng.gen::<u64>()),
+        // Quarters parse exactly, so the round trip does not depend on float parsing.
+        _ => Value::from(f64::from(rng.gen_range(-1_000_000..1_000_000)) / 4.0),
+      },
+      3 => Value::String(text(rng)),
+      4 => (0..rng.gen_range(0..4))
+        .map(|_| json(rng, depth - 1))
+        .collect(),
+      _ => Value::Object(
+        (0..rng.gen_range(0..4))
+          .map(|_| (text(rng), json(rng, depth - 1)))
+          .collect(),
+      ),
+    }
+  }
+
+  #[test]
+  fn json_codec_should_round_trip_any_document() {
+    let mut rng = StdRng::seed_from_u64(682);
+
+    for _ in 0..CASES {
+      let document = serde_json::to_vec(&json(&mut rng, 3)).unwrap();
+      json_codec(&document);
+      json_codec(&bytes(&mut rng));
+    }
+  }
+
+  #[cfg(feature = "longrunning")]
+  #[test]
+  fn protobuf_codec_should_round_trip_any_operation() {
+    use std::collections::HashMap;
+
+    use prost::Message;
+
+    use crate::proto::longrunning::Operation;
+
+    let mut rng = StdRng::seed_from_u64(682);
+
+    for _ in 0..CASES {
+      let operation = Operation {
+        operation_id: text(&mut rng),
+        metadata: HashMap::from([(text(&mut rng), text(&mut rng))]),
+        done: rng.gen(),
+        state: rng.gen_range(0..8),
+        parent_operation_id: text(&mut rng),
+        child_operation_ids: vec![text(&mut rng)],
+        ..Default::default()
+      };
+
+      protobuf_codec(&operation.encode_to_vec());
+      protobuf_codec(&bytes(&mut rng));
+    }
+  }
+
+  #[cfg(feature = "msgpack")]
+  #[test]
+  fn msgpack_codec_should_round_trip_any_document() {
+    let mut rng = StdRng::seed_from_u64(682);
+
+    for _ in 0..CASES {
+      let document = rmp_serde::to_vec_named(&json(&mut rng, 3)).unwrap();
+      msgpack_codec(&document);
+      msgpack_codec(&bytes(&mut rng));
+    }
+  }
+
+  #[cfg(all(feature = "longrunning", feature = "redis"))]
+  #[test]
+  fn operation_hash_should_not_panic_on_malformed_fields() {
+    let mut rng = StdRng::seed_from_u64(682);
+    let fields = [
+      "operation_id",
+      "status",
+      "done",
+      "publish_ts",
+      "dequeue_ts",
+      "end_ts",
+      "error",
+      "protocol_version",
+    ];
+
+    for _ in 0..CASES {
+      let mut hash = Vec::default();
+      for field in fields {
+        if rng.gen() {
+          hash.extend_from_slice(field.as_bytes());
+          hash.push(b'\n');
+          hash.extend(bytes(&mut rng).into_iter().filter(|byte| *byte != b'\n'));
+          hash.push(b'\n');
+        }
+      }
+
+      operation_hash(&hash);
+      operation_hash(&bytes(&mut rng));
+    }
+  }
+}
@@ -0,0 +1,1044 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bytes::Buf;
+use bytes::BufMut;
+use futures::Stream;
+use futures::StreamExt;
+use prost::Message;
+use serde_json::Map;
+use serde_json::Number;
+use serde_json::Value;
+use tonic::codec::DecodeBuf;
+use tonic::codec::EncodeBuf;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::Channel;
+
+use crate::proto::google::protobuf::field_descriptor_proto::Label;
+use crate::proto::google::protobuf::field_descriptor_proto::Type;
+use crate::proto::google::protobuf::DescriptorProto;
+use crate::proto::google::protobuf::EnumDescriptorProto;
+use crate::proto::google::protobuf::FileDescriptorSet;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("Invalid descriptor set: {0}")]
+  Descriptor(#[from] prost::DecodeError),
+
+  #[error("Unknown message type: {0}")]
+  UnknownMessage(String),
+
+  #[error("Unknown method: {0}")]
+  UnknownMethod(String),
+
+  #[error("Unknown field `{1}` in {0}")]
+  UnknownField(String, String),
+
+  #[error("Invalid value for field `{0}`: {1}")]
+  InvalidValue(String, String),
+
+  #[error("Malformed message {0}: {1}")]
+  Malformed(String, String),
+}
+
+impl From<Error> for tonic::Status {
+  fn from(error: Error) -> Self {
+    tonic::Status::invalid_argument(error.to_string())
+  }
+}
+
+/// A gRPC method resolved from a descriptor set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MethodDescriptor {
+  /// Request path, e.g. `/longrunning.Operations/Get`.
+  pub path: String,
+  pub input_type: String,
+  pub output_type: String,
+  pub client_streaming: bool,
+  pub server_streaming: bool,
+}
+
+#[derive(Clone, Debug)]
+struct FieldDescriptor {
+  name: String,
+  json_name: String,
+  number: u32,
+  kind: Type,
+  type_name: String,
+  repeated: bool,
+}
+
+#[derive(Clone, Debug)]
+struct MessageDescriptor {
+  name: String,
+  fields: Vec<FieldDescriptor>,
+  map_entry: bool,
+}
+
+impl MessageDescriptor {
+  fn field_by_name(&self, name: &str) -> Option<&FieldDescriptor> {
+    self
+      .fields
+      .iter()
+      .find(|f| f.json_name == name || f.name == name)
+  }
+
+  fn field_by_number(&self, number: u32) -> Option<&FieldDescriptor> {
+    self.fields.iter().find(|f| f.number == number)
+  }
+}
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_START_GROUP: u64 = 3;
+const WIRE_END_GROUP: u64 = 4;
+const WIRE_FIXED32: u64 = 5;
+
+/// Encodes and decodes protobuf messages as JSON at runtime, using a file descriptor set instead
+/// of generated types. Field names follow the proto3 JSON mapping: both the lowerCamelCase JSON
+/// name and the original field name are accepted, 64 bit integers are written as strings, enums
+/// by name and bytes as base64.
+///
+/// ```rust
+/// use rappel::grpc::dynamic::DescriptorPool;
+///
+/// let pool = DescriptorPool::rappel().unwrap();
+/// let request = serde_json::json!({ "operationId": "42" });
+/// let bytes = pool.encode("longrunning.GetOperationRequest", &request).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct DescriptorPool {
+  messages: HashMap<String, MessageDescriptor>,
+  enums: HashMap<String, Vec<(String, i32)>>,
+  methods: HashMap<String, MethodDescriptor>,
+}
+
+impl DescriptorPool {
+  /// Builds a pool from the descriptor set embedded in the crate.
+  pub fn rappel() -> Result<Self, Error> {
+    Self::decode(crate::proto::FILE_DESCRIPTOR_SET)
+  }
+
+  /// Builds a pool from an encoded `google.protobuf.FileDescriptorSet`.
+  pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+    let set = FileDescriptorSet::decode(buf)?;
+    let mut pool = Self::default();
+
+    for file in set.file {
+      let package = file.package().to_string();
+
+      for message in &file.message_type {
+        pool.add_message(&package, message);
+      }
+
+      for enumeration in &file.enum_type {
+        pool.add_enum(&package, enumeration);
+      }
+
+      for service in &file.service {
+        let service_name = qualify(&package, service.name());
+
+        for method in &service.method {
+          let path = format!("/{}/{}", service_name, method.name());
+          pool.methods.insert(
+            path.clone(),
+            MethodDescriptor {
+              path,
+              input_type: method.input_type().trim_start_matches('.').to_string(),
+              output_type: method.output_type().trim_start_matches('.').to_string(),
+              client_streaming: method.client_streaming(),
+              server_streaming: method.server_streaming(),
+            },
+          );
+        }
+      }
+    }
+
+    Ok(pool)
+  }
+
+  fn add_message(&mut self, scope: &str, message: &DescriptorProto) {
+    let name = qualify(scope, message.name());
+
+    for nested in &message.nested_type {
+      self.add_message(&name, nested);
+    }
+
+    for enumeration in &message.enum_type {
+      self.add_enum(&name, enumeration);
+    }
+
+    let fields = message
+      .field
+      .iter()
+      .map(|f| FieldDescriptor {
+        name: f.name().to_string(),
+        json_name: f.json_name().to_string(),
+        number: f.number() as u32,
+        kind: f.r#type(),
+        type_name: f.type_name().trim_start_matches('.').to_string(),
+        repeated: f.label() == Label::Repeated,
+      })
+      .collect();
+
+    let map_entry = message
+      .options
+      .as_ref()
+      .map(|o| o.map_entry())
+      .unwrap_or(false);
+
+    self.messages.insert(
+      name.clone(),
+      MessageDescriptor {
+        name,
+        fields,
+        map_entry,
+      },
+    );
+  }
+
+  fn add_enum(&mut self, scope: &str, enumeration: &EnumDescriptorProto) {
+    let values = enumeration
+      .value
+      .iter()
+      .map(|v| (v.name().to_string(), v.number()))
+      .collect();
+
+    self
+      .enums
+      .insert(qualify(scope, enumeration.name()), values);
+  }
+
+  /// Looks up a method by `package.Service/Method`, with or without the leading slash.
+  pub fn method(&self, name: &str) -> Result<&MethodDescriptor, Error> {
+    let path = format!("/{}", name.trim_start_matches('/'));
+
+    self
+      .methods
+      .get(&path)
+      .ok_or_else(|| Error::UnknownMethod(name.to_string()))
+  }
+
+  /// Lists the paths of all the methods in the pool.
+  pub fn methods(&self) -> impl Iterator<Item = &MethodDescriptor> {
+    self.methods.values()
+  }
+
+  /// Encodes the JSON representation of the message `message` (e.g. `longrunning.Operation`).
+  pub fn encode(&self, message: &str, value: &Value) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::default();
+    self.encode_message(self.message(message)?, value, &mut buf)?;
+    Ok(buf)
+  }
+
+  /// Decodes an encoded message `message` into its JSON representation.
+  pub fn decode_message(&self, message: &str, buf: &[u8]) -> Result<Value, Error> {
+    self.decode_fields(self.message(message)?, buf)
+  }
+
+  fn message(&self, name: &str) -> Result<&MessageDescriptor, Error> {
+    self
+      .messages
+      .get(name.trim_start_matches('.'))
+      .ok_or_else(|| Error::UnknownMessage(name.to_string()))
+  }
+
+  fn encode_message(
+    &self,
+    desc: &MessageDescriptor,
+    value: &Value,
+    buf: &mut Vec<u8>,
+  ) -> Result<(), Error> {
+    match desc.name.as_str() {
+      "google.protobuf.Timestamp" if value.is_string() => {
+        return self.encode_message(desc, &timestamp_to_json(value)?, buf);
+      }
+      "google.protobuf.Duration" if value.is_string() => {
+        return self.encode_message(desc, &duration_to_json(value)?, buf);
+      }
+      _ => {}
+    }
+
+    let object = value
+      .as_object()
+      .ok_or_else(|| Error::InvalidValue(desc.name.clone(), "expected an object".to_string()))?;
+
+    for (key, value) in object {
+      let field = desc
+        .field_by_name(key)
+        .ok_or_else(|| Error::UnknownField(desc.name.clone(), key.clone()))?;
+
+      if value.is_null() {
+        continue;
+      }
+
+      if field.repeated {
+        self.encode_repeated(field, value, buf)?;
+      } else {
+        self.encode_field(field, value, buf)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn encode_repeated(
+    &self,
+    field: &FieldDescriptor,
+    value: &Value,
+    buf: &mut Vec<u8>,
+  ) -> Result<(), Error> {
+    if let Some(entry) = self.map_entry(field) {
+      let object = value
+        .as_object()
+        .ok_or_else(|| Error::InvalidValue(field.name.clone(), "expected an object".to_string()))?;
+
+      for (key, value) in object {
+        let mut entry_buf = Vec::default();
+        self.encode_field(
+          &entry.fields[0],
+          &Value::String(key.clone()),
+          &mut entry_buf,
+        )?;
+        self.encode_field(&entry.fields[1], value, &mut entry_buf)?;
+
+        encode_key(field.number, WIRE_LEN, buf);
+        encode_bytes(&entry_buf, buf);
+      }
+
+      return Ok(());
+    }
+
+    let items = value
+      .as_array()
+      .ok_or_else(|| Error::InvalidValue(field.name.clone(), "expected an array".to_string()))?;
+
+    if is_packable(field.kind) {
+      let mut packed = Vec::default();
+      for item in items {
+        self.encode_scalar(field, item, &mut packed)?;
+      }
+
+      encode_key(field.number, WIRE_LEN, buf);
+      encode_bytes(&packed, buf);
+      return Ok(());
+    }
+
+    for item in items {
+      self.encode_field(field, item, buf)?;
+    }
+
+    Ok(())
+  }
+
+  fn encode_field(
+    &self,
+    field: &FieldDescriptor,
+    value: &Value,
+    buf: &mut Vec<u8>,
+  ) -> Result<(), Error> {
+    match field.kind {
+      Type::Message => {
+        let mut nested = Vec::default();
+        self.encode_message(self.message(&field.type_name)?, value, &mut nested)?;
+        encode_key(field.number, WIRE_LEN, buf);
+        encode_bytes(&nested, buf);
+      }
+      Type::String => {
+        let s = value.as_str().ok_or_else(|| {
+          Error::InvalidValue(field.name.clone(), "expected a string".to_string())
+        })?;
+        encode_key(field.number, WIRE_LEN, buf);
+        encode_bytes(s.as_bytes(), buf);
+      }
+      Type::Bytes => {
+        let s = value
+          .as_str()
+          .ok_or_else(|| Error::InvalidValue(field.name.clone(), "expected base64".to_string()))?;
+        let bytes = base64::decode(s)
+          .map_err(|error| Error::InvalidValue(field.name.clone(), error.to_string()))?;
+        encode_key(field.number, WIRE_LEN, buf);
+        encode_bytes(&bytes, buf);
+      }
+      Type::Group => {
+        return Err(Error::InvalidValue(
+          field.name.clone(),
+          "groups are not supported".to_string(),
+        ))
+      }
+      kind => {
+        encode_key(field.number, wire_type(kind), buf);
+        self.encode_scalar(field, value, buf)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn encode_scalar(
+    &self,
+    field: &FieldDescriptor,
+    value: &Value,
+    buf: &mut Vec<u8>,
+  ) -> Result<(), Error> {
+    let invalid = |reason: &str| Error::InvalidValue(field.name.clone(), reason.to_string());
+
+    match field.kind {
+      Type::Double => buf.put_f64_le(to_f64(value).ok_or_else(|| invalid("expected a number"))?),
+      Type::Float => {
+        buf.put_f32_le(to_f64(value).ok_or_else(|| invalid("expected a number"))? as f32)
+      }
+      Type::Int64 | Type::Int32 => encode_varint(
+        to_i64(value).ok_or_else(|| invalid("expected an integer"))? as u64,
+        buf,
+      ),
+      Type::Uint64 | Type::Uint32 => encode_varint(
+        to_u64(value).ok_or_else(|| invalid("expected an integer"))?,
+        buf,
+      ),
+      Type::Sint64 | Type::Sint32 => {
+        let v = to_i64(value).ok_or_else(|| invalid("expected an integer"))?;
+        encode_varint(((v << 1) ^ (v >> 63)) as u64, buf)
+      }
+      Type::Fixed64 => buf.put_u64_le(to_u64(value).ok_or_else(|| invalid("expected an integer"))?),
+      Type::Sfixed64 => {
+        buf.put_i64_le(to_i64(value).ok_or_else(|| invalid("expected an integer"))?)
+      }
+      Type::Fixed32 => {
+        buf.put_u32_le(to_u64(value).ok_or_else(|| invalid("expected an integer"))? as u32)
+      }
+      Type::Sfixed32 => {
+        buf.put_i32_le(to_i64(value).ok_or_else(|| invalid("expected an integer"))? as i32)
+      }
+      Type::Bool => encode_varint(
+        value
+          .as_bool()
+          .ok_or_else(|| invalid("expected a boolean"))? as u64,
+        buf,
+      ),
+      Type::Enum => {
+        let number = match value {
+          Value::String(name) => self
+            .enums
+            .get(&field.type_name)
+            .and_then(|values| values.iter().find(|(n, _)| n == name))
+            .map(|(_, number)| *number)
+            .ok_or_else(|| invalid("unknown enum value"))?,
+          value => to_i64(value).ok_or_else(|| invalid("expected an enum value"))? as i32,
+        };
+        encode_varint(number as i64 as u64, buf)
+      }
+      _ => return Err(invalid("expected a scalar type")),
+    }
+
+    Ok(())
+  }
+
+  fn map_entry(&self, field: &FieldDescriptor) -> Option<&MessageDescriptor> {
+    if field.kind != Type::Message {
+      return None;
+    }
+
+    self
+      .messages
+      .get(&field.type_name)
+      .filter(|m| m.map_entry && m.fields.len() == 2)
+  }
+
+  fn decode_fields(&self, desc: &MessageDescriptor, mut buf: &[u8]) -> Result<Value, Error> {
+    let malformed = |reason: &str| Error::Malformed(desc.name.clone(), reason.to_string());
+    let mut object = Map::new();
+
+    while buf.has_remaining() {
+      let key = decode_varint(&mut buf).ok_or_else(|| malformed("truncated key"))?;
+      let (number, wire) = ((key >> 3) as u32, key & 0x07);
+
+      let field = match desc.field_by_number(number) {
+        Some(field) => field,
+        None => {
+          skip_field(wire, &mut buf).ok_or_else(|| malformed("truncated unknown field"))?;
+          continue;
+        }
+      };
+
+      if let Some(entry) = self.map_entry(field) {
+        let bytes = take_bytes(&mut buf).ok_or_else(|| malformed("truncated map entry"))?;
+        let decoded = self.decode_fields(entry, bytes)?;
+        let key = match decoded.get(&entry.fields[0].json_name) {
+          Some(Value::String(key)) => key.clone(),
+          Some(key) => key.to_string(),
+          None => String::default(),
+        };
+        let value = decoded
+          .get(&entry.fields[1].json_name)
+          .cloned()
+          .unwrap_or_else(|| self.default_value(&entry.fields[1]));
+
+        if let Value::Object(map) = object
+          .entry(field.json_name.clone())
+          .or_insert_with(|| Value::Object(Map::new()))
+        {
+          map.insert(key, value);
+        }
+        continue;
+      }
+
+      let mut values = Vec::default();
+
+      if field.repeated && wire == WIRE_LEN && is_packable(field.kind) {
+        let mut packed = take_bytes(&mut buf).ok_or_else(|| malformed("truncated packed field"))?;
+        while packed.has_remaining() {
+          values.push(
+            self
+              .decode_scalar(field, wire_type(field.kind), &mut packed)
+              .ok_or_else(|| malformed("truncated packed value"))?,
+          );
+        }
+      } else {
+        values.push(self.decode_value(field, wire, &mut buf)?);
+      }
+
+      if field.repeated {
+        if let Value::Array(array) = object
+          .entry(field.json_name.clone())
+          .or_insert_with(|| Value::Array(Vec::default()))
+        {
+          array.extend(values);
+        }
+      } else if let Some(value) = values.pop() {
+        object.insert(field.json_name.clone(), value);
+      }
+    }
+
+    let value = Value::Object(object);
+
+    match desc.name.as_str() {
+      "google.protobuf.Timestamp" => Ok(timestamp_from_json(&value)),
+      "google.protobuf.Duration" => Ok(duration_from_json(&value)),
+      _ => Ok(value),
+    }
+  }
+
+  fn decode_value(
+    &self,
+    field: &FieldDescriptor,
+    wire: u64,
+    buf: &mut &[u8],
+  ) -> Result<Value, Error> {
+    let malformed = |reason: &str| Error::Malformed(field.name.clone(), reason.to_string());
+
+    match field.kind {
+      Type::Message => {
+        let bytes = take_bytes(buf).ok_or_else(|| malformed("truncated message"))?;
+        self.decode_fields(self.message(&field.type_name)?, bytes)
+      }
+      Type::String => {
+        let bytes = take_bytes(buf).ok_or_else(|| malformed("truncated string"))?;
+        String::from_utf8(bytes.to_vec())
+          .map(Value::String)
+          .map_err(|_| malformed("invalid utf-8"))
+      }
+      Type::Bytes => {
+        let bytes = take_bytes(buf).ok_or_else(|| malformed("truncated bytes"))?;
+        Ok(Value::String(base64::encode(bytes)))
+      }
+      _ => self
+        .decode_scalar(field, wire, buf)
+        .ok_or_else(|| malformed("truncated scalar")),
+    }
+  }
+
+  fn decode_scalar(&self, field: &FieldDescriptor, wire: u64, buf: &mut &[u8]) -> Option<Value> {
+    let value = match (field.kind, wire) {
+      (Type::Double, WIRE_FIXED64) if buf.remaining() >= 8 => float(buf.get_f64_le()),
+      (Type::Float, WIRE_FIXED32) if buf.remaining() >= 4 => float(buf.get_f32_le() as f64),
+      (Type::Fixed64, WIRE_FIXED64) if buf.remaining() >= 8 => {
+        Value::String(buf.get_u64_le().to_string())
+      }
+      (Type::Sfixed64, WIRE_FIXED64) if buf.remaining() >= 8 => {
+        Value::String(buf.get_i64_le().to_string())
+      }
+      (Type::Fixed32, WIRE_FIXED32) if buf.remaining() >= 4 => Value::from(buf.get_u32_le()),
+      (Type::Sfixed32, WIRE_FIXED32) if buf.remaining() >= 4 => Value::from(buf.get_i32_le()),
+      (kind, WIRE_VARINT) => {
+        let v = decode_varint(buf)?;
+        match kind {
+          Type::Int64 => Value::String((v as i64).to_string()),
+          Type::Uint64 => Value::String(v.to_string()),
+          Type::Int32 => Value::from(v as i32),
+          Type::Uint32 => Value::from(v as u32),
+          Type::Sint64 => Value::String((((v >> 1) as i64) ^ -((v & 1) as i64)).to_string()),
+          Type::Sint32 => Value::from((((v >> 1) as i64) ^ -((v & 1) as i64)) as i32),
+          Type::Bool => Value::Bool(v != 0),
+          Type::Enum => self
+            .enums
+            .get(&field.type_name)
+            .and_then(|values| values.iter().find(|(_, n)| *n == v as i32))
+            .map(|(name, _)| Value::String(name.clone()))
+            .unwrap_or_else(|| Value::from(v as i32)),
+          _ => return None,
+        }
+      }
+      _ => return None,
+    };
+
+    Some(value)
+  }
+
+  fn default_value(&self, field: &FieldDescriptor) -> Value {
+    match field.kind {
+      Type::Message => Value::Object(Map::new()),
+      Type::String | Type::Bytes => Value::String(String::default()),
+      Type::Bool => Value::Bool(false),
+      Type::Int64 | Type::Uint64 | Type::Sint64 | Type::Fixed64 | Type::Sfixed64 => {
+        Value::String("0".to_string())
+      }
+      Type::Enum => self
+        .enums
+        .get(&field.type_name)
+        .and_then(|values| values.iter().find(|(_, n)| *n == 0))
+        .map(|(name, _)| Value::String(name.clone()))
+        .unwrap_or_else(|| Value::from(0)),
+      _ => Value::from(0),
+    }
+  }
+}
+
+/// Calls arbitrary methods of a service with JSON requests and responses.
+#[derive(Clone, Debug)]
+pub struct DynamicClient {
+  pool: Arc<DescriptorPool>,
+  grpc: tonic::client::Grpc<Channel>,
+}
+
+impl DynamicClient {
+  pub fn new(channel: Channel, pool: Arc<DescriptorPool>) -> Self {
+    Self {
+      pool,
+      grpc: tonic::client::Grpc::new(channel),
+    }
+  }
+
+  pub fn pool(&self) -> &DescriptorPool {
+    &self.pool
+  }
+
+  /// Calls the unary method `method` (`package.Service/Method`).
+  pub async fn unary(&mut self, method: &str, request: &Value) -> Result<Value, tonic::Status> {
+    let (method, path, request) = self.prepare(method, request)?;
+
+    self.ready().await?;
+    let response: tonic::Response<Vec<u8>> = self
+      .grpc
+      .unary(tonic::Request::new(request), path, RawCodec)
+      .await?;
+
+    Ok(
+      self
+        .pool
+        .decode_message(&method.output_type, &response.into_inner())?,
+    )
+  }
+
+  /// Calls the server streaming method `method` (`package.Service/Method`).
+  #[allow(clippy::result_large_err)]
+  pub async fn server_streaming(
+    &mut self,
+    method: &str,
+    request: &Value,
+  ) -> Result<impl Stream<Item = Result<Value, tonic::Status>>, tonic::Status> {
+    let (method, path, request) = self.prepare(method, request)?;
+
+    self.ready().await?;
+    let response: tonic::Response<tonic::Streaming<Vec<u8>>> = self
+      .grpc
+      .server_streaming(tonic::Request::new(request), path, RawCodec)
+      .await?;
+
+    let pool = self.pool.clone();
+    let output_type = method.output_type;
+
+    Ok(
+      response.into_inner().map(move |message| {
+        message.and_then(|bytes| Ok(pool.decode_message(&output_type, &bytes)?))
+      }),
+    )
+  }
+
+  fn prepare(
+    &self,
+    method: &str,
+    request: &Value,
+  ) -> Result<(MethodDescriptor, PathAndQuery, Vec<u8>), Error> {
+    let method = self.pool.method(method)?.clone();
+    let path = PathAndQuery::try_from(method.path.clone())
+      .map_err(|_| Error::UnknownMethod(method.path.clone()))?;
+    let request = self.pool.encode(&method.input_type, request)?;
+
+    Ok((method, path, request))
+  }
+
+  async fn ready(&mut self) -> Result<(), tonic::Status> {
+    self
+      .grpc
+      .ready()
+      .await
+      .map_err(|error| tonic::Status::unavailable(format!("Service was not ready: {}", error)))
+  }
+}
+
+/// Passes already encoded messages through tonic untouched.
+#[derive(Clone, Copy, Debug, Default)]
+struct RawCodec;
+
+impl tonic::codec::Codec for RawCodec {
+  type Encode = Vec<u8>;
+  type Decode = Vec<u8>;
+  type Encoder = RawCodec;
+  type Decoder = RawCodec;
+
+  fn encoder(&mut self) -> Self::Encoder {
+    RawCodec
+  }
+
+  fn decoder(&mut self) -> Self::Decoder {
+    RawCodec
+  }
+}
+
+impl tonic::codec::Encoder for RawCodec {
+  type Item = Vec<u8>;
+  type Error = tonic::Status;
+
+  fn encode(&mut self, item: Self::Item, dst: &mut EncodeBuf<'_>) -> Result<(), Self::Error> {
+    dst.put_slice(&item);
+    Ok(())
+  }
+}
+
+impl tonic::codec::Decoder for RawCodec {
+  type Item = Vec<u8>;
+  type Error = tonic::Status;
+
+  fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Self::Error> {
+    Ok(Some(src.copy_to_bytes(src.remaining()).to_vec()))
+  }
+}
+
+fn qualify(scope: &str, name: &str) -> String {
+  if scope.is_empty() {
+    name.to_string()
+  } else {
+    format!("{}.{}", scope, name)
+  }
+}
+
+fn is_packable(kind: Type) -> bool {
+  !matches!(
+    kind,
+    Type::String | Type::Bytes | Type::Message | Type::Group
+  )
+}
+
+fn wire_type(kind: Type) -> u64 {
+  match kind {
+    Type::Double | Type::Fixed64 | Type::Sfixed64 => WIRE_FIXED64,
+    Type::Float | Type::Fixed32 | Type::Sfixed32 => WIRE_FIXED32,
+    Type::String | Type::Bytes | Type::Message => WIRE_LEN,
+    Type::Group => WIRE_START_GROUP,
+    _ => WIRE_VARINT,
+  }
+}
+
+fn encode_key(number: u32, wire: u64, buf: &mut Vec<u8>) {
+  encode_varint(((number as u64) << 3) | wire, buf);
+}
+
+fn encode_varint(mut value: u64, buf: &mut Vec<u8>) {
+  while value >= 0x80 {
+    buf.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  buf.push(value as u8);
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+  encode_varint(bytes.len() as u64, buf);
+  buf.extend_from_slice(bytes);
+}
+
+fn decode_varint(buf: &mut &[u8]) -> Option<u64> {
+  let mut value = 0u64;
+
+  for shift in (0..64).step_by(7) {
+    if !buf.has_remaining() {
+      return None;
+    }
+
+    let byte = buf.get_u8();
+    value |= ((byte & 0x7F) as u64) << shift;
+
+    if byte < 0x80 {
+      return Some(value);
+    }
+  }
+
+  None
+}
+
+fn take_bytes<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+  let len = decode_varint(buf)? as usize;
+
+  if buf.len() < len {
+    return None;
+  }
+
+  let (bytes, rest) = buf.split_at(len);
+  *buf = rest;
+  Some(bytes)
+}
+
+fn skip_field(wire: u64, buf: &mut &[u8]) -> Option<()> {
+  let len = match wire {
+    WIRE_VARINT => return decode_varint(buf).map(|_| ()),
+    WIRE_FIXED64 => 8,
+    WIRE_LEN => return take_bytes(buf).map(|_| ()),
+    WIRE_FIXED32 => 4,
+    WIRE_START_GROUP | WIRE_END_GROUP => return None,
+    _ => return None,
+  };
+
+  if buf.len() < len {
+    return None;
+  }
+
+  buf.advance(len);
+  Some(())
+}
+
+fn to_i64(value: &Value) -> Option<i64> {
+  match value {
+    Value::Number(n) => n
+      .as_i64()
+      .or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i64)),
+    Value::String(s) => s.parse().ok(),
+    _ => None,
+  }
+}
+
+fn to_u64(value: &Value) -> Option<u64> {
+  match value {
+    Value::Number(n) => n.as_u64(),
+    Value::String(s) => s.parse().ok(),
+    _ => None,
+  }
+}
+
+fn to_f64(value: &Value) -> Option<f64> {
+  match value {
+    Value::Number(n) => n.as_f64(),
+    Value::String(s) => match s.as_str() {
+      "NaN" => Some(f64::NAN),
+      "Infinity" => Some(f64::INFINITY),
+      "-Infinity" => Some(f64::NEG_INFINITY),
+      s => s.parse().ok(),
+    },
+    _ => None,
+  }
+}
+
+fn float(value: f64) -> Value {
+  Number::from_f64(value)
+    .map(Value::Number)
+    .unwrap_or_else(|| {
+      let s = if value.is_nan() {
+        "NaN"
+      } else if value > 0.0 {
+        "Infinity"
+      } else {
+        "-Infinity"
+      };
+      Value::String(s.to_string())
+    })
+}
+
+fn seconds_and_nanos(value: &Value) -> (i64, i64) {
+  let get = |name: &str| value.get(name).and_then(to_i64).unwrap_or_default();
+  (get("seconds"), get("nanos"))
+}
+
+fn timestamp_to_json(value: &Value) -> Result<Value, Error> {
+  let ts = value
+    .as_str()
+    .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+    .ok_or_else(|| {
+      Error::InvalidValue(
+        "google.protobuf.Timestamp".to_string(),
+        "expected an RFC 3339 string".to_string(),
+      )
+    })?;
+
+  Ok(serde_json::json!({
+    "seconds": ts.timestamp(),
+    "nanos": ts.timestamp_subsec_nanos(),
+  }))
+}
+
+fn timestamp_from_json(value: &Value) -> Value {
+  let (seconds, nanos) = seconds_and_nanos(value);
+
+  chrono::DateTime::from_timestamp(seconds, nanos as u32)
+    .map(|ts| Value::String(ts.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)))
+    .unwrap_or_else(|| value.clone())
+}
+
+fn duration_to_json(value: &Value) -> Result<Value, Error> {
+  let invalid = || {
+    Error::InvalidValue(
+      "google.protobuf.Duration".to_string(),
+      "expected a duration like `1.5s`".to_string(),
+    )
+  };
+
+  let s = value
+    .as_str()
+    .and_then(|s| s.strip_suffix('s'))
+    .ok_or_else(invalid)?;
+  let negative = s.starts_with('-');
+  let (seconds, fraction) = s.split_once('.').unwrap_or((s, ""));
+
+  // Durations have nanosecond precision, so at most 9 fractional digits.
+  if fraction.len() > 9 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+    return Err(invalid());
+  }
+
+  let seconds: i64 = seconds.parse().map_err(|_| invalid())?;
+  let mut nanos: i64 = if fraction.is_empty() {
+    0
+  } else {
+    format!("{:0<9}", fraction).parse().map_err(|_| invalid())?
+  };
+
+  if negative {
+    nanos = -nanos;
+  }
+
+  Ok(serde_json::json!({ "seconds": seconds, "nanos": nanos }))
+}
+
+fn duration_from_json(value: &Value) -> Value {
+  let (seconds, nanos) = seconds_and_nanos(value);
+  let sign = if seconds < 0 || nanos < 0 { "-" } else { "" };
+
+  if nanos == 0 {
+    Value::String(format!("{}{}s", sign, seconds.abs()))
+  } else {
+    Value::String(format!("{}{}.{:09}s", sign, seconds.abs(), nanos.abs()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::proto::longrunning::Operation;
+  use crate::proto::prelude::Timestamp;
+
+  use super::*;
+
+  #[test]
+  fn should_round_trip_generated_message() {
+    let pool = DescriptorPool::rappel().unwrap();
+    let operation = Operation {
+      operation_id: "42".to_string(),
+      metadata: HashMap::from([("queue".to_string(), "workspaces".to_string())]),
+      done: true,
+      creation_ts: Some(Timestamp {
+        seconds: 1_656_000_000,
+        nanos: 500_000_000,
+      }),
+      ..Default::default()
+    };
+
+    let value = pool
+      .decode_message("longrunning.Operation", &operation.encode_to_vec())
+      .unwrap();
+
+    assert_eq!(
+      value,
+      json!({
+        "operationId": "42",
+        "metadata": { "queue": "workspaces" },
+        "done": true,
+        "creationTs": "2022-06-23T16:00:00.500Z",
+      })
+    );
+
+    let bytes = pool.encode("longrunning.Operation", &value).unwrap();
+    assert_eq!(Operation::decode(bytes.as_slice()).unwrap(), operation);
+  }
+
+  #[test]
+  fn should_encode_enums_and_repeated_fields() {
+    let pool = DescriptorPool::rappel().unwrap();
+    let value = json!({
+      "queues": ["a", "b"],
+      "event_types": ["OPERATION_EVENT_TYPE_STARTED", 5],
+    });
+
+    let bytes = pool
+      .encode("longrunning.StreamOperationsRequest", &value)
+      .unwrap();
+    let decoded = pool
+      .decode_message("longrunning.StreamOperationsRequest", &bytes)
+      .unwrap();
+
+    assert_eq!(
+      decoded,
+      json!({
+        "queues": ["a", "b"],
+        "eventTypes": ["OPERATION_EVENT_TYPE_STARTED", "OPERATION_EVENT_TYPE_COMPLETED"],
+      })
+    );
+  }
+
+  #[test]
+  fn should_resolve_methods_and_reject_unknown_fields() {
+    let pool = DescriptorPool::rappel().unwrap();
+
+    let method = pool
+      .method("longrunning.Operations/StreamOperations")
+      .unwrap();
+    assert_eq!(method.input_type, "longrunning.StreamOperationsRequest");
+    assert!(method.server_streaming);
+
+    assert!(pool.method("longrunning.Operations/Missing").is_err());
+    assert!(pool
+      .encode("longrunning.GetOperationRequest", &json!({ "id": "1" }))
+      .is_err());
+  }
+
+  #[test]
+  fn duration_to_json_should_reject_malformed_fractions() {
+    assert_eq!(
+      duration_to_json(&json!("1.5s")).unwrap(),
+      json!({ "seconds": 1, "nanos": 500_000_000 })
+    );
+    assert_eq!(
+      duration_to_json(&json!("-0.000000001s")).unwrap(),
+      json!({ "seconds": 0, "nanos": -1 })
+    );
+
+    for duration in ["1.5és", "1.é5s", "1.-5s", "1.1234567890s", "1.5"] {
+      assert!(
+        matches!(
+          duration_to_json(&json!(duration)),
+          Err(Error::InvalidValue(..))
+        ),
+        "{}",
+        duration
+      );
+    }
+  }
+}
@@ -0,0 +1,265 @@
+//! Rich gRPC errors: `tonic::Status` carrying `google.rpc` detail messages.
+//!
+//! Services attach machine-readable causes with [`StatusBuilder`] or the shortcuts below, and
+//! clients read them back with [`StatusExt`] instead of parsing the message.
+//!
+//! ```rust,ignore
+//! // Server
+//! return Err(status::precondition_failed("STATE", &workspace_id, "The workspace is stopped"));
+//!
+//! // Client
+//! if let Err(status) = client.start_ide(request).await {
+//!   if let Some(delay) = status.retry_delay() {
+//!     tokio::time::sleep(delay).await;
+//!   }
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use prost::Message;
+
+use crate::proto::google::protobuf::Any;
+use crate::proto::google::rpc::bad_request;
+use crate::proto::google::rpc::precondition_failure;
+use crate::proto::google::rpc::quota_failure;
+use crate::proto::google::rpc::BadRequest;
+use crate::proto::google::rpc::ErrorInfo;
+use crate::proto::google::rpc::PreconditionFailure;
+use crate::proto::google::rpc::QuotaFailure;
+use crate::proto::google::rpc::RetryInfo;
+use crate::proto::google::rpc::Status;
+
+/// A `google.rpc` message that can be attached to a status.
+pub trait Detail: Message + Default {
+  const TYPE_URL: &'static str;
+}
+
+impl Detail for ErrorInfo {
+  const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.ErrorInfo";
+}
+
+impl Detail for RetryInfo {
+  const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.RetryInfo";
+}
+
+impl Detail for QuotaFailure {
+  const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.QuotaFailure";
+}
+
+impl Detail for PreconditionFailure {
+  const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.PreconditionFailure";
+}
+
+impl Detail for BadRequest {
+  const TYPE_URL: &'static str = "type.googleapis.com/google.rpc.BadRequest";
+}
+
+/// Builds a `tonic::Status` whose binary details hold a `google.rpc.Status` with the attached
+/// messages.
+#[derive(Clone, Debug)]
+pub struct StatusBuilder {
+  code: tonic::Code,
+  message: String,
+  details: Vec<Any>,
+}
+
+impl StatusBuilder {
+  pub fn new(code: tonic::Code, message: impl Into<String>) -> Self {
+    Self {
+      code,
+      message: message.into(),
+      details: Vec::default(),
+    }
+  }
+
+  /// Attaches any detail message.
+  pub fn with_detail<D: Detail>(mut self, detail: &D) -> Self {
+    self.details.push(Any {
+      type_url: D::TYPE_URL.to_string(),
+      value: detail.encode_to_vec(),
+    });
+    self
+  }
+
+  /// Attaches an `ErrorInfo` identifying the cause by a `reason` unique within `domain`.
+  pub fn with_error_info(
+    self,
+    reason: &str,
+    domain: &str,
+    metadata: HashMap<String, String>,
+  ) -> Self {
+    self.with_detail(&ErrorInfo {
+      reason: reason.to_string(),
+      domain: domain.to_string(),
+      metadata,
+    })
+  }
+
+  /// Attaches a `RetryInfo` telling the client how long to wait before retrying.
+  pub fn with_retry_delay(self, delay: Duration) -> Self {
+    self.with_detail(&RetryInfo {
+      retry_delay: Some(crate::proto::google::protobuf::Duration {
+        seconds: delay.as_secs() as i64,
+        nanos: delay.subsec_nanos() as i32,
+      }),
+    })
+  }
+
+  /// Attaches a `QuotaFailure` with a single violation.
+  pub fn with_quota_violation(self, subject: &str, description: &str) -> Self {
+    self.with_detail(&QuotaFailure {
+      violations: vec![quota_failure::Violation {
+        subject: subject.to_string(),
+        description: description.to_string(),
+      }],
+    })
+  }
+
+  /// Attaches a `PreconditionFailure` with a single violation.
+  pub fn with_precondition_violation(self, kind: &str, subject: &str, description: &str) -> Self {
+    self.with_detail(&PreconditionFailure {
+      violations: vec![precondition_failure::Violation {
+        r#type: kind.to_string(),
+        subject: subject.to_string(),
+        description: description.to_string(),
+      }],
+    })
+  }
+
+  /// Attaches a `BadRequest` with a single field violation.
+  pub fn with_field_violation(self, field: &str, description: &str) -> Self {
+    self.with_detail(&BadRequest {
+      field_violations: vec![bad_request::FieldViolation {
+        field: field.to_string(),
+        description: description.to_string(),
+      }],
+    })
+  }
+
+  pub fn build(self) -> tonic::Status {
+    let status = Status {
+      code: self.code as i32,
+      message: self.message.clone(),
+      details: self.details,
+    };
+
+    tonic::Status::with_details(self.code, self.message, status.encode_to_vec().into())
+  }
+}
+
+/// Builds an `INVALID_ARGUMENT` status with a `BadRequest` detail naming the field.
+pub fn invalid_argument(field: &str, description: &str) -> tonic::Status {
+  StatusBuilder::new(tonic::Code::InvalidArgument, description)
+    .with_field_violation(field, description)
+    .build()
+}
+
+/// Builds a `FAILED_PRECONDITION` status with a `PreconditionFailure` detail.
+pub fn precondition_failed(kind: &str, subject: &str, description: &str) -> tonic::Status {
+  StatusBuilder::new(tonic::Code::FailedPrecondition, description)
+    .with_precondition_violation(kind, subject, description)
+    .build()
+}
+
+/// Builds a `RESOURCE_EXHAUSTED` status with a `QuotaFailure` detail.
+pub fn quota_exceeded(subject: &str, description: &str) -> tonic::Status {
+  StatusBuilder::new(tonic::Code::ResourceExhausted, description)
+    .with_quota_violation(subject, description)
+    .build()
+}
+
+/// Builds an `UNAVAILABLE` status with a `RetryInfo` detail.
+pub fn unavailable(message: &str, retry_delay: Duration) -> tonic::Status {
+  StatusBuilder::new(tonic::Code::Unavailable, message)
+    .with_retry_delay(retry_delay)
+    .build()
+}
+
+/// Reads the `google.rpc` details of a status, e.g. on the client side of a call.
+pub trait StatusExt {
+  /// Returns every detail of type `D`. Details of other types, and statuses without or with
+  /// malformed details, are skipped.
+  fn details_of<D: Detail>(&self) -> Vec<D>;
+
+  /// Returns the first detail of type `D`.
+  fn detail<D: Detail>(&self) -> Option<D> {
+    self.details_of().into_iter().next()
+  }
+
+  fn error_info(&self) -> Option<ErrorInfo> {
+    self.detail()
+  }
+
+  fn quota_failure(&self) -> Option<QuotaFailure> {
+    self.detail()
+  }
+
+  fn precondition_failure(&self) -> Option<PreconditionFailure> {
+    self.detail()
+  }
+
+  fn bad_request(&self) -> Option<BadRequest> {
+    self.detail()
+  }
+
+  /// Returns how long the server asked the client to wait before retrying.
+  fn retry_delay(&self) -> Option<Duration> {
+    let delay = self.detail::<RetryInfo>()?.retry_delay?;
+    Some(Duration::new(
+      u64::try_from(delay.seconds).ok()?,
+      u32::try_from(delay.nanos).ok()?,
+    ))
+  }
+}
+
+impl StatusExt for tonic::Status {
+  fn details_of<D: Detail>(&self) -> Vec<D> {
+    let status = match Status::decode(self.details()) {
+      Ok(status) => status,
+      Err(_) => return Vec::default(),
+    };
+
+    status
+      .details
+      .iter()
+      .filter(|any| any.type_url == D::TYPE_URL)
+      .filter_map(|any| D::decode(any.value.as_slice()).ok())
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn status_ext_should_read_attached_details() {
+    let status = StatusBuilder::new(tonic::Code::Unavailable, "Cluster is draining")
+      .with_error_info(
+        "CLUSTER_DRAINING",
+        "rappel.dev",
+        HashMap::from([("cluster".to_string(), "eu-1".to_string())]),
+      )
+      .with_retry_delay(Duration::from_millis(1500))
+      .build();
+
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+    assert_eq!(status.message(), "Cluster is draining");
+
+    let info = status.error_info().unwrap();
+    assert_eq!(info.reason, "CLUSTER_DRAINING");
+    assert_eq!(info.metadata["cluster"], "eu-1");
+    assert_eq!(status.retry_delay(), Some(Duration::from_millis(1500)));
+    assert_eq!(status.quota_failure(), None);
+
+    let status = precondition_failed("STATE", "workspace:1", "The workspace is stopped");
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    assert_eq!(
+      status.precondition_failure().unwrap().violations[0].subject,
+      "workspace:1"
+    );
+    assert!(tonic::Status::internal("boom").error_info().is_none());
+  }
+}
@@ -0,0 +1,102 @@
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use uuid::Uuid;
+
+use super::UidGenerator;
+use super::UuidGenerator;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Failed to generate an id: {0}")]
+pub struct IdError(pub String);
+
+/// Generates the ids of resources, e.g. the operations of a
+/// [`crate::longrunning::redis::RedisQueue`]. Unlike [`UidGenerator`], it is shared by concurrent
+/// callers, so generators with state are wrapped in a [`Mutex`].
+pub trait IdGenerator: Debug + Send + Sync {
+  fn generate(&self) -> Result<String, IdError>;
+}
+
+/// An id generator shared by the components of a process.
+pub type SharedIdGenerator = Arc<dyn IdGenerator>;
+
+/// UUID V7 generator. The ids sort by creation time, to the millisecond.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidV7Generator;
+
+impl IdGenerator for UuidV7Generator {
+  fn generate(&self) -> Result<String, IdError> {
+    Ok(Uuid::now_v7().to_string())
+  }
+}
+
+impl IdGenerator for UuidGenerator {
+  fn generate(&self) -> Result<String, IdError> {
+    Ok(Uuid::new_v4().to_string())
+  }
+}
+
+/// Shares a [`UidGenerator`], e.g. a [`super::Snowflake`].
+impl<G> IdGenerator for Mutex<G>
+where
+  G: UidGenerator + Debug + Send,
+  G::Item: Display,
+  G::Error: Display,
+{
+  fn generate(&self) -> Result<String, IdError> {
+    let mut generator = self.lock().unwrap();
+    generator
+      .next()
+      .map(|id| id.to_string())
+      .map_err(|error| IdError(error.to_string()))
+  }
+}
+
+/// Generates `{prefix}1`, `{prefix}2`... for tests expecting known ids.
+#[derive(Debug, Default)]
+pub struct SequentialIdGenerator {
+  prefix: String,
+  last: AtomicU64,
+}
+
+impl SequentialIdGenerator {
+  pub fn new(prefix: &str) -> Self {
+    Self {
+      prefix: prefix.to_string(),
+      last: AtomicU64::default(),
+    }
+  }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+  fn generate(&self) -> Result<String, IdError> {
+    let id = self.last.fetch_add(1, Ordering::Relaxed) + 1;
+    Ok(format!("{}{}", self.prefix, id))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::id::Snowflake;
+
+  #[test]
+  fn generators_should_produce_distinct_ids() {
+    let v7 = UuidV7Generator;
+    let first = v7.generate().unwrap();
+    let second = v7.generate().unwrap();
+    assert_ne!(first, second);
+    assert_eq!(Uuid::parse_str(&first).unwrap().get_version_num(), 7);
+
+    let snowflake = Mutex::new(Snowflake::new(1));
+    assert_ne!(snowflake.generate().unwrap(), snowflake.generate().unwrap());
+
+    let sequential = SequentialIdGenerator::new("op-");
+    assert_eq!(sequential.generate().unwrap(), "op-1");
+    assert_eq!(sequential.generate().unwrap(), "op-2");
+  }
+}
@@ -1,6 +1,10 @@
+mod generator;
+mod name;
 mod snowflake;
 mod uuid;
 
+pub use super::id::generator::*;
+pub use super::id::name::*;
 pub use super::id::snowflake::*;
 pub use super::id::uuid::*;
 
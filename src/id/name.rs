@@ -0,0 +1,68 @@
+/// Longest accepted resource name, the length of a DNS label.
+pub const MAX_NAME_LENGTH: usize = 63;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NameError {
+  #[error("Name must not be empty")]
+  Empty,
+
+  #[error("Name must be at most {MAX_NAME_LENGTH} characters long")]
+  TooLong,
+
+  #[error("Name must start with a lowercase letter")]
+  InvalidStart,
+
+  #[error("Name must not end with a hyphen")]
+  InvalidEnd,
+
+  #[error(
+    "Name contains invalid character {0:?}, only lowercase letters, digits and hyphens are allowed"
+  )]
+  InvalidCharacter(char),
+}
+
+/// Checks that `name` is a valid resource name: a lowercase DNS label starting with a letter, so
+/// it can be used in hostnames and Kubernetes object names.
+pub fn validate_name(name: &str) -> Result<(), NameError> {
+  let first = name.chars().next().ok_or(NameError::Empty)?;
+
+  if name.len() > MAX_NAME_LENGTH {
+    return Err(NameError::TooLong);
+  }
+
+  if !first.is_ascii_lowercase() {
+    return Err(NameError::InvalidStart);
+  }
+
+  if let Some(c) = name
+    .chars()
+    .find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-'))
+  {
+    return Err(NameError::InvalidCharacter(c));
+  }
+
+  if name.ends_with('-') {
+    return Err(NameError::InvalidEnd);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn validate_name_should_accept_dns_labels_only() {
+    assert_eq!(validate_name("my-workspace-1"), Ok(()));
+    assert_eq!(validate_name(""), Err(NameError::Empty));
+    assert_eq!(validate_name("1st"), Err(NameError::InvalidStart));
+    assert_eq!(validate_name("trailing-"), Err(NameError::InvalidEnd));
+    assert_eq!(validate_name("My workspace"), Err(NameError::InvalidStart));
+    assert_eq!(
+      validate_name("my_workspace"),
+      Err(NameError::InvalidCharacter('_'))
+    );
+    assert_eq!(validate_name(&"a".repeat(64)), Err(NameError::TooLong));
+  }
+}
@@ -3,7 +3,7 @@ use core::marker::PhantomData;
 use uuid::Uuid;
 
 /// UUID V4 generator
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct UuidGenerator {
   _guard: PhantomData<i32>,
 }
@@ -14,14 +14,6 @@ impl UuidGenerator {
   }
 }
 
-impl Default for UuidGenerator {
-  fn default() -> Self {
-    Self {
-      _guard: PhantomData::default(),
-    }
-  }
-}
-
 impl UidGenerator for UuidGenerator {
   type Item = Uuid;
   type Error = ();
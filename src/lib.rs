@@ -1,7 +1,18 @@
 extern crate core;
 
+#[cfg(feature = "proto")]
+pub mod cluster;
+
 pub mod codec;
 
+#[cfg(feature = "redis")]
+pub mod flags;
+
+pub mod fuzz;
+
+#[cfg(feature = "proto")]
+pub mod grpc;
+
 pub mod id;
 
 #[cfg(feature = "proto")]
@@ -10,7 +21,12 @@ pub mod proto;
 #[cfg(feature = "longrunning")]
 pub mod longrunning;
 
+#[cfg(all(feature = "proto", feature = "redis"))]
+pub mod quota;
+
 #[cfg(feature = "redis")]
 pub mod redis;
 
 pub mod service;
+
+pub mod util;
@@ -0,0 +1,1358 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures::Stream;
+use prost::Message;
+use redis::AsyncCommands;
+use redis::FromRedisValue;
+use serde_json::Value;
+use tracing_futures::Instrument;
+
+use crate::codec::json::JsonCodec;
+use crate::grpc::dynamic::DescriptorPool;
+use crate::proto::google::rpc::Code;
+use crate::proto::google::rpc::Status;
+use crate::proto::longrunning::LatencySummary;
+use crate::proto::longrunning::ListTaskSchemasResponse;
+use crate::proto::longrunning::Operation;
+use crate::proto::longrunning::OperationEvent;
+use crate::proto::longrunning::OperationEventType;
+use crate::proto::longrunning::OperationTree;
+use crate::proto::longrunning::QueueMetrics;
+use crate::proto::longrunning::QueueWindowMetrics;
+use crate::proto::longrunning::StreamOperationsRequest;
+use crate::proto::longrunning::TaskLatency;
+use crate::proto::longrunning::TaskSchema;
+use crate::proto::prelude::ProstTimestamp;
+use crate::redis::Keys;
+use crate::redis::ProtoValue;
+use crate::util::clock;
+use crate::util::clock::SharedClock;
+use crate::util::redis_exec;
+use crate::util::redis_exec::InstrumentedConnection;
+
+use super::index;
+use super::maintenance;
+use super::maintenance::EnqueuePolicy;
+use super::maintenance::Maintenance;
+use super::redis::InvalidMessage;
+use super::redis::RedisEventBus;
+use super::redis::RedisQueue;
+use super::redis::RedisQueueError;
+use super::redis::OPERATION_EVENTS_CHANNEL;
+use super::schema;
+use super::sla;
+use super::store;
+use super::Context;
+use super::EventBus;
+use super::OperationFilter;
+use super::OperationState;
+
+/// Makes the operations deferred during maintenance due now. Returns how many there were.
+///
+/// KEYS: delayed set. ARGV: epoch milliseconds.
+const RELEASE_DEFERRED_SCRIPT: &str = r"
+local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '+inf', '+inf')
+for _, id in ipairs(ids) do
+  redis.call('ZADD', KEYS[1], ARGV[1], id)
+end
+return #ids
+";
+
+/// Moves a completed operation to its tombstone. Returns 1 once deleted, 0 when the operation is
+/// not done and -1 when it does not exist.
+///
+/// KEYS: operation hash, tombstone hash, tombstones set. ARGV: operation id, epoch milliseconds.
+const DELETE_SCRIPT: &str = r"
+local status = redis.call('HGET', KEYS[1], 'status')
+if not status then
+  return -1
+end
+if status ~= 'Succeeded' and status ~= 'Failed' and status ~= 'Cancelled' then
+  return 0
+end
+redis.call('RENAME', KEYS[1], KEYS[2])
+redis.call('HSET', KEYS[2], 'deleted_ts', ARGV[2])
+redis.call('ZADD', KEYS[3], ARGV[2], ARGV[1])
+return 1
+";
+
+/// Moves a tombstone back to its operation hash. Returns 1 once restored, 0 when an operation
+/// with the same id exists and -1 when there is no tombstone.
+///
+/// KEYS: tombstone hash, operation hash, tombstones set. ARGV: operation id.
+const RESTORE_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+  return -1
+end
+if redis.call('EXISTS', KEYS[2]) == 1 then
+  return 0
+end
+redis.call('RENAME', KEYS[1], KEYS[2])
+redis.call('HDEL', KEYS[2], 'deleted_ts')
+redis.call('ZREM', KEYS[3], ARGV[1])
+return 1
+";
+
+/// Time the deleted operations can be restored for by default, see [`RedisAdmin::purge_deleted`].
+pub const DEFAULT_DELETION_GRACE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Key prefixes of the per queue lists that are not queues themselves.
+const SUBLISTS: &[&str] = &[
+  "ack",
+  "invalid",
+  "quarantine",
+  "paused",
+  "delayed",
+  "lease",
+  "interactive",
+];
+
+/// Windows of [`RedisAdmin::queue_metrics`] when none is requested: the last 5 minutes, hour and
+/// day.
+pub const DEFAULT_METRICS_WINDOWS: [Duration; 3] = [
+  Duration::from_secs(5 * 60),
+  Duration::from_secs(60 * 60),
+  Duration::from_secs(24 * 60 * 60),
+];
+
+/// Newest operations of a window read by [`RedisAdmin::queue_metrics`].
+pub const METRICS_SAMPLES: usize = 1000;
+
+/// `count`, `threshold_ms` and `violations` fields of an SLA hash, see [`Keys::sla`].
+type SlaCounters = (Option<u64>, Option<i64>, Option<i64>);
+
+/// Point in time counters of a queue.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct QueueStats {
+  pub name: String,
+  pub pending: i64,
+  /// Pending operations enqueued with an interactive priority, included in `pending`.
+  pub interactive: i64,
+  pub in_flight: i64,
+  /// Operations declined with [`super::Queue::nack`] waiting for their redelivery delay.
+  pub delayed: i64,
+  pub invalid: i64,
+  pub quarantined: i64,
+  pub paused: bool,
+}
+
+/// Operator side view of the queues and operations stored in Redis, regardless of their task
+/// type.
+///
+/// ```rust,no_run
+/// # async fn example() -> Result<(), rappel::longrunning::redis::RedisQueueError> {
+/// use rappel::longrunning::admin::RedisAdmin;
+///
+/// let admin = RedisAdmin::new(redis::Client::open("redis://127.0.0.1/").unwrap());
+///
+/// for queue in admin.queues().await? {
+///   println!("{}: {} pending", queue.name, queue.pending);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RedisAdmin {
+  client: redis::Client,
+  events: RedisEventBus,
+  keys: Keys,
+  clock: SharedClock,
+}
+
+impl RedisAdmin {
+  pub fn new(client: redis::Client) -> Self {
+    Self {
+      events: RedisEventBus::new(client.clone(), OPERATION_EVENTS_CHANNEL),
+      client,
+      keys: Keys::default(),
+      clock: clock::system(),
+    }
+  }
+
+  /// Manages the queues stored under the prefix of `keys`.
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.events = RedisEventBus::new(self.client.clone(), &keys.channel(OPERATION_EVENTS_CHANNEL));
+    self.keys = keys;
+    self
+  }
+
+  /// Tails and publishes the operation events on `events`. Replaces the bus set up by
+  /// [`Self::with_keys`].
+  pub fn with_events(mut self, events: RedisEventBus) -> Self {
+    self.events = events;
+    self
+  }
+
+  /// Reads the time of the deletions, cancellations, pauses, maintenances, events and metrics
+  /// windows from `clock`, the system clock by default.
+  pub fn with_clock(mut self, clock: SharedClock) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  /// Lists every queue that has pending, in-flight, delayed, invalid or quarantined operations,
+  /// or that is paused.
+  pub async fn queues(&self) -> Result<Vec<QueueStats>, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let keys: Vec<String> = {
+      let mut iter = conn
+        .scan_match::<_, String>(self.keys.queue("*"))
+        .instrument(tracing::info_span!("redis-admin-scan"))
+        .await?;
+      let mut keys = Vec::default();
+      while let Some(key) = iter.next_item().await {
+        keys.push(key);
+      }
+      keys
+    };
+
+    let names: BTreeSet<String> = keys
+      .iter()
+      .filter_map(|key| self.keys.strip(key)?.strip_prefix("queue:"))
+      .map(|name| match name.split_once(':') {
+        Some((prefix, queue)) if SUBLISTS.contains(&prefix) => queue.to_string(),
+        _ => name.to_string(),
+      })
+      .collect();
+
+    let mut stats = Vec::with_capacity(names.len());
+    for name in names {
+      stats.push(self.stats(&mut conn, name).await?);
+    }
+
+    Ok(stats)
+  }
+
+  /// Returns the counters of `queue`.
+  pub async fn queue(&self, queue: &str) -> Result<QueueStats, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    self.stats(&mut conn, queue.to_string()).await
+  }
+
+  async fn stats(
+    &self,
+    conn: &mut InstrumentedConnection,
+    name: String,
+  ) -> Result<QueueStats, RedisQueueError> {
+    let (background, interactive, in_flight, delayed, invalid, quarantined, paused): (
+      i64,
+      i64,
+      i64,
+      i64,
+      i64,
+      i64,
+      bool,
+    ) = redis::pipe()
+      .llen(self.keys.queue(&name))
+      .llen(self.keys.interactive(&name))
+      .llen(self.keys.ack(&name))
+      .zcard(self.keys.delayed(&name))
+      .llen(self.keys.invalid(&name))
+      .llen(self.keys.quarantine(&name))
+      .exists(self.keys.paused(&name))
+      .query_async(conn)
+      .instrument(tracing::info_span!("redis-admin-stats", queue = %name))
+      .await?;
+
+    Ok(QueueStats {
+      name,
+      pending: background + interactive,
+      interactive,
+      in_flight,
+      delayed,
+      invalid,
+      quarantined,
+      paused,
+    })
+  }
+
+  /// Returns the operation `id`.
+  pub async fn operation(&self, id: &str) -> Result<Operation, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    self.get(&mut conn, id).await
+  }
+
+  /// Returns the operations `ids` in one round trip, `None` for those that do not exist.
+  pub async fn operations(
+    &self,
+    ids: &[String],
+  ) -> Result<Vec<Option<Operation>>, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    store::get_many(&mut conn, &self.keys, ids).await
+  }
+
+  /// Returns the operation `id` with its descendants, up to `max_depth` levels below it or every
+  /// level when 0. Children that no longer exist are left out.
+  pub async fn tree(&self, id: &str, max_depth: u32) -> Result<OperationTree, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let root = self.get(&mut conn, id).await?;
+
+    let mut seen = HashSet::from([id.to_string()]);
+    let mut levels = vec![vec![(id.to_string(), root)]];
+
+    while max_depth == 0 || levels.len() <= max_depth as usize {
+      let mut next = Vec::default();
+
+      for (_, operation) in levels.last().into_iter().flatten() {
+        for child in &operation.child_operation_ids {
+          // Guards against cycles in corrupted data.
+          if !seen.insert(child.clone()) {
+            continue;
+          }
+
+          match self.get(&mut conn, child).await {
+            Ok(operation) => next.push((child.clone(), operation)),
+            Err(RedisQueueError::NotFound(_)) => {}
+            Err(error) => return Err(error),
+          }
+        }
+      }
+
+      if next.is_empty() {
+        break;
+      }
+      levels.push(next);
+    }
+
+    // Builds the subtrees bottom-up, every child level is complete when its parents are built.
+    let mut subtrees: HashMap<String, OperationTree> = HashMap::default();
+    for level in levels.into_iter().rev() {
+      for (id, operation) in level {
+        let children = operation
+          .child_operation_ids
+          .iter()
+          .filter_map(|child| subtrees.remove(child))
+          .collect();
+
+        subtrees.insert(
+          id,
+          OperationTree {
+            operation: Some(operation),
+            children,
+          },
+        );
+      }
+    }
+
+    Ok(subtrees.remove(id).unwrap_or_default())
+  }
+
+  /// Summarizes the latencies recorded by the queues with an [`sla::SlaTracker`] for
+  /// `task_types`, or for every task type when empty.
+  pub async fn latency(&self, task_types: &[String]) -> Result<LatencySummary, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let task_types: BTreeSet<String> = if task_types.is_empty() {
+      let mut iter = conn
+        .scan_match::<_, String>(self.keys.latency("*"))
+        .instrument(tracing::info_span!("redis-admin-scan"))
+        .await?;
+      let mut task_types = BTreeSet::default();
+      while let Some(key) = iter.next_item().await {
+        if let Some(task_type) = self
+          .keys
+          .strip(&key)
+          .and_then(|k| k.strip_prefix("latency:"))
+        {
+          task_types.insert(task_type.to_string());
+        }
+      }
+      task_types
+    } else {
+      task_types.iter().cloned().collect()
+    };
+
+    let mut task_latencies = Vec::with_capacity(task_types.len());
+    for task_type in task_types {
+      let (samples, (count, threshold_ms, violations)): (Vec<u64>, SlaCounters) = redis::pipe()
+        .lrange(self.keys.latency(&task_type), 0, -1)
+        .hget(
+          self.keys.sla(&task_type),
+          &["count", "threshold_ms", "violations"],
+        )
+        .query_async(&mut conn)
+        .instrument(tracing::info_span!("redis-admin-latency", %task_type))
+        .await?;
+
+      task_latencies.push(TaskLatency {
+        sla_ms: threshold_ms.unwrap_or_default(),
+        sla_violations: violations.unwrap_or_default(),
+        ..sla::summarize(&task_type, samples, count.unwrap_or_default())
+      });
+    }
+
+    Ok(LatencySummary { task_latencies })
+  }
+
+  /// Counters of `queue` and metrics of the operations it enqueued within each of `windows`, or
+  /// within [`DEFAULT_METRICS_WINDOWS`] when empty, read from the daily queue indexes, see
+  /// [`index`]. Windows are summarized from their [`METRICS_SAMPLES`] newest operations.
+  pub async fn queue_metrics(
+    &self,
+    queue: &str,
+    windows: &[Duration],
+  ) -> Result<QueueMetrics, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let stats = self.stats(&mut conn, queue.to_string()).await?;
+
+    let windows = match windows {
+      [] => &DEFAULT_METRICS_WINDOWS[..],
+      windows => windows,
+    };
+    let filter = OperationFilter {
+      queues: vec![queue.to_string()],
+      ..Default::default()
+    };
+    let until = self.clock.now();
+
+    let mut metrics = Vec::with_capacity(windows.len());
+    for window in windows {
+      let since = until - chrono::Duration::from_std(*window).unwrap_or_default();
+      let (since_ms, until_ms) = (since.timestamp_millis(), until.timestamp_millis());
+
+      let mut enqueued = 0;
+      let mut ids = Vec::default();
+      for day in index::days(since, until) {
+        let partitions = index::partitions(&self.keys, &filter, day).unwrap_or_default();
+        enqueued += index::count(&mut conn, &partitions, since_ms, until_ms).await?;
+
+        let remaining = METRICS_SAMPLES.saturating_sub(ids.len());
+        if remaining > 0 {
+          ids.extend(
+            index::read(&mut conn, &partitions, since_ms, until_ms, Some(remaining)).await?,
+          );
+        }
+      }
+
+      let operations: Vec<Operation> = store::get_many(&mut conn, &self.keys, &ids)
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+      metrics.push(window_metrics(*window, enqueued, &operations));
+    }
+
+    Ok(QueueMetrics {
+      queue: stats.name,
+      pending: stats.pending,
+      in_flight: stats.in_flight,
+      delayed: stats.delayed,
+      invalid: stats.invalid,
+      quarantined: stats.quarantined,
+      paused: stats.paused,
+      windows: metrics,
+    })
+  }
+
+  /// Publishes the schemas of the task types of a worker, replacing the ones published before for
+  /// the same task types. See [`super::registry::TaskRegistry::register_schema`].
+  pub async fn publish_schemas<'a>(
+    &self,
+    schemas: impl IntoIterator<Item = &'a TaskSchema>,
+  ) -> Result<(), RedisQueueError> {
+    let schemas: Vec<(&str, ProtoValue<TaskSchema>)> = schemas
+      .into_iter()
+      .map(|schema| (schema.task_type.as_str(), ProtoValue(schema.clone())))
+      .collect();
+    if schemas.is_empty() {
+      return Ok(());
+    }
+
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let _: () = conn
+      .hset_multiple(self.keys.schemas(), &schemas)
+      .instrument(tracing::info_span!("redis-admin-publish-schemas"))
+      .await?;
+
+    Ok(())
+  }
+
+  /// Lists the schemas published for `task_types`, or for every task type when empty. Task types
+  /// without a published schema are left out.
+  pub async fn schemas(
+    &self,
+    task_types: &[String],
+  ) -> Result<ListTaskSchemasResponse, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let mut task_schemas: Vec<TaskSchema> = if task_types.is_empty() {
+      let schemas: HashMap<String, ProtoValue<TaskSchema>> = conn
+        .hgetall(self.keys.schemas())
+        .instrument(tracing::info_span!("redis-admin-schemas"))
+        .await?;
+      schemas.into_values().map(|schema| schema.0).collect()
+    } else {
+      let schemas: Vec<Option<ProtoValue<TaskSchema>>> = redis::cmd("HMGET")
+        .arg(self.keys.schemas())
+        .arg(task_types)
+        .query_async(&mut conn)
+        .instrument(tracing::info_span!("redis-admin-schemas"))
+        .await?;
+      schemas
+        .into_iter()
+        .flatten()
+        .map(|schema| schema.0)
+        .collect()
+    };
+    task_schemas.sort_by(|a, b| a.task_type.cmp(&b.task_type));
+
+    Ok(ListTaskSchemasResponse { task_schemas })
+  }
+
+  /// Enqueues a task of type `task_type` to `queue` from its JSON payload, e.g. to run again a
+  /// task that failed. The payload is validated against the published schema of the task type,
+  /// see [`Self::publish_schemas`], and encoded with its content type.
+  pub async fn submit(
+    &self,
+    task_type: &str,
+    queue: &str,
+    json_payload: &str,
+    ctx: &Context,
+  ) -> Result<Operation, RedisQueueError> {
+    let task_schema = self
+      .schemas(&[task_type.to_string()])
+      .await?
+      .task_schemas
+      .pop()
+      .ok_or_else(|| {
+        RedisQueueError::NotFound(format!("No schema published for task type {}", task_type))
+      })?;
+
+    let value: Value = serde_json::from_str(json_payload)
+      .map_err(|error| RedisQueueError::InvalidArgument(format!("Invalid payload: {}", error)))?;
+
+    if !task_schema.payload_json_schema.is_empty() {
+      let payload_schema: Value =
+        serde_json::from_str(&task_schema.payload_json_schema).map_err(|error| {
+          RedisQueueError::Internal(format!("Invalid schema of {}: {}", task_type, error))
+        })?;
+      schema::validate(&payload_schema, &value)
+        .map_err(|violation| RedisQueueError::InvalidArgument(violation.to_string()))?;
+    }
+
+    let payload = match task_schema.content_type.as_str() {
+      crate::codec::json::CONTENT_TYPE => {
+        serde_json::to_vec(&value).map_err(|error| RedisQueueError::Internal(error.to_string()))?
+      }
+      crate::codec::protobuf::CONTENT_TYPE => DescriptorPool::decode(&task_schema.descriptors)
+        .and_then(|pool| pool.encode(&task_schema.payload_message, &value))
+        .map_err(|error| RedisQueueError::InvalidArgument(error.to_string()))?,
+      content_type => {
+        return Err(RedisQueueError::UnsupportedContentType(
+          content_type.to_string(),
+        ))
+      }
+    };
+
+    let id = self
+      .raw_queue(queue)
+      .offer_raw(task_type, &task_schema.content_type, payload, ctx)
+      .await?;
+
+    tracing::info!(message = "Submitted task", operation_id = %id, %task_type, %queue);
+    self.operation(&id).await
+  }
+
+  /// Lists the messages of `queue` that could not be decoded, newest first, see
+  /// [`RedisQueue::invalid`].
+  pub async fn invalid(
+    &self,
+    queue: &str,
+    offset: isize,
+    count: isize,
+  ) -> Result<Vec<InvalidMessage>, RedisQueueError> {
+    self.raw_queue(queue).invalid(offset, count).await
+  }
+
+  /// Puts the invalid message `id` back on its queue, e.g. after a decoder fix was deployed, see
+  /// [`RedisQueue::replay_invalid`].
+  pub async fn replay_invalid(&self, id: &str) -> Result<Operation, RedisQueueError> {
+    let operation = self.operation(id).await?;
+    self.raw_queue(&operation.queue).replay_invalid(id).await?;
+    self.operation(id).await
+  }
+
+  /// Queue of whatever task type, for the operations that do not decode their tasks.
+  fn raw_queue(&self, queue: &str) -> RedisQueue<(), JsonCodec<Value, Value>> {
+    RedisQueue::new(self.client.clone(), queue.to_string(), JsonCodec::new())
+      .with_keys(self.keys.clone())
+      .with_events(self.events.clone())
+  }
+
+  async fn get(
+    &self,
+    conn: &mut InstrumentedConnection,
+    id: &str,
+  ) -> Result<Operation, RedisQueueError> {
+    let (fields, children, annotations): (redis::Value, Vec<String>, HashMap<String, String>) =
+      redis::pipe()
+        .hgetall(self.keys.operation(id))
+        .lrange(self.keys.children(id), 0, -1)
+        .hgetall(self.keys.annotations(id))
+        .query_async(conn)
+        .instrument(tracing::info_span!("redis-admin-hgetall", operation_id = %id))
+        .await?;
+
+    if fields == redis::Value::Bulk(Vec::default()) {
+      return Err(RedisQueueError::NotFound(format!(
+        "No operation with operation_id = {}",
+        id
+      )));
+    }
+
+    let mut operation = Operation::from_redis_value(&fields)?;
+    operation.child_operation_ids = children;
+    operation.annotations = annotations;
+
+    Ok(operation)
+  }
+
+  /// Sets the annotation `key` of the operation `id` to `value`, e.g. a link to an incident, or
+  /// removes it when `value` is empty. Returns the annotated operation.
+  pub async fn annotate(
+    &self,
+    id: &str,
+    key: &str,
+    value: &str,
+  ) -> Result<Operation, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    store::annotate(&mut conn, &self.keys, id, key, value).await?;
+
+    tracing::info!(message = "Annotated operation", operation_id = %id, %key);
+    self.get(&mut conn, id).await
+  }
+
+  /// Deletes the completed operation `id`. The operation is kept aside until purged by
+  /// [`Self::purge_deleted`], and can be restored meanwhile with [`Self::restore`].
+  pub async fn delete(&self, id: &str) -> Result<(), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let deleted: i64 = redis::Script::new(DELETE_SCRIPT)
+      .key(self.keys.operation(id))
+      .key(self.keys.tombstone(id))
+      .key(self.keys.tombstones())
+      .arg(id)
+      .arg(self.clock.now().timestamp_millis())
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-admin-delete", operation_id = %id))
+      .await?;
+
+    match deleted {
+      1 => {
+        tracing::info!(message = "Deleted operation", operation_id = %id);
+        Ok(())
+      }
+      0 => Err(RedisQueueError::Conflict(format!(
+        "Operation {} is not done",
+        id
+      ))),
+      _ => Err(RedisQueueError::NotFound(format!(
+        "No operation with operation_id = {}",
+        id
+      ))),
+    }
+  }
+
+  /// Restores the deleted operation `id`, as long as it was not purged.
+  pub async fn restore(&self, id: &str) -> Result<Operation, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let restored: i64 = redis::Script::new(RESTORE_SCRIPT)
+      .key(self.keys.tombstone(id))
+      .key(self.keys.operation(id))
+      .key(self.keys.tombstones())
+      .arg(id)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-admin-restore", operation_id = %id))
+      .await?;
+
+    match restored {
+      1 => {
+        tracing::info!(message = "Restored operation", operation_id = %id);
+        self.get(&mut conn, id).await
+      }
+      0 => Err(RedisQueueError::Conflict(format!(
+        "An operation with operation_id = {} exists",
+        id
+      ))),
+      _ => Err(RedisQueueError::NotFound(format!(
+        "No deleted operation with operation_id = {}",
+        id
+      ))),
+    }
+  }
+
+  /// Permanently removes the operations deleted more than `grace` ago, with their children and
+  /// logs. Returns their ids.
+  pub async fn purge_deleted(&self, grace: Duration) -> Result<Vec<String>, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let before = self.clock.now().timestamp_millis() - grace.as_millis() as i64;
+
+    let ids: Vec<String> = conn
+      .zrangebyscore(self.keys.tombstones(), "-inf", before)
+      .instrument(tracing::info_span!("redis-admin-purge-scan"))
+      .await?;
+
+    if ids.is_empty() {
+      return Ok(ids);
+    }
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for id in &ids {
+      pipe
+        .del(&[
+          self.keys.tombstone(id),
+          self.keys.children(id),
+          self.keys.annotations(id),
+          self.keys.logs(id),
+        ])
+        .ignore();
+    }
+    let _: () = pipe
+      .zrem(self.keys.tombstones(), &ids)
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-admin-purge", count = ids.len()))
+      .await?;
+
+    tracing::info!(message = "Purged deleted operations", count = ids.len());
+    Ok(ids)
+  }
+
+  /// Cancels an operation that did not complete yet: it is removed from every list of its queue
+  /// and terminated with a `CANCELLED` error. An operation completing concurrently is left
+  /// completed. Workers performing it notice the cancellation within their
+  /// [`super::worker::Worker::with_cancel_poll_interval`], stop the task through its
+  /// [`super::TaskContext::cancellation`] and discard its result.
+  pub async fn cancel(&self, id: &str, reason: &str) -> Result<Operation, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let status = Status {
+      code: Code::Cancelled as i32,
+      message: reason.to_string(),
+      details: Vec::default(),
+    };
+    let fields = vec![
+      ("done".to_string(), b"true".to_vec()),
+      (
+        "status".to_string(),
+        OperationState::Cancelled.as_str().as_bytes().to_vec(),
+      ),
+      (
+        "end_ts".to_string(),
+        self.clock.timestamp_nanos().to_string().into_bytes(),
+      ),
+      ("error".to_string(), status.encode_to_vec()),
+    ];
+
+    // The operation as read by the attempt that cancelled it, if it was not done.
+    let mut cancelled = None;
+    store::modify(&mut conn, &self.keys, id, |operation| {
+      cancelled = (!operation.done).then(|| operation.clone());
+      cancelled.as_ref().map(|_| fields.clone())
+    })
+    .instrument(tracing::info_span!("redis-admin-cancel", operation_id = %id))
+    .await?;
+
+    let operation = match cancelled {
+      Some(operation) => operation,
+      None => return self.get(&mut conn, id).await,
+    };
+    let queue = operation.queue.clone();
+
+    let _: () = redis::pipe()
+      .atomic()
+      .lrem(self.keys.queue(&queue), 0, id)
+      .ignore()
+      .lrem(self.keys.interactive(&queue), 0, id)
+      .ignore()
+      .lrem(self.keys.ack(&queue), 0, id)
+      .ignore()
+      .lrem(self.keys.invalid(&queue), 0, id)
+      .ignore()
+      .lrem(self.keys.quarantine(&queue), 0, id)
+      .ignore()
+      .zrem(self.keys.delayed(&queue), id)
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-admin-cancel-dequeue", operation_id = %id))
+      .await?;
+
+    crate::quota::release_operation(&mut conn, &self.keys, id).await?;
+
+    tracing::info!(message = "Cancelled operation", operation_id = %id, %queue, %reason);
+
+    self
+      .publish(
+        id,
+        &queue,
+        OperationEventType::Cancelled,
+        &operation.user_id,
+        HashMap::from([("reason".to_string(), reason.to_string())]),
+      )
+      .await;
+
+    self.get(&mut conn, id).await
+  }
+
+  /// Puts an operation back at the head of its queue for another attempt, wherever it currently
+  /// is: in flight, delayed, invalid, quarantined or already completed. Its previous result and
+  /// failure count are discarded.
+  pub async fn requeue(&self, id: &str) -> Result<Operation, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let operation = self.get(&mut conn, id).await?;
+    let queue = operation.queue.clone();
+
+    let _: () = redis::pipe()
+      .atomic()
+      .lrem(self.keys.queue(&queue), 0, id)
+      .ignore()
+      .lrem(self.keys.interactive(&queue), 0, id)
+      .ignore()
+      .lrem(self.keys.ack(&queue), 0, id)
+      .ignore()
+      .lrem(self.keys.invalid(&queue), 0, id)
+      .ignore()
+      .lrem(self.keys.quarantine(&queue), 0, id)
+      .ignore()
+      .zrem(self.keys.delayed(&queue), id)
+      .ignore()
+      .hdel(
+        self.keys.operation(id),
+        &[
+          "done",
+          "error",
+          "result",
+          "end_ts",
+          "failure_count",
+          "decode_error",
+        ],
+      )
+      .ignore()
+      .hset(self.keys.operation(id), "status", OperationState::Queued)
+      .ignore()
+      .rpush(self.keys.queue(&queue), id)
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-admin-requeue", operation_id = %id))
+      .await?;
+
+    tracing::info!(message = "Requeued operation", operation_id = %id, %queue);
+    self.get(&mut conn, id).await
+  }
+
+  /// Stops the workers of `queue` from pulling new operations. Operations can still be offered,
+  /// and operations already in flight complete normally.
+  pub async fn pause(&self, queue: &str) -> Result<(), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = conn
+      .set(
+        self.keys.paused(queue),
+        self.clock.timestamp_nanos().to_string(),
+      )
+      .instrument(tracing::info_span!("redis-admin-pause", %queue))
+      .await?;
+
+    tracing::info!(message = "Paused queue", %queue);
+    Ok(())
+  }
+
+  /// Lets the workers of a paused `queue` pull operations again.
+  pub async fn resume(&self, queue: &str) -> Result<(), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = conn
+      .del(self.keys.paused(queue))
+      .instrument(tracing::info_span!("redis-admin-resume", %queue))
+      .await?;
+
+    tracing::info!(message = "Resumed queue", %queue);
+    Ok(())
+  }
+
+  /// Starts a cluster-wide maintenance, or changes the one in progress: enqueues follow `enqueue`
+  /// and, with `pause_pulls`, the workers of every queue stop pulling operations.
+  pub async fn start_maintenance(
+    &self,
+    enqueue: EnqueuePolicy,
+    pause_pulls: bool,
+    reason: &str,
+  ) -> Result<Maintenance, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let maintenance = Maintenance {
+      enqueue,
+      pause_pulls,
+      reason: reason.to_string(),
+      since_ts: self.clock.timestamp_nanos(),
+    };
+
+    let _: () = redis::pipe()
+      .atomic()
+      .del(self.keys.maintenance())
+      .ignore()
+      .hset_multiple(self.keys.maintenance(), &maintenance.fields())
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-admin-start-maintenance"))
+      .await?;
+
+    tracing::info!(message = "Started maintenance", %enqueue, %pause_pulls, %reason);
+    Ok(maintenance)
+  }
+
+  /// Ends the maintenance in progress. The operations deferred during the maintenance become due
+  /// at once. Returns how many operations were deferred.
+  pub async fn end_maintenance(&self) -> Result<u64, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = conn
+      .del(self.keys.maintenance())
+      .instrument(tracing::info_span!("redis-admin-end-maintenance"))
+      .await?;
+
+    let delayed: Vec<String> = {
+      let mut scan_conn = redis_exec::connect(&self.client).await?;
+      let mut iter = scan_conn
+        .scan_match::<_, String>(self.keys.delayed("*"))
+        .instrument(tracing::info_span!("redis-admin-scan"))
+        .await?;
+      let mut keys = Vec::default();
+      while let Some(key) = iter.next_item().await {
+        keys.push(key);
+      }
+      keys
+    };
+
+    let now = self.clock.now().timestamp_millis();
+    let mut released = 0;
+    for key in delayed {
+      let count: u64 = redis::Script::new(RELEASE_DEFERRED_SCRIPT)
+        .key(&key)
+        .arg(now)
+        .invoke_async(&mut conn)
+        .instrument(tracing::info_span!("redis-admin-release-deferred"))
+        .await?;
+      released += count;
+    }
+
+    tracing::info!(message = "Ended maintenance", deferred = released);
+    Ok(released)
+  }
+
+  /// Returns the maintenance in progress, if any.
+  pub async fn maintenance(&self) -> Result<Option<Maintenance>, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    Ok(maintenance::read(&mut conn, &self.keys).await?)
+  }
+
+  /// Streams the lifecycle events matching `filter` as they are published.
+  pub async fn tail(
+    &self,
+    filter: StreamOperationsRequest,
+  ) -> Result<impl Stream<Item = OperationEvent> + Send, RedisQueueError> {
+    self.events.subscribe(filter).await
+  }
+
+  async fn publish(
+    &self,
+    id: &str,
+    queue: &str,
+    event_type: OperationEventType,
+    user_id: &str,
+    attributes: HashMap<String, String>,
+  ) {
+    let event = OperationEvent {
+      operation_id: id.to_string(),
+      queue: queue.to_string(),
+      event_type: event_type as i32,
+      attributes,
+      event_ts: Some(ProstTimestamp::from(self.clock.now()).into_inner()),
+      user_id: user_id.to_string(),
+    };
+
+    if let Err(error) = self.events.publish(event).await {
+      tracing::warn!(message = "Failed to publish operation event", operation_id = %id, %error);
+    }
+  }
+}
+
+/// Metrics of a `window` of `enqueued` operations, out of which `operations` were sampled.
+fn window_metrics(window: Duration, enqueued: i64, operations: &[Operation]) -> QueueWindowMetrics {
+  let mut completed = 0;
+  let mut failed = 0;
+  let mut latencies = Vec::with_capacity(operations.len());
+
+  for operation in operations {
+    match operation.operation_state() {
+      Some(OperationState::Succeeded) => {}
+      Some(OperationState::Failed) => failed += 1,
+      _ => continue,
+    }
+    completed += 1;
+
+    if let (Some(created), Some(ended)) = (&operation.creation_ts, &operation.end_ts) {
+      let nanos =
+        ProstTimestamp(ended.clone()).to_nanos() - ProstTimestamp(created.clone()).to_nanos();
+      latencies.push((nanos.max(0) / 1_000_000) as u64);
+    }
+  }
+  latencies.sort_unstable();
+
+  let sampled = operations.len() as i64;
+  let minutes = window.as_secs_f64() / 60.0;
+  // Completions of the sample, extrapolated to every operation of the window.
+  let throughput_per_minute = match (sampled, minutes > 0.0) {
+    (0, _) | (_, false) => 0.0,
+    _ => completed as f64 * enqueued.max(sampled) as f64 / sampled as f64 / minutes,
+  };
+
+  QueueWindowMetrics {
+    window_secs: window.as_secs() as i64,
+    enqueued,
+    sampled,
+    completed,
+    failed,
+    throughput_per_minute,
+    failure_rate: match completed {
+      0 => 0.0,
+      completed => failed as f64 / completed as f64,
+    },
+    p50_ms: sla::percentile(&latencies, 50),
+    p95_ms: sla::percentile(&latencies, 95),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use serde::Deserialize;
+  use serde::Serialize;
+  use uuid::Uuid;
+
+  use crate::longrunning::Performable;
+  use crate::longrunning::Queue;
+  use crate::proto::google::protobuf::Empty;
+  use crate::proto::longrunning::OperationState as ProtoOperationState;
+  use crate::util::clock::TestClock;
+
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Clone)]
+  struct Task {
+    item: i32,
+  }
+
+  #[async_trait::async_trait]
+  impl Performable for Task {
+    type Error = std::io::Error;
+    type Context = ();
+    type Output = Empty;
+
+    fn type_name() -> &'static str {
+      "longrunning::admin::tests::Task"
+    }
+
+    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+      Ok(Empty::default())
+    }
+  }
+
+  #[tokio::test]
+  async fn maintenance_should_reject_or_defer_enqueues_and_pause_pulls() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), "backups".to_string(), JsonCodec::new())
+        .with_keys(keys.clone());
+    let admin = RedisAdmin::new(client).with_keys(keys);
+
+    q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    admin
+      .start_maintenance(EnqueuePolicy::Reject, true, "Redis upgrade")
+      .await
+      .unwrap();
+
+    assert!(matches!(
+      q.offer(Task { item: 2 }, &ctx).await,
+      Err(RedisQueueError::Maintenance(error)) if error.reason == "Redis upgrade"
+    ));
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+
+    admin
+      .start_maintenance(EnqueuePolicy::Defer, false, "Redis upgrade")
+      .await
+      .unwrap();
+    let deferred = q.offer(Task { item: 3 }, &ctx).await.unwrap();
+    let first = q.pull(&ctx).await.unwrap().unwrap();
+    assert_ne!(first.ack_id, deferred);
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+
+    assert_eq!(admin.end_maintenance().await.unwrap(), 1);
+    assert_eq!(admin.maintenance().await.unwrap(), None);
+    assert_eq!(q.pull(&ctx).await.unwrap().unwrap().ack_id, deferred);
+  }
+
+  #[tokio::test]
+  async fn queue_metrics_should_summarize_the_operations_of_the_windows() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), "backups".to_string(), JsonCodec::new())
+        .with_keys(keys.clone());
+    let admin = RedisAdmin::new(client).with_keys(keys);
+
+    q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    q.offer(Task { item: 2 }, &ctx).await.unwrap();
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    q.complete(&message.ack_id, Ok::<_, Status>(Empty::default()), &ctx)
+      .await
+      .unwrap();
+
+    let metrics = admin.queue_metrics("backups", &[]).await.unwrap();
+    assert_eq!((metrics.pending, metrics.in_flight), (1, 0));
+    assert_eq!(metrics.windows.len(), DEFAULT_METRICS_WINDOWS.len());
+    let window = &metrics.windows[0];
+    assert_eq!(
+      (window.enqueued, window.sampled, window.completed),
+      (2, 2, 1)
+    );
+    assert_eq!(window.failure_rate, 0.0);
+  }
+
+  #[test]
+  fn window_metrics_should_extrapolate_the_sampled_completions() {
+    let operation = |state: OperationState, latency_ms: i64| Operation {
+      state: ProtoOperationState::from(state) as i32,
+      creation_ts: Some(ProstTimestamp::from_nanos(0).into_inner()),
+      end_ts: Some(ProstTimestamp::from_nanos(latency_ms * 1_000_000).into_inner()),
+      ..Default::default()
+    };
+    let operations = [
+      operation(OperationState::Succeeded, 100),
+      operation(OperationState::Succeeded, 200),
+      operation(OperationState::Failed, 1000),
+      operation(OperationState::Running, 0),
+    ];
+
+    let metrics = window_metrics(Duration::from_secs(120), 8, &operations);
+    assert_eq!(
+      (metrics.sampled, metrics.completed, metrics.failed),
+      (4, 3, 1)
+    );
+    assert_eq!(metrics.throughput_per_minute, 3.0);
+    assert!((metrics.failure_rate - 1.0 / 3.0).abs() < f64::EPSILON);
+    assert_eq!((metrics.p50_ms, metrics.p95_ms), (200, 1000));
+    assert_eq!(
+      window_metrics(Duration::from_secs(60), 0, &[]).throughput_per_minute,
+      0.0
+    );
+  }
+
+  #[tokio::test]
+  async fn schemas_should_list_the_published_schemas() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let admin = RedisAdmin::new(client).with_keys(Keys::new(&format!("{}:", Uuid::new_v4())));
+    let schema = |task_type: &str| TaskSchema {
+      task_type: task_type.to_string(),
+      payload_json_schema: r#"{"type":"object"}"#.to_string(),
+      ..Default::default()
+    };
+
+    admin
+      .publish_schemas(&[schema("backup"), schema("restore")])
+      .await
+      .unwrap();
+
+    assert_eq!(
+      admin.schemas(&[]).await.unwrap().task_schemas,
+      vec![schema("backup"), schema("restore")]
+    );
+    assert_eq!(
+      admin
+        .schemas(&["restore".to_string(), "unknown".to_string()])
+        .await
+        .unwrap()
+        .task_schemas,
+      vec![schema("restore")]
+    );
+  }
+
+  #[tokio::test]
+  async fn submit_should_validate_and_enqueue_the_payload() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), "backups".to_string(), JsonCodec::new())
+        .with_keys(keys.clone());
+    let admin = RedisAdmin::new(client).with_keys(keys);
+
+    assert!(matches!(
+      admin
+        .submit(Task::type_name(), "backups", r#"{"item":1}"#, &ctx)
+        .await,
+      Err(RedisQueueError::NotFound(_))
+    ));
+
+    admin
+      .publish_schemas(&[TaskSchema {
+        task_type: Task::type_name().to_string(),
+        content_type: crate::codec::json::CONTENT_TYPE.to_string(),
+        payload_json_schema: r#"{"type":"object","required":["item"]}"#.to_string(),
+        ..Default::default()
+      }])
+      .await
+      .unwrap();
+
+    assert!(matches!(
+      admin.submit(Task::type_name(), "backups", "{}", &ctx).await,
+      Err(RedisQueueError::InvalidArgument(_))
+    ));
+
+    let operation = admin
+      .submit(Task::type_name(), "backups", r#"{"item":1}"#, &ctx)
+      .await
+      .unwrap();
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(message.ack_id, operation.operation_id);
+    assert_eq!(message.data.item, 1);
+  }
+
+  #[tokio::test]
+  async fn delete_should_keep_operations_restorable_until_purged() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), "backups".to_string(), JsonCodec::new())
+        .with_keys(keys.clone());
+    let clock = TestClock::new();
+    let admin = RedisAdmin::new(client)
+      .with_keys(keys)
+      .with_clock(Arc::new(clock.clone()));
+
+    let id = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    assert!(matches!(
+      admin.delete(&id).await,
+      Err(RedisQueueError::Conflict(_))
+    ));
+
+    admin.cancel(&id, "Not needed").await.unwrap();
+    let annotated = admin.annotate(&id, "incident", "INC-42").await.unwrap();
+    assert_eq!(annotated.annotations["incident"], "INC-42");
+    admin.delete(&id).await.unwrap();
+    assert!(matches!(
+      admin.operation(&id).await,
+      Err(RedisQueueError::NotFound(_))
+    ));
+
+    admin.annotate(&id, "incident", "INC-42").await.unwrap();
+    let restored = admin.restore(&id).await.unwrap();
+    assert_eq!(restored.state, ProtoOperationState::Cancelled as i32);
+    assert!(!restored.metadata.contains_key("deleted_ts"));
+    assert_eq!(restored.annotations["incident"], "INC-42");
+    assert!(admin
+      .annotate(&id, "incident", "")
+      .await
+      .unwrap()
+      .annotations
+      .is_empty());
+
+    admin.delete(&id).await.unwrap();
+    assert!(admin
+      .purge_deleted(DEFAULT_DELETION_GRACE)
+      .await
+      .unwrap()
+      .is_empty());
+    clock.advance(DEFAULT_DELETION_GRACE + Duration::from_secs(1));
+    assert_eq!(
+      admin.purge_deleted(DEFAULT_DELETION_GRACE).await.unwrap(),
+      vec![id.clone()]
+    );
+    assert!(matches!(
+      admin.restore(&id).await,
+      Err(RedisQueueError::NotFound(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn tree_should_nest_child_operations() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
+    let admin = RedisAdmin::new(client);
+
+    let root = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    let child = q
+      .offer(
+        Task { item: 2 },
+        &ctx.clone().with_parent_operation_id(&root),
+      )
+      .await
+      .unwrap();
+    let grandchild = q
+      .offer(
+        Task { item: 3 },
+        &ctx.clone().with_parent_operation_id(&child),
+      )
+      .await
+      .unwrap();
+
+    let tree = admin.tree(&root, 0).await.unwrap();
+    assert_eq!(
+      tree.operation.unwrap().child_operation_ids,
+      vec![child.clone()]
+    );
+    let operation = tree.children[0].operation.clone().unwrap();
+    assert_eq!(operation.parent_operation_id, root);
+    assert_eq!(
+      tree.children[0].children[0]
+        .operation
+        .clone()
+        .unwrap()
+        .operation_id,
+      grandchild
+    );
+
+    assert!(admin.tree(&root, 1).await.unwrap().children[0]
+      .children
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn should_pause_cancel_and_requeue_operations() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
+    let admin = RedisAdmin::new(client);
+
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+
+    admin.pause(&queue).await.unwrap();
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+    assert!(admin.queue(&queue).await.unwrap().paused);
+    admin.resume(&queue).await.unwrap();
+
+    let operation = admin.cancel(&id, "Not needed anymore").await.unwrap();
+    assert!(operation.done);
+    assert_eq!(operation.error.unwrap().code, Code::Cancelled as i32);
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+
+    let operation = admin.requeue(&id).await.unwrap();
+    assert!(!operation.done);
+    assert_eq!(admin.queue(&queue).await.unwrap().pending, 1);
+    assert_eq!(q.pull(&ctx).await.unwrap().unwrap().ack_id, id);
+  }
+}
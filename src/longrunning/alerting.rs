@@ -0,0 +1,419 @@
+//! Alerts on queues that keep failing.
+//!
+//! [`FailureMonitor`] follows the operation events and raises an [`Alert`] when the failure rate
+//! of a queue or the number of messages it quarantines within a window crosses a threshold. The
+//! alerts go to every configured [`AlertNotifier`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::proto::longrunning::OperationEvent;
+use crate::proto::longrunning::OperationEventType;
+use crate::proto::longrunning::StreamOperationsRequest;
+
+use super::redis::RedisEventBus;
+use super::redis::RedisQueueError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+  /// Share of the operations completed in the window that failed.
+  FailureRate,
+  /// Messages quarantined in the window.
+  Quarantined,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Alert {
+  pub queue: String,
+  pub kind: AlertKind,
+  pub value: f64,
+  pub threshold: f64,
+  pub window_secs: u64,
+}
+
+impl fmt::Display for Alert {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.kind {
+      AlertKind::FailureRate => write!(
+        f,
+        "Queue {}: {:.0}% of the operations failed in the last {}s (threshold {:.0}%)",
+        self.queue,
+        self.value * 100.0,
+        self.window_secs,
+        self.threshold * 100.0
+      ),
+      AlertKind::Quarantined => write!(
+        f,
+        "Queue {}: {} messages quarantined in the last {}s (threshold {})",
+        self.queue, self.value, self.window_secs, self.threshold
+      ),
+    }
+  }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to send alert: {0}")]
+pub struct AlertError(pub String);
+
+/// Delivers alerts, e.g. to a chat channel or a paging system.
+#[async_trait::async_trait]
+pub trait AlertNotifier: Send + Sync + fmt::Debug {
+  async fn notify(&self, alert: &Alert) -> Result<(), AlertError>;
+}
+
+/// Logs alerts as errors, for setups that alert on logs.
+#[derive(Clone, Debug, Default)]
+pub struct LogNotifier;
+
+#[async_trait::async_trait]
+impl AlertNotifier for LogNotifier {
+  async fn notify(&self, alert: &Alert) -> Result<(), AlertError> {
+    tracing::error!(message = "Queue alert", queue = %alert.queue, kind = ?alert.kind, %alert);
+    Ok(())
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct AlertThresholds {
+  /// Failure rate above which a queue alerts, between 0 and 1.
+  pub failure_rate: f64,
+  /// Completions needed in the window before the failure rate is considered.
+  pub min_completions: u64,
+  /// Quarantined messages in the window above which a queue alerts.
+  pub quarantined: u64,
+  pub window: Duration,
+  /// Time before the same alert is raised again for a queue.
+  pub cooldown: Duration,
+}
+
+impl Default for AlertThresholds {
+  fn default() -> Self {
+    Self {
+      failure_rate: 0.5,
+      min_completions: 10,
+      quarantined: 5,
+      window: Duration::from_secs(300),
+      cooldown: Duration::from_secs(1800),
+    }
+  }
+}
+
+/// Counters of a queue over the current window.
+#[derive(Clone, Debug, Default)]
+struct QueueWindow {
+  completed: u64,
+  failed: u64,
+  quarantined: u64,
+}
+
+/// Counts the events per queue and evaluates the thresholds once per window.
+#[derive(Debug)]
+struct Tracker {
+  thresholds: AlertThresholds,
+  windows: HashMap<String, QueueWindow>,
+  raised: HashMap<(String, AlertKind), Instant>,
+}
+
+impl Tracker {
+  fn new(thresholds: AlertThresholds) -> Self {
+    Self {
+      thresholds,
+      windows: HashMap::default(),
+      raised: HashMap::default(),
+    }
+  }
+
+  fn record(&mut self, event: &OperationEvent) {
+    let window = self.windows.entry(event.queue.clone()).or_default();
+
+    match OperationEventType::from_i32(event.event_type) {
+      Some(OperationEventType::Completed) => {
+        window.completed += 1;
+        if event.attributes.get("outcome").map(String::as_str) == Some("failed") {
+          window.failed += 1;
+        }
+      }
+      Some(OperationEventType::Quarantined) => window.quarantined += 1,
+      _ => {}
+    }
+  }
+
+  /// Returns the alerts of the window that just ended and starts a new one.
+  fn evaluate(&mut self, now: Instant) -> Vec<Alert> {
+    let thresholds = &self.thresholds;
+    let window_secs = thresholds.window.as_secs();
+    let mut alerts = Vec::default();
+
+    for (queue, window) in std::mem::take(&mut self.windows) {
+      if window.completed >= thresholds.min_completions.max(1) {
+        let rate = window.failed as f64 / window.completed as f64;
+
+        if rate > thresholds.failure_rate {
+          alerts.push(Alert {
+            queue: queue.clone(),
+            kind: AlertKind::FailureRate,
+            value: rate,
+            threshold: thresholds.failure_rate,
+            window_secs,
+          });
+        }
+      }
+
+      if window.quarantined > thresholds.quarantined {
+        alerts.push(Alert {
+          queue,
+          kind: AlertKind::Quarantined,
+          value: window.quarantined as f64,
+          threshold: thresholds.quarantined as f64,
+          window_secs,
+        });
+      }
+    }
+
+    let cooldown = thresholds.cooldown;
+    alerts.retain(|alert| {
+      let key = (alert.queue.clone(), alert.kind);
+      match self.raised.get(&key) {
+        Some(raised) if now.duration_since(*raised) < cooldown => false,
+        _ => {
+          self.raised.insert(key, now);
+          true
+        }
+      }
+    });
+
+    alerts
+  }
+}
+
+/// Raises alerts for the queues whose operations keep failing.
+///
+/// ```rust,ignore
+/// let monitor = FailureMonitor::new(events, AlertThresholds::default())
+///   .with_notifier(Arc::new(HttpAlertNotifier::slack("http://slack-proxy/hooks/oncall")));
+/// tokio::spawn(async move { monitor.run().await });
+/// ```
+#[derive(Clone, Debug)]
+pub struct FailureMonitor {
+  events: RedisEventBus,
+  thresholds: AlertThresholds,
+  notifiers: Vec<Arc<dyn AlertNotifier>>,
+}
+
+impl FailureMonitor {
+  pub fn new(events: RedisEventBus, thresholds: AlertThresholds) -> Self {
+    Self {
+      events,
+      thresholds,
+      notifiers: Vec::default(),
+    }
+  }
+
+  pub fn with_notifier(mut self, notifier: Arc<dyn AlertNotifier>) -> Self {
+    self.notifiers.push(notifier);
+    self
+  }
+
+  /// Follows the events until the subscription ends.
+  pub async fn run(&self) -> Result<(), RedisQueueError> {
+    let filter = StreamOperationsRequest {
+      event_types: vec![
+        OperationEventType::Completed as i32,
+        OperationEventType::Quarantined as i32,
+      ],
+      ..Default::default()
+    };
+    let mut events = Box::pin(self.events.subscribe(filter).await?);
+    let mut tracker = Tracker::new(self.thresholds.clone());
+    let mut ticks = tokio::time::interval(self.thresholds.window);
+    // The first tick completes immediately.
+    ticks.tick().await;
+
+    loop {
+      tokio::select! {
+        event = events.next() => match event {
+          Some(event) => tracker.record(&event),
+          None => return Ok(()),
+        },
+        _ = ticks.tick() => {
+          for alert in tracker.evaluate(Instant::now()) {
+            self.alert(&alert).await;
+          }
+        }
+      }
+    }
+  }
+
+  async fn alert(&self, alert: &Alert) {
+    for notifier in &self.notifiers {
+      if let Err(error) = notifier.notify(alert).await {
+        tracing::warn!(message = "Failed to send alert", ?notifier, %error);
+      }
+    }
+  }
+}
+
+#[cfg(feature = "webhook")]
+pub use http::HttpAlertNotifier;
+
+#[cfg(feature = "webhook")]
+mod http {
+  use chrono::Utc;
+  use hyper::client::connect::Connect;
+  use hyper::client::HttpConnector;
+  use hyper::Body;
+
+  use super::super::webhook::sign;
+  use super::super::webhook::SIGNATURE_HEADER;
+  use super::*;
+
+  #[derive(Clone, Debug)]
+  enum Format {
+    /// The [`Alert`] as JSON, signed like the operation webhooks when a secret is set.
+    Json { secret: Option<Vec<u8>> },
+    /// A Slack incoming webhook message.
+    Slack,
+  }
+
+  /// POSTs alerts to an HTTP endpoint. Plain HTTP is used by default, pass a TLS connector to
+  /// [`HttpAlertNotifier::with_connector`] for HTTPS endpoints such as Slack's.
+  #[derive(Clone, Debug)]
+  pub struct HttpAlertNotifier<C = HttpConnector> {
+    client: hyper::Client<C>,
+    url: String,
+    format: Format,
+  }
+
+  impl HttpAlertNotifier<HttpConnector> {
+    /// Sends the alert as JSON, signed with `secret` if any, see [`super::super::webhook`].
+    pub fn webhook(url: &str, secret: Option<&[u8]>) -> Self {
+      Self {
+        client: hyper::Client::new(),
+        url: url.to_string(),
+        format: Format::Json {
+          secret: secret.map(<[u8]>::to_vec),
+        },
+      }
+    }
+
+    /// Sends the alert as a message to a Slack incoming webhook.
+    pub fn slack(url: &str) -> Self {
+      Self {
+        client: hyper::Client::new(),
+        url: url.to_string(),
+        format: Format::Slack,
+      }
+    }
+  }
+
+  impl<C> HttpAlertNotifier<C> {
+    pub fn with_connector<D: Connect + Clone>(self, connector: D) -> HttpAlertNotifier<D> {
+      HttpAlertNotifier {
+        client: hyper::Client::builder().build(connector),
+        url: self.url,
+        format: self.format,
+      }
+    }
+  }
+
+  #[async_trait::async_trait]
+  impl<C> AlertNotifier for HttpAlertNotifier<C>
+  where
+    C: Connect + Clone + Send + Sync + fmt::Debug + 'static,
+  {
+    async fn notify(&self, alert: &Alert) -> Result<(), AlertError> {
+      let error = |error: &dyn fmt::Display| AlertError(error.to_string());
+
+      let body = match &self.format {
+        Format::Json { .. } => serde_json::to_vec(alert),
+        Format::Slack => serde_json::to_vec(&serde_json::json!({ "text": alert.to_string() })),
+      }
+      .map_err(|e| error(&e))?;
+
+      let mut request =
+        hyper::Request::post(&self.url).header(hyper::header::CONTENT_TYPE, "application/json");
+      if let Format::Json {
+        secret: Some(secret),
+      } = &self.format
+      {
+        request = request.header(
+          SIGNATURE_HEADER,
+          sign(secret, Utc::now().timestamp(), &body),
+        );
+      }
+
+      let request = request.body(Body::from(body)).map_err(|e| error(&e))?;
+      let response = self.client.request(request).await.map_err(|e| error(&e))?;
+
+      match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(AlertError(format!(
+          "{} responded with {}",
+          self.url, status
+        ))),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn event(queue: &str, event_type: OperationEventType, outcome: &str) -> OperationEvent {
+    OperationEvent {
+      queue: queue.to_string(),
+      event_type: event_type as i32,
+      attributes: HashMap::from([("outcome".to_string(), outcome.to_string())]),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn tracker_should_alert_once_per_cooldown() {
+    let mut tracker = Tracker::new(AlertThresholds {
+      failure_rate: 0.5,
+      min_completions: 4,
+      quarantined: 1,
+      window: Duration::from_secs(60),
+      cooldown: Duration::from_secs(600),
+    });
+    let now = Instant::now();
+
+    for outcome in ["failed", "failed", "failed", "succeeded"] {
+      tracker.record(&event("backups", OperationEventType::Completed, outcome));
+    }
+    for _ in 0..2 {
+      tracker.record(&event("backups", OperationEventType::Quarantined, ""));
+    }
+    // Too few completions to judge the failure rate.
+    tracker.record(&event("billing", OperationEventType::Completed, "failed"));
+
+    let mut alerts = tracker.evaluate(now);
+    alerts.sort_by_key(|alert| alert.kind as u8);
+    assert_eq!(alerts.len(), 2);
+    assert_eq!(
+      (alerts[0].queue.as_str(), alerts[0].kind, alerts[0].value),
+      ("backups", AlertKind::FailureRate, 0.75)
+    );
+    assert_eq!(alerts[1].kind, AlertKind::Quarantined);
+
+    for _ in 0..2 {
+      tracker.record(&event("backups", OperationEventType::Quarantined, ""));
+    }
+    assert!(tracker.evaluate(now + Duration::from_secs(60)).is_empty());
+    assert!(tracker.evaluate(now + Duration::from_secs(660)).is_empty());
+
+    for _ in 0..2 {
+      tracker.record(&event("backups", OperationEventType::Quarantined, ""));
+    }
+    assert_eq!(tracker.evaluate(now + Duration::from_secs(720)).len(), 1);
+  }
+}
@@ -0,0 +1,450 @@
+//! Synthetic load against a [`RedisQueue`], to measure the throughput and latencies of its hot
+//! path: offer, pull and complete.
+//!
+//! Producers enqueue the operations of a [`LoadProfile`] into a fresh queue while workers pull and
+//! complete them, and [`run`] reports the percentiles once every operation completed. The
+//! operations and lists of the queue are deleted afterwards.
+//!
+//! ```rust,ignore
+//! let profile = LoadProfile::new(10_000).with_producers(4).with_workers(8);
+//! let report = bench::run(redis::Client::open("redis://127.0.0.1/")?, &profile).await?;
+//! println!("{}", report);
+//! ```
+//!
+//! `cargo bench --bench queue` runs the usual profiles against `REDIS_URL`.
+
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::codec::json::JsonCodec;
+use crate::proto::google::protobuf::Empty;
+use crate::redis::Keys;
+use crate::util::redis_exec;
+
+use super::redis::RedisQueue;
+use super::redis::RedisQueueError;
+use super::AckMode;
+use super::Context;
+use super::Performable;
+use super::Queue;
+use super::Task;
+
+/// Delay before pulling again from an empty queue.
+const IDLE_DELAY: Duration = Duration::from_millis(1);
+
+type BenchQueue = RedisQueue<BenchTask, JsonCodec<BenchTask, BenchTask>>;
+
+/// Shape of the load generated by [`run`].
+#[derive(Clone, Debug)]
+pub struct LoadProfile {
+  operations: usize,
+  producers: usize,
+  workers: usize,
+  payload_bytes: usize,
+  rate: Option<u32>,
+  ack_mode: AckMode,
+}
+
+impl LoadProfile {
+  /// Enqueues `operations` as fast as a single producer can, completed by a single worker.
+  pub fn new(operations: usize) -> Self {
+    Self {
+      operations,
+      producers: 1,
+      workers: 1,
+      payload_bytes: 64,
+      rate: None,
+      ack_mode: AckMode::default(),
+    }
+  }
+
+  pub fn with_producers(mut self, producers: usize) -> Self {
+    self.producers = producers.max(1);
+    self
+  }
+
+  pub fn with_workers(mut self, workers: usize) -> Self {
+    self.workers = workers.max(1);
+    self
+  }
+
+  /// Pads every task to about `payload_bytes` of JSON.
+  pub fn with_payload_bytes(mut self, payload_bytes: usize) -> Self {
+    self.payload_bytes = payload_bytes;
+    self
+  }
+
+  /// Caps the operations enqueued per second across the producers, e.g. to measure latencies
+  /// under a steady load rather than a burst.
+  pub fn with_rate(mut self, per_second: u32) -> Self {
+    self.rate = Some(per_second).filter(|rate| *rate > 0);
+    self
+  }
+
+  pub fn with_ack_mode(mut self, ack_mode: AckMode) -> Self {
+    self.ack_mode = ack_mode;
+    self
+  }
+}
+
+/// Nearest-rank percentiles of a set of latencies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Percentiles {
+  pub p50: Duration,
+  pub p90: Duration,
+  pub p99: Duration,
+  pub max: Duration,
+}
+
+impl Percentiles {
+  pub fn of(mut samples: Vec<Duration>) -> Self {
+    samples.sort_unstable();
+
+    let percentile = |percent: usize| {
+      let rank = (samples.len() * percent).div_ceil(100);
+      samples
+        .get(rank.saturating_sub(1))
+        .copied()
+        .unwrap_or_default()
+    };
+
+    Self {
+      p50: percentile(50),
+      p90: percentile(90),
+      p99: percentile(99),
+      max: samples.last().copied().unwrap_or_default(),
+    }
+  }
+}
+
+impl fmt::Display for Percentiles {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+      self.p50, self.p90, self.p99, self.max
+    )
+  }
+}
+
+/// Outcome of [`run`].
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+  pub operations: usize,
+  /// From the first offer to the last completion.
+  pub elapsed: Duration,
+  /// Duration of the offers.
+  pub enqueue: Percentiles,
+  /// Duration of the pulls returning an operation, then of the completions.
+  pub pull: Percentiles,
+  pub complete: Percentiles,
+  /// From the start of the offer of an operation to its completion.
+  pub end_to_end: Percentiles,
+}
+
+impl BenchReport {
+  /// Operations completed per second.
+  pub fn throughput(&self) -> f64 {
+    match self.elapsed.as_secs_f64() {
+      elapsed if elapsed > 0.0 => self.operations as f64 / elapsed,
+      _ => 0.0,
+    }
+  }
+}
+
+impl fmt::Display for BenchReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "{} operations in {:?}, {:.0} ops/s",
+      self.operations,
+      self.elapsed,
+      self.throughput()
+    )?;
+    writeln!(f, "  enqueue     {}", self.enqueue)?;
+    writeln!(f, "  pull        {}", self.pull)?;
+    writeln!(f, "  complete    {}", self.complete)?;
+    write!(f, "  end to end  {}", self.end_to_end)
+  }
+}
+
+/// Task enqueued by the producers, carrying the time its offer started.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BenchTask {
+  enqueued_us: i64,
+  padding: String,
+}
+
+#[async_trait::async_trait]
+impl Performable for BenchTask {
+  type Error = std::io::Error;
+  type Context = ();
+  type Output = Empty;
+
+  fn type_name() -> &'static str {
+    "longrunning::bench::BenchTask"
+  }
+
+  async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+    Ok(Empty::default())
+  }
+}
+
+/// Latencies recorded by a worker.
+#[derive(Default)]
+struct WorkerSamples {
+  pull: Vec<Duration>,
+  complete: Vec<Duration>,
+  end_to_end: Vec<Duration>,
+}
+
+/// Generates the load of `profile` against a fresh queue of `client`, and reports once every
+/// operation completed. Fails on the first error of a producer or a worker.
+pub async fn run(
+  client: redis::Client,
+  profile: &LoadProfile,
+) -> Result<BenchReport, RedisQueueError> {
+  let name = format!("bench-{}", Uuid::new_v4());
+  let queue =
+    RedisQueue::new(client.clone(), name.clone(), JsonCodec::new()).with_ack_mode(profile.ack_mode);
+  let ctx = Context::new("bench".to_string(), "bench".to_string());
+  let completed = Arc::new(AtomicUsize::new(0));
+  let started = Instant::now();
+
+  let producers: Vec<_> = (0..profile.producers)
+    .map(|producer| {
+      tokio::spawn(produce(
+        queue.clone(),
+        ctx.clone(),
+        share(profile.operations, profile.producers, producer),
+        profile.clone(),
+      ))
+    })
+    .collect();
+  let workers: Vec<_> = (0..profile.workers)
+    .map(|_| {
+      let work = work(
+        queue.clone(),
+        ctx.clone(),
+        profile.clone(),
+        completed.clone(),
+      );
+      let completed = completed.clone();
+      let operations = profile.operations;
+      tokio::spawn(async move {
+        let samples = work.await;
+        if samples.is_err() {
+          // The other workers would wait forever for the operation of the failed one.
+          completed.fetch_add(operations, Ordering::SeqCst);
+        }
+        samples
+      })
+    })
+    .collect();
+
+  let mut ids = Vec::with_capacity(profile.operations);
+  let mut enqueue = Vec::with_capacity(profile.operations);
+  let mut samples = WorkerSamples::default();
+  let mut failure = None;
+  for producer in producers {
+    match join(producer).await {
+      Ok((produced, latencies)) => {
+        ids.extend(produced);
+        enqueue.extend(latencies);
+      }
+      Err(error) => failure = failure.or(Some(error)),
+    }
+  }
+  if failure.is_some() {
+    // The workers would wait forever for the operations never enqueued.
+    completed.fetch_add(profile.operations, Ordering::SeqCst);
+  }
+  for worker in workers {
+    match join(worker).await {
+      Ok(worker) => {
+        samples.pull.extend(worker.pull);
+        samples.complete.extend(worker.complete);
+        samples.end_to_end.extend(worker.end_to_end);
+      }
+      Err(error) => failure = failure.or(Some(error)),
+    }
+  }
+  let elapsed = started.elapsed();
+
+  cleanup(&client, &name, &ids).await?;
+  if let Some(error) = failure {
+    return Err(error);
+  }
+
+  Ok(BenchReport {
+    operations: profile.operations,
+    elapsed,
+    enqueue: Percentiles::of(enqueue),
+    pull: Percentiles::of(samples.pull),
+    complete: Percentiles::of(samples.complete),
+    end_to_end: Percentiles::of(samples.end_to_end),
+  })
+}
+
+/// Operations enqueued by the producer `index` out of `producers`.
+fn share(operations: usize, producers: usize, index: usize) -> usize {
+  operations / producers + usize::from(index < operations % producers)
+}
+
+async fn join<T>(
+  handle: tokio::task::JoinHandle<Result<T, RedisQueueError>>,
+) -> Result<T, RedisQueueError> {
+  handle
+    .await
+    .map_err(|error| RedisQueueError::Internal(format!("Bench task failed: {}", error)))?
+}
+
+async fn produce(
+  queue: BenchQueue,
+  ctx: Context,
+  operations: usize,
+  profile: LoadProfile,
+) -> Result<(Vec<String>, Vec<Duration>), RedisQueueError> {
+  let mut interval = profile.rate.map(|rate| {
+    let period = Duration::from_secs_f64(profile.producers as f64 / rate as f64);
+    tokio::time::interval(period)
+  });
+  let padding = "x".repeat(profile.payload_bytes);
+
+  let mut ids = Vec::with_capacity(operations);
+  let mut latencies = Vec::with_capacity(operations);
+  for _ in 0..operations {
+    if let Some(interval) = &mut interval {
+      interval.tick().await;
+    }
+
+    let task = BenchTask {
+      enqueued_us: Utc::now().timestamp_micros(),
+      padding: padding.clone(),
+    };
+    let started = Instant::now();
+    ids.push(queue.offer(task, &ctx).await?);
+    latencies.push(started.elapsed());
+  }
+
+  Ok((ids, latencies))
+}
+
+async fn work(
+  queue: BenchQueue,
+  ctx: Context,
+  profile: LoadProfile,
+  completed: Arc<AtomicUsize>,
+) -> Result<WorkerSamples, RedisQueueError> {
+  let mut samples = WorkerSamples::default();
+
+  while completed.load(Ordering::SeqCst) < profile.operations {
+    let started = Instant::now();
+    let message = match queue.pull(&ctx).await? {
+      Some(message) => message,
+      None => {
+        tokio::time::sleep(IDLE_DELAY).await;
+        continue;
+      }
+    };
+    samples.pull.push(started.elapsed());
+
+    let started = Instant::now();
+    queue
+      .complete(
+        message.ack_id(),
+        Ok::<_, tonic::Status>(Empty::default()),
+        &ctx,
+      )
+      .await?;
+    if profile.ack_mode == AckMode::Manual {
+      queue.ack(message.ack_id(), &ctx).await?;
+    }
+    samples.complete.push(started.elapsed());
+
+    let end_to_end = Utc::now().timestamp_micros() - message.data().enqueued_us;
+    samples
+      .end_to_end
+      .push(Duration::from_micros(end_to_end.max(0) as u64));
+    completed.fetch_add(1, Ordering::SeqCst);
+  }
+
+  Ok(samples)
+}
+
+/// Deletes the operations and lists of the bench queue `name`.
+async fn cleanup(
+  client: &redis::Client,
+  name: &str,
+  ids: &[String],
+) -> Result<(), RedisQueueError> {
+  let keys = Keys::default();
+  let mut conn = redis_exec::connect(client).await?;
+
+  for chunk in ids.chunks(500) {
+    let operations: Vec<String> = chunk.iter().map(|id| keys.operation(id)).collect();
+    let _: () = redis::cmd("DEL")
+      .arg(operations)
+      .query_async(&mut conn)
+      .await?;
+  }
+  let _: () = redis::cmd("DEL")
+    .arg(keys.queue(name))
+    .arg(keys.interactive(name))
+    .arg(keys.ack(name))
+    .arg(keys.delayed(name))
+    .query_async(&mut conn)
+    .await?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn percentiles_should_use_the_nearest_rank() {
+    let samples = (1..=200).map(Duration::from_micros).collect();
+    assert_eq!(
+      Percentiles::of(samples),
+      Percentiles {
+        p50: Duration::from_micros(100),
+        p90: Duration::from_micros(180),
+        p99: Duration::from_micros(198),
+        max: Duration::from_micros(200),
+      }
+    );
+    assert_eq!(Percentiles::of(Vec::default()), Percentiles::default());
+  }
+
+  #[test]
+  fn share_should_split_the_operations_between_producers() {
+    let shares: Vec<usize> = (0..3).map(|index| share(10, 3, index)).collect();
+    assert_eq!(shares, vec![4, 3, 3]);
+  }
+
+  #[tokio::test]
+  async fn run_should_complete_every_operation() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let profile = LoadProfile::new(50)
+      .with_producers(2)
+      .with_workers(3)
+      .with_payload_bytes(16);
+
+    let report = run(client, &profile).await.unwrap();
+
+    assert_eq!(report.operations, 50);
+    assert!(report.throughput() > 0.0);
+    assert!(report.end_to_end.max >= report.end_to_end.p50);
+  }
+}
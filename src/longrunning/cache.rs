@@ -0,0 +1,299 @@
+//! In-process read-through cache of the operations, for services answering `GetOperation` from
+//! clients polling the same operations over and over.
+//!
+//! Cached operations are dropped as soon as an event about them is published on the
+//! [`RedisEventBus`], so a poll never sees a state older than the last event received. Events
+//! are not replayed though, so the cache is bypassed while it is not subscribed, e.g. when Redis
+//! is unreachable, and every entry expires after a TTL whatever happens.
+//!
+//! ```rust,ignore
+//! let cache = OperationCache::new(store).with_invalidation(events);
+//! let operation = cache.get(&request.operation_id).await?;
+//! ```
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::StreamExt;
+
+use crate::proto::longrunning::Operation;
+use crate::proto::longrunning::StreamOperationsRequest;
+use crate::util::backoff::Backoff;
+
+use super::redis::RedisEventBus;
+use super::store::RedisTaskStore;
+use super::TaskStore;
+
+/// Time an operation stays cached by default.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Operations cached at most by default.
+pub const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+
+/// A read-through cache of [`TaskStore::get`], see the [module](self). Clones share the cache.
+#[derive(Clone, Debug)]
+pub struct OperationCache<S = RedisTaskStore> {
+  store: S,
+  state: Arc<CacheState>,
+  ttl: Duration,
+  capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+  entries: Mutex<HashMap<String, (Operation, Instant)>>,
+  /// Whether the cache is subscribed to the events, without which nothing is cached.
+  live: AtomicBool,
+  /// Bumped by every invalidation, so a lookup racing with one does not cache what it read.
+  epoch: AtomicU64,
+}
+
+impl CacheState {
+  fn invalidate(&self, id: &str) {
+    self.epoch.fetch_add(1, Ordering::SeqCst);
+    self.entries.lock().unwrap().remove(id);
+  }
+
+  fn clear(&self) {
+    self.epoch.fetch_add(1, Ordering::SeqCst);
+    self.entries.lock().unwrap().clear();
+  }
+}
+
+impl<S: TaskStore> OperationCache<S> {
+  /// Caches nothing until [`Self::with_invalidation`] subscribes the cache to the events.
+  pub fn new(store: S) -> Self {
+    Self {
+      store,
+      state: Arc::default(),
+      ttl: DEFAULT_CACHE_TTL,
+      capacity: DEFAULT_CACHE_CAPACITY,
+    }
+  }
+
+  pub fn with_ttl(mut self, ttl: Duration) -> Self {
+    self.ttl = ttl;
+    self
+  }
+
+  /// Sets how many operations are cached at most. Once full, expired entries are evicted, and
+  /// the new operations are not cached until some expire.
+  pub fn with_capacity(mut self, capacity: usize) -> Self {
+    self.capacity = capacity;
+    self
+  }
+
+  /// Drops the cached operations as the events about them are published on `events`. The
+  /// subscription stops once every clone of the cache is dropped. Must be called within a Tokio
+  /// runtime.
+  pub fn with_invalidation(self, events: RedisEventBus) -> Self {
+    tokio::spawn(invalidate(events, Arc::downgrade(&self.state)));
+    self
+  }
+
+  /// Returns the operation `id`, from the cache when it was read recently.
+  pub async fn get(&self, id: &str) -> Result<Option<Operation>, S::Error> {
+    if let Some(operation) = self.cached(id) {
+      return Ok(Some(operation));
+    }
+
+    let epoch = self.state.epoch.load(Ordering::SeqCst);
+    let operation = self.store.get(id).await?;
+
+    if let Some(operation) = &operation {
+      self.insert(id, operation, epoch);
+    }
+
+    Ok(operation)
+  }
+
+  /// Returns the operations `ids`, `None` for the ones that do not exist, in the order of `ids`.
+  /// The ones not read recently are read together, see [`TaskStore::get_many`].
+  pub async fn get_many(&self, ids: &[String]) -> Result<Vec<Option<Operation>>, S::Error> {
+    let mut operations = ids.iter().map(|id| self.cached(id)).collect::<Vec<_>>();
+    let missing = ids
+      .iter()
+      .zip(&operations)
+      .filter(|(_, operation)| operation.is_none())
+      .map(|(id, _)| id.clone())
+      .collect::<Vec<_>>();
+    if missing.is_empty() {
+      return Ok(operations);
+    }
+
+    let epoch = self.state.epoch.load(Ordering::SeqCst);
+    let mut read = self.store.get_many(&missing).await?.into_iter();
+
+    for (id, operation) in ids.iter().zip(operations.iter_mut()) {
+      if operation.is_none() {
+        *operation = read.next().flatten();
+        if let Some(operation) = operation {
+          self.insert(id, operation, epoch);
+        }
+      }
+    }
+
+    Ok(operations)
+  }
+
+  /// Drops the operation `id` from the cache.
+  pub fn invalidate(&self, id: &str) {
+    self.state.invalidate(id);
+  }
+
+  /// Number of cached operations, expired ones included.
+  pub fn len(&self) -> usize {
+    self.state.entries.lock().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  fn cached(&self, id: &str) -> Option<Operation> {
+    let entries = self.state.entries.lock().unwrap();
+    let (operation, cached) = entries.get(id)?;
+    (cached.elapsed() < self.ttl).then(|| operation.clone())
+  }
+
+  /// Caches the operations without subscribing to the events, for tests invalidating the
+  /// operations themselves.
+  #[cfg(test)]
+  pub(crate) fn live(self) -> Self {
+    self.state.live.store(true, Ordering::SeqCst);
+    self
+  }
+
+  fn insert(&self, id: &str, operation: &Operation, epoch: u64) {
+    let mut entries = self.state.entries.lock().unwrap();
+
+    // Checked under the lock, as invalidations take it too.
+    if !self.state.live.load(Ordering::SeqCst) || self.state.epoch.load(Ordering::SeqCst) != epoch {
+      return;
+    }
+
+    if entries.len() >= self.capacity {
+      let ttl = self.ttl;
+      entries.retain(|_, (_, cached)| cached.elapsed() < ttl);
+      if entries.len() >= self.capacity {
+        return;
+      }
+    }
+
+    entries.insert(id.to_string(), (operation.clone(), Instant::now()));
+  }
+}
+
+async fn invalidate(events: RedisEventBus, state: Weak<CacheState>) {
+  let mut delays = Backoff::default().delays();
+
+  loop {
+    match events.subscribe(StreamOperationsRequest::default()).await {
+      Ok(subscription) => {
+        delays.reset();
+        let mut subscription = Box::pin(subscription);
+
+        match state.upgrade() {
+          Some(state) => state.live.store(true, Ordering::SeqCst),
+          None => return,
+        }
+
+        while let Some(event) = subscription.next().await {
+          match state.upgrade() {
+            Some(state) => state.invalidate(&event.operation_id),
+            None => return,
+          }
+        }
+
+        tracing::warn!(message = "Operation events subscription ended, bypassing the cache");
+      }
+      Err(error) => {
+        tracing::warn!(message = "Failed to subscribe to the operation events", %error);
+      }
+    }
+
+    // Events may have been missed meanwhile.
+    match state.upgrade() {
+      Some(state) => {
+        state.live.store(false, Ordering::SeqCst);
+        state.clear();
+      }
+      None => return,
+    }
+
+    tokio::time::sleep(delays.next().unwrap_or_default()).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use uuid::Uuid;
+
+  use crate::longrunning::EventBus;
+  use crate::proto::longrunning::OperationEvent;
+  use crate::redis::Keys;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn cache_should_be_bypassed_until_subscribed() {
+    let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let cache = OperationCache::new(RedisTaskStore::new(client));
+
+    cache.insert("1", &Operation::default(), 0);
+    assert!(cache.is_empty());
+
+    cache.state.live.store(true, Ordering::SeqCst);
+    cache.insert("1", &Operation::default(), 0);
+    assert_eq!(cache.len(), 1);
+
+    // A lookup that started before an invalidation does not cache what it read.
+    cache.invalidate("1");
+    cache.insert("1", &Operation::default(), 0);
+    assert!(cache.is_empty());
+  }
+
+  #[tokio::test]
+  async fn cache_should_drop_operations_on_events() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let store = RedisTaskStore::new(client.clone()).with_keys(keys.clone());
+    let events = RedisEventBus::new(client.clone(), &keys.channel("events"));
+    let cache = OperationCache::new(store.clone()).with_invalidation(events.clone());
+
+    tokio::time::timeout(Duration::from_secs(1), async {
+      while !cache.state.live.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    })
+    .await
+    .expect("the cache should subscribe to the events");
+
+    store.update("1", &[("status", "Queued")]).await.unwrap();
+    assert!(cache.get("1").await.unwrap().is_some());
+    assert_eq!(cache.len(), 1);
+
+    events
+      .publish(OperationEvent {
+        operation_id: "1".to_string(),
+        ..Default::default()
+      })
+      .await
+      .unwrap();
+
+    for _ in 0..100 {
+      if cache.is_empty() {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(cache.is_empty());
+  }
+}
@@ -0,0 +1,196 @@
+//! Resource usage of the operations, accounted to their organization.
+//!
+//! A [`CostMeter`] attached to queues with [`super::redis::RedisQueue::with_cost`] turns every
+//! completed operation of an organization into [`MeterEvent`]s: its execution wall time, its
+//! retries and the size of its payload. The events are appended to a Redis list drained by the
+//! billing service with [`CostMeter::take_events`], and summed per organization for budgets,
+//! see [`CostMeter::usage`].
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use prost::Message;
+use tracing_futures::Instrument;
+
+use crate::proto::account::MeterEvent;
+use crate::proto::prelude::ProstTimestamp;
+use crate::redis::Keys;
+use crate::util::redis_exec::InstrumentedConnection;
+
+/// Execution wall time of the operations, in milliseconds.
+pub const WALL_TIME_MS: &str = "operation_wall_time_ms";
+
+/// Deliveries of the operations after the first one.
+pub const RETRIES: &str = "operation_retries";
+
+/// Size of the task and the result of the operations, in bytes.
+pub const PAYLOAD_BYTES: &str = "operation_payload_bytes";
+
+/// Completed operations.
+pub const OPERATIONS: &str = "operations";
+
+/// Events kept until the billing service takes them by default. Older events are dropped.
+pub const DEFAULT_MAX_EVENTS: usize = 100_000;
+
+/// Resources used by a completed operation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OperationUsage {
+  pub operation_id: String,
+  pub organization_id: String,
+  pub task_type: String,
+  /// Time from the last delivery to the completion.
+  pub wall_time: Duration,
+  pub retries: u32,
+  pub payload_bytes: u64,
+}
+
+impl OperationUsage {
+  /// One event per meter, labelled with the task type.
+  pub fn events(&self) -> Vec<MeterEvent> {
+    let event_ts = Some(ProstTimestamp::now().into_inner());
+    let labels = HashMap::from([("task_type".to_string(), self.task_type.clone())]);
+
+    [
+      (OPERATIONS, 1),
+      (WALL_TIME_MS, saturating_i64(self.wall_time.as_millis())),
+      (RETRIES, i64::from(self.retries)),
+      (PAYLOAD_BYTES, saturating_i64(self.payload_bytes.into())),
+    ]
+    .into_iter()
+    .map(|(meter, value)| MeterEvent {
+      organization_id: self.organization_id.clone(),
+      meter: meter.to_string(),
+      value,
+      resource_id: self.operation_id.clone(),
+      event_ts: event_ts.clone(),
+      labels: labels.clone(),
+    })
+    .collect()
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct CostMeter {
+  max_events: usize,
+}
+
+impl Default for CostMeter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl CostMeter {
+  pub fn new() -> Self {
+    Self {
+      max_events: DEFAULT_MAX_EVENTS,
+    }
+  }
+
+  /// Keeps the last `max_events` events not taken yet, [`DEFAULT_MAX_EVENTS`] by default.
+  pub fn with_max_events(mut self, max_events: usize) -> Self {
+    self.max_events = max_events.max(1);
+    self
+  }
+
+  /// Adds the Redis writes recording `usage` to `pipe`.
+  pub(crate) fn record(&self, pipe: &mut redis::Pipeline, keys: &Keys, usage: &OperationUsage) {
+    for event in usage.events() {
+      pipe
+        .hincr(
+          keys.usage(&event.organization_id),
+          &event.meter,
+          event.value,
+        )
+        .ignore()
+        .rpush(keys.meter_events(), event.encode_to_vec())
+        .ignore();
+    }
+
+    pipe
+      .ltrim(keys.meter_events(), -(self.max_events as isize), -1)
+      .ignore();
+  }
+
+  /// Removes and returns up to `count` of the oldest events.
+  pub async fn take_events(
+    conn: &mut InstrumentedConnection,
+    keys: &Keys,
+    count: usize,
+  ) -> redis::RedisResult<Vec<MeterEvent>> {
+    if count == 0 {
+      return Ok(Vec::default());
+    }
+
+    let (events,): (Vec<Vec<u8>>,) = redis::pipe()
+      .atomic()
+      .lrange(keys.meter_events(), 0, count as isize - 1)
+      .ltrim(keys.meter_events(), count as isize, -1)
+      .ignore()
+      .query_async(conn)
+      .instrument(tracing::info_span!("redis-cost-take"))
+      .await?;
+
+    Ok(
+      events
+        .iter()
+        .filter_map(|event| MeterEvent::decode(event.as_slice()).ok())
+        .collect(),
+    )
+  }
+
+  /// Usage summed over the operations of `organization_id`, by meter.
+  pub async fn usage(
+    conn: &mut InstrumentedConnection,
+    keys: &Keys,
+    organization_id: &str,
+  ) -> redis::RedisResult<HashMap<String, i64>> {
+    redis::cmd("HGETALL")
+      .arg(keys.usage(organization_id))
+      .query_async(conn)
+      .instrument(tracing::info_span!("redis-cost-usage", %organization_id))
+      .await
+  }
+}
+
+fn saturating_i64(value: u128) -> i64 {
+  i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn events_should_cover_every_meter() {
+    let usage = OperationUsage {
+      operation_id: "1".to_string(),
+      organization_id: "acme".to_string(),
+      task_type: "build".to_string(),
+      wall_time: Duration::from_millis(1500),
+      retries: 2,
+      payload_bytes: 64,
+    };
+
+    let events: HashMap<String, i64> = usage
+      .events()
+      .into_iter()
+      .inspect(|event| {
+        assert_eq!(event.organization_id, "acme");
+        assert_eq!(event.resource_id, "1");
+        assert_eq!(event.labels["task_type"], "build");
+      })
+      .map(|event| (event.meter, event.value))
+      .collect();
+
+    assert_eq!(
+      events,
+      HashMap::from([
+        (OPERATIONS.to_string(), 1),
+        (WALL_TIME_MS.to_string(), 1500),
+        (RETRIES.to_string(), 2),
+        (PAYLOAD_BYTES.to_string(), 64),
+      ])
+    );
+  }
+}
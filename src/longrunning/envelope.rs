@@ -0,0 +1,177 @@
+//! Serialization of the [`Context`] an operation is enqueued with, so the worker performing it
+//! gets the whole context back instead of the user id alone.
+//!
+//! The context is stored in the `context` field of the operation hash, in a versioned envelope.
+//! Readers accept the envelopes of their version and of older ones, and fail on newer ones.
+//!
+//! ```rust,ignore
+//! let queue = RedisQueue::new(client, queue, JsonCodec::new())
+//!   .with_context_serializer(Arc::new(JsonContextSerializer));
+//! ```
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use chrono::TimeZone;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::service::Priority;
+
+use super::Context;
+
+/// Version of the envelopes written by [`JsonContextSerializer`].
+pub const CONTEXT_ENVELOPE_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ContextEnvelopeError {
+  #[error("Malformed context envelope: {0}")]
+  Malformed(#[from] serde_json::Error),
+
+  #[error("Unsupported context envelope version {0}")]
+  UnsupportedVersion(u32),
+}
+
+/// Writes a [`Context`] to the operation hash and reads it back.
+pub trait ContextSerializer: Debug + Send + Sync {
+  fn serialize(&self, context: &Context) -> Result<Vec<u8>, ContextEnvelopeError>;
+
+  fn deserialize(&self, envelope: &[u8]) -> Result<Context, ContextEnvelopeError>;
+}
+
+/// A serializer shared by the queues of a process.
+pub type SharedContextSerializer = Arc<dyn ContextSerializer>;
+
+/// Serializes the context to a JSON object, whose `v` field is the envelope version.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonContextSerializer;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Envelope {
+  v: u32,
+  user_id: String,
+  system_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  principal: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  organization_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  parent_operation_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  callback_url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  trace_parent: Option<String>,
+  /// Milliseconds since the Unix epoch.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  deadline_ms: Option<i64>,
+  /// Set for interactive operations only.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  priority: Option<Priority>,
+}
+
+impl ContextSerializer for JsonContextSerializer {
+  fn serialize(&self, context: &Context) -> Result<Vec<u8>, ContextEnvelopeError> {
+    let envelope = Envelope {
+      v: CONTEXT_ENVELOPE_VERSION,
+      user_id: context.user_id().to_string(),
+      system_id: context.system_id().to_string(),
+      principal: Some(context.principal())
+        .filter(|_| context.is_impersonating())
+        .map(str::to_string),
+      organization_id: context.organization_id().map(str::to_string),
+      parent_operation_id: context.parent_operation_id().map(str::to_string),
+      callback_url: context.callback_url().map(str::to_string),
+      trace_parent: context.trace_parent().map(str::to_string),
+      deadline_ms: context
+        .deadline()
+        .map(|deadline| deadline.timestamp_millis()),
+      priority: Some(context.priority()).filter(|priority| *priority != Priority::default()),
+    };
+
+    Ok(serde_json::to_vec(&envelope)?)
+  }
+
+  fn deserialize(&self, envelope: &[u8]) -> Result<Context, ContextEnvelopeError> {
+    let envelope: Envelope = serde_json::from_slice(envelope)?;
+    if envelope.v > CONTEXT_ENVELOPE_VERSION {
+      return Err(ContextEnvelopeError::UnsupportedVersion(envelope.v));
+    }
+
+    let mut context = Context::new(envelope.user_id, envelope.system_id);
+    if let Some(principal) = &envelope.principal {
+      context = context.with_principal(principal);
+    }
+    if let Some(organization_id) = &envelope.organization_id {
+      context = context.with_organization_id(organization_id);
+    }
+    if let Some(parent_operation_id) = &envelope.parent_operation_id {
+      context = context.with_parent_operation_id(parent_operation_id);
+    }
+    if let Some(callback_url) = &envelope.callback_url {
+      context = context.with_callback_url(callback_url);
+    }
+    if let Some(trace_parent) = &envelope.trace_parent {
+      context = context.with_trace_parent(trace_parent);
+    }
+    if let Some(deadline) = envelope
+      .deadline_ms
+      .and_then(|ms| Utc.timestamp_millis_opt(ms).single())
+    {
+      context = context.with_deadline(deadline);
+    }
+    if let Some(priority) = envelope.priority {
+      context = context.with_priority(priority);
+    }
+
+    Ok(context)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn json_serializer_should_round_trip_the_context() {
+    let deadline = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+    let context = Context::new("42".to_string(), "worker-1".to_string())
+      .with_principal("backup-service")
+      .with_organization_id("acme")
+      .with_parent_operation_id("parent")
+      .with_callback_url("https://example.com/done")
+      .with_trace_parent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+      .with_deadline(deadline)
+      .with_priority(Priority::Interactive);
+
+    let envelope = JsonContextSerializer.serialize(&context).unwrap();
+    assert_eq!(
+      JsonContextSerializer.deserialize(&envelope).unwrap(),
+      context
+    );
+
+    let minimal = Context::new("42".to_string(), String::default());
+    let envelope = JsonContextSerializer.serialize(&minimal).unwrap();
+    assert_eq!(
+      String::from_utf8(envelope.clone()).unwrap(),
+      r#"{"v":1,"user_id":"42","system_id":""}"#
+    );
+    assert_eq!(
+      JsonContextSerializer.deserialize(&envelope).unwrap(),
+      minimal
+    );
+  }
+
+  #[test]
+  fn json_serializer_should_reject_newer_envelopes() {
+    assert!(matches!(
+      JsonContextSerializer.deserialize(br#"{"v":2,"user_id":"42"}"#),
+      Err(ContextEnvelopeError::UnsupportedVersion(2))
+    ));
+    assert!(matches!(
+      JsonContextSerializer.deserialize(b"not json"),
+      Err(ContextEnvelopeError::Malformed(_))
+    ));
+  }
+}
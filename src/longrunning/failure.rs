@@ -0,0 +1,207 @@
+//! Classification of task failures, which decides what the workers do with a failed operation:
+//! deliver it again, delay it, move it to the invalid list or complete it with the error.
+//!
+//! ```rust,ignore
+//! #[derive(Debug)]
+//! struct Quotas;
+//!
+//! impl FailureClassifier for Quotas {
+//!   fn classify(&self, status: &Status) -> Failure {
+//!     match status.message.starts_with("quota") {
+//!       true => Failure::RateLimited,
+//!       false => DefaultClassifier.classify(status),
+//!     }
+//!   }
+//! }
+//!
+//! let worker = Worker::new(queue, ctx).with_failure_classifier(Arc::new(Quotas));
+//! ```
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::Code;
+
+use crate::proto::google::rpc::Status;
+use crate::util::backoff::Backoff;
+use crate::util::backoff::Jitter;
+
+/// Delay before a rate limited operation is delivered again, doubling with every attempt.
+pub const DEFAULT_RATE_LIMIT_DELAY: Duration = Duration::from_secs(1);
+
+/// What went wrong with a task, as far as retrying it is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+  /// A transient failure: the operation is delivered again, and quarantined once it failed as
+  /// many times as the poison threshold of the queue.
+  Retryable,
+  /// The operation completes with the error.
+  Fatal,
+  /// A dependency is saturated: the operation is delivered again after a delay, without counting
+  /// towards quarantine.
+  RateLimited,
+  /// The task can never succeed: the operation moves to the invalid list, where it can be
+  /// inspected and replayed.
+  Invalid,
+}
+
+impl Failure {
+  /// Classifies a gRPC status code. Codes telling the call may succeed later are retryable,
+  /// codes blaming the request are invalid, and the others are fatal.
+  pub fn of_code(code: Code) -> Self {
+    match code {
+      Code::Unavailable | Code::DeadlineExceeded | Code::Aborted | Code::Unknown => {
+        Failure::Retryable
+      }
+      Code::ResourceExhausted => Failure::RateLimited,
+      Code::InvalidArgument | Code::OutOfRange | Code::Unimplemented => Failure::Invalid,
+      _ => Failure::Fatal,
+    }
+  }
+
+  pub fn of_tonic(status: &tonic::Status) -> Self {
+    Self::of_code(status.code())
+  }
+
+  /// Connection, timeout and cluster errors are retryable, the others are fatal.
+  #[cfg(feature = "redis")]
+  pub fn of_redis(error: &redis::RedisError) -> Self {
+    use redis::ErrorKind;
+
+    if error.is_io_error()
+      || error.is_timeout()
+      || error.is_connection_dropped()
+      || error.is_connection_refusal()
+      || error.is_cluster_error()
+    {
+      return Failure::Retryable;
+    }
+
+    match error.kind() {
+      ErrorKind::BusyLoadingError | ErrorKind::TryAgain | ErrorKind::MasterDown => {
+        Failure::Retryable
+      }
+      ErrorKind::TypeError => Failure::Invalid,
+      _ => Failure::Fatal,
+    }
+  }
+
+  pub fn of_io(error: &std::io::Error) -> Self {
+    use std::io::ErrorKind;
+
+    match error.kind() {
+      ErrorKind::ConnectionRefused
+      | ErrorKind::ConnectionReset
+      | ErrorKind::ConnectionAborted
+      | ErrorKind::NotConnected
+      | ErrorKind::BrokenPipe
+      | ErrorKind::TimedOut
+      | ErrorKind::Interrupted
+      | ErrorKind::WouldBlock
+      | ErrorKind::UnexpectedEof => Failure::Retryable,
+      ErrorKind::InvalidInput | ErrorKind::InvalidData => Failure::Invalid,
+      _ => Failure::Fatal,
+    }
+  }
+
+  /// Name of the failure in the metrics and logs.
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Failure::Retryable => "retryable",
+      Failure::Fatal => "fatal",
+      Failure::RateLimited => "rate_limited",
+      Failure::Invalid => "invalid",
+    }
+  }
+}
+
+/// Tells the workers what kind of failure the error of a task is.
+pub trait FailureClassifier: Debug + Send + Sync {
+  fn classify(&self, status: &Status) -> Failure;
+}
+
+/// A classifier shared by the workers of a process.
+pub type SharedFailureClassifier = Arc<dyn FailureClassifier>;
+
+/// Classifies errors by their status code, see [`Failure::of_code`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultClassifier;
+
+impl FailureClassifier for DefaultClassifier {
+  fn classify(&self, status: &Status) -> Failure {
+    Failure::of_code(Code::from_i32(status.code))
+  }
+}
+
+/// Delay before the `attempt`-th delivery of a rate limited operation is retried.
+pub(crate) fn rate_limit_delay(attempt: i64) -> Duration {
+  Backoff::exponential(DEFAULT_RATE_LIMIT_DELAY)
+    .with_jitter(Jitter::Full)
+    .delays()
+    .nth(attempt.saturating_sub(1).clamp(0, 31) as usize)
+    .unwrap_or(DEFAULT_RATE_LIMIT_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn default_classifier_should_classify_by_code() {
+    let status = |code: Code| Status {
+      code: code as i32,
+      ..Default::default()
+    };
+
+    assert_eq!(
+      DefaultClassifier.classify(&status(Code::Unavailable)),
+      Failure::Retryable
+    );
+    assert_eq!(
+      DefaultClassifier.classify(&status(Code::ResourceExhausted)),
+      Failure::RateLimited
+    );
+    assert_eq!(
+      DefaultClassifier.classify(&status(Code::InvalidArgument)),
+      Failure::Invalid
+    );
+    assert_eq!(
+      DefaultClassifier.classify(&status(Code::PermissionDenied)),
+      Failure::Fatal
+    );
+  }
+
+  #[test]
+  fn io_and_redis_errors_should_be_classified() {
+    use std::io::Error;
+    use std::io::ErrorKind;
+
+    assert_eq!(
+      Failure::of_io(&Error::from(ErrorKind::ConnectionReset)),
+      Failure::Retryable
+    );
+    assert_eq!(
+      Failure::of_io(&Error::from(ErrorKind::InvalidData)),
+      Failure::Invalid
+    );
+    assert_eq!(
+      Failure::of_io(&Error::from(ErrorKind::PermissionDenied)),
+      Failure::Fatal
+    );
+
+    #[cfg(feature = "redis")]
+    {
+      let refused = redis::RedisError::from(Error::from(ErrorKind::ConnectionRefused));
+      let malformed = redis::RedisError::from((redis::ErrorKind::TypeError, "not a number"));
+      assert_eq!(Failure::of_redis(&refused), Failure::Retryable);
+      assert_eq!(Failure::of_redis(&malformed), Failure::Invalid);
+    }
+  }
+
+  #[test]
+  fn rate_limit_delay_should_grow_with_the_attempts() {
+    assert!(rate_limit_delay(1) <= DEFAULT_RATE_LIMIT_DELAY);
+    assert!(rate_limit_delay(4) <= DEFAULT_RATE_LIMIT_DELAY * 8);
+  }
+}
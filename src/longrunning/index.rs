@@ -0,0 +1,217 @@
+//! Daily partitioned indexes of the operations by user and by queue, so listing the operations of
+//! a user or a queue reads a few bounded keys instead of scanning the whole store, and no key
+//! grows with the total volume of operations.
+//!
+//! Every offer adds the operation to the partitions of its publish day, `index:user:{user}:{day}`
+//! and `index:queue:{queue}:{day}`, which expire [`DEFAULT_INDEX_RETENTION`] after their day
+//! ends. [`super::store::RedisTaskStore::with_indexed_lists`] lists from the partitions of the
+//! days of a filter, newest first, merging the partitions of a day by publish time.
+
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::NaiveDate;
+use chrono::Utc;
+use tracing_futures::Instrument;
+
+use crate::redis::Keys;
+use crate::util::redis_exec::InstrumentedConnection;
+
+use super::OperationFilter;
+
+/// Time the partitions are kept after their day ends.
+pub const DEFAULT_INDEX_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// Adds the operation `id` to the partitions of its user and queue for the day of `published`.
+pub(crate) fn record(
+  pipe: &mut redis::Pipeline,
+  keys: &Keys,
+  id: &str,
+  queue: &str,
+  user_id: &str,
+  published: DateTime<Utc>,
+) {
+  let day = published.date_naive();
+  let expire_at = expiry(day);
+
+  let mut partitions = vec![keys.queue_index(queue, &format_day(day))];
+  if !user_id.is_empty() {
+    partitions.push(keys.user_index(user_id, &format_day(day)));
+  }
+
+  for partition in partitions {
+    pipe
+      .zadd(&partition, id, published.timestamp_millis())
+      .ignore()
+      .expire_at(&partition, expire_at)
+      .ignore();
+  }
+}
+
+/// Epoch seconds the partitions of `day` expire at.
+fn expiry(day: NaiveDate) -> usize {
+  let end = day
+    .succ_opt()
+    .and_then(|next| next.and_hms_opt(0, 0, 0))
+    .map(|end| end.and_utc().timestamp())
+    .unwrap_or_default();
+
+  (end.max(0) as u64 + DEFAULT_INDEX_RETENTION.as_secs()) as usize
+}
+
+fn format_day(day: NaiveDate) -> String {
+  day.format(DAY_FORMAT).to_string()
+}
+
+/// Days of the operations published from `since` until `until` excluded, newest first.
+pub fn days(since: DateTime<Utc>, until: DateTime<Utc>) -> Vec<NaiveDate> {
+  if since >= until {
+    return Vec::default();
+  }
+
+  let first = since.date_naive();
+  let mut day = (until - chrono::Duration::nanoseconds(1)).date_naive();
+  let mut days = vec![day];
+  while day > first {
+    day = match day.pred_opt() {
+      Some(previous) => previous,
+      None => break,
+    };
+    days.push(day);
+  }
+
+  days
+}
+
+/// Partitions of `day` holding the operations `filter` may match, by user when it selects one and
+/// by queue otherwise. `None` when it selects neither, as only a scan finds the operations then.
+pub fn partitions(keys: &Keys, filter: &OperationFilter, day: NaiveDate) -> Option<Vec<String>> {
+  let day = format_day(day);
+
+  match (&filter.user_id, filter.queues.as_slice()) {
+    (Some(user_id), _) => Some(vec![keys.user_index(user_id, &day)]),
+    (None, []) => None,
+    (None, queues) => Some(
+      queues
+        .iter()
+        .map(|queue| keys.queue_index(queue, &day))
+        .collect(),
+    ),
+  }
+}
+
+/// Ids of the operations of `partitions` published from `since_ms` until `until_ms` excluded,
+/// merged newest first, the newest `limit` ones only when set.
+pub(crate) async fn read(
+  conn: &mut InstrumentedConnection,
+  partitions: &[String],
+  since_ms: i64,
+  until_ms: i64,
+  limit: Option<usize>,
+) -> redis::RedisResult<Vec<String>> {
+  let mut pipe = redis::pipe();
+  for partition in partitions {
+    match limit {
+      Some(limit) => pipe.zrevrangebyscore_limit_withscores(
+        partition,
+        format!("({}", until_ms),
+        since_ms,
+        0,
+        limit as isize,
+      ),
+      None => pipe.zrevrangebyscore_withscores(partition, format!("({}", until_ms), since_ms),
+    };
+  }
+
+  let read: Vec<Vec<(String, i64)>> = pipe
+    .query_async(conn)
+    .instrument(tracing::info_span!(
+      "redis-index-read",
+      partitions = partitions.len()
+    ))
+    .await?;
+
+  let mut entries: Vec<(String, i64)> = read.into_iter().flatten().collect();
+  entries.sort_by(|(a_id, a_ms), (b_id, b_ms)| b_ms.cmp(a_ms).then_with(|| b_id.cmp(a_id)));
+  entries.dedup_by(|(a, _), (b, _)| a == b);
+  if let Some(limit) = limit {
+    entries.truncate(limit);
+  }
+
+  Ok(entries.into_iter().map(|(id, _)| id).collect())
+}
+
+/// Number of operations of `partitions` published from `since_ms` until `until_ms` excluded. An
+/// operation in several partitions is counted once per partition.
+pub(crate) async fn count(
+  conn: &mut InstrumentedConnection,
+  partitions: &[String],
+  since_ms: i64,
+  until_ms: i64,
+) -> redis::RedisResult<i64> {
+  let mut pipe = redis::pipe();
+  for partition in partitions {
+    pipe.zcount(partition, since_ms, format!("({}", until_ms));
+  }
+
+  let counts: Vec<i64> = pipe
+    .query_async(conn)
+    .instrument(tracing::info_span!(
+      "redis-index-count",
+      partitions = partitions.len()
+    ))
+    .await?;
+
+  Ok(counts.into_iter().sum())
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::TimeZone;
+
+  use super::*;
+
+  #[test]
+  fn days_should_cover_the_window_newest_first() {
+    let at = |day, hour| Utc.with_ymd_and_hms(2024, 2, day, hour, 0, 0).unwrap();
+    let day = |day| NaiveDate::from_ymd_opt(2024, 2, day).unwrap();
+
+    assert_eq!(days(at(27, 12), at(29, 0)), vec![day(28), day(27)]);
+    assert_eq!(days(at(28, 1), at(28, 2)), vec![day(28)]);
+    assert!(days(at(28, 2), at(28, 2)).is_empty());
+  }
+
+  #[test]
+  fn partitions_should_prefer_the_user_index() {
+    let keys = Keys::default();
+    let day = NaiveDate::from_ymd_opt(2024, 2, 28).unwrap();
+    let mut filter = OperationFilter {
+      queues: vec!["backups".to_string(), "restores".to_string()],
+      ..Default::default()
+    };
+
+    assert_eq!(
+      partitions(&keys, &filter, day).unwrap(),
+      vec![
+        "index:queue:backups:2024-02-28",
+        "index:queue:restores:2024-02-28"
+      ]
+    );
+
+    filter.user_id = Some("42".to_string());
+    assert_eq!(
+      partitions(&keys, &filter, day).unwrap(),
+      vec!["index:user:42:2024-02-28"]
+    );
+    assert_eq!(partitions(&keys, &OperationFilter::default(), day), None);
+    assert_eq!(
+      expiry(day),
+      Utc
+        .with_ymd_and_hms(2024, 3, 30, 0, 0, 0)
+        .unwrap()
+        .timestamp() as usize
+    );
+  }
+}
@@ -0,0 +1,275 @@
+//! Live output of running operations, e.g. build logs, kept in a Redis stream per operation.
+//!
+//! The worker appends chunks while the task runs and closes the log once it is done. Callers
+//! follow it with [`OperationLogs::stream_logs`] from the first chunk, so they see the whole
+//! log however late they start following.
+//!
+//! ```rust,ignore
+//! let (chunks, mut rx) = tokio::sync::mpsc::channel(16);
+//! let logs = self.logs.clone();
+//! let forward = tokio::spawn(async move {
+//!   while let Some(chunk) = rx.recv().await {
+//!     logs.append(&id, chunk.stream.as_str(), &chunk.data).await.ok();
+//!   }
+//!   logs.close(&id).await.ok();
+//! });
+//! Supervised::new("make").run_streaming(chunks).await?;
+//! ```
+
+use std::time::Duration;
+
+use futures::Stream;
+use tracing_futures::Instrument;
+
+use crate::redis::Keys;
+use crate::util::redis_exec;
+use crate::util::redis_exec::InstrumentedConnection;
+
+/// Chunks kept per operation by default. Older chunks are trimmed.
+pub const DEFAULT_MAX_CHUNKS: usize = 10_000;
+
+/// Time a log is kept after its last chunk by default.
+pub const DEFAULT_LOG_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Name of the stream of the entry closing a log.
+const EOF: &str = "eof";
+
+/// Entries of the stream of a log, as returned by `XREAD`.
+type StreamEntries = Vec<(String, Vec<(String, Vec<Vec<u8>>)>)>;
+
+/// A chunk of the output of an operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+  /// Id of the entry in the Redis stream, increasing with time.
+  pub id: String,
+  /// Output the chunk was written to, e.g. `stdout`.
+  pub stream: String,
+  pub data: Vec<u8>,
+}
+
+/// Writes and follows the logs of operations, stored in the Redis stream
+/// `operation:logs:{operation_id}`.
+#[derive(Clone, Debug)]
+pub struct OperationLogs {
+  client: redis::Client,
+  keys: Keys,
+  max_chunks: usize,
+  ttl: Duration,
+  block: Duration,
+}
+
+impl OperationLogs {
+  pub fn new(client: redis::Client) -> Self {
+    Self {
+      client,
+      keys: Keys::default(),
+      max_chunks: DEFAULT_MAX_CHUNKS,
+      ttl: DEFAULT_LOG_TTL,
+      block: Duration::from_secs(5),
+    }
+  }
+
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Keeps about the last `max_chunks` chunks of every log, [`DEFAULT_MAX_CHUNKS`] by default.
+  pub fn with_max_chunks(mut self, max_chunks: usize) -> Self {
+    self.max_chunks = max_chunks;
+    self
+  }
+
+  /// Deletes logs `ttl` after their last chunk, [`DEFAULT_LOG_TTL`] by default.
+  pub fn with_ttl(mut self, ttl: Duration) -> Self {
+    self.ttl = ttl;
+    self
+  }
+
+  /// Appends a chunk written to `stream` by the operation `id`. Returns the id of the entry.
+  pub async fn append(&self, id: &str, stream: &str, data: &[u8]) -> redis::RedisResult<String> {
+    let key = self.keys.logs(id);
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let (entry_id,): (String,) = redis::pipe()
+      .atomic()
+      .cmd("XADD")
+      .arg(&key)
+      .arg("MAXLEN")
+      .arg("~")
+      .arg(self.max_chunks)
+      .arg("*")
+      .arg("stream")
+      .arg(stream)
+      .arg("data")
+      .arg(data)
+      .cmd("PEXPIRE")
+      .arg(&key)
+      .arg(self.ttl.as_millis() as u64)
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-logs-append", operation_id = %id))
+      .await?;
+
+    Ok(entry_id)
+  }
+
+  /// Marks the log of the operation `id` complete, which ends the streams following it.
+  pub async fn close(&self, id: &str) -> redis::RedisResult<()> {
+    self.append(id, EOF, b"").await.map(|_| ())
+  }
+
+  /// Returns the chunks after the entry `after`, `0` for the whole log, waiting up to `block`
+  /// for one. The second value tells whether the log is closed.
+  pub async fn read(
+    &self,
+    id: &str,
+    after: &str,
+    block: Duration,
+  ) -> redis::RedisResult<(Vec<LogEntry>, bool)> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    read(&mut conn, &self.keys.logs(id), after, block).await
+  }
+
+  /// Follows the log of the operation `id` from its first chunk until it is closed.
+  pub fn stream_logs(&self, id: &str) -> impl Stream<Item = redis::RedisResult<LogEntry>> {
+    struct Follow {
+      conn: Option<InstrumentedConnection>,
+      after: String,
+      pending: std::vec::IntoIter<LogEntry>,
+      closed: bool,
+    }
+
+    let logs = self.clone();
+    let key = self.keys.logs(id);
+    let follow = Follow {
+      conn: None,
+      after: "0".to_string(),
+      pending: Vec::default().into_iter(),
+      closed: false,
+    };
+
+    futures::stream::unfold(follow, move |mut follow| {
+      let logs = logs.clone();
+      let key = key.clone();
+
+      async move {
+        loop {
+          if let Some(entry) = follow.pending.next() {
+            follow.after = entry.id.clone();
+            return Some((Ok(entry), follow));
+          }
+          if follow.closed {
+            return None;
+          }
+
+          // A blocking read holds its connection, so the stream keeps one of its own.
+          let conn = match &mut follow.conn {
+            Some(conn) => conn,
+            None => match redis_exec::connect(&logs.client).await {
+              Ok(conn) => follow.conn.insert(conn),
+              Err(error) => {
+                follow.closed = true;
+                return Some((Err(error), follow));
+              }
+            },
+          };
+
+          match read(conn, &key, &follow.after, logs.block).await {
+            Ok((entries, closed)) => {
+              follow.pending = entries.into_iter();
+              follow.closed = closed;
+            }
+            Err(error) => {
+              follow.closed = true;
+              return Some((Err(error), follow));
+            }
+          }
+        }
+      }
+    })
+  }
+}
+
+async fn read(
+  conn: &mut InstrumentedConnection,
+  key: &str,
+  after: &str,
+  block: Duration,
+) -> redis::RedisResult<(Vec<LogEntry>, bool)> {
+  let streams: Option<StreamEntries> = redis::cmd("XREAD")
+    .arg("BLOCK")
+    .arg(block.as_millis() as u64)
+    .arg("STREAMS")
+    .arg(key)
+    .arg(after)
+    .query_async(conn)
+    .instrument(tracing::info_span!("redis-logs-read"))
+    .await?;
+
+  let mut entries = Vec::default();
+  let mut closed = false;
+
+  for (id, fields) in streams
+    .into_iter()
+    .flatten()
+    .flat_map(|(_, entries)| entries)
+  {
+    let mut stream = String::default();
+    let mut data = Vec::default();
+    for pair in fields.chunks_exact(2) {
+      match pair[0].as_slice() {
+        b"stream" => stream = String::from_utf8_lossy(&pair[1]).into_owned(),
+        b"data" => data = pair[1].clone(),
+        _ => {}
+      }
+    }
+
+    if stream == EOF {
+      closed = true;
+      break;
+    }
+    entries.push(LogEntry { id, stream, data });
+  }
+
+  Ok((entries, closed))
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::TryStreamExt;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn stream_logs_should_follow_until_closed() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let logs =
+      OperationLogs::new(client).with_keys(Keys::new(&format!("{}:", uuid::Uuid::new_v4())));
+
+    logs.append("1", "stdout", b"step 1\n").await.unwrap();
+
+    let follower = {
+      let logs = logs.clone();
+      tokio::spawn(async move {
+        logs
+          .stream_logs("1")
+          .map_ok(|entry| (entry.stream, entry.data))
+          .try_collect::<Vec<_>>()
+          .await
+      })
+    };
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    logs.append("1", "stderr", b"warning\n").await.unwrap();
+    logs.close("1").await.unwrap();
+
+    assert_eq!(
+      follower.await.unwrap().unwrap(),
+      vec![
+        ("stdout".to_string(), b"step 1\n".to_vec()),
+        ("stderr".to_string(), b"warning\n".to_vec()),
+      ]
+    );
+  }
+}
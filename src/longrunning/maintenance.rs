@@ -0,0 +1,161 @@
+//! Cluster-wide maintenance mode, stored in the `maintenance` hash so every process sees it.
+//!
+//! During maintenance, enqueues are rejected with [`MaintenanceMode`] or deferred until the
+//! maintenance ends, and the workers of every queue may stop pulling. Started and ended with
+//! [`super::admin::RedisAdmin::start_maintenance`] and
+//! [`super::admin::RedisAdmin::end_maintenance`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use tracing_futures::Instrument;
+
+use crate::redis::Keys;
+use crate::util::redis_exec::InstrumentedConnection;
+
+/// Score of the deferred operations in the delayed set of their queue: due once the maintenance
+/// ends.
+pub(crate) const DEFERRED_SCORE: &str = "+inf";
+
+/// What happens to the operations enqueued during maintenance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnqueuePolicy {
+  /// Enqueued as usual.
+  Accept,
+  /// Rejected with [`MaintenanceMode`].
+  #[default]
+  Reject,
+  /// Created but delivered only once the maintenance ends.
+  Defer,
+}
+
+impl EnqueuePolicy {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      EnqueuePolicy::Accept => "accept",
+      EnqueuePolicy::Reject => "reject",
+      EnqueuePolicy::Defer => "defer",
+    }
+  }
+}
+
+impl fmt::Display for EnqueuePolicy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown enqueue policy {0:?}")]
+pub struct UnknownEnqueuePolicy(String);
+
+impl FromStr for EnqueuePolicy {
+  type Err = UnknownEnqueuePolicy;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "accept" => Ok(EnqueuePolicy::Accept),
+      "reject" => Ok(EnqueuePolicy::Reject),
+      "defer" => Ok(EnqueuePolicy::Defer),
+      _ => Err(UnknownEnqueuePolicy(s.to_string())),
+    }
+  }
+}
+
+/// A maintenance in progress.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Maintenance {
+  pub enqueue: EnqueuePolicy,
+  /// Whether the workers stop pulling operations.
+  pub pause_pulls: bool,
+  pub reason: String,
+  /// Nanoseconds since the Unix epoch.
+  pub since_ts: i64,
+}
+
+impl Maintenance {
+  pub(crate) fn fields(&self) -> [(&'static str, String); 4] {
+    let pulls = if self.pause_pulls {
+      "paused"
+    } else {
+      "running"
+    };
+
+    [
+      ("enqueue", self.enqueue.to_string()),
+      ("pulls", pulls.to_string()),
+      ("reason", self.reason.clone()),
+      ("since_ts", self.since_ts.to_string()),
+    ]
+  }
+
+  fn from_fields(mut fields: HashMap<String, String>) -> Option<Self> {
+    if fields.is_empty() {
+      return None;
+    }
+
+    Some(Self {
+      // Enqueues are rejected when the policy is unreadable, as maintenance is in progress.
+      enqueue: fields
+        .get("enqueue")
+        .and_then(|enqueue| enqueue.parse().ok())
+        .unwrap_or_default(),
+      pause_pulls: fields.get("pulls").map(String::as_str) == Some("paused"),
+      reason: fields.remove("reason").unwrap_or_default(),
+      since_ts: fields
+        .get("since_ts")
+        .and_then(|ts| ts.parse().ok())
+        .unwrap_or_default(),
+    })
+  }
+}
+
+/// Error of the enqueues rejected during maintenance.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Maintenance in progress: {reason}")]
+pub struct MaintenanceMode {
+  pub reason: String,
+}
+
+/// Reads the maintenance in progress, if any.
+pub async fn read(
+  conn: &mut InstrumentedConnection,
+  keys: &Keys,
+) -> Result<Option<Maintenance>, redis::RedisError> {
+  let fields: HashMap<String, String> = redis::cmd("HGETALL")
+    .arg(keys.maintenance())
+    .query_async(conn)
+    .instrument(tracing::info_span!("redis-maintenance-read"))
+    .await?;
+
+  Ok(Maintenance::from_fields(fields))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn maintenance_should_round_trip_its_fields() {
+    let maintenance = Maintenance {
+      enqueue: EnqueuePolicy::Defer,
+      pause_pulls: true,
+      reason: "Redis upgrade".to_string(),
+      since_ts: 1_700_000_000_000_000_000,
+    };
+
+    let fields = maintenance
+      .fields()
+      .into_iter()
+      .map(|(name, value)| (name.to_string(), value))
+      .collect();
+    assert_eq!(Maintenance::from_fields(fields), Some(maintenance));
+    assert_eq!(Maintenance::from_fields(HashMap::default()), None);
+
+    let unreadable = HashMap::from([("enqueue".to_string(), "later".to_string())]);
+    let maintenance = Maintenance::from_fields(unreadable).unwrap();
+    assert_eq!(maintenance.enqueue, EnqueuePolicy::Reject);
+    assert!(!maintenance.pause_pulls);
+  }
+}
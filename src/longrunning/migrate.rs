@@ -0,0 +1,257 @@
+//! Moves the pending tasks of a queue to another queue, e.g. to another Redis instance or key
+//! prefix, or to another [`Queue`] implementation.
+//!
+//! A migration without downtime goes as follows:
+//!
+//! 1. Producers switch to the destination queue and workers start consuming it.
+//! 2. [`Migration::run`] drains the pending tasks of the source into the destination.
+//! 3. Workers of the source complete the tasks they already pulled, then stop.
+//!
+//! Tasks are acknowledged in the source only once offered to the destination, so a migration
+//! interrupted in between offers a task twice rather than losing it.
+
+use std::collections::HashMap;
+
+use super::Context;
+use super::Queue;
+use super::Task;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError<S, D> {
+  #[error("Failed to read from the source queue: {0}")]
+  Source(S),
+
+  #[error("Failed to offer to the destination queue: {0}")]
+  Destination(D),
+}
+
+/// Outcome of a [`Migration`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+  /// Operation ids in the destination by operation id in the source.
+  pub operation_ids: HashMap<String, String>,
+}
+
+impl MigrationReport {
+  pub fn migrated(&self) -> usize {
+    self.operation_ids.len()
+  }
+}
+
+/// Drains a source queue into a destination queue.
+///
+/// ```rust,ignore
+/// let report = Migration::new(&old, &new, ctx).with_limit(10_000).run().await?;
+/// println!("Migrated {} operations", report.migrated());
+/// ```
+///
+/// The tasks keep the user that enqueued them when the source records it, see
+/// [`Task::user_id`], and get new operation ids in the destination.
+#[derive(Debug)]
+pub struct Migration<'a, S, D> {
+  source: &'a S,
+  destination: &'a D,
+  ctx: Context,
+  limit: Option<usize>,
+}
+
+impl<'a, S, D> Migration<'a, S, D>
+where
+  S: Queue + Sync,
+  D: Queue<Item = S::Item> + Sync,
+  S::Item: Clone,
+{
+  /// Migrates with `ctx` as the context of the pulls, and of the offers of tasks without a user.
+  pub fn new(source: &'a S, destination: &'a D, ctx: Context) -> Self {
+    Self {
+      source,
+      destination,
+      ctx,
+      limit: None,
+    }
+  }
+
+  /// Stops after migrating `limit` tasks, e.g. to migrate in batches.
+  pub fn with_limit(mut self, limit: usize) -> Self {
+    self.limit = Some(limit);
+    self
+  }
+
+  /// Migrates the tasks until the source is empty or the limit is reached. A task that cannot be
+  /// offered is given back to the source before failing.
+  pub async fn run(&self) -> Result<MigrationReport, MigrationError<S::Error, D::Error>> {
+    let mut report = MigrationReport::default();
+
+    while self.limit.is_none_or(|limit| report.migrated() < limit) {
+      let message = match self
+        .source
+        .pull(&self.ctx)
+        .await
+        .map_err(MigrationError::Source)?
+      {
+        Some(message) => message,
+        None => break,
+      };
+
+      let ctx = match message.user_id() {
+        Some(user_id) => Context::new(user_id.to_string(), self.ctx.system_id().to_string()),
+        None => self.ctx.clone(),
+      };
+
+      let id = match self.destination.offer(message.data().clone(), &ctx).await {
+        Ok(id) => id,
+        Err(error) => {
+          self
+            .source
+            .nack(message.ack_id(), None, &self.ctx)
+            .await
+            .map_err(MigrationError::Source)?;
+          return Err(MigrationError::Destination(error));
+        }
+      };
+
+      self
+        .source
+        .ack(message.ack_id(), &self.ctx)
+        .await
+        .map_err(MigrationError::Source)?;
+
+      tracing::debug!(message = "Migrated operation", from = %message.ack_id(), to = %id);
+      report
+        .operation_ids
+        .insert(message.ack_id().to_string(), id);
+    }
+
+    tracing::info!(
+      message = "Migrated operations",
+      migrated = report.migrated()
+    );
+    Ok(report)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::VecDeque;
+  use std::sync::Mutex;
+  use std::time::Duration;
+
+  use crate::proto::google::protobuf::Empty;
+
+  use super::super::Performable;
+  use super::*;
+
+  #[derive(Clone, Debug, PartialEq, Eq)]
+  struct Job(i32);
+
+  #[async_trait::async_trait]
+  impl Performable for Job {
+    type Error = std::io::Error;
+    type Context = ();
+    type Output = Empty;
+
+    fn type_name() -> &'static str {
+      "longrunning::migrate::tests::Job"
+    }
+
+    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+      Ok(Empty {})
+    }
+  }
+
+  struct Message {
+    id: String,
+    job: Job,
+  }
+
+  impl Task<Job> for Message {
+    fn ack_id(&self) -> &str {
+      &self.id
+    }
+
+    fn data(&self) -> &Job {
+      &self.job
+    }
+  }
+
+  /// Queue in memory whose offers fail once `capacity` tasks are pending.
+  #[derive(Default)]
+  struct MemoryQueue {
+    pending: Mutex<VecDeque<(String, Job)>>,
+    in_flight: Mutex<HashMap<String, Job>>,
+    capacity: usize,
+  }
+
+  #[async_trait::async_trait]
+  impl Queue for MemoryQueue {
+    type Item = Job;
+    type ReceivedItem = Message;
+    type Error = String;
+
+    async fn offer(&self, job: Job, _: &Context) -> Result<String, String> {
+      let mut pending = self.pending.lock().unwrap();
+      if pending.len() >= self.capacity {
+        return Err("full".to_string());
+      }
+      let id = format!("{}", job.0);
+      pending.push_back((id.clone(), job));
+      Ok(id)
+    }
+
+    async fn pull(&self, _: &Context) -> Result<Option<Message>, String> {
+      let next = self.pending.lock().unwrap().pop_front();
+      Ok(next.map(|(id, job)| {
+        self
+          .in_flight
+          .lock()
+          .unwrap()
+          .insert(id.clone(), job.clone());
+        Message { id, job }
+      }))
+    }
+
+    async fn ack(&self, id: &str, _: &Context) -> Result<(), String> {
+      self.in_flight.lock().unwrap().remove(id);
+      Ok(())
+    }
+
+    async fn nack(&self, id: &str, _: Option<Duration>, _: &Context) -> Result<(), String> {
+      let job = self.in_flight.lock().unwrap().remove(id).unwrap();
+      self
+        .pending
+        .lock()
+        .unwrap()
+        .push_front((id.to_string(), job));
+      Ok(())
+    }
+  }
+
+  #[tokio::test]
+  async fn migration_should_give_back_tasks_it_cannot_offer() {
+    let ctx = Context::new("migration".to_string(), "migration".to_string());
+    let source = MemoryQueue {
+      capacity: 10,
+      ..Default::default()
+    };
+    let destination = MemoryQueue {
+      capacity: 2,
+      ..Default::default()
+    };
+    for i in 0..3 {
+      source.offer(Job(i), &ctx).await.unwrap();
+    }
+
+    let report = Migration::new(&source, &destination, ctx.clone())
+      .with_limit(1)
+      .run()
+      .await
+      .unwrap();
+    assert_eq!(report.migrated(), 1);
+
+    let error = Migration::new(&source, &destination, ctx).run().await;
+    assert!(matches!(error, Err(MigrationError::Destination(_))));
+    assert_eq!(destination.pending.lock().unwrap().len(), 2);
+    assert_eq!(source.pending.lock().unwrap().front().unwrap().1, Job(2));
+    assert!(source.in_flight.lock().unwrap().is_empty());
+  }
+}
@@ -1,6 +1,48 @@
 #[cfg(feature = "redis")]
+pub mod admin;
+#[cfg(feature = "redis")]
+pub mod alerting;
+#[cfg(feature = "redis")]
+pub mod bench;
+#[cfg(feature = "redis")]
+pub mod cache;
+#[cfg(feature = "redis")]
+pub mod cost;
+pub mod envelope;
+pub mod failure;
+#[cfg(feature = "redis")]
+pub mod index;
+#[cfg(feature = "redis")]
+pub mod logs;
+#[cfg(feature = "redis")]
+pub mod maintenance;
+pub mod migrate;
+#[cfg(feature = "redis")]
+pub mod ordered;
+pub mod payload;
+pub mod process;
+#[cfg(feature = "redis")]
 pub mod redis;
+pub mod registry;
+#[cfg(feature = "redis")]
+pub mod replication;
+#[cfg(feature = "redis")]
+pub mod routing;
+#[cfg(feature = "runner")]
+pub mod runner;
+pub mod schema;
+#[cfg(feature = "redis")]
+pub mod server;
+pub mod skew;
+#[cfg(feature = "redis")]
+pub mod sla;
+#[cfg(feature = "redis")]
+pub mod store;
 mod types;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+#[cfg(feature = "redis")]
+pub mod worker;
 
 use std::time::Duration;
 pub use types::*;
@@ -8,12 +50,31 @@ pub use types::*;
 use crate::proto::longrunning::GetOperationRequest;
 use crate::proto::longrunning::Operation;
 use crate::service::OperationsSvcClient;
+use crate::util::backoff::Backoff;
+use crate::util::backoff::Jitter;
+use crate::util::clock::Clock;
+use crate::util::clock::SystemClock;
 
+/// Polls the operation `operation_id` until it is done. Polls back off from 100 milliseconds to a
+/// second, with jitter.
 pub async fn wait(
   client: &mut OperationsSvcClient,
   operation_id: &str,
+) -> Result<Operation, tonic::Status> {
+  wait_with_clock(client, operation_id, &SystemClock).await
+}
+
+/// [`wait`], sleeping between polls on `clock`.
+pub async fn wait_with_clock(
+  client: &mut OperationsSvcClient,
+  operation_id: &str,
+  clock: &dyn Clock,
 ) -> Result<Operation, tonic::Status> {
   let id = operation_id.to_string();
+  let mut delays = Backoff::exponential(Duration::from_millis(100))
+    .with_max(Duration::from_secs(1))
+    .with_jitter(Jitter::Full)
+    .delays();
   loop {
     let operation_id = id.clone();
     tracing::trace!(message = "Polling the operation status", %operation_id);
@@ -31,6 +92,6 @@ pub async fn wait(
         return Err(error);
       }
     }
-    let _ = tokio::time::sleep(Duration::from_millis(1000)).await;
+    clock.sleep(delays.next().unwrap_or_default()).await;
   }
 }
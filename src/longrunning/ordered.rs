@@ -0,0 +1,365 @@
+//! Ordered delivery per entity: the tasks offered with the same ordering key, e.g. a workspace
+//! id, are performed one at a time in the order they were offered, while the tasks of different
+//! keys run in parallel.
+//!
+//! The keys are spread over a fixed number of partitions, each a queue of its own named
+//! `{queue}:{partition}`. A consumer pulls from a partition only while it holds the lease of the
+//! partition, from the pull until the operation completes, so a partition has a single consumer.
+//!
+//! ```rust,ignore
+//! let queue = OrderedQueue::new(RedisQueue::new(client, "workspaces".to_string(), codec), 16);
+//! queue.offer_keyed(task, workspace_id, &ctx).await?;
+//!
+//! if let Some(message) = queue.pull(&ctx).await? {
+//!   let result = message.data().perform(task_ctx).await;
+//!   queue.complete(&message, result, &ctx).await?;
+//! }
+//! ```
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use prost::Message;
+use tracing_futures::Instrument;
+use uuid::Uuid;
+
+use crate::codec::Codec;
+use crate::proto::google::rpc::Status;
+use crate::service::rendezvous;
+use crate::util::redis_exec;
+
+use super::redis::RedisMessage;
+use super::redis::RedisQueue;
+use super::redis::RedisQueueError;
+use super::Context;
+use super::Performable;
+use super::Queue;
+
+/// Time a consumer holds a partition by default, renewed with [`OrderedQueue::renew`].
+pub const DEFAULT_PARTITION_LEASE: Duration = Duration::from_secs(60);
+
+const RELEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+const RENEW_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+";
+
+/// A queue delivering the tasks of an ordering key in order, see the [module](self).
+#[derive(Clone, Debug)]
+pub struct OrderedQueue<T, C: Codec> {
+  queue: String,
+  partitions: Vec<RedisQueue<T, C>>,
+  names: Vec<String>,
+  lease: Duration,
+  next: Arc<AtomicUsize>,
+}
+
+/// A task pulled from an [`OrderedQueue`]. The partition stays leased to the consumer until the
+/// operation is completed or the lease is released.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OrderedMessage<T> {
+  pub message: RedisMessage<T>,
+  /// Index of the partition the task was pulled from.
+  pub partition: usize,
+  lease_token: String,
+}
+
+impl<T> super::Task<T> for OrderedMessage<T> {
+  fn ack_id(&self) -> &str {
+    &self.message.ack_id
+  }
+
+  fn data(&self) -> &T {
+    &self.message.data
+  }
+
+  fn user_id(&self) -> Option<&str> {
+    super::Task::user_id(&self.message)
+  }
+
+  fn context(&self) -> Option<&Context> {
+    Some(&self.message.context)
+  }
+}
+
+impl<T, C> OrderedQueue<T, C>
+where
+  C: Codec,
+  RedisQueue<T, C>: Clone,
+{
+  /// Splits `queue` into `partitions` partitions, at least one, configured as `queue`.
+  pub fn new(queue: RedisQueue<T, C>, partitions: usize) -> Self {
+    let names: Vec<String> = (0..partitions.max(1))
+      .map(|partition| format!("{}:{}", queue.name(), partition))
+      .collect();
+
+    Self {
+      queue: queue.name().to_string(),
+      partitions: names
+        .iter()
+        .map(|name| queue.renamed(name.clone()))
+        .collect(),
+      names,
+      lease: DEFAULT_PARTITION_LEASE,
+      next: Arc::default(),
+    }
+  }
+
+  /// Sets how long a consumer holds a partition without renewing it, after which the partition
+  /// goes to another consumer and the task in flight is delivered again.
+  pub fn with_lease(mut self, lease: Duration) -> Self {
+    self.lease = lease;
+    self
+  }
+
+  pub fn name(&self) -> &str {
+    &self.queue
+  }
+
+  /// The partitions, e.g. to pause them or list their invalid messages.
+  pub fn partitions(&self) -> &[RedisQueue<T, C>] {
+    &self.partitions
+  }
+
+  /// Index of the partition of `ordering_key`. Stable across processes, but changes for some keys
+  /// when the number of partitions changes.
+  pub fn partition_of(&self, ordering_key: &str) -> usize {
+    rendezvous(ordering_key, &self.names)
+      .and_then(|name| self.names.iter().position(|other| other == name))
+      .unwrap_or_default()
+  }
+
+  async fn acquire(&self, partition: usize) -> Result<Option<String>, RedisQueueError> {
+    let queue = &self.partitions[partition];
+    let mut conn = redis_exec::connect(queue.client()).await?;
+    let token = Uuid::new_v4().to_string();
+
+    let acquired: Option<String> = redis::cmd("SET")
+      .arg(queue.keys().partition_lease(queue.name()))
+      .arg(&token)
+      .arg("NX")
+      .arg("PX")
+      .arg(self.lease.as_millis() as u64)
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-ordered-acquire", queue = %queue.name()))
+      .await?;
+
+    Ok(acquired.map(|_| token))
+  }
+
+  async fn release_partition(&self, partition: usize, token: &str) -> Result<(), RedisQueueError> {
+    let queue = &self.partitions[partition];
+    let mut conn = redis_exec::connect(queue.client()).await?;
+
+    let _: i64 = redis::Script::new(RELEASE_SCRIPT)
+      .key(queue.keys().partition_lease(queue.name()))
+      .arg(token)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-ordered-release", queue = %queue.name()))
+      .await?;
+
+    Ok(())
+  }
+
+  /// Extends the lease of the partition of `message` by the lease duration, for tasks running
+  /// longer than it. Returns `false` when the lease was lost, in which case the task is being
+  /// delivered to another consumer.
+  pub async fn renew(&self, message: &OrderedMessage<T>) -> Result<bool, RedisQueueError> {
+    let queue = &self.partitions[message.partition];
+    let mut conn = redis_exec::connect(queue.client()).await?;
+
+    let renewed: i64 = redis::Script::new(RENEW_SCRIPT)
+      .key(queue.keys().partition_lease(queue.name()))
+      .arg(&message.lease_token)
+      .arg(self.lease.as_millis() as u64)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-ordered-renew", queue = %queue.name()))
+      .await?;
+
+    Ok(renewed == 1)
+  }
+
+  /// Gives up the partition of `message` without completing its operation, which stays in flight
+  /// and is delivered again to the next consumer of the partition.
+  pub async fn release(&self, message: &OrderedMessage<T>) -> Result<(), RedisQueueError> {
+    self
+      .release_partition(message.partition, &message.lease_token)
+      .await
+  }
+}
+
+impl<T, C> OrderedQueue<T, C>
+where
+  T: Send + Sync + Performable + 'static,
+  C: Codec<Encodable = T, Decodable = T> + Send + Sync,
+  C::EncodingError: std::error::Error + Send + Sync + 'static,
+  C::DecodingError: std::error::Error + Send + Sync + 'static,
+  RedisQueue<T, C>: Clone,
+{
+  /// Enqueues `item` in the partition of `ordering_key`, after the tasks of the key offered
+  /// before. Returns the operation id.
+  pub async fn offer_keyed(
+    &self,
+    item: T,
+    ordering_key: &str,
+    ctx: &Context,
+  ) -> Result<String, RedisQueueError> {
+    self.partitions[self.partition_of(ordering_key)]
+      .offer(item, ctx)
+      .await
+  }
+
+  /// Pulls the next task of the first partition not leased by another consumer, and leases the
+  /// partition. Operations left in flight by a previous consumer of the partition, e.g. one that
+  /// died, are delivered first. Returns `None` when every free partition is empty.
+  pub async fn pull(&self, ctx: &Context) -> Result<Option<OrderedMessage<T>>, RedisQueueError> {
+    let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+    for offset in 0..self.partitions.len() {
+      let partition = (start + offset) % self.partitions.len();
+      let lease_token = match self.acquire(partition).await? {
+        Some(lease_token) => lease_token,
+        None => continue,
+      };
+
+      let queue = &self.partitions[partition];
+      // The lease is exclusive, so whatever is in flight was abandoned by a previous consumer.
+      let pulled = match queue.recover_expired(Duration::ZERO).await {
+        Ok(_) => queue.pull(ctx).await,
+        Err(error) => Err(error),
+      };
+
+      match pulled {
+        Ok(Some(message)) => {
+          return Ok(Some(OrderedMessage {
+            message,
+            partition,
+            lease_token,
+          }))
+        }
+        Ok(None) => self.release_partition(partition, &lease_token).await?,
+        Err(error) => {
+          self.release_partition(partition, &lease_token).await?;
+          return Err(error);
+        }
+      }
+    }
+
+    Ok(None)
+  }
+
+  /// Records the result of `message` and releases its partition, letting the next task of the
+  /// partition be pulled.
+  pub async fn complete<M: Message, E: Into<Status>>(
+    &self,
+    message: &OrderedMessage<T>,
+    result: Result<M, E>,
+    ctx: &Context,
+  ) -> Result<(), RedisQueueError> {
+    self.partitions[message.partition]
+      .complete(&message.message.ack_id, result, ctx)
+      .await?;
+    self.release(message).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde::Deserialize;
+  use serde::Serialize;
+
+  use crate::codec::json::JsonCodec;
+  use crate::proto::google::protobuf::Empty;
+  use crate::redis::Keys;
+
+  use super::*;
+
+  #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+  struct Task {
+    item: i32,
+  }
+
+  #[async_trait::async_trait]
+  impl Performable for Task {
+    type Error = tonic::Status;
+    type Context = ();
+    type Output = Empty;
+
+    fn type_name() -> &'static str {
+      "longrunning::ordered::tests::Task"
+    }
+
+    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+      Ok(Empty::default())
+    }
+  }
+
+  fn queue(partitions: usize) -> OrderedQueue<Task, JsonCodec<Task, Task>> {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let queue = RedisQueue::new(client, Uuid::new_v4().to_string(), JsonCodec::new())
+      .with_keys(Keys::new(&format!("{}:", Uuid::new_v4())));
+    OrderedQueue::new(queue, partitions)
+  }
+
+  #[test]
+  fn partition_of_should_be_stable() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let partitioned = |partitions| {
+      let queue: RedisQueue<Task, JsonCodec<Task, Task>> =
+        RedisQueue::new(client.clone(), "workspaces".to_string(), JsonCodec::new());
+      OrderedQueue::new(queue, partitions)
+    };
+    let (first, second) = (partitioned(8), partitioned(8));
+
+    for key in (0..100).map(|i| format!("workspace-{}", i)) {
+      assert_eq!(first.partition_of(&key), second.partition_of(&key));
+      assert!(first.partition_of(&key) < 8);
+    }
+    assert_eq!(first.partitions()[3].name(), "workspaces:3");
+    assert_eq!(partitioned(0).partitions().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn pull_should_deliver_a_key_in_order_to_one_consumer_at_a_time() {
+    let queue = queue(4);
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+
+    queue
+      .offer_keyed(Task { item: 1 }, "a", &ctx)
+      .await
+      .unwrap();
+    queue
+      .offer_keyed(Task { item: 2 }, "a", &ctx)
+      .await
+      .unwrap();
+
+    let first = queue.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(first.message.data, Task { item: 1 });
+    assert_eq!(first.partition, queue.partition_of("a"));
+
+    // The partition of `a` is leased until its first task completes.
+    assert_eq!(queue.pull(&ctx).await.unwrap(), None);
+
+    queue
+      .complete(&first, Ok::<_, Status>(Empty::default()), &ctx)
+      .await
+      .unwrap();
+    let second = queue.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(second.message.data, Task { item: 2 });
+
+    // A consumer giving up its partition leaves the task to the next one.
+    queue.release(&second).await.unwrap();
+    let again = queue.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(again.message.ack_id, second.message.ack_id);
+  }
+}
@@ -0,0 +1,165 @@
+//! Size limits of the encoded payloads offered to a queue, so one misbehaving producer cannot fill
+//! Redis with multi-megabyte tasks.
+//!
+//! Payloads above the soft limit are enqueued but logged and counted in the process wide
+//! [`metrics`], those above the hard limit are rejected with [`PayloadTooLarge`]:
+//!
+//! ```rust,ignore
+//! let queue = RedisQueue::new(client, "exports".to_string(), JsonCodec::new())
+//!   .with_payload_limits(PayloadLimits::new().with_soft_limit(64 * 1024).with_hard_limit(1 << 20));
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+use serde::Deserialize;
+
+static METRICS: PayloadMetrics = PayloadMetrics::new();
+
+/// Payloads counted by every queue of the process.
+pub fn metrics() -> &'static PayloadMetrics {
+  &METRICS
+}
+
+/// Error of the offers whose encoded payload exceeds the hard limit of the queue.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Payload of {size} bytes exceeds the limit of {limit} bytes of queue {queue}")]
+pub struct PayloadTooLarge {
+  pub queue: String,
+  pub size: usize,
+  pub limit: usize,
+}
+
+/// Soft and hard limits in bytes of the encoded payloads of a queue, unlimited when unset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct PayloadLimits {
+  #[serde(default)]
+  pub soft_bytes: Option<usize>,
+  #[serde(default)]
+  pub hard_bytes: Option<usize>,
+}
+
+impl PayloadLimits {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Logs and counts the payloads above `bytes`, see [`metrics`].
+  pub fn with_soft_limit(mut self, bytes: usize) -> Self {
+    self.soft_bytes = Some(bytes);
+    self
+  }
+
+  /// Rejects the payloads above `bytes` with [`PayloadTooLarge`].
+  pub fn with_hard_limit(mut self, bytes: usize) -> Self {
+    self.hard_bytes = Some(bytes);
+    self
+  }
+
+  /// Checks a payload of `size` bytes of `task_type` offered to `queue`, recording it in
+  /// [`metrics`] when it exceeds a limit.
+  pub fn check(&self, queue: &str, task_type: &str, size: usize) -> Result<(), PayloadTooLarge> {
+    if let Some(limit) = self.hard_bytes.filter(|limit| size > *limit) {
+      METRICS.record(queue, REJECTED);
+      tracing::warn!(message = "Rejected oversized payload", %queue, %task_type, size, limit);
+      return Err(PayloadTooLarge {
+        queue: queue.to_string(),
+        size,
+        limit,
+      });
+    }
+
+    if let Some(limit) = self.soft_bytes.filter(|limit| size > *limit) {
+      METRICS.record(queue, OVERSIZED);
+      tracing::warn!(message = "Payload exceeds the soft limit", %queue, %task_type, size, limit);
+    }
+
+    Ok(())
+  }
+}
+
+/// Outcome of the payloads above the soft limit, enqueued anyway.
+pub const OVERSIZED: &str = "oversized";
+
+/// Outcome of the payloads above the hard limit.
+pub const REJECTED: &str = "rejected";
+
+/// Payloads above the limits of their queue, rendered in the Prometheus text format.
+#[derive(Debug)]
+pub struct PayloadMetrics {
+  payloads: Mutex<BTreeMap<(String, &'static str), u64>>,
+}
+
+impl PayloadMetrics {
+  const fn new() -> Self {
+    Self {
+      payloads: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  fn record(&self, queue: &str, outcome: &'static str) {
+    let mut payloads = self.payloads.lock().unwrap_or_else(PoisonError::into_inner);
+    *payloads.entry((queue.to_string(), outcome)).or_default() += 1;
+  }
+
+  /// Number of payloads of `queue` that were [`OVERSIZED`] or [`REJECTED`].
+  pub fn count(&self, queue: &str, outcome: &'static str) -> u64 {
+    self
+      .payloads
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .get(&(queue.to_string(), outcome))
+      .copied()
+      .unwrap_or_default()
+  }
+
+  pub fn render(&self) -> String {
+    let mut out = String::default();
+
+    let _ = writeln!(out, "# TYPE rappel_oversized_payloads_total counter");
+    let payloads = self.payloads.lock().unwrap_or_else(PoisonError::into_inner);
+    for ((queue, outcome), count) in payloads.iter() {
+      let _ = writeln!(
+        out,
+        "rappel_oversized_payloads_total{{queue=\"{}\",outcome=\"{}\"}} {}",
+        queue, outcome, count
+      );
+    }
+
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn check_should_count_soft_and_reject_hard_overflows() {
+    let limits = PayloadLimits::new()
+      .with_soft_limit(10)
+      .with_hard_limit(100);
+
+    assert!(limits.check("payload-limits", "Export", 10).is_ok());
+    assert!(limits.check("payload-limits", "Export", 11).is_ok());
+    assert_eq!(
+      limits.check("payload-limits", "Export", 101),
+      Err(PayloadTooLarge {
+        queue: "payload-limits".to_string(),
+        size: 101,
+        limit: 100,
+      })
+    );
+    assert!(PayloadLimits::new()
+      .check("payload-limits", "Export", 1 << 30)
+      .is_ok());
+
+    assert_eq!(metrics().count("payload-limits", OVERSIZED), 1);
+    assert_eq!(metrics().count("payload-limits", REJECTED), 1);
+    assert!(metrics().render().contains(
+      "rappel_oversized_payloads_total{queue=\"payload-limits\",outcome=\"rejected\"} 1"
+    ));
+  }
+}
@@ -0,0 +1,570 @@
+//! Supervision of the child processes tasks shell out to, e.g. image builders or CLIs.
+//!
+//! A [`Supervised`] process has its output streamed in chunks while it runs and the tail of it
+//! kept for the operation metadata, is killed when it exceeds its timeout or when the task is
+//! dropped, is terminated when the operation is cancelled, and can be confined to a cgroup
+//! limiting its CPU and memory on Linux.
+//!
+//! ```rust,ignore
+//! async fn perform(&self, ctx: TaskContext) -> Result<Image, Self::Error> {
+//!   let (chunks, mut rx) = tokio::sync::mpsc::channel(16);
+//!   tokio::spawn(async move {
+//!     while let Some(chunk) = rx.recv().await {
+//!       queue.report_progress(&id, &user_id, chunk.metadata()).await.ok();
+//!     }
+//!   });
+//!
+//!   let output = Supervised::new("buildah")
+//!     .with_args(["bud", "-t", &self.tag, "."])
+//!     .with_timeout(Duration::from_secs(1800))
+//!     .with_limits(ResourceLimits::new().with_cpus(2.0).with_memory(4 << 30))
+//!     .with_cancellation(ctx.cancellation().clone())
+//!     .run_streaming(chunks)
+//!     .await?
+//!     .check()?;
+//!   ...
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+use crate::util::shutdown::ShutdownToken;
+
+/// Bytes of each output stream kept in [`ProcessOutput`] by default.
+pub const DEFAULT_OUTPUT_TAIL: usize = 64 * 1024;
+
+/// Parent of the cgroups created for limited processes by default, on the cgroup v2 hierarchy.
+pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup/rappel";
+
+/// Time a cancelled process is given to exit after `SIGTERM` before it is killed, by default.
+pub const DEFAULT_TERMINATION_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessError {
+  #[error("Failed to spawn {program}: {source}")]
+  Spawn { program: String, source: io::Error },
+
+  #[error("Failed to read the process output: {0}")]
+  Io(#[from] io::Error),
+
+  #[error("Failed to set up the cgroup {path}: {source}")]
+  Cgroup { path: PathBuf, source: io::Error },
+
+  #[error("Process timed out after {0:?}")]
+  TimedOut(Duration),
+
+  #[error("Process cancelled")]
+  Cancelled,
+
+  #[error("Process exited with {code:?}: {stderr}")]
+  Failed { code: Option<i32>, stderr: String },
+}
+
+impl From<ProcessError> for tonic::Status {
+  fn from(error: ProcessError) -> Self {
+    match error {
+      ProcessError::TimedOut(_) => tonic::Status::deadline_exceeded(error.to_string()),
+      ProcessError::Cancelled => tonic::Status::cancelled(error.to_string()),
+      ProcessError::Failed { .. } => tonic::Status::aborted(error.to_string()),
+      error => tonic::Status::internal(error.to_string()),
+    }
+  }
+}
+
+/// Output stream of a process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputStream {
+  Stdout,
+  Stderr,
+}
+
+impl OutputStream {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      OutputStream::Stdout => "stdout",
+      OutputStream::Stderr => "stderr",
+    }
+  }
+}
+
+/// Bytes read from an output stream of a running process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputChunk {
+  pub stream: OutputStream,
+  pub data: Vec<u8>,
+}
+
+impl OutputChunk {
+  /// Returns the chunk as progress attributes, keyed by stream name.
+  pub fn metadata(&self) -> HashMap<String, String> {
+    HashMap::from([(
+      self.stream.as_str().to_string(),
+      String::from_utf8_lossy(&self.data).into_owned(),
+    )])
+  }
+}
+
+/// Outcome of a process that ran to completion.
+#[derive(Clone, Debug)]
+pub struct ProcessOutput {
+  /// Exit code, `None` if the process was killed by a signal.
+  pub code: Option<i32>,
+  /// Last bytes written to stdout.
+  pub stdout: Vec<u8>,
+  /// Last bytes written to stderr.
+  pub stderr: Vec<u8>,
+  pub duration: Duration,
+}
+
+impl ProcessOutput {
+  pub fn success(&self) -> bool {
+    self.code == Some(0)
+  }
+
+  /// Fails with [`ProcessError::Failed`] unless the process exited with 0.
+  pub fn check(self) -> Result<Self, ProcessError> {
+    match self.success() {
+      true => Ok(self),
+      false => Err(ProcessError::Failed {
+        code: self.code,
+        stderr: String::from_utf8_lossy(&self.stderr).into_owned(),
+      }),
+    }
+  }
+
+  /// Returns the exit code, duration and output tails as operation metadata.
+  pub fn metadata(&self) -> HashMap<String, String> {
+    let code = self
+      .code
+      .map_or_else(|| "signal".to_string(), |code| code.to_string());
+
+    HashMap::from([
+      ("exit_code".to_string(), code),
+      (
+        "duration_ms".to_string(),
+        self.duration.as_millis().to_string(),
+      ),
+      (
+        "stdout".to_string(),
+        String::from_utf8_lossy(&self.stdout).into_owned(),
+      ),
+      (
+        "stderr".to_string(),
+        String::from_utf8_lossy(&self.stderr).into_owned(),
+      ),
+    ])
+  }
+}
+
+/// CPU and memory limits enforced through a cgroup v2, on Linux only.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+  cpus: Option<f64>,
+  memory_bytes: Option<u64>,
+  root: Option<PathBuf>,
+}
+
+impl ResourceLimits {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Caps the CPU time to `cpus` cores.
+  pub fn with_cpus(mut self, cpus: f64) -> Self {
+    self.cpus = Some(cpus);
+    self
+  }
+
+  pub fn with_memory(mut self, memory_bytes: u64) -> Self {
+    self.memory_bytes = Some(memory_bytes);
+    self
+  }
+
+  /// Creates the cgroups under `root`, [`DEFAULT_CGROUP_ROOT`] by default. The worker must be
+  /// allowed to write to it, and the `cpu` and `memory` controllers enabled for its children.
+  pub fn with_cgroup_root(mut self, root: impl Into<PathBuf>) -> Self {
+    self.root = Some(root.into());
+    self
+  }
+}
+
+/// A cgroup holding one supervised process, removed once dropped.
+struct Cgroup {
+  path: PathBuf,
+}
+
+impl Cgroup {
+  fn create(limits: &ResourceLimits) -> Result<Self, ProcessError> {
+    let root = limits
+      .root
+      .clone()
+      .unwrap_or_else(|| PathBuf::from(DEFAULT_CGROUP_ROOT));
+    let path = root.join(uuid::Uuid::new_v4().to_string());
+    let cgroup = Self { path };
+
+    cgroup.write_with(|| std::fs::create_dir_all(&cgroup.path))?;
+    if let Some(cpus) = limits.cpus {
+      let period = 100_000;
+      let quota = ((cpus * period as f64) as u64).max(1000);
+      cgroup.write("cpu.max", &format!("{} {}", quota, period))?;
+    }
+    if let Some(memory_bytes) = limits.memory_bytes {
+      cgroup.write("memory.max", &memory_bytes.to_string())?;
+      cgroup.write("memory.swap.max", "0")?;
+    }
+
+    Ok(cgroup)
+  }
+
+  fn add(&self, pid: u32) -> Result<(), ProcessError> {
+    self.write("cgroup.procs", &pid.to_string())
+  }
+
+  fn write(&self, file: &str, value: &str) -> Result<(), ProcessError> {
+    self.write_with(|| std::fs::write(self.path.join(file), value))
+  }
+
+  fn write_with(&self, write: impl FnOnce() -> io::Result<()>) -> Result<(), ProcessError> {
+    write().map_err(|source| ProcessError::Cgroup {
+      path: self.path.clone(),
+      source,
+    })
+  }
+}
+
+impl Drop for Cgroup {
+  fn drop(&mut self) {
+    // Fails while processes are left in the cgroup, e.g. daemons forked by the child.
+    if let Err(error) = std::fs::remove_dir(&self.path) {
+      tracing::warn!(message = "Failed to remove cgroup", path = %self.path.display(), %error);
+    }
+  }
+}
+
+/// A child process run to completion under supervision, see the [module](self) documentation.
+#[derive(Clone, Debug)]
+pub struct Supervised {
+  program: String,
+  args: Vec<String>,
+  env: Vec<(String, String)>,
+  cwd: Option<PathBuf>,
+  timeout: Option<Duration>,
+  limits: Option<ResourceLimits>,
+  output_tail: usize,
+  cancellation: Option<ShutdownToken>,
+  termination_grace: Duration,
+}
+
+impl Supervised {
+  pub fn new(program: &str) -> Self {
+    Self {
+      program: program.to_string(),
+      args: Vec::default(),
+      env: Vec::default(),
+      cwd: None,
+      timeout: None,
+      limits: None,
+      output_tail: DEFAULT_OUTPUT_TAIL,
+      cancellation: None,
+      termination_grace: DEFAULT_TERMINATION_GRACE,
+    }
+  }
+
+  pub fn with_args<I, S>(mut self, args: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+  {
+    self
+      .args
+      .extend(args.into_iter().map(|arg| arg.as_ref().to_string()));
+    self
+  }
+
+  pub fn with_env(mut self, key: &str, value: &str) -> Self {
+    self.env.push((key.to_string(), value.to_string()));
+    self
+  }
+
+  pub fn with_cwd(mut self, cwd: impl Into<PathBuf>) -> Self {
+    self.cwd = Some(cwd.into());
+    self
+  }
+
+  /// Kills the process once it ran for `timeout`.
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  /// Terminates the process once `cancellation` is shut down, e.g. the
+  /// [`super::TaskContext::cancellation`] of the operation: it is sent `SIGTERM`, then killed if
+  /// it is still running after the termination grace, and the run fails with
+  /// [`ProcessError::Cancelled`].
+  pub fn with_cancellation(mut self, cancellation: ShutdownToken) -> Self {
+    self.cancellation = Some(cancellation);
+    self
+  }
+
+  /// Gives a cancelled process `grace` to exit before killing it, [`DEFAULT_TERMINATION_GRACE`] by
+  /// default.
+  pub fn with_termination_grace(mut self, grace: Duration) -> Self {
+    self.termination_grace = grace;
+    self
+  }
+
+  /// Confines the process to a cgroup enforcing `limits`. The process is moved into the cgroup
+  /// right after it is spawned, so its very first instructions run unconfined.
+  pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+    self.limits = Some(limits);
+    self
+  }
+
+  /// Keeps the last `bytes` of each output stream in [`ProcessOutput`], [`DEFAULT_OUTPUT_TAIL`]
+  /// by default.
+  pub fn with_output_tail(mut self, bytes: usize) -> Self {
+    self.output_tail = bytes;
+    self
+  }
+
+  /// Runs the process to completion.
+  pub async fn run(&self) -> Result<ProcessOutput, ProcessError> {
+    let (chunks, _) = mpsc::channel(1);
+    self.run_streaming(chunks).await
+  }
+
+  /// Runs the process to completion, sending its output to `chunks` as it is written. Chunks are
+  /// dropped once the receiver is gone, but the process keeps running.
+  pub async fn run_streaming(
+    &self,
+    chunks: mpsc::Sender<OutputChunk>,
+  ) -> Result<ProcessOutput, ProcessError> {
+    let cgroup = match &self.limits {
+      Some(limits) if cfg!(target_os = "linux") => Some(Cgroup::create(limits)?),
+      Some(_) => {
+        tracing::warn!(message = "Resource limits are only enforced on Linux", program = %self.program);
+        None
+      }
+      None => None,
+    };
+
+    let mut command = Command::new(&self.program);
+    command
+      .args(&self.args)
+      .envs(self.env.iter().map(|(key, value)| (key, value)))
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .kill_on_drop(true);
+    if let Some(cwd) = &self.cwd {
+      command.current_dir(cwd);
+    }
+
+    let started = Instant::now();
+    let mut child = command.spawn().map_err(|source| ProcessError::Spawn {
+      program: self.program.clone(),
+      source,
+    })?;
+
+    let pid = child.id();
+    if let (Some(cgroup), Some(pid)) = (&cgroup, pid) {
+      cgroup.add(pid)?;
+    }
+    tracing::debug!(message = "Spawned process", program = %self.program, pid = ?child.id());
+
+    let stdout = child.stdout.take().map(|stdout| {
+      follow(
+        stdout,
+        OutputStream::Stdout,
+        chunks.clone(),
+        self.output_tail,
+      )
+    });
+    let stderr = child
+      .stderr
+      .take()
+      .map(|stderr| follow(stderr, OutputStream::Stderr, chunks, self.output_tail));
+
+    let completion = async {
+      let (stdout, stderr) = futures::future::join(
+        async {
+          match stdout {
+            Some(stdout) => stdout.await,
+            None => Ok(Vec::default()),
+          }
+        },
+        async {
+          match stderr {
+            Some(stderr) => stderr.await,
+            None => Ok(Vec::default()),
+          }
+        },
+      )
+      .await;
+      let status = child.wait().await?;
+      Ok::<_, ProcessError>((status, stdout?, stderr?))
+    };
+    tokio::pin!(completion);
+
+    let cancellable = async {
+      let cancelled = match &self.cancellation {
+        Some(cancellation) => cancellation.wait(),
+        None => return completion.as_mut().await,
+      };
+
+      tokio::select! {
+        completion = completion.as_mut() => completion,
+        _ = cancelled => {
+          tracing::info!(message = "Terminating cancelled process", program = %self.program, ?pid);
+          terminate(pid);
+          if tokio::time::timeout(self.termination_grace, completion.as_mut()).await.is_err() {
+            tracing::warn!(message = "Killing cancelled process", program = %self.program, ?pid);
+          }
+          Err(ProcessError::Cancelled)
+        }
+      }
+    };
+
+    let (status, stdout, stderr) = match self.timeout {
+      Some(timeout) => match tokio::time::timeout(timeout, cancellable).await {
+        Ok(completion) => completion?,
+        // Dropping the completion drops the child, which kills it.
+        Err(_) => {
+          tracing::warn!(message = "Killing timed out process", program = %self.program, ?timeout);
+          return Err(ProcessError::TimedOut(timeout));
+        }
+      },
+      None => cancellable.await?,
+    };
+
+    let output = ProcessOutput {
+      code: status.code(),
+      stdout,
+      stderr,
+      duration: started.elapsed(),
+    };
+    tracing::debug!(message = "Process exited", program = %self.program, code = ?output.code, duration_ms = output.duration.as_millis() as u64);
+
+    drop(cgroup);
+    Ok(output)
+  }
+}
+
+/// Asks the process `pid` to exit with `SIGTERM`. It is killed when dropped on other platforms.
+#[cfg(unix)]
+fn terminate(pid: Option<u32>) {
+  if let Some(pid) = pid {
+    // SAFETY: `kill` has no memory effects, and the child is not reaped before it is waited for,
+    // so its pid was not reused.
+    unsafe {
+      libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+  }
+}
+
+#[cfg(not(unix))]
+fn terminate(_: Option<u32>) {}
+
+/// Reads `reader` to the end, sending every chunk and returning the last `tail` bytes.
+async fn follow<R: AsyncRead + Unpin>(
+  mut reader: R,
+  stream: OutputStream,
+  chunks: mpsc::Sender<OutputChunk>,
+  tail: usize,
+) -> io::Result<Vec<u8>> {
+  let mut kept = VecDeque::with_capacity(tail.min(DEFAULT_OUTPUT_TAIL));
+  let mut buf = vec![0; 8192];
+
+  loop {
+    let read = reader.read(&mut buf).await?;
+    if read == 0 {
+      return Ok(kept.into());
+    }
+
+    let data = &buf[..read];
+    kept.extend(data);
+    let excess = kept.len().saturating_sub(tail);
+    kept.drain(..excess);
+
+    let chunk = OutputChunk {
+      stream,
+      data: data.to_vec(),
+    };
+    // Output is dropped rather than stalling the process behind a slow receiver.
+    let _ = chunks.try_send(chunk);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn supervised_should_capture_output_and_enforce_timeout() {
+    let (chunks, mut rx) = mpsc::channel(16);
+    let output = Supervised::new("sh")
+      .with_args(["-c", "echo hello; echo oops >&2; exit 3"])
+      .with_output_tail(4)
+      .run_streaming(chunks)
+      .await
+      .unwrap();
+
+    assert_eq!(output.code, Some(3));
+    assert_eq!(output.stdout, b"llo\n");
+    assert_eq!(output.stderr, b"ops\n");
+    assert_eq!(output.metadata()["exit_code"], "3");
+    assert!(matches!(
+      output.check(),
+      Err(ProcessError::Failed { code: Some(3), .. })
+    ));
+
+    let mut received = Vec::default();
+    while let Some(chunk) = rx.recv().await {
+      received.push(chunk);
+    }
+    assert!(received.contains(&OutputChunk {
+      stream: OutputStream::Stdout,
+      data: b"hello\n".to_vec(),
+    }));
+
+    let error = Supervised::new("sleep")
+      .with_args(["5"])
+      .with_timeout(Duration::from_millis(50))
+      .run()
+      .await
+      .unwrap_err();
+    assert!(matches!(error, ProcessError::TimedOut(_)));
+  }
+
+  #[tokio::test]
+  async fn supervised_should_terminate_cancelled_processes() {
+    let cancellation = ShutdownToken::new();
+    let process = Supervised::new("sh")
+      .with_args(["-c", "trap 'exit 0' TERM; while true; do sleep 0.01; done"])
+      .with_cancellation(cancellation.clone())
+      .with_termination_grace(Duration::from_secs(5));
+    let running = tokio::spawn(async move { process.run().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    cancellation.shutdown();
+
+    let started = Instant::now();
+    let error = tokio::time::timeout(Duration::from_secs(5), running)
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap_err();
+    assert!(matches!(error, ProcessError::Cancelled));
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+}
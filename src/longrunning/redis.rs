@@ -1,7 +1,14 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
 
+use chrono::DateTime;
+use chrono::TimeZone;
 use chrono::Utc;
+use futures::Stream;
+use futures::StreamExt;
 use prost::Message;
 use redis::from_redis_value;
 use redis::AsyncCommands;
@@ -9,84 +16,777 @@ use redis::FromRedisValue;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tracing_futures::Instrument;
-use uuid::Uuid;
 
 use crate::codec::json::JsonCodec;
 use crate::codec::Codec;
 use crate::codec::Decoder;
+use crate::codec::DynCodec;
 use crate::codec::Encoder;
+use crate::id::IdError;
+use crate::id::SharedIdGenerator;
+use crate::id::UuidV7Generator;
 use crate::proto::google::rpc::Status;
 use crate::proto::longrunning::Operation;
-
+use crate::proto::longrunning::OperationEvent;
+use crate::proto::longrunning::OperationEventType;
+use crate::proto::longrunning::OperationState as ProtoOperationState;
+use crate::proto::longrunning::StreamOperationsRequest;
+use crate::proto::prelude::ProstTimestamp;
+use crate::quota;
+use crate::quota::QuotaError;
+use crate::quota::RedisQuota;
+use crate::redis::Keys;
+use crate::redis::ProtoValue;
+use crate::redis::RedisRegistry;
+use crate::redis::RedisRole;
+use crate::service::Priority;
+use crate::util::clock;
+use crate::util::clock::SharedClock;
+use crate::util::error_context::Contextual;
+use crate::util::error_context::ErrorContext;
+use crate::util::error_context::ResultExt;
+use crate::util::redis_exec;
+use crate::util::redis_exec::InstrumentedConnection;
+use crate::util::shutdown::ShutdownToken;
+
+use super::admin::RedisAdmin;
+use super::cost::CostMeter;
+use super::cost::OperationUsage;
+use super::envelope::ContextEnvelopeError;
+use super::envelope::JsonContextSerializer;
+use super::envelope::SharedContextSerializer;
+use super::failure;
+use super::failure::Failure;
+use super::index;
+use super::maintenance;
+use super::maintenance::EnqueuePolicy;
+use super::maintenance::MaintenanceMode;
+use super::payload::PayloadLimits;
+use super::payload::PayloadTooLarge;
+use super::replication::ReplicationEvent;
+use super::skew;
+use super::sla::SlaTracker;
+use super::store::RedisTaskStore;
+use super::store::COMPLETE_SCRIPT;
+use super::AckMode;
 use super::Broker;
 use super::Context;
+use super::EventBus;
+use super::OperationState;
 use super::Performable;
 use super::Queue;
+use super::TaskStore;
 
 #[derive(Debug, thiserror::Error)]
 pub enum BrokerError {
   #[error("Failed to enqueue the task: {0}")]
   QueueError(#[from] RedisQueueError),
+
+  #[error("Failed to cancel the operation: {0}")]
+  CancelError(RedisQueueError),
+
+  #[error("Shutting down")]
+  ShuttingDown,
+
+  #[error("No queue is routed task type {0}")]
+  Unrouted(&'static str),
 }
 
-#[derive(Clone, Debug)]
-pub struct RedisBroker<T: Serialize + DeserializeOwned + Performable> {
-  _client: redis::Client,
+/// The context of a broker error is the one of its queue error, see [`RedisQueueError::Context`].
+/// Shutting down and unrouted task types are about no queue, so they carry none.
+impl Contextual for BrokerError {
+  fn with_context(self, context: ErrorContext) -> Self {
+    match self {
+      BrokerError::QueueError(error) => BrokerError::QueueError(error.with_context(context)),
+      BrokerError::CancelError(error) => BrokerError::CancelError(error.with_context(context)),
+      error @ (BrokerError::ShuttingDown | BrokerError::Unrouted(_)) => error,
+    }
+  }
+
+  fn context(&self) -> Option<&ErrorContext> {
+    match self {
+      BrokerError::QueueError(error) | BrokerError::CancelError(error) => error.context(),
+      BrokerError::ShuttingDown | BrokerError::Unrouted(_) => None,
+    }
+  }
+}
+
+/// Fails with `RESOURCE_EXHAUSTED` and a `google.rpc.QuotaFailure` detail when a quota is exceeded,
+/// with `UNAVAILABLE` during maintenance or shutdown, with `PERMISSION_DENIED` when the principal
+/// may not impersonate the user, with `INVALID_ARGUMENT` when the payload exceeds the hard limit
+/// of the queue, with `FAILED_PRECONDITION` when no queue is routed the task type, and with
+/// `ABORTED` when a concurrent write won. The context of the error is appended to the message.
+impl From<BrokerError> for tonic::Status {
+  fn from(error: BrokerError) -> Self {
+    let (error, context) = match error {
+      BrokerError::QueueError(error) => {
+        let (error, context) = error.split();
+        (BrokerError::QueueError(error), context)
+      }
+      BrokerError::CancelError(error) => {
+        let (error, context) = error.split();
+        (BrokerError::CancelError(error), context)
+      }
+      error => (error, None),
+    };
+
+    let status = match error {
+      BrokerError::QueueError(RedisQueueError::Quota(error)) => error.into(),
+      BrokerError::QueueError(RedisQueueError::Maintenance(error)) => {
+        tonic::Status::unavailable(error.to_string())
+      }
+      BrokerError::ShuttingDown => {
+        tonic::Status::unavailable(BrokerError::ShuttingDown.to_string())
+      }
+      BrokerError::QueueError(RedisQueueError::PermissionDenied(error)) => {
+        tonic::Status::permission_denied(error)
+      }
+      BrokerError::QueueError(RedisQueueError::PayloadTooLarge(error)) => {
+        tonic::Status::invalid_argument(error.to_string())
+      }
+      error @ BrokerError::Unrouted(_) => tonic::Status::failed_precondition(error.to_string()),
+      BrokerError::QueueError(RedisQueueError::Conflict(error))
+      | BrokerError::CancelError(RedisQueueError::Conflict(error)) => tonic::Status::aborted(error),
+      error => tonic::Status::internal(error.to_string()),
+    };
+
+    match context {
+      Some(context) => tonic::Status::with_details(
+        status.code(),
+        format!("{} ({})", status.message(), context),
+        bytes::Bytes::copy_from_slice(status.details()),
+      ),
+      None => status,
+    }
+  }
+}
+
+/// Enqueues tasks on a [`RedisQueue`] and cancels their operations. The operations are looked up
+/// in a [`TaskStore`], see [`RedisBroker::with_store`].
+///
+/// A broker is a handle: clones are cheap and share their queue and settings. Configuring a clone
+/// copies the settings, leaving the other clones as they were.
+#[derive(Debug)]
+pub struct RedisBroker<T: Serialize + DeserializeOwned + Performable, S = RedisTaskStore> {
+  inner: Arc<BrokerState<T, S>>,
+}
+
+#[derive(Debug)]
+struct BrokerState<T: Serialize + DeserializeOwned + Performable, S> {
   queue: RedisQueue<T, JsonCodec<T, T>>,
-  _phantom: PhantomData<T>,
+  store: S,
+  admin: RedisAdmin,
+  shutdown: Option<ShutdownToken>,
+}
+
+impl<T: Serialize + DeserializeOwned + Performable, S: Clone> Clone for BrokerState<T, S> {
+  fn clone(&self) -> Self {
+    Self {
+      queue: self.queue.clone(),
+      store: self.store.clone(),
+      admin: self.admin.clone(),
+      shutdown: self.shutdown.clone(),
+    }
+  }
+}
+
+impl<T: Serialize + DeserializeOwned + Performable, S> Clone for RedisBroker<T, S> {
+  fn clone(&self) -> Self {
+    Self {
+      inner: self.inner.clone(),
+    }
+  }
 }
 
 impl<T: Send + Sync + Serialize + DeserializeOwned + Performable> RedisBroker<T> {
+  /// Looks the operations up in a [`RedisTaskStore`] of `client`.
   pub fn new(client: redis::Client, queue_name: &str) -> Self {
     Self {
-      _client: client.clone(),
-      queue: RedisQueue::new(client, queue_name.to_string(), JsonCodec::new()),
-      _phantom: PhantomData,
+      inner: Arc::new(BrokerState {
+        store: RedisTaskStore::new(client.clone()),
+        admin: RedisAdmin::new(client.clone()),
+        queue: RedisQueue::new(client, queue_name.to_string(), JsonCodec::new()),
+        shutdown: None,
+      }),
+    }
+  }
+
+  /// Uses the [`RedisRole::Queue`] connection of `registry` for the queue and its operations,
+  /// and the [`RedisRole::PubSub`] one for their events.
+  pub fn from_registry(registry: &RedisRegistry, queue_name: &str) -> Self {
+    let keys = registry.keys().clone();
+    let events = RedisEventBus::new(
+      registry.client(RedisRole::PubSub),
+      &keys.channel(OPERATION_EVENTS_CHANNEL),
+    );
+
+    Self::new(registry.client(RedisRole::Queue), queue_name)
+      .with_keys(keys)
+      .configure(|state| BrokerState {
+        admin: state.admin.with_events(events.clone()),
+        queue: state.queue.with_events(events),
+        ..state
+      })
+  }
+
+  /// Stores the queue and operations under the prefix of `keys`.
+  pub fn with_keys(self, keys: Keys) -> Self {
+    self.configure(|state| BrokerState {
+      store: state.store.with_keys(keys.clone()),
+      admin: state.admin.with_keys(keys.clone()),
+      queue: state.queue.with_keys(keys),
+      ..state
+    })
+  }
+}
+
+impl<T, S> RedisBroker<T, S>
+where
+  T: Send + Sync + Serialize + DeserializeOwned + Performable,
+  S: TaskStore + Clone,
+{
+  /// Looks the operations up in `store` rather than in the Redis of the queue, e.g. when the
+  /// operations are kept in another database.
+  pub fn with_store<U: TaskStore + Clone>(self, store: U) -> RedisBroker<T, U> {
+    let state = Arc::unwrap_or_clone(self.inner);
+    RedisBroker {
+      inner: Arc::new(BrokerState {
+        queue: state.queue,
+        store,
+        admin: state.admin,
+        shutdown: state.shutdown,
+      }),
+    }
+  }
+
+  /// Enforces the concurrent operations quota on enqueue, see [`RedisQueue::with_quota`].
+  pub fn with_quota(self, quota: RedisQuota) -> Self {
+    self.configure(|state| BrokerState {
+      queue: state.queue.with_quota(quota),
+      ..state
+    })
+  }
+
+  /// Enables replication on the underlying queue, see [`RedisQueue::with_replication`].
+  pub fn with_replication(self, region: &str) -> Self {
+    self.configure(|state| BrokerState {
+      queue: state.queue.with_replication(region),
+      ..state
+    })
+  }
+
+  /// Limits the size of the enqueued payloads, see [`RedisQueue::with_payload_limits`].
+  pub fn with_payload_limits(self, limits: PayloadLimits) -> Self {
+    self.configure(|state| BrokerState {
+      queue: state.queue.with_payload_limits(limits),
+      ..state
+    })
+  }
+
+  /// Rejects the enqueues with [`BrokerError::ShuttingDown`] once `shutdown` is shut down.
+  /// Cancellations and lookups keep working.
+  pub fn with_shutdown(self, shutdown: ShutdownToken) -> Self {
+    self.configure(|state| BrokerState {
+      shutdown: Some(shutdown),
+      ..state
+    })
+  }
+
+  fn configure(self, f: impl FnOnce(BrokerState<T, S>) -> BrokerState<T, S>) -> Self {
+    Self {
+      inner: Arc::new(f(Arc::unwrap_or_clone(self.inner))),
+    }
+  }
+
+  fn accepting(&self) -> Result<(), BrokerError> {
+    match &self.inner.shutdown {
+      Some(shutdown) if shutdown.is_shutdown() => Err(BrokerError::ShuttingDown),
+      _ => Ok(()),
     }
   }
 }
 
 #[async_trait::async_trait]
-impl<T: Performable> Broker<T> for RedisBroker<T>
+impl<T: Performable, S> Broker<T> for RedisBroker<T, S>
 where
-  T: Send + Sync + Serialize + DeserializeOwned,
+  T: Send + Sync + Serialize + DeserializeOwned + 'static,
+  S: TaskStore + Clone + 'static,
+  BrokerError: From<S::Error>,
 {
   type Error = BrokerError;
 
   async fn enqueue(&self, task: T, ctx: &Context) -> Result<Operation, Self::Error> {
-    let id = self.queue.offer(task, ctx).await?;
+    self.accepting()?;
+    let id = self
+      .inner
+      .queue
+      .offer(task, ctx)
+      .await
+      .with_context(|| ErrorContext::new().with_queue(self.inner.queue.name()))?;
+    Ok(queued::<T>(id, self.inner.queue.name(), ctx))
+  }
 
-    let operation = Operation {
-      operation_id: id,
-      metadata: HashMap::default(),
-      done: false,
-      error: None,
-      response: HashMap::default(),
-      creation_ts: None,
-      start_ts: None,
-      end_ts: None,
+  async fn enqueue_all(&self, tasks: Vec<T>, ctx: &Context) -> Result<Vec<Operation>, Self::Error> {
+    self.accepting()?;
+    let ids = self
+      .inner
+      .queue
+      .offer_all(tasks, ctx)
+      .await
+      .with_context(|| ErrorContext::new().with_queue(self.inner.queue.name()))?;
+    Ok(
+      ids
+        .into_iter()
+        .map(|id| queued::<T>(id, self.inner.queue.name(), ctx))
+        .collect(),
+    )
+  }
+
+  async fn cancel(&self, id: &str, ctx: &Context) -> Result<Operation, Self::Error> {
+    let reason = format!("Cancelled by {}", ctx.user_id());
+    self
+      .inner
+      .admin
+      .cancel(id, &reason)
+      .await
+      .with_context(|| self.inner.queue.error_context(id))
+      .map_err(BrokerError::CancelError)
+  }
+
+  async fn schedule_at(
+    &self,
+    task: T,
+    at: DateTime<Utc>,
+    ctx: &Context,
+  ) -> Result<Operation, Self::Error> {
+    self.accepting()?;
+    let id = self
+      .inner
+      .queue
+      .offer_at(task, at, ctx)
+      .await
+      .with_context(|| ErrorContext::new().with_queue(self.inner.queue.name()))?;
+    Ok(queued::<T>(id, self.inner.queue.name(), ctx))
+  }
+
+  /// Concurrent calls with the same key write a single operation, the one claiming the key,
+  /// which the others return.
+  async fn enqueue_unique(
+    &self,
+    task: T,
+    key: &str,
+    ctx: &Context,
+  ) -> Result<Operation, Self::Error> {
+    let unique = self.inner.queue.keys().unique(self.inner.queue.name(), key);
+    let unique_context = || {
+      ErrorContext::new()
+        .with_queue(self.inner.queue.name())
+        .with_key(&unique)
     };
+    let mut conn = redis_exec::connect(self.inner.queue.client())
+      .await
+      .map_err(RedisQueueError::from)
+      .with_context(unique_context)?;
+
+    let current: Option<String> = conn
+      .get(&unique)
+      .await
+      .map_err(RedisQueueError::from)
+      .with_context(unique_context)?;
+    if let Some(id) = &current {
+      match self.status(id).await? {
+        Some(operation) if !operation.done => return Ok(operation),
+        _ => {}
+      }
+    }
+
+    self.accepting()?;
+    let (winner, enqueued) = self
+      .inner
+      .queue
+      .offer_unique(task, &unique, current.as_deref(), ctx)
+      .instrument(tracing::info_span!("redis-broker-claim-unique", %key))
+      .await
+      .with_context(unique_context)?;
+
+    if enqueued {
+      return Ok(queued::<T>(winner, self.inner.queue.name(), ctx));
+    }
+
+    self.status(&winner).await?.ok_or_else(|| {
+      RedisQueueError::NotFound(format!("No operation with operation_id = {}", winner)).into()
+    })
+  }
+
+  async fn status(&self, id: &str) -> Result<Option<Operation>, Self::Error> {
+    self
+      .inner
+      .store
+      .get(id)
+      .await
+      .map_err(|error| BrokerError::from(error).with_context(self.inner.queue.error_context(id)))
+  }
+
+  async fn find_by_key(&self, key: &str) -> Result<Option<Operation>, Self::Error> {
+    let unique = self.inner.queue.keys().unique(self.inner.queue.name(), key);
+    let mut conn = redis_exec::connect(self.inner.queue.client())
+      .await
+      .map_err(RedisQueueError::from)?;
+
+    let id: Option<String> = conn.get(&unique).await.map_err(RedisQueueError::from)?;
+    match id {
+      Some(id) => self.status(&id).await,
+      None => Ok(None),
+    }
+  }
+}
 
-    Ok(operation)
+/// The operation `id`, just enqueued on `queue`.
+fn queued<T: Performable>(id: String, queue: &str, ctx: &Context) -> Operation {
+  Operation {
+    operation_id: id,
+    metadata: HashMap::default(),
+    task_type: T::type_name().to_string(),
+    queue: queue.to_string(),
+    user_id: ctx.user_id().to_string(),
+    attempt: 0,
+    version: 0,
+    labels: HashMap::default(),
+    done: false,
+    error: None,
+    response: HashMap::default(),
+    state: ProtoOperationState::from(OperationState::Queued) as i32,
+    parent_operation_id: ctx.parent_operation_id().unwrap_or_default().to_string(),
+    child_operation_ids: Vec::default(),
+    annotations: HashMap::default(),
+    creation_ts: None,
+    start_ts: None,
+    end_ts: None,
   }
+}
+
+/// Runs the transaction `pipe`, which points the key of `unique` at a new operation, only if the
+/// key is unset or still points at the operation it was read to point at. The key is watched
+/// meanwhile, and the transaction is tried again if another caller changed it. Returns the
+/// operation the key points at instead, in which case nothing was written.
+async fn claim(
+  conn: &mut InstrumentedConnection,
+  pipe: &redis::Pipeline,
+  unique: &UniqueClaim<'_>,
+) -> Result<Option<String>, redis::RedisError> {
+  loop {
+    let _: () = redis::cmd("WATCH")
+      .arg(unique.key)
+      .query_async(conn)
+      .await?;
 
-  async fn cancel(&self, _id: &str, _ctx: &Context) -> Result<Operation, Self::Error> {
-    todo!()
+    let current: Option<String> = conn.get(unique.key).await?;
+    if let Some(current) = current.filter(|current| Some(current.as_str()) != unique.expected) {
+      let _: () = redis::cmd("UNWATCH").query_async(conn).await?;
+      return Ok(Some(current));
+    }
+
+    // The transaction is aborted, and replies nil, when the key changed since it was watched.
+    let written: Option<()> = pipe.query_async(conn).await?;
+    if written.is_some() {
+      return Ok(None);
+    }
   }
 }
 
+/// Redis pub/sub channel the operation lifecycle events are published to.
+pub const OPERATION_EVENTS_CHANNEL: &str = "events:operations";
+
+/// Number of failed deliveries after which a message is quarantined.
+pub const DEFAULT_POISON_THRESHOLD: i64 = 3;
+
+/// Version of the operation hashes written by [`RedisQueue::offer`], in their `protocol_version`
+/// field:
+///
+/// 1. No `protocol_version` field. The payload is JSON unless a `content_type` says otherwise.
+/// 2. `protocol_version` and `content_type` are always set.
+///
+/// Readers accept every version: changes are additive, so hashes of a newer version are read
+/// as the latest known one. During a rolling upgrade, producers newer than some of the workers
+/// can keep writing an older version with [`RedisQueue::with_protocol_version`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Returns the version of an operation hash, see [`PROTOCOL_VERSION`].
+fn protocol_version(fields: &HashMap<String, Vec<u8>>) -> u32 {
+  fields
+    .get("protocol_version")
+    .and_then(|version| std::str::from_utf8(version).ok()?.parse().ok())
+    .unwrap_or(1)
+}
+
+/// Takes the next operation id of a queue that is not paused, moving it to the ack list unless
+/// it is acknowledged on pull, sets the given fields of the operation, counts the attempt and
+/// returns the id with the whole hash. Delayed ids that are due are queued first, and interactive
+/// ids are taken before the others. Ids of operations already done, e.g. cancelled while still
+/// queued, are dropped.
+///
+/// KEYS: paused flag, queue, ack list, delayed set, maintenance hash, interactive list. ARGV: prefix of the operation keys, `1` to
+/// keep the id in flight, the current epoch milliseconds, then field/value pairs.
+const PULL_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+  return false
+end
+if redis.call('HGET', KEYS[5], 'pulls') == 'paused' then
+  return false
+end
+local due = redis.call('ZRANGEBYSCORE', KEYS[4], '-inf', ARGV[3])
+for _, id in ipairs(due) do
+  redis.call('ZREM', KEYS[4], id)
+  redis.call('RPUSH', KEYS[2], id)
+end
+local id, key
+while true do
+  if ARGV[2] == '1' then
+    id = redis.call('LMOVE', KEYS[6], KEYS[3], 'RIGHT', 'LEFT')
+      or redis.call('LMOVE', KEYS[2], KEYS[3], 'RIGHT', 'LEFT')
+  else
+    id = redis.call('RPOP', KEYS[6]) or redis.call('RPOP', KEYS[2])
+  end
+  if not id then
+    return false
+  end
+  key = ARGV[1] .. id
+  if redis.call('HGET', key, 'done') ~= 'true' then
+    break
+  end
+  if ARGV[2] == '1' then
+    redis.call('LREM', KEYS[3], 1, id)
+  end
+end
+redis.call('HSET', key, unpack(ARGV, 4))
+redis.call('HINCRBY', key, 'attempt', 1)
+redis.call('HINCRBY', key, 'version', 1)
+return {id, redis.call('HGETALL', key)}
+";
+
+/// Takes an in-flight operation id off the ack list and queues it again, right away or once it
+/// is due. Returns 0 if the id was not in flight.
+///
+/// KEYS: ack list, queue, delayed set, operation. ARGV: id, epoch milliseconds the id is due at
+/// or 0, queued state.
+const NACK_SCRIPT: &str = r"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+  return 0
+end
+if tonumber(ARGV[2]) > 0 then
+  redis.call('ZADD', KEYS[3], ARGV[2], ARGV[1])
+else
+  redis.call('RPUSH', KEYS[2], ARGV[1])
+end
+redis.call('HSET', KEYS[4], 'status', ARGV[3])
+redis.call('HINCRBY', KEYS[4], 'version', 1)
+return 1
+";
+
+/// Moves an operation in flight that is not done yet to the invalid list and fails it, releasing
+/// its slot of the concurrent operations quota. Returns 0 if it was not in flight or already done.
+///
+/// KEYS: ack list, invalid list, operation. ARGV: id, `1` if in-flight ids are kept in the ack
+/// list, failed state, end timestamp, decode error, quota subject field, quota resource, prefix
+/// of the keys.
+const INVALIDATE_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[3], 'done') == 'true' then
+  return 0
+end
+if ARGV[2] == '1' and redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+  return 0
+end
+redis.call('LPUSH', KEYS[2], ARGV[1])
+redis.call('HSET', KEYS[3], 'done', 'true', 'status', ARGV[3], 'end_ts', ARGV[4], 'decode_error', ARGV[5])
+redis.call('HINCRBY', KEYS[3], 'version', 1)
+local subject = redis.call('HGET', KEYS[3], ARGV[6])
+if subject then
+  redis.call('HDEL', KEYS[3], ARGV[6])
+  local key = ARGV[8] .. 'quota:usage:' .. subject
+  if redis.call('HINCRBY', key, ARGV[7], -1) < 0 then
+    redis.call('HSET', key, ARGV[7], 0)
+  end
+end
+return 1
+";
+
+/// Puts an invalid message back on its queue: takes it off the invalid list, reserves a slot of
+/// the concurrent operations quota of its organization if given, resets its state and pushes it.
+/// Returns `{1}` once replayed, `{0}` if the message is not invalid, and `{-1, usage, limit}`
+/// without changing anything if the quota is exhausted.
+///
+/// KEYS: invalid list, queue, operation, then the quota usage and limits hashes if any. ARGV: id,
+/// queued state, then the quota resource, its default limit, the quota subject field and the
+/// subject if any.
+const REPLAY_INVALID_SCRIPT: &str = r"
+if not redis.call('LPOS', KEYS[1], ARGV[1]) then
+  return {0}
+end
+if #KEYS > 3 then
+  local limit = tonumber(redis.call('HGET', KEYS[5], ARGV[3]) or ARGV[4])
+  local usage = tonumber(redis.call('HGET', KEYS[4], ARGV[3]) or '0')
+  if limit >= 0 and usage + 1 > limit then
+    return {-1, usage, limit}
+  end
+  redis.call('HINCRBY', KEYS[4], ARGV[3], 1)
+  redis.call('HSET', KEYS[3], ARGV[5], ARGV[6])
+end
+redis.call('LREM', KEYS[1], 1, ARGV[1])
+redis.call('HDEL', KEYS[3], 'decode_error', 'done', 'end_ts')
+redis.call('HSET', KEYS[3], 'status', ARGV[2])
+redis.call('HINCRBY', KEYS[3], 'version', 1)
+redis.call('LPUSH', KEYS[2], ARGV[1])
+return {1}
+";
+
+/// Takes an in-flight operation id off the ack list and counts the failed delivery. The id is
+/// queued again until it failed as many times as the threshold, then the operation fails and its
+/// payload is quarantined. Returns the failures, or 0 if the id was not in flight.
+///
+/// KEYS: ack list, queue, operation, quarantine list, quarantined hash. ARGV: id, poison
+/// threshold, queue name, reason, current epoch nanoseconds, failed state.
+const FAILURE_SCRIPT: &str = r"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 0 then
+  return 0
+end
+local failures = redis.call('HINCRBY', KEYS[3], 'failure_count', 1)
+if failures < tonumber(ARGV[2]) then
+  redis.call('RPUSH', KEYS[2], ARGV[1])
+  return failures
+end
+local payload = redis.call('HGET', KEYS[3], 'task') or ''
+redis.call('LPUSH', KEYS[4], ARGV[1])
+redis.call('HSET', KEYS[5], 'queue', ARGV[3], 'reason', ARGV[4], 'failures', failures,
+  'quarantine_ts', ARGV[5], 'payload', payload)
+redis.call('HSET', KEYS[3], 'status', ARGV[6], 'done', 'true', 'end_ts', ARGV[5])
+redis.call('HINCRBY', KEYS[3], 'version', 1)
+return failures
+";
+
+/// Publishes [`OperationEvent`]s on a Redis pub/sub channel.
 #[derive(Clone, Debug)]
+pub struct RedisEventBus {
+  client: redis::Client,
+  channel: String,
+}
+
+impl RedisEventBus {
+  pub fn new(client: redis::Client, channel: &str) -> Self {
+    Self {
+      client,
+      channel: channel.to_string(),
+    }
+  }
+
+  /// Subscribes to the channel and yields the events matching `filter`, e.g. to serve the
+  /// `Operations.Stream` RPC. Events published before the subscription are not replayed.
+  pub async fn subscribe(
+    &self,
+    filter: StreamOperationsRequest,
+  ) -> Result<impl Stream<Item = OperationEvent> + Send, RedisQueueError> {
+    let mut pubsub = redis_exec::connect(&self.client).await?.into_pubsub();
+    pubsub.subscribe(&self.channel).await?;
+
+    let events = pubsub.into_on_message().filter_map(move |msg| {
+      let event = match msg.get_payload::<ProtoValue<OperationEvent>>() {
+        Ok(ProtoValue(event)) if filter.matches(&event) => Some(event),
+        Ok(_) => None,
+        Err(error) => {
+          tracing::debug!(message = "Dropping malformed operation event", %error);
+          None
+        }
+      };
+
+      futures::future::ready(event)
+    });
+
+    Ok(events)
+  }
+}
+
+#[async_trait::async_trait]
+impl EventBus for RedisEventBus {
+  type Error = RedisQueueError;
+
+  async fn publish(&self, event: OperationEvent) -> Result<(), Self::Error> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = conn
+      .publish(&self.channel, ProtoValue(event))
+      .instrument(tracing::info_span!("redis-event-bus-publish"))
+      .await?;
+
+    Ok(())
+  }
+}
+
+#[derive(Debug)]
 pub struct RedisQueue<T, C: Codec> {
   client: redis::Client,
   queue: String,
   codec: C,
+  decoders: HashMap<&'static str, Arc<dyn DynCodec>>,
+  replication: Option<String>,
+  events: RedisEventBus,
+  poison_threshold: i64,
+  quota: Option<RedisQuota>,
+  keys: Keys,
+  store: Option<RedisTaskStore>,
+  ack_mode: AckMode,
+  sla: Option<SlaTracker>,
+  cost: Option<CostMeter>,
+  clock: SharedClock,
+  ids: SharedIdGenerator,
+  contexts: SharedContextSerializer,
+  protocol_version: u32,
+  system_context: Option<Context>,
+  impersonators: Option<Arc<HashSet<String>>>,
+  payload_limits: PayloadLimits,
+  app_version: String,
+  holding_queue: Option<String>,
   _phantom: PhantomData<T>,
 }
 
+// Not derived, which would require the task type to be `Clone` too.
+impl<T, C: Codec + Clone> Clone for RedisQueue<T, C> {
+  fn clone(&self) -> Self {
+    Self {
+      client: self.client.clone(),
+      queue: self.queue.clone(),
+      codec: self.codec.clone(),
+      decoders: self.decoders.clone(),
+      replication: self.replication.clone(),
+      events: self.events.clone(),
+      poison_threshold: self.poison_threshold,
+      quota: self.quota.clone(),
+      keys: self.keys.clone(),
+      store: self.store.clone(),
+      ack_mode: self.ack_mode,
+      sla: self.sla.clone(),
+      cost: self.cost.clone(),
+      clock: self.clock.clone(),
+      ids: self.ids.clone(),
+      contexts: self.contexts.clone(),
+      protocol_version: self.protocol_version,
+      system_context: self.system_context.clone(),
+      impersonators: self.impersonators.clone(),
+      payload_limits: self.payload_limits,
+      app_version: self.app_version.clone(),
+      holding_queue: self.holding_queue.clone(),
+      _phantom: PhantomData,
+    }
+  }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct RedisMessage<T> {
   pub ack_id: String,
   pub data: T,
+  /// Number of times the message has been delivered, including this delivery.
+  pub attempt: i64,
+  pub user_id: String,
+  /// Context the task was enqueued with.
+  pub context: Context,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -97,6 +797,15 @@ pub enum RedisQueueError {
   #[error("Failed at codec")]
   CodecError(#[from] crate::codec::json::Error),
 
+  #[error("Failed at codec: {0}")]
+  Codec(#[from] crate::codec::Error),
+
+  #[error("{0}")]
+  Quota(#[from] QuotaError),
+
+  #[error("No decoder for content type {0}")]
+  UnsupportedContentType(String),
+
   #[error("Invalid Task Type. Expected {0}, Found {1}")]
   InvalidTaskType(String, String),
 
@@ -106,8 +815,122 @@ pub enum RedisQueueError {
   #[error("NotFound: {0}")]
   NotFound(String),
 
+  #[error("InvalidArgument: {0}")]
+  InvalidArgument(String),
+
+  #[error("PermissionDenied: {0}")]
+  PermissionDenied(String),
+
+  #[error("{0}")]
+  Id(#[from] IdError),
+
+  #[error("{0}")]
+  ContextEnvelope(#[from] ContextEnvelopeError),
+
+  #[error("{0}")]
+  Maintenance(#[from] MaintenanceMode),
+
+  #[error("Conflict: {0}")]
+  Conflict(String),
+
+  #[error("{0}")]
+  PayloadTooLarge(#[from] PayloadTooLarge),
+
   #[error("Unknown")]
   Unknown(#[from] anyhow::Error),
+
+  /// An error with the resources it is about, see [`Contextual`]. Match on [`Self::inner`].
+  #[error("{source} ({context})")]
+  Context {
+    context: ErrorContext,
+    source: Box<RedisQueueError>,
+  },
+}
+
+impl RedisQueueError {
+  /// The error without its context, e.g. to match on its kind.
+  pub fn inner(&self) -> &Self {
+    match self {
+      RedisQueueError::Context { source, .. } => source.inner(),
+      error => error,
+    }
+  }
+
+  /// The error without its context, and its context.
+  pub fn split(self) -> (Self, Option<ErrorContext>) {
+    match self {
+      RedisQueueError::Context { context, source } => (*source, Some(context)),
+      error => (error, None),
+    }
+  }
+}
+
+impl Contextual for RedisQueueError {
+  fn with_context(self, context: ErrorContext) -> Self {
+    let (source, inner) = self.split();
+    let context = match inner {
+      Some(inner) => inner.or(context),
+      None => context,
+    };
+
+    RedisQueueError::Context {
+      context,
+      source: Box::new(source),
+    }
+  }
+
+  fn context(&self) -> Option<&ErrorContext> {
+    match self {
+      RedisQueueError::Context { context, .. } => Some(context),
+      _ => None,
+    }
+  }
+}
+
+/// A dequeued message whose payload was not decoded yet, see [`RedisQueue::pull_raw`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawMessage {
+  pub ack_id: String,
+  pub task_type: String,
+  /// Media type of the payload, see [`Codec::content_type`].
+  pub content_type: String,
+  pub payload: bytes::Bytes,
+  /// Number of times the message has been delivered, including this delivery.
+  pub attempt: i64,
+  pub user_id: String,
+  /// Context the task was enqueued with, rebuilt from the user id for operations enqueued without
+  /// a context envelope.
+  pub context: Context,
+  /// Version of the operation hash, see [`PROTOCOL_VERSION`].
+  pub protocol_version: u32,
+  /// [`Performable::schema_version`] of the task, `None` when its producer did not record one.
+  pub schema_version: Option<u32>,
+  /// Version of the application that enqueued the task, empty when it did not record one.
+  pub producer_version: String,
+}
+
+/// A task encoded by the codec of its queue, before it is written by [`RedisQueue::offer_batch`].
+struct EncodedTask<'a> {
+  task_type: &'a str,
+  content_type: &'a str,
+  payload: Vec<u8>,
+  schema_version: Option<u32>,
+}
+
+/// A unique key an operation is only written for if it claims it, see [`claim`].
+struct UniqueClaim<'a> {
+  key: &'a str,
+  /// Operation the key pointed at when it was read, if any.
+  expected: Option<&'a str>,
+}
+
+/// A message whose payload could not be decoded, kept verbatim in `queue:invalid:{queue}`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidMessage {
+  pub operation_id: String,
+  pub task_type: String,
+  pub payload: Vec<u8>,
+  pub error: String,
 }
 
 impl<T> super::Task<T> for RedisMessage<T> {
@@ -118,337 +941,2356 @@ impl<T> super::Task<T> for RedisMessage<T> {
   fn data(&self) -> &T {
     &self.data
   }
+
+  fn user_id(&self) -> Option<&str> {
+    Some(self.user_id.as_str()).filter(|user_id| !user_id.is_empty())
+  }
+
+  fn context(&self) -> Option<&Context> {
+    Some(&self.context)
+  }
+}
+
+/// User, task type, publish timestamp, organization, dequeue timestamp and attempt of a completed
+/// operation.
+type CompletedFields = (
+  Option<String>,
+  Option<String>,
+  Option<i64>,
+  Option<String>,
+  Option<i64>,
+  Option<i64>,
+);
+
+/// Fields recording who acknowledged an operation and when.
+fn ack_audit<'a>(ctx: &'a Context, ack_ts: &'a str) -> [(&'static str, &'a str); 3] {
+  [
+    ("ack_system_id", ctx.system_id()),
+    ("ack_ts", ack_ts),
+    ("ack_user_id", ctx.user_id()),
+  ]
 }
 
-impl<T: Performable, C: Codec> RedisQueue<T, C> {
+impl<T, C: Codec> RedisQueue<T, C> {
   pub fn new(client: redis::Client, queue: String, codec: C) -> Self {
     Self {
+      events: RedisEventBus::new(client.clone(), OPERATION_EVENTS_CHANNEL),
       client,
       queue,
       codec,
+      decoders: HashMap::default(),
+      replication: None,
+      poison_threshold: DEFAULT_POISON_THRESHOLD,
+      quota: None,
+      keys: Keys::default(),
+      store: None,
+      ack_mode: AckMode::default(),
+      sla: None,
+      cost: None,
+      clock: clock::system(),
+      ids: Arc::new(UuidV7Generator),
+      contexts: Arc::new(JsonContextSerializer),
+      protocol_version: PROTOCOL_VERSION,
+      system_context: None,
+      impersonators: None,
+      payload_limits: PayloadLimits::default(),
+      app_version: skew::CRATE_VERSION.to_string(),
+      holding_queue: None,
       _phantom: PhantomData,
     }
   }
 
-  pub async fn complete<M: Message, E: Into<Status>>(
-    &self,
-    id: &str,
-    r: Result<M, E>,
-    _ctx: &Context,
-  ) -> Result<(), RedisQueueError> {
-    let mut conn = self.client.get_async_connection().await?;
-    let mut pipe = redis::pipe();
+  pub fn name(&self) -> &str {
+    &self.queue
+  }
 
-    let mut pipeline = pipe
-      .atomic()
-      .hset_multiple(
-        format!("operation:{}", id),
-        &[
-          ("done", "true"),
-          ("status", "Terminated"),
-          ("end_ts", &Utc::now().timestamp_nanos().to_string()),
-        ],
-      )
-      .ignore();
+  /// The queue `queue` configured as this one, e.g. a partition of it.
+  pub(crate) fn renamed(&self, queue: String) -> Self
+  where
+    Self: Clone,
+  {
+    Self {
+      queue,
+      ..self.clone()
+    }
+  }
 
-    pipeline = match r {
-      Err(error) => {
-        let status: Status = error.into();
+  pub(crate) fn client(&self) -> &redis::Client {
+    &self.client
+  }
 
-        pipeline
-          .hset(format!("operation:{}", id), "error", status.encode_to_vec())
-          .ignore()
-      }
-      Ok(output) => pipeline
-        .hset(
-          format!("operation:{}", id),
-          "result",
-          output.encode_to_vec(),
-        )
-        .ignore(),
-    };
+  pub(crate) fn keys(&self) -> &Keys {
+    &self.keys
+  }
 
-    let _ = pipeline
-      .query_async(&mut conn)
-      .instrument(tracing::info_span!("redis-queue-complete"))
-      .await?;
+  /// Context of the errors about the operation `id` of the queue.
+  pub(crate) fn error_context(&self, id: &str) -> ErrorContext {
+    ErrorContext::new()
+      .with_queue(&self.queue)
+      .with_operation(id)
+      .with_key(&self.keys.operation(id))
+  }
 
-    Ok(())
+  /// Registers a codec decoding the payloads stored with its content type, besides the codec of
+  /// the queue. Offers always use the codec of the queue, so a queue migrates to another codec by
+  /// switching its codec and keeping the previous one as a decoder until older payloads drained.
+  pub fn with_decoder(mut self, codec: Arc<dyn DynCodec>) -> Self {
+    self.decoders.insert(codec.content_type(), codec);
+    self
   }
-}
 
-#[async_trait::async_trait]
-impl<T: Send + Sync + Serialize + DeserializeOwned + Performable> super::Queue
-  for RedisQueue<T, JsonCodec<T, T>>
-{
-  type Item = T;
+  /// Sets the number of failed deliveries after which a message is quarantined.
+  pub fn with_poison_threshold(mut self, threshold: i64) -> Self {
+    self.poison_threshold = threshold.max(1);
+    self
+  }
 
-  type ReceivedItem = RedisMessage<T>;
+  /// Records every offered and completed operation in the replication outbox of the queue,
+  /// tagged with `region` as its origin. The outbox is drained into another region by a
+  /// [`super::replication::ReplicationRelay`].
+  pub fn with_replication(mut self, region: &str) -> Self {
+    self.replication = Some(region.to_string());
+    self
+  }
 
-  type Error = RedisQueueError;
+  /// Stores the queue under the prefix of `keys`, and publishes its events on the prefixed
+  /// [`OPERATION_EVENTS_CHANNEL`].
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.events = RedisEventBus::new(self.client.clone(), &keys.channel(OPERATION_EVENTS_CHANNEL));
+    self.keys = keys;
+    self
+  }
 
-  async fn offer(&self, item: Self::Item, ctx: &Context) -> Result<String, Self::Error> {
-    let mut encoder = self.codec.encoder();
-    let id = Uuid::new_v4().to_string();
-    let publish_ts = Utc::now().timestamp_nanos();
+  /// Publishes the operation events on `events` rather than on the queue's connection. Replaces
+  /// the bus set up by [`Self::with_keys`].
+  pub fn with_events(mut self, events: RedisEventBus) -> Self {
+    self.events = events;
+    self
+  }
 
-    let mut task = Vec::default();
-    let _ = encoder.encode(&item, &mut task);
-    let task = String::from_utf8_lossy(&task);
+  /// Sets when pulled messages leave the in-flight list, [`AckMode::OnComplete`] by default.
+  pub fn with_ack_mode(mut self, ack_mode: AckMode) -> Self {
+    self.ack_mode = ack_mode;
+    self
+  }
 
-    let mut conn = self.client.get_async_connection().await?;
+  pub fn ack_mode(&self) -> AckMode {
+    self.ack_mode
+  }
 
-    let _ = redis::pipe()
-      .atomic()
-      .lpush(format!("queue:{}", self.queue), id.clone())
-      .ignore()
-      .hset_multiple(
-        format!("operation:{}", id),
-        &[
-          ("status", "New"),
-          ("operation_id", &id),
-          ("queue", &self.queue),
-          ("publish_ts", &publish_ts.to_string()),
-          ("task", &task),
-          ("user_id", ctx.user_id()),
-          ("task_type", Self::Item::type_name()),
-        ],
-      )
-      .ignore()
-      .query_async(&mut conn)
-      .instrument(tracing::info_span!("redis-queue-offer", operation_id=%id))
-      .await?;
+  /// Writes the dequeue and ack audit fields (`dequeue_system_id`, `dequeue_user_id` and the
+  /// `ack_*` fields) through `store`, so a store with write-behind batches them across
+  /// operations. The fields the queue relies on are still written immediately.
+  pub fn with_store(mut self, store: RedisTaskStore) -> Self {
+    self.store = Some(store);
+    self
+  }
 
-    Ok(id)
+  /// Reserves a [`quota::CONCURRENT_OPERATIONS`] slot of the organization of the context for
+  /// every offered operation, released once the operation completes or is cancelled. Offers
+  /// without an organization are not restricted.
+  pub fn with_quota(mut self, quota: RedisQuota) -> Self {
+    self.quota = Some(quota);
+    self
   }
 
-  async fn pull(&self, ctx: &Context) -> Result<Option<Self::ReceivedItem>, Self::Error> {
-    let mut conn = self.client.get_async_connection().await?;
+  /// Counts the offered payloads above the soft limit of `limits` and rejects those above its hard
+  /// limit with [`RedisQueueError::PayloadTooLarge`], before anything is written. Unlimited by
+  /// default.
+  pub fn with_payload_limits(mut self, limits: PayloadLimits) -> Self {
+    self.payload_limits = limits;
+    self
+  }
 
-    let maybe_id: Option<String> = redis::cmd("LMOVE")
-      .arg(format!("queue:{}", self.queue))
-      .arg(format!("queue:ack:{}", self.queue))
-      .arg("RIGHT")
-      .arg("LEFT")
-      .query_async(&mut conn)
-      .instrument(tracing::info_span!("redis-queue-pull-lmove"))
-      .await?;
+  /// Records `version` as the version of the application enqueuing the operations, the version
+  /// of this crate by default. See [`skew`].
+  pub fn with_app_version(mut self, version: &str) -> Self {
+    self.app_version = version.to_string();
+    self
+  }
 
-    let op_id = match maybe_id {
-      None => return Ok(None),
-      Some(id) => id,
-    };
+  /// Moves the pulled tasks of a newer schema version than the worker supports to `queue`, to be
+  /// performed by upgraded workers, rather than performing them anyway. See [`skew`].
+  pub fn with_holding_queue(mut self, queue: &str) -> Self {
+    self.holding_queue = Some(queue.to_string());
+    self
+  }
 
-    let (op,): (HashMap<String, String>,) = redis::pipe()
-      .atomic()
-      .hset_multiple(
-        format!("operation:{}", op_id),
-        &[
-          ("dequeue_system_id", ctx.system_id()),
-          ("dequeue_ts", &Utc::now().timestamp_nanos().to_string()),
-          ("dequeue_user_id", ctx.user_id()),
-        ],
-      )
-      .ignore()
-      .hgetall(format!("operation:{}", op_id))
-      .query_async(&mut conn)
-      .instrument(tracing::info_span!("redis-queue-pull-hget"))
-      .await?;
+  /// Writes the operations in an older format, [`PROTOCOL_VERSION`] by default, so workers not
+  /// upgraded yet still read them. Version 1 has no content type, so it requires a JSON codec.
+  pub fn with_protocol_version(mut self, version: u32) -> Self {
+    self.protocol_version = version.clamp(1, PROTOCOL_VERSION);
+    self
+  }
 
-    if op["task_type"] != Self::Item::type_name() {
-      tracing::error!(message = "Invalid task type encountered in the queue", task_type = %op["task_type"]);
+  /// Sets the context the internal components enqueue with, see [`Self::offer_as_system`]. Its
+  /// principal, usually a service account, may always impersonate users.
+  pub fn with_system_context(mut self, ctx: Context) -> Self {
+    self.system_context = Some(ctx);
+    self
+  }
+
+  pub fn system_context(&self) -> Option<&Context> {
+    self.system_context.as_ref()
+  }
+
+  /// Rejects the offers whose context impersonates a user, see [`Context::with_principal`], unless
+  /// their principal is one of `principals` or the principal of the system context. Any principal
+  /// may impersonate by default.
+  pub fn with_impersonators<I, S>(mut self, principals: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    self.impersonators = Some(Arc::new(principals.into_iter().map(Into::into).collect()));
+    self
+  }
+
+  /// Records the enqueue to completion latency of every completed operation in `sla`, see
+  /// [`super::sla`].
+  pub fn with_sla(mut self, sla: SlaTracker) -> Self {
+    self.sla = Some(sla);
+    self
+  }
+
+  /// Generates the ids of the operations offered with `ids`, UUID v7 by default so the ids sort
+  /// by enqueue time.
+  pub fn with_id_generator(mut self, ids: SharedIdGenerator) -> Self {
+    self.ids = ids;
+    self
+  }
+
+  /// Sets how the context of the operations is stored in their hash, [`JsonContextSerializer`]
+  /// by default.
+  pub fn with_context_serializer(mut self, contexts: SharedContextSerializer) -> Self {
+    self.contexts = contexts;
+    self
+  }
+
+  /// Reads the time of the timestamps and leases from `clock`, the system clock by default.
+  pub fn with_clock(mut self, clock: SharedClock) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  /// Records the resources used by every completed operation of an organization in `cost`, see
+  /// [`super::cost`].
+  pub fn with_cost(mut self, cost: CostMeter) -> Self {
+    self.cost = Some(cost);
+    self
+  }
+
+  pub async fn complete<M: Message, E: Into<Status>>(
+    &self,
+    id: &str,
+    r: Result<M, E>,
+    ctx: &Context,
+  ) -> Result<(), RedisQueueError> {
+    let r = r.map(|output| output.encode_to_vec()).map_err(Into::into);
+    self.complete_raw(id, r, ctx).await
+  }
+
+  /// Records the already encoded result of the operation `id`, see [`RedisQueue::complete`].
+  /// With [`AckMode::OnComplete`] the operation is acknowledged by the same script that records
+  /// its result, even if it was already done.
+  pub async fn complete_raw(
+    &self,
+    id: &str,
+    r: Result<Vec<u8>, Status>,
+    ctx: &Context,
+  ) -> Result<(), RedisQueueError> {
+    let outcome = if r.is_ok() { "succeeded" } else { "failed" };
+    let result_bytes = r.as_ref().map_or(0, Vec::len) as u64;
+    let end_ts = self.clock.timestamp_nanos();
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let acknowledge = self.ack_mode == AckMode::OnComplete;
+
+    let (state, field, value) = match r {
+      Ok(output) => (OperationState::Succeeded, "result", output),
+      Err(status) => (OperationState::Failed, "error", status.encode_to_vec()),
+    };
+    let script = redis::Script::new(COMPLETE_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation
+      .key(self.keys.operation(id))
+      .arg(state.as_str())
+      .arg(end_ts)
+      .arg(field)
+      .arg(value);
+    if acknowledge {
+      invocation.key(self.keys.ack(&self.queue)).arg(id);
+    }
+
+    let completed: Option<String> = invocation
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-complete-script"))
+      .await?;
+
+    if completed.is_none() {
+      // Cancelled while it was performed, the cancellation already completed the operation.
+      tracing::debug!(message = "Discarded the result of a completed operation", operation_id = %id);
+      return Ok(());
+    }
+
+    let mut pipe = redis::pipe();
+    let mut pipeline = pipe.atomic();
+
+    if let Some(region) = &self.replication {
+      pipeline = pipeline
+        .lpush(
+          self.keys.replication(&self.queue),
+          ReplicationEvent::completed(region, id).to_string(),
+        )
+        .ignore();
+    }
+
+    let now = self.clock.timestamp_nanos().to_string();
+    let audit = ack_audit(ctx, &now);
+
+    if acknowledge && self.store.is_none() {
+      pipeline = pipeline
+        .hset_multiple(self.keys.operation(id), &audit)
+        .ignore();
+    }
+
+    let (fields, task_bytes): (CompletedFields, u64) = pipeline
+      .hget(
+        self.keys.operation(id),
+        &[
+          "user_id",
+          "task_type",
+          "publish_ts",
+          "organization_id",
+          "dequeue_ts",
+          "attempt",
+        ],
+      )
+      .cmd("HSTRLEN")
+      .arg(self.keys.operation(id))
+      .arg("task")
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-complete"))
+      .await?;
+    let (user_id, task_type, publish_ts, organization_id, dequeue_ts, attempt) = fields;
+
+    if let (Some(cost), Some(organization_id)) = (&self.cost, organization_id) {
+      let usage = OperationUsage {
+        operation_id: id.to_string(),
+        organization_id,
+        task_type: task_type.clone().unwrap_or_default(),
+        wall_time: dequeue_ts
+          .map(|dequeue_ts| Duration::from_nanos(end_ts.saturating_sub(dequeue_ts).max(0) as u64))
+          .unwrap_or_default(),
+        retries: attempt.unwrap_or(1).saturating_sub(1).max(0) as u32,
+        payload_bytes: task_bytes + result_bytes,
+      };
+      let mut pipe = redis::pipe();
+      cost.record(&mut pipe, &self.keys, &usage);
+
+      let _: () = pipe
+        .atomic()
+        .query_async(&mut conn)
+        .instrument(tracing::info_span!("redis-queue-cost"))
+        .await?;
+    }
+
+    if let (Some(sla), Some(task_type), Some(publish_ts)) = (&self.sla, task_type, publish_ts) {
+      let latency = Duration::from_nanos(end_ts.saturating_sub(publish_ts).max(0) as u64);
+      let mut pipe = redis::pipe();
+
+      if sla.record(&mut pipe, &self.keys, id, &task_type, latency) {
+        tracing::warn!(message = "Operation violated its SLA", operation_id = %id, %task_type, ?latency);
+      }
+
+      let _: () = pipe
+        .query_async(&mut conn)
+        .instrument(tracing::info_span!("redis-queue-sla"))
+        .await?;
+    }
+
+    if let (true, Some(store)) = (acknowledge, &self.store) {
+      store.update(id, &audit).await?;
+    }
+
+    quota::release_operation(&mut conn, &self.keys, id).await?;
+
+    self
+      .publish_event(
+        id,
+        OperationEventType::Completed,
+        &user_id.unwrap_or_default(),
+        HashMap::from([("outcome".to_string(), outcome.to_string())]),
+      )
+      .await;
+
+    Ok(())
+  }
+
+  /// Publishes a progress event for a running operation, e.g. the percentage done.
+  pub async fn report_progress(
+    &self,
+    id: &str,
+    user_id: &str,
+    progress: HashMap<String, String>,
+  ) -> Result<(), RedisQueueError> {
+    let event = self.event(id, OperationEventType::Progress, user_id, progress);
+    self.events.publish(event).await
+  }
+
+  fn event(
+    &self,
+    id: &str,
+    event_type: OperationEventType,
+    user_id: &str,
+    attributes: HashMap<String, String>,
+  ) -> OperationEvent {
+    OperationEvent {
+      operation_id: id.to_string(),
+      queue: self.queue.clone(),
+      event_type: event_type as i32,
+      attributes,
+      event_ts: Some(ProstTimestamp::from(self.clock.now()).into_inner()),
+      user_id: user_id.to_string(),
+    }
+  }
+
+  /// Publishes a lifecycle event. A failed publish is logged rather than failing the operation,
+  /// the event bus is best effort.
+  async fn publish_event(
+    &self,
+    id: &str,
+    event_type: OperationEventType,
+    user_id: &str,
+    attributes: HashMap<String, String>,
+  ) {
+    let event = self.event(id, event_type, user_id, attributes);
+
+    if let Err(error) = self.events.publish(event).await {
+      tracing::warn!(message = "Failed to publish operation event", operation_id = %id, %error);
+    }
+  }
+
+  /// Handles the failure of the `attempt`-th delivery of the operation `id` according to its
+  /// classification, see [`Failure`]. Rate limited operations pulled with [`AckMode::Auto`],
+  /// which cannot be declined, are requeued as retryable failures.
+  pub async fn fail(
+    &self,
+    id: &str,
+    error: Status,
+    failure: Failure,
+    attempt: i64,
+    ctx: &Context,
+  ) -> Result<(), RedisQueueError> {
+    tracing::debug!(message = "Classified failure", operation_id = %id, failure = failure.as_str());
+
+    match failure {
+      Failure::Fatal => self.complete_raw(id, Err(error), ctx).await,
+      Failure::Invalid => self.invalidate(id, &error.message).await,
+      Failure::Retryable => self.record_failure(id, &error.message).await.map(|_| ()),
+      Failure::RateLimited => {
+        match self
+          .nack_raw(id, Some(failure::rate_limit_delay(attempt)), ctx)
+          .await
+        {
+          Err(nacked) if matches!(nacked.inner(), RedisQueueError::NotFound(_)) => {
+            self.record_failure(id, &error.message).await.map(|_| ())
+          }
+          result => result,
+        }
+      }
+    }
+  }
+
+  /// Counts a failed delivery of the in-flight operation `id`. The operation goes back to the
+  /// queue until it failed `poison_threshold` times, then it fails and its payload is
+  /// quarantined. Failures of operations no longer in flight, e.g. recovered meanwhile, are not
+  /// counted. Returns whether the operation was quarantined.
+  pub async fn record_failure(&self, id: &str, reason: &str) -> Result<bool, RedisQueueError> {
+    let failures = self.count_failure(id, reason).await?;
+    Ok(failures >= self.poison_threshold)
+  }
+
+  /// Counts a failed delivery by [`FAILURE_SCRIPT`]. Returns the failures of the operation, or 0
+  /// if it was not in flight.
+  async fn count_failure(&self, id: &str, reason: &str) -> Result<i64, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let failures: i64 = redis::Script::new(FAILURE_SCRIPT)
+      .key(self.keys.ack(&self.queue))
+      .key(self.keys.queue(&self.queue))
+      .key(self.keys.operation(id))
+      .key(self.keys.quarantine(&self.queue))
+      .key(self.keys.quarantined(id))
+      .arg(id)
+      .arg(self.poison_threshold)
+      .arg(&self.queue)
+      .arg(reason)
+      .arg(self.clock.timestamp_nanos())
+      .arg(OperationState::Failed)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-failure", operation_id = %id))
+      .await?;
+
+    if failures == 0 {
+      tracing::debug!(message = "Ignored the failure of a message no longer in flight", operation_id = %id);
+    } else if failures < self.poison_threshold {
+      tracing::debug!(message = "Requeued failed message", operation_id = %id, %failures);
+    } else {
+      quota::release_operation(&mut conn, &self.keys, id).await?;
+      self.quarantined(&mut conn, id, reason, failures).await?;
+    }
+
+    Ok(failures)
+  }
+
+  /// Treats every in-flight operation dequeued more than `lease` ago as a delivery whose worker
+  /// died, see [`RedisQueue::record_failure`]. Completed operations left in the in-flight list
+  /// are dropped from it. Returns the ids of the recovered operations.
+  pub async fn recover_expired(&self, lease: Duration) -> Result<Vec<String>, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let now = self.clock.timestamp_nanos();
+    let lease = lease.as_nanos() as i64;
+
+    let in_flight: Vec<String> = conn
+      .lrange(self.keys.ack(&self.queue), 0, -1)
+      .instrument(tracing::info_span!("redis-queue-recover-lrange"))
+      .await?;
+
+    let mut recovered = Vec::default();
+
+    for id in in_flight {
+      let (done, dequeue_ts): (Option<String>, Option<i64>) = conn
+        .hget(self.keys.operation(&id), &["done", "dequeue_ts"])
+        .await?;
+
+      if done.as_deref() == Some("true") {
+        let _: () = conn.lrem(self.keys.ack(&self.queue), 1, &id).await?;
+        continue;
+      }
+
+      // Another worker may have recovered or completed the operation since it was listed.
+      if dequeue_ts.map(|ts| now - ts > lease).unwrap_or(true)
+        && self
+          .count_failure(&id, "Lease expired while the operation was in flight")
+          .await?
+          > 0
+      {
+        recovered.push(id);
+      }
+    }
+
+    Ok(recovered)
+  }
+
+  /// Moves the in-flight operation `id` to `queue:invalid:{queue}` and fails it, recording why
+  /// it is invalid, e.g. the decode error, on the operation. The payload is left untouched so it
+  /// can be inspected and replayed. Operations no longer in flight or already done, e.g.
+  /// completed meanwhile, are left as they are.
+  pub async fn invalidate(&self, id: &str, error: &str) -> Result<(), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let invalidated: bool = redis::Script::new(INVALIDATE_SCRIPT)
+      .key(self.keys.ack(&self.queue))
+      .key(self.keys.invalid(&self.queue))
+      .key(self.keys.operation(id))
+      .arg(id)
+      .arg(self.ack_mode != AckMode::Auto)
+      .arg(OperationState::Failed.as_str())
+      .arg(self.clock.timestamp_nanos())
+      .arg(error)
+      .arg(quota::OPERATION_SUBJECT_FIELD)
+      .arg(quota::CONCURRENT_OPERATIONS)
+      .arg(self.keys.prefix())
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-invalidate", operation_id = %id))
+      .await?;
+
+    if !invalidated {
+      tracing::debug!(message = "Not invalidating an operation no longer in flight", operation_id = %id);
+      return Ok(());
+    }
+
+    tracing::warn!(message = "Moved undecodable message to the invalid list", operation_id = %id, queue = %self.queue, %error);
+    Ok(())
+  }
+
+  /// Checks the schema version of the pulled `message` against the `supported` version of its task
+  /// type. A newer one is logged and counted in the [`skew::metrics`], and the operation is moved
+  /// to the holding queue when there is one, see [`RedisQueue::with_holding_queue`]. Returns
+  /// whether it was held, in which case the caller must not perform it.
+  pub async fn hold_skewed(
+    &self,
+    message: &RawMessage,
+    supported: u32,
+  ) -> Result<bool, RedisQueueError> {
+    if !skew::is_newer(message.schema_version, supported) {
+      return Ok(false);
+    }
+
+    skew::metrics().record(&self.queue, &message.task_type);
+    tracing::warn!(
+      message = "Pulled a task of a newer schema version than supported",
+      operation_id = %message.ack_id,
+      queue = %self.queue,
+      task_type = %message.task_type,
+      schema_version = ?message.schema_version,
+      supported,
+      producer_version = %message.producer_version,
+      worker_version = %self.app_version,
+    );
+
+    let holding_queue = match &self.holding_queue {
+      None => return Ok(false),
+      Some(holding_queue) => holding_queue,
+    };
+
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let _: () = redis::pipe()
+      .atomic()
+      .lrem(self.keys.ack(&self.queue), 1, &message.ack_id)
+      .ignore()
+      .lpush(self.keys.queue(holding_queue), &message.ack_id)
+      .ignore()
+      .hset_multiple(
+        self.keys.operation(&message.ack_id),
+        &[
+          ("queue", holding_queue.as_str()),
+          ("status", OperationState::Queued.as_str()),
+        ],
+      )
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-hold", operation_id = %message.ack_id))
+      .await?;
+
+    tracing::info!(message = "Moved skewed message to the holding queue", operation_id = %message.ack_id, %holding_queue);
+    Ok(true)
+  }
+
+  /// Lists the invalid messages of the queue, newest first.
+  pub async fn invalid(
+    &self,
+    offset: isize,
+    count: isize,
+  ) -> Result<Vec<InvalidMessage>, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let ids: Vec<String> = conn
+      .lrange(self.keys.invalid(&self.queue), offset, offset + count - 1)
+      .instrument(tracing::info_span!("redis-queue-invalid-lrange"))
+      .await?;
+
+    let mut messages = Vec::with_capacity(ids.len());
+
+    for id in ids {
+      let (task_type, payload, error): (Option<String>, Option<Vec<u8>>, Option<String>) = conn
+        .hget(
+          self.keys.operation(&id),
+          &["task_type", "task", "decode_error"],
+        )
+        .await?;
+
+      messages.push(InvalidMessage {
+        operation_id: id,
+        task_type: task_type.unwrap_or_default(),
+        payload: payload.unwrap_or_default(),
+        error: error.unwrap_or_default(),
+      });
+    }
+
+    Ok(messages)
+  }
+
+  /// Puts an invalid message back on the queue, e.g. after a decoder fix was deployed. The
+  /// operation takes a slot of the concurrent operations quota of its organization again, and
+  /// fails with [`QuotaError::Exceeded`] if there is none left.
+  pub async fn replay_invalid(&self, id: &str) -> Result<(), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let subject = match &self.quota {
+      Some(quota) => {
+        let organization_id: Option<String> = conn
+          .hget(self.keys.operation(id), "organization_id")
+          .await?;
+        organization_id.map(|organization_id| (quota, quota::organization(&organization_id)))
+      }
+      None => None,
+    };
+
+    let script = redis::Script::new(REPLAY_INVALID_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation
+      .key(self.keys.invalid(&self.queue))
+      .key(self.keys.queue(&self.queue))
+      .key(self.keys.operation(id))
+      .arg(id)
+      .arg(OperationState::Queued.as_str());
+    if let Some((quota, subject)) = &subject {
+      invocation
+        .key(quota.usage_key(subject))
+        .key(quota.limits_key(subject))
+        .arg(quota::CONCURRENT_OPERATIONS)
+        .arg(quota.default_limit(quota::CONCURRENT_OPERATIONS))
+        .arg(quota::OPERATION_SUBJECT_FIELD)
+        .arg(subject);
+    }
+
+    let replayed: Vec<i64> = invocation
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-replay-invalid", operation_id = %id))
+      .await?;
+
+    match replayed.as_slice() {
+      [1] => {}
+      [-1, usage, limit] => {
+        return Err(
+          QuotaError::Exceeded {
+            subject: subject.map(|(_, subject)| subject).unwrap_or_default(),
+            resource: quota::CONCURRENT_OPERATIONS.to_string(),
+            usage: *usage,
+            limit: *limit,
+          }
+          .into(),
+        )
+      }
+      _ => {
+        return Err(RedisQueueError::NotFound(format!(
+          "No invalid message with operation_id = {}",
+          id
+        )))
+      }
+    }
+
+    tracing::info!(message = "Replayed invalid message", operation_id = %id, queue = %self.queue);
+    Ok(())
+  }
+
+  /// Enqueues a task of type `task_type` whose payload is already encoded as `content_type`,
+  /// for callers that do not know the task types at compile time.
+  pub async fn offer_raw(
+    &self,
+    task_type: &str,
+    content_type: &str,
+    payload: Vec<u8>,
+    ctx: &Context,
+  ) -> Result<String, RedisQueueError> {
+    self
+      .offer_encoded(task_type, content_type, payload, None, None, ctx)
+      .await
+  }
+
+  fn check_impersonation(&self, ctx: &Context) -> Result<(), RedisQueueError> {
+    let impersonators = match &self.impersonators {
+      Some(impersonators) if ctx.is_impersonating() => impersonators,
+      _ => return Ok(()),
+    };
+
+    let principal = ctx.principal();
+    let system = self.system_context.as_ref().map(Context::principal);
+    if impersonators.contains(principal) || system == Some(principal) {
+      return Ok(());
+    }
+
+    Err(RedisQueueError::PermissionDenied(format!(
+      "{} may not enqueue on behalf of {} to {}",
+      principal,
+      ctx.user_id(),
+      self.queue
+    )))
+  }
+
+  /// Enqueues a task already encoded as `content_type`, whatever its task type.
+  async fn offer_encoded(
+    &self,
+    task_type: &str,
+    content_type: &str,
+    task: Vec<u8>,
+    schema_version: Option<u32>,
+    due: Option<DateTime<Utc>>,
+    ctx: &Context,
+  ) -> Result<String, RedisQueueError> {
+    let encoded = EncodedTask {
+      task_type,
+      content_type,
+      payload: task,
+      schema_version,
+    };
+    let (mut ids, _) = self.offer_batch(vec![encoded], due, None, ctx).await?;
+    Ok(ids.remove(0))
+  }
+
+  /// Enqueues tasks already encoded as their content type in a single transaction, so either all
+  /// of them are enqueued or none is. Returns their ids, in the order of `tasks`.
+  ///
+  /// With `unique`, the tasks are only written if they claim its key. Otherwise nothing is
+  /// written and the operation the key points at is returned instead. Meant for a single task.
+  async fn offer_batch(
+    &self,
+    tasks: Vec<EncodedTask<'_>>,
+    due: Option<DateTime<Utc>>,
+    unique: Option<UniqueClaim<'_>>,
+    ctx: &Context,
+  ) -> Result<(Vec<String>, Option<String>), RedisQueueError> {
+    if tasks.is_empty() {
+      return Ok((Vec::default(), None));
+    }
+    for task in &tasks {
+      if self.protocol_version < 2 && task.content_type != crate::codec::json::CONTENT_TYPE {
+        return Err(RedisQueueError::UnsupportedContentType(
+          task.content_type.to_string(),
+        ));
+      }
+      self
+        .payload_limits
+        .check(&self.queue, task.task_type, task.payload.len())?;
+    }
+    self.check_impersonation(ctx)?;
+
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let deferred = match maintenance::read(&mut conn, &self.keys).await? {
+      Some(maintenance) if maintenance.enqueue == EnqueuePolicy::Reject => {
+        return Err(
+          MaintenanceMode {
+            reason: maintenance.reason,
+          }
+          .into(),
+        )
+      }
+      Some(maintenance) => maintenance.enqueue == EnqueuePolicy::Defer,
+      None => false,
+    };
+
+    let ids = tasks
+      .iter()
+      .map(|_| self.ids.generate())
+      .collect::<Result<Vec<_>, _>>()?;
+    let context = self.contexts.serialize(ctx)?;
+    let reserved = tasks.len() as i64;
+
+    let subject = match (&self.quota, ctx.organization_id()) {
+      (Some(quota), Some(organization_id)) => {
+        let subject = quota::organization(organization_id);
+        quota
+          .check_and_reserve(&subject, quota::CONCURRENT_OPERATIONS, reserved)
+          .await?;
+        Some(subject)
+      }
+      _ => None,
+    };
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+
+    let mut offered = Vec::with_capacity(tasks.len());
+    for (id, task) in ids.into_iter().zip(tasks) {
+      let EncodedTask {
+        task_type,
+        content_type,
+        payload,
+        schema_version,
+      } = task;
+      let publish_ts = self.clock.timestamp_nanos();
+
+      // Deferred operations are due once the maintenance ends, see `RedisAdmin::end_maintenance`.
+      let (destination, score) = match (deferred, due) {
+        (true, _) => (
+          self.keys.delayed(&self.queue),
+          Some(maintenance::DEFERRED_SCORE.to_string()),
+        ),
+        (false, Some(due)) => (
+          self.keys.delayed(&self.queue),
+          Some(due.timestamp_millis().to_string()),
+        ),
+        (false, None) if ctx.priority() == Priority::Interactive => {
+          (self.keys.interactive(&self.queue), None)
+        }
+        (false, None) => (self.keys.queue(&self.queue), None),
+      };
+
+      match score {
+        Some(score) => pipe.zadd(destination, id.clone(), score).ignore(),
+        None => pipe.lpush(destination, id.clone()).ignore(),
+      };
+
+      if let Some(unique) = &unique {
+        pipe.set(unique.key, &id).ignore();
+      }
+
+      let mut pipeline = pipe
+        .hset_multiple(
+          self.keys.operation(&id),
+          &[
+            ("status", OperationState::Queued.as_str()),
+            ("operation_id", &id),
+            ("queue", &self.queue),
+            ("publish_ts", &publish_ts.to_string()),
+            ("user_id", ctx.user_id()),
+            ("task_type", task_type),
+            ("content_type", content_type),
+            ("producer_version", &self.app_version),
+          ],
+        )
+        .ignore()
+        .hset(self.keys.operation(&id), "task", payload)
+        .ignore()
+        .hset(self.keys.operation(&id), "context", &context)
+        .ignore();
+
+      index::record(
+        pipeline,
+        &self.keys,
+        &id,
+        &self.queue,
+        ctx.user_id(),
+        Utc.timestamp_nanos(publish_ts),
+      );
+
+      if let Some(schema_version) = schema_version {
+        pipeline = pipeline
+          .hset(self.keys.operation(&id), "schema_version", schema_version)
+          .ignore();
+      }
+
+      if let Some(organization_id) = ctx.organization_id() {
+        pipeline = pipeline
+          .hset(self.keys.operation(&id), "organization_id", organization_id)
+          .ignore();
+      }
+
+      if let Some(subject) = &subject {
+        pipeline = pipeline
+          .hset(
+            self.keys.operation(&id),
+            quota::OPERATION_SUBJECT_FIELD,
+            subject,
+          )
+          .ignore();
+      }
+
+      if self.protocol_version >= 2 {
+        pipeline = pipeline
+          .hset(
+            self.keys.operation(&id),
+            "protocol_version",
+            self.protocol_version,
+          )
+          .ignore();
+      }
+
+      if let Some(callback_url) = ctx.callback_url() {
+        pipeline = pipeline
+          .hset(self.keys.operation(&id), "callback_url", callback_url)
+          .ignore();
+      }
+
+      if let Some(parent) = ctx.parent_operation_id() {
+        pipeline = pipeline
+          .hset(self.keys.operation(&id), "parent_operation_id", parent)
+          .ignore()
+          .rpush(self.keys.children(parent), &id)
+          .ignore();
+      }
+
+      if let Some(region) = &self.replication {
+        pipeline
+          .hset(self.keys.operation(&id), "origin_region", region)
+          .ignore()
+          .lpush(
+            self.keys.replication(&self.queue),
+            ReplicationEvent::enqueued(region, &id).to_string(),
+          )
+          .ignore();
+      }
+
+      offered.push((id, task_type));
+    }
+
+    let span = tracing::info_span!(
+      "redis-queue-offer",
+      operation_id = %offered[0].0,
+      count = offered.len()
+    );
+    let written = match &unique {
+      Some(unique) => claim(&mut conn, &pipe, unique).instrument(span).await,
+      None => pipe
+        .query_async(&mut conn)
+        .instrument(span)
+        .await
+        .map(|()| None),
+    };
+
+    match written {
+      Ok(None) => {}
+      Ok(Some(holder)) => {
+        self.release_reserved(&subject, reserved).await;
+        return Ok((Vec::default(), Some(holder)));
+      }
+      Err(error) => {
+        self.release_reserved(&subject, reserved).await;
+        return Err(error.into());
+      }
+    }
+
+    for (id, task_type) in &offered {
+      self
+        .publish_event(
+          id,
+          OperationEventType::Created,
+          ctx.user_id(),
+          HashMap::from([("task_type".to_string(), task_type.to_string())]),
+        )
+        .await;
+    }
+
+    Ok((offered.into_iter().map(|(id, _)| id).collect(), None))
+  }
+
+  /// Releases the quota slots reserved for operations that were not enqueued.
+  async fn release_reserved(&self, subject: &Option<String>, reserved: i64) {
+    if let (Some(quota), Some(subject)) = (&self.quota, subject) {
+      let _ = quota
+        .release(subject, quota::CONCURRENT_OPERATIONS, reserved)
+        .await;
+    }
+  }
+
+  /// Dequeues the next operation without decoding it, whatever its task type. Returns `None` when
+  /// the queue is empty or paused.
+  pub async fn pull_raw(&self, ctx: &Context) -> Result<Option<RawMessage>, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let dequeue_ts = self.clock.timestamp_nanos().to_string();
+    let mut fields = vec![
+      ("status", OperationState::Running.as_str()),
+      ("dequeue_ts", dequeue_ts.as_str()),
+    ];
+    let mut audit = vec![
+      ("dequeue_system_id", ctx.system_id()),
+      ("dequeue_user_id", ctx.user_id()),
+    ];
+    if self.ack_mode == AckMode::Auto {
+      audit.extend(ack_audit(ctx, &dequeue_ts));
+    }
+
+    if self.store.is_none() {
+      fields.extend(audit.iter().copied());
+    }
+
+    let script = redis::Script::new(PULL_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation
+      .key(self.keys.paused(&self.queue))
+      .key(self.keys.queue(&self.queue))
+      .key(self.keys.ack(&self.queue))
+      .key(self.keys.delayed(&self.queue))
+      .key(self.keys.maintenance())
+      .key(self.keys.interactive(&self.queue))
+      .arg(self.keys.operation(""))
+      .arg(self.ack_mode != AckMode::Auto)
+      .arg(self.clock.now().timestamp_millis());
+    for (name, value) in &fields {
+      invocation.arg(*name).arg(*value);
+    }
+
+    let pulled: Option<(String, HashMap<String, Vec<u8>>)> = invocation
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-pull"))
+      .await?;
+
+    let (op_id, mut op) = match pulled {
+      None => return Ok(None),
+      Some(pulled) => pulled,
+    };
+
+    if let Some(store) = &self.store {
+      store.update(&op_id, &audit).await?;
+    }
+
+    let field = |name: &str| {
+      op.get(name)
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+        .unwrap_or_default()
+    };
+    let attempt = field("attempt");
+    let user_id = field("user_id");
+    let task_type = field("task_type");
+    let schema_version = field("schema_version").parse().ok();
+    let producer_version = field("producer_version");
+    let context = match op.get("context") {
+      Some(envelope) => self.contexts.deserialize(envelope).unwrap_or_else(|error| {
+        tracing::warn!(message = "Failed to read the context of the operation", operation_id = %op_id, %error);
+        Context::new(user_id.clone(), String::default())
+      }),
+      None => Context::new(user_id.clone(), String::default()),
+    };
+    let version = protocol_version(&op);
+    if version > PROTOCOL_VERSION {
+      tracing::debug!(message = "Reading an operation of a newer protocol version", operation_id = %op_id, %version);
+    }
+    // Payloads stored before content types were recorded are JSON.
+    let content_type = Some(field("content_type"))
+      .filter(|content_type| !content_type.is_empty())
+      .unwrap_or_else(|| crate::codec::json::CONTENT_TYPE.to_string());
+
+    self
+      .publish_event(
+        &op_id,
+        OperationEventType::Started,
+        &user_id,
+        HashMap::from([
+          ("attempt".to_string(), attempt.clone()),
+          ("system_id".to_string(), ctx.system_id().to_string()),
+        ]),
+      )
+      .await;
+
+    Ok(Some(RawMessage {
+      attempt: attempt.parse().unwrap_or(1),
+      user_id,
+      context,
+      task_type,
+      content_type,
+      payload: op.remove("task").unwrap_or_default().into(),
+      schema_version,
+      producer_version,
+      ack_id: op_id,
+      protocol_version: version,
+    }))
+  }
+
+  /// State of the operation `id`, `None` if it does not exist or its status is unknown. Lets
+  /// workers notice that the operation they perform was cancelled.
+  pub async fn state(&self, id: &str) -> Result<Option<OperationState>, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let status: Option<String> = conn
+      .hget(self.keys.operation(id), "status")
+      .instrument(tracing::info_span!("redis-queue-state-hget"))
+      .await?;
+
+    Ok(status.and_then(|status| status.parse().ok()))
+  }
+
+  /// Number of operations waiting in the queue, interactive ones included, and of operations
+  /// pulled but not acknowledged yet. Delayed operations are not counted.
+  pub async fn outstanding(&self) -> Result<(i64, i64), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let (background, interactive, in_flight): (i64, i64, i64) = redis::pipe()
+      .llen(self.keys.queue(&self.queue))
+      .llen(self.keys.interactive(&self.queue))
+      .llen(self.keys.ack(&self.queue))
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-outstanding", queue = %self.queue))
+      .await?;
+
+    Ok((background + interactive, in_flight))
+  }
+
+  /// Acknowledges the operation `ack_id` returned by [`RedisQueue::pull_raw`], removing it from
+  /// the in-flight list. Only needed with [`AckMode::Manual`], the other modes acknowledge on
+  /// their own.
+  pub async fn ack_raw(&self, ack_id: &str, ctx: &Context) -> Result<(), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let maybe_queue: Option<String> = conn
+      .hget(self.keys.operation(ack_id), "queue")
+      .instrument(tracing::info_span!("redis-queue-ack-hget"))
+      .await?;
+
+    let queue = match maybe_queue {
+      None => {
+        tracing::debug!(message = "Cannot find queue name", %ack_id);
+        return Err(RedisQueueError::NotFound(format!(
+          "Missing operation queue info for ack_id = {}",
+          ack_id
+        )));
+      }
+      Some(q) => q,
+    };
+
+    let ack_ts = self.clock.timestamp_nanos().to_string();
+    let audit = ack_audit(ctx, &ack_ts);
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+
+    match &self.store {
+      Some(store) => store.update(ack_id, &audit).await?,
+      None => {
+        pipe
+          .hset_multiple(self.keys.operation(ack_id), &audit)
+          .ignore();
+      }
+    }
+
+    let _: () = pipe
+      .lrem(self.keys.ack(&queue), 1, ack_id)
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-ack-lrem"))
+      .await?;
+
+    tracing::debug!(message = "Acknowledged message", %ack_id);
+    Ok(())
+  }
+
+  /// Declines the in-flight operation `ack_id` returned by [`RedisQueue::pull_raw`]: it is
+  /// delivered again, after `delay` if any, as a new attempt. Unlike
+  /// [`RedisQueue::record_failure`] this does not count towards quarantine. Fails with
+  /// [`RedisQueueError::NotFound`] if the operation is not in flight, e.g. because it was pulled
+  /// with [`AckMode::Auto`].
+  pub async fn nack_raw(
+    &self,
+    ack_id: &str,
+    delay: Option<Duration>,
+    ctx: &Context,
+  ) -> Result<(), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let due = match delay {
+      Some(delay) if !delay.is_zero() => {
+        self.clock.now().timestamp_millis() + delay.as_millis().min(i64::MAX as u128) as i64
+      }
+      _ => 0,
+    };
+
+    let requeued: bool = redis::Script::new(NACK_SCRIPT)
+      .key(self.keys.ack(&self.queue))
+      .key(self.keys.queue(&self.queue))
+      .key(self.keys.delayed(&self.queue))
+      .key(self.keys.operation(ack_id))
+      .arg(ack_id)
+      .arg(due)
+      .arg(OperationState::Queued)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-queue-nack"))
+      .await?;
+
+    if !requeued {
+      return Err(RedisQueueError::NotFound(format!(
+        "Operation {} is not in flight",
+        ack_id
+      )));
+    }
+
+    tracing::debug!(message = "Declined message", %ack_id, ?delay, system_id = %ctx.system_id());
+    Ok(())
+  }
+
+  /// Logs and publishes the quarantine of the operation `id` by [`FAILURE_SCRIPT`].
+  async fn quarantined(
+    &self,
+    conn: &mut InstrumentedConnection,
+    id: &str,
+    reason: &str,
+    failures: i64,
+  ) -> Result<(), RedisQueueError> {
+    tracing::error!(message = "Quarantined poison message", operation_id = %id, queue = %self.queue, %failures, %reason);
+
+    let user_id: Option<String> = conn.hget(self.keys.operation(id), "user_id").await?;
+    let event = self.event(
+      id,
+      OperationEventType::Quarantined,
+      &user_id.unwrap_or_default(),
+      HashMap::from([
+        ("reason".to_string(), reason.to_string()),
+        ("failures".to_string(), failures.to_string()),
+      ]),
+    );
+
+    self.events.publish(event).await
+  }
+}
+
+impl<T, C> RedisQueue<T, C>
+where
+  T: Send + Sync + Performable + 'static,
+  C: Codec<Encodable = T, Decodable = T> + Send + Sync,
+  C::EncodingError: std::error::Error + Send + Sync + 'static,
+  C::DecodingError: std::error::Error + Send + Sync + 'static,
+{
+  /// Enqueues `item` with `priority` rather than the priority of `ctx`, e.g. to demote the
+  /// maintenance an interactive request triggers. Interactive operations are pulled before the
+  /// background ones, but keep no precedence once delayed or released again.
+  pub async fn offer_with_priority(
+    &self,
+    item: T,
+    priority: Priority,
+    ctx: &Context,
+  ) -> Result<String, RedisQueueError> {
+    let ctx = ctx.clone().with_priority(priority);
+    self.offer_due(item, None, &ctx).await
+  }
+
+  /// Enqueues `item` to be delivered once `at` is due, right away if it is past. Until then the
+  /// operation waits in the delayed set of the queue.
+  pub async fn offer_at(
+    &self,
+    item: T,
+    at: DateTime<Utc>,
+    ctx: &Context,
+  ) -> Result<String, RedisQueueError> {
+    self.offer_due(item, Some(at), ctx).await
+  }
+
+  /// Enqueues `item` with the system context of the queue, for internal components enqueuing
+  /// work of their own. Fails when the queue has none, see [`Self::with_system_context`].
+  pub async fn offer_as_system(&self, item: T) -> Result<String, RedisQueueError> {
+    let ctx = self.system_context.clone().ok_or_else(|| {
+      RedisQueueError::Internal(format!("No system context for queue {}", self.queue))
+    })?;
+
+    self.offer_due(item, None, &ctx).await
+  }
+
+  /// Enqueues `items` in a single transaction, so either all of them are enqueued or none is.
+  /// Returns their ids, in the order of `items`.
+  pub async fn offer_all(
+    &self,
+    items: Vec<T>,
+    ctx: &Context,
+  ) -> Result<Vec<String>, RedisQueueError> {
+    let tasks = items
+      .iter()
+      .map(|item| self.encode(item))
+      .collect::<Result<Vec<_>, _>>()?;
+
+    let (ids, _) = self.offer_batch(tasks, None, None, ctx).await?;
+    Ok(ids)
+  }
+
+  /// Enqueues `item` unless the unique `key` points at another operation than `expected`, which
+  /// the key was read to point at, if at all. The operation is only written if it claims the key,
+  /// in the same transaction. Returns the id of the operation the key points at afterwards, and
+  /// whether it is the one just enqueued.
+  async fn offer_unique(
+    &self,
+    item: T,
+    key: &str,
+    expected: Option<&str>,
+    ctx: &Context,
+  ) -> Result<(String, bool), RedisQueueError> {
+    let task = self.encode(&item)?;
+    let unique = UniqueClaim { key, expected };
+    let (mut ids, holder) = self
+      .offer_batch(vec![task], None, Some(unique), ctx)
+      .await?;
+    Ok(match holder {
+      Some(holder) => (holder, false),
+      None => (ids.remove(0), true),
+    })
+  }
+
+  async fn offer_due(
+    &self,
+    item: T,
+    due: Option<DateTime<Utc>>,
+    ctx: &Context,
+  ) -> Result<String, RedisQueueError> {
+    let EncodedTask {
+      task_type,
+      content_type,
+      payload,
+      schema_version,
+    } = self.encode(&item)?;
+
+    self
+      .offer_encoded(task_type, content_type, payload, schema_version, due, ctx)
+      .await
+  }
+
+  fn encode(&self, item: &T) -> Result<EncodedTask<'static>, RedisQueueError> {
+    let mut task = Vec::default();
+    self
+      .codec
+      .encoder()
+      .encode(item, &mut task)
+      .map_err(|error| crate::codec::Error::Encode(Box::new(error)))?;
+
+    Ok(EncodedTask {
+      task_type: T::type_name(),
+      content_type: self.codec.content_type(),
+      payload: task,
+      schema_version: Some(T::schema_version()),
+    })
+  }
+}
+
+#[async_trait::async_trait]
+impl<T, C> super::Queue for RedisQueue<T, C>
+where
+  T: Send + Sync + Performable + 'static,
+  C: Codec<Encodable = T, Decodable = T> + Send + Sync,
+  C::EncodingError: std::error::Error + Send + Sync + 'static,
+  C::DecodingError: std::error::Error + Send + Sync + 'static,
+{
+  type Item = T;
+
+  type ReceivedItem = RedisMessage<T>;
+
+  type Error = RedisQueueError;
+
+  async fn offer(&self, item: Self::Item, ctx: &Context) -> Result<String, Self::Error> {
+    self.offer_due(item, None, ctx).await
+  }
+
+  async fn pull(&self, ctx: &Context) -> Result<Option<Self::ReceivedItem>, Self::Error> {
+    let message = match self.pull_raw(ctx).await? {
+      None => return Ok(None),
+      Some(message) => message,
+    };
+
+    if message.task_type != Self::Item::type_name() {
+      tracing::error!(message = "Invalid task type encountered in the queue", task_type = %message.task_type);
       return Err(Self::Error::InvalidTaskType(
         std::any::type_name::<Self::Item>().to_string(),
-        op["task_type"].to_string(),
+        message.task_type,
       ));
     }
 
-    let mut decoder = self.codec.decoder();
-    let task = op["task"].clone();
-    let mut buf = task.into_bytes();
-    let task: Option<Self::Item> = decoder.decode(&mut buf)?;
+    if self
+      .hold_skewed(&message, Self::Item::schema_version())
+      .await?
+    {
+      return Ok(None);
+    }
+
+    let task: Option<Self::Item> = match self.decode(&message) {
+      Ok(task) => task,
+      Err(error) => {
+        self.invalidate(&message.ack_id, &error.to_string()).await?;
+        return Err(error);
+      }
+    };
+
+    match task {
+      Some(t) => Ok(Some(RedisMessage {
+        attempt: message.attempt,
+        user_id: message.user_id,
+        context: message.context,
+        ack_id: message.ack_id,
+        data: t,
+      })),
+      None => Err(Self::Error::Internal("Failed to decode task".to_string())),
+    }
+  }
+
+  async fn ack(&self, ack_id: &str, ctx: &Context) -> Result<(), Self::Error> {
+    self.ack_raw(ack_id, ctx).await
+  }
+
+  async fn nack(
+    &self,
+    ack_id: &str,
+    delay: Option<Duration>,
+    ctx: &Context,
+  ) -> Result<(), Self::Error> {
+    self.nack_raw(ack_id, delay, ctx).await
+  }
+}
+
+impl<T, C> RedisQueue<T, C>
+where
+  T: 'static,
+  C: Codec<Decodable = T>,
+  C::DecodingError: std::error::Error + Send + Sync + 'static,
+{
+  /// Decodes the payload with the codec of its content type.
+  fn decode(&self, message: &RawMessage) -> Result<Option<T>, RedisQueueError> {
+    if message.content_type == self.codec.content_type() {
+      let mut buf = message.payload.clone();
+
+      return self
+        .codec
+        .decoder()
+        .decode(&mut buf)
+        .map_err(|error| crate::codec::Error::Decode(Box::new(error)).into());
+    }
+
+    let codec = self
+      .decoders
+      .get(message.content_type.as_str())
+      .ok_or_else(|| RedisQueueError::UnsupportedContentType(message.content_type.clone()))?;
+
+    match codec.decode_any(&message.payload)? {
+      None => Ok(None),
+      Some(task) => task.downcast::<T>().map(|task| Some(*task)).map_err(|_| {
+        RedisQueueError::Internal(format!(
+          "Decoder of {} does not decode {}",
+          message.content_type,
+          std::any::type_name::<T>()
+        ))
+      }),
+    }
+  }
+}
+
+fn invalid_field(field: &str, error: impl std::fmt::Display) -> redis::RedisError {
+  redis::RedisError::from((
+    redis::ErrorKind::TypeError,
+    "Invalid operation field",
+    format!("{}: {}", field, error),
+  ))
+}
+
+/// Reads an operation hash. Missing fields take their default value and unknown fields are
+/// ignored, while malformed timestamps, counters, flags or errors fail with a `TypeError` instead
+/// of panicking. Text fields are read lossily. The task itself is not read, `metadata` only holds the
+/// values supplied with the operation.
+impl FromRedisValue for Operation {
+  fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+    let mut fields: HashMap<String, Vec<u8>> = from_redis_value(v)?;
+    let protocol = protocol_version(&fields);
+
+    let mut text = |field: &str| {
+      fields
+        .remove(field)
+        .map(|value| String::from_utf8_lossy(&value).into_owned())
+    };
+
+    let operation_id = text("operation_id").unwrap_or_default();
+    let parent_operation_id = text("parent_operation_id").unwrap_or_default();
+    let task_type = text("task_type").unwrap_or_default();
+    let queue = text("queue").unwrap_or_default();
+    let user_id = text("user_id").unwrap_or_default();
+
+    let mut labels = HashMap::from([("protocol_version".to_string(), protocol.to_string())]);
+    // Unknown states are kept verbatim in the labels rather than failing the whole operation.
+    let status = text("status").unwrap_or_default();
+    let state = match status.parse::<OperationState>() {
+      Ok(state) => ProtoOperationState::from(state) as i32,
+      Err(_) => {
+        if !status.is_empty() {
+          labels.insert("status".to_string(), status);
+        }
+        ProtoOperationState::Unspecified as i32
+      }
+    };
+    if let Some(value) = text("sla_violated") {
+      labels.insert("sla_violated".to_string(), value);
+    }
+
+    let mut metadata = HashMap::default();
+    if let Some(callback_url) = text("callback_url") {
+      metadata.insert("callback_url".to_string(), callback_url);
+    }
+
+    let attempt = match text("attempt") {
+      None => 0,
+      Some(value) => value
+        .parse()
+        .map_err(|error| invalid_field("attempt", error))?,
+    };
+    let version = match text("version") {
+      None => 0,
+      Some(value) => value
+        .parse()
+        .map_err(|error| invalid_field("version", error))?,
+    };
+
+    let done = match text("done").as_deref() {
+      None | Some("false") => false,
+      Some("true") => true,
+      Some(value) => return Err(invalid_field("done", format!("{:?} is not a bool", value))),
+    };
+
+    let mut timestamp = |field: &str| {
+      text(field)
+        .map(|value| {
+          value
+            .parse::<ProstTimestamp>()
+            .map(ProstTimestamp::into_inner)
+            .map_err(|error| invalid_field(field, error))
+        })
+        .transpose()
+    };
+
+    let creation_ts = timestamp("publish_ts")?;
+    let start_ts = timestamp("dequeue_ts")?;
+    let end_ts = timestamp("end_ts")?;
+
+    let error = fields
+      .remove("error")
+      .map(|error| Status::decode(error.as_slice()))
+      .transpose()
+      .map_err(|error| invalid_field("error", error))?;
+
+    Ok(Self {
+      operation_id,
+      metadata,
+      task_type,
+      queue,
+      user_id,
+      attempt,
+      version,
+      labels,
+      done,
+      error,
+      response: HashMap::default(),
+      state,
+      parent_operation_id,
+      child_operation_ids: Vec::default(),
+      annotations: HashMap::default(),
+      creation_ts,
+      start_ts,
+      end_ts,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use chrono::Utc;
+  use redis::AsyncCommands;
+  use serde::Deserialize;
+  use uuid::Uuid;
+
+  use crate::util::clock::TestClock;
+  use crate::{longrunning::Queue, proto::google::protobuf::Empty};
+
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Clone)]
+  struct Task {
+    item: i32,
+  }
+
+  #[async_trait::async_trait]
+  impl Performable for Task {
+    type Error = std::io::Error;
+    type Context = ();
+    type Output = Empty;
+
+    fn type_name() -> &'static str {
+      "longrunning::redis::tests::Task"
+    }
+
+    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+      Ok(Empty::default())
+    }
+  }
+
+  fn hash(fields: &[(&str, &[u8])]) -> redis::Value {
+    redis::Value::Bulk(
+      fields
+        .iter()
+        .flat_map(|(key, value)| {
+          [
+            redis::Value::Data(key.as_bytes().to_vec()),
+            redis::Value::Data(value.to_vec()),
+          ]
+        })
+        .collect(),
+    )
+  }
+
+  #[test]
+  fn broker_error_status_should_keep_the_context() {
+    let error = RedisQueueError::Conflict("Version changed".to_string())
+      .with_context(ErrorContext::new().with_operation("42"))
+      .with_context(
+        ErrorContext::new()
+          .with_queue("backups")
+          .with_operation("7"),
+      );
+    assert!(matches!(error.inner(), RedisQueueError::Conflict(_)));
+    assert_eq!(
+      error.to_string(),
+      "Conflict: Version changed (queue=backups operation_id=42)"
+    );
+
+    let status = tonic::Status::from(BrokerError::CancelError(error));
+    assert_eq!(status.code(), tonic::Code::Aborted);
+    assert_eq!(
+      status.message(),
+      "Version changed (queue=backups operation_id=42)"
+    );
+  }
+
+  #[test]
+  fn broker_should_be_configurable_after_being_cloned() {
+    let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let broker: RedisBroker<Task> = RedisBroker::new(client, "backups");
+    let shutdown = ShutdownToken::new();
+    shutdown.shutdown();
+
+    let configured = broker.clone().with_shutdown(shutdown);
+    assert!(matches!(
+      configured.accepting(),
+      Err(BrokerError::ShuttingDown)
+    ));
+    assert!(broker.accepting().is_ok());
+  }
+
+  #[test]
+  fn operation_from_redis_value_should_reject_malformed_fields() {
+    let operation = Operation::from_redis_value(&hash(&[
+      ("operation_id", b"1"),
+      ("task", &[0xff, 0x00]),
+      ("publish_ts", b"1500000000"),
+      ("unknown", b"ignored"),
+    ]))
+    .unwrap();
+
+    assert_eq!(operation.operation_id, "1");
+    assert_eq!(operation.creation_ts.unwrap().seconds, 1);
+    assert!(!operation.done);
+    assert_eq!(operation.queue, "");
+    assert_eq!(operation.labels["protocol_version"], "1");
+    assert!(operation.metadata.is_empty());
+
+    for (field, value) in [
+      ("publish_ts", &b"yesterday"[..]),
+      ("done", b"maybe"),
+      ("attempt", b"twice"),
+      ("error", &[0xff]),
+    ] {
+      let error = Operation::from_redis_value(&hash(&[(field, value)])).unwrap_err();
+      assert_eq!(error.kind(), redis::ErrorKind::TypeError, "{}", field);
+    }
+  }
+
+  #[tokio::test]
+  async fn pull_should_restore_the_enqueue_context() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"))
+      .with_organization_id("acme")
+      .with_trace_parent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+      .with_deadline(chrono::DateTime::from_timestamp_millis(1_700_000_000_123).unwrap());
+    let worker = Context::new(String::from("worker"), String::from("5678"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue, JsonCodec::new());
+
+    let id = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    let message = q.pull(&worker).await.unwrap().unwrap();
+    assert_eq!(message.context, ctx);
+
+    // Operations enqueued before the context was stored only get their user back.
+    let mut conn = client.get_async_connection().await.unwrap();
+    let _: () = conn
+      .hdel(format!("operation:{}", id), "context")
+      .await
+      .unwrap();
+    q.nack(&id, None, &worker).await.unwrap();
+    let message = q.pull(&worker).await.unwrap().unwrap();
+    assert_eq!(
+      message.context,
+      Context::new(ctx.user_id().to_string(), String::default())
+    );
+  }
+
+  #[tokio::test]
+  async fn pull_should_prefer_interactive_operations() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue, JsonCodec::new());
+
+    q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    q.offer_with_priority(Task { item: 2 }, Priority::Interactive, &ctx)
+      .await
+      .unwrap();
+    q.offer(
+      Task { item: 3 },
+      &ctx.clone().with_priority(Priority::Interactive),
+    )
+    .await
+    .unwrap();
+
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(message.data.item, 2);
+    assert_eq!(message.context.priority(), Priority::Interactive);
+    assert_eq!(q.pull(&ctx).await.unwrap().unwrap().data.item, 3);
+
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(message.data.item, 1);
+    assert_eq!(message.context.priority(), Priority::Background);
+  }
+
+  #[tokio::test]
+  async fn pull_should_drop_operations_already_done() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
+
+    let cancelled = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    q.offer(Task { item: 2 }, &ctx).await.unwrap();
+
+    // Cancelled, but not taken off the queue yet.
+    let mut conn = client.get_async_connection().await.unwrap();
+    let _: () = conn
+      .hset_multiple(
+        format!("operation:{}", cancelled),
+        &[("done", "true"), ("status", "Cancelled")],
+      )
+      .await
+      .unwrap();
+
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(message.data.item, 2);
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+
+    let in_flight: Vec<String> = conn
+      .lrange(format!("queue:ack:{}", queue), 0, -1)
+      .await
+      .unwrap();
+    assert_eq!(in_flight, vec![message.ack_id]);
+
+    let op: HashMap<String, String> = conn
+      .hgetall(format!("operation:{}", cancelled))
+      .await
+      .unwrap();
+    assert_eq!(op["status"], "Cancelled");
+    assert!(!op.contains_key("attempt"));
+  }
+
+  #[tokio::test]
+  async fn pull_should_read_older_protocol_versions() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let current: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
+    let legacy = current.clone().with_protocol_version(1);
+
+    let v1 = legacy.offer(Task { item: 1 }, &ctx).await.unwrap();
+    let v2 = current.offer(Task { item: 2 }, &ctx).await.unwrap();
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let versions: Vec<Option<u32>> = redis::pipe()
+      .hget(format!("operation:{}", v1), "protocol_version")
+      .hget(format!("operation:{}", v2), "protocol_version")
+      .query_async(&mut conn)
+      .await
+      .unwrap();
+    assert_eq!(versions, vec![None, Some(PROTOCOL_VERSION)]);
+
+    let first = current.pull_raw(&ctx).await.unwrap().unwrap();
+    let second = current.pull_raw(&ctx).await.unwrap().unwrap();
+    assert_eq!((first.ack_id, first.protocol_version), (v1, 1));
+    assert_eq!((second.ack_id, second.protocol_version), (v2, 2));
+    assert_eq!(first.content_type, crate::codec::json::CONTENT_TYPE);
+  }
+
+  #[tokio::test]
+  async fn offer_should_add_item_to_queue() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
+    let task = Task { item: 10 };
+
+    let id = q.offer(task, &ctx).await.unwrap();
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let result: Vec<String> = conn
+      .lrange(format!("queue:{}", queue), 0, -1)
+      .await
+      .unwrap();
+    assert_eq!(vec![id], result);
+  }
+
+  #[tokio::test]
+  async fn nack_should_redeliver_after_delay() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
+
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    q.nack(&message.ack_id, None, &ctx).await.unwrap();
+    assert!(q.nack(&message.ack_id, None, &ctx).await.is_err());
+
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(message.attempt, 2);
+    q.nack(&message.ack_id, Some(Duration::from_millis(200)), &ctx)
+      .await
+      .unwrap();
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!((message.ack_id, message.attempt), (id, 3));
+  }
+
+  #[tokio::test]
+  async fn pull_should_hold_tasks_of_newer_schema_versions() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let holding = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new())
+        .with_app_version("2.0.0")
+        .with_holding_queue(&holding);
+
+    let current = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    let newer = q.offer(Task { item: 2 }, &ctx).await.unwrap();
+    let mut conn = client.get_async_connection().await.unwrap();
+    let _: () = conn
+      .hset(q.keys.operation(&newer), "schema_version", 2)
+      .await
+      .unwrap();
+
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(message.ack_id, current);
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+    assert_eq!(q.outstanding().await.unwrap(), (0, 1));
+    assert_eq!(skew::metrics().count(&queue, Task::type_name()), 1);
+
+    let held: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), holding.clone(), JsonCodec::new());
+    let message = held.pull_raw(&ctx).await.unwrap().unwrap();
+    assert_eq!(message.ack_id, newer);
+    assert_eq!(message.schema_version, Some(2));
+    assert_eq!(message.producer_version, "2.0.0");
+  }
+
+  #[tokio::test]
+  async fn complete_should_mark_sla_violations() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let sla = SlaTracker::new().with_threshold(Task::type_name(), Duration::from_millis(50));
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), "sla".to_string(), JsonCodec::new())
+        .with_keys(keys.clone())
+        .with_sla(sla.clone());
+
+    let fast = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    let slow = q.offer(Task { item: 2 }, &ctx).await.unwrap();
+    q.complete(&fast, Ok::<_, Status>(Empty {}), &ctx)
+      .await
+      .unwrap();
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    q.complete(&slow, Ok::<_, Status>(Empty {}), &ctx)
+      .await
+      .unwrap();
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let violated: Vec<Option<String>> = redis::pipe()
+      .hget(keys.operation(&fast), "sla_violated")
+      .hget(keys.operation(&slow), "sla_violated")
+      .query_async(&mut conn)
+      .await
+      .unwrap();
+    assert_eq!(violated, vec![None, Some("true".to_string())]);
+
+    let summary = RedisAdmin::new(client)
+      .with_keys(keys)
+      .latency(&[])
+      .await
+      .unwrap();
+    assert_eq!(summary.task_latencies.len(), 1);
+    assert_eq!(summary.task_latencies[0].count, 2);
+    assert_eq!(summary.task_latencies[0].sla_ms, 50);
+    assert_eq!(summary.task_latencies[0].sla_violations, 1);
+    assert_eq!(sla.summaries()[0].sla_violations, 1);
+  }
+
+  #[tokio::test]
+  async fn complete_should_not_overwrite_cancelled_operations() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let admin = RedisAdmin::new(client.clone()).with_keys(keys.clone());
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, "cancelled".to_string(), JsonCodec::new()).with_keys(keys);
 
-    match task {
-      Some(t) => Ok(Some(RedisMessage {
-        ack_id: op_id,
-        data: t,
-      })),
-      None => Err(Self::Error::Internal("Failed to decode task".to_string())),
-    }
+    let id = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    q.pull(&ctx).await.unwrap().unwrap();
+    admin.cancel(&id, "Cancelled by test").await.unwrap();
+    q.complete(&id, Ok::<_, Status>(Empty {}), &ctx)
+      .await
+      .unwrap();
+
+    let operation = admin.operation(&id).await.unwrap();
+    assert!(operation.done);
+    assert_eq!(operation.state, ProtoOperationState::Cancelled as i32);
+    assert_eq!(operation.error.unwrap().message, "Cancelled by test");
   }
 
-  async fn ack(&self, ack_id: &str, ctx: &Context) -> Result<(), Self::Error> {
-    let mut conn = self.client.get_async_connection().await?;
+  #[tokio::test]
+  async fn complete_should_acknowledge_the_operation() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), "acked".to_string(), JsonCodec::new())
+        .with_keys(keys.clone());
 
-    let maybe_queue: Option<String> = conn
-      .hget(format!("operation:{}", ack_id), "queue")
-      .instrument(tracing::info_span!("redis-queue-ack-hget"))
-      .await?;
+    let id = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    q.pull(&ctx).await.unwrap().unwrap();
 
-    let queue = match maybe_queue {
-      None => {
-        tracing::debug!(message = "Cannot find queue name", %ack_id);
-        return Err(Self::Error::NotFound(format!(
-          "Missing operation queue info for ack_id = {}",
-          ack_id
-        )));
-      }
-      Some(q) => q,
-    };
+    let mut conn = client.get_async_connection().await.unwrap();
+    let in_flight: Vec<String> = conn.lrange(keys.ack("acked"), 0, -1).await.unwrap();
+    assert_eq!(in_flight, vec![id.clone()]);
 
-    let _ = redis::pipe()
-      .atomic()
-      .hset_multiple(
-        format!("operation:{}", ack_id),
-        &[
-          ("ack_system_id", ctx.system_id()),
-          ("ack_ts", &Utc::now().timestamp_nanos().to_string()),
-          ("ack_user_id", ctx.user_id()),
-        ],
-      )
-      .ignore()
-      .lrem(format!("queue:{}", queue), -1, queue)
-      .ignore()
-      .query_async(&mut conn)
-      .instrument(tracing::info_span!("redis-queue-ack-lrem"))
-      .await?;
+    q.complete(&id, Ok::<_, Status>(Empty {}), &ctx)
+      .await
+      .unwrap();
 
-    tracing::debug!(message = "Acknowledged message", %ack_id);
-    Ok(())
+    let in_flight: Vec<String> = conn.lrange(keys.ack("acked"), 0, -1).await.unwrap();
+    assert!(in_flight.is_empty());
   }
-}
 
-impl FromRedisValue for Operation {
-  fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-    let mut map: HashMap<String, String> = from_redis_value(v)?;
-
-    let op = Self {
-      operation_id: map.remove("operation_id").unwrap_or(String::default()),
-      metadata: HashMap::from([
-        (
-          "task_type".to_string(),
-          map.remove("task_type").unwrap_or(String::default()),
-        ),
-        (
-          "task".to_string(),
-          map.remove("task").unwrap_or(String::default()),
-        ),
-        (
-          "user_id".to_string(),
-          map.remove("user_id").unwrap_or(String::default()),
-        ),
-        (
-          "queue".to_string(),
-          map.remove("queue").unwrap_or(String::default()),
-        ),
-        (
-          "status".to_string(),
-          map.remove("status").unwrap_or(String::default()),
-        ),
-      ]),
-      done: map.remove("done").map(|v| v == "true").unwrap_or(false),
-      error: None,
-      response: HashMap::default(),
-      creation_ts: map.remove("publish_ts").map(|v| {
-        let ts = v.parse::<i64>().expect("Failed to parse timestamp");
-        crate::proto::google::protobuf::Timestamp {
-          seconds: ts / 1000_000_000,
-          nanos: (ts % 1000_000_000) as i32,
-        }
-      }),
-      start_ts: map.remove("dequeue_ts").map(|v| {
-        let ts = v.parse::<i64>().expect("Failed to parse timestamp");
-        crate::proto::google::protobuf::Timestamp {
-          seconds: ts / 1000_000_000,
-          nanos: (ts % 1000_000_000) as i32,
-        }
-      }),
-      end_ts: map.remove("end_ts").map(|v| {
-        let ts = v.parse::<i64>().expect("Failed to parse timestamp");
-        crate::proto::google::protobuf::Timestamp {
-          seconds: ts / 1000_000_000,
-          nanos: (ts % 1000_000_000) as i32,
-        }
-      }),
+  #[tokio::test]
+  async fn offer_should_reject_unprivileged_impersonation() {
+    // Rejected before connecting, so no Redis is needed.
+    let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let system = Context::new("backup-service".to_string(), "1234".to_string());
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, "backups".to_string(), JsonCodec::new())
+        .with_system_context(system.clone())
+        .with_impersonators(["operator"]);
+    let on_behalf_of = |principal: &str| {
+      Context::new("42".to_string(), "1234".to_string()).with_principal(principal)
     };
 
-    Ok(op)
+    assert!(matches!(
+      q.offer(Task { item: 1 }, &on_behalf_of("intruder")).await,
+      Err(RedisQueueError::PermissionDenied(_))
+    ));
+    for ctx in [
+      on_behalf_of("operator"),
+      on_behalf_of("backup-service"),
+      on_behalf_of("42"),
+    ] {
+      assert!(matches!(
+        q.offer(Task { item: 1 }, &ctx).await,
+        Err(RedisQueueError::Redis(_))
+      ));
+    }
+    assert!(matches!(
+      q.offer_as_system(Task { item: 1 }).await,
+      Err(RedisQueueError::Redis(_))
+    ));
+    assert!(!on_behalf_of("42").is_impersonating());
+  }
+
+  #[tokio::test]
+  async fn offer_should_reject_payloads_above_the_hard_limit() {
+    // Rejected before connecting, so no Redis is needed.
+    let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let ctx = Context::new("42".to_string(), "1234".to_string());
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, "oversized".to_string(), JsonCodec::new())
+        .with_payload_limits(PayloadLimits::new().with_hard_limit(8));
+
+    let error = q.offer(Task { item: 1 }, &ctx).await.unwrap_err();
+    assert!(matches!(
+      error,
+      RedisQueueError::PayloadTooLarge(PayloadTooLarge {
+        size: 10,
+        limit: 8,
+        ..
+      })
+    ));
+    assert_eq!(
+      tonic::Status::from(BrokerError::from(error)).code(),
+      tonic::Code::InvalidArgument
+    );
+    assert!(matches!(
+      q.offer_raw(
+        "Task",
+        crate::codec::json::CONTENT_TYPE,
+        b"{}".to_vec(),
+        &ctx
+      )
+      .await,
+      Err(RedisQueueError::Redis(_))
+    ));
   }
-}
 
-#[cfg(test)]
-mod tests {
-  use std::collections::HashMap;
+  #[tokio::test]
+  async fn offer_should_set_metadata_while_adding_item_to_queue() {
+    let queue = Uuid::new_v4().to_string();
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let ts = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
+    let task = Task { item: 10 };
 
-  use chrono::Utc;
-  use redis::AsyncCommands;
-  use serde::Deserialize;
+    let id = q.offer(task, &ctx).await.unwrap();
 
-  use crate::{longrunning::Queue, proto::google::protobuf::Empty};
+    let mut conn = client.get_async_connection().await.unwrap();
 
-  use super::*;
+    let result: HashMap<String, String> = conn.hgetall(format!("operation:{}", id)).await.unwrap();
 
-  #[derive(Serialize, Deserialize, Clone)]
-  struct Task {
-    item: i32,
+    assert_eq!(result["status"], "Queued");
+    assert!(result["publish_ts"].parse::<i64>().unwrap() >= ts);
+    assert_eq!(result["task_type"], "longrunning::redis::tests::Task");
+    assert_eq!(result["task"], "{\"item\":10}");
+    assert_eq!(result["queue"], queue);
+    assert_eq!(result["user_id"], ctx.user_id());
   }
 
-  #[async_trait::async_trait]
-  impl Performable for Task {
-    type Error = std::io::Error;
-    type Context = ();
-    type Output = Empty;
+  #[tokio::test]
+  async fn pull_should_move_undecodable_payload_to_invalid_list() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
 
-    fn type_name() -> &'static str {
-      "longrunning::redis::tests::Task"
-    }
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
 
-    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
-      Ok(Empty::default())
-    }
+    let mut conn = client.get_async_connection().await.unwrap();
+    let _: () = conn
+      .hset(format!("operation:{}", id), "task", "not json")
+      .await
+      .unwrap();
+
+    assert!(q.pull(&ctx).await.is_err());
+
+    let invalid = q.invalid(0, 10).await.unwrap();
+    assert_eq!(invalid.len(), 1);
+    assert_eq!(invalid[0].operation_id, id);
+    assert_eq!(invalid[0].payload, b"not json".to_vec());
+    assert!(!invalid[0].error.is_empty());
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+
+    let admin = RedisAdmin::new(client);
+    assert_eq!(admin.invalid(&queue, 0, 10).await.unwrap(), invalid);
+    assert!(admin.operation(&id).await.unwrap().done);
+
+    let _: () = conn
+      .hset(format!("operation:{}", id), "task", "{\"item\":10}")
+      .await
+      .unwrap();
+    let replayed = admin.replay_invalid(&id).await.unwrap();
+    assert!(!replayed.done);
+    assert_eq!(replayed.state, ProtoOperationState::Queued as i32);
+
+    let message = q.pull(&ctx).await.unwrap().unwrap();
+    assert_eq!(message.ack_id, id);
+    assert!(q.invalid(0, 10).await.unwrap().is_empty());
   }
 
   #[tokio::test]
-  async fn offer_should_add_item_to_queue() {
+  async fn invalidate_should_leave_completed_operations() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, queue, JsonCodec::new());
+
+    let id = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    q.pull(&ctx).await.unwrap().unwrap();
+    q.complete(&id, Ok::<_, Status>(Empty {}), &ctx)
+      .await
+      .unwrap();
+
+    q.invalidate(&id, "Too late").await.unwrap();
+
+    assert!(q.invalid(0, 10).await.unwrap().is_empty());
+    assert!(matches!(
+      q.replay_invalid(&id).await,
+      Err(RedisQueueError::NotFound(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn replay_invalid_should_reserve_quota_again() {
+    let organization = Uuid::new_v4().to_string();
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"))
+      .with_organization_id(&organization);
+    let subject = quota::organization(&organization);
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let quota = RedisQuota::new(client.clone()).with_limit(quota::CONCURRENT_OPERATIONS, 1);
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, Uuid::new_v4().to_string(), JsonCodec::new())
+        .with_quota(quota.clone());
+
+    let id = q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    q.pull(&ctx).await.unwrap().unwrap();
+    q.invalidate(&id, "Undecodable").await.unwrap();
+    assert_eq!(
+      quota
+        .usage(&subject, quota::CONCURRENT_OPERATIONS)
+        .await
+        .unwrap(),
+      0
+    );
+
+    // The only slot is taken meanwhile, so the message stays invalid.
+    let other = q.offer(Task { item: 2 }, &ctx).await.unwrap();
+    assert!(matches!(
+      q.replay_invalid(&id).await,
+      Err(RedisQueueError::Quota(QuotaError::Exceeded { .. }))
+    ));
+    assert_eq!(q.invalid(0, 10).await.unwrap().len(), 1);
+
+    q.pull(&ctx).await.unwrap().unwrap();
+    q.complete(&other, Ok::<_, Status>(Empty {}), &ctx)
+      .await
+      .unwrap();
+    q.replay_invalid(&id).await.unwrap();
+    assert_eq!(
+      quota
+        .usage(&subject, quota::CONCURRENT_OPERATIONS)
+        .await
+        .unwrap(),
+      1
+    );
+    assert_eq!(q.pull(&ctx).await.unwrap().unwrap().ack_id, id);
+  }
+
+  #[tokio::test]
+  async fn pull_should_select_decoder_by_content_type() {
     let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
     let queue = Uuid::new_v4().to_string();
     let client = redis::Client::open("redis://127.0.0.1/").unwrap();
     let q: RedisQueue<Task, JsonCodec<Task, Task>> =
       RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
-    let task = Task { item: 10 };
 
-    let id = q.offer(task, &ctx).await.unwrap();
+    let legacy = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+    let unknown = q.offer(Task { item: 11 }, &ctx).await.unwrap();
 
     let mut conn = client.get_async_connection().await.unwrap();
-    let result: Vec<String> = conn
-      .lrange(format!("queue:{}", queue), 0, -1)
+    let content_type: String = conn
+      .hget(format!("operation:{}", legacy), "content_type")
       .await
       .unwrap();
-    assert_eq!(vec![id], result);
+    assert_eq!(content_type, "application/json");
+
+    let _: () = conn
+      .hdel(format!("operation:{}", legacy), "content_type")
+      .await
+      .unwrap();
+    let _: () = conn
+      .hset(
+        format!("operation:{}", unknown),
+        "content_type",
+        "application/x-unknown",
+      )
+      .await
+      .unwrap();
+
+    assert_eq!(q.pull(&ctx).await.unwrap().unwrap().data.item, 10);
+    assert!(matches!(
+      q.pull(&ctx).await,
+      Err(RedisQueueError::UnsupportedContentType(_))
+    ));
+    assert_eq!(q.invalid(0, 10).await.unwrap()[0].operation_id, unknown);
   }
 
   #[tokio::test]
-  async fn offer_should_set_metadata_while_adding_item_to_queue() {
+  async fn recover_expired_should_quarantine_after_repeated_failures() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new()).with_poison_threshold(2);
+
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+
+    assert!(q.pull(&ctx).await.unwrap().is_some());
+    assert_eq!(
+      q.recover_expired(Duration::ZERO).await.unwrap(),
+      vec![id.clone()]
+    );
+    assert!(q.pull(&ctx).await.unwrap().is_some());
+    assert_eq!(
+      q.recover_expired(Duration::ZERO).await.unwrap(),
+      vec![id.clone()]
+    );
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let quarantined: Vec<String> = conn
+      .lrange(format!("queue:quarantine:{}", queue), 0, -1)
+      .await
+      .unwrap();
+    let diagnostics: HashMap<String, String> =
+      conn.hgetall(format!("quarantine:{}", id)).await.unwrap();
+
+    assert_eq!(vec![id.clone()], quarantined);
+    assert_eq!(diagnostics["payload"], "{\"item\":10}");
+    assert_eq!(diagnostics["failures"], "2");
+    assert!(q.pull(&ctx).await.unwrap().is_none());
+
+    let (done, status, failures): (String, String, i64) = conn
+      .hget(
+        format!("operation:{}", id),
+        &["done", "status", "failure_count"],
+      )
+      .await
+      .unwrap();
+    assert_eq!(done, "true");
+    assert_eq!(status, OperationState::Failed.as_str());
+    assert_eq!(failures, 2);
+
+    // The operation is no longer in flight, so a late failure is not counted.
+    assert!(!q.record_failure(&id, "Late failure").await.unwrap());
+    let failures: i64 = conn
+      .hget(format!("operation:{}", id), "failure_count")
+      .await
+      .unwrap();
+    assert_eq!(failures, 2);
+  }
+
+  #[tokio::test]
+  async fn recover_expired_should_wait_for_the_lease() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
     let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let clock = TestClock::new();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, queue, JsonCodec::new()).with_clock(Arc::new(clock.clone()));
+
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+    assert!(q.pull(&ctx).await.unwrap().is_some());
+
+    clock.advance(Duration::from_secs(20));
+    assert!(q
+      .recover_expired(Duration::from_secs(30))
+      .await
+      .unwrap()
+      .is_empty());
+
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(
+      q.recover_expired(Duration::from_secs(30)).await.unwrap(),
+      vec![id]
+    );
+  }
+
+  #[tokio::test]
+  async fn event_bus_should_stream_matching_lifecycle_events() {
     let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
-    let ts = Utc::now().timestamp_nanos();
+    let queue = Uuid::new_v4().to_string();
     let client = redis::Client::open("redis://127.0.0.1/").unwrap();
     let q: RedisQueue<Task, JsonCodec<Task, Task>> =
       RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
-    let task = Task { item: 10 };
+    let bus = RedisEventBus::new(client.clone(), OPERATION_EVENTS_CHANNEL);
+    let filter = StreamOperationsRequest {
+      queues: vec![queue.clone()],
+      ..Default::default()
+    };
 
-    let id = q.offer(task, &ctx).await.unwrap();
+    let mut events = Box::pin(bus.subscribe(filter).await.unwrap());
 
-    let mut conn = client.get_async_connection().await.unwrap();
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+    let _ = q.pull(&ctx).await.unwrap();
 
-    let result: HashMap<String, String> = conn.hgetall(format!("operation:{}", id)).await.unwrap();
+    let created = events.next().await.unwrap();
+    let started = events.next().await.unwrap();
 
-    assert_eq!(result["status"], "New");
-    assert!(result["publish_ts"].parse::<i64>().unwrap() >= ts);
-    assert_eq!(result["task_type"], "longrunning::redis::tests::Task");
-    assert_eq!(result["task"], "{\"item\":10}");
-    assert_eq!(result["queue"], queue);
-    assert_eq!(result["user_id"], ctx.user_id());
+    assert_eq!(created.operation_id, id);
+    assert_eq!(created.event_type, OperationEventType::Created as i32);
+    assert_eq!(created.user_id, ctx.user_id());
+    assert_eq!(started.event_type, OperationEventType::Started as i32);
   }
 
   #[tokio::test]
@@ -468,4 +3310,139 @@ mod tests {
       .unwrap();
     assert_eq!(vec![operation.operation_id], result);
   }
+
+  #[tokio::test]
+  async fn broker_should_enqueue_batches_atomically() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let broker: RedisBroker<Task> = RedisBroker::new(client.clone(), &queue)
+      .with_payload_limits(PayloadLimits::new().with_hard_limit(16));
+
+    let operations = broker
+      .enqueue_all(vec![Task { item: 1 }, Task { item: 2 }], &ctx)
+      .await
+      .unwrap();
+    assert_eq!(operations.len(), 2);
+    assert!(broker
+      .enqueue_all(Vec::new(), &ctx)
+      .await
+      .unwrap()
+      .is_empty());
+
+    let error = broker
+      .enqueue_all(vec![Task { item: 3 }, Task { item: 1 << 30 }], &ctx)
+      .await
+      .unwrap_err();
+    assert!(matches!(
+      error,
+      BrokerError::QueueError(ref error) if matches!(error.inner(), RedisQueueError::PayloadTooLarge(_))
+    ));
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let result: Vec<String> = conn
+      .lrange(format!("queue:{}", queue), 0, -1)
+      .await
+      .unwrap();
+    assert_eq!(
+      result,
+      vec![
+        operations[1].operation_id.clone(),
+        operations[0].operation_id.clone()
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn offer_unique_should_only_push_the_operation_claiming_the_key() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), "unique".to_string(), JsonCodec::new())
+        .with_keys(keys.clone());
+    let unique = keys.unique("unique", "nightly");
+
+    let (first, enqueued) = q
+      .offer_unique(Task { item: 1 }, &unique, None, &ctx)
+      .await
+      .unwrap();
+    assert!(enqueued);
+
+    // A concurrent caller read the key before the first one claimed it.
+    let (winner, enqueued) = q
+      .offer_unique(Task { item: 2 }, &unique, None, &ctx)
+      .await
+      .unwrap();
+    assert!(!enqueued);
+    assert_eq!(winner, first);
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let queued: Vec<String> = conn.lrange(keys.queue("unique"), 0, -1).await.unwrap();
+    assert_eq!(queued, vec![first.clone()]);
+
+    // The losing operation left no hash, index or event behind.
+    let operations: Vec<String> = conn.keys(keys.operation("*")).await.unwrap();
+    assert_eq!(operations, vec![keys.operation(&first)]);
+  }
+
+  #[tokio::test]
+  async fn broker_should_enqueue_one_operation_per_unique_key() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let broker: RedisBroker<Task> = RedisBroker::new(client.clone(), &queue);
+
+    let first = broker
+      .enqueue_unique(Task { item: 1 }, "nightly", &ctx)
+      .await
+      .unwrap();
+    let second = broker
+      .enqueue_unique(Task { item: 2 }, "nightly", &ctx)
+      .await
+      .unwrap();
+    assert_eq!(first.operation_id, second.operation_id);
+
+    let cancelled = broker
+      .cancel_by_key("nightly", &ctx)
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(cancelled.done);
+    assert!(broker
+      .cancel_by_key("weekly", &ctx)
+      .await
+      .unwrap()
+      .is_none());
+
+    let third = broker
+      .enqueue_unique(Task { item: 3 }, "nightly", &ctx)
+      .await
+      .unwrap();
+    assert_ne!(third.operation_id, first.operation_id);
+    assert!(
+      !broker
+        .status(&third.operation_id)
+        .await
+        .unwrap()
+        .unwrap()
+        .done
+    );
+    assert!(broker.status("missing").await.unwrap().is_none());
+
+    let scheduled = broker
+      .schedule_at(
+        Task { item: 4 },
+        Utc::now() + chrono::Duration::hours(1),
+        &ctx,
+      )
+      .await
+      .unwrap();
+    let mut conn = client.get_async_connection().await.unwrap();
+    let delayed: Vec<String> = conn
+      .zrange(format!("queue:delayed:{}", queue), 0, -1)
+      .await
+      .unwrap();
+    assert_eq!(delayed, vec![scheduled.operation_id]);
+  }
 }
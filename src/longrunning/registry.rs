@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::codec::json::JsonCodec;
+use crate::codec::DynCodec;
+use crate::proto::google::rpc::Code;
+use crate::proto::google::rpc::Status;
+use crate::proto::longrunning::TaskSchema;
+
+use super::Performable;
+use super::TaskContext;
+
+/// Performs the tasks of one task type from their encoded payload.
+#[async_trait::async_trait]
+pub trait TaskHandler: Send + Sync {
+  /// Performs the task and returns its encoded output. `content_type` is the media type of the
+  /// payload, see [`crate::codec::Codec::content_type`].
+  async fn handle(
+    &self,
+    payload: &[u8],
+    content_type: &str,
+    ctx: TaskContext,
+  ) -> Result<Vec<u8>, Status>;
+}
+
+/// Maps task types to the handlers performing them, so a single worker can serve queues holding
+/// heterogeneous tasks.
+///
+/// ```rust,ignore
+/// let mut registry = TaskRegistry::new();
+/// registry.register::<CreateWorkspace>();
+/// registry.register_handler("backup", Arc::new(CommandHandler::new("/usr/bin/backup")));
+/// ```
+#[derive(Clone, Default)]
+pub struct TaskRegistry {
+  handlers: HashMap<String, Arc<dyn TaskHandler>>,
+  schemas: HashMap<String, TaskSchema>,
+  schema_versions: HashMap<String, u32>,
+}
+
+impl std::fmt::Debug for TaskRegistry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("TaskRegistry")
+      .field("task_types", &self.handlers.keys().collect::<Vec<_>>())
+      .finish()
+  }
+}
+
+impl TaskRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Registers a [`Performable`] under its [`Performable::type_name`]. Payloads are decoded as
+  /// JSON, like [`super::redis::RedisQueue`] encodes them.
+  pub fn register<T>(&mut self) -> &mut Self
+  where
+    T: Serialize + DeserializeOwned + Performable + Send + Sync + 'static,
+    T::Context: From<TaskContext>,
+    T::Error: Into<Status>,
+  {
+    self.register_with_codec::<T>(Arc::new(JsonCodec::<T, T>::new()))
+  }
+
+  /// Registers a [`Performable`] whose payloads are decoded by `codec`. The codec must decode
+  /// values of type `T`.
+  pub fn register_with_codec<T>(&mut self, codec: Arc<dyn DynCodec>) -> &mut Self
+  where
+    T: Performable + Send + Sync + 'static,
+    T::Context: From<TaskContext>,
+    T::Error: Into<Status>,
+  {
+    if let Some(mut schema) = T::schema() {
+      if schema.task_type.is_empty() {
+        schema.task_type = T::type_name().to_string();
+      }
+      if schema.content_type.is_empty() {
+        schema.content_type = codec.content_type().to_string();
+      }
+      if schema.schema_version == 0 {
+        schema.schema_version = T::schema_version();
+      }
+      self.register_schema(schema);
+    }
+
+    self
+      .schema_versions
+      .insert(T::type_name().to_string(), T::schema_version());
+
+    let handler = PerformableHandler::<T> {
+      codec,
+      _phantom: PhantomData,
+    };
+
+    self.register_handler(T::type_name(), Arc::new(handler))
+  }
+
+  /// Registers the schema of the tasks of type `schema.task_type`, replacing any previous schema.
+  /// Handlers registered without one, like [`CommandHandler`], can be described this way.
+  pub fn register_schema(&mut self, schema: TaskSchema) -> &mut Self {
+    self.schemas.insert(schema.task_type.clone(), schema);
+    self
+  }
+
+  pub fn schema(&self, task_type: &str) -> Option<&TaskSchema> {
+    self.schemas.get(task_type)
+  }
+
+  pub fn schemas(&self) -> impl Iterator<Item = &TaskSchema> {
+    self.schemas.values()
+  }
+
+  /// [`Performable::schema_version`] supported by the handler of `task_type`, `None` for the
+  /// handlers registered without a [`Performable`].
+  pub fn schema_version(&self, task_type: &str) -> Option<u32> {
+    self.schema_versions.get(task_type).copied()
+  }
+
+  /// Registers `handler` for the tasks of type `task_type`, replacing any previous handler.
+  pub fn register_handler(&mut self, task_type: &str, handler: Arc<dyn TaskHandler>) -> &mut Self {
+    self.handlers.insert(task_type.to_string(), handler);
+    self
+  }
+
+  pub fn get(&self, task_type: &str) -> Option<Arc<dyn TaskHandler>> {
+    self.handlers.get(task_type).cloned()
+  }
+
+  pub fn task_types(&self) -> impl Iterator<Item = &str> {
+    self.handlers.keys().map(String::as_str)
+  }
+}
+
+/// Adapts a [`Performable`] to [`TaskHandler`].
+struct PerformableHandler<T> {
+  codec: Arc<dyn DynCodec>,
+  _phantom: PhantomData<fn() -> T>,
+}
+
+#[async_trait::async_trait]
+impl<T> TaskHandler for PerformableHandler<T>
+where
+  T: Performable + Send + Sync + 'static,
+  T::Context: From<TaskContext>,
+  T::Error: Into<Status>,
+{
+  async fn handle(
+    &self,
+    payload: &[u8],
+    content_type: &str,
+    ctx: TaskContext,
+  ) -> Result<Vec<u8>, Status> {
+    if content_type != self.codec.content_type() {
+      return Err(status(
+        Code::InvalidArgument,
+        format!(
+          "Cannot decode {} payloads of {}",
+          content_type,
+          T::type_name()
+        ),
+      ));
+    }
+
+    let task = self
+      .codec
+      .decode_any(payload)
+      .map_err(|error| status(Code::InvalidArgument, error.to_string()))?
+      .ok_or_else(|| status(Code::InvalidArgument, "Empty task payload".to_string()))?
+      .downcast::<T>()
+      .map_err(|_| {
+        status(
+          Code::Internal,
+          format!("Codec of {} decodes another type", T::type_name()),
+        )
+      })?;
+
+    task
+      .perform(ctx.into())
+      .await
+      .map(|output| output.encode_to_vec())
+      .map_err(Into::into)
+  }
+}
+
+/// Runs an external command per task: the payload is written to its stdin and its stdout becomes
+/// the output of the operation. A non-zero exit status fails the operation with the command's
+/// stderr. The task's operation id, task type, queue, attempt and payload content type are
+/// exported as `RAPPEL_*` environment variables.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CommandHandler {
+  pub command: String,
+  #[serde(default)]
+  pub args: Vec<String>,
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+}
+
+impl CommandHandler {
+  pub fn new(command: &str) -> Self {
+    Self {
+      command: command.to_string(),
+      args: Vec::default(),
+      env: HashMap::default(),
+    }
+  }
+
+  pub fn with_args(mut self, args: Vec<String>) -> Self {
+    self.args = args;
+    self
+  }
+}
+
+#[async_trait::async_trait]
+impl TaskHandler for CommandHandler {
+  async fn handle(
+    &self,
+    payload: &[u8],
+    content_type: &str,
+    ctx: TaskContext,
+  ) -> Result<Vec<u8>, Status> {
+    let mut child = tokio::process::Command::new(&self.command)
+      .args(&self.args)
+      .envs(&self.env)
+      .env("RAPPEL_OPERATION_ID", ctx.operation_id())
+      .env("RAPPEL_TASK_TYPE", ctx.task_type())
+      .env("RAPPEL_QUEUE", ctx.queue())
+      .env("RAPPEL_ATTEMPT", ctx.attempt().to_string())
+      .env("RAPPEL_CONTENT_TYPE", content_type)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn()
+      .map_err(|error| {
+        status(
+          Code::Internal,
+          format!("Failed to start {}: {}", self.command, error),
+        )
+      })?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+      stdin
+        .write_all(payload)
+        .await
+        .map_err(|error| status(Code::Internal, error.to_string()))?;
+    }
+
+    let output = child
+      .wait_with_output()
+      .await
+      .map_err(|error| status(Code::Internal, error.to_string()))?;
+
+    if !output.status.success() {
+      return Err(status(
+        Code::Unknown,
+        format!(
+          "{} exited with {}: {}",
+          self.command,
+          output.status,
+          String::from_utf8_lossy(&output.stderr).trim()
+        ),
+      ));
+    }
+
+    Ok(output.stdout)
+  }
+}
+
+fn status(code: Code, message: String) -> Status {
+  Status {
+    code: code as i32,
+    message,
+    details: Vec::default(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::codec::json::CONTENT_TYPE as JSON;
+  use crate::proto::google::protobuf::Empty;
+
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Clone)]
+  struct Task {
+    item: i32,
+  }
+
+  #[async_trait::async_trait]
+  impl Performable for Task {
+    type Error = tonic::Status;
+    type Context = TaskContext;
+    type Output = Empty;
+
+    fn type_name() -> &'static str {
+      "longrunning::registry::tests::Task"
+    }
+
+    fn schema() -> Option<TaskSchema> {
+      Some(TaskSchema {
+        payload_json_schema: r#"{"type":"object","properties":{"item":{"type":"integer"}}}"#
+          .to_string(),
+        ..Default::default()
+      })
+    }
+
+    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+      if self.item < 0 {
+        return Err(tonic::Status::invalid_argument("negative item"));
+      }
+
+      Ok(Empty::default())
+    }
+  }
+
+  fn ctx() -> TaskContext {
+    TaskContext::new("1", Task::type_name(), "tasks", 1, "user")
+  }
+
+  #[tokio::test]
+  async fn registry_should_dispatch_to_registered_performable() {
+    let mut registry = TaskRegistry::new();
+    registry.register::<Task>();
+
+    let handler = registry.get(Task::type_name()).unwrap();
+
+    assert!(handler.handle(b"{\"item\":1}", JSON, ctx()).await.is_ok());
+    assert_eq!(
+      handler
+        .handle(b"{\"item\":-1}", JSON, ctx())
+        .await
+        .unwrap_err()
+        .code,
+      Code::InvalidArgument as i32
+    );
+    assert!(handler.handle(b"not json", JSON, ctx()).await.is_err());
+    assert!(handler
+      .handle(b"{\"item\":1}", "application/protobuf", ctx())
+      .await
+      .is_err());
+    assert!(registry.get("unknown").is_none());
+  }
+
+  #[test]
+  fn registry_should_fill_in_the_schemas() {
+    let mut registry = TaskRegistry::new();
+    registry.register::<Task>();
+    registry.register_schema(TaskSchema {
+      task_type: "backup".to_string(),
+      ..Default::default()
+    });
+
+    let schema = registry.schema(Task::type_name()).unwrap();
+    assert_eq!(schema.task_type, Task::type_name());
+    assert_eq!(schema.content_type, JSON);
+    assert!(schema.payload_json_schema.contains("item"));
+    assert_eq!(registry.schemas().count(), 2);
+    assert!(registry.schema("unknown").is_none());
+  }
+
+  #[tokio::test]
+  async fn command_handler_should_pipe_payload_through_command() {
+    let handler = CommandHandler::new("cat");
+
+    assert_eq!(
+      handler.handle(b"payload", JSON, ctx()).await.unwrap(),
+      b"payload"
+    );
+    assert!(CommandHandler::new("false")
+      .handle(b"", JSON, ctx())
+      .await
+      .is_err());
+  }
+}
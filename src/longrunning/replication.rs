@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use redis::AsyncCommands;
+use tracing_futures::Instrument;
+
+use crate::redis::Keys;
+use crate::util::backoff::Backoff;
+use crate::util::redis_exec;
+use crate::util::redis_exec::InstrumentedConnection;
+
+use super::redis::RedisQueueError;
+
+/// Moves every event parked in the ack list back to the outbox, oldest first so they are
+/// relayed in their original order. Returns how many there were.
+///
+/// KEYS: ack list, outbox.
+const RECOVER_SCRIPT: &str = r"
+local count = 0
+while redis.call('LMOVE', KEYS[1], KEYS[2], 'LEFT', 'RIGHT') do
+  count = count + 1
+end
+return count
+";
+
+/// The kind of change recorded in a replication outbox.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplicationKind {
+  /// A task was offered to the queue.
+  Enqueued,
+  /// An operation reached its final state.
+  Completed,
+}
+
+/// An entry of the replication outbox `replication:{queue}`.
+///
+/// The entry carries the region the change originated from. A relay never delivers an event
+/// back into its region of origin, and the relay writes replicated operations straight into the
+/// target hashes and lists (never into the target outbox), so changes cannot bounce between
+/// regions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicationEvent {
+  pub kind: ReplicationKind,
+  pub origin: String,
+  pub operation_id: String,
+}
+
+impl ReplicationEvent {
+  pub fn enqueued(origin: &str, operation_id: &str) -> Self {
+    Self {
+      kind: ReplicationKind::Enqueued,
+      origin: origin.to_string(),
+      operation_id: operation_id.to_string(),
+    }
+  }
+
+  pub fn completed(origin: &str, operation_id: &str) -> Self {
+    Self {
+      kind: ReplicationKind::Completed,
+      origin: origin.to_string(),
+      operation_id: operation_id.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for ReplicationEvent {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let kind = match self.kind {
+      ReplicationKind::Enqueued => "enqueued",
+      ReplicationKind::Completed => "completed",
+    };
+
+    write!(f, "{}:{}:{}", kind, self.origin, self.operation_id)
+  }
+}
+
+impl FromStr for ReplicationEvent {
+  type Err = RedisQueueError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let malformed = || RedisQueueError::Internal(format!("Malformed replication event: {}", s));
+
+    let (kind, rest) = s.split_once(':').ok_or_else(malformed)?;
+    let (origin, operation_id) = rest.rsplit_once(':').ok_or_else(malformed)?;
+
+    let kind = match kind {
+      "enqueued" => ReplicationKind::Enqueued,
+      "completed" => ReplicationKind::Completed,
+      _ => return Err(malformed()),
+    };
+
+    Ok(Self {
+      kind,
+      origin: origin.to_string(),
+      operation_id: operation_id.to_string(),
+    })
+  }
+}
+
+/// Mirrors the replication outbox of a queue from one Redis into another region's Redis.
+///
+/// Enqueued operations are copied and pushed to the target queue, completed operations overwrite
+/// the target hash and are removed from the target queue so a passive region does not execute
+/// them again after a failover. Replicated hashes carry a `replicated_from` field.
+///
+/// Events are delivered at least once. A single relay must drain a given outbox, since the
+/// events in flight are recovered without telling which relay parked them.
+///
+/// ```rust,no_run
+/// use rappel::longrunning::replication::ReplicationRelay;
+///
+/// let source = redis::Client::open("redis://primary/").unwrap();
+/// let target = redis::Client::open("redis://standby/").unwrap();
+/// let relay = ReplicationRelay::new(source, target, "workspaces", "europe-west1");
+///
+/// tokio::spawn(async move { relay.run(std::time::Duration::from_secs(1)).await });
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReplicationRelay {
+  source: redis::Client,
+  target: redis::Client,
+  queue: String,
+  target_region: String,
+  keys: Keys,
+}
+
+impl ReplicationRelay {
+  pub fn new(
+    source: redis::Client,
+    target: redis::Client,
+    queue: &str,
+    target_region: &str,
+  ) -> Self {
+    Self {
+      source,
+      target,
+      queue: queue.to_string(),
+      target_region: target_region.to_string(),
+      keys: Keys::default(),
+    }
+  }
+
+  /// Uses the key prefix of `keys` in both regions.
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Relays events forever, sleeping for `poll_interval` whenever the outbox is empty. The
+  /// events left in flight by a previous run or by a failed delivery are moved back to the
+  /// outbox first, see [`Self::recover`], and errors are retried with an exponential backoff.
+  pub async fn run(&self, poll_interval: Duration) {
+    let mut delays = Backoff::default().delays();
+    let mut in_flight = true;
+
+    loop {
+      match self.relay_next(in_flight).await {
+        Ok(relayed) => {
+          in_flight = false;
+          delays.reset();
+          if relayed.is_none() {
+            tokio::time::sleep(poll_interval).await;
+          }
+        }
+        Err(error) => {
+          in_flight = true;
+          let delay = delays.next().unwrap_or_default();
+          tracing::warn!(
+            message = "Replication failed, retrying",
+            queue = %self.queue,
+            %error,
+            delay_ms = delay.as_millis() as u64
+          );
+          tokio::time::sleep(delay).await;
+        }
+      }
+    }
+  }
+
+  async fn relay_next(&self, recover: bool) -> Result<Option<ReplicationEvent>, RedisQueueError> {
+    if recover {
+      let recovered = self.recover().await?;
+      if recovered > 0 {
+        tracing::info!(message = "Recovered replication events in flight", queue = %self.queue, recovered);
+      }
+    }
+
+    self.relay_once().await
+  }
+
+  /// Moves the events parked in `replication:ack:{queue}` back to the outbox, e.g. after a relay
+  /// stopped or failed to deliver them. Returns how many there were.
+  pub async fn recover(&self) -> Result<u64, RedisQueueError> {
+    let mut source = redis_exec::connect(&self.source).await?;
+
+    let recovered: u64 = redis::Script::new(RECOVER_SCRIPT)
+      .key(self.keys.replication_ack(&self.queue))
+      .key(self.keys.replication(&self.queue))
+      .invoke_async(&mut source)
+      .instrument(tracing::info_span!("redis-replication-recover"))
+      .await?;
+
+    Ok(recovered)
+  }
+
+  /// Relays the oldest event of the outbox. Returns `None` when the outbox is empty. Malformed
+  /// events are dropped and the next one is relayed instead.
+  ///
+  /// The event is parked in `replication:ack:{queue}` while it is delivered, and dropped from the
+  /// source once the target write succeeded. An event whose delivery failed stays parked until
+  /// [`Self::recover`] moves it back to the outbox.
+  pub async fn relay_once(&self) -> Result<Option<ReplicationEvent>, RedisQueueError> {
+    let mut source = redis_exec::connect(&self.source).await?;
+
+    let (entry, event) = loop {
+      let maybe_entry: Option<String> = redis::cmd("LMOVE")
+        .arg(self.keys.replication(&self.queue))
+        .arg(self.keys.replication_ack(&self.queue))
+        .arg("RIGHT")
+        .arg("LEFT")
+        .query_async(&mut source)
+        .instrument(tracing::info_span!("redis-replication-lmove"))
+        .await?;
+
+      let entry = match maybe_entry {
+        None => return Ok(None),
+        Some(entry) => entry,
+      };
+
+      match entry.parse::<ReplicationEvent>() {
+        Ok(event) => break (entry, event),
+        Err(error) => {
+          tracing::error!(message = "Dropping malformed replication event", %entry, %error);
+          self.release(&mut source, &entry).await?;
+        }
+      }
+    };
+
+    if event.origin == self.target_region {
+      tracing::debug!(message = "Skipping event originating from the target region", %entry);
+      self.release(&mut source, &entry).await?;
+      return Ok(Some(event));
+    }
+
+    let mut fields: HashMap<String, Vec<u8>> = source
+      .hgetall(self.keys.operation(&event.operation_id))
+      .instrument(tracing::info_span!("redis-replication-hgetall"))
+      .await?;
+
+    if fields.is_empty() {
+      tracing::warn!(message = "Replicated operation no longer exists", %entry);
+      self.release(&mut source, &entry).await?;
+      return Ok(Some(event));
+    }
+
+    fields.insert(
+      "replicated_from".to_string(),
+      event.origin.clone().into_bytes(),
+    );
+    let fields: Vec<(String, Vec<u8>)> = fields.into_iter().collect();
+
+    let mut target = redis_exec::connect(&self.target).await?;
+    let mut pipe = redis::pipe();
+    let operation_key = self.keys.operation(&event.operation_id);
+
+    let mut pipeline = pipe
+      .atomic()
+      .hset_multiple(&operation_key, &fields)
+      .ignore();
+
+    pipeline = match event.kind {
+      ReplicationKind::Enqueued => pipeline
+        .lpush(self.keys.queue(&self.queue), &event.operation_id)
+        .ignore(),
+      ReplicationKind::Completed => pipeline
+        .lrem(self.keys.queue(&self.queue), 0, &event.operation_id)
+        .ignore()
+        .lrem(self.keys.interactive(&self.queue), 0, &event.operation_id)
+        .ignore()
+        .lrem(self.keys.ack(&self.queue), 0, &event.operation_id)
+        .ignore(),
+    };
+
+    let _: () = pipeline
+      .query_async(&mut target)
+      .instrument(
+        tracing::info_span!("redis-replication-write", operation_id = %event.operation_id),
+      )
+      .await?;
+
+    self.release(&mut source, &entry).await?;
+
+    tracing::debug!(message = "Replicated operation", %entry, target_region = %self.target_region);
+    Ok(Some(event))
+  }
+
+  async fn release(
+    &self,
+    conn: &mut InstrumentedConnection,
+    entry: &str,
+  ) -> Result<(), RedisQueueError> {
+    let _: () = conn
+      .lrem(self.keys.replication_ack(&self.queue), 1, entry)
+      .await?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use redis::AsyncCommands;
+  use serde::Deserialize;
+  use serde::Serialize;
+  use uuid::Uuid;
+
+  use crate::codec::json::JsonCodec;
+  use crate::longrunning::redis::RedisQueue;
+  use crate::longrunning::Context;
+  use crate::longrunning::Performable;
+  use crate::longrunning::Queue;
+  use crate::proto::google::protobuf::Empty;
+
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Clone)]
+  struct Task {
+    item: i32,
+  }
+
+  #[async_trait::async_trait]
+  impl Performable for Task {
+    type Error = std::io::Error;
+    type Context = ();
+    type Output = Empty;
+
+    fn type_name() -> &'static str {
+      "longrunning::replication::tests::Task"
+    }
+
+    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+      Ok(Empty::default())
+    }
+  }
+
+  #[test]
+  fn replication_event_should_round_trip() {
+    let event = ReplicationEvent::completed("us:east", "d2a1c1aa-5f4c-4b43-9e57-1cdd1e24b3b6");
+
+    let parsed: ReplicationEvent = event.to_string().parse().unwrap();
+
+    assert_eq!(event, parsed);
+    assert!("unknown:us:1".parse::<ReplicationEvent>().is_err());
+  }
+
+  #[tokio::test]
+  async fn relay_should_mirror_enqueued_operation() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let source = redis::Client::open("redis://127.0.0.1/0").unwrap();
+    let target = redis::Client::open("redis://127.0.0.1/1").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(source.clone(), queue.clone(), JsonCodec::new()).with_replication("primary");
+
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+
+    let relay = ReplicationRelay::new(source, target.clone(), &queue, "standby");
+    let event = relay.relay_once().await.unwrap();
+
+    assert_eq!(event, Some(ReplicationEvent::enqueued("primary", &id)));
+
+    let mut conn = target.get_async_connection().await.unwrap();
+    let queued: Vec<String> = conn
+      .lrange(format!("queue:{}", queue), 0, -1)
+      .await
+      .unwrap();
+    let op: HashMap<String, String> = conn.hgetall(format!("operation:{}", id)).await.unwrap();
+
+    assert_eq!(vec![id], queued);
+    assert_eq!(op["replicated_from"], "primary");
+    assert_eq!(op["task"], "{\"item\":10}");
+    assert_eq!(relay.relay_once().await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn relay_should_drop_malformed_events_and_recover_events_in_flight() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let source = redis::Client::open("redis://127.0.0.1/0").unwrap();
+    let target = redis::Client::open("redis://127.0.0.1/1").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(source.clone(), queue.clone(), JsonCodec::new()).with_replication("primary");
+
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+
+    let mut conn = source.get_async_connection().await.unwrap();
+    let keys = Keys::default();
+    let _: () = conn
+      .rpush(keys.replication(&queue), "malformed")
+      .await
+      .unwrap();
+    let entry: String = redis::cmd("LMOVE")
+      .arg(keys.replication(&queue))
+      .arg(keys.replication_ack(&queue))
+      .arg("LEFT")
+      .arg("LEFT")
+      .query_async(&mut conn)
+      .await
+      .unwrap();
+    assert_eq!(
+      entry,
+      ReplicationEvent::enqueued("primary", &id).to_string()
+    );
+
+    let relay = ReplicationRelay::new(source, target, &queue, "standby");
+    let _: () = conn
+      .lpush(keys.replication(&queue), "still:malformed")
+      .await
+      .unwrap();
+    assert_eq!(relay.relay_once().await.unwrap(), None);
+
+    assert_eq!(relay.recover().await.unwrap(), 1);
+    assert_eq!(
+      relay.relay_once().await.unwrap(),
+      Some(ReplicationEvent::enqueued("primary", &id))
+    );
+
+    let parked: Vec<String> = conn
+      .lrange(keys.replication_ack(&queue), 0, -1)
+      .await
+      .unwrap();
+    assert!(parked.is_empty());
+  }
+
+  #[tokio::test]
+  async fn relay_should_skip_events_from_target_region() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let source = redis::Client::open("redis://127.0.0.1/0").unwrap();
+    let target = redis::Client::open("redis://127.0.0.1/1").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(source.clone(), queue.clone(), JsonCodec::new()).with_replication("standby");
+
+    let _ = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+
+    let relay = ReplicationRelay::new(source, target.clone(), &queue, "standby");
+    let _ = relay.relay_once().await.unwrap();
+
+    let mut conn = target.get_async_connection().await.unwrap();
+    let queued: Vec<String> = conn
+      .lrange(format!("queue:{}", queue), 0, -1)
+      .await
+      .unwrap();
+
+    assert!(queued.is_empty());
+  }
+}
@@ -0,0 +1,281 @@
+//! Routing of the tasks to their queue by task type, so producers enqueue without naming a queue
+//! and operators control the queue topology from the configuration.
+//!
+//! ```yaml
+//! routes:
+//!   - pattern: "backup::*"
+//!     queue: backups
+//!   - pattern: "*::Export"
+//!     queue: exports
+//! default_queue: default
+//! ```
+//!
+//! Patterns match the [`Performable::type_name`] of the tasks, `*` standing for any sequence of
+//! characters. The first matching route wins, and the tasks no route matches go to the default
+//! queue when there is one.
+//!
+//! ```rust,ignore
+//! let broker = RoutingBroker::new(registry, table);
+//! let operation = broker.enqueue(Backup { .. }, &ctx).await?;
+//! ```
+
+use std::sync::Arc;
+
+use chrono::DateTime;
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::redis::RedisRegistry;
+use crate::util::shutdown::ShutdownToken;
+
+use super::redis::BrokerError;
+use super::redis::RedisBroker;
+use super::Broker;
+use super::Context;
+use super::Operation;
+use super::Performable;
+
+/// Sends the task types matching `pattern` to `queue`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Route {
+  pub pattern: String,
+  pub queue: String,
+}
+
+/// Queue of each task type, usually loaded from the configuration, see the [module](self)
+/// documentation.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct RoutingTable {
+  #[serde(default)]
+  pub routes: Vec<Route>,
+
+  /// Queue of the task types no route matches. They are rejected when unset.
+  #[serde(default)]
+  pub default_queue: Option<String>,
+}
+
+impl RoutingTable {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Appends a route, matched after the routes added before it.
+  pub fn with_route(mut self, pattern: &str, queue: &str) -> Self {
+    self.routes.push(Route {
+      pattern: pattern.to_string(),
+      queue: queue.to_string(),
+    });
+    self
+  }
+
+  pub fn with_default_queue(mut self, queue: &str) -> Self {
+    self.default_queue = Some(queue.to_string());
+    self
+  }
+
+  /// Queue of the tasks of `task_type`, `None` when no route matches and there is no default
+  /// queue.
+  pub fn route(&self, task_type: &str) -> Option<&str> {
+    self
+      .routes
+      .iter()
+      .find(|route| matches(&route.pattern, task_type))
+      .map(|route| route.queue.as_str())
+      .or(self.default_queue.as_deref())
+  }
+}
+
+/// Whether `name` matches `pattern`, whose `*` match any sequence of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+  let mut parts = pattern.split('*');
+  let first = parts.next().unwrap_or_default();
+  let mut rest = match name.strip_prefix(first) {
+    Some(rest) => rest,
+    None => return false,
+  };
+
+  let parts: Vec<&str> = parts.collect();
+  let (last, middle) = match parts.split_last() {
+    Some((last, middle)) => (*last, middle),
+    // No `*`, the pattern is the name itself.
+    None => return rest.is_empty(),
+  };
+
+  for part in middle {
+    match rest.find(part) {
+      Some(start) => rest = &rest[start + part.len()..],
+      None => return false,
+    }
+  }
+
+  rest.ends_with(last)
+}
+
+/// Enqueues every task type on the queue its [`RoutingTable`] routes it to, through a
+/// [`RedisBroker`] of that queue. The tasks of types no route matches are rejected with
+/// [`BrokerError::Unrouted`].
+#[derive(Clone, Debug)]
+pub struct RoutingBroker {
+  registry: RedisRegistry,
+  table: Arc<RoutingTable>,
+  shutdown: Option<ShutdownToken>,
+}
+
+impl RoutingBroker {
+  pub fn new(registry: RedisRegistry, table: RoutingTable) -> Self {
+    Self {
+      registry,
+      table: Arc::new(table),
+      shutdown: None,
+    }
+  }
+
+  /// Rejects the enqueues once `shutdown` is shut down, see [`RedisBroker::with_shutdown`].
+  pub fn with_shutdown(mut self, shutdown: ShutdownToken) -> Self {
+    self.shutdown = Some(shutdown);
+    self
+  }
+
+  pub fn table(&self) -> &RoutingTable {
+    &self.table
+  }
+
+  /// The broker of the queue the tasks of `T` are routed to.
+  pub fn broker<T>(&self) -> Result<RedisBroker<T>, BrokerError>
+  where
+    T: Performable + Send + Sync + Serialize + DeserializeOwned,
+  {
+    let queue = self
+      .table
+      .route(T::type_name())
+      .ok_or(BrokerError::Unrouted(T::type_name()))?;
+
+    let broker = RedisBroker::from_registry(&self.registry, queue);
+    Ok(match &self.shutdown {
+      Some(shutdown) => broker.with_shutdown(shutdown.clone()),
+      None => broker,
+    })
+  }
+}
+
+#[async_trait::async_trait]
+impl<T: Performable> Broker<T> for RoutingBroker
+where
+  T: Send + Sync + Serialize + DeserializeOwned + 'static,
+{
+  type Error = BrokerError;
+
+  async fn enqueue(&self, task: T, ctx: &Context) -> Result<Operation, Self::Error> {
+    self.broker::<T>()?.enqueue(task, ctx).await
+  }
+
+  async fn enqueue_all(&self, tasks: Vec<T>, ctx: &Context) -> Result<Vec<Operation>, Self::Error> {
+    self.broker::<T>()?.enqueue_all(tasks, ctx).await
+  }
+
+  async fn cancel(&self, id: &str, ctx: &Context) -> Result<Operation, Self::Error> {
+    Broker::<T>::cancel(&self.broker::<T>()?, id, ctx).await
+  }
+
+  async fn schedule_at(
+    &self,
+    task: T,
+    at: DateTime<Utc>,
+    ctx: &Context,
+  ) -> Result<Operation, Self::Error> {
+    self.broker::<T>()?.schedule_at(task, at, ctx).await
+  }
+
+  async fn enqueue_unique(
+    &self,
+    task: T,
+    key: &str,
+    ctx: &Context,
+  ) -> Result<Operation, Self::Error> {
+    self.broker::<T>()?.enqueue_unique(task, key, ctx).await
+  }
+
+  async fn status(&self, id: &str) -> Result<Option<Operation>, Self::Error> {
+    Broker::<T>::status(&self.broker::<T>()?, id).await
+  }
+
+  async fn find_by_key(&self, key: &str) -> Result<Option<Operation>, Self::Error> {
+    Broker::<T>::find_by_key(&self.broker::<T>()?, key).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::proto::google::protobuf::Empty;
+  use crate::redis::RedisConf;
+
+  use super::*;
+
+  #[derive(Serialize, serde::Deserialize)]
+  struct Export;
+
+  #[async_trait::async_trait]
+  impl Performable for Export {
+    type Error = std::io::Error;
+    type Context = ();
+    type Output = Empty;
+
+    fn type_name() -> &'static str {
+      "reports::Export"
+    }
+
+    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+      Ok(Empty::default())
+    }
+  }
+
+  #[test]
+  fn route_should_pick_the_first_matching_route() {
+    let table = RoutingTable::new()
+      .with_route("backup::*", "backups")
+      .with_route("*::Export", "exports")
+      .with_route("reports::*", "reports");
+
+    assert_eq!(table.route("backup::Full"), Some("backups"));
+    assert_eq!(table.route("reports::Export"), Some("exports"));
+    assert_eq!(table.route("reports::Summary"), Some("reports"));
+    assert_eq!(table.route("billing::Invoice"), None);
+    assert_eq!(
+      table
+        .with_default_queue("default")
+        .route("billing::Invoice"),
+      Some("default")
+    );
+  }
+
+  #[test]
+  fn matches_should_expand_wildcards() {
+    assert!(matches("backup", "backup"));
+    assert!(!matches("backup", "backups"));
+    assert!(matches("*", "anything"));
+    assert!(matches("a*b*c", "a-b-c"));
+    assert!(matches("a*b*c", "abc"));
+    assert!(!matches("a*b*c", "a-c-b"));
+    assert!(!matches("ab*ba", "aba"));
+  }
+
+  #[tokio::test]
+  async fn enqueue_should_reject_unrouted_task_types() {
+    // Rejected before connecting, so no Redis is needed.
+    let registry = RedisRegistry::new(&RedisConf::new("redis://127.0.0.1:1/")).unwrap();
+    let ctx = Context::new("42".to_string(), "1234".to_string());
+    let broker = RoutingBroker::new(
+      registry,
+      RoutingTable::new().with_route("backup::*", "backups"),
+    );
+
+    let error = broker.enqueue(Export, &ctx).await.unwrap_err();
+    assert!(matches!(error, BrokerError::Unrouted("reports::Export")));
+    assert_eq!(
+      tonic::Status::from(error).code(),
+      tonic::Code::FailedPrecondition
+    );
+  }
+}
@@ -0,0 +1,799 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::service::make_service_fn;
+use hyper::service::service_fn;
+use hyper::Body;
+use hyper::Request;
+use hyper::Response;
+use hyper::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing_futures::Instrument;
+use uuid::Uuid;
+
+use crate::codec::json::JsonCodec;
+use crate::proto::longrunning::TaskSchema;
+use crate::redis::Keys;
+use crate::service::panic_metrics;
+use crate::service::shutdown_signal;
+use crate::service::DEFAULT_SHUTDOWN_GRACE;
+use crate::util::error_context::ErrorContext;
+use crate::util::error_context::ResultExt;
+use crate::util::redis_exec;
+use crate::util::runtime::RuntimeStats;
+use crate::util::runtime::TaskMonitor;
+use crate::util::shutdown::ShutdownToken;
+
+use super::admin::RedisAdmin;
+use super::failure::DefaultClassifier;
+use super::failure::Failure;
+use super::failure::SharedFailureClassifier;
+use super::payload;
+use super::redis::RedisQueue;
+use super::redis::RedisQueueError;
+use super::redis::DEFAULT_POISON_THRESHOLD;
+use super::registry::CommandHandler;
+use super::registry::TaskRegistry;
+use super::skew;
+use super::sla::SlaTracker;
+use super::store::RedisTaskStore;
+use super::worker::error_backoff;
+use super::worker::until_cancelled;
+use super::worker::DEFAULT_CANCEL_POLL_INTERVAL;
+use super::AckMode;
+use super::Context;
+use super::TaskContext;
+
+/// Queue whose payloads are left encoded and dispatched through a [`TaskRegistry`].
+type RawQueue = RedisQueue<(), JsonCodec<Value, Value>>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum RunnerError {
+  #[error("Invalid configuration: {0}")]
+  Config(String),
+
+  #[error("Redis command failed: {0}")]
+  Redis(#[from] redis::RedisError),
+
+  #[error("Health server failed: {0}")]
+  Server(#[from] hyper::Error),
+}
+
+/// Configuration of a [`Runner`], usually loaded from a file.
+///
+/// ```yaml
+/// redis_url: redis://127.0.0.1/
+/// key_prefix: "rappel:production:"
+/// address: 0.0.0.0:9090
+/// queues:
+///   - name: backups
+///     concurrency: 4
+/// sla_ms:
+///   backup: 600000
+/// handlers:
+///   backup:
+///     command: /usr/local/bin/backup
+///     args: ["--compress"]
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct RunnerConfig {
+  pub redis_url: String,
+
+  /// Prefix of the Redis keys of the queues, see [`Keys`].
+  #[serde(default)]
+  pub key_prefix: String,
+
+  /// Identifies this process in the operations it dequeues. Defaults to a random id.
+  #[serde(default)]
+  pub system_id: Option<String>,
+
+  /// Address of the HTTP server exposing `/healthz` and `/metrics`. Not started when missing.
+  #[serde(default)]
+  pub address: Option<String>,
+
+  pub queues: Vec<QueueConfig>,
+
+  /// SLA threshold in milliseconds by task type, see [`SlaTracker`].
+  #[serde(default)]
+  pub sla_ms: HashMap<String, u64>,
+
+  /// Task types handled by an external command, see [`CommandHandler`].
+  #[serde(default)]
+  pub handlers: HashMap<String, CommandHandler>,
+
+  /// Milliseconds the tasks in progress get to complete on shutdown, 30 seconds when unset.
+  #[serde(default)]
+  pub shutdown_grace_ms: Option<u64>,
+
+  /// Adds the metrics of the Tokio runtime and the poll times of the tasks to `/metrics`, see
+  /// [`crate::util::runtime`].
+  #[serde(default)]
+  pub runtime_metrics: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct QueueConfig {
+  pub name: String,
+
+  /// Number of tasks of the queue performed concurrently.
+  #[serde(default = "default_concurrency")]
+  pub concurrency: usize,
+
+  #[serde(default = "default_poll_interval_ms")]
+  pub poll_interval_ms: u64,
+
+  #[serde(default = "default_poison_threshold")]
+  pub poison_threshold: i64,
+
+  /// `auto` or `on_complete`, the default. Handlers cannot acknowledge, so `manual` is rejected.
+  #[serde(default)]
+  pub ack_mode: AckMode,
+
+  /// User the workers of the queue dequeue and enqueue as, the system id of the runner when unset.
+  #[serde(default)]
+  pub service_account: Option<String>,
+
+  /// Principals allowed to enqueue to the queue on behalf of other users, besides the service
+  /// account. Any principal may when empty, see [`RedisQueue::with_impersonators`].
+  #[serde(default)]
+  pub impersonators: Vec<String>,
+
+  /// Queue the tasks of a newer schema version than their handler supports are moved to, rather
+  /// than being performed, see [`RedisQueue::with_holding_queue`].
+  #[serde(default)]
+  pub holding_queue: Option<String>,
+}
+
+fn default_concurrency() -> usize {
+  1
+}
+
+fn default_poll_interval_ms() -> u64 {
+  1000
+}
+
+fn default_poison_threshold() -> i64 {
+  DEFAULT_POISON_THRESHOLD
+}
+
+/// Task counters of a [`Runner`], rendered in the Prometheus text format on `/metrics` along
+/// with the Redis commands of the process, see [`crate::util::redis_exec`].
+#[derive(Debug, Default)]
+pub struct RunnerMetrics {
+  tasks: Mutex<BTreeMap<(String, String, &'static str), u64>>,
+  busy: AtomicI64,
+  sla: SlaTracker,
+  monitor: Option<TaskMonitor>,
+}
+
+impl RunnerMetrics {
+  fn record(&self, queue: &str, task_type: &str, outcome: &'static str) {
+    let mut tasks = self.tasks.lock().unwrap();
+    *tasks
+      .entry((queue.to_string(), task_type.to_string(), outcome))
+      .or_default() += 1;
+  }
+
+  /// Number of tasks of `queue` and `task_type` that ended with `outcome`.
+  pub fn count(&self, queue: &str, task_type: &str, outcome: &'static str) -> u64 {
+    let tasks = self.tasks.lock().unwrap();
+    tasks
+      .get(&(queue.to_string(), task_type.to_string(), outcome))
+      .copied()
+      .unwrap_or_default()
+  }
+
+  pub fn render(&self) -> String {
+    let mut out = String::default();
+
+    let _ = writeln!(out, "# TYPE rappel_runner_tasks_total counter");
+    for ((queue, task_type, outcome), count) in self.tasks.lock().unwrap().iter() {
+      let _ = writeln!(
+        out,
+        "rappel_runner_tasks_total{{queue=\"{}\",task_type=\"{}\",outcome=\"{}\"}} {}",
+        queue, task_type, outcome, count
+      );
+    }
+
+    let _ = writeln!(out, "# TYPE rappel_runner_busy_workers gauge");
+    let _ = writeln!(
+      out,
+      "rappel_runner_busy_workers {}",
+      self.busy.load(Ordering::Relaxed)
+    );
+
+    let latencies = self.sla.summaries();
+    let _ = writeln!(out, "# TYPE rappel_runner_task_latency_ms summary");
+    for latency in &latencies {
+      for (quantile, value) in [
+        ("0.5", latency.p50_ms),
+        ("0.9", latency.p90_ms),
+        ("0.99", latency.p99_ms),
+      ] {
+        let _ = writeln!(
+          out,
+          "rappel_runner_task_latency_ms{{task_type=\"{}\",quantile=\"{}\"}} {}",
+          latency.task_type, quantile, value
+        );
+      }
+      let _ = writeln!(
+        out,
+        "rappel_runner_task_latency_ms_count{{task_type=\"{}\"}} {}",
+        latency.task_type, latency.count
+      );
+    }
+
+    let _ = writeln!(out, "# TYPE rappel_runner_sla_violations_total counter");
+    for latency in &latencies {
+      let _ = writeln!(
+        out,
+        "rappel_runner_sla_violations_total{{task_type=\"{}\"}} {}",
+        latency.task_type, latency.sla_violations
+      );
+    }
+
+    if let Some(monitor) = &self.monitor {
+      render_runtime(&mut out, monitor);
+    }
+    out.push_str(&payload::metrics().render());
+    out.push_str(&skew::metrics().render());
+    out.push_str(&panic_metrics().render());
+    out.push_str(&redis_exec::metrics().render());
+
+    out
+  }
+
+  /// Performs `task`, timing its polls when the runtime metrics are enabled.
+  async fn time_polls<F: Future>(&self, task: F) -> F::Output {
+    match &self.monitor {
+      Some(monitor) => monitor.instrument(task).await,
+      None => task.await,
+    }
+  }
+}
+
+fn render_runtime(out: &mut String, monitor: &TaskMonitor) {
+  let tasks = monitor.stats();
+  for (name, kind, value) in [
+    (
+      "rappel_runner_task_polls_total",
+      "counter",
+      tasks.polls as f64,
+    ),
+    (
+      "rappel_runner_task_slow_polls_total",
+      "counter",
+      tasks.slow_polls as f64,
+    ),
+    (
+      "rappel_runner_task_poll_seconds_total",
+      "counter",
+      tasks.poll_time.as_secs_f64(),
+    ),
+    (
+      "rappel_runner_task_first_poll_delay_seconds_total",
+      "counter",
+      tasks.first_poll_delay.as_secs_f64(),
+    ),
+  ] {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+  }
+
+  let runtime = match RuntimeStats::sample() {
+    Some(runtime) => runtime,
+    None => return,
+  };
+  for (name, kind, value) in [
+    ("rappel_runtime_workers", "gauge", runtime.workers as f64),
+    (
+      "rappel_runtime_alive_tasks",
+      "gauge",
+      runtime.alive_tasks as f64,
+    ),
+    (
+      "rappel_runtime_global_queue_depth",
+      "gauge",
+      runtime.global_queue_depth as f64,
+    ),
+    (
+      "rappel_runtime_busy_seconds_total",
+      "counter",
+      runtime.busy.as_secs_f64(),
+    ),
+    (
+      "rappel_runtime_parks_total",
+      "counter",
+      runtime.parks as f64,
+    ),
+  ] {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+  }
+}
+
+/// Runs a pool of workers over several queues, dispatching every task to the handler registered
+/// for its task type. Tasks without a handler are moved to the invalid list of their queue.
+///
+/// ```rust,no_run
+/// # async fn example(config: rappel::longrunning::runner::RunnerConfig) {
+/// use rappel::longrunning::registry::TaskRegistry;
+/// use rappel::longrunning::runner::Runner;
+///
+/// let registry = TaskRegistry::new();
+/// Runner::new(config, registry).unwrap().run().await.unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Runner {
+  config: RunnerConfig,
+  client: redis::Client,
+  registry: Arc<TaskRegistry>,
+  metrics: Arc<RunnerMetrics>,
+  classifier: SharedFailureClassifier,
+  shutdown: ShutdownToken,
+}
+
+impl Runner {
+  /// Creates a runner for `config`. The command handlers of the configuration are added to
+  /// `registry`.
+  pub fn new(config: RunnerConfig, mut registry: TaskRegistry) -> Result<Self, RunnerError> {
+    if config.queues.is_empty() {
+      return Err(RunnerError::Config("No queue configured".to_string()));
+    }
+
+    if let Some(queue) = config
+      .queues
+      .iter()
+      .find(|queue| queue.ack_mode == AckMode::Manual)
+    {
+      return Err(RunnerError::Config(format!(
+        "Queue {} cannot use manual acks",
+        queue.name
+      )));
+    }
+
+    for (task_type, handler) in &config.handlers {
+      registry.register_handler(task_type, Arc::new(handler.clone()));
+    }
+
+    let sla = config
+      .sla_ms
+      .iter()
+      .fold(SlaTracker::new(), |sla, (task_type, threshold_ms)| {
+        sla.with_threshold(task_type, Duration::from_millis(*threshold_ms))
+      });
+    let metrics = RunnerMetrics {
+      sla,
+      monitor: config.runtime_metrics.then(TaskMonitor::new),
+      ..Default::default()
+    };
+
+    Ok(Self {
+      client: redis::Client::open(config.redis_url.as_str())?,
+      config,
+      registry: Arc::new(registry),
+      metrics: Arc::new(metrics),
+      classifier: Arc::new(DefaultClassifier),
+      shutdown: ShutdownToken::new(),
+    })
+  }
+
+  /// Sets how the errors of the tasks are handled, [`DefaultClassifier`] by default.
+  pub fn with_failure_classifier(mut self, classifier: SharedFailureClassifier) -> Self {
+    self.classifier = classifier;
+    self
+  }
+
+  /// Stops the runner once `shutdown` is shut down, as on a shutdown signal. The runner shuts
+  /// the token down itself when it receives the signal, so the rest of the process observes it.
+  pub fn with_shutdown(mut self, shutdown: ShutdownToken) -> Self {
+    self.shutdown = shutdown;
+    self
+  }
+
+  pub fn metrics(&self) -> Arc<RunnerMetrics> {
+    self.metrics.clone()
+  }
+
+  /// Starts the workers and the HTTP server, and runs until the process receives SIGTERM or
+  /// Ctrl-C. See [`Runner::run_until`].
+  pub async fn run(self) -> Result<(), RunnerError> {
+    self.run_until(shutdown_signal()).await
+  }
+
+  /// Starts the workers and the HTTP server, and runs until `signal` resolves or the runner is
+  /// shut down, see [`Runner::with_shutdown`]. The workers then stop pulling tasks, and the tasks in progress get `shutdown_grace_ms` to complete before
+  /// being aborted. Aborted tasks are delivered again once their lease expires.
+  pub async fn run_until(self, signal: impl Future<Output = ()>) -> Result<(), RunnerError> {
+    let system_id = self
+      .config
+      .system_id
+      .clone()
+      .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    tracing::info!(
+      message = "Starting runner",
+      task_types = ?self.registry.task_types().collect::<Vec<_>>(),
+      queues = ?self.config.queues.iter().map(|q| &q.name).collect::<Vec<_>>(),
+    );
+
+    let admin = RedisAdmin::new(self.client.clone()).with_keys(Keys::new(&self.config.key_prefix));
+    let schemas: Vec<TaskSchema> = self
+      .registry
+      .schemas()
+      .map(|schema| TaskSchema {
+        worker_version: skew::CRATE_VERSION.to_string(),
+        ..schema.clone()
+      })
+      .collect();
+    if let Err(error) = admin.publish_schemas(&schemas).await {
+      tracing::warn!(message = "Failed to publish the task schemas", %error);
+    }
+
+    let mut workers = Vec::default();
+
+    for queue in &self.config.queues {
+      let user_id = queue.service_account.as_ref().unwrap_or(&system_id);
+      let ctx = Context::new(user_id.clone(), system_id.clone());
+      let mut raw: RawQueue =
+        RedisQueue::new(self.client.clone(), queue.name.clone(), JsonCodec::new())
+          .with_poison_threshold(queue.poison_threshold)
+          .with_ack_mode(queue.ack_mode)
+          .with_keys(Keys::new(&self.config.key_prefix))
+          .with_sla(self.metrics.sla.clone())
+          .with_system_context(ctx.clone());
+      if !queue.impersonators.is_empty() {
+        raw = raw.with_impersonators(queue.impersonators.clone());
+      }
+      if let Some(holding_queue) = &queue.holding_queue {
+        raw = raw.with_holding_queue(holding_queue);
+      }
+
+      let store = RedisTaskStore::new(self.client.clone()).with_keys(raw.keys().clone());
+
+      for _ in 0..queue.concurrency.max(1) {
+        let worker = RegistryWorker {
+          queue: raw.clone(),
+          store: store.clone(),
+          registry: self.registry.clone(),
+          metrics: self.metrics.clone(),
+          ctx: ctx.clone(),
+          poll_interval: Duration::from_millis(queue.poll_interval_ms),
+          classifier: self.classifier.clone(),
+        };
+        let shutdown = self.shutdown.clone();
+
+        workers.push(tokio::spawn(async move { worker.run(shutdown).await }));
+      }
+    }
+
+    // The health server keeps serving while the workers drain.
+    let server = self.serve();
+    tokio::pin!(server);
+
+    tokio::select! {
+      result = &mut server => return result,
+      _ = signal => {}
+      _ = self.shutdown.wait() => {}
+    }
+
+    let grace = self
+      .config
+      .shutdown_grace_ms
+      .map_or(DEFAULT_SHUTDOWN_GRACE, Duration::from_millis);
+    tracing::info!(
+      message = "Stopping runner",
+      grace_ms = grace.as_millis() as u64
+    );
+    self.shutdown.shutdown();
+
+    let drained = tokio::time::timeout(grace, futures::future::join_all(workers.iter_mut()));
+    tokio::select! {
+      result = &mut server => return result,
+      drained = drained => {
+        if drained.is_err() {
+          tracing::warn!(message = "Grace period elapsed, aborting tasks in progress");
+          workers.iter().for_each(|worker| worker.abort());
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  async fn serve(&self) -> Result<(), RunnerError> {
+    let address: SocketAddr = match &self.config.address {
+      None => return futures::future::pending().await,
+      Some(address) => address
+        .parse()
+        .map_err(|_| RunnerError::Config(format!("Invalid address {}", address)))?,
+    };
+
+    let client = self.client.clone();
+    let metrics = self.metrics.clone();
+
+    let make_service = make_service_fn(move |_| {
+      let client = client.clone();
+      let metrics = metrics.clone();
+
+      async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+          let client = client.clone();
+          let metrics = metrics.clone();
+
+          async move { Ok::<_, Infallible>(respond(request, &client, &metrics).await) }
+        }))
+      }
+    });
+
+    tracing::info!(message = "Serving health and metrics", %address);
+    hyper::Server::try_bind(&address)?
+      .serve(make_service)
+      .await?;
+
+    Ok(())
+  }
+}
+
+async fn respond(
+  request: Request<Body>,
+  client: &redis::Client,
+  metrics: &RunnerMetrics,
+) -> Response<Body> {
+  let (status, body) = match request.uri().path() {
+    "/healthz" => match ping(client).await {
+      Ok(()) => (StatusCode::OK, "ok".to_string()),
+      Err(error) => (StatusCode::SERVICE_UNAVAILABLE, error.to_string()),
+    },
+    "/metrics" => (StatusCode::OK, metrics.render()),
+    _ => (StatusCode::NOT_FOUND, String::default()),
+  };
+
+  let mut response = Response::new(Body::from(body));
+  *response.status_mut() = status;
+  response
+}
+
+async fn ping(client: &redis::Client) -> Result<(), redis::RedisError> {
+  let mut conn = redis_exec::connect(client).await?;
+  redis::cmd("PING").query_async(&mut conn).await
+}
+
+/// Worker of a [`Runner`], see [`super::worker::Worker`] for the typed equivalent.
+struct RegistryWorker {
+  queue: RawQueue,
+  store: RedisTaskStore,
+  registry: Arc<TaskRegistry>,
+  metrics: Arc<RunnerMetrics>,
+  ctx: Context,
+  poll_interval: Duration,
+  classifier: SharedFailureClassifier,
+}
+
+impl RegistryWorker {
+  /// Performs tasks until `shutdown` is shut down. The task in progress then is completed first.
+  async fn run(&self, shutdown: ShutdownToken) {
+    let mut failures = error_backoff(self.poll_interval).delays();
+
+    while !shutdown.is_shutdown() {
+      let delay = match self.metrics.time_polls(self.process_one()).await {
+        Ok(true) => {
+          failures.reset();
+          continue;
+        }
+        Ok(false) => {
+          failures.reset();
+          self.poll_interval
+        }
+        Err(error) => {
+          tracing::error!(message = "Failed to process task", queue = %self.queue.name(), %error);
+          failures.next().unwrap_or(self.poll_interval)
+        }
+      };
+
+      tokio::select! {
+        _ = tokio::time::sleep(delay) => {}
+        _ = shutdown.wait() => {}
+      }
+    }
+  }
+
+  async fn process_one(&self) -> Result<bool, RedisQueueError> {
+    let pulled = self
+      .queue
+      .pull_raw(&self.ctx)
+      .await
+      .with_context(|| ErrorContext::new().with_queue(self.queue.name()))?;
+    let message = match pulled {
+      None => return Ok(false),
+      Some(message) => message,
+    };
+
+    if let Some(supported) = self.registry.schema_version(&message.task_type) {
+      let held = self
+        .queue
+        .hold_skewed(&message, supported)
+        .await
+        .with_context(|| self.queue.error_context(&message.ack_id))?;
+      if held {
+        self
+          .metrics
+          .record(self.queue.name(), &message.task_type, "held");
+        return Ok(true);
+      }
+    }
+
+    let handler = match self.registry.get(&message.task_type) {
+      Some(handler) => handler,
+      None => {
+        self
+          .metrics
+          .record(self.queue.name(), &message.task_type, "unhandled");
+        let error = format!("No handler registered for task type {}", message.task_type);
+        self
+          .queue
+          .invalidate(&message.ack_id, &error)
+          .await
+          .with_context(|| self.queue.error_context(&message.ack_id))?;
+        return Ok(true);
+      }
+    };
+
+    let cancellation = ShutdownToken::new();
+    let task_ctx = TaskContext::new(
+      &message.ack_id,
+      &message.task_type,
+      self.queue.name(),
+      message.attempt,
+      &message.user_id,
+    )
+    .with_context(message.context.clone())
+    .with_cancellation(cancellation.clone());
+    let span = task_ctx.logger().clone();
+
+    async {
+      self.metrics.busy.fetch_add(1, Ordering::Relaxed);
+      tracing::debug!(message = "Performing task");
+      let result = until_cancelled(
+        &self.store,
+        &message.ack_id,
+        DEFAULT_CANCEL_POLL_INTERVAL,
+        &cancellation,
+        handler.handle(&message.payload, &message.content_type, task_ctx),
+      )
+      .await;
+      self.metrics.busy.fetch_sub(1, Ordering::Relaxed);
+
+      let outcome = match result {
+        // The cancellation already completed the operation and took it off the queue.
+        _ if cancellation.is_shutdown() => "cancelled",
+        Ok(output) => {
+          self
+            .queue
+            .complete_raw(&message.ack_id, Ok(output), &self.ctx)
+            .await
+            .with_context(|| self.queue.error_context(&message.ack_id))?;
+          "succeeded"
+        }
+        Err(error) => {
+          let failure = self.classifier.classify(&error);
+          self
+            .queue
+            .fail(&message.ack_id, error, failure, message.attempt, &self.ctx)
+            .await
+            .with_context(|| self.queue.error_context(&message.ack_id))?;
+          match failure {
+            Failure::Fatal => "failed",
+            failure => failure.as_str(),
+          }
+        }
+      };
+      self
+        .metrics
+        .record(self.queue.name(), &message.task_type, outcome);
+
+      tracing::debug!(message = "Task completed", %outcome);
+      Ok(true)
+    }
+    .instrument(span)
+    .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use redis::AsyncCommands;
+
+  use super::*;
+
+  #[test]
+  fn metrics_should_render_prometheus_text() {
+    let metrics = RunnerMetrics::default();
+    metrics.record("backups", "backup", "succeeded");
+    metrics.record("backups", "backup", "succeeded");
+
+    assert_eq!(metrics.count("backups", "backup", "succeeded"), 2);
+    assert!(metrics.render().contains(
+      "rappel_runner_tasks_total{queue=\"backups\",task_type=\"backup\",outcome=\"succeeded\"} 2"
+    ));
+    assert!(!metrics.render().contains("rappel_runtime_workers"));
+  }
+
+  #[tokio::test]
+  async fn metrics_should_render_the_runtime_when_enabled() {
+    let metrics = RunnerMetrics {
+      monitor: Some(TaskMonitor::new()),
+      ..Default::default()
+    };
+
+    assert_eq!(metrics.time_polls(async { 1 }).await, 1);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("rappel_runner_task_polls_total 1"));
+    assert!(rendered.contains("rappel_runtime_workers 1"));
+  }
+
+  #[tokio::test]
+  async fn registry_worker_should_dispatch_by_task_type() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut registry = TaskRegistry::new();
+    registry.register_handler("echo", Arc::new(CommandHandler::new("cat")));
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    for (id, task_type) in [("a", "echo"), ("b", "unknown")] {
+      let id = format!("{}-{}", queue, id);
+      let _: () = conn
+        .hset_multiple(
+          format!("operation:{}", id),
+          &[
+            ("queue", queue.as_str()),
+            ("task_type", task_type),
+            ("task", "{}"),
+          ],
+        )
+        .await
+        .unwrap();
+      let _: () = conn.lpush(format!("queue:{}", queue), &id).await.unwrap();
+    }
+
+    let worker = RegistryWorker {
+      queue: RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new()),
+      store: RedisTaskStore::new(client.clone()),
+      registry: Arc::new(registry),
+      metrics: Arc::new(RunnerMetrics::default()),
+      ctx,
+      poll_interval: Duration::from_millis(10),
+      classifier: Arc::new(DefaultClassifier),
+    };
+
+    assert!(worker.process_one().await.unwrap());
+    assert!(worker.process_one().await.unwrap());
+    assert!(!worker.process_one().await.unwrap());
+
+    let result: Vec<u8> = conn
+      .hget(format!("operation:{}-a", queue), "result")
+      .await
+      .unwrap();
+    let invalid: Vec<String> = conn
+      .lrange(format!("queue:invalid:{}", queue), 0, -1)
+      .await
+      .unwrap();
+
+    assert_eq!(result, b"{}");
+    assert_eq!(invalid, vec![format!("{}-b", queue)]);
+    assert_eq!(worker.metrics.count(&queue, "echo", "succeeded"), 1);
+  }
+}
@@ -0,0 +1,194 @@
+//! Validation of JSON payloads against the JSON schemas of their task type, see
+//! [`crate::proto::longrunning::TaskSchema`].
+//!
+//! Only the keywords describing the shape of a payload are checked: `type`, `enum`, `required`,
+//! `properties`, `additionalProperties`, `items`, `minimum` and `maximum`. Other keywords are
+//! ignored, so a payload accepted here may still be rejected by the task.
+
+use serde_json::Value;
+
+/// A payload not matching its schema. `path` locates the offending value, e.g. `$.disks[0]`.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{path}: {message}")]
+pub struct SchemaViolation {
+  pub path: String,
+  pub message: String,
+}
+
+/// Checks `payload` against `schema`, failing on the first violation.
+pub fn validate(schema: &Value, payload: &Value) -> Result<(), SchemaViolation> {
+  check(schema, payload, "$")
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), SchemaViolation> {
+  let schema = match schema {
+    Value::Bool(true) => return Ok(()),
+    Value::Bool(false) => return Err(violation(path, "no value is allowed".to_string())),
+    Value::Object(schema) => schema,
+    _ => return Ok(()),
+  };
+
+  if let Some(types) = schema.get("type") {
+    let allowed: Vec<&str> = match types {
+      Value::String(kind) => vec![kind.as_str()],
+      Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+      _ => Vec::default(),
+    };
+    if !allowed.is_empty() && !allowed.iter().any(|kind| is_type(value, kind)) {
+      return Err(violation(
+        path,
+        format!(
+          "expected {}, found {}",
+          allowed.join(" or "),
+          type_name(value)
+        ),
+      ));
+    }
+  }
+
+  if let Some(Value::Array(values)) = schema.get("enum") {
+    if !values.contains(value) {
+      return Err(violation(
+        path,
+        format!("{} is not one of {:?}", value, values),
+      ));
+    }
+  }
+
+  if let Some(number) = value.as_f64() {
+    if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+      if number < minimum {
+        return Err(violation(
+          path,
+          format!("{} is less than {}", number, minimum),
+        ));
+      }
+    }
+    if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+      if number > maximum {
+        return Err(violation(
+          path,
+          format!("{} is greater than {}", number, maximum),
+        ));
+      }
+    }
+  }
+
+  match value {
+    Value::Object(fields) => {
+      if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+          if !fields.contains_key(name) {
+            return Err(violation(
+              path,
+              format!("missing required property {}", name),
+            ));
+          }
+        }
+      }
+
+      let properties = schema.get("properties").and_then(Value::as_object);
+      for (name, field) in fields {
+        let path = format!("{}.{}", path, name);
+        match (
+          properties.and_then(|p| p.get(name)),
+          schema.get("additionalProperties"),
+        ) {
+          (Some(property), _) => check(property, field, &path)?,
+          (None, Some(additional)) => check(additional, field, &path)?,
+          (None, None) => {}
+        }
+      }
+    }
+    Value::Array(items) => {
+      if let Some(item_schema) = schema.get("items") {
+        for (index, item) in items.iter().enumerate() {
+          check(item_schema, item, &format!("{}[{}]", path, index))?;
+        }
+      }
+    }
+    _ => {}
+  }
+
+  Ok(())
+}
+
+fn is_type(value: &Value, kind: &str) -> bool {
+  match kind {
+    "integer" => value.is_i64() || value.is_u64(),
+    "number" => value.is_number(),
+    kind => type_name(value) == kind,
+  }
+}
+
+fn type_name(value: &Value) -> &'static str {
+  match value {
+    Value::Null => "null",
+    Value::Bool(_) => "boolean",
+    Value::Number(_) => "number",
+    Value::String(_) => "string",
+    Value::Array(_) => "array",
+    Value::Object(_) => "object",
+  }
+}
+
+fn violation(path: &str, message: String) -> SchemaViolation {
+  SchemaViolation {
+    path: path.to_string(),
+    message,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn validate_should_check_the_shape_of_payloads() {
+    let schema = json!({
+      "type": "object",
+      "required": ["workspace_id"],
+      "additionalProperties": false,
+      "properties": {
+        "workspace_id": {"type": "string"},
+        "retention_days": {"type": "integer", "minimum": 1},
+        "tier": {"enum": ["cold", "hot"]},
+        "disks": {"type": "array", "items": {"type": "string"}}
+      }
+    });
+
+    assert_eq!(
+      validate(
+        &schema,
+        &json!({"workspace_id": "1", "retention_days": 7, "tier": "cold", "disks": ["a"]})
+      ),
+      Ok(())
+    );
+
+    let error = |payload: Value| validate(&schema, &payload).unwrap_err().to_string();
+    assert_eq!(error(json!([])), "$: expected object, found array");
+    assert_eq!(
+      error(json!({})),
+      "$: missing required property workspace_id"
+    );
+    assert_eq!(
+      error(json!({"workspace_id": "1", "retention_days": 1.5})),
+      "$.retention_days: expected integer, found number"
+    );
+    assert_eq!(
+      error(json!({"workspace_id": "1", "retention_days": 0})),
+      "$.retention_days: 0 is less than 1"
+    );
+    assert_eq!(
+      error(json!({"workspace_id": "1", "disks": ["a", 2]})),
+      "$.disks[1]: expected string, found number"
+    );
+    assert_eq!(
+      error(json!({"workspace_id": "1", "owner": "me"})),
+      "$.owner: no value is allowed"
+    );
+    assert!(error(json!({"workspace_id": "1", "tier": "warm"})).starts_with("$.tier: "));
+  }
+}
@@ -0,0 +1,521 @@
+//! The `longrunning.Operations` gRPC service, backed by a [`TaskStore`] and, for the queues, a
+//! [`RedisAdmin`].
+//!
+//! ```rust,ignore
+//! let service = OperationsService::new(RedisTaskStore::new(client.clone()), "rappel")
+//!   .with_admin(RedisAdmin::new(client));
+//! tonic::transport::Server::builder()
+//!   .add_service(OperationsServer::new(service))
+//!   .serve(address)
+//!   .await?;
+//! ```
+
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::Stream;
+use futures::StreamExt;
+
+use crate::proto::google::protobuf::Empty;
+use crate::proto::longrunning::operations_server::Operations;
+use crate::proto::longrunning::AnnotateOperationRequest;
+use crate::proto::longrunning::BatchGetOperationsRequest;
+use crate::proto::longrunning::BatchGetOperationsResponse;
+use crate::proto::longrunning::CancelOperationRequest;
+use crate::proto::longrunning::GetLatencySummaryRequest;
+use crate::proto::longrunning::GetOperationRequest;
+use crate::proto::longrunning::GetOperationTreeRequest;
+use crate::proto::longrunning::GetQueueMetricsRequest;
+use crate::proto::longrunning::LatencySummary;
+use crate::proto::longrunning::ListTaskSchemasRequest;
+use crate::proto::longrunning::ListTaskSchemasResponse;
+use crate::proto::longrunning::Operation;
+use crate::proto::longrunning::OperationEvent;
+use crate::proto::longrunning::OperationTree;
+use crate::proto::longrunning::QueueMetrics;
+use crate::proto::longrunning::RestoreOperationRequest;
+use crate::proto::longrunning::StreamOperationsRequest;
+use crate::proto::longrunning::SubmitTaskRequest;
+use crate::service;
+
+use super::admin::RedisAdmin;
+use super::cache::OperationCache;
+use super::redis::BrokerError;
+use super::redis::RedisQueueError;
+use super::store::RedisTaskStore;
+use super::Context;
+use super::OperationState;
+use super::TaskStore;
+
+type OperationEvents = Pin<Box<dyn Stream<Item = Result<OperationEvent, tonic::Status>> + Send>>;
+
+/// Serves the operations of a [`TaskStore`]. The RPCs about the queues, and `SubmitTask`,
+/// `AnnotateOperation` and `StreamOperations`, need a [`RedisAdmin`], see
+/// [`OperationsService::with_admin`], and are unimplemented without one. Tasks submitted through
+/// `SubmitTask` are enqueued on behalf of the caller, from the system `system_id`.
+#[derive(Clone, Debug)]
+pub struct OperationsService<S = RedisTaskStore> {
+  store: S,
+  cache: Option<OperationCache<S>>,
+  admin: Option<RedisAdmin>,
+  system_id: String,
+}
+
+impl<S: TaskStore> OperationsService<S> {
+  pub fn new(store: S, system_id: &str) -> Self {
+    Self {
+      store,
+      cache: None,
+      admin: None,
+      system_id: system_id.to_string(),
+    }
+  }
+
+  /// Serves `Get` and `BatchGetOperations` from `cache`, for clients polling their operations.
+  /// The operations cancelled or annotated through the service are dropped from the cache at once,
+  /// the others once the cache receives their events, see [`OperationCache::with_invalidation`].
+  pub fn with_cache(mut self, cache: OperationCache<S>) -> Self {
+    self.cache = Some(cache);
+    self
+  }
+
+  /// Serves the RPCs about the queues from `admin`, which also cancels the operations, taking them
+  /// off their queue rather than only recording their cancellation in the store.
+  pub fn with_admin(mut self, admin: RedisAdmin) -> Self {
+    self.admin = Some(admin);
+    self
+  }
+
+  fn invalidate(&self, id: &str) {
+    if let Some(cache) = &self.cache {
+      cache.invalidate(id);
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl<S> Operations for OperationsService<S>
+where
+  S: TaskStore + 'static,
+  S::Error: Into<tonic::Status>,
+{
+  type StreamOperationsStream = OperationEvents;
+
+  async fn get(
+    &self,
+    request: tonic::Request<GetOperationRequest>,
+  ) -> Result<tonic::Response<Operation>, tonic::Status> {
+    let request = request.into_inner();
+    let operation = match &self.cache {
+      Some(cache) => cache.get(&request.operation_id).await,
+      None => self.store.get(&request.operation_id).await,
+    }
+    .map_err(Into::into)?
+    .ok_or_else(|| not_found(&request.operation_id))?;
+    Ok(tonic::Response::new(operation))
+  }
+
+  async fn batch_get_operations(
+    &self,
+    request: tonic::Request<BatchGetOperationsRequest>,
+  ) -> Result<tonic::Response<BatchGetOperationsResponse>, tonic::Status> {
+    let request = request.into_inner();
+    let operations = match &self.cache {
+      Some(cache) => cache.get_many(&request.operation_ids).await,
+      None => self.store.get_many(&request.operation_ids).await,
+    }
+    .map_err(Into::into)?;
+    Ok(tonic::Response::new(BatchGetOperationsResponse::of(
+      &request.operation_ids,
+      operations,
+    )))
+  }
+
+  /// Without a [`RedisAdmin`] the cancellation is only recorded in the store: the workers notice
+  /// it, and the operation is dropped rather than delivered when pulled.
+  async fn cancel(
+    &self,
+    request: tonic::Request<CancelOperationRequest>,
+  ) -> Result<tonic::Response<Empty>, tonic::Status> {
+    let ctx = service::Context::from_request(&request)?;
+    let request = request.into_inner();
+    if let Some(admin) = &self.admin {
+      admin
+        .cancel(
+          &request.operation_id,
+          &format!("Cancelled by user {}", ctx.user_id()),
+        )
+        .await?;
+      self.invalidate(&request.operation_id);
+      return Ok(tonic::Response::new(Empty::default()));
+    }
+
+    let id = &request.operation_id;
+    let cancelled = self
+      .store
+      .update_state(id, OperationState::Cancelled)
+      .await
+      .map_err(Into::into)?;
+    // Operations already done are left as they are, as by the admin.
+    let exists = cancelled || self.store.get(id).await.map_err(Into::into)?.is_some();
+    if !exists {
+      return Err(not_found(id));
+    }
+    self.invalidate(id);
+    Ok(tonic::Response::new(Empty::default()))
+  }
+
+  /// Fails with `ALREADY_EXISTS` when an operation with the same id was written since.
+  async fn restore(
+    &self,
+    request: tonic::Request<RestoreOperationRequest>,
+  ) -> Result<tonic::Response<Operation>, tonic::Status> {
+    let request = request.into_inner();
+    let operation = self
+      .admin
+      .as_ref()
+      .ok_or_else(without_admin)?
+      .restore(&request.operation_id)
+      .await
+      .map_err(|error| match error.inner() {
+        RedisQueueError::Conflict(message) => tonic::Status::already_exists(message.clone()),
+        _ => error.into(),
+      })?;
+    self.invalidate(&request.operation_id);
+    Ok(tonic::Response::new(operation))
+  }
+
+  async fn stream_operations(
+    &self,
+    request: tonic::Request<StreamOperationsRequest>,
+  ) -> Result<tonic::Response<Self::StreamOperationsStream>, tonic::Status> {
+    let events = self
+      .admin
+      .as_ref()
+      .ok_or_else(without_admin)?
+      .tail(request.into_inner())
+      .await?;
+    Ok(tonic::Response::new(Box::pin(events.map(Ok))))
+  }
+
+  async fn annotate_operation(
+    &self,
+    request: tonic::Request<AnnotateOperationRequest>,
+  ) -> Result<tonic::Response<Operation>, tonic::Status> {
+    let request = request.into_inner();
+    let operation = self
+      .admin
+      .as_ref()
+      .ok_or_else(without_admin)?
+      .annotate(&request.operation_id, &request.key, &request.value)
+      .await?;
+    self.invalidate(&request.operation_id);
+    Ok(tonic::Response::new(operation))
+  }
+
+  async fn get_operation_tree(
+    &self,
+    request: tonic::Request<GetOperationTreeRequest>,
+  ) -> Result<tonic::Response<OperationTree>, tonic::Status> {
+    let request = request.into_inner();
+    let max_depth = u32::try_from(request.max_depth)
+      .map_err(|_| tonic::Status::invalid_argument("max_depth must not be negative"))?;
+    let tree = self
+      .admin
+      .as_ref()
+      .ok_or_else(without_admin)?
+      .tree(&request.operation_id, max_depth)
+      .await?;
+    Ok(tonic::Response::new(tree))
+  }
+
+  async fn get_latency_summary(
+    &self,
+    request: tonic::Request<GetLatencySummaryRequest>,
+  ) -> Result<tonic::Response<LatencySummary>, tonic::Status> {
+    let request = request.into_inner();
+    let summary = self
+      .admin
+      .as_ref()
+      .ok_or_else(without_admin)?
+      .latency(&request.task_types)
+      .await?;
+    Ok(tonic::Response::new(summary))
+  }
+
+  async fn get_queue_metrics(
+    &self,
+    request: tonic::Request<GetQueueMetricsRequest>,
+  ) -> Result<tonic::Response<QueueMetrics>, tonic::Status> {
+    let request = request.into_inner();
+    let windows = request
+      .window_secs
+      .iter()
+      .map(|&secs| u64::try_from(secs).ok().filter(|&secs| secs > 0))
+      .map(|secs| secs.map(Duration::from_secs))
+      .collect::<Option<Vec<_>>>()
+      .ok_or_else(|| tonic::Status::invalid_argument("window_secs must be positive"))?;
+    let metrics = self
+      .admin
+      .as_ref()
+      .ok_or_else(without_admin)?
+      .queue_metrics(&request.queue, &windows)
+      .await?;
+    Ok(tonic::Response::new(metrics))
+  }
+
+  async fn list_task_schemas(
+    &self,
+    request: tonic::Request<ListTaskSchemasRequest>,
+  ) -> Result<tonic::Response<ListTaskSchemasResponse>, tonic::Status> {
+    let request = request.into_inner();
+    let schemas = self
+      .admin
+      .as_ref()
+      .ok_or_else(without_admin)?
+      .schemas(&request.task_types)
+      .await?;
+    Ok(tonic::Response::new(schemas))
+  }
+
+  async fn submit_task(
+    &self,
+    request: tonic::Request<SubmitTaskRequest>,
+  ) -> Result<tonic::Response<Operation>, tonic::Status> {
+    let caller = service::Context::from_request(&request)?;
+    let ctx = Context::new(caller.user_id().to_string(), self.system_id.clone())
+      .with_priority(caller.priority());
+    let request = request.into_inner();
+    let operation = self
+      .admin
+      .as_ref()
+      .ok_or_else(without_admin)?
+      .submit(
+        &request.task_type,
+        &request.queue,
+        &request.json_payload,
+        &ctx,
+      )
+      .await?;
+    Ok(tonic::Response::new(operation))
+  }
+}
+
+fn without_admin() -> tonic::Status {
+  tonic::Status::unimplemented("The operations are served without their queues")
+}
+
+fn not_found(id: &str) -> tonic::Status {
+  tonic::Status::not_found(format!("No operation with operation_id = {}", id))
+}
+
+/// Missing operations and invalid requests are the caller's fault rather than internal errors.
+impl From<RedisQueueError> for tonic::Status {
+  fn from(error: RedisQueueError) -> Self {
+    match error.inner() {
+      RedisQueueError::NotFound(message) => tonic::Status::not_found(message.clone()),
+      RedisQueueError::InvalidArgument(message) => tonic::Status::invalid_argument(message.clone()),
+      RedisQueueError::UnsupportedContentType(_) => {
+        tonic::Status::failed_precondition(error.to_string())
+      }
+      _ => BrokerError::QueueError(error).into(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+
+  use crate::proto::google::rpc::Status;
+  use crate::proto::longrunning::OperationState as ProtoOperationState;
+
+  use super::super::OperationFilter;
+  use super::*;
+
+  /// Keeps the operations in memory, as another backend than Redis would, and counts the reads.
+  #[derive(Clone, Debug, Default)]
+  struct MemoryStore {
+    operations: Arc<Mutex<HashMap<String, Operation>>>,
+    reads: Arc<AtomicUsize>,
+  }
+
+  impl MemoryStore {
+    fn insert(&self, operation: Operation) {
+      let mut operations = self.operations.lock().unwrap();
+      operations.insert(operation.operation_id.clone(), operation);
+    }
+  }
+
+  #[tonic::async_trait]
+  impl TaskStore for MemoryStore {
+    type Error = tonic::Status;
+
+    async fn get(&self, id: &str) -> Result<Option<Operation>, Self::Error> {
+      self.reads.fetch_add(1, Ordering::SeqCst);
+      Ok(self.operations.lock().unwrap().get(id).cloned())
+    }
+
+    async fn list(
+      &self,
+      _filter: &OperationFilter,
+      limit: usize,
+    ) -> Result<Vec<Operation>, Self::Error> {
+      let operations = self.operations.lock().unwrap();
+      Ok(operations.values().take(limit).cloned().collect())
+    }
+
+    async fn update_state(&self, id: &str, state: OperationState) -> Result<bool, Self::Error> {
+      let mut operations = self.operations.lock().unwrap();
+      match operations.get_mut(id) {
+        Some(operation) if !operation.done => {
+          operation.state = ProtoOperationState::from(state) as i32;
+          operation.done = state.is_done();
+          Ok(true)
+        }
+        _ => Ok(false),
+      }
+    }
+
+    async fn complete(
+      &self,
+      _id: &str,
+      _result: Result<Vec<u8>, Status>,
+    ) -> Result<bool, Self::Error> {
+      Err(tonic::Status::unimplemented("Not needed by the service"))
+    }
+  }
+
+  #[tokio::test]
+  async fn service_should_serve_the_operations_of_any_store() {
+    let store = MemoryStore::default();
+    let operation = Operation {
+      operation_id: "operation-1".to_string(),
+      state: ProtoOperationState::from(OperationState::Queued) as i32,
+      ..Default::default()
+    };
+    store.insert(operation.clone());
+    let service = OperationsService::new(store, "test");
+
+    let request = tonic::Request::new(GetOperationRequest {
+      operation_id: "operation-1".to_string(),
+    });
+    assert_eq!(service.get(request).await.unwrap().into_inner(), operation);
+
+    let request = tonic::Request::new(BatchGetOperationsRequest {
+      operation_ids: vec!["operation-1".to_string(), "missing-operation".to_string()],
+    });
+    let response = service
+      .batch_get_operations(request)
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(response.operations, vec![operation]);
+    assert_eq!(
+      response.missing_operation_ids,
+      vec!["missing-operation".to_string()]
+    );
+
+    let mut request = tonic::Request::new(CancelOperationRequest {
+      operation_id: "operation-1".to_string(),
+    });
+    request
+      .metadata_mut()
+      .insert("x-user-id", "1".parse().unwrap());
+    service.cancel(request).await.unwrap();
+    let cancelled = service.store.get("operation-1").await.unwrap().unwrap();
+    assert!(cancelled.done);
+    assert_eq!(cancelled.operation_state(), Some(OperationState::Cancelled));
+
+    let mut request = tonic::Request::new(CancelOperationRequest {
+      operation_id: "missing-operation".to_string(),
+    });
+    request
+      .metadata_mut()
+      .insert("x-user-id", "1".parse().unwrap());
+    let error = service.cancel(request).await.unwrap_err();
+    assert_eq!(error.code(), tonic::Code::NotFound);
+
+    let request = tonic::Request::new(GetOperationTreeRequest {
+      operation_id: "operation-1".to_string(),
+      max_depth: 1,
+    });
+    let error = service.get_operation_tree(request).await.unwrap_err();
+    assert_eq!(error.code(), tonic::Code::Unimplemented);
+  }
+
+  #[test]
+  fn status_should_keep_caller_errors() {
+    let error = RedisQueueError::NotFound("Operation 1 not found".to_string());
+    assert_eq!(tonic::Status::from(error).code(), tonic::Code::NotFound);
+
+    let error = RedisQueueError::InvalidArgument("Invalid payload".to_string());
+    assert_eq!(
+      tonic::Status::from(error).code(),
+      tonic::Code::InvalidArgument
+    );
+
+    let error = RedisQueueError::Internal("Invalid schema".to_string());
+    assert_eq!(tonic::Status::from(error).code(), tonic::Code::Internal);
+  }
+
+  #[tokio::test]
+  async fn get_should_be_answered_from_the_cache_until_the_state_changes() {
+    let store = MemoryStore::default();
+    store.insert(Operation {
+      operation_id: "operation-1".to_string(),
+      state: ProtoOperationState::from(OperationState::Queued) as i32,
+      ..Default::default()
+    });
+    let cache = OperationCache::new(store.clone()).live();
+    let service = OperationsService::new(store.clone(), "test").with_cache(cache);
+    let get = || {
+      service.get(tonic::Request::new(GetOperationRequest {
+        operation_id: "operation-1".to_string(),
+      }))
+    };
+
+    get().await.unwrap();
+    let operation = get().await.unwrap().into_inner();
+    assert_eq!(operation.operation_state(), Some(OperationState::Queued));
+    assert_eq!(store.reads.load(Ordering::SeqCst), 1);
+
+    let mut request = tonic::Request::new(CancelOperationRequest {
+      operation_id: "operation-1".to_string(),
+    });
+    request
+      .metadata_mut()
+      .insert("x-user-id", "1".parse().unwrap());
+    service.cancel(request).await.unwrap();
+
+    let reads = store.reads.load(Ordering::SeqCst);
+    let operation = get().await.unwrap().into_inner();
+    assert_eq!(operation.operation_state(), Some(OperationState::Cancelled));
+    assert_eq!(store.reads.load(Ordering::SeqCst), reads + 1);
+  }
+
+  #[tokio::test]
+  async fn get_should_fail_with_not_found_for_missing_operations() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let service = OperationsService::new(RedisTaskStore::new(client), "test");
+
+    let request = tonic::Request::new(GetOperationRequest {
+      operation_id: "missing-operation".to_string(),
+    });
+    let error = service.get(request).await.unwrap_err();
+    assert_eq!(error.code(), tonic::Code::NotFound);
+
+    let request = tonic::Request::new(BatchGetOperationsRequest {
+      operation_ids: vec!["missing-operation".to_string()],
+    });
+    let response = service.batch_get_operations(request).await.unwrap();
+    assert_eq!(
+      response.into_inner().missing_operation_ids,
+      vec!["missing-operation".to_string()]
+    );
+  }
+}
@@ -0,0 +1,97 @@
+//! Version skew between the producers enqueuing tasks and the workers performing them.
+//!
+//! Offers record the version of the producing application and the
+//! [`Performable::schema_version`](super::Performable::schema_version) of the task in the
+//! operation hash, as `producer_version` and `schema_version`. A worker pulling a task of a newer
+//! schema version than it supports logs a warning and counts it in the process wide [`metrics`],
+//! then performs it anyway unless the queue holds such tasks back, see
+//! [`super::redis::RedisQueue::with_holding_queue`].
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+/// Version of this crate, recorded as the application version unless another one is set.
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+static METRICS: SkewMetrics = SkewMetrics::new();
+
+/// Skewed tasks pulled by every queue of the process.
+pub fn metrics() -> &'static SkewMetrics {
+  &METRICS
+}
+
+/// Whether a task of `schema_version`, `None` when its producer did not record one, is newer than
+/// the `supported` version.
+pub fn is_newer(schema_version: Option<u32>, supported: u32) -> bool {
+  schema_version.is_some_and(|version| version > supported)
+}
+
+/// Tasks pulled with a newer schema version than their worker supports, rendered in the
+/// Prometheus text format.
+#[derive(Debug)]
+pub struct SkewMetrics {
+  skewed: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+impl SkewMetrics {
+  const fn new() -> Self {
+    Self {
+      skewed: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  pub(crate) fn record(&self, queue: &str, task_type: &str) {
+    let mut skewed = self.skewed.lock().unwrap_or_else(PoisonError::into_inner);
+    *skewed
+      .entry((queue.to_string(), task_type.to_string()))
+      .or_default() += 1;
+  }
+
+  /// Number of skewed tasks of `task_type` pulled from `queue`.
+  pub fn count(&self, queue: &str, task_type: &str) -> u64 {
+    self
+      .skewed
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .get(&(queue.to_string(), task_type.to_string()))
+      .copied()
+      .unwrap_or_default()
+  }
+
+  pub fn render(&self) -> String {
+    let mut out = String::default();
+
+    let _ = writeln!(out, "# TYPE rappel_version_skew_total counter");
+    let skewed = self.skewed.lock().unwrap_or_else(PoisonError::into_inner);
+    for ((queue, task_type), count) in skewed.iter() {
+      let _ = writeln!(
+        out,
+        "rappel_version_skew_total{{queue=\"{}\",task_type=\"{}\"}} {}",
+        queue, task_type, count
+      );
+    }
+
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn metrics_should_count_skewed_tasks() {
+    assert!(is_newer(Some(3), 2));
+    assert!(!is_newer(Some(2), 2));
+    assert!(!is_newer(None, 2));
+
+    metrics().record("skew", "backup");
+    metrics().record("skew", "backup");
+    assert_eq!(metrics().count("skew", "backup"), 2);
+    assert!(metrics()
+      .render()
+      .contains("rappel_version_skew_total{queue=\"skew\",task_type=\"backup\"} 2"));
+  }
+}
@@ -0,0 +1,214 @@
+//! Enqueue to completion latency of the operations, per task type.
+//!
+//! A [`SlaTracker`] attached to queues with [`super::redis::RedisQueue::with_sla`] records the
+//! latency of every completed operation twice: in the process, for metrics such as the runner's
+//! `/metrics`, and in Redis, where [`super::admin::RedisAdmin::latency`] summarizes it across
+//! every worker. Operations slower than the SLA threshold of their task type get the
+//! `sla_violated` metadata.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::proto::longrunning::TaskLatency;
+use crate::redis::Keys;
+
+/// Latencies kept per task type to compute the percentiles.
+pub const DEFAULT_SAMPLES: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct SlaTracker {
+  thresholds: HashMap<String, Duration>,
+  samples: usize,
+  recorded: Arc<Mutex<BTreeMap<String, Latencies>>>,
+}
+
+#[derive(Debug, Default)]
+struct Latencies {
+  latest: VecDeque<u64>,
+  count: u64,
+  violations: u64,
+}
+
+impl Default for SlaTracker {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl SlaTracker {
+  pub fn new() -> Self {
+    Self {
+      thresholds: HashMap::default(),
+      samples: DEFAULT_SAMPLES,
+      recorded: Arc::default(),
+    }
+  }
+
+  /// Marks the operations of `task_type` completed later than `threshold` after being enqueued.
+  pub fn with_threshold(mut self, task_type: &str, threshold: Duration) -> Self {
+    self.thresholds.insert(task_type.to_string(), threshold);
+    self
+  }
+
+  /// Latencies kept per task type to compute the percentiles, [`DEFAULT_SAMPLES`] by default.
+  pub fn with_samples(mut self, samples: usize) -> Self {
+    self.samples = samples.max(1);
+    self
+  }
+
+  pub fn threshold(&self, task_type: &str) -> Option<Duration> {
+    self.thresholds.get(task_type).copied()
+  }
+
+  /// Records the latency of the operation `id` and adds its Redis writes to `pipe`. Returns
+  /// whether the operation violated its SLA.
+  pub(crate) fn record(
+    &self,
+    pipe: &mut redis::Pipeline,
+    keys: &Keys,
+    id: &str,
+    task_type: &str,
+    latency: Duration,
+  ) -> bool {
+    let millis = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+    let threshold = self.threshold(task_type);
+    let violated = threshold.is_some_and(|threshold| latency > threshold);
+
+    {
+      let mut recorded = self.recorded.lock().unwrap();
+      let latencies = recorded.entry(task_type.to_string()).or_default();
+      if latencies.latest.len() == self.samples {
+        latencies.latest.pop_front();
+      }
+      latencies.latest.push_back(millis);
+      latencies.count += 1;
+      latencies.violations += u64::from(violated);
+    }
+
+    pipe
+      .lpush(keys.latency(task_type), millis)
+      .ignore()
+      .ltrim(keys.latency(task_type), 0, self.samples as isize - 1)
+      .ignore()
+      .hincr(keys.sla(task_type), "count", 1)
+      .ignore();
+
+    if let Some(threshold) = threshold {
+      pipe
+        .hset(
+          keys.sla(task_type),
+          "threshold_ms",
+          threshold.as_millis() as u64,
+        )
+        .ignore();
+    }
+
+    if violated {
+      pipe
+        .hset(keys.operation(id), "sla_violated", "true")
+        .ignore()
+        .hincr(keys.sla(task_type), "violations", 1)
+        .ignore();
+    }
+
+    violated
+  }
+
+  /// Latencies recorded by this process, by task type.
+  pub fn summaries(&self) -> Vec<TaskLatency> {
+    let recorded = self.recorded.lock().unwrap();
+
+    recorded
+      .iter()
+      .map(|(task_type, latencies)| TaskLatency {
+        sla_ms: self
+          .threshold(task_type)
+          .map(|threshold| threshold.as_millis() as i64)
+          .unwrap_or_default(),
+        sla_violations: latencies.violations as i64,
+        ..summarize(
+          task_type,
+          latencies.latest.iter().copied().collect(),
+          latencies.count,
+        )
+      })
+      .collect()
+  }
+}
+
+/// Nearest-rank `percent` percentile of the `sorted` samples, 0 when there are none.
+pub(crate) fn percentile(sorted: &[u64], percent: usize) -> i64 {
+  let rank = (sorted.len() * percent).div_ceil(100);
+  sorted
+    .get(rank.saturating_sub(1))
+    .map(|&millis| millis as i64)
+    .unwrap_or_default()
+}
+
+/// Computes the nearest-rank percentiles of `samples`, in milliseconds, out of `count`
+/// completions.
+pub fn summarize(task_type: &str, mut samples: Vec<u64>, count: u64) -> TaskLatency {
+  samples.sort_unstable();
+
+  TaskLatency {
+    task_type: task_type.to_string(),
+    count: count as i64,
+    p50_ms: percentile(&samples, 50),
+    p90_ms: percentile(&samples, 90),
+    p99_ms: percentile(&samples, 99),
+    max_ms: samples
+      .last()
+      .map(|&millis| millis as i64)
+      .unwrap_or_default(),
+    sla_ms: 0,
+    sla_violations: 0,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tracker_should_summarize_latest_samples() {
+    let sla = SlaTracker::new()
+      .with_threshold("backup", Duration::from_millis(90))
+      .with_samples(100);
+    let keys = Keys::default();
+    let mut pipe = redis::pipe();
+
+    let violations = (1..=150)
+      .filter(|&millis| {
+        sla.record(
+          &mut pipe,
+          &keys,
+          "1",
+          "backup",
+          Duration::from_millis(millis),
+        )
+      })
+      .count();
+    assert_eq!(violations, 60);
+
+    let summaries = sla.summaries();
+    assert_eq!(summaries.len(), 1);
+
+    let summary = &summaries[0];
+    assert_eq!(summary.count, 150);
+    assert_eq!(
+      (
+        summary.p50_ms,
+        summary.p90_ms,
+        summary.p99_ms,
+        summary.max_ms
+      ),
+      (100, 140, 149, 150)
+    );
+    assert_eq!((summary.sla_ms, summary.sla_violations), (90, 60));
+    assert_eq!(summarize("empty", Vec::default(), 0).p99_ms, 0);
+  }
+}
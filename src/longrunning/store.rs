@@ -0,0 +1,1202 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::Duration;
+
+use chrono::TimeZone;
+use chrono::Utc;
+use redis::AsyncCommands;
+use redis::FromRedisValue;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tracing_futures::Instrument;
+
+use prost::Message;
+
+use crate::proto::google::rpc::Status;
+use crate::proto::longrunning::Operation;
+use crate::quota;
+use crate::redis::Keys;
+use crate::redis::RedisRegistry;
+use crate::redis::RedisRole;
+use crate::util::clock;
+use crate::util::clock::SharedClock;
+use crate::util::redis_exec;
+use crate::util::redis_exec::InstrumentedConnection;
+
+use super::index;
+use super::redis::RedisQueueError;
+use super::OperationFilter;
+use super::OperationState;
+use super::TaskStore;
+
+/// Pending field updates keyed by operation id, later values of a field replace earlier ones.
+type Updates = HashMap<String, HashMap<String, Vec<u8>>>;
+
+/// Hash, children and annotations of a listed operation.
+type ListedKeys = (redis::Value, Vec<String>, HashMap<String, String>);
+
+/// Operations read per round trip when listing from the indexes or getting several at once.
+const READ_BATCH: usize = 100;
+
+/// Fields of the operation hash holding binary data, exported base64 encoded.
+const BINARY_FIELDS: &[&str] = &["task", "result", "error"];
+
+/// Times [`RedisTaskStore::modify`] reads an operation again after a concurrent write before
+/// giving up with [`RedisQueueError::Conflict`].
+const MAX_MODIFY_ATTEMPTS: usize = 8;
+
+/// Sets fields of an operation if its `version` is still the expected one, and bumps the
+/// version. Returns `{1, new version}`, `{0, current version}` if the operation changed
+/// meanwhile, or `{-1, 0}` if it does not exist.
+///
+/// KEYS: operation. ARGV: expected version, then field/value pairs.
+const COMPARE_AND_SET_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+  return {-1, 0}
+end
+local version = tonumber(redis.call('HGET', KEYS[1], 'version') or '0')
+if version ~= tonumber(ARGV[1]) then
+  return {0, version}
+end
+if #ARGV > 1 then
+  redis.call('HSET', KEYS[1], unpack(ARGV, 2))
+end
+return {1, redis.call('HINCRBY', KEYS[1], 'version', 1)}
+";
+
+/// Completes an operation unless it is already done, e.g. cancelled while it was performed:
+/// sets `done`, `status`, `end_ts` and the result or error field, and bumps the version. Takes the
+/// operation off the ack list given, if any, either way. Returns the queue of the operation, or
+/// nil if it was already done or does not exist.
+///
+/// KEYS: operation, optional ack list. ARGV: status, end timestamp, `result` or `error`, its
+/// value, then the operation id with an ack list.
+pub(crate) const COMPLETE_SCRIPT: &str = r"
+if #KEYS > 1 then
+  redis.call('LREM', KEYS[2], 1, ARGV[5])
+end
+if redis.call('EXISTS', KEYS[1]) == 0 or redis.call('HGET', KEYS[1], 'done') == 'true' then
+  return false
+end
+redis.call('HSET', KEYS[1], 'done', 'true', 'status', ARGV[1], 'end_ts', ARGV[2], ARGV[3], ARGV[4])
+redis.call('HINCRBY', KEYS[1], 'version', 1)
+return redis.call('HGET', KEYS[1], 'queue') or ''
+";
+
+/// Sets or removes an annotation of an existing operation. Returns 0 if the operation does not
+/// exist.
+///
+/// KEYS: operation, annotations. ARGV: annotation key, value, empty to remove it.
+const ANNOTATE_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+  return 0
+end
+if ARGV[2] == '' then
+  redis.call('HDEL', KEYS[2], ARGV[1])
+else
+  redis.call('HSET', KEYS[2], ARGV[1], ARGV[2])
+end
+return 1
+";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+  #[error("{0}")]
+  Queue(#[from] RedisQueueError),
+
+  #[error("Redis command failed: {0}")]
+  Redis(#[from] redis::RedisError),
+
+  #[error("Failed to encode the operation: {0}")]
+  Json(#[from] serde_json::Error),
+
+  #[error("Failed to write the export: {0}")]
+  Io(#[from] std::io::Error),
+}
+
+/// Hash, children and annotations of an exported operation.
+type ExportedKeys = (
+  HashMap<String, Vec<u8>>,
+  Vec<String>,
+  BTreeMap<String, String>,
+);
+
+/// Line of an [`RedisTaskStore::export`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedOperation {
+  pub operation_id: String,
+  /// Text fields of the operation hash, e.g. `queue`, `status` or `publish_ts`.
+  pub fields: BTreeMap<String, String>,
+  /// Binary fields, base64 encoded: the `task` payload, the encoded `result` and the
+  /// `google.rpc.Status` `error`.
+  pub binary_fields: BTreeMap<String, String>,
+  pub child_operation_ids: Vec<String>,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub annotations: BTreeMap<String, String>,
+}
+
+/// Reads and writes the operation hashes.
+///
+/// Every write bumps the `version` field of the hash, so that writers racing from several
+/// replicas can update an operation with [`RedisTaskStore::compare_and_update`] or
+/// [`RedisTaskStore::modify`] only if nothing changed it since they read it.
+///
+/// With [`RedisTaskStore::with_write_behind`], [`RedisTaskStore::update`] only buffers the fields
+/// and a background task writes the updates of every operation in one pipeline per interval.
+/// Buffered fields become visible up to an interval later and are lost if the process exits
+/// before they are flushed, so only metadata that nothing waits on should be written this way.
+///
+/// With [`RedisTaskStore::with_replica`], [`RedisTaskStore::get`], [`TaskStore::list`],
+/// [`TaskStore::history`] and [`RedisTaskStore::export`] read from a replica and may miss the
+/// latest writes. Writes, and the reads they are based on, always go to the primary.
+#[derive(Clone, Debug)]
+pub struct RedisTaskStore {
+  client: redis::Client,
+  replica: Option<redis::Client>,
+  indexed_lists: bool,
+  keys: Keys,
+  write_behind: Option<WriteBehind>,
+  clock: SharedClock,
+}
+
+#[derive(Clone, Debug)]
+struct WriteBehind {
+  pending: Arc<Mutex<Updates>>,
+  flush: Arc<Notify>,
+  max_pending: usize,
+}
+
+impl RedisTaskStore {
+  pub fn new(client: redis::Client) -> Self {
+    Self {
+      client,
+      replica: None,
+      indexed_lists: false,
+      keys: Keys::default(),
+      write_behind: None,
+      clock: clock::system(),
+    }
+  }
+
+  /// Uses the [`RedisRole::Queue`] connection of `registry` for the writes and the
+  /// [`RedisRole::Replica`] one for the reads.
+  pub fn from_registry(registry: &RedisRegistry) -> Self {
+    Self::new(registry.client(RedisRole::Queue))
+      .with_keys(registry.keys().clone())
+      .with_replica(registry.client(RedisRole::Replica))
+  }
+
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Reads the operations from `replica`, to keep the primary focused on the queues.
+  pub fn with_replica(mut self, replica: redis::Client) -> Self {
+    self.replica = Some(replica);
+    self
+  }
+
+  /// Lists the operations of a user or of queues from their daily indexes rather than by scanning
+  /// the store, see [`super::index`]. Operations enqueued before the indexes were written, or
+  /// longer than [`super::index::DEFAULT_INDEX_RETENTION`] ago, are not listed then.
+  pub fn with_indexed_lists(mut self) -> Self {
+    self.indexed_lists = true;
+    self
+  }
+
+  /// Client of the reads that tolerate replication lag.
+  fn reader(&self) -> &redis::Client {
+    self.replica.as_ref().unwrap_or(&self.client)
+  }
+
+  /// Sets the clock of the `end_ts` of [`Self::complete_many`].
+  pub fn with_clock(mut self, clock: SharedClock) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  /// Buffers [`Self::update`]s and flushes them every `interval`, or as soon as `max_pending`
+  /// operations have pending updates. The flushing task stops once every clone of the store is
+  /// dropped. Must be called within a Tokio runtime.
+  pub fn with_write_behind(mut self, interval: Duration, max_pending: usize) -> Self {
+    let write_behind = WriteBehind {
+      pending: Arc::default(),
+      flush: Arc::default(),
+      max_pending: max_pending.max(1),
+    };
+
+    tokio::spawn(flush_periodically(
+      self.client.clone(),
+      self.keys.clone(),
+      Arc::downgrade(&write_behind.pending),
+      write_behind.flush.clone(),
+      interval,
+    ));
+
+    self.write_behind = Some(write_behind);
+    self
+  }
+
+  pub async fn get(&self, id: &str) -> Result<Option<Operation>, RedisQueueError> {
+    let mut conn = redis_exec::connect(self.reader()).await?;
+
+    let (value, children, annotations): (redis::Value, Vec<String>, HashMap<String, String>) =
+      redis::pipe()
+        .cmd("HGETALL")
+        .arg(self.keys.operation(id))
+        .cmd("LRANGE")
+        .arg(self.keys.children(id))
+        .arg(0)
+        .arg(-1)
+        .cmd("HGETALL")
+        .arg(self.keys.annotations(id))
+        .query_async(&mut conn)
+        .instrument(tracing::info_span!("redis-store-get", operation_id = %id))
+        .await?;
+
+    match value {
+      redis::Value::Bulk(fields) if fields.is_empty() => Ok(None),
+      value => {
+        let mut operation = Operation::from_redis_value(&value)?;
+        operation.child_operation_ids = children;
+        operation.annotations = annotations;
+        Ok(Some(operation))
+      }
+    }
+  }
+
+  /// Reads the operations `ids` in pipelined round trips, `None` for the ones that do not exist,
+  /// in the order of `ids`.
+  pub async fn get_many(&self, ids: &[String]) -> Result<Vec<Option<Operation>>, RedisQueueError> {
+    let mut conn = redis_exec::connect(self.reader()).await?;
+    get_many(&mut conn, &self.keys, ids).await
+  }
+
+  /// Sets the annotation `key` of the operation `id` to `value`, or removes it when `value` is
+  /// empty. Annotations do not change the version of the operation.
+  pub async fn annotate(&self, id: &str, key: &str, value: &str) -> Result<(), RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    annotate(&mut conn, &self.keys, id, key, value).await
+  }
+
+  /// Sets `fields` of the operation `id` only if it is still at `version`, see [`version`], and
+  /// returns its new version. Fails with [`RedisQueueError::Conflict`] if another writer changed
+  /// the operation meanwhile. Never buffered, even when write-behind is enabled.
+  pub async fn compare_and_update<V: AsRef<[u8]>>(
+    &self,
+    id: &str,
+    version: u64,
+    fields: &[(&str, V)],
+  ) -> Result<u64, RedisQueueError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    compare_and_set(&mut conn, &self.keys, id, version, fields).await
+  }
+
+  /// Reads the operation `id` and sets the fields returned by `f`, reading it again and calling
+  /// `f` anew whenever another writer changed the operation in between. `f` returns `None` to
+  /// leave the operation as it is, e.g. once it is done. Returns the new version, if any.
+  pub async fn modify<F>(&self, id: &str, f: F) -> Result<Option<u64>, RedisQueueError>
+  where
+    F: FnMut(&Operation) -> Option<Vec<(String, Vec<u8>)>>,
+  {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    modify(&mut conn, &self.keys, id, f).await
+  }
+
+  /// Sets `fields` of the operation `id`, buffered when write-behind is enabled.
+  pub async fn update<V: AsRef<[u8]>>(
+    &self,
+    id: &str,
+    fields: &[(&str, V)],
+  ) -> Result<(), RedisQueueError> {
+    let write_behind = match &self.write_behind {
+      None => {
+        let mut conn = redis_exec::connect(&self.client).await?;
+        let updates = HashMap::from([(id.to_string(), to_owned(fields))]);
+        return write(&mut conn, &self.keys, updates).await;
+      }
+      Some(write_behind) => write_behind,
+    };
+
+    let full = {
+      let mut pending = write_behind.pending.lock().unwrap();
+      pending
+        .entry(id.to_string())
+        .or_default()
+        .extend(to_owned(fields));
+      pending.len() >= write_behind.max_pending
+    };
+
+    if full {
+      write_behind.flush.notify_one();
+    }
+
+    Ok(())
+  }
+
+  /// Records the results of many operations in two round trips, whatever their number, e.g. for
+  /// workers completing a batch of operations at once. The operations are removed from the
+  /// in-flight list of their queue and their quota is released, but unlike
+  /// [`super::redis::RedisQueue::complete`] no event is published and no cost or SLA is
+  /// recorded. Operations that do not exist or are done already are skipped. Returns the ids of
+  /// the completed operations.
+  pub async fn complete_many<I>(&self, results: I) -> Result<Vec<String>, RedisQueueError>
+  where
+    I: IntoIterator<Item = (String, Result<Vec<u8>, Status>)>,
+  {
+    let results: Vec<_> = results.into_iter().collect();
+    if results.is_empty() {
+      return Ok(Vec::default());
+    }
+
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let end_ts = self.clock.timestamp_nanos();
+    let mut pipe = redis::pipe();
+    let mut ids = Vec::default();
+    for (id, result) in results {
+      complete_in(&mut pipe, &self.keys, &id, result, end_ts);
+      ids.push(id);
+    }
+
+    // Each operation is completed by a script, so an operation cancelled or deleted meanwhile is
+    // left as it is.
+    let queues: Vec<Option<String>> = pipe
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!(
+        "redis-store-complete",
+        operations = ids.len()
+      ))
+      .await?;
+
+    let mut pipe = redis::pipe();
+    let mut completed = Vec::default();
+    for (id, queue) in ids.into_iter().zip(queues) {
+      let queue = match queue {
+        Some(queue) => queue,
+        None => continue,
+      };
+
+      pipe.lrem(self.keys.ack(&queue), 1, &id).ignore();
+      quota::release_operation_in(&mut pipe, &self.keys, &id);
+      completed.push(id);
+    }
+
+    if !completed.is_empty() {
+      let _: () = pipe
+        .query_async(&mut conn)
+        .instrument(tracing::info_span!(
+          "redis-store-complete-release",
+          operations = completed.len()
+        ))
+        .await?;
+    }
+
+    Ok(completed)
+  }
+
+  /// Writes the operations matching `filter` to `writer` as newline-delimited JSON, one
+  /// [`ExportedOperation`] per line, and returns how many were written. Operations are read one
+  /// at a time while scanning, so the export is not a consistent snapshot of a changing store.
+  pub async fn export<W: AsyncWrite + Unpin>(
+    &self,
+    filter: &OperationFilter,
+    writer: &mut W,
+  ) -> Result<u64, ExportError> {
+    let mut scan_conn = redis_exec::connect(self.reader()).await?;
+    let mut conn = redis_exec::connect(self.reader()).await?;
+    let mut exported = 0;
+
+    let mut keys = scan_conn
+      .scan_match::<_, String>(self.keys.operation("*"))
+      .instrument(tracing::info_span!("redis-store-scan"))
+      .await?;
+
+    while let Some(key) = keys.next_item().await {
+      let id = match self.operation_id(&key) {
+        Some(id) => id.to_string(),
+        None => continue,
+      };
+
+      let (hash, child_operation_ids, annotations): ExportedKeys = redis::pipe()
+        .hgetall(&key)
+        .lrange(self.keys.children(&id), 0, -1)
+        .hgetall(self.keys.annotations(&id))
+        .query_async(&mut conn)
+        .instrument(tracing::info_span!("redis-store-export", operation_id = %id))
+        .await?;
+
+      let mut operation = ExportedOperation {
+        operation_id: id,
+        child_operation_ids,
+        annotations,
+        ..Default::default()
+      };
+
+      for (field, value) in hash {
+        if BINARY_FIELDS.contains(&field.as_str()) {
+          operation.binary_fields.insert(field, base64::encode(value));
+          continue;
+        }
+
+        match String::from_utf8(value) {
+          Ok(value) => operation.fields.insert(field, value),
+          Err(error) => operation
+            .binary_fields
+            .insert(field, base64::encode(error.into_bytes())),
+        };
+      }
+
+      // Deleted while scanning.
+      if operation.fields.is_empty() && operation.binary_fields.is_empty() {
+        continue;
+      }
+
+      if !filter.matches(&operation.fields) {
+        continue;
+      }
+
+      let mut line = serde_json::to_vec(&operation)?;
+      line.push(b'\n');
+      writer.write_all(&line).await?;
+      exported += 1;
+    }
+
+    writer.flush().await?;
+    Ok(exported)
+  }
+
+  /// Lists from the daily indexes of the user or queues of `filter`, newest first. `None` when
+  /// the filter selects neither.
+  async fn list_indexed(
+    &self,
+    filter: &OperationFilter,
+    limit: usize,
+  ) -> Result<Option<Vec<Operation>>, RedisQueueError> {
+    let now = self.clock.now();
+    let until = filter
+      .until_ns
+      .map(|ns| Utc.timestamp_nanos(ns))
+      .unwrap_or_else(|| now + chrono::Duration::days(1));
+    let since = filter
+      .since_ns
+      .map(|ns| Utc.timestamp_nanos(ns))
+      .unwrap_or_else(|| {
+        now - chrono::Duration::from_std(index::DEFAULT_INDEX_RETENTION).unwrap_or_default()
+      });
+
+    let mut conn = redis_exec::connect(self.reader()).await?;
+    let mut operations = Vec::default();
+
+    for day in index::days(since, until) {
+      let partitions = match index::partitions(&self.keys, filter, day) {
+        Some(partitions) => partitions,
+        None => return Ok(None),
+      };
+      let ids = index::read(
+        &mut conn,
+        &partitions,
+        since.timestamp_millis(),
+        until.timestamp_millis(),
+        None,
+      )
+      .await?;
+
+      for ids in ids.chunks(READ_BATCH) {
+        for read in read_many(&mut conn, &self.keys, ids).await? {
+          operations.extend(matching(read, filter)?);
+          if operations.len() >= limit {
+            operations.truncate(limit);
+            return Ok(Some(operations));
+          }
+        }
+      }
+    }
+
+    Ok(Some(operations))
+  }
+
+  /// Returns the id of the operation of an operation hash key, `None` for the other keys under
+  /// `operation:`, e.g. the children lists.
+  fn operation_id<'a>(&self, key: &'a str) -> Option<&'a str> {
+    self
+      .keys
+      .strip(key)
+      .and_then(|key| key.strip_prefix("operation:"))
+      .filter(|id| !id.contains(':'))
+  }
+
+  /// Writes the buffered updates now, e.g. before shutting down.
+  pub async fn flush(&self) -> Result<(), RedisQueueError> {
+    let updates = match &self.write_behind {
+      Some(write_behind) => std::mem::take(&mut *write_behind.pending.lock().unwrap()),
+      None => return Ok(()),
+    };
+
+    let mut conn = redis_exec::connect(&self.client).await?;
+    write(&mut conn, &self.keys, updates).await
+  }
+}
+
+/// Writes bypass the write-behind buffer, as callers rely on them.
+#[async_trait::async_trait]
+impl TaskStore for RedisTaskStore {
+  type Error = RedisQueueError;
+
+  async fn get(&self, id: &str) -> Result<Option<Operation>, Self::Error> {
+    RedisTaskStore::get(self, id).await
+  }
+
+  async fn get_many(&self, ids: &[String]) -> Result<Vec<Option<Operation>>, Self::Error> {
+    RedisTaskStore::get_many(self, ids).await
+  }
+
+  /// Reads the status alone, from the primary so that a cancellation is noticed at once.
+  async fn state(&self, id: &str) -> Result<Option<OperationState>, Self::Error> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let status: Option<String> = conn
+      .hget(self.keys.operation(id), "status")
+      .instrument(tracing::info_span!("redis-store-state", operation_id = %id))
+      .await?;
+
+    Ok(status.and_then(|status| status.parse().ok()))
+  }
+
+  /// Scans the whole store, one operation at a time, until `limit` operations matched, unless
+  /// the filter selects a user or queues and lists are indexed, see [`Self::with_indexed_lists`].
+  async fn list(
+    &self,
+    filter: &OperationFilter,
+    limit: usize,
+  ) -> Result<Vec<Operation>, Self::Error> {
+    if self.indexed_lists {
+      if let Some(operations) = self.list_indexed(filter, limit).await? {
+        return Ok(operations);
+      }
+    }
+
+    let mut scan_conn = redis_exec::connect(self.reader()).await?;
+    let mut conn = redis_exec::connect(self.reader()).await?;
+    let mut operations = Vec::default();
+
+    let mut keys = scan_conn
+      .scan_match::<_, String>(self.keys.operation("*"))
+      .instrument(tracing::info_span!("redis-store-scan"))
+      .await?;
+
+    while let Some(key) = keys.next_item().await {
+      if operations.len() >= limit {
+        break;
+      }
+
+      let id = match self.operation_id(&key) {
+        Some(id) => id.to_string(),
+        None => continue,
+      };
+
+      let read: ListedKeys = redis::pipe()
+        .hgetall(&key)
+        .lrange(self.keys.children(&id), 0, -1)
+        .hgetall(self.keys.annotations(&id))
+        .query_async(&mut conn)
+        .instrument(tracing::info_span!("redis-store-list", operation_id = %id))
+        .await?;
+
+      operations.extend(matching(read, filter)?);
+    }
+
+    Ok(operations)
+  }
+
+  async fn update_state(&self, id: &str, state: OperationState) -> Result<bool, Self::Error> {
+    let version = self
+      .modify(id, |operation| {
+        (!operation.done).then(|| {
+          vec![
+            ("status".to_string(), state.as_str().as_bytes().to_vec()),
+            ("done".to_string(), state.is_done().to_string().into_bytes()),
+          ]
+        })
+      })
+      .await?;
+
+    Ok(version.is_some())
+  }
+
+  async fn complete(&self, id: &str, result: Result<Vec<u8>, Status>) -> Result<bool, Self::Error> {
+    let completed = self.complete_many([(id.to_string(), result)]).await?;
+    Ok(!completed.is_empty())
+  }
+}
+
+/// Version of an operation read from the store, bumped by every write to its hash.
+pub fn version(operation: &Operation) -> u64 {
+  operation.version
+}
+
+/// The operation read with its children and annotations if it exists and matches `filter`.
+fn matching(
+  (value, children, annotations): ListedKeys,
+  filter: &OperationFilter,
+) -> Result<Option<Operation>, RedisQueueError> {
+  let fields: BTreeMap<String, String> = match &value {
+    redis::Value::Bulk(values) if values.is_empty() => return Ok(None),
+    value => HashMap::<String, Vec<u8>>::from_redis_value(value)?
+      .into_iter()
+      .filter_map(|(field, value)| Some((field, String::from_utf8(value).ok()?)))
+      .collect(),
+  };
+
+  if !filter.matches(&fields) {
+    return Ok(None);
+  }
+
+  let mut operation = Operation::from_redis_value(&value)?;
+  operation.child_operation_ids = children;
+  operation.annotations = annotations;
+  Ok(Some(operation))
+}
+
+/// Reads the hash, children and annotations of the operations `ids` in one round trip.
+/// See [`RedisTaskStore::get_many`].
+pub(crate) async fn get_many(
+  conn: &mut InstrumentedConnection,
+  keys: &Keys,
+  ids: &[String],
+) -> Result<Vec<Option<Operation>>, RedisQueueError> {
+  let mut operations = Vec::with_capacity(ids.len());
+
+  for ids in ids.chunks(READ_BATCH) {
+    for read in read_many(conn, keys, ids).await? {
+      operations.push(matching(read, &OperationFilter::default())?);
+    }
+  }
+
+  Ok(operations)
+}
+
+async fn read_many(
+  conn: &mut InstrumentedConnection,
+  keys: &Keys,
+  ids: &[String],
+) -> Result<Vec<ListedKeys>, RedisQueueError> {
+  let mut pipe = redis::pipe();
+  for id in ids {
+    pipe
+      .hgetall(keys.operation(id))
+      .lrange(keys.children(id), 0, -1)
+      .hgetall(keys.annotations(id));
+  }
+
+  let read: Vec<redis::Value> = pipe
+    .query_async(conn)
+    .instrument(tracing::info_span!(
+      "redis-store-read-many",
+      operations = ids.len()
+    ))
+    .await?;
+
+  read
+    .chunks(3)
+    .filter_map(|read| match read {
+      [value, children, annotations] => Some((value, children, annotations)),
+      _ => None,
+    })
+    .map(|(value, children, annotations)| {
+      Ok((
+        value.clone(),
+        Vec::from_redis_value(children)?,
+        HashMap::from_redis_value(annotations)?,
+      ))
+    })
+    .collect()
+}
+
+/// See [`RedisTaskStore::compare_and_update`].
+pub(crate) async fn compare_and_set<V: AsRef<[u8]>>(
+  conn: &mut InstrumentedConnection,
+  keys: &Keys,
+  id: &str,
+  version: u64,
+  fields: &[(&str, V)],
+) -> Result<u64, RedisQueueError> {
+  let script = redis::Script::new(COMPARE_AND_SET_SCRIPT);
+  let mut invocation = script.key(keys.operation(id));
+  invocation.arg(version);
+  for (name, value) in fields {
+    invocation.arg(*name).arg(value.as_ref());
+  }
+
+  let (outcome, current): (i64, u64) = invocation
+    .invoke_async(conn)
+    .instrument(tracing::info_span!("redis-store-compare-and-set", operation_id = %id))
+    .await?;
+
+  match outcome {
+    1 => Ok(current),
+    0 => Err(RedisQueueError::Conflict(format!(
+      "Operation {} is at version {}, not {}",
+      id, current, version
+    ))),
+    _ => Err(RedisQueueError::NotFound(format!(
+      "No operation with operation_id = {}",
+      id
+    ))),
+  }
+}
+
+/// See [`RedisTaskStore::annotate`].
+pub(crate) async fn annotate(
+  conn: &mut InstrumentedConnection,
+  keys: &Keys,
+  id: &str,
+  key: &str,
+  value: &str,
+) -> Result<(), RedisQueueError> {
+  if key.is_empty() {
+    return Err(RedisQueueError::InvalidArgument(
+      "Empty annotation key".to_string(),
+    ));
+  }
+
+  let annotated: i64 = redis::Script::new(ANNOTATE_SCRIPT)
+    .key(keys.operation(id))
+    .key(keys.annotations(id))
+    .arg(key)
+    .arg(value)
+    .invoke_async(conn)
+    .instrument(tracing::info_span!("redis-store-annotate", operation_id = %id))
+    .await?;
+
+  if annotated == 0 {
+    return Err(RedisQueueError::NotFound(format!(
+      "No operation with operation_id = {}",
+      id
+    )));
+  }
+
+  Ok(())
+}
+
+/// See [`RedisTaskStore::modify`].
+pub(crate) async fn modify<F>(
+  conn: &mut InstrumentedConnection,
+  keys: &Keys,
+  id: &str,
+  mut f: F,
+) -> Result<Option<u64>, RedisQueueError>
+where
+  F: FnMut(&Operation) -> Option<Vec<(String, Vec<u8>)>>,
+{
+  let mut attempts = 0;
+
+  loop {
+    let value: redis::Value = conn
+      .hgetall(keys.operation(id))
+      .instrument(tracing::info_span!("redis-store-modify-read", operation_id = %id))
+      .await?;
+
+    if value == redis::Value::Bulk(Vec::default()) {
+      return Err(RedisQueueError::NotFound(format!(
+        "No operation with operation_id = {}",
+        id
+      )));
+    }
+
+    let operation = Operation::from_redis_value(&value)?;
+    let fields = match f(&operation) {
+      Some(fields) => fields,
+      None => return Ok(None),
+    };
+    let fields: Vec<(&str, &[u8])> = fields
+      .iter()
+      .map(|(name, value)| (name.as_str(), value.as_slice()))
+      .collect();
+
+    attempts += 1;
+    match compare_and_set(conn, keys, id, version(&operation), &fields).await {
+      Err(RedisQueueError::Conflict(_)) if attempts < MAX_MODIFY_ATTEMPTS => {
+        tracing::debug!(message = "Operation changed concurrently, retrying", operation_id = %id);
+      }
+      result => return result.map(Some),
+    }
+  }
+}
+
+fn to_owned<V: AsRef<[u8]>>(fields: &[(&str, V)]) -> HashMap<String, Vec<u8>> {
+  fields
+    .iter()
+    .map(|(name, value)| (name.to_string(), value.as_ref().to_vec()))
+    .collect()
+}
+
+async fn write(
+  conn: &mut InstrumentedConnection,
+  keys: &Keys,
+  updates: Updates,
+) -> Result<(), RedisQueueError> {
+  if updates.is_empty() {
+    return Ok(());
+  }
+
+  let operations = updates.len();
+  let mut pipe = redis::pipe();
+
+  for (id, fields) in updates {
+    let fields: Vec<(String, Vec<u8>)> = fields.into_iter().collect();
+    pipe
+      .hset_multiple(keys.operation(&id), &fields)
+      .ignore()
+      .hincr(keys.operation(&id), "version", 1)
+      .ignore();
+  }
+
+  let _: () = pipe
+    .query_async(conn)
+    .instrument(tracing::info_span!("redis-store-write", operations))
+    .await?;
+
+  Ok(())
+}
+
+async fn flush_periodically(
+  client: redis::Client,
+  keys: Keys,
+  pending: Weak<Mutex<Updates>>,
+  flush: Arc<Notify>,
+  interval: Duration,
+) {
+  let mut ticks = tokio::time::interval(interval);
+
+  loop {
+    tokio::select! {
+      _ = ticks.tick() => {}
+      _ = flush.notified() => {}
+    }
+
+    let updates = match pending.upgrade() {
+      Some(pending) => std::mem::take(&mut *pending.lock().unwrap()),
+      None => return,
+    };
+
+    if updates.is_empty() {
+      continue;
+    }
+
+    let result = match redis_exec::connect(&client).await {
+      Ok(mut conn) => write(&mut conn, &keys, updates).await,
+      Err(error) => Err(error.into()),
+    };
+
+    if let Err(error) = result {
+      tracing::warn!(message = "Failed to write buffered operation updates", %error);
+    }
+  }
+}
+
+/// Adds the completion of the operation `id` with `result` by [`COMPLETE_SCRIPT`] to `pipe`,
+/// replying its queue, or nil if it was already done.
+pub(crate) fn complete_in(
+  pipe: &mut redis::Pipeline,
+  keys: &Keys,
+  id: &str,
+  result: Result<Vec<u8>, Status>,
+  end_ts: i64,
+) {
+  let (state, field, value) = match result {
+    Ok(output) => (OperationState::Succeeded, "result", output),
+    Err(status) => (OperationState::Failed, "error", status.encode_to_vec()),
+  };
+
+  pipe
+    .cmd("EVAL")
+    .arg(COMPLETE_SCRIPT)
+    .arg(1)
+    .arg(keys.operation(id))
+    .arg(state.as_str())
+    .arg(end_ts)
+    .arg(field)
+    .arg(value);
+}
+
+#[cfg(test)]
+mod tests {
+  use redis::AsyncCommands;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn write_behind_should_coalesce_updates() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let store = RedisTaskStore::new(client.clone()).with_write_behind(Duration::from_secs(60), 100);
+    let id = uuid::Uuid::new_v4().to_string();
+
+    store.update(&id, &[("ack_user_id", "a")]).await.unwrap();
+    store
+      .update(&id, &[("ack_user_id", "b"), ("ack_system_id", "worker")])
+      .await
+      .unwrap();
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let exists: bool = conn.exists(format!("operation:{}", id)).await.unwrap();
+    assert!(!exists);
+
+    store.flush().await.unwrap();
+
+    let fields: HashMap<String, String> = conn.hgetall(format!("operation:{}", id)).await.unwrap();
+    assert_eq!(fields["ack_user_id"], "b");
+    assert_eq!(fields["ack_system_id"], "worker");
+  }
+
+  #[tokio::test]
+  async fn indexed_lists_should_merge_the_daily_partitions() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("test:{}:", uuid::Uuid::new_v4()));
+    let store = RedisTaskStore::new(client.clone())
+      .with_keys(keys.clone())
+      .with_indexed_lists();
+    let now = Utc::now();
+
+    let mut pipe = redis::pipe();
+    for (id, queue, days_ago) in [
+      ("a", "backups", 2),
+      ("b", "restores", 1),
+      ("c", "backups", 0),
+      ("d", "other", 0),
+    ] {
+      let published = now - chrono::Duration::days(days_ago);
+      store
+        .update(
+          id,
+          &[("operation_id", id), ("queue", queue), ("user_id", "42")],
+        )
+        .await
+        .unwrap();
+      index::record(&mut pipe, &keys, id, queue, "42", published);
+    }
+    let mut conn = client.get_async_connection().await.unwrap();
+    let _: () = pipe.query_async(&mut conn).await.unwrap();
+
+    let listed = |operations: Vec<Operation>| {
+      operations
+        .into_iter()
+        .map(|operation| operation.operation_id)
+        .collect::<Vec<_>>()
+    };
+    let filter = OperationFilter {
+      queues: vec!["backups".to_string(), "restores".to_string()],
+      ..Default::default()
+    };
+    assert_eq!(
+      listed(store.list(&filter, 10).await.unwrap()),
+      vec!["c", "b", "a"]
+    );
+    assert_eq!(
+      listed(store.list(&filter, 2).await.unwrap()),
+      vec!["c", "b"]
+    );
+
+    let filter = OperationFilter {
+      user_id: Some("42".to_string()),
+      since_ns: (now - chrono::Duration::hours(36)).timestamp_nanos_opt(),
+      ..Default::default()
+    };
+    assert_eq!(
+      listed(store.list(&filter, 10).await.unwrap()),
+      vec!["d", "c", "b"]
+    );
+  }
+
+  #[tokio::test]
+  async fn get_many_should_read_the_operations_in_order() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let store =
+      RedisTaskStore::new(client).with_keys(Keys::new(&format!("{}:", uuid::Uuid::new_v4())));
+    let ids: Vec<String> = (0..READ_BATCH + 2).map(|i| format!("op-{}", i)).collect();
+    for id in ids.iter().step_by(2) {
+      store
+        .update(id, &[("operation_id", id.as_str()), ("status", "Queued")])
+        .await
+        .unwrap();
+    }
+    store.annotate("op-0", "incident", "INC-1").await.unwrap();
+
+    let operations = store.get_many(&ids).await.unwrap();
+    assert_eq!(operations.len(), ids.len());
+    for (i, operation) in operations.iter().enumerate() {
+      assert_eq!(
+        operation
+          .as_ref()
+          .map(|operation| operation.operation_id.as_str()),
+        (i % 2 == 0).then(|| ids[i].as_str())
+      );
+    }
+    assert_eq!(
+      operations[0].as_ref().unwrap().annotations["incident"],
+      "INC-1"
+    );
+  }
+
+  #[tokio::test]
+  async fn replica_should_serve_reads_only() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let unreachable = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let store = RedisTaskStore::new(client.clone()).with_replica(unreachable);
+    let id = uuid::Uuid::new_v4().to_string();
+
+    store.update(&id, &[("status", "Running")]).await.unwrap();
+    assert_eq!(
+      store
+        .modify(&id, |_| Some(vec![(
+          "status".to_string(),
+          b"Cancelled".to_vec()
+        )]))
+        .await
+        .unwrap(),
+      Some(2)
+    );
+    assert!(store.get(&id).await.is_err());
+    assert!(TaskStore::history(&store, &id).await.is_err());
+
+    let operation = RedisTaskStore::new(client).get(&id).await.unwrap().unwrap();
+    assert_eq!(operation.state_name(), "Cancelled");
+  }
+
+  #[tokio::test]
+  async fn compare_and_update_should_reject_stale_versions() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let store = RedisTaskStore::new(client);
+    let id = uuid::Uuid::new_v4().to_string();
+
+    store.update(&id, &[("status", "Running")]).await.unwrap();
+    let operation = store.get(&id).await.unwrap().unwrap();
+    assert_eq!(version(&operation), 1);
+
+    // A heartbeat lands between the read and the write of a cancellation.
+    store.update(&id, &[("heartbeat_ts", "1")]).await.unwrap();
+    assert!(matches!(
+      store
+        .compare_and_update(&id, version(&operation), &[("status", "Cancelled")])
+        .await,
+      Err(RedisQueueError::Conflict(_))
+    ));
+    assert_eq!(
+      store
+        .compare_and_update(&id, 2, &[("status", "Cancelled")])
+        .await
+        .unwrap(),
+      3
+    );
+
+    let mut reads = 0;
+    let modified = store
+      .modify(&id, |operation| {
+        reads += 1;
+        (operation.state_name() != "Cancelled")
+          .then(|| vec![("status".to_string(), b"Succeeded".to_vec())])
+      })
+      .await
+      .unwrap();
+    assert_eq!((modified, reads), (None, 1));
+    assert!(matches!(
+      store
+        .compare_and_update("missing", 0, &[("status", "Queued")])
+        .await,
+      Err(RedisQueueError::NotFound(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn complete_many_should_complete_every_operation_at_once() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", uuid::Uuid::new_v4()));
+    let store = RedisTaskStore::new(client.clone()).with_keys(keys.clone());
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let _: () = redis::pipe()
+      .hset(keys.operation("1"), "queue", "billing")
+      .hset(keys.operation("2"), "queue", "billing")
+      .hset_multiple(
+        keys.operation("3"),
+        &[("queue", "billing"), ("done", "true")],
+      )
+      .rpush(keys.ack("billing"), &["1", "2", "3"])
+      .query_async(&mut conn)
+      .await
+      .unwrap();
+
+    let completed = store
+      .complete_many([
+        ("1".to_string(), Ok(b"rollup".to_vec())),
+        ("2".to_string(), Err(Status::default())),
+        ("3".to_string(), Ok(Vec::default())),
+        ("4".to_string(), Ok(Vec::default())),
+      ])
+      .await
+      .unwrap();
+    assert_eq!(completed, vec!["1".to_string(), "2".to_string()]);
+
+    let first = store.get("1").await.unwrap().unwrap();
+    let second = store.get("2").await.unwrap().unwrap();
+    assert!(first.done && second.done);
+    assert_eq!(
+      first.state,
+      crate::proto::longrunning::OperationState::Succeeded as i32
+    );
+    assert_eq!(
+      second.state,
+      crate::proto::longrunning::OperationState::Failed as i32
+    );
+
+    let in_flight: Vec<String> = conn.lrange(keys.ack("billing"), 0, -1).await.unwrap();
+    assert_eq!(in_flight, vec!["3".to_string()]);
+    let recreated: bool = conn.exists(keys.operation("4")).await.unwrap();
+    assert!(!recreated);
+  }
+
+  #[tokio::test]
+  async fn export_should_write_matching_operations() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", uuid::Uuid::new_v4()));
+    let store = RedisTaskStore::new(client.clone()).with_keys(keys.clone());
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let _: () = redis::pipe()
+      .hset_multiple(
+        keys.operation("1"),
+        &[("queue", &b"backups"[..]), ("task", &[0xff, 0x00][..])],
+      )
+      .hset(keys.operation("2"), "queue", "billing")
+      .rpush(keys.children("1"), "2")
+      .hset(keys.annotations("1"), "incident", "INC-42")
+      .query_async(&mut conn)
+      .await
+      .unwrap();
+
+    let filter = OperationFilter {
+      queues: vec!["backups".to_string()],
+      ..Default::default()
+    };
+    let mut out = Vec::default();
+    assert_eq!(store.export(&filter, &mut out).await.unwrap(), 1);
+
+    let lines: Vec<ExportedOperation> = out
+      .split(|&byte| byte == b'\n')
+      .filter(|line| !line.is_empty())
+      .map(|line| serde_json::from_slice(line).unwrap())
+      .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].operation_id, "1");
+    assert_eq!(lines[0].fields["queue"], "backups");
+    assert_eq!(lines[0].binary_fields["task"], "/wA=");
+    assert_eq!(lines[0].child_operation_ids, vec!["2".to_string()]);
+    assert_eq!(lines[0].annotations["incident"], "INC-42");
+  }
+}
@@ -1,7 +1,23 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::Utc;
 use prost::Message;
+use serde::Deserialize;
 
 use crate::proto::google::rpc::Status;
+use crate::proto::longrunning::BatchGetOperationsResponse;
 use crate::proto::longrunning::Operation;
+use crate::proto::longrunning::OperationEvent;
+use crate::proto::longrunning::OperationEventType;
+use crate::proto::longrunning::OperationState as ProtoOperationState;
+use crate::proto::longrunning::StreamOperationsRequest;
+use crate::proto::longrunning::TaskSchema;
+use crate::service::Priority;
+use crate::util::shutdown::ShutdownToken;
 
 #[async_trait::async_trait]
 pub trait Performable {
@@ -13,6 +29,20 @@ pub trait Performable {
 
   fn type_name() -> &'static str;
 
+  /// Shape of the payload and of the output, published by the workers registering the task so
+  /// clients can build tasks by hand. The task type and content type are filled in on
+  /// registration when left empty.
+  fn schema() -> Option<TaskSchema> {
+    None
+  }
+
+  /// Version of the payload of the task, bumped when workers built before the change can no
+  /// longer perform it. Recorded on enqueue so older workers notice tasks they do not support,
+  /// see [`super::skew`].
+  fn schema_version() -> u32 {
+    1
+  }
+
   async fn perform(&self, ctx: Self::Context) -> Result<Self::Output, Self::Error>;
 }
 
@@ -22,24 +52,510 @@ pub trait Broker<P: Performable> {
 
   async fn enqueue(&self, task: P, ctx: &Context) -> Result<Operation, Self::Error>;
 
+  /// Enqueues `tasks` atomically: either all of them are enqueued or none is. Returns their
+  /// operations, in the order of `tasks`.
+  ///
+  /// Enqueues them one at a time unless the broker can enqueue them at once, so the tasks before
+  /// a failing one stay enqueued.
+  async fn enqueue_all(&self, tasks: Vec<P>, ctx: &Context) -> Result<Vec<Operation>, Self::Error>
+  where
+    Self: Sync,
+    P: Send + 'async_trait,
+  {
+    let mut operations = Vec::with_capacity(tasks.len());
+    for task in tasks {
+      operations.push(self.enqueue(task, ctx).await?);
+    }
+    Ok(operations)
+  }
+
   async fn cancel(&self, id: &str, ctx: &Context) -> Result<Operation, Self::Error>;
+
+  /// Enqueues `task` to be performed once `at` is due, right away if it is past.
+  ///
+  /// Waits until `at` to enqueue `task` unless the broker can delay operations itself, so the
+  /// task is lost if the caller stops waiting.
+  async fn schedule_at(
+    &self,
+    task: P,
+    at: DateTime<Utc>,
+    ctx: &Context,
+  ) -> Result<Operation, Self::Error>
+  where
+    Self: Sync,
+    P: Send + 'async_trait,
+  {
+    if let Ok(delay) = (at - Utc::now()).to_std() {
+      tokio::time::sleep(delay).await;
+    }
+    self.enqueue(task, ctx).await
+  }
+
+  /// Enqueues `task` unless an operation enqueued with the same `key` is not done yet, in which
+  /// case that operation is returned instead.
+  async fn enqueue_unique(
+    &self,
+    task: P,
+    key: &str,
+    ctx: &Context,
+  ) -> Result<Operation, Self::Error>;
+
+  /// Returns the operation `id`, or `None` if there is no such operation.
+  async fn status(&self, id: &str) -> Result<Option<Operation>, Self::Error>;
+
+  /// Returns the last operation enqueued with `key` by [`Self::enqueue_unique`], done or not.
+  async fn find_by_key(&self, key: &str) -> Result<Option<Operation>, Self::Error>;
+
+  /// Cancels the operation enqueued with `key` unless it is done, and returns it. Returns `None`
+  /// if no operation was enqueued with `key`.
+  async fn cancel_by_key(&self, key: &str, ctx: &Context) -> Result<Option<Operation>, Self::Error>
+  where
+    Self: Sync,
+    Self::Error: Send,
+  {
+    match self.find_by_key(key).await? {
+      Some(operation) if !operation.done => {
+        self.cancel(&operation.operation_id, ctx).await.map(Some)
+      }
+      operation => Ok(operation),
+    }
+  }
+}
+
+#[async_trait::async_trait]
+pub trait EventBus {
+  type Error;
+
+  async fn publish(&self, event: OperationEvent) -> Result<(), Self::Error>;
+}
+
+/// Storage of the operations, e.g. [`super::store::RedisTaskStore`]. Layers reading and updating
+/// operations depend on this trait rather than on a backend, so the operations can be kept in
+/// another database.
+#[async_trait::async_trait]
+pub trait TaskStore: Send + Sync {
+  type Error: Send;
+
+  async fn get(&self, id: &str) -> Result<Option<Operation>, Self::Error>;
+
+  /// Returns the operations `ids`, `None` for the ones that do not exist, in the order of `ids`.
+  /// Reads them one at a time unless the store can batch the reads.
+  async fn get_many(&self, ids: &[String]) -> Result<Vec<Option<Operation>>, Self::Error> {
+    let mut operations = Vec::with_capacity(ids.len());
+    for id in ids {
+      operations.push(self.get(id).await?);
+    }
+    Ok(operations)
+  }
+
+  /// Returns the state of the operation `id`, `None` if it does not exist or its state is
+  /// unknown. Reads the whole operation unless the store can read its state alone.
+  async fn state(&self, id: &str) -> Result<Option<OperationState>, Self::Error> {
+    let operation = self.get(id).await?;
+    Ok(operation.and_then(|operation| operation.operation_state()))
+  }
+
+  /// Returns at most `limit` operations matching `filter`, in no particular order.
+  async fn list(
+    &self,
+    filter: &OperationFilter,
+    limit: usize,
+  ) -> Result<Vec<Operation>, Self::Error>;
+
+  /// Moves the operation `id` to `state` unless it is done, and returns whether it moved.
+  async fn update_state(&self, id: &str, state: OperationState) -> Result<bool, Self::Error>;
+
+  /// Records the result of the operation `id` unless it is done, and returns whether it
+  /// completed.
+  async fn complete(&self, id: &str, result: Result<Vec<u8>, Status>) -> Result<bool, Self::Error>;
+
+  /// Returns the lifecycle events of the operation `id`, oldest first, see
+  /// [`Operation::history`].
+  async fn history(&self, id: &str) -> Result<Vec<OperationEvent>, Self::Error> {
+    let operation = self.get(id).await?;
+    Ok(
+      operation
+        .map(|operation| operation.history())
+        .unwrap_or_default(),
+    )
+  }
+}
+
+/// Selects operations, e.g. for [`TaskStore::list`]. Empty criteria match every operation.
+#[derive(Clone, Debug, Default)]
+pub struct OperationFilter {
+  pub queues: Vec<String>,
+  pub task_types: Vec<String>,
+  pub user_id: Option<String>,
+  /// Statuses of the operations, e.g. `Succeeded`, see [`OperationState`].
+  pub statuses: Vec<String>,
+  /// Operations published at or after this epoch timestamp in nanoseconds.
+  pub since_ns: Option<i64>,
+  /// Operations published before this epoch timestamp in nanoseconds.
+  pub until_ns: Option<i64>,
+}
+
+impl OperationFilter {
+  /// Returns whether the text fields of an operation hash match the filter.
+  pub fn matches(&self, fields: &BTreeMap<String, String>) -> bool {
+    let any = |values: &[String], field: &str| {
+      values.is_empty()
+        || fields
+          .get(field)
+          .is_some_and(|value| values.contains(value))
+    };
+    let publish_ts = fields
+      .get("publish_ts")
+      .and_then(|ts| ts.parse::<i64>().ok())
+      .unwrap_or_default();
+
+    any(&self.queues, "queue")
+      && any(&self.task_types, "task_type")
+      && any(&self.statuses, "status")
+      && self
+        .user_id
+        .as_ref()
+        .is_none_or(|user_id| fields.get("user_id") == Some(user_id))
+      && self.since_ns.is_none_or(|since| publish_ts >= since)
+      && self.until_ns.is_none_or(|until| publish_ts < until)
+  }
+}
+
+impl Operation {
+  /// State of the operation, `None` when it is unspecified or unknown.
+  pub fn operation_state(&self) -> Option<OperationState> {
+    ProtoOperationState::from_i32(self.state).and_then(|state| OperationState::try_from(state).ok())
+  }
+
+  /// Name of the state of the operation, e.g. `Succeeded`, or its raw status when it is not a
+  /// known state.
+  pub fn state_name(&self) -> String {
+    self
+      .operation_state()
+      .map(|state| state.to_string())
+      .or_else(|| self.labels.get("status").cloned())
+      .unwrap_or_default()
+  }
+
+  /// Rebuilds the lifecycle events of the operation from its timestamps: created, started and
+  /// completed or cancelled. Progress events and failed deliveries are not recorded.
+  pub fn history(&self) -> Vec<OperationEvent> {
+    let event = |event_type: OperationEventType, event_ts, attributes| OperationEvent {
+      operation_id: self.operation_id.clone(),
+      queue: self.queue.clone(),
+      event_type: event_type as i32,
+      attributes,
+      event_ts: Some(event_ts),
+      user_id: self.user_id.clone(),
+    };
+
+    let mut events = Vec::default();
+    if let Some(ts) = &self.creation_ts {
+      events.push(event(
+        OperationEventType::Created,
+        ts.clone(),
+        HashMap::default(),
+      ));
+    }
+    if let Some(ts) = &self.start_ts {
+      events.push(event(
+        OperationEventType::Started,
+        ts.clone(),
+        HashMap::default(),
+      ));
+    }
+
+    let end = match self.operation_state() {
+      Some(OperationState::Cancelled) => Some((OperationEventType::Cancelled, None)),
+      Some(OperationState::Succeeded) => Some((OperationEventType::Completed, Some("succeeded"))),
+      Some(OperationState::Failed) => Some((OperationEventType::Completed, Some("failed"))),
+      _ => None,
+    };
+    if let (true, Some(ts), Some((event_type, outcome))) = (self.done, &self.end_ts, end) {
+      let attributes = outcome
+        .map(|outcome| HashMap::from([("outcome".to_string(), outcome.to_string())]))
+        .unwrap_or_default();
+      events.push(event(event_type, ts.clone(), attributes));
+    }
+
+    events
+  }
+}
+
+impl StreamOperationsRequest {
+  /// Returns whether the event passes the filter. Empty filter fields match every event.
+  pub fn matches(&self, event: &OperationEvent) -> bool {
+    (self.queues.is_empty() || self.queues.contains(&event.queue))
+      && (self.event_types.is_empty() || self.event_types.contains(&event.event_type))
+      && (self.user_id.is_empty() || self.user_id == event.user_id)
+      && (self.operation_id.is_empty() || self.operation_id == event.operation_id)
+      && self.after_ts.as_ref().is_none_or(|after| {
+        event
+          .event_ts
+          .as_ref()
+          .is_some_and(|ts| (ts.seconds, ts.nanos) > (after.seconds, after.nanos))
+      })
+  }
+}
+
+impl OperationEvent {
+  /// State of the operation once the event happened, `None` for unknown event types.
+  /// Quarantined operations are [`OperationState::Failed`], as when read from Redis.
+  pub fn state(&self) -> Option<OperationState> {
+    match OperationEventType::from_i32(self.event_type)? {
+      OperationEventType::Unknown => None,
+      OperationEventType::Created => Some(OperationState::Queued),
+      OperationEventType::Started | OperationEventType::Progress => Some(OperationState::Running),
+      OperationEventType::Completed => match self.attributes.get("outcome").map(String::as_str) {
+        Some("failed") => Some(OperationState::Failed),
+        _ => Some(OperationState::Succeeded),
+      },
+      OperationEventType::Cancelled => Some(OperationState::Cancelled),
+      OperationEventType::Quarantined => Some(OperationState::Failed),
+    }
+  }
+}
+
+impl BatchGetOperationsResponse {
+  /// Response for the operations `ids` read by [`TaskStore::get_many`].
+  pub fn of(ids: &[String], operations: Vec<Option<Operation>>) -> Self {
+    let mut response = Self::default();
+    for (id, operation) in ids.iter().zip(operations) {
+      match operation {
+        Some(operation) => response.operations.push(operation),
+        None => response.missing_operation_ids.push(id.clone()),
+      }
+    }
+    response
+  }
+}
+
+/// Lifecycle state of an operation, stored in the `status` field of its Redis hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperationState {
+  /// Created but not queued yet.
+  New,
+  /// Waiting in its queue for a worker.
+  Queued,
+  /// Pulled by a worker.
+  Running,
+  /// Cancellation was requested but the operation did not stop yet.
+  Cancelling,
+  Succeeded,
+  /// Completed with an error, or its message was invalidated or quarantined. Requeueing the
+  /// operation puts it back to [`OperationState::Queued`].
+  Failed,
+  Cancelled,
+}
+
+impl OperationState {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      OperationState::New => "New",
+      OperationState::Queued => "Queued",
+      OperationState::Running => "Running",
+      OperationState::Cancelling => "Cancelling",
+      OperationState::Succeeded => "Succeeded",
+      OperationState::Failed => "Failed",
+      OperationState::Cancelled => "Cancelled",
+    }
+  }
+
+  /// Whether the operation reached a final state.
+  pub fn is_done(&self) -> bool {
+    matches!(
+      self,
+      OperationState::Succeeded | OperationState::Failed | OperationState::Cancelled
+    )
+  }
+}
+
+impl std::fmt::Display for OperationState {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown operation state {0:?}")]
+pub struct UnknownOperationState(String);
+
+/// Parses the stored state, including the `Terminated`, `Invalid` and `Quarantined` statuses
+/// written before operation states existed.
+impl FromStr for OperationState {
+  type Err = UnknownOperationState;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "New" => Ok(OperationState::New),
+      "Queued" => Ok(OperationState::Queued),
+      "Running" => Ok(OperationState::Running),
+      "Cancelling" => Ok(OperationState::Cancelling),
+      "Succeeded" | "Terminated" => Ok(OperationState::Succeeded),
+      "Failed" | "Invalid" | "Quarantined" => Ok(OperationState::Failed),
+      "Cancelled" => Ok(OperationState::Cancelled),
+      _ => Err(UnknownOperationState(s.to_string())),
+    }
+  }
+}
+
+impl From<OperationState> for ProtoOperationState {
+  fn from(state: OperationState) -> Self {
+    match state {
+      OperationState::New => ProtoOperationState::New,
+      OperationState::Queued => ProtoOperationState::Queued,
+      OperationState::Running => ProtoOperationState::Running,
+      OperationState::Cancelling => ProtoOperationState::Cancelling,
+      OperationState::Succeeded => ProtoOperationState::Succeeded,
+      OperationState::Failed => ProtoOperationState::Failed,
+      OperationState::Cancelled => ProtoOperationState::Cancelled,
+    }
+  }
+}
+
+impl TryFrom<ProtoOperationState> for OperationState {
+  type Error = UnknownOperationState;
+
+  fn try_from(state: ProtoOperationState) -> Result<Self, Self::Error> {
+    match state {
+      ProtoOperationState::Unspecified => Err(UnknownOperationState(format!("{:?}", state))),
+      ProtoOperationState::New => Ok(OperationState::New),
+      ProtoOperationState::Queued => Ok(OperationState::Queued),
+      ProtoOperationState::Running => Ok(OperationState::Running),
+      ProtoOperationState::Cancelling => Ok(OperationState::Cancelling),
+      ProtoOperationState::Succeeded => Ok(OperationState::Succeeded),
+      ProtoOperationState::Failed => Ok(OperationState::Failed),
+      ProtoOperationState::Cancelled => Ok(OperationState::Cancelled),
+    }
+  }
+}
+
+#[cfg(feature = "redis")]
+impl redis::ToRedisArgs for OperationState {
+  fn write_redis_args<W: ?Sized + redis::RedisWrite>(&self, out: &mut W) {
+    out.write_arg(self.as_str().as_bytes())
+  }
+}
+
+#[cfg(feature = "redis")]
+impl redis::FromRedisValue for OperationState {
+  fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+    let state: String = redis::from_redis_value(v)?;
+
+    state.parse().map_err(|error: UnknownOperationState| {
+      redis::RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Invalid operation state",
+        error.to_string(),
+      ))
+    })
+  }
+}
+
+/// When a pulled message leaves the in-flight list of its queue. A message still in flight when
+/// its lease expires is delivered again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckMode {
+  /// On pull. A message is delivered at most once and lost if its worker dies.
+  Auto,
+  /// In the same transaction that records the result of the operation.
+  #[default]
+  OnComplete,
+  /// Only when [`Queue::ack`] is called, e.g. once user code has persisted side effects.
+  Manual,
 }
 
 pub trait Task<T> {
   fn ack_id(&self) -> &str;
 
   fn data(&self) -> &T;
+
+  /// User that enqueued the task, when the queue records it.
+  fn user_id(&self) -> Option<&str> {
+    None
+  }
+
+  /// Context the task was enqueued with, when the queue records it.
+  fn context(&self) -> Option<&Context> {
+    None
+  }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Context {
   user_id: String,
   system_id: String,
+  principal: Option<String>,
+  organization_id: Option<String>,
+  parent_operation_id: Option<String>,
+  callback_url: Option<String>,
+  trace_parent: Option<String>,
+  deadline: Option<DateTime<Utc>>,
+  priority: Priority,
 }
 
 impl Context {
   pub fn new(user_id: String, system_id: String) -> Self {
-    Self { user_id, system_id }
+    Self {
+      user_id,
+      system_id,
+      principal: None,
+      organization_id: None,
+      parent_operation_id: None,
+      callback_url: None,
+      trace_parent: None,
+      deadline: None,
+      priority: Priority::default(),
+    }
+  }
+
+  /// Sets the authenticated principal enqueueing on behalf of the user, e.g. a service account.
+  /// Enqueuing for another user than the principal is impersonation, which queues only allow to
+  /// privileged principals, see [`super::redis::RedisQueue::with_impersonators`].
+  pub fn with_principal(mut self, principal: &str) -> Self {
+    self.principal = Some(principal.to_string()).filter(|principal| *principal != self.user_id);
+    self
+  }
+
+  /// Records the operations enqueued with this context as children of `parent_operation_id`,
+  /// e.g. the operation of the task spawning them.
+  pub fn with_parent_operation_id(mut self, parent_operation_id: &str) -> Self {
+    self.parent_operation_id = Some(parent_operation_id.to_string());
+    self
+  }
+
+  /// Sets the URL notified when the operations enqueued with this context complete, see
+  /// `WebhookNotifier`.
+  pub fn with_callback_url(mut self, callback_url: &str) -> Self {
+    self.callback_url = Some(callback_url.to_string());
+    self
+  }
+
+  /// Sets the organization the operations are accounted to, see [`crate::quota`].
+  pub fn with_organization_id(mut self, organization_id: &str) -> Self {
+    self.organization_id = Some(organization_id.to_string());
+    self
+  }
+
+  /// Sets the W3C `traceparent` of the trace the operations belong to, so the workers performing
+  /// them continue it.
+  pub fn with_trace_parent(mut self, trace_parent: &str) -> Self {
+    self.trace_parent = Some(trace_parent.to_string());
+    self
+  }
+
+  /// Sets the time after which performing the operations is pointless.
+  pub fn with_deadline(mut self, deadline: DateTime<Utc>) -> Self {
+    self.deadline = Some(deadline);
+    self
+  }
+
+  /// Sets the priority of the operations, usually inherited from the request enqueueing them, see
+  /// [`crate::service::Context::priority`]. Interactive operations are pulled first.
+  pub fn with_priority(mut self, priority: Priority) -> Self {
+    self.priority = priority;
+    self
   }
 
   pub fn user_id(&self) -> &str {
@@ -49,6 +565,146 @@ impl Context {
   pub fn system_id(&self) -> &str {
     &self.system_id
   }
+
+  /// Principal enqueueing the operations, the user itself unless set otherwise.
+  pub fn principal(&self) -> &str {
+    self.principal.as_deref().unwrap_or(&self.user_id)
+  }
+
+  /// Whether the principal enqueues on behalf of another user.
+  pub fn is_impersonating(&self) -> bool {
+    self.principal() != self.user_id
+  }
+
+  pub fn organization_id(&self) -> Option<&str> {
+    self.organization_id.as_deref()
+  }
+
+  pub fn parent_operation_id(&self) -> Option<&str> {
+    self.parent_operation_id.as_deref()
+  }
+
+  pub fn callback_url(&self) -> Option<&str> {
+    self.callback_url.as_deref()
+  }
+
+  pub fn trace_parent(&self) -> Option<&str> {
+    self.trace_parent.as_deref()
+  }
+
+  pub fn deadline(&self) -> Option<DateTime<Utc>> {
+    self.deadline
+  }
+
+  pub fn priority(&self) -> Priority {
+    self.priority
+  }
+}
+
+/// Execution context the worker hands to a task.
+///
+/// The context owns a span carrying the operation id, task type, queue, attempt and user id. The
+/// worker runs the task inside that span, so events recorded by the task inherit these fields.
+/// Futures spawned by the task can be attached to it through [`TaskContext::logger`].
+///
+/// The worker shuts down the [`TaskContext::cancellation`] token once the operation is cancelled,
+/// so the task stops what it runs, e.g. a [`super::process::Supervised`] process.
+#[derive(Debug, Clone)]
+pub struct TaskContext {
+  operation_id: String,
+  task_type: String,
+  queue: String,
+  attempt: i64,
+  user_id: String,
+  context: Option<Context>,
+  cancellation: ShutdownToken,
+  span: tracing::Span,
+}
+
+impl TaskContext {
+  pub fn new(
+    operation_id: &str,
+    task_type: &str,
+    queue: &str,
+    attempt: i64,
+    user_id: &str,
+  ) -> Self {
+    let span = tracing::info_span!(
+      "task",
+      %operation_id,
+      %task_type,
+      %queue,
+      %attempt,
+      %user_id,
+    );
+
+    Self {
+      operation_id: operation_id.to_string(),
+      task_type: task_type.to_string(),
+      queue: queue.to_string(),
+      attempt,
+      user_id: user_id.to_string(),
+      context: None,
+      cancellation: ShutdownToken::new(),
+      span,
+    }
+  }
+
+  /// Sets the context the task was enqueued with, see [`TaskContext::context`].
+  pub fn with_context(mut self, context: Context) -> Self {
+    self.context = Some(context);
+    self
+  }
+
+  pub fn operation_id(&self) -> &str {
+    &self.operation_id
+  }
+
+  pub fn task_type(&self) -> &str {
+    &self.task_type
+  }
+
+  pub fn queue(&self) -> &str {
+    &self.queue
+  }
+
+  pub fn attempt(&self) -> i64 {
+    self.attempt
+  }
+
+  pub fn user_id(&self) -> &str {
+    &self.user_id
+  }
+
+  /// Context the task was enqueued with, e.g. to enqueue follow-up operations in the same trace
+  /// and before the same deadline.
+  pub fn context(&self) -> Option<&Context> {
+    self.context.as_ref()
+  }
+
+  /// Sets the token shut down once the operation is cancelled, see [`TaskContext::cancellation`].
+  pub fn with_cancellation(mut self, cancellation: ShutdownToken) -> Self {
+    self.cancellation = cancellation;
+    self
+  }
+
+  /// Shut down once the operation is cancelled. The task should then stop as soon as it can,
+  /// its result is discarded.
+  pub fn cancellation(&self) -> &ShutdownToken {
+    &self.cancellation
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancellation.is_shutdown()
+  }
+
+  pub fn logger(&self) -> &tracing::Span {
+    &self.span
+  }
+}
+
+impl From<TaskContext> for () {
+  fn from(_: TaskContext) -> Self {}
 }
 
 #[async_trait::async_trait]
@@ -64,6 +720,16 @@ pub trait Queue {
   async fn pull(&self, ctx: &Context) -> Result<Option<Self::ReceivedItem>, Self::Error>;
 
   async fn ack(&self, ack_id: &str, ctx: &Context) -> Result<(), Self::Error>;
+
+  /// Gives back a pulled message for redelivery, after `delay` if any, without failing its
+  /// operation, e.g. when a dependency of the task is not ready yet. The redelivery counts as a
+  /// new attempt.
+  async fn nack(
+    &self,
+    ack_id: &str,
+    delay: Option<Duration>,
+    ctx: &Context,
+  ) -> Result<(), Self::Error>;
 }
 
 #[async_trait::async_trait]
@@ -82,3 +748,231 @@ pub trait TaskLoop {
 
   fn await_termination(&mut self);
 }
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+
+  #[test]
+  fn stream_filter_should_match_events() {
+    let event = OperationEvent {
+      operation_id: "1".to_string(),
+      queue: "workspaces".to_string(),
+      event_type: OperationEventType::Started as i32,
+      user_id: "42".to_string(),
+      ..Default::default()
+    };
+
+    let everything = StreamOperationsRequest::default();
+    let by_queue = StreamOperationsRequest {
+      queues: vec!["workspaces".to_string()],
+      ..Default::default()
+    };
+    let by_type = StreamOperationsRequest {
+      event_types: vec![OperationEventType::Completed as i32],
+      ..Default::default()
+    };
+    let by_user = StreamOperationsRequest {
+      user_id: "7".to_string(),
+      ..Default::default()
+    };
+
+    assert!(everything.matches(&event));
+    assert!(by_queue.matches(&event));
+    assert!(!by_type.matches(&event));
+    assert!(!by_user.matches(&event));
+  }
+
+  #[test]
+  fn operation_event_should_give_the_state_it_leads_to() {
+    let event = |event_type: OperationEventType, outcome: Option<&str>| OperationEvent {
+      event_type: event_type as i32,
+      attributes: outcome
+        .map(|outcome| HashMap::from([("outcome".to_string(), outcome.to_string())]))
+        .unwrap_or_default(),
+      ..Default::default()
+    };
+
+    assert_eq!(event(OperationEventType::Unknown, None).state(), None);
+    assert_eq!(
+      event(OperationEventType::Created, None).state(),
+      Some(OperationState::Queued)
+    );
+    assert_eq!(
+      event(OperationEventType::Progress, None).state(),
+      Some(OperationState::Running)
+    );
+    assert_eq!(
+      event(OperationEventType::Completed, Some("succeeded")).state(),
+      Some(OperationState::Succeeded)
+    );
+    assert_eq!(
+      event(OperationEventType::Completed, Some("failed")).state(),
+      Some(OperationState::Failed)
+    );
+    assert_eq!(
+      event(OperationEventType::Quarantined, None).state(),
+      Some(OperationState::Failed)
+    );
+  }
+
+  #[test]
+  fn batch_get_response_should_list_the_missing_operations() {
+    let operation = |id: &str| Operation {
+      operation_id: id.to_string(),
+      ..Default::default()
+    };
+    let ids = ["1", "2", "3"].map(String::from);
+
+    let response =
+      BatchGetOperationsResponse::of(&ids, vec![Some(operation("1")), None, Some(operation("3"))]);
+    assert_eq!(response.operations, vec![operation("1"), operation("3")]);
+    assert_eq!(response.missing_operation_ids, vec!["2"]);
+  }
+
+  #[test]
+  fn operation_history_should_follow_the_timestamps() {
+    let ts = |seconds| crate::proto::google::protobuf::Timestamp { seconds, nanos: 0 };
+    let mut operation = Operation {
+      operation_id: "1".to_string(),
+      queue: "workspaces".to_string(),
+      state: ProtoOperationState::Running as i32,
+      creation_ts: Some(ts(1)),
+      start_ts: Some(ts(2)),
+      ..Default::default()
+    };
+
+    let types = |operation: &Operation| -> Vec<i32> {
+      operation
+        .history()
+        .iter()
+        .map(|event| event.event_type)
+        .collect()
+    };
+    assert_eq!(
+      types(&operation),
+      [
+        OperationEventType::Created as i32,
+        OperationEventType::Started as i32
+      ]
+    );
+
+    operation.done = true;
+    operation.state = ProtoOperationState::Failed as i32;
+    operation.end_ts = Some(ts(3));
+    let history = operation.history();
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[2].event_type, OperationEventType::Completed as i32);
+    assert_eq!(history[2].attributes["outcome"], "failed");
+    assert_eq!(history[2].queue, "workspaces");
+    assert_eq!(history[2].state(), Some(OperationState::Failed));
+  }
+
+  #[test]
+  fn operation_state_should_round_trip_and_read_legacy_statuses() {
+    for state in [
+      OperationState::New,
+      OperationState::Queued,
+      OperationState::Running,
+      OperationState::Cancelling,
+      OperationState::Succeeded,
+      OperationState::Failed,
+      OperationState::Cancelled,
+    ] {
+      assert_eq!(state.as_str().parse::<OperationState>().unwrap(), state);
+      assert_eq!(
+        OperationState::try_from(ProtoOperationState::from(state)).unwrap(),
+        state
+      );
+    }
+
+    assert_eq!(
+      "Terminated".parse::<OperationState>().unwrap(),
+      OperationState::Succeeded
+    );
+    assert_eq!(
+      "Quarantined".parse::<OperationState>().unwrap(),
+      OperationState::Failed
+    );
+    assert!("Paused".parse::<OperationState>().is_err());
+    assert!(OperationState::Cancelled.is_done());
+    assert!(!OperationState::Cancelling.is_done());
+  }
+
+  struct Nightly(i32);
+
+  #[async_trait::async_trait]
+  impl Performable for Nightly {
+    type Error = std::io::Error;
+    type Context = ();
+    type Output = crate::proto::google::protobuf::Empty;
+
+    fn type_name() -> &'static str {
+      "longrunning::types::tests::Nightly"
+    }
+
+    async fn perform(&self, _: Self::Context) -> Result<Self::Output, Self::Error> {
+      Ok(Default::default())
+    }
+  }
+
+  /// Broker relying on the default implementations, enqueuing operations to a vector.
+  #[derive(Default)]
+  struct Enqueued(std::sync::Mutex<Vec<i32>>);
+
+  #[async_trait::async_trait]
+  impl Broker<Nightly> for Enqueued {
+    type Error = std::io::Error;
+
+    async fn enqueue(&self, task: Nightly, _: &Context) -> Result<Operation, Self::Error> {
+      let mut tasks = self.0.lock().unwrap();
+      tasks.push(task.0);
+      Ok(Operation {
+        operation_id: tasks.len().to_string(),
+        ..Default::default()
+      })
+    }
+
+    async fn cancel(&self, id: &str, _: &Context) -> Result<Operation, Self::Error> {
+      Err(std::io::Error::other(format!("Cannot cancel {}", id)))
+    }
+
+    async fn enqueue_unique(
+      &self,
+      task: Nightly,
+      _: &str,
+      ctx: &Context,
+    ) -> Result<Operation, Self::Error> {
+      self.enqueue(task, ctx).await
+    }
+
+    async fn status(&self, _: &str) -> Result<Option<Operation>, Self::Error> {
+      Ok(None)
+    }
+
+    async fn find_by_key(&self, _: &str) -> Result<Option<Operation>, Self::Error> {
+      Ok(None)
+    }
+  }
+
+  #[tokio::test]
+  async fn broker_should_enqueue_one_at_a_time_by_default() {
+    let ctx = Context::new("system".to_string(), "1234".to_string());
+    let broker = Enqueued::default();
+
+    let operations = broker
+      .enqueue_all(vec![Nightly(1), Nightly(2)], &ctx)
+      .await
+      .unwrap();
+    assert_eq!(operations[0].operation_id, "1");
+    assert_eq!(operations[1].operation_id, "2");
+
+    let at = Utc::now() + chrono::Duration::milliseconds(20);
+    let scheduled = broker.schedule_at(Nightly(3), at, &ctx).await.unwrap();
+    assert!(Utc::now() >= at);
+    assert_eq!(scheduled.operation_id, "3");
+    assert_eq!(*broker.0.lock().unwrap(), vec![1, 2, 3]);
+  }
+}
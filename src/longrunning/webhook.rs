@@ -0,0 +1,340 @@
+//! Notifies external systems over HTTP when operations complete.
+//!
+//! [`WebhookNotifier`] listens to the operation events and POSTs a [`WebhookPayload`] to the
+//! callback URL of the operation, see [`super::Context::with_callback_url`], or of its queue. The
+//! body is signed with HMAC-SHA256 in the [`SIGNATURE_HEADER`] header as `t={ts},v1={hex}`, where
+//! the MAC covers `{ts}.{body}`; receivers check it with [`verify`].
+//!
+//! Events are delivered at most once per running notifier: completions happening while no
+//! notifier is subscribed are not notified.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::StreamExt;
+use hyper::client::connect::Connect;
+use hyper::client::HttpConnector;
+use hyper::Body;
+use hyper::StatusCode;
+use ring::hmac;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::proto::longrunning::Operation;
+use crate::proto::longrunning::OperationEventType;
+use crate::proto::longrunning::StreamOperationsRequest;
+use crate::util::backoff::retry;
+use crate::util::backoff::Backoff;
+
+use super::redis::RedisEventBus;
+use super::redis::RedisQueueError;
+use super::store::RedisTaskStore;
+
+pub const SIGNATURE_HEADER: &str = "x-rappel-signature";
+
+/// Unique id of a delivery, identical across its retries so receivers can deduplicate.
+pub const DELIVERY_HEADER: &str = "x-rappel-delivery";
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+  #[error("Invalid webhook request: {0}")]
+  Request(#[from] hyper::http::Error),
+
+  #[error("Webhook request failed: {0}")]
+  Transport(#[from] hyper::Error),
+
+  #[error("Webhook request timed out")]
+  Timeout,
+
+  #[error("Webhook responded with {0}")]
+  Status(StatusCode),
+
+  #[error("Failed to encode the webhook payload: {0}")]
+  Json(#[from] serde_json::Error),
+
+  #[error("{0}")]
+  Queue(#[from] RedisQueueError),
+}
+
+impl WebhookError {
+  /// Client errors other than `429 Too Many Requests` are not retried.
+  fn is_transient(&self) -> bool {
+    match self {
+      WebhookError::Status(status) => {
+        !status.is_client_error() || *status == StatusCode::TOO_MANY_REQUESTS
+      }
+      WebhookError::Transport(_) | WebhookError::Timeout => true,
+      _ => false,
+    }
+  }
+}
+
+/// JSON body POSTed to the callback URL.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct WebhookPayload {
+  pub operation_id: String,
+  pub queue: String,
+  pub task_type: String,
+  pub user_id: String,
+  /// Final state of the operation, e.g. `Succeeded`, `Failed` or `Cancelled`.
+  pub state: String,
+  /// `google.rpc.Code` of the error, 0 when the operation succeeded.
+  pub error_code: i32,
+  pub error_message: String,
+}
+
+impl From<&Operation> for WebhookPayload {
+  fn from(operation: &Operation) -> Self {
+    let error = operation.error.clone().unwrap_or_default();
+
+    Self {
+      operation_id: operation.operation_id.clone(),
+      queue: operation.queue.clone(),
+      task_type: operation.task_type.clone(),
+      user_id: operation.user_id.clone(),
+      state: operation.state_name(),
+      error_code: error.code,
+      error_message: error.message,
+    }
+  }
+}
+
+/// Returns the [`SIGNATURE_HEADER`] value of `body` sent at `timestamp`, in epoch seconds.
+pub fn sign(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+  let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+  let tag = hmac::sign(&key, &signed_content(timestamp, body));
+
+  format!("t={},v1={}", timestamp, hex(tag.as_ref()))
+}
+
+/// Checks the [`SIGNATURE_HEADER`] of a received `body`, rejecting signatures older than
+/// `tolerance` to limit replays.
+pub fn verify(secret: &[u8], header: &str, body: &[u8], tolerance: Duration) -> bool {
+  let mut timestamp = None;
+  let mut signature = None;
+
+  for part in header.split(',') {
+    match part.split_once('=') {
+      Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+      Some(("v1", value)) => signature = unhex(value),
+      _ => {}
+    }
+  }
+
+  let (timestamp, signature) = match (timestamp, signature) {
+    (Some(timestamp), Some(signature)) => (timestamp, signature),
+    _ => return false,
+  };
+
+  if (Utc::now().timestamp() - timestamp).unsigned_abs() > tolerance.as_secs() {
+    return false;
+  }
+
+  let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+  hmac::verify(&key, &signed_content(timestamp, body), &signature).is_ok()
+}
+
+fn signed_content(timestamp: i64, body: &[u8]) -> Vec<u8> {
+  let mut content = format!("{}.", timestamp).into_bytes();
+  content.extend_from_slice(body);
+  content
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+  if !value.len().is_multiple_of(2) {
+    return None;
+  }
+
+  (0..value.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+/// POSTs a signed [`WebhookPayload`] when an operation completes, fails or is cancelled.
+///
+/// ```rust,ignore
+/// let notifier = WebhookNotifier::new(store, events, b"secret")
+///   .with_queue_url("backups", "http://billing.internal/hooks/backups");
+/// tokio::spawn(async move { notifier.run().await });
+/// ```
+///
+/// Plain HTTP is used by default, pass a TLS connector to [`WebhookNotifier::with_connector`] for
+/// HTTPS callbacks.
+#[derive(Clone, Debug)]
+pub struct WebhookNotifier<C = HttpConnector> {
+  client: hyper::Client<C>,
+  store: RedisTaskStore,
+  events: RedisEventBus,
+  secret: Vec<u8>,
+  queue_urls: HashMap<String, String>,
+  attempts: u32,
+  backoff: Duration,
+  timeout: Duration,
+}
+
+impl WebhookNotifier<HttpConnector> {
+  pub fn new(store: RedisTaskStore, events: RedisEventBus, secret: &[u8]) -> Self {
+    Self {
+      client: hyper::Client::new(),
+      store,
+      events,
+      secret: secret.to_vec(),
+      queue_urls: HashMap::default(),
+      attempts: 5,
+      backoff: Duration::from_secs(1),
+      timeout: Duration::from_secs(10),
+    }
+  }
+}
+
+impl<C> WebhookNotifier<C>
+where
+  C: Connect + Clone + Send + Sync + 'static,
+{
+  pub fn with_connector<D: Connect + Clone>(self, connector: D) -> WebhookNotifier<D> {
+    WebhookNotifier {
+      client: hyper::Client::builder().build(connector),
+      store: self.store,
+      events: self.events,
+      secret: self.secret,
+      queue_urls: self.queue_urls,
+      attempts: self.attempts,
+      backoff: self.backoff,
+      timeout: self.timeout,
+    }
+  }
+
+  /// Notifies `url` for the operations of `queue` without a callback URL of their own.
+  pub fn with_queue_url(mut self, queue: &str, url: &str) -> Self {
+    self.queue_urls.insert(queue.to_string(), url.to_string());
+    self
+  }
+
+  /// Attempts per delivery, 5 by default. The backoff between attempts doubles every time, see
+  /// [`Backoff`].
+  pub fn with_retry(mut self, attempts: u32, backoff: Duration) -> Self {
+    self.attempts = attempts.max(1);
+    self.backoff = backoff;
+    self
+  }
+
+  /// Time allowed for each attempt, 10 seconds by default.
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Notifies the completed and cancelled operations until the event subscription ends. Every
+  /// delivery runs in its own task, so a slow receiver does not hold up the others.
+  pub async fn run(&self) -> Result<(), WebhookError> {
+    let filter = StreamOperationsRequest {
+      event_types: vec![
+        OperationEventType::Completed as i32,
+        OperationEventType::Cancelled as i32,
+      ],
+      ..Default::default()
+    };
+    let mut events = Box::pin(self.events.subscribe(filter).await?);
+
+    while let Some(event) = events.next().await {
+      let notifier = self.clone();
+
+      tokio::spawn(async move {
+        if let Err(error) = notifier.notify(&event.operation_id).await {
+          tracing::warn!(
+            message = "Failed to notify webhook",
+            operation_id = %event.operation_id,
+            %error
+          );
+        }
+      });
+    }
+
+    Ok(())
+  }
+
+  /// Notifies the callback URL of the operation `id`. Returns `false` if neither the operation
+  /// nor its queue has one.
+  pub async fn notify(&self, id: &str) -> Result<bool, WebhookError> {
+    let operation = match self.store.get(id).await? {
+      Some(operation) => operation,
+      None => return Ok(false),
+    };
+
+    let url = operation
+      .metadata
+      .get("callback_url")
+      .or_else(|| self.queue_urls.get(&operation.queue));
+
+    let url = match url {
+      Some(url) => url,
+      None => return Ok(false),
+    };
+
+    let body = serde_json::to_vec(&WebhookPayload::from(&operation))?;
+    self.deliver(url, body).await?;
+
+    tracing::debug!(message = "Notified webhook", operation_id = %id, %url);
+    Ok(true)
+  }
+
+  async fn deliver(&self, url: &str, body: Vec<u8>) -> Result<(), WebhookError> {
+    let delivery = Uuid::new_v4().to_string();
+    let backoff = Backoff::exponential(self.backoff);
+
+    retry(backoff, self.attempts, WebhookError::is_transient, || {
+      self.post(url, &delivery, body.clone())
+    })
+    .await
+  }
+
+  async fn post(&self, url: &str, delivery: &str, body: Vec<u8>) -> Result<(), WebhookError> {
+    // Signed per attempt, so retries are not rejected by the receiver's replay tolerance.
+    let signature = sign(&self.secret, Utc::now().timestamp(), &body);
+
+    let request = hyper::Request::post(url)
+      .header(hyper::header::CONTENT_TYPE, "application/json")
+      .header(SIGNATURE_HEADER, signature)
+      .header(DELIVERY_HEADER, delivery)
+      .body(Body::from(body))?;
+
+    let response = tokio::time::timeout(self.timeout, self.client.request(request))
+      .await
+      .map_err(|_| WebhookError::Timeout)??;
+
+    match response.status() {
+      status if status.is_success() => Ok(()),
+      status => Err(WebhookError::Status(status)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn verify_should_accept_only_fresh_matching_signatures() {
+    let body = br#"{"operation_id":"1"}"#;
+    let now = Utc::now().timestamp();
+    let header = sign(b"secret", now, body);
+    let minute = Duration::from_secs(60);
+
+    assert!(verify(b"secret", &header, body, minute));
+    assert!(!verify(b"other", &header, body, minute));
+    assert!(!verify(b"secret", &header, b"{}", minute));
+    assert!(!verify(
+      b"secret",
+      &sign(b"secret", now - 600, body),
+      body,
+      minute
+    ));
+    assert!(!verify(b"secret", "t=1,v1=zz", body, minute));
+  }
+}
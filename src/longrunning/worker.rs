@@ -0,0 +1,682 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing_futures::Instrument;
+
+use crate::codec::json::JsonCodec;
+use crate::proto::google::rpc::Status;
+use crate::util::backoff::Backoff;
+use crate::util::backoff::Jitter;
+use crate::util::error_context::ErrorContext;
+use crate::util::error_context::ResultExt;
+use crate::util::shutdown::ShutdownToken;
+
+use super::failure::DefaultClassifier;
+use super::failure::Failure;
+use super::failure::SharedFailureClassifier;
+use super::redis::RedisQueue;
+use super::redis::RedisQueueError;
+use super::store::RedisTaskStore;
+use super::Context;
+use super::OperationState;
+use super::Performable;
+use super::Queue;
+use super::TaskContext;
+use super::TaskStore;
+
+/// Pulls tasks from a [`RedisQueue`], performs them and records their result.
+///
+/// Every execution runs inside the span of its [`TaskContext`]. The worker never acknowledges
+/// operations itself, see [`super::AckMode`]: with [`super::AckMode::Manual`] something else must
+/// call [`Queue::ack`] or the operations are delivered again once their lease expires.
+///
+/// Every lease, see [`Worker::with_lease`], the worker puts the operations left in flight by
+/// workers that died back on the queue, see [`RedisQueue::recover_expired`].
+///
+/// While a task runs the worker checks whether its operation was cancelled, reading its state from
+/// the [`TaskStore`], see [`Worker::with_store`], and then shuts down the
+/// [`TaskContext::cancellation`] token and discards the result of the task, leaving the operation
+/// cancelled.
+///
+/// A worker is a handle: clones are cheap and share their queue and settings. Configuring a clone
+/// copies the settings, leaving the other clones as they were.
+#[derive(Debug)]
+pub struct Worker<T: Serialize + DeserializeOwned + Performable, S = RedisTaskStore> {
+  inner: Arc<WorkerState<T, S>>,
+}
+
+#[derive(Debug)]
+struct WorkerState<T: Serialize + DeserializeOwned + Performable, S> {
+  queue: RedisQueue<T, JsonCodec<T, T>>,
+  store: S,
+  ctx: Context,
+  poll_interval: Duration,
+  cancel_poll_interval: Duration,
+  lease: Duration,
+  classifier: SharedFailureClassifier,
+  shutdown: ShutdownToken,
+}
+
+impl<T: Serialize + DeserializeOwned + Performable, S: Clone> Clone for WorkerState<T, S> {
+  fn clone(&self) -> Self {
+    Self {
+      queue: self.queue.clone(),
+      store: self.store.clone(),
+      ctx: self.ctx.clone(),
+      poll_interval: self.poll_interval,
+      cancel_poll_interval: self.cancel_poll_interval,
+      lease: self.lease,
+      classifier: self.classifier.clone(),
+      shutdown: self.shutdown.clone(),
+    }
+  }
+}
+
+impl<T: Serialize + DeserializeOwned + Performable, S> Clone for Worker<T, S> {
+  fn clone(&self) -> Self {
+    Self {
+      inner: self.inner.clone(),
+    }
+  }
+}
+
+impl<T> Worker<T>
+where
+  T: Send + Sync + Serialize + DeserializeOwned + Performable + 'static,
+  T::Context: From<TaskContext>,
+  T::Error: Into<Status>,
+{
+  /// Reads the state of the operations from a [`RedisTaskStore`] under the keys of `queue`.
+  pub fn new(queue: RedisQueue<T, JsonCodec<T, T>>, ctx: Context) -> Self {
+    let store = RedisTaskStore::new(queue.client().clone()).with_keys(queue.keys().clone());
+    Self {
+      inner: Arc::new(WorkerState {
+        queue,
+        store,
+        ctx,
+        poll_interval: Duration::from_millis(1000),
+        cancel_poll_interval: DEFAULT_CANCEL_POLL_INTERVAL,
+        lease: DEFAULT_LEASE,
+        classifier: Arc::new(DefaultClassifier),
+        shutdown: ShutdownToken::new(),
+      }),
+    }
+  }
+}
+
+impl<T, S> Worker<T, S>
+where
+  T: Send + Sync + Serialize + DeserializeOwned + Performable + 'static,
+  T::Context: From<TaskContext>,
+  T::Error: Into<Status>,
+  S: TaskStore + Clone,
+  S::Error: Display,
+{
+  /// Reads the state of the operations from `store` rather than from the Redis of the queue, e.g.
+  /// when the operations are kept in another database.
+  pub fn with_store<U: TaskStore + Clone>(self, store: U) -> Worker<T, U> {
+    let state = Arc::unwrap_or_clone(self.inner);
+    Worker {
+      inner: Arc::new(WorkerState {
+        queue: state.queue,
+        store,
+        ctx: state.ctx,
+        poll_interval: state.poll_interval,
+        cancel_poll_interval: state.cancel_poll_interval,
+        lease: state.lease,
+        classifier: state.classifier,
+        shutdown: state.shutdown,
+      }),
+    }
+  }
+
+  /// Sets how the errors of the tasks are handled, [`DefaultClassifier`] by default.
+  pub fn with_failure_classifier(mut self, classifier: SharedFailureClassifier) -> Self {
+    self.state().classifier = classifier;
+    self
+  }
+
+  /// Sets how long the worker sleeps when the queue is empty.
+  pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+    self.state().poll_interval = poll_interval;
+    self
+  }
+
+  /// Sets how often the state of the operation is read while its task runs, to notice its
+  /// cancellation, [`DEFAULT_CANCEL_POLL_INTERVAL`] by default.
+  pub fn with_cancel_poll_interval(mut self, cancel_poll_interval: Duration) -> Self {
+    self.state().cancel_poll_interval = cancel_poll_interval;
+    self
+  }
+
+  /// Sets how long an operation may stay in flight before it is considered abandoned by its
+  /// worker and delivered again, [`DEFAULT_LEASE`] by default. It must exceed the longest task,
+  /// which would otherwise be performed twice.
+  pub fn with_lease(mut self, lease: Duration) -> Self {
+    self.state().lease = lease;
+    self
+  }
+
+  /// Stops [`Self::run`] once `shutdown` is shut down, after the task in progress.
+  pub fn with_shutdown(mut self, shutdown: ShutdownToken) -> Self {
+    self.state().shutdown = shutdown;
+    self
+  }
+
+  pub fn worker_id(&self) -> &str {
+    self.inner.ctx.system_id()
+  }
+
+  fn state(&mut self) -> &mut WorkerState<T, S> {
+    Arc::make_mut(&mut self.inner)
+  }
+
+  /// Processes tasks until the surrounding future is dropped or the worker is shut down, see
+  /// [`Self::with_shutdown`]. Consecutive failures back off from the poll interval, see
+  /// [`Backoff`]. Expired leases are recovered on start and then every lease.
+  pub async fn run(&self) {
+    let poll_interval = self.inner.poll_interval;
+    let shutdown = &self.inner.shutdown;
+    let mut failures = error_backoff(poll_interval).delays();
+    let mut recovered_at: Option<Instant> = None;
+
+    while !shutdown.is_shutdown() {
+      if recovered_at.is_none_or(|at| at.elapsed() >= self.inner.lease) {
+        self.recover_expired().await;
+        recovered_at = Some(Instant::now());
+      }
+
+      let delay = match self.process_one().await {
+        Ok(true) => {
+          failures.reset();
+          continue;
+        }
+        Ok(false) => {
+          failures.reset();
+          poll_interval
+        }
+        Err(error) => {
+          tracing::error!(message = "Failed to process task", worker_id = %self.worker_id(), %error);
+          failures.next().unwrap_or(poll_interval)
+        }
+      };
+
+      tokio::select! {
+        _ = tokio::time::sleep(delay) => {}
+        _ = shutdown.wait() => {}
+      }
+    }
+  }
+
+  /// Processes tasks until neither the queue nor its in-flight list holds operations, e.g. to drain
+  /// a queue during a migration or before the assertions of an integration test. Operations in
+  /// flight with other workers are waited for, polling every poll interval, until their lease
+  /// expires and they are recovered, see [`Self::with_lease`]. Delayed and scheduled operations
+  /// are not waited for. Stops early, with the tasks processed so far, when the worker
+  /// is shut down, and fails on the first error pulling or recording a task.
+  pub async fn run_until_empty(&self) -> Result<DrainStats, RedisQueueError> {
+    let started = Instant::now();
+    let mut stats = DrainStats::default();
+
+    while !self.inner.shutdown.is_shutdown() {
+      match self.tick().await? {
+        Tick::Performed { outcome, .. } => {
+          stats.processed += 1;
+          stats.failed += u64::from(outcome.is_err());
+        }
+        Tick::Cancelled { .. } => stats.cancelled += 1,
+        Tick::Idle => {
+          let outstanding = self
+            .inner
+            .queue
+            .outstanding()
+            .await
+            .with_context(|| ErrorContext::new().with_queue(self.inner.queue.name()))?;
+          if outstanding == (0, 0) {
+            break;
+          }
+
+          // Operations left in flight by a worker that died would otherwise be waited for forever.
+          if outstanding.1 > 0 {
+            let recovered = self
+              .inner
+              .queue
+              .recover_expired(self.inner.lease)
+              .await
+              .with_context(|| ErrorContext::new().with_queue(self.inner.queue.name()))?;
+            if !recovered.is_empty() {
+              stats.recovered += recovered.len() as u64;
+              continue;
+            }
+          }
+
+          tokio::select! {
+            _ = tokio::time::sleep(self.inner.poll_interval) => {}
+            _ = self.inner.shutdown.wait() => {}
+          }
+        }
+      }
+    }
+
+    stats.duration = started.elapsed();
+    Ok(stats)
+  }
+
+  /// Puts the operations in flight for longer than the lease back on the queue. Errors are logged
+  /// and only delay their recovery until the next lease.
+  async fn recover_expired(&self) {
+    match self.inner.queue.recover_expired(self.inner.lease).await {
+      Ok(recovered) if !recovered.is_empty() => {
+        tracing::warn!(message = "Recovered operations with expired leases", worker_id = %self.worker_id(), count = recovered.len());
+      }
+      Ok(_) => {}
+      Err(error) => {
+        tracing::error!(message = "Failed to recover expired leases", worker_id = %self.worker_id(), %error);
+      }
+    }
+  }
+
+  /// Pulls and performs a single task. Returns `false` when the queue is empty.
+  pub async fn process_one(&self) -> Result<bool, RedisQueueError> {
+    Ok(self.tick().await? != Tick::Idle)
+  }
+
+  /// Pulls and performs a single task, without sleeping or retrying, and tells what happened.
+  /// Lets tests step through the delivery, failure and completion of operations:
+  ///
+  /// ```rust,ignore
+  /// let id = queue.offer(task, &ctx).await?;
+  /// assert!(matches!(worker.tick().await?, Tick::Performed { attempt: 1, .. }));
+  /// assert_eq!(worker.tick().await?, Tick::Idle);
+  /// ```
+  pub async fn tick(&self) -> Result<Tick, RedisQueueError> {
+    let pulled = self
+      .inner
+      .queue
+      .pull(&self.inner.ctx)
+      .await
+      .with_context(|| ErrorContext::new().with_queue(self.inner.queue.name()))?;
+    let message = match pulled {
+      None => return Ok(Tick::Idle),
+      Some(message) => message,
+    };
+
+    let cancellation = ShutdownToken::new();
+    let task_ctx = TaskContext::new(
+      &message.ack_id,
+      T::type_name(),
+      self.inner.queue.name(),
+      message.attempt,
+      &message.user_id,
+    )
+    .with_context(message.context.clone())
+    .with_cancellation(cancellation.clone());
+    let span = task_ctx.logger().clone();
+
+    async {
+      tracing::debug!(message = "Performing task");
+      let performed = message.data.perform(task_ctx.into());
+      let result = until_cancelled(
+        &self.inner.store,
+        &message.ack_id,
+        self.inner.cancel_poll_interval,
+        &cancellation,
+        performed,
+      )
+      .await
+      .map_err(Into::into);
+
+      // The cancellation already completed the operation and took it off the queue.
+      if cancellation.is_shutdown() {
+        tracing::info!(message = "Task cancelled");
+        return Ok(Tick::Cancelled {
+          operation_id: message.ack_id.clone(),
+          attempt: message.attempt,
+        });
+      }
+
+      let outcome = result.as_ref().map(|_| ()).map_err(Status::clone);
+      let failure = match result {
+        Err(error) => {
+          let failure = self.inner.classifier.classify(&error);
+          tracing::warn!(message = "Task failed", failure = failure.as_str());
+          self
+            .inner
+            .queue
+            .fail(
+              &message.ack_id,
+              error,
+              failure,
+              message.attempt,
+              &self.inner.ctx,
+            )
+            .await
+            .with_context(|| self.inner.queue.error_context(&message.ack_id))?;
+          Some(failure)
+        }
+        Ok(output) => {
+          self
+            .inner
+            .queue
+            .complete(&message.ack_id, Ok::<_, Status>(output), &self.inner.ctx)
+            .await
+            .with_context(|| self.inner.queue.error_context(&message.ack_id))?;
+          tracing::debug!(message = "Task completed");
+          None
+        }
+      };
+
+      Ok(Tick::Performed {
+        operation_id: message.ack_id.clone(),
+        attempt: message.attempt,
+        outcome,
+        failure,
+      })
+    }
+    .instrument(span)
+    .await
+  }
+}
+
+/// Default of [`Worker::with_cancel_poll_interval`].
+pub const DEFAULT_CANCEL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default of [`Worker::with_lease`].
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(60 * 60);
+
+/// What a [`Worker::tick`] did.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tick {
+  /// No task was ready.
+  Idle,
+  /// A task was performed. Its operation completed with the outcome, unless the classification
+  /// of its failure put it back on the queue or on the invalid list.
+  Performed {
+    operation_id: String,
+    /// Delivery of the task, starting at 1.
+    attempt: i64,
+    outcome: Result<(), Status>,
+    failure: Option<Failure>,
+  },
+  /// A task was performed while its operation got cancelled. Its result was discarded.
+  Cancelled { operation_id: String, attempt: i64 },
+}
+
+/// Tasks processed by [`Worker::run_until_empty`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DrainStats {
+  /// Tasks performed, failed ones included.
+  pub processed: u64,
+  /// Tasks performed that failed, whether or not they will be retried.
+  pub failed: u64,
+  /// Tasks whose operation got cancelled while they ran.
+  pub cancelled: u64,
+  /// Operations in flight for longer than the lease, put back on the queue.
+  pub recovered: u64,
+  pub duration: Duration,
+}
+
+/// Awaits `performed`, shutting down `cancellation` once the operation `id` is cancelled, as read
+/// from `store` every `interval`. Failing to read the state of the operation only delays its
+/// cancellation.
+pub(crate) async fn until_cancelled<S: TaskStore, F: Future>(
+  store: &S,
+  id: &str,
+  interval: Duration,
+  cancellation: &ShutdownToken,
+  performed: F,
+) -> F::Output
+where
+  S::Error: Display,
+{
+  tokio::pin!(performed);
+
+  loop {
+    tokio::select! {
+      output = &mut performed => return output,
+      _ = tokio::time::sleep(interval), if !cancellation.is_shutdown() => {
+        match store.state(id).await {
+          Ok(Some(OperationState::Cancelling | OperationState::Cancelled)) => {
+            tracing::info!(message = "Operation cancelled, stopping the task");
+            cancellation.shutdown();
+          }
+          Ok(_) => {}
+          Err(error) => {
+            tracing::warn!(message = "Failed to read the state of the operation", %error)
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Delays of a worker failing to pull or complete tasks, e.g. while Redis is down.
+pub(crate) fn error_backoff(poll_interval: Duration) -> Backoff {
+  Backoff::exponential(poll_interval).with_jitter(Jitter::Decorrelated)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use redis::AsyncCommands;
+  use serde::Deserialize;
+  use uuid::Uuid;
+
+  use crate::id::SequentialIdGenerator;
+  use crate::proto::google::protobuf::Empty;
+  use crate::redis::Keys;
+
+  use super::super::admin::RedisAdmin;
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Clone)]
+  struct Task {
+    item: i32,
+  }
+
+  #[async_trait::async_trait]
+  impl Performable for Task {
+    type Error = tonic::Status;
+    type Context = TaskContext;
+    type Output = Empty;
+
+    fn type_name() -> &'static str {
+      "longrunning::worker::tests::Task"
+    }
+
+    async fn perform(&self, ctx: Self::Context) -> Result<Self::Output, Self::Error> {
+      assert_eq!(ctx.task_type(), Self::type_name());
+      assert_eq!(ctx.attempt(), 1);
+      if self.item < 0 {
+        return Err(tonic::Status::invalid_argument("Negative item"));
+      }
+      if self.item == 0 {
+        ctx.cancellation().wait().await;
+        return Err(tonic::Status::cancelled("Stopped"));
+      }
+      Ok(Empty::default())
+    }
+  }
+
+  #[tokio::test]
+  async fn tick_should_report_every_step() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, Uuid::new_v4().to_string(), JsonCodec::new())
+        .with_keys(Keys::new(&format!("{}:", Uuid::new_v4())))
+        .with_id_generator(Arc::new(SequentialIdGenerator::new("op-")));
+    let worker = Worker::new(q.clone(), ctx.clone());
+
+    q.offer(Task { item: 10 }, &ctx).await.unwrap();
+    q.offer(Task { item: -1 }, &ctx).await.unwrap();
+
+    assert_eq!(
+      worker.tick().await.unwrap(),
+      Tick::Performed {
+        operation_id: "op-1".to_string(),
+        attempt: 1,
+        outcome: Ok(()),
+        failure: None,
+      }
+    );
+    assert!(matches!(
+      worker.tick().await.unwrap(),
+      Tick::Performed { operation_id, outcome: Err(status), failure: Some(Failure::Invalid), .. }
+        if operation_id == "op-2" && status.message == "Negative item"
+    ));
+    assert_eq!(worker.tick().await.unwrap(), Tick::Idle);
+  }
+
+  #[tokio::test]
+  async fn tick_should_stop_cancelled_tasks() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", Uuid::new_v4()));
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), Uuid::new_v4().to_string(), JsonCodec::new())
+        .with_keys(keys.clone());
+    let worker =
+      Worker::new(q.clone(), ctx.clone()).with_cancel_poll_interval(Duration::from_millis(10));
+
+    let id = q.offer(Task { item: 0 }, &ctx).await.unwrap();
+    let ticking = tokio::spawn({
+      let worker = worker.clone();
+      async move { worker.tick().await }
+    });
+    while q.state(&id).await.unwrap() != Some(OperationState::Running) {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    RedisAdmin::new(client)
+      .with_keys(keys)
+      .cancel(&id, "Not needed")
+      .await
+      .unwrap();
+
+    let tick = tokio::time::timeout(Duration::from_secs(5), ticking)
+      .await
+      .expect("the task should stop once cancelled")
+      .unwrap()
+      .unwrap();
+    assert_eq!(
+      tick,
+      Tick::Cancelled {
+        operation_id: id.clone(),
+        attempt: 1,
+      }
+    );
+    assert_eq!(q.state(&id).await.unwrap(), Some(OperationState::Cancelled));
+  }
+
+  #[tokio::test]
+  async fn run_until_empty_should_drain_the_queue() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, Uuid::new_v4().to_string(), JsonCodec::new())
+        .with_keys(Keys::new(&format!("{}:", Uuid::new_v4())));
+    let worker = Worker::new(q.clone(), ctx.clone()).with_poll_interval(Duration::from_millis(10));
+
+    for item in [1, 2, -1] {
+      q.offer(Task { item }, &ctx).await.unwrap();
+    }
+
+    let stats = worker.run_until_empty().await.unwrap();
+    assert_eq!((stats.processed, stats.failed, stats.cancelled), (3, 1, 0));
+    assert_eq!(q.outstanding().await.unwrap(), (0, 0));
+    assert_eq!(worker.run_until_empty().await.unwrap().processed, 0);
+  }
+
+  #[tokio::test]
+  async fn run_until_empty_should_recover_abandoned_operations() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, Uuid::new_v4().to_string(), JsonCodec::new())
+        .with_keys(Keys::new(&format!("{}:", Uuid::new_v4())));
+    let worker = Worker::new(q.clone(), ctx.clone())
+      .with_poll_interval(Duration::from_millis(10))
+      .with_lease(Duration::from_millis(50));
+
+    // Pulled by a worker that died before completing it.
+    q.offer(Task { item: 1 }, &ctx).await.unwrap();
+    q.pull(&ctx).await.unwrap().unwrap();
+
+    let stats = tokio::time::timeout(Duration::from_secs(5), worker.run_until_empty())
+      .await
+      .expect("the abandoned operation should be recovered")
+      .unwrap();
+    assert_eq!((stats.processed, stats.recovered), (1, 1));
+    assert_eq!(q.outstanding().await.unwrap(), (0, 0));
+  }
+
+  #[test]
+  fn worker_should_be_configurable_after_being_cloned() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, Uuid::new_v4().to_string(), JsonCodec::new());
+    let worker = Worker::new(q, ctx);
+
+    let configured = worker.clone().with_poll_interval(Duration::from_millis(10));
+    assert_eq!(configured.inner.poll_interval, Duration::from_millis(10));
+    assert_eq!(worker.inner.poll_interval, Duration::from_millis(1000));
+  }
+
+  #[tokio::test]
+  async fn run_should_stop_on_shutdown() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client, Uuid::new_v4().to_string(), JsonCodec::new());
+    let shutdown = ShutdownToken::new();
+    let worker = Worker::new(q, ctx)
+      .with_poll_interval(Duration::from_secs(60))
+      .with_shutdown(shutdown.clone());
+
+    let running = tokio::spawn({
+      let worker = worker.clone();
+      async move { worker.run().await }
+    });
+    shutdown.shutdown();
+
+    tokio::time::timeout(Duration::from_secs(1), running)
+      .await
+      .expect("the worker should stop on shutdown")
+      .unwrap();
+  }
+
+  #[tokio::test]
+  async fn process_one_should_complete_operation() {
+    let ctx = Context::new(Uuid::new_v4().to_string(), String::from("1234"));
+    let queue = Uuid::new_v4().to_string();
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let q: RedisQueue<Task, JsonCodec<Task, Task>> =
+      RedisQueue::new(client.clone(), queue.clone(), JsonCodec::new());
+    let worker = Worker::new(q.clone(), ctx.clone());
+
+    let id = q.offer(Task { item: 10 }, &ctx).await.unwrap();
+
+    assert!(worker.process_one().await.unwrap());
+    assert!(!worker.process_one().await.unwrap());
+
+    let mut conn = client.get_async_connection().await.unwrap();
+    let op: HashMap<String, Vec<u8>> = conn.hgetall(format!("operation:{}", id)).await.unwrap();
+
+    assert_eq!(op["done"], b"true".to_vec());
+    assert!(op.contains_key("result"));
+
+    let in_flight: Vec<String> = conn
+      .lrange(format!("queue:ack:{}", queue), 0, -1)
+      .await
+      .unwrap();
+    assert!(in_flight.is_empty());
+    assert!(op.contains_key("ack_ts"));
+  }
+}
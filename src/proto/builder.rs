@@ -0,0 +1,400 @@
+//! Validating builders for the workspace and IDE messages.
+//!
+//! Producers construct requests through these builders rather than the generated structs, so
+//! invalid names or resource sizes are rejected before they reach a service.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use crate::id::validate_name;
+use crate::id::NameError;
+use crate::proto::workspace::create_workspace_request::SourceTemplate;
+use crate::proto::workspace::CreateIdeRequest;
+use crate::proto::workspace::CreateWorkspaceRequest;
+use crate::proto::workspace::Disk;
+use crate::proto::workspace::Template;
+
+pub const CPU_COUNT: RangeInclusive<i32> = 1..=64;
+
+pub const MEMORY_IN_MB: RangeInclusive<i32> = 512..=262_144;
+
+pub const DISK_SIZE_GB: RangeInclusive<i32> = 1..=16_384;
+
+/// Disk type of the boot disk, which must be mounted at `/`.
+pub const BOOT_DISK_TYPE: &str = "BOOT";
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ValidationError {
+  #[error("Invalid {field}: {reason}")]
+  Field { field: String, reason: String },
+
+  #[error("Invalid {field}: {source}")]
+  Name { field: String, source: NameError },
+
+  #[error("Missing {0}")]
+  Missing(String),
+}
+
+impl ValidationError {
+  pub fn field(&self) -> &str {
+    match self {
+      ValidationError::Field { field, .. } => field,
+      ValidationError::Name { field, .. } => field,
+      ValidationError::Missing(field) => field,
+    }
+  }
+
+  fn field_error(field: &str, reason: String) -> Self {
+    ValidationError::Field {
+      field: field.to_string(),
+      reason,
+    }
+  }
+}
+
+/// Fails with `INVALID_ARGUMENT` carrying a `google.rpc.BadRequest` detail naming the field.
+impl From<ValidationError> for tonic::Status {
+  fn from(error: ValidationError) -> Self {
+    let message = error.to_string();
+    crate::grpc::status::invalid_argument(error.field(), &message)
+  }
+}
+
+fn check_name(field: &str, name: &str) -> Result<(), ValidationError> {
+  validate_name(name).map_err(|source| ValidationError::Name {
+    field: field.to_string(),
+    source,
+  })
+}
+
+fn check_range(
+  field: &str,
+  value: i32,
+  range: &RangeInclusive<i32>,
+) -> Result<(), ValidationError> {
+  if !range.contains(&value) {
+    return Err(ValidationError::field_error(
+      field,
+      format!(
+        "{} is outside of {}..={}",
+        value,
+        range.start(),
+        range.end()
+      ),
+    ));
+  }
+
+  Ok(())
+}
+
+fn check_id(field: &str, id: i64) -> Result<(), ValidationError> {
+  if id <= 0 {
+    return Err(ValidationError::field_error(
+      field,
+      format!("{} is not a valid id", id),
+    ));
+  }
+
+  Ok(())
+}
+
+/// Builds a [`Template`] with validated resources and exactly one boot disk.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateBuilder {
+  template: Template,
+}
+
+impl TemplateBuilder {
+  pub fn new(display_name: &str, location: &str) -> Self {
+    Self {
+      template: Template {
+        display_name: display_name.to_string(),
+        location: location.to_string(),
+        ..Default::default()
+      },
+    }
+  }
+
+  pub fn with_template_id(mut self, template_id: i64) -> Self {
+    self.template.template_id = template_id;
+    self
+  }
+
+  pub fn with_owner_id(mut self, owner_id: i64) -> Self {
+    self.template.owner_id = owner_id;
+    self
+  }
+
+  pub fn with_cpu_count(mut self, cpu_count: i32) -> Self {
+    self.template.cpu_count = cpu_count;
+    self
+  }
+
+  pub fn with_memory_in_mb(mut self, memory_in_mb: i32) -> Self {
+    self.template.memory_in_mb = memory_in_mb;
+    self
+  }
+
+  /// Adds the boot disk, created from `image_url` and mounted at `/`.
+  pub fn with_boot_disk(self, size_gb: i32, image_url: &str) -> Self {
+    self.with_disk(Disk {
+      disk_id: String::default(),
+      mount_point: "/".to_string(),
+      disk_type: BOOT_DISK_TYPE.to_string(),
+      disk_size_gb: size_gb,
+      disk_image_url: image_url.to_string(),
+    })
+  }
+
+  pub fn with_disk(mut self, disk: Disk) -> Self {
+    self.template.disk.push(disk);
+    self
+  }
+
+  pub fn build(self) -> Result<Template, ValidationError> {
+    let template = self.template;
+
+    check_name("display_name", &template.display_name)?;
+    if template.location.is_empty() {
+      return Err(ValidationError::Missing("location".to_string()));
+    }
+    check_range("cpu_count", template.cpu_count, &CPU_COUNT)?;
+    check_range("memory_in_mb", template.memory_in_mb, &MEMORY_IN_MB)?;
+
+    let mut boot_disks = 0;
+    let mut mount_points = Vec::default();
+
+    for (i, disk) in template.disk.iter().enumerate() {
+      let field = format!("disk[{}]", i);
+
+      check_range(
+        &format!("{}.disk_size_gb", field),
+        disk.disk_size_gb,
+        &DISK_SIZE_GB,
+      )?;
+
+      if !disk.mount_point.starts_with('/') {
+        return Err(ValidationError::field_error(
+          &format!("{}.mount_point", field),
+          format!("{:?} is not an absolute path", disk.mount_point),
+        ));
+      }
+
+      if mount_points.contains(&disk.mount_point) {
+        return Err(ValidationError::field_error(
+          &format!("{}.mount_point", field),
+          format!("{} is mounted twice", disk.mount_point),
+        ));
+      }
+      mount_points.push(disk.mount_point.clone());
+
+      if disk.disk_type == BOOT_DISK_TYPE {
+        boot_disks += 1;
+
+        if disk.mount_point != "/" {
+          return Err(ValidationError::field_error(
+            &format!("{}.mount_point", field),
+            "the boot disk must be mounted at /".to_string(),
+          ));
+        }
+
+        if disk.disk_image_url.is_empty() {
+          return Err(ValidationError::Missing(format!(
+            "{}.disk_image_url",
+            field
+          )));
+        }
+      }
+    }
+
+    if boot_disks != 1 {
+      return Err(ValidationError::field_error(
+        "disk",
+        format!("expected exactly one boot disk, found {}", boot_disks),
+      ));
+    }
+
+    Ok(template)
+  }
+}
+
+/// Builds the [`CreateWorkspaceRequest`] describing a new workspace, from either an existing
+/// template instance or a new [`Template`].
+#[derive(Debug, Clone, Default)]
+pub struct WorkspaceSpecBuilder {
+  display_name: String,
+  source: Option<SourceTemplate>,
+  labels: HashMap<String, String>,
+}
+
+impl WorkspaceSpecBuilder {
+  pub fn new(display_name: &str) -> Self {
+    Self {
+      display_name: display_name.to_string(),
+      ..Default::default()
+    }
+  }
+
+  pub fn with_template_instance_id(mut self, template_instance_id: i64) -> Self {
+    self.source = Some(SourceTemplate::TemplateInstanceId(template_instance_id));
+    self
+  }
+
+  /// Creates the workspace from a new template, see [`TemplateBuilder`].
+  pub fn with_template(mut self, template: Template) -> Self {
+    self.source = Some(SourceTemplate::NewTemplate(template));
+    self
+  }
+
+  pub fn with_label(mut self, key: &str, value: &str) -> Self {
+    self.labels.insert(key.to_string(), value.to_string());
+    self
+  }
+
+  pub fn build(self) -> Result<CreateWorkspaceRequest, ValidationError> {
+    check_name("display_name", &self.display_name)?;
+
+    match &self.source {
+      None => return Err(ValidationError::Missing("source_template".to_string())),
+      Some(SourceTemplate::TemplateInstanceId(id)) => check_id("template_instance_id", *id)?,
+      Some(SourceTemplate::NewTemplate(template)) => {
+        TemplateBuilder {
+          template: template.clone(),
+        }
+        .build()?;
+      }
+    }
+
+    for key in self.labels.keys() {
+      check_name(&format!("labels[{}]", key), key)?;
+    }
+
+    Ok(CreateWorkspaceRequest {
+      source_template: self.source,
+      display_name: self.display_name,
+      labels: self.labels,
+    })
+  }
+}
+
+/// Builds a [`CreateIdeRequest`].
+#[derive(Debug, Clone, Default)]
+pub struct CreateIdeBuilder {
+  workspace_id: i64,
+  args: Vec<String>,
+}
+
+impl CreateIdeBuilder {
+  pub fn new(workspace_id: i64) -> Self {
+    Self {
+      workspace_id,
+      args: Vec::default(),
+    }
+  }
+
+  pub fn with_arg(mut self, arg: &str) -> Self {
+    self.args.push(arg.to_string());
+    self
+  }
+
+  pub fn build(self) -> Result<CreateIdeRequest, ValidationError> {
+    check_id("workspace_id", self.workspace_id)?;
+
+    if let Some(i) = self.args.iter().position(|arg| arg.contains('\0')) {
+      return Err(ValidationError::field_error(
+        &format!("args[{}]", i),
+        "contains a NUL character".to_string(),
+      ));
+    }
+
+    Ok(CreateIdeRequest {
+      workspace_id: self.workspace_id,
+      args: self.args,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use prost::Message;
+
+  use crate::proto::google::rpc::BadRequest;
+  use crate::proto::google::rpc::Status;
+
+  use super::*;
+
+  fn template() -> TemplateBuilder {
+    TemplateBuilder::new("python", "eu-1")
+      .with_cpu_count(2)
+      .with_memory_in_mb(4096)
+      .with_boot_disk(20, "gs://images/python")
+  }
+
+  #[test]
+  fn template_builder_should_validate_resources() {
+    assert!(template().build().is_ok());
+
+    let error = template().with_cpu_count(0).build().unwrap_err();
+    assert_eq!(error.field(), "cpu_count");
+
+    let error = TemplateBuilder::new("python", "eu-1")
+      .with_cpu_count(2)
+      .with_memory_in_mb(4096)
+      .build()
+      .unwrap_err();
+    assert_eq!(error.field(), "disk");
+
+    let error = template()
+      .with_boot_disk(20, "gs://images/other")
+      .build()
+      .unwrap_err();
+    assert_eq!(error.field(), "disk[1].mount_point");
+  }
+
+  #[test]
+  fn workspace_spec_builder_should_validate_name_and_source() {
+    let request = WorkspaceSpecBuilder::new("my-workspace")
+      .with_template(template().build().unwrap())
+      .with_label("team", "core")
+      .build()
+      .unwrap();
+    assert!(matches!(
+      request.source_template,
+      Some(SourceTemplate::NewTemplate(_))
+    ));
+
+    let error = WorkspaceSpecBuilder::new("My Workspace")
+      .with_template_instance_id(1)
+      .build()
+      .unwrap_err();
+    assert_eq!(
+      error,
+      ValidationError::Name {
+        field: "display_name".to_string(),
+        source: NameError::InvalidStart,
+      }
+    );
+
+    let status = tonic::Status::from(
+      WorkspaceSpecBuilder::new("my-workspace")
+        .build()
+        .unwrap_err(),
+    );
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    let details = Status::decode(status.details()).unwrap();
+    let bad_request = BadRequest::decode(details.details[0].value.as_slice()).unwrap();
+    assert_eq!(bad_request.field_violations[0].field, "source_template");
+  }
+
+  #[test]
+  fn create_ide_builder_should_require_workspace() {
+    assert!(CreateIdeBuilder::new(1)
+      .with_arg("--port=8080")
+      .build()
+      .is_ok());
+    assert_eq!(
+      CreateIdeBuilder::new(0).build().unwrap_err().field(),
+      "workspace_id"
+    );
+  }
+}
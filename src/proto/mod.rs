@@ -5,9 +5,11 @@ pub use self::rappel::rpc;
 pub use self::rappel::system;
 pub use self::rappel::workspace;
 
+pub mod builder;
 pub mod prelude;
 
 pub mod google {
+  #[allow(clippy::doc_lazy_continuation)]
   pub mod protobuf {
     tonic::include_proto!("google.protobuf");
   }
@@ -1,13 +1,137 @@
 pub use crate::proto::google::protobuf::Timestamp;
+use std::cmp::Ordering;
+use std::num::ParseIntError;
+use std::ops::Add;
 use std::ops::Deref;
+use std::ops::Sub;
+use std::str::FromStr;
+use std::time::Duration;
 
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+/// A [`Timestamp`] with ordering and arithmetic. Timestamps are compared after normalization,
+/// so out of range `nanos` do not break the ordering.
 #[derive(Clone, Debug)]
 pub struct ProstTimestamp(pub Timestamp);
 
 impl ProstTimestamp {
+  pub fn now() -> Self {
+    chrono::Utc::now().into()
+  }
+
+  /// Converts nanoseconds since the Unix epoch, as stored in Redis.
+  pub fn from_nanos(nanos: i64) -> Self {
+    Self(Timestamp {
+      seconds: nanos.div_euclid(NANOS_PER_SECOND),
+      nanos: nanos.rem_euclid(NANOS_PER_SECOND) as i32,
+    })
+  }
+
+  /// Returns the nanoseconds since the Unix epoch, saturating outside of the `i64` range.
+  pub fn to_nanos(&self) -> i64 {
+    let nanos = self.0.seconds as i128 * NANOS_PER_SECOND as i128 + self.0.nanos as i128;
+    nanos.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+  }
+
+  /// Returns the time passed since the timestamp, zero if it lies in the future.
+  pub fn elapsed(&self) -> Duration {
+    (Self::now() - self.clone()).to_std().unwrap_or_default()
+  }
+
   pub fn into_inner(self) -> Timestamp {
     self.0
   }
+
+  fn normalized(&self) -> (i64, i32) {
+    let seconds = self
+      .0
+      .seconds
+      .saturating_add((self.0.nanos as i64).div_euclid(NANOS_PER_SECOND));
+    (
+      seconds,
+      (self.0.nanos as i64).rem_euclid(NANOS_PER_SECOND) as i32,
+    )
+  }
+}
+
+impl PartialEq for ProstTimestamp {
+  fn eq(&self, other: &Self) -> bool {
+    self.normalized() == other.normalized()
+  }
+}
+
+impl Eq for ProstTimestamp {}
+
+impl PartialOrd for ProstTimestamp {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for ProstTimestamp {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self.normalized().cmp(&other.normalized())
+  }
+}
+
+impl Add<Duration> for ProstTimestamp {
+  type Output = Self;
+
+  fn add(self, duration: Duration) -> Self::Output {
+    let (seconds, nanos) = self.normalized();
+    let nanos = nanos as i64 + duration.subsec_nanos() as i64;
+
+    Self(Timestamp {
+      seconds: seconds
+        .saturating_add(duration.as_secs().min(i64::MAX as u64) as i64)
+        .saturating_add(nanos / NANOS_PER_SECOND),
+      nanos: (nanos % NANOS_PER_SECOND) as i32,
+    })
+  }
+}
+
+impl Sub<Duration> for ProstTimestamp {
+  type Output = Self;
+
+  fn sub(self, duration: Duration) -> Self::Output {
+    let (seconds, nanos) = self.normalized();
+    let nanos = nanos as i64 - duration.subsec_nanos() as i64;
+
+    Self(Timestamp {
+      seconds: seconds
+        .saturating_sub(duration.as_secs().min(i64::MAX as u64) as i64)
+        .saturating_add(nanos.div_euclid(NANOS_PER_SECOND)),
+      nanos: nanos.rem_euclid(NANOS_PER_SECOND) as i32,
+    })
+  }
+}
+
+/// The signed time between two timestamps.
+impl Sub for ProstTimestamp {
+  type Output = chrono::Duration;
+
+  fn sub(self, other: Self) -> Self::Output {
+    let (seconds, nanos) = self.normalized();
+    let (other_seconds, other_nanos) = other.normalized();
+
+    chrono::Duration::seconds(seconds.saturating_sub(other_seconds))
+      + chrono::Duration::nanoseconds(nanos as i64 - other_nanos as i64)
+  }
+}
+
+/// Parses nanoseconds since the Unix epoch, see [`ProstTimestamp::from_nanos`].
+impl FromStr for ProstTimestamp {
+  type Err = ParseIntError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    s.trim().parse().map(Self::from_nanos)
+  }
+}
+
+impl From<Timestamp> for ProstTimestamp {
+  fn from(timestamp: Timestamp) -> Self {
+    Self(timestamp)
+  }
 }
 
 impl Deref for ProstTimestamp {
@@ -31,3 +155,52 @@ impl From<()> for super::google::protobuf::Empty {
     Self::default()
   }
 }
+
+impl From<tonic::Status> for super::google::rpc::Status {
+  fn from(status: tonic::Status) -> Self {
+    Self {
+      code: status.code() as i32,
+      message: status.message().to_string(),
+      details: Vec::default(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn from_nanos_should_round_trip_and_order() {
+    let ts = ProstTimestamp::from_nanos(1_500_000_000);
+    assert_eq!((ts.seconds, ts.nanos), (1, 500_000_000));
+    assert_eq!(ts.to_nanos(), 1_500_000_000);
+
+    let before_epoch = ProstTimestamp::from_nanos(-1);
+    assert_eq!(
+      (before_epoch.seconds, before_epoch.nanos),
+      (-1, 999_999_999)
+    );
+    assert!(before_epoch < ts);
+
+    let denormalized = ProstTimestamp(Timestamp {
+      seconds: 0,
+      nanos: 1_500_000_000,
+    });
+    assert_eq!(denormalized, ts);
+
+    assert!("not a number".parse::<ProstTimestamp>().is_err());
+    assert_eq!("42".parse::<ProstTimestamp>().unwrap().to_nanos(), 42);
+  }
+
+  #[test]
+  fn arithmetic_should_carry_nanos() {
+    let ts = ProstTimestamp::from_nanos(1_900_000_000);
+
+    let later = ts.clone() + Duration::from_millis(200);
+    assert_eq!(later.to_nanos(), 2_100_000_000);
+    assert_eq!((later.clone() - Duration::from_millis(200)), ts);
+    assert_eq!(later - ts.clone(), chrono::Duration::milliseconds(200));
+    assert!(ts.elapsed() > Duration::from_secs(1));
+  }
+}
@@ -0,0 +1,429 @@
+//! Per-organization quotas backed by Redis counters.
+//!
+//! Usage is kept in the hash `quota:usage:{subject}` and per-subject limit overrides in
+//! `quota:limits:{subject}`, both keyed by resource name. A subject is e.g.
+//! `organization:{organization_id}`, see [`organization`]. Both are stored under the prefix of
+//! the [`Keys`] the quota is configured with.
+//!
+//! Request rates are counted in one second windows in `quota:rate:{subject}:{resource}`, see
+//! [`RedisRateLimiter`].
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tracing_futures::Instrument;
+
+pub use crate::grpc::status::quota_exceeded;
+use crate::grpc::status::StatusBuilder;
+use crate::redis::Keys;
+use crate::util::redis_exec;
+use crate::util::redis_exec::InstrumentedConnection;
+
+/// Workspaces owned by the subject.
+pub const WORKSPACES: &str = "workspaces";
+
+/// Operations of the subject that did not complete yet.
+pub const CONCURRENT_OPERATIONS: &str = "concurrent_operations";
+
+/// Field of the operation hash holding the subject its [`CONCURRENT_OPERATIONS`] slot is
+/// reserved for, until [`release_operation`] frees it.
+pub const OPERATION_SUBJECT_FIELD: &str = "quota_subject";
+
+const RESERVE_SCRIPT: &str = r"
+local limit = tonumber(redis.call('HGET', KEYS[2], ARGV[1]) or ARGV[3])
+local usage = tonumber(redis.call('HGET', KEYS[1], ARGV[1]) or '0')
+local amount = tonumber(ARGV[2])
+if limit >= 0 and usage + amount > limit then
+  return {0, usage, limit}
+end
+redis.call('HINCRBY', KEYS[1], ARGV[1], amount)
+return {1, usage + amount, limit}
+";
+
+const RELEASE_SCRIPT: &str = r"
+local usage = redis.call('HINCRBY', KEYS[1], ARGV[1], -tonumber(ARGV[2]))
+if usage < 0 then
+  redis.call('HSET', KEYS[1], ARGV[1], 0)
+end
+return 0
+";
+
+const RELEASE_OPERATION_SCRIPT: &str = r"
+local subject = redis.call('HGET', KEYS[1], ARGV[1])
+if not subject then
+  return 0
+end
+redis.call('HDEL', KEYS[1], ARGV[1])
+local key = ARGV[3] .. 'quota:usage:' .. subject
+local usage = redis.call('HINCRBY', key, ARGV[2], -1)
+if usage < 0 then
+  redis.call('HSET', key, ARGV[2], 0)
+end
+return 1
+";
+
+/// Counts a request in the current window of a rate, returning the milliseconds until the window
+/// ends when the request exceeds the limit and 0 otherwise.
+///
+/// KEYS: window counter. ARGV: window in milliseconds, requests allowed per window.
+const RATE_SCRIPT: &str = r"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+  redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+if count > tonumber(ARGV[2]) then
+  return math.max(redis.call('PTTL', KEYS[1]), 1)
+end
+return 0
+";
+
+/// Window in which the requests counted by [`RedisRateLimiter`] are limited.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaError {
+  #[error("Quota {resource} of {subject} exceeded: {usage} of {limit} in use")]
+  Exceeded {
+    subject: String,
+    resource: String,
+    usage: i64,
+    limit: i64,
+  },
+
+  #[error("Rate of {resource} of {subject} exceeded, retry after {retry_after:?}")]
+  RateLimited {
+    subject: String,
+    resource: String,
+    retry_after: Duration,
+  },
+
+  #[error("Redis command failed: {0}")]
+  Redis(#[from] redis::RedisError),
+}
+
+/// Fails with `RESOURCE_EXHAUSTED` carrying a `google.rpc.QuotaFailure` detail when the quota is
+/// exceeded.
+impl From<QuotaError> for tonic::Status {
+  fn from(error: QuotaError) -> Self {
+    match &error {
+      QuotaError::Exceeded {
+        subject, resource, ..
+      } => quota_exceeded(subject, &format!("{} quota exceeded", resource)),
+      QuotaError::RateLimited {
+        subject,
+        resource,
+        retry_after,
+      } => {
+        let description = format!("{} rate exceeded", resource);
+        StatusBuilder::new(tonic::Code::ResourceExhausted, &description)
+          .with_quota_violation(subject, &description)
+          .with_retry_delay(*retry_after)
+          .build()
+      }
+      QuotaError::Redis(_) => tonic::Status::internal(error.to_string()),
+    }
+  }
+}
+
+/// Returns the quota subject of an organization.
+pub fn organization(organization_id: &str) -> String {
+  format!("organization:{}", organization_id)
+}
+
+/// Reserves and releases quota for subjects. Limits default to the ones configured with
+/// [`RedisQuota::with_limit`] and can be overridden per subject with [`RedisQuota::set_limit`].
+/// Resources without a limit are not restricted.
+#[derive(Clone, Debug)]
+pub struct RedisQuota {
+  client: redis::Client,
+  limits: HashMap<String, i64>,
+  keys: Keys,
+}
+
+impl RedisQuota {
+  pub fn new(client: redis::Client) -> Self {
+    Self {
+      client,
+      limits: HashMap::default(),
+      keys: Keys::default(),
+    }
+  }
+
+  /// Stores usage and limits under the prefix of `keys`.
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Sets the default limit of `resource`.
+  pub fn with_limit(mut self, resource: &str, limit: i64) -> Self {
+    self.limits.insert(resource.to_string(), limit);
+    self
+  }
+
+  /// Overrides the limit of `resource` for `subject`.
+  pub async fn set_limit(
+    &self,
+    subject: &str,
+    resource: &str,
+    limit: i64,
+  ) -> Result<(), QuotaError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = redis::cmd("HSET")
+      .arg(self.limits_key(subject))
+      .arg(resource)
+      .arg(limit)
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-quota-set-limit", %subject, %resource))
+      .await?;
+
+    Ok(())
+  }
+
+  pub async fn usage(&self, subject: &str, resource: &str) -> Result<i64, QuotaError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let usage: Option<i64> = redis::cmd("HGET")
+      .arg(self.usage_key(subject))
+      .arg(resource)
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-quota-usage", %subject, %resource))
+      .await?;
+
+    Ok(usage.unwrap_or_default())
+  }
+
+  /// Reserves `amount` of `resource` for `subject`, or fails with [`QuotaError::Exceeded`]
+  /// without reserving anything if that would exceed its limit.
+  pub async fn check_and_reserve(
+    &self,
+    subject: &str,
+    resource: &str,
+    amount: i64,
+  ) -> Result<(), QuotaError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+    let default_limit = self.default_limit(resource);
+
+    let (reserved, usage, limit): (bool, i64, i64) = redis::Script::new(RESERVE_SCRIPT)
+      .key(self.usage_key(subject))
+      .key(self.limits_key(subject))
+      .arg(resource)
+      .arg(amount)
+      .arg(default_limit)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-quota-reserve", %subject, %resource))
+      .await?;
+
+    if !reserved {
+      return Err(QuotaError::Exceeded {
+        subject: subject.to_string(),
+        resource: resource.to_string(),
+        usage,
+        limit,
+      });
+    }
+
+    Ok(())
+  }
+
+  /// Limit of `resource` for the subjects without one of their own, -1 when unlimited.
+  pub(crate) fn default_limit(&self, resource: &str) -> i64 {
+    self.limits.get(resource).copied().unwrap_or(-1)
+  }
+
+  pub(crate) fn usage_key(&self, subject: &str) -> String {
+    self.keys.key(&format!("quota:usage:{}", subject))
+  }
+
+  pub(crate) fn limits_key(&self, subject: &str) -> String {
+    self.keys.key(&format!("quota:limits:{}", subject))
+  }
+
+  /// Releases `amount` of `resource` reserved for `subject`. Usage never drops below zero.
+  pub async fn release(
+    &self,
+    subject: &str,
+    resource: &str,
+    amount: i64,
+  ) -> Result<(), QuotaError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: i64 = redis::Script::new(RELEASE_SCRIPT)
+      .key(self.usage_key(subject))
+      .arg(resource)
+      .arg(amount)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-quota-release", %subject, %resource))
+      .await?;
+
+    Ok(())
+  }
+}
+
+/// Limits the rate of requests of subjects, e.g. the RPCs of each user, in fixed one second
+/// windows shared by every process using the same Redis.
+#[derive(Clone, Debug)]
+pub struct RedisRateLimiter {
+  client: redis::Client,
+  keys: Keys,
+}
+
+impl RedisRateLimiter {
+  pub fn new(client: redis::Client) -> Self {
+    Self {
+      client,
+      keys: Keys::default(),
+    }
+  }
+
+  /// Stores the counters under the prefix of `keys`.
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Counts a request of `subject` to `resource`, or fails with [`QuotaError::RateLimited`] when
+  /// `subject` already made `per_second` of them in the current window.
+  pub async fn acquire(
+    &self,
+    subject: &str,
+    resource: &str,
+    per_second: u32,
+  ) -> Result<(), QuotaError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let retry_after_ms: u64 = redis::Script::new(RATE_SCRIPT)
+      .key(
+        self
+          .keys
+          .key(&format!("quota:rate:{}:{}", subject, resource)),
+      )
+      .arg(RATE_WINDOW.as_millis() as u64)
+      .arg(per_second)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-quota-rate", %subject, %resource))
+      .await?;
+
+    if retry_after_ms > 0 {
+      return Err(QuotaError::RateLimited {
+        subject: subject.to_string(),
+        resource: resource.to_string(),
+        retry_after: Duration::from_millis(retry_after_ms),
+      });
+    }
+
+    Ok(())
+  }
+}
+
+/// Releases the [`CONCURRENT_OPERATIONS`] slot reserved for the operation `id`, if any. Returns
+/// whether a slot was released; releasing twice is a no-op.
+pub async fn release_operation(
+  conn: &mut InstrumentedConnection,
+  keys: &Keys,
+  id: &str,
+) -> Result<bool, redis::RedisError> {
+  redis::Script::new(RELEASE_OPERATION_SCRIPT)
+    .key(keys.operation(id))
+    .arg(OPERATION_SUBJECT_FIELD)
+    .arg(CONCURRENT_OPERATIONS)
+    .arg(keys.prefix())
+    .invoke_async(conn)
+    .instrument(tracing::info_span!("redis-quota-release-operation", operation_id = %id))
+    .await
+}
+
+/// Adds the release of [`release_operation`] to `pipe`, to release the quota of many operations
+/// in one round trip.
+pub(crate) fn release_operation_in(pipe: &mut redis::Pipeline, keys: &Keys, id: &str) {
+  pipe
+    .cmd("EVAL")
+    .arg(RELEASE_OPERATION_SCRIPT)
+    .arg(1)
+    .arg(keys.operation(id))
+    .arg(OPERATION_SUBJECT_FIELD)
+    .arg(CONCURRENT_OPERATIONS)
+    .arg(keys.prefix())
+    .ignore();
+}
+
+#[cfg(test)]
+mod tests {
+  use prost::Message;
+
+  use crate::grpc::status::StatusExt;
+  use crate::proto::google::rpc::QuotaFailure;
+  use crate::proto::google::rpc::Status;
+
+  use super::*;
+
+  #[test]
+  fn quota_exceeded_should_carry_quota_failure() {
+    let status = quota_exceeded("organization:1", "workspaces quota exceeded");
+
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+    let details = Status::decode(status.details()).unwrap();
+    assert_eq!(
+      details.details[0].type_url,
+      "type.googleapis.com/google.rpc.QuotaFailure"
+    );
+
+    let failure = QuotaFailure::decode(details.details[0].value.as_slice()).unwrap();
+    assert_eq!(failure.violations[0].subject, "organization:1");
+  }
+
+  #[tokio::test]
+  async fn acquire_should_limit_the_rate() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let limiter = RedisRateLimiter::new(client);
+    let subject = organization(&uuid::Uuid::new_v4().to_string());
+
+    limiter.acquire(&subject, "Export", 2).await.unwrap();
+    limiter.acquire(&subject, "Export", 2).await.unwrap();
+    let error = limiter.acquire(&subject, "Export", 2).await.unwrap_err();
+    assert!(matches!(
+      &error,
+      QuotaError::RateLimited { retry_after, .. } if *retry_after <= RATE_WINDOW
+    ));
+    limiter.acquire(&subject, "Import", 2).await.unwrap();
+
+    let status = tonic::Status::from(error);
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert!(status.retry_delay().is_some());
+  }
+
+  #[tokio::test]
+  async fn check_and_reserve_should_enforce_limit() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let quota = RedisQuota::new(client).with_limit(WORKSPACES, 2);
+    let subject = organization(&uuid::Uuid::new_v4().to_string());
+
+    quota
+      .check_and_reserve(&subject, WORKSPACES, 2)
+      .await
+      .unwrap();
+    assert!(matches!(
+      quota.check_and_reserve(&subject, WORKSPACES, 1).await,
+      Err(QuotaError::Exceeded {
+        usage: 2,
+        limit: 2,
+        ..
+      })
+    ));
+
+    quota.release(&subject, WORKSPACES, 1).await.unwrap();
+    quota
+      .check_and_reserve(&subject, WORKSPACES, 1)
+      .await
+      .unwrap();
+
+    quota.set_limit(&subject, WORKSPACES, 3).await.unwrap();
+    quota
+      .check_and_reserve(&subject, WORKSPACES, 1)
+      .await
+      .unwrap();
+    assert_eq!(quota.usage(&subject, WORKSPACES).await.unwrap(), 3);
+  }
+}
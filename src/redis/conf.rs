@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::util::redis_exec;
+use crate::util::redis_exec::InstrumentedConnection;
+
+use super::Keys;
+
+/// What a Redis connection is used for. Each role may be served by its own Redis, e.g. to keep
+/// cache evictions away from the queues.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedisRole {
+  /// Queues, operations and quotas. Must not evict keys.
+  Queue,
+  /// Data that can be rebuilt, e.g. shard maps.
+  Cache,
+  /// Pub/sub channels, e.g. operation events.
+  PubSub,
+  /// Read-only replica of the queue Redis, serving the operation reads that tolerate replication
+  /// lag, see [`crate::longrunning::store::RedisTaskStore::with_replica`]. The queue connection
+  /// when unset.
+  Replica,
+}
+
+impl RedisRole {
+  pub const ALL: [RedisRole; 4] = [
+    RedisRole::Queue,
+    RedisRole::Cache,
+    RedisRole::PubSub,
+    RedisRole::Replica,
+  ];
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      RedisRole::Queue => "queue",
+      RedisRole::Cache => "cache",
+      RedisRole::PubSub => "pubsub",
+      RedisRole::Replica => "replica",
+    }
+  }
+}
+
+impl fmt::Display for RedisRole {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+impl FromStr for RedisRole {
+  type Err = RedisConfError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    RedisRole::ALL
+      .into_iter()
+      .find(|role| role.as_str() == s)
+      .ok_or_else(|| RedisConfError::UnknownRole(s.to_string()))
+  }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedisConfError {
+  #[error("Unknown Redis role {0}")]
+  UnknownRole(String),
+
+  #[error("Invalid Redis connection for {role}: {source}")]
+  Connection {
+    role: String,
+    source: redis::RedisError,
+  },
+}
+
+/// A Redis connection. The database of `url` can be overridden with `database`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConnectionConf {
+  pub url: String,
+
+  #[serde(default)]
+  pub database: Option<i64>,
+
+  /// Time allowed to open a connection, unlimited when unset.
+  #[serde(default)]
+  pub connect_timeout_ms: Option<u64>,
+}
+
+impl ConnectionConf {
+  pub fn new(url: &str) -> Self {
+    Self {
+      url: url.to_string(),
+      database: None,
+      connect_timeout_ms: None,
+    }
+  }
+
+  fn open(&self) -> redis::RedisResult<redis::Client> {
+    let mut info = redis::IntoConnectionInfo::into_connection_info(self.url.as_str())?;
+    if let Some(database) = self.database {
+      info.redis.db = database;
+    }
+
+    redis::Client::open(info)
+  }
+}
+
+/// Redis configuration of a process, usually loaded from a file. Roles without a connection of
+/// their own use the default one, except the replica which uses the queue one.
+///
+/// ```yaml
+/// url: redis://queues/
+/// key_prefix: "rappel:production:"
+/// connections:
+///   cache:
+///     url: redis://cache/
+///     database: 2
+///   pubsub:
+///     url: redis://events/
+///     connect_timeout_ms: 500
+///   replica:
+///     url: redis://queues-replica/
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedisConf {
+  #[serde(flatten)]
+  pub default: ConnectionConf,
+
+  /// Prefix of every key, see [`Keys`].
+  #[serde(default)]
+  pub key_prefix: String,
+
+  #[serde(default)]
+  pub connections: HashMap<RedisRole, ConnectionConf>,
+}
+
+impl RedisConf {
+  pub fn new(url: &str) -> Self {
+    Self {
+      default: ConnectionConf::new(url),
+      key_prefix: String::default(),
+      connections: HashMap::default(),
+    }
+  }
+
+  pub fn with_key_prefix(mut self, key_prefix: &str) -> Self {
+    self.key_prefix = key_prefix.to_string();
+    self
+  }
+
+  /// Serves `role` from its own connection.
+  pub fn with_connection(mut self, role: RedisRole, connection: ConnectionConf) -> Self {
+    self.connections.insert(role, connection);
+    self
+  }
+
+  /// Returns the connection serving `role`.
+  pub fn connection(&self, role: RedisRole) -> &ConnectionConf {
+    match (self.connections.get(&role), role) {
+      (Some(connection), _) => connection,
+      (None, RedisRole::Replica) => self.connection(RedisRole::Queue),
+      (None, _) => &self.default,
+    }
+  }
+}
+
+/// Hands out the Redis client of each [`RedisRole`], so components ask for the connection they
+/// need instead of sharing a single client.
+///
+/// ```rust,ignore
+/// let registry = RedisRegistry::new(&conf)?;
+/// let broker = RedisBroker::<Backup>::from_registry(&registry, "backups");
+/// let shard_maps = locator.with_shard_maps(registry.client(RedisRole::Cache));
+/// ```
+#[derive(Clone, Debug)]
+pub struct RedisRegistry {
+  clients: HashMap<RedisRole, (redis::Client, Option<Duration>)>,
+  keys: Keys,
+}
+
+impl RedisRegistry {
+  /// Validates the connection of every role. No connection is opened yet.
+  pub fn new(conf: &RedisConf) -> Result<Self, RedisConfError> {
+    let mut clients = HashMap::default();
+
+    for role in RedisRole::ALL {
+      let connection = conf.connection(role);
+      let client = connection
+        .open()
+        .map_err(|source| RedisConfError::Connection {
+          role: role.to_string(),
+          source,
+        })?;
+      let timeout = connection.connect_timeout_ms.map(Duration::from_millis);
+
+      clients.insert(role, (client, timeout));
+    }
+
+    Ok(Self {
+      clients,
+      keys: Keys::new(&conf.key_prefix),
+    })
+  }
+
+  pub fn client(&self, role: RedisRole) -> redis::Client {
+    self.clients[&role].0.clone()
+  }
+
+  pub fn keys(&self) -> &Keys {
+    &self.keys
+  }
+
+  /// Opens a connection for `role`, within its `connect_timeout_ms`.
+  pub async fn connection(&self, role: RedisRole) -> redis::RedisResult<InstrumentedConnection> {
+    let (client, timeout) = &self.clients[&role];
+
+    match timeout {
+      None => redis_exec::connect(client).await,
+      Some(timeout) => tokio::time::timeout(*timeout, redis_exec::connect(client))
+        .await
+        .map_err(|_| {
+          redis::RedisError::from((
+            redis::ErrorKind::IoError,
+            "Connection timed out",
+            role.to_string(),
+          ))
+        })?,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn registry_should_fall_back_to_default_connection() {
+    let conf: RedisConf = serde_json::from_value(serde_json::json!({
+      "url": "redis://queues/",
+      "key_prefix": "rappel:test:",
+      "connections": {
+        "cache": { "url": "redis://cache/", "database": 2 },
+      },
+    }))
+    .unwrap();
+
+    assert_eq!(conf.connection(RedisRole::PubSub).url, "redis://queues/");
+
+    let registry = RedisRegistry::new(&conf).unwrap();
+    let cache = registry
+      .client(RedisRole::Cache)
+      .get_connection_info()
+      .clone();
+    assert_eq!(cache.redis.db, 2);
+    assert_eq!(registry.keys().prefix(), "rappel:test:");
+
+    let invalid = RedisConf::new("redis://queues/")
+      .with_connection(RedisRole::Cache, ConnectionConf::new("not a url"));
+    assert!(matches!(
+      RedisRegistry::new(&invalid),
+      Err(RedisConfError::Connection { role, .. }) if role == "cache"
+    ));
+    assert!("pubsub".parse::<RedisRole>().is_ok());
+  }
+
+  #[test]
+  fn replica_should_fall_back_to_queue_connection() {
+    let conf = RedisConf::new("redis://default/")
+      .with_connection(RedisRole::Queue, ConnectionConf::new("redis://queues/"));
+    assert_eq!(conf.connection(RedisRole::Replica).url, "redis://queues/");
+
+    let conf = conf.with_connection(RedisRole::Replica, ConnectionConf::new("redis://replica/"));
+    assert_eq!(conf.connection(RedisRole::Replica).url, "redis://replica/");
+    assert_eq!(conf.connection(RedisRole::Queue).url, "redis://queues/");
+  }
+}
@@ -1,11 +1,213 @@
-use redis::FromRedisValue;
-pub use redis::RedisError;
-use redis::RedisResult;
-use redis::RedisWrite;
-use redis::ToRedisArgs;
-use redis::Value;
 pub use redis::*;
 
+mod conf;
+mod session;
+
+pub use conf::ConnectionConf;
+pub use conf::RedisConf;
+pub use conf::RedisConfError;
+pub use conf::RedisRegistry;
+pub use conf::RedisRole;
+pub use session::SessionError;
+pub use session::SessionStore;
+
+/// Builds the Redis keys used by rappel. Every key starts with the same prefix, e.g.
+/// `rappel:staging:`, so several environments can share one Redis without colliding. The default
+/// prefix is empty, which keeps the historical key names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keys {
+  prefix: String,
+}
+
+impl Keys {
+  pub fn new(prefix: &str) -> Self {
+    Self {
+      prefix: prefix.to_string(),
+    }
+  }
+
+  /// Uses the `rappel:{environment}:` prefix.
+  pub fn for_environment(environment: &str) -> Self {
+    Self::new(&format!("rappel:{}:", environment))
+  }
+
+  pub fn prefix(&self) -> &str {
+    &self.prefix
+  }
+
+  /// Prefixes an arbitrary key.
+  pub fn key(&self, key: &str) -> String {
+    format!("{}{}", self.prefix, key)
+  }
+
+  /// Returns `key` without the prefix, `None` if it does not start with it.
+  pub fn strip<'a>(&self, key: &'a str) -> Option<&'a str> {
+    key.strip_prefix(self.prefix.as_str())
+  }
+
+  /// List of the operation ids waiting in `queue`.
+  pub fn queue(&self, queue: &str) -> String {
+    format!("{}queue:{}", self.prefix, queue)
+  }
+
+  /// List of the operation ids of interactive operations of `queue`, pulled before the ones of
+  /// [`Keys::queue`].
+  pub fn interactive(&self, queue: &str) -> String {
+    format!("{}queue:interactive:{}", self.prefix, queue)
+  }
+
+  /// List of the operation ids of `queue` pulled but not acknowledged yet.
+  pub fn ack(&self, queue: &str) -> String {
+    format!("{}queue:ack:{}", self.prefix, queue)
+  }
+
+  pub fn invalid(&self, queue: &str) -> String {
+    format!("{}queue:invalid:{}", self.prefix, queue)
+  }
+
+  pub fn quarantine(&self, queue: &str) -> String {
+    format!("{}queue:quarantine:{}", self.prefix, queue)
+  }
+
+  /// Sorted set of the operation ids of `queue` waiting to be redelivered, scored by the epoch
+  /// milliseconds they are due at.
+  pub fn delayed(&self, queue: &str) -> String {
+    format!("{}queue:delayed:{}", self.prefix, queue)
+  }
+
+  pub fn paused(&self, queue: &str) -> String {
+    format!("{}queue:paused:{}", self.prefix, queue)
+  }
+
+  /// Lease of the single consumer of the ordered partition `queue`, see
+  /// [`crate::longrunning::ordered::OrderedQueue`].
+  pub fn partition_lease(&self, queue: &str) -> String {
+    format!("{}queue:lease:{}", self.prefix, queue)
+  }
+
+  pub fn replication(&self, queue: &str) -> String {
+    format!("{}replication:{}", self.prefix, queue)
+  }
+
+  pub fn replication_ack(&self, queue: &str) -> String {
+    format!("{}replication:ack:{}", self.prefix, queue)
+  }
+
+  /// Hash holding the payload of the quarantined operation `id`.
+  pub fn quarantined(&self, id: &str) -> String {
+    format!("{}quarantine:{}", self.prefix, id)
+  }
+
+  /// Pub/sub channel, e.g. the operation lifecycle events.
+  pub fn channel(&self, channel: &str) -> String {
+    format!("{}{}", self.prefix, channel)
+  }
+
+  /// List of the ids of the operations enqueued by the operation `id`.
+  pub fn children(&self, id: &str) -> String {
+    format!("{}operation:children:{}", self.prefix, id)
+  }
+
+  /// Hash of the annotations of the operation `id`, kept apart so annotating never races with
+  /// the writes of the workers.
+  pub fn annotations(&self, id: &str) -> String {
+    format!("{}operation:annotations:{}", self.prefix, id)
+  }
+
+  /// Stream of the output chunks of the operation `id`, see
+  /// [`crate::longrunning::logs::OperationLogs`].
+  pub fn logs(&self, id: &str) -> String {
+    format!("{}operation:logs:{}", self.prefix, id)
+  }
+
+  /// Id of the last operation enqueued to `queue` with the unique `key`, see
+  /// [`crate::longrunning::Broker::enqueue_unique`].
+  pub fn unique(&self, queue: &str, key: &str) -> String {
+    format!("{}unique:{}:{}", self.prefix, queue, key)
+  }
+
+  /// Hash holding the outcome of an idempotent request, see
+  /// [`crate::service::Idempotency`].
+  pub fn idempotency(&self, key: &str) -> String {
+    format!("{}idempotency:{}", self.prefix, key)
+  }
+
+  /// Hash describing the maintenance in progress, see
+  /// [`crate::longrunning::maintenance::Maintenance`].
+  pub fn maintenance(&self) -> String {
+    format!("{}maintenance", self.prefix)
+  }
+
+  /// Hash of the feature flags as JSON, keyed by flag name.
+  pub fn flags(&self) -> String {
+    format!("{}flags", self.prefix)
+  }
+
+  /// List of the latest enqueue to completion latencies of `task_type`, in milliseconds.
+  pub fn latency(&self, task_type: &str) -> String {
+    format!("{}latency:{}", self.prefix, task_type)
+  }
+
+  /// Hash of the encoded [`crate::proto::longrunning::TaskSchema`] published by the workers,
+  /// keyed by task type.
+  pub fn schemas(&self) -> String {
+    format!("{}schemas", self.prefix)
+  }
+
+  /// Hash counting the completions of `task_type` and the SLA violations among them.
+  pub fn sla(&self, task_type: &str) -> String {
+    format!("{}sla:{}", self.prefix, task_type)
+  }
+
+  /// Hash summing the usage of `organization_id` by meter, see
+  /// [`crate::longrunning::cost::CostMeter`].
+  pub fn usage(&self, organization_id: &str) -> String {
+    format!("{}usage:{}", self.prefix, organization_id)
+  }
+
+  /// List of the encoded meter events not taken by the billing service yet.
+  pub fn meter_events(&self) -> String {
+    format!("{}meter:events", self.prefix)
+  }
+
+  /// Hash holding the session `id`, see [`SessionStore`].
+  pub fn session(&self, id: &str) -> String {
+    format!("{}session:{}", self.prefix, id)
+  }
+
+  /// Set of the session ids of `user_id`.
+  pub fn user_sessions(&self, user_id: &str) -> String {
+    format!("{}sessions:user:{}", self.prefix, user_id)
+  }
+
+  /// Hash holding the state of the deleted operation `id` until it is purged, see
+  /// [`crate::longrunning::admin::RedisAdmin::delete`].
+  pub fn tombstone(&self, id: &str) -> String {
+    format!("{}tombstone:{}", self.prefix, id)
+  }
+
+  /// Sorted set of the deleted operation ids, scored by their deletion time in milliseconds.
+  pub fn tombstones(&self) -> String {
+    format!("{}tombstones", self.prefix)
+  }
+
+  /// Sorted set of the operation ids enqueued by `user_id` on `day`, `YYYY-MM-DD`, scored by
+  /// their publish time in milliseconds, see [`crate::longrunning::index`].
+  pub fn user_index(&self, user_id: &str, day: &str) -> String {
+    format!("{}index:user:{}:{}", self.prefix, user_id, day)
+  }
+
+  /// Sorted set of the operation ids enqueued to `queue` on `day`, see [`Keys::user_index`].
+  pub fn queue_index(&self, queue: &str, day: &str) -> String {
+    format!("{}index:queue:{}:{}", self.prefix, queue, day)
+  }
+
+  /// Hash holding the state of the operation `id`.
+  pub fn operation(&self, id: &str) -> String {
+    format!("{}operation:{}", self.prefix, id)
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct ProtoValue<T: prost::Message>(pub T);
 
@@ -43,3 +245,22 @@ impl<T: prost::Message + std::default::Default> ToRedisArgs for ProtoValue<T> {
     out.write_arg(&buf);
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keys_should_prefix_and_strip() {
+    let keys = Keys::for_environment("staging");
+
+    assert_eq!(keys.queue("backups"), "rappel:staging:queue:backups");
+    assert_eq!(keys.operation("1"), "rappel:staging:operation:1");
+    assert_eq!(
+      keys.strip("rappel:staging:queue:backups"),
+      Some("queue:backups")
+    );
+    assert_eq!(keys.strip("queue:backups"), None);
+    assert_eq!(Keys::default().queue("backups"), "queue:backups");
+  }
+}
@@ -0,0 +1,233 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use redis::AsyncCommands;
+use tracing_futures::Instrument;
+
+use crate::codec::Codec;
+use crate::codec::Decoder;
+use crate::codec::Encoder;
+use crate::util::redis_exec;
+
+use super::Keys;
+
+/// Deletes every session of a user and the user's index. Returns the number of sessions deleted.
+///
+/// KEYS: sessions of the user. ARGV: prefix of the session keys.
+const REVOKE_SCRIPT: &str = r"
+local ids = redis.call('SMEMBERS', KEYS[1])
+local deleted = 0
+for _, id in ipairs(ids) do
+  deleted = deleted + redis.call('DEL', ARGV[1] .. id)
+end
+redis.call('DEL', KEYS[1])
+return deleted
+";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+  #[error("Redis command failed: {0}")]
+  Redis(#[from] redis::RedisError),
+
+  #[error("Failed at codec: {0}")]
+  Codec(#[from] crate::codec::Error),
+}
+
+/// Caches sessions, e.g. the claims of validated tokens, under `session:{id}`. A session expires
+/// once it is not read for the TTL of the store: every [`SessionStore::get`] pushes its expiry
+/// back. Sessions are indexed by user under `sessions:user:{user_id}`, so
+/// [`SessionStore::revoke_user`] logs a user out everywhere.
+///
+/// ```rust,ignore
+/// let sessions: SessionStore<Claims, _> =
+///   SessionStore::new(client, JsonCodec::new(), Duration::from_secs(900));
+///
+/// let claims = match sessions.get(&token_digest).await? {
+///   Some(claims) => claims,
+///   None => {
+///     let claims = idp.validate(&token).await?;
+///     sessions.insert(&token_digest, &claims.sub, &claims).await?;
+///     claims
+///   }
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct SessionStore<T, C> {
+  client: redis::Client,
+  codec: C,
+  ttl: Duration,
+  keys: Keys,
+  _phantom: PhantomData<T>,
+}
+
+impl<T, C> SessionStore<T, C>
+where
+  C: Codec<Encodable = T, Decodable = T>,
+  C::EncodingError: std::error::Error + Send + Sync + 'static,
+  C::DecodingError: std::error::Error + Send + Sync + 'static,
+{
+  pub fn new(client: redis::Client, codec: C, ttl: Duration) -> Self {
+    Self {
+      client,
+      codec,
+      ttl,
+      keys: Keys::default(),
+      _phantom: PhantomData,
+    }
+  }
+
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Stores the session `id` of `user_id`, replacing any session with the same id.
+  pub async fn insert(&self, id: &str, user_id: &str, session: &T) -> Result<(), SessionError> {
+    let mut payload = Vec::default();
+    self
+      .codec
+      .encoder()
+      .encode(session, &mut payload)
+      .map_err(|error| crate::codec::Error::Encode(Box::new(error)))?;
+
+    let ttl = self.ttl_millis();
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = redis::pipe()
+      .atomic()
+      .hset_multiple(
+        self.keys.session(id),
+        &[("user_id", user_id.as_bytes()), ("payload", &payload)],
+      )
+      .ignore()
+      .pexpire(self.keys.session(id), ttl)
+      .ignore()
+      .sadd(self.keys.user_sessions(user_id), id)
+      .ignore()
+      .pexpire(self.keys.user_sessions(user_id), ttl)
+      .ignore()
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-session-insert"))
+      .await?;
+
+    Ok(())
+  }
+
+  /// Returns the session `id` and extends its expiry, `None` if it expired or was revoked.
+  pub async fn get(&self, id: &str) -> Result<Option<T>, SessionError> {
+    let ttl = self.ttl_millis();
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let ((user_id, payload),): ((Option<String>, Option<Vec<u8>>),) = redis::pipe()
+      .atomic()
+      .pexpire(self.keys.session(id), ttl)
+      .ignore()
+      .hget(self.keys.session(id), &["user_id", "payload"])
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-session-get"))
+      .await?;
+
+    let (user_id, mut payload) = match (user_id, payload) {
+      (Some(user_id), Some(payload)) => (user_id, payload),
+      _ => return Ok(None),
+    };
+
+    // The index must outlive every session it lists.
+    let _: () = conn
+      .pexpire(self.keys.user_sessions(&user_id), ttl)
+      .instrument(tracing::info_span!("redis-session-touch-user"))
+      .await?;
+
+    let session = self
+      .codec
+      .decoder()
+      .decode(&mut payload)
+      .map_err(|error| crate::codec::Error::Decode(Box::new(error)))?;
+
+    Ok(session)
+  }
+
+  /// Deletes the session `id`. Returns `false` if there was no such session.
+  pub async fn remove(&self, id: &str) -> Result<bool, SessionError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let user_id: Option<String> = conn.hget(self.keys.session(id), "user_id").await?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(self.keys.session(id));
+    if let Some(user_id) = &user_id {
+      pipe.srem(self.keys.user_sessions(user_id), id).ignore();
+    }
+
+    let (deleted,): (i64,) = pipe
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-session-remove"))
+      .await?;
+
+    Ok(deleted > 0)
+  }
+
+  /// Deletes every session of `user_id` and returns how many there were.
+  pub async fn revoke_user(&self, user_id: &str) -> Result<u64, SessionError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let deleted: u64 = redis::Script::new(REVOKE_SCRIPT)
+      .key(self.keys.user_sessions(user_id))
+      .arg(self.keys.session(""))
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-session-revoke", %user_id))
+      .await?;
+
+    tracing::info!(message = "Revoked sessions", %user_id, deleted);
+    Ok(deleted)
+  }
+
+  fn ttl_millis(&self) -> usize {
+    usize::try_from(self.ttl.as_millis()).unwrap_or(usize::MAX)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde::Deserialize;
+  use serde::Serialize;
+
+  use crate::codec::json::JsonCodec;
+
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+  struct Claims {
+    sub: String,
+    scope: String,
+  }
+
+  #[tokio::test]
+  async fn revoke_user_should_remove_every_session() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let keys = Keys::new(&format!("{}:", uuid::Uuid::new_v4()));
+    let sessions: SessionStore<Claims, JsonCodec<Claims, Claims>> =
+      SessionStore::new(client, JsonCodec::new(), Duration::from_millis(300)).with_keys(keys);
+    let claims = Claims {
+      sub: "42".to_string(),
+      scope: "workspaces".to_string(),
+    };
+
+    sessions.insert("a", "42", &claims).await.unwrap();
+    sessions.insert("b", "42", &claims).await.unwrap();
+    sessions.insert("c", "7", &claims).await.unwrap();
+
+    // Reading the session keeps it alive past its initial expiry.
+    for _ in 0..3 {
+      tokio::time::sleep(Duration::from_millis(150)).await;
+      assert_eq!(sessions.get("c").await.unwrap(), Some(claims.clone()));
+    }
+    assert_eq!(sessions.get("a").await.unwrap(), None);
+
+    sessions.insert("a", "42", &claims).await.unwrap();
+    assert_eq!(sessions.revoke_user("42").await.unwrap(), 1);
+    assert_eq!(sessions.get("a").await.unwrap(), None);
+    assert!(sessions.remove("c").await.unwrap());
+    assert!(!sessions.remove("c").await.unwrap());
+  }
+}
@@ -0,0 +1,212 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::FutureExt;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::codegen::BoxFuture;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use super::REQUEST_ID_HEADER;
+
+static METRICS: PanicMetrics = PanicMetrics::new();
+
+/// Handler panics caught by every server of the process.
+pub fn metrics() -> &'static PanicMetrics {
+  &METRICS
+}
+
+/// Converts the panics of the handlers into `INTERNAL` responses, see [`CatchPanic`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CatchPanicLayer;
+
+impl CatchPanicLayer {
+  pub fn new() -> Self {
+    Self
+  }
+}
+
+impl<S> Layer<S> for CatchPanicLayer {
+  type Service = CatchPanic<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    CatchPanic { inner }
+  }
+}
+
+/// Answers the requests whose handler panics with an `INTERNAL` status carrying a correlation id,
+/// the [`REQUEST_ID_HEADER`] of the request or a new id, instead of letting the panic tear down
+/// the HTTP/2 connection and every concurrent stream on it. The panic is logged with the id and
+/// counted in the process wide [`metrics`].
+#[derive(Clone, Debug)]
+pub struct CatchPanic<S> {
+  inner: S,
+}
+
+impl<S, B> Service<http::Request<B>> for CatchPanic<S>
+where
+  S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = BoxFuture<S::Response, S::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    let method = request.uri().path().to_string();
+    let correlation_id = request
+      .headers()
+      .get(REQUEST_ID_HEADER)
+      .and_then(|value| value.to_str().ok())
+      .map(str::to_string)
+      .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    // Handlers may panic before returning their future as well as while it is polled.
+    let inner = &mut self.inner;
+    let response = match std::panic::catch_unwind(AssertUnwindSafe(|| inner.call(request))) {
+      Ok(response) => response,
+      Err(panic) => {
+        let response = internal(&method, &correlation_id, panic);
+        return Box::pin(futures::future::ready(Ok(response)));
+      }
+    };
+
+    Box::pin(async move {
+      match AssertUnwindSafe(response).catch_unwind().await {
+        Ok(response) => response,
+        Err(panic) => Ok(internal(&method, &correlation_id, panic)),
+      }
+    })
+  }
+}
+
+/// Logs and counts the `panic` of the handler of `method`, and returns its `INTERNAL` response.
+fn internal(
+  method: &str,
+  correlation_id: &str,
+  panic: Box<dyn Any + Send>,
+) -> http::Response<BoxBody> {
+  let reason = panic
+    .downcast_ref::<&str>()
+    .copied()
+    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+    .unwrap_or("unknown");
+
+  METRICS.record(method);
+  tracing::error!(message = "Handler panicked", %method, %correlation_id, %reason);
+
+  let status =
+    tonic::Status::internal(format!("Internal error, correlation id {}", correlation_id));
+  let mut response = status.to_http();
+  if let Ok(value) = correlation_id.parse() {
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+  }
+  response
+}
+
+/// Handler panics per method, rendered in the Prometheus text format.
+#[derive(Debug)]
+pub struct PanicMetrics {
+  panics: Mutex<BTreeMap<String, u64>>,
+}
+
+impl PanicMetrics {
+  const fn new() -> Self {
+    Self {
+      panics: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  fn record(&self, method: &str) {
+    let mut panics = self.panics.lock().unwrap_or_else(PoisonError::into_inner);
+    *panics.entry(method.to_string()).or_default() += 1;
+  }
+
+  /// Number of panics of the handler of `method`.
+  pub fn count(&self, method: &str) -> u64 {
+    self
+      .panics
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .get(method)
+      .copied()
+      .unwrap_or_default()
+  }
+
+  pub fn render(&self) -> String {
+    let mut out = String::default();
+
+    let _ = writeln!(out, "# TYPE rappel_rpc_panics_total counter");
+    let panics = self.panics.lock().unwrap_or_else(PoisonError::into_inner);
+    for (method, count) in panics.iter() {
+      let _ = writeln!(
+        out,
+        "rappel_rpc_panics_total{{method=\"{}\"}} {}",
+        method, count
+      );
+    }
+
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::BoxFuture as Boxed;
+
+  use super::*;
+
+  /// Panics on `/Panic/Sync` before returning a future, and on `/Panic/Async` while polled.
+  struct Panicking;
+
+  impl Service<http::Request<()>> for Panicking {
+    type Response = http::Response<BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = Boxed<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<()>) -> Self::Future {
+      if request.uri().path() == "/Panic/Sync" {
+        panic!("sync");
+      }
+
+      Box::pin(async { panic!("async") })
+    }
+  }
+
+  #[tokio::test]
+  async fn catch_panic_should_answer_internal() {
+    let mut service = CatchPanicLayer::new().layer(Panicking);
+
+    for method in ["/Panic/Sync", "/Panic/Async"] {
+      let request = http::Request::builder()
+        .uri(method)
+        .header(REQUEST_ID_HEADER, "42")
+        .body(())
+        .unwrap();
+      let response = service.call(request).await.unwrap();
+      let status = tonic::Status::from_header_map(response.headers()).unwrap();
+      assert_eq!(status.code(), tonic::Code::Internal);
+      assert!(status.message().contains("42"));
+      assert_eq!(metrics().count(method), 1);
+    }
+    assert!(metrics()
+      .render()
+      .contains("rappel_rpc_panics_total{method=\"/Panic/Async\"} 1"));
+  }
+}
@@ -1,47 +1,635 @@
+use std::future::Future;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
 use tonic::transport::Channel;
+use tonic::transport::Endpoint;
+use tower_layer::Layer;
+
+use super::config::ServiceConf;
+use super::config::TlsConf;
+use super::shard_map::rendezvous;
+use super::shard_map::weighted_rendezvous;
+use super::shard_map::ShardMap;
+use super::unix_path;
+use super::ClientChannel;
+use super::ResponseLimitLayer;
+use super::SlowCallLayer;
+use super::UnixConnector;
+use super::DEFAULT_SLOW_CALL_THRESHOLD;
+use crate::proto::system::Location;
+use crate::util::error_context::ErrorContext;
+use crate::util::error_context::ResultExt;
 
-use super::locator::ServiceConf;
+/// Time a connection to an instance gets to open by default, so calls to an unreachable instance
+/// fail rather than wait for the TCP timeout of the system.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Clients of every instance of a service. Keys are routed to the instance they are pinned to in
+/// the [`ShardMap`], if any, and by [`rendezvous`] hashing over the instance addresses otherwise,
+/// so routing does not depend on the order of the configured instances.
+///
+/// Every instance can be served by several HTTP/2 connections, used in round-robin, so a busy
+/// instance is not limited by the concurrent stream limit of a single connection.
 #[derive(Clone, Debug)]
 pub struct ShardedClient<T: Clone> {
   name: String,
-  clients: Vec<T>,
+  addresses: Vec<String>,
+  clients: Vec<Vec<T>>,
+  /// A channel to every instance, for health checks.
+  channels: Vec<ClientChannel>,
+  next: Arc<AtomicUsize>,
+  shard_map: Option<ShardMap>,
+  limiters: Option<Vec<Limiter>>,
+  /// Name of the [`Location`] of every instance, empty when not configured.
+  locations: Vec<String>,
+  localities: Option<Vec<Locality>>,
+  /// Addresses of the instances closest to the caller, see [`Self::with_locality`].
+  preferred: Option<Vec<String>>,
+  /// Weight of every instance, `None` when they are all weighted one.
+  weights: Option<Vec<u32>>,
+  /// Arguments of [`Self::with_locality`], to route the clients rebuilt by discovery alike.
+  local: Option<(Location, Vec<Location>)>,
+}
+
+/// How close an instance is to the caller, from the closest to the farthest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Locality {
+  Zone,
+  Region,
+  Remote,
+}
+
+impl Locality {
+  /// Returns the locality of `instance` as seen from `local`.
+  pub fn of(local: &Location, instance: &Location) -> Self {
+    match (&instance.region, &instance.zone) {
+      (region, zone) if !region.is_empty() && *region == local.region => {
+        match !zone.is_empty() && *zone == local.zone {
+          true => Locality::Zone,
+          false => Locality::Region,
+        }
+      }
+      _ => Locality::Remote,
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Locality::Zone => "zone",
+      Locality::Region => "region",
+      Locality::Remote => "remote",
+    }
+  }
 }
 
 impl<T: Clone> ShardedClient<T> {
-  pub(crate) fn try_new<F: Fn(Channel) -> T>(
+  /// Connects lazily to the instances of `config`, wrapping every connection with `builder`.
+  /// Nothing is awaited: connections open on their first call, within the connect timeout of
+  /// `config`, so an unreachable instance fails the calls it serves rather than the startup.
+  /// Fails when `config` does not pass [`ServiceConf::validate`].
+  pub fn try_new<F: Fn(ClientChannel) -> T>(
     config: ServiceConf,
     builder: F,
   ) -> Result<Self, super::Error> {
+    config.validate()?;
+
     let name = config.name;
+    let context = || ErrorContext::new().with_service(&name);
+    let tls = config
+      .tls
+      .as_ref()
+      .map(TlsConf::client_config)
+      .transpose()
+      .map_err(super::Error::from)
+      .with_context(context)?;
+    let mut addresses = Vec::default();
+    let mut weights = Vec::default();
     let mut clients = Vec::default();
+    let mut channels = Vec::default();
+    let mut locations = Vec::default();
 
     tracing::debug!(message = "Initializing ShardedClient", %name);
 
+    let connections = config.connections.unwrap_or(1).max(1);
+    let slow_call_threshold = config
+      .slow_call_ms
+      .map_or(DEFAULT_SLOW_CALL_THRESHOLD, Duration::from_millis);
+    let connect_timeout = config
+      .connect_timeout_ms
+      .map_or(DEFAULT_CONNECT_TIMEOUT, Duration::from_millis);
+    let call_timeout = config.call_timeout_ms.map(Duration::from_millis);
+    let keepalive_interval = config.keepalive_interval_ms.map(Duration::from_millis);
+    let keepalive_timeout = config.keepalive_timeout_ms.map(Duration::from_millis);
+    let tcp_nodelay = config.tcp_nodelay.unwrap_or(true);
+    let response_limit = ResponseLimitLayer::new(config.max_message_bytes);
+    let tune = |endpoint: Endpoint| {
+      let endpoint = endpoint
+        .connect_timeout(connect_timeout)
+        .tcp_nodelay(tcp_nodelay);
+      let endpoint = match call_timeout {
+        Some(timeout) => endpoint.timeout(timeout),
+        None => endpoint,
+      };
+      let endpoint = match keepalive_interval {
+        Some(interval) => endpoint
+          .http2_keep_alive_interval(interval)
+          .keep_alive_while_idle(true),
+        None => endpoint,
+      };
+      match keepalive_timeout {
+        Some(timeout) => endpoint.keep_alive_timeout(timeout),
+        None => endpoint,
+      }
+    };
+
     for instance in config.instances {
       let address = instance.address.clone();
-      let channel = tonic::transport::Channel::from_shared(address)?.connect_lazy();
-      clients.push(builder(channel));
+      let slow_calls = SlowCallLayer::client(&address, slow_call_threshold);
+      let connections: Vec<ClientChannel> = match unix_path(&address) {
+        Some(path) => {
+          // The URI is only used for the HTTP/2 authority, the connector picks the socket.
+          let endpoint = tune(Endpoint::from_static("http://localhost"));
+          (0..connections)
+            .map(|_| {
+              let channel = endpoint.connect_with_connector_lazy(UnixConnector::new(path));
+              slow_calls.layer(response_limit.layer(channel))
+            })
+            .collect()
+        }
+        None => {
+          let endpoint = Channel::from_shared(address.clone())
+            .map_err(super::Error::from)
+            .with_context(context)?;
+          let endpoint = match &tls {
+            Some(tls) if address.starts_with("https://") => endpoint
+              .tls_config(tls.clone())
+              .map_err(super::Error::from)
+              .with_context(context)?,
+            _ => endpoint,
+          };
+          let endpoint = tune(endpoint);
+          (0..connections)
+            .map(|_| slow_calls.layer(response_limit.layer(endpoint.connect_lazy())))
+            .collect()
+        }
+      };
+
+      addresses.push(address);
+      weights.push(instance.weight());
+      channels.push(connections[0].clone());
+      clients.push(connections.into_iter().map(&builder).collect());
+      locations.push(instance.location);
     }
 
-    let client = Self { name, clients };
+    let client = Self {
+      name,
+      addresses,
+      clients,
+      channels,
+      next: Arc::default(),
+      shard_map: None,
+      limiters: None,
+      locations,
+      localities: None,
+      preferred: None,
+      weights: match weights.iter().all(|weight| *weight == 1) {
+        true => None,
+        false => Some(weights),
+      },
+      local: None,
+    };
 
-    tracing::debug!(message = "Initialized ShardedClient", name = %client.name, count = client.clients.len());
+    let client = match (config.max_in_flight, config.max_queued) {
+      (Some(max_in_flight), max_queued) => {
+        client.with_concurrency_limit(max_in_flight, max_queued.unwrap_or_default())
+      }
+      (None, _) => client,
+    };
+
+    tracing::debug!(message = "Initialized ShardedClient", name = %client.name, count = client.clients.len(), connections);
 
     Ok(client)
   }
 
-  pub fn borrow(&self, key: &str) -> Result<&T, super::Error> {
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Consults `shard_map` before hashing keys to instances.
+  pub fn with_shard_map(mut self, shard_map: ShardMap) -> Self {
+    self.shard_map = Some(shard_map);
+    self
+  }
+
+  /// Allows at most `max_in_flight` concurrent requests per instance through [`Self::acquire`].
+  /// Up to `max_queued` further callers wait for a slot in FIFO order, the others are rejected with
+  /// [`super::Error::ResourceExhausted`].
+  pub fn with_concurrency_limit(mut self, max_in_flight: usize, max_queued: usize) -> Self {
+    let limiters = self
+      .addresses
+      .iter()
+      .map(|_| Limiter::new(max_in_flight, max_queued))
+      .collect();
+
+    self.limiters = Some(limiters);
     self
-      .clients
-      .get(0)
-      .ok_or_else(|| super::Error::MissingClient(key.to_string()))
+  }
+
+  /// Routes keys to the instances closest to `local` among `locations`: in the same zone, or else
+  /// in the same region, or else anywhere. Keys pinned in the [`ShardMap`] stay on their instance.
+  /// Instances without a known location are remote.
+  pub fn with_locality(mut self, local: &Location, locations: &[Location]) -> Self {
+    let localities: Vec<Locality> = self
+      .locations
+      .iter()
+      .map(|name| {
+        locations
+          .iter()
+          .find(|location| !name.is_empty() && location.name == *name)
+          .map_or(Locality::Remote, |location| Locality::of(local, location))
+      })
+      .collect();
+
+    let nearest = localities.iter().min().copied();
+    let preferred = self
+      .addresses
+      .iter()
+      .zip(&localities)
+      .filter(|(_, locality)| Some(**locality) == nearest)
+      .map(|(address, _)| address.clone())
+      .collect();
+
+    tracing::debug!(message = "Routing ShardedClient by locality", name = %self.name, nearest = ?nearest);
+
+    self.localities = Some(localities);
+    self.preferred = Some(preferred);
+    self.local = Some((local.clone(), locations.to_vec()));
+    self
+  }
+
+  /// Routes like `previous`, with its [`ShardMap`] and locality, e.g. after rebuilding the client
+  /// for new instances.
+  pub(crate) fn with_routing_of(self, previous: &Self) -> Self {
+    let client = match &previous.shard_map {
+      Some(shard_map) => self.with_shard_map(shard_map.clone()),
+      None => self,
+    };
+
+    match &previous.local {
+      Some((local, locations)) => client.with_locality(local, locations),
+      None => client,
+    }
+  }
+
+  /// Returns the locality of the instance serving `key`, `None` without [`Self::with_locality`].
+  pub fn locality(&self, key: &str) -> Result<Option<Locality>, super::Error> {
+    let index = self.index(key)?;
+    Ok(self.localities.as_ref().map(|localities| localities[index]))
+  }
+
+  pub fn shard_map(&self) -> Option<&ShardMap> {
+    self.shard_map.as_ref()
+  }
+
+  /// Returns the address of the instance serving `key`.
+  pub fn address(&self, key: &str) -> Result<&str, super::Error> {
+    self.index(key).map(|index| self.addresses[index].as_str())
+  }
+
+  /// Pins `key` to the instance currently serving it, unless it is pinned already, so it keeps
+  /// being routed there when instances are added or removed.
+  pub async fn pin(&self, key: &str) -> Result<String, super::Error> {
+    let shard_map = self
+      .shard_map
+      .as_ref()
+      .ok_or_else(|| super::Error::MissingShardMap(self.name.clone()))?;
+    let address = self.address(key)?;
+
+    shard_map
+      .assign_if_absent(key, address)
+      .await
+      .map_err(super::Error::from)
+      .with_context(|| self.error_context())
+  }
+
+  /// Returns the client of the instance serving `key` once the instance has a free request slot.
+  /// The slot is released when the returned [`Lease`] is dropped. Without a concurrency limit this
+  /// never waits.
+  pub async fn acquire(&self, key: &str) -> Result<Lease<T>, super::Error> {
+    let index = self.index(key)?;
+
+    let permit = match &self.limiters {
+      Some(limiters) => Some(
+        limiters[index]
+          .acquire(&self.addresses[index])
+          .await
+          .with_context(|| self.error_context())?,
+      ),
+      None => None,
+    };
+
+    Ok(Lease {
+      client: self.connection(index).clone(),
+      locality: self.localities.as_ref().map(|localities| localities[index]),
+      _permit: permit,
+    })
+  }
+
+  pub fn borrow(&self, key: &str) -> Result<&T, super::Error> {
+    let index = self.index(key)?;
+    Ok(self.connection(index))
   }
 
   pub fn borrow_mut(&mut self, key: &str) -> Result<&mut T, super::Error> {
+    let index = self.index(key)?;
+    let connection = self.next.fetch_add(1, Ordering::Relaxed) % self.clients[index].len();
+    Ok(&mut self.clients[index][connection])
+  }
+
+  /// Sends `rpc` through every connection of every instance, so the first requests do not pay for
+  /// the TLS and HTTP/2 handshakes. Fails with the first failing RPC.
+  ///
+  /// ```rust,ignore
+  /// clusters
+  ///   .warm_up(|mut client| async move { client.list_clusters(()).await.map(|_| ()) })
+  ///   .await?;
+  /// ```
+  pub async fn warm_up<F, Fut>(&self, rpc: F) -> Result<(), tonic::Status>
+  where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), tonic::Status>>,
+  {
+    let rpcs = self.clients.iter().flatten().cloned().map(rpc);
+    futures::future::try_join_all(rpcs).await?;
+
+    tracing::debug!(message = "Warmed up ShardedClient", name = %self.name);
+    Ok(())
+  }
+
+  /// Address and a channel of every instance, e.g. for health checks.
+  pub fn instances(&self) -> impl Iterator<Item = (&str, &ClientChannel)> {
+    self
+      .addresses
+      .iter()
+      .map(String::as_str)
+      .zip(self.channels.iter())
+  }
+
+  fn connection(&self, index: usize) -> &T {
+    let connections = &self.clients[index];
+    &connections[self.next.fetch_add(1, Ordering::Relaxed) % connections.len()]
+  }
+
+  /// Hashes `key` to one of the preferred instances, in proportion to their weights.
+  fn route(&self, key: &str) -> Option<&str> {
+    let candidates = self.preferred.as_deref().unwrap_or(&self.addresses);
+    let weights = match &self.weights {
+      Some(weights) => weights,
+      None => return rendezvous(key, candidates),
+    };
+
+    let weighted = candidates.iter().filter_map(|candidate| {
+      let index = self
+        .addresses
+        .iter()
+        .position(|address| address == candidate)?;
+      Some((candidate.as_str(), weights[index]))
+    });
+    weighted_rendezvous(key, weighted)
+  }
+
+  fn error_context(&self) -> ErrorContext {
+    ErrorContext::new().with_service(&self.name)
+  }
+
+  fn index(&self, key: &str) -> Result<usize, super::Error> {
+    let pinned = self.shard_map.as_ref().and_then(|map| map.get(key));
+
+    let address = match &pinned {
+      Some(address) => address.as_str(),
+      None => self
+        .route(key)
+        .ok_or_else(|| super::Error::MissingClient(key.to_string()))
+        .with_context(|| self.error_context())?,
+    };
+
     self
-      .clients
-      .get_mut(0)
+      .addresses
+      .iter()
+      .position(|candidate| candidate == address)
       .ok_or_else(|| super::Error::MissingClient(key.to_string()))
+      .with_context(|| self.error_context())
+  }
+}
+
+/// A client holding one of the request slots of its instance.
+#[derive(Debug)]
+pub struct Lease<T> {
+  client: T,
+  locality: Option<Locality>,
+  _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<T> Lease<T> {
+  pub fn locality(&self) -> Option<Locality> {
+    self.locality
+  }
+
+  /// Wraps `message` in a request carrying the locality of the instance in its extensions, so
+  /// the client layers can log it.
+  pub fn request<M>(&self, message: M) -> tonic::Request<M> {
+    let mut request = tonic::Request::new(message);
+    if let Some(locality) = self.locality {
+      request.extensions_mut().insert(locality);
+    }
+    request
+  }
+}
+
+impl<T> Deref for Lease<T> {
+  type Target = T;
+
+  fn deref(&self) -> &Self::Target {
+    &self.client
+  }
+}
+
+impl<T> DerefMut for Lease<T> {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.client
+  }
+}
+
+/// Request slots of one instance. Tokio's semaphore is fair, so waiters get a slot in FIFO order.
+#[derive(Clone, Debug)]
+struct Limiter {
+  semaphore: Arc<Semaphore>,
+  queued: Arc<AtomicUsize>,
+  max_queued: usize,
+}
+
+impl Limiter {
+  fn new(max_in_flight: usize, max_queued: usize) -> Self {
+    Self {
+      semaphore: Arc::new(Semaphore::new(max_in_flight)),
+      queued: Arc::default(),
+      max_queued,
+    }
+  }
+
+  async fn acquire(&self, address: &str) -> Result<OwnedSemaphorePermit, super::Error> {
+    if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+      return Ok(permit);
+    }
+
+    if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+      self.queued.fetch_sub(1, Ordering::SeqCst);
+      return Err(super::Error::ResourceExhausted(address.to_string()));
+    }
+
+    // Leaves the queue even when the caller is cancelled while waiting.
+    let _queued = Queued(&self.queued);
+
+    self
+      .semaphore
+      .clone()
+      .acquire_owned()
+      .await
+      .map_err(|_| super::Error::ResourceExhausted(address.to_string()))
+  }
+}
+
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::proto::health::proto::health_client::HealthClient;
+  use crate::proto::health::proto::HealthCheckRequest;
+
+  use super::super::config::ServiceInstance;
+  use super::*;
+
+  fn location(name: &str, region: &str, zone: &str) -> Location {
+    Location {
+      name: name.to_string(),
+      region: region.to_string(),
+      zone: zone.to_string(),
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn calls_should_time_out_on_unresponsive_instances() {
+    // Connections are accepted by the kernel, but never answered.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = ServiceConf::builder("clusters")
+      .with_address(format!("http://{}", listener.local_addr().unwrap()))
+      .with_call_timeout(Duration::from_millis(100))
+      .build()
+      .unwrap();
+    let client = ShardedClient::try_new(config, HealthClient::new).unwrap();
+
+    let mut health = client.borrow("key").unwrap().clone();
+    let call = health.check(HealthCheckRequest::default());
+    let result = tokio::time::timeout(Duration::from_secs(5), call)
+      .await
+      .expect("the call should time out");
+    assert!(result.is_err());
+  }
+
+  #[tokio::test]
+  async fn with_locality_should_prefer_the_closest_instances() {
+    let instance = |address: &str, location: &str| ServiceInstance {
+      address: address.to_string(),
+      shard_ranges: Vec::default(),
+      location: location.to_string(),
+      weight: None,
+    };
+    let config = ServiceConf {
+      name: "clusters".to_string(),
+      instances: vec![
+        instance("http://10.0.0.1:50051", "eu-1a"),
+        instance("http://10.0.0.2:50051", "eu-1b"),
+        instance("http://10.1.0.1:50051", "us-1a"),
+        instance("http://10.1.0.2:50051", ""),
+      ],
+      connections: None,
+      max_in_flight: None,
+      max_queued: None,
+      slow_call_ms: None,
+      connect_timeout_ms: None,
+      call_timeout_ms: None,
+      keepalive_interval_ms: None,
+      keepalive_timeout_ms: None,
+      tcp_nodelay: None,
+      max_message_bytes: None,
+      tls: None,
+    };
+    let locations = [
+      location("eu-1a", "eu-1", "a"),
+      location("eu-1b", "eu-1", "b"),
+      location("us-1a", "us-1", "a"),
+    ];
+    let client = ShardedClient::try_new(config, |channel| channel).unwrap();
+
+    let in_region = client
+      .clone()
+      .with_locality(&location("eu-1c", "eu-1", "c"), &locations);
+    for key in ["1", "2", "3", "4", "5"] {
+      assert!(in_region.address(key).unwrap().starts_with("http://10.0."));
+      assert_eq!(in_region.locality(key).unwrap(), Some(Locality::Region));
+    }
+
+    let in_zone = client
+      .clone()
+      .with_locality(&location("eu-1b", "eu-1", "b"), &locations);
+    assert_eq!(in_zone.address("1").unwrap(), "http://10.0.0.2:50051");
+    let lease = in_zone.acquire("1").await.unwrap();
+    assert_eq!(
+      lease.request(()).extensions().get::<Locality>(),
+      Some(&Locality::Zone)
+    );
+
+    // Without a close instance, keys spread over every instance.
+    let remote = client.with_locality(&location("ap-1a", "ap-1", "a"), &locations);
+    assert_eq!(remote.locality("1").unwrap(), Some(Locality::Remote));
+  }
+
+  #[tokio::test]
+  async fn limiter_should_reject_callers_past_the_queue_bound() {
+    let limiter = Limiter::new(1, 1);
+
+    let permit = limiter.acquire("a").await.unwrap();
+
+    let waiter = {
+      let limiter = limiter.clone();
+      tokio::spawn(async move { limiter.acquire("a").await.map(|_| ()) })
+    };
+    while limiter.queued.load(Ordering::SeqCst) == 0 {
+      tokio::task::yield_now().await;
+    }
+
+    assert!(matches!(
+      limiter.acquire("a").await,
+      Err(super::super::Error::ResourceExhausted(_))
+    ));
+
+    drop(permit);
+    assert!(waiter.await.unwrap().is_ok());
+    assert_eq!(limiter.queued.load(Ordering::SeqCst), 0);
+    assert!(limiter.acquire("a").await.is_ok());
   }
 }
@@ -1,17 +1,379 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
 use serde_derive::Deserialize;
+use tonic::codegen::http::Uri;
+use tonic::transport::Certificate;
+use tonic::transport::ClientTlsConfig;
+use tonic::transport::Identity;
+
+use super::uds::unix_path;
+use crate::proto::system::ServiceEndpoints;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ServiceInstance {
+  /// `http://host:port`, or `unix:///path.sock` for a Unix domain socket.
   pub address: String,
   pub shard_ranges: Vec<(String, String)>,
+  /// Name of the [`crate::proto::system::Location`] the instance runs in.
+  #[serde(default)]
+  pub location: String,
+  /// Share of the keys routed to the instance relative to the other instances, one when unset.
+  #[serde(default)]
+  pub weight: Option<u32>,
+}
+
+impl ServiceInstance {
+  pub fn new(address: impl Into<String>) -> Self {
+    Self {
+      address: address.into(),
+      shard_ranges: Vec::default(),
+      location: String::default(),
+      weight: None,
+    }
+  }
+
+  pub fn with_location(mut self, location: impl Into<String>) -> Self {
+    self.location = location.into();
+    self
+  }
+
+  pub fn with_weight(mut self, weight: u32) -> Self {
+    self.weight = Some(weight);
+    self
+  }
+
+  pub fn with_shard_range(mut self, start: impl Into<String>, end: impl Into<String>) -> Self {
+    self.shard_ranges.push((start.into(), end.into()));
+    self
+  }
+
+  pub fn weight(&self) -> u32 {
+    self.weight.unwrap_or(1)
+  }
+}
+
+/// TLS of the connections to the `https://` instances of a service. Paths are read when the
+/// clients are built.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConf {
+  /// PEM file of the certificate authorities trusted to sign the certificates of the instances.
+  pub ca_certificate: String,
+  /// Name expected in the certificates of the instances, the host of their address when unset.
+  #[serde(default)]
+  pub domain_name: Option<String>,
+  /// PEM files of the certificate and key of the client, for mutual TLS.
+  #[serde(default)]
+  pub client_certificate: Option<String>,
+  #[serde(default)]
+  pub client_key: Option<String>,
+}
+
+impl TlsConf {
+  pub fn new(ca_certificate: impl Into<String>) -> Self {
+    Self {
+      ca_certificate: ca_certificate.into(),
+      domain_name: None,
+      client_certificate: None,
+      client_key: None,
+    }
+  }
+
+  pub fn with_domain_name(mut self, domain_name: impl Into<String>) -> Self {
+    self.domain_name = Some(domain_name.into());
+    self
+  }
+
+  pub fn with_identity(mut self, certificate: impl Into<String>, key: impl Into<String>) -> Self {
+    self.client_certificate = Some(certificate.into());
+    self.client_key = Some(key.into());
+    self
+  }
+
+  pub(crate) fn client_config(&self) -> Result<ClientTlsConfig, std::io::Error> {
+    let ca = std::fs::read(&self.ca_certificate)?;
+    let config = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+
+    let config = match &self.domain_name {
+      Some(domain_name) => config.domain_name(domain_name),
+      None => config,
+    };
+
+    match (&self.client_certificate, &self.client_key) {
+      (Some(certificate), Some(key)) => Ok(config.identity(Identity::from_pem(
+        std::fs::read(certificate)?,
+        std::fs::read(key)?,
+      ))),
+      _ => Ok(config),
+    }
+  }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ServiceConf {
   pub name: String,
   pub instances: Vec<ServiceInstance>,
+  /// HTTP/2 connections opened per instance, one when unset.
+  #[serde(default)]
+  pub connections: Option<usize>,
+  /// Concurrent requests allowed per instance, unlimited when unset.
+  #[serde(default)]
+  pub max_in_flight: Option<usize>,
+  /// Callers waiting for a request slot per instance before requests are rejected.
+  #[serde(default)]
+  pub max_queued: Option<usize>,
+  /// Calls answered after more milliseconds than this are logged, one second when unset.
+  #[serde(default)]
+  pub slow_call_ms: Option<u64>,
+  /// Milliseconds a connection to an instance gets to open, see
+  /// [`super::DEFAULT_CONNECT_TIMEOUT`] when unset.
+  #[serde(default)]
+  pub connect_timeout_ms: Option<u64>,
+  /// Milliseconds a call gets to complete, connecting included, unlimited when unset.
+  #[serde(default)]
+  pub call_timeout_ms: Option<u64>,
+  /// Milliseconds between the HTTP/2 pings keeping the connections alive, idle ones included, so
+  /// NATs and load balancers do not drop them silently between calls. No pings are sent when
+  /// unset.
+  #[serde(default)]
+  pub keepalive_interval_ms: Option<u64>,
+  /// Milliseconds a ping gets to be acknowledged before its connection is closed and reopened, 20
+  /// seconds when unset.
+  #[serde(default)]
+  pub keepalive_timeout_ms: Option<u64>,
+  /// Whether small requests are sent without waiting to fill a TCP segment, enabled when unset.
+  #[serde(default)]
+  pub tcp_nodelay: Option<bool>,
+  /// Bytes of each message received from the instances, as encoded on the wire, unlimited when
+  /// unset. Larger responses fail with `RESOURCE_EXHAUSTED` before they are buffered.
+  #[serde(default)]
+  pub max_message_bytes: Option<usize>,
+  #[serde(default)]
+  pub tls: Option<TlsConf>,
+}
+
+/// A [`ServiceConf`] failing [`ServiceConf::validate`].
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ServiceConfError {
+  #[error("The service has no name")]
+  MissingName,
+
+  #[error("Service {0} has no instances")]
+  NoInstances(String),
+
+  #[error("Service {service} lists the instance {address} more than once")]
+  DuplicateAddress { service: String, address: String },
+
+  #[error("Service {service} has an invalid instance address {address:?}: {reason}")]
+  InvalidAddress {
+    service: String,
+    address: String,
+    reason: String,
+  },
+
+  #[error("Service {service} gives the instance {address} a weight of zero")]
+  ZeroWeight { service: String, address: String },
+}
+
+impl ServiceConf {
+  /// Builds a configuration in code rather than deserializing it.
+  ///
+  /// ```rust,ignore
+  /// let conf = ServiceConf::builder("clusters")
+  ///   .with_instance(ServiceInstance::new("http://10.0.0.1:50051").with_location("eu-1a"))
+  ///   .with_instance(ServiceInstance::new("http://10.0.0.2:50051").with_weight(2))
+  ///   .with_concurrency_limit(64, 128)
+  ///   .build()?;
+  /// ```
+  pub fn builder(name: impl Into<String>) -> ServiceConfBuilder {
+    ServiceConfBuilder {
+      conf: ServiceConf {
+        name: name.into(),
+        instances: Vec::default(),
+        connections: None,
+        max_in_flight: None,
+        max_queued: None,
+        slow_call_ms: None,
+        connect_timeout_ms: None,
+        call_timeout_ms: None,
+        keepalive_interval_ms: None,
+        keepalive_timeout_ms: None,
+        tcp_nodelay: None,
+        max_message_bytes: None,
+        tls: None,
+      },
+    }
+  }
+
+  /// Checks the service is named and its instances have distinct, parseable addresses and
+  /// non-zero weights.
+  pub fn validate(&self) -> Result<(), ServiceConfError> {
+    if self.name.is_empty() {
+      return Err(ServiceConfError::MissingName);
+    }
+    if self.instances.is_empty() {
+      return Err(ServiceConfError::NoInstances(self.name.clone()));
+    }
+
+    let mut addresses = HashSet::new();
+    for instance in &self.instances {
+      let address = &instance.address;
+      if let Err(reason) = check_address(address) {
+        return Err(ServiceConfError::InvalidAddress {
+          service: self.name.clone(),
+          address: address.clone(),
+          reason,
+        });
+      }
+      if !addresses.insert(address.as_str()) {
+        return Err(ServiceConfError::DuplicateAddress {
+          service: self.name.clone(),
+          address: address.clone(),
+        });
+      }
+      if instance.weight == Some(0) {
+        return Err(ServiceConfError::ZeroWeight {
+          service: self.name.clone(),
+          address: address.clone(),
+        });
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl ServiceConf {
+  /// This configuration with the instances of `endpoints`, and its policies where they are set.
+  pub fn with_endpoints(&self, endpoints: &ServiceEndpoints) -> ServiceConf {
+    let policy = endpoints.policy.clone().unwrap_or_default();
+    let or = |value: u32, local: Option<usize>| match value {
+      0 => local,
+      value => Some(value as usize),
+    };
+
+    ServiceConf {
+      name: self.name.clone(),
+      instances: endpoints
+        .endpoints
+        .iter()
+        .map(|endpoint| ServiceInstance {
+          address: endpoint.address.clone(),
+          shard_ranges: Vec::default(),
+          location: endpoint.location.clone(),
+          weight: (endpoint.weight > 0).then_some(endpoint.weight),
+        })
+        .collect(),
+      connections: or(policy.connections, self.connections),
+      max_in_flight: or(policy.max_in_flight, self.max_in_flight),
+      max_queued: or(policy.max_queued, self.max_queued),
+      slow_call_ms: match policy.slow_call_ms {
+        0 => self.slow_call_ms,
+        slow_call_ms => Some(slow_call_ms),
+      },
+      connect_timeout_ms: self.connect_timeout_ms,
+      call_timeout_ms: self.call_timeout_ms,
+      keepalive_interval_ms: self.keepalive_interval_ms,
+      keepalive_timeout_ms: self.keepalive_timeout_ms,
+      tcp_nodelay: self.tcp_nodelay,
+      max_message_bytes: self.max_message_bytes,
+      tls: self.tls.clone(),
+    }
+  }
+}
+
+fn check_address(address: &str) -> Result<(), String> {
+  if let Some(path) = unix_path(address) {
+    return match path.is_empty() {
+      true => Err("the socket path is empty".to_string()),
+      false => Ok(()),
+    };
+  }
+
+  let uri: Uri = address.parse().map_err(|error| format!("{}", error))?;
+  match (uri.scheme_str(), uri.authority()) {
+    (Some("http" | "https"), Some(_)) => Ok(()),
+    (Some(scheme), _) if scheme != "http" && scheme != "https" => Err(format!(
+      "unsupported scheme {}, expected http, https or unix",
+      scheme
+    )),
+    _ => Err("expected scheme://host:port".to_string()),
+  }
+}
+
+/// Builder of a [`ServiceConf`], see [`ServiceConf::builder`].
+#[derive(Clone, Debug)]
+pub struct ServiceConfBuilder {
+  conf: ServiceConf,
 }
 
+impl ServiceConfBuilder {
+  pub fn with_instance(mut self, instance: ServiceInstance) -> Self {
+    self.conf.instances.push(instance);
+    self
+  }
+
+  /// Adds an instance at `address` with the default settings.
+  pub fn with_address(self, address: impl Into<String>) -> Self {
+    self.with_instance(ServiceInstance::new(address))
+  }
+
+  pub fn with_connections(mut self, connections: usize) -> Self {
+    self.conf.connections = Some(connections);
+    self
+  }
+
+  /// Allows `max_in_flight` concurrent requests per instance, with `max_queued` callers waiting.
+  pub fn with_concurrency_limit(mut self, max_in_flight: usize, max_queued: usize) -> Self {
+    self.conf.max_in_flight = Some(max_in_flight);
+    self.conf.max_queued = Some(max_queued);
+    self
+  }
+
+  pub fn with_slow_call_threshold(mut self, threshold: Duration) -> Self {
+    self.conf.slow_call_ms = Some(threshold.as_millis() as u64);
+    self
+  }
+
+  pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+    self.conf.connect_timeout_ms = Some(timeout.as_millis() as u64);
+    self
+  }
+
+  pub fn with_call_timeout(mut self, timeout: Duration) -> Self {
+    self.conf.call_timeout_ms = Some(timeout.as_millis() as u64);
+    self
+  }
+
+  /// Pings the connections every `interval`, closing those not acknowledging within `timeout`.
+  pub fn with_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+    self.conf.keepalive_interval_ms = Some(interval.as_millis() as u64);
+    self.conf.keepalive_timeout_ms = Some(timeout.as_millis() as u64);
+    self
+  }
+
+  pub fn with_tcp_nodelay(mut self, enabled: bool) -> Self {
+    self.conf.tcp_nodelay = Some(enabled);
+    self
+  }
+
+  pub fn with_max_message_bytes(mut self, max_bytes: usize) -> Self {
+    self.conf.max_message_bytes = Some(max_bytes);
+    self
+  }
+
+  pub fn with_tls(mut self, tls: TlsConf) -> Self {
+    self.conf.tls = Some(tls);
+    self
+  }
+
+  pub fn build(self) -> Result<ServiceConf, ServiceConfError> {
+    self.conf.validate()?;
+    Ok(self.conf)
+  }
+}
+
+#[allow(dead_code)]
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
   pub cluster: ServiceConf,
@@ -19,3 +381,62 @@ pub struct Config {
   pub system: ServiceConf,
   pub version: i64,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn build_should_validate_the_instances() {
+    let conf = ServiceConf::builder("clusters")
+      .with_instance(ServiceInstance::new("http://10.0.0.1:50051").with_location("eu-1a"))
+      .with_instance(ServiceInstance::new("unix:///run/clusters.sock").with_weight(2))
+      .with_concurrency_limit(8, 16)
+      .with_slow_call_threshold(Duration::from_millis(250))
+      .with_connect_timeout(Duration::from_secs(1))
+      .with_keepalive(Duration::from_secs(30), Duration::from_secs(10))
+      .with_tcp_nodelay(false)
+      .with_max_message_bytes(16 << 20)
+      .build()
+      .unwrap();
+    assert_eq!(conf.instances.len(), 2);
+    assert_eq!(conf.instances[1].weight(), 2);
+    assert_eq!(conf.max_in_flight, Some(8));
+    assert_eq!(conf.slow_call_ms, Some(250));
+    assert_eq!(conf.connect_timeout_ms, Some(1000));
+    assert_eq!(conf.keepalive_interval_ms, Some(30000));
+    assert_eq!(conf.keepalive_timeout_ms, Some(10000));
+    assert_eq!(conf.tcp_nodelay, Some(false));
+    assert_eq!(conf.max_message_bytes, Some(16 << 20));
+
+    let error = |builder: ServiceConfBuilder| builder.build().unwrap_err().to_string();
+    assert_eq!(error(ServiceConf::builder("")), "The service has no name");
+    assert_eq!(
+      error(ServiceConf::builder("clusters")),
+      "Service clusters has no instances"
+    );
+    assert_eq!(
+      error(
+        ServiceConf::builder("clusters")
+          .with_address("http://a:1")
+          .with_address("http://a:1")
+      ),
+      "Service clusters lists the instance http://a:1 more than once"
+    );
+    assert_eq!(
+      error(ServiceConf::builder("clusters").with_address("ftp://a:1")),
+      "Service clusters has an invalid instance address \"ftp://a:1\": unsupported scheme ftp, expected http, https or unix"
+    );
+    assert_eq!(
+      error(ServiceConf::builder("clusters").with_address("10.0.0.1:50051")),
+      "Service clusters has an invalid instance address \"10.0.0.1:50051\": expected scheme://host:port"
+    );
+    assert_eq!(
+      error(
+        ServiceConf::builder("clusters")
+          .with_instance(ServiceInstance::new("http://a:1").with_weight(0))
+      ),
+      "Service clusters gives the instance http://a:1 a weight of zero"
+    );
+  }
+}
@@ -1,10 +1,48 @@
 use std::num::ParseIntError;
 
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
 use tonic::metadata::errors::ToStrError;
 
+/// Header carrying the [`Priority`] of a request.
+pub const PRIORITY_HEADER: &str = "x-request-priority";
+
+/// Request header carrying the key identifying retries of the same request.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "x-idempotency-key";
+
 #[derive(Debug, Clone)]
 pub struct Context {
   user_id: i64,
+  priority: Priority,
+}
+
+/// Urgency of a request, inherited by the operations it enqueues. Interactive operations are
+/// pulled before the background ones waiting in the same queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+  /// A user is waiting for the outcome.
+  Interactive,
+  /// Batch and maintenance work.
+  #[default]
+  Background,
+}
+
+impl Priority {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Priority::Interactive => "interactive",
+      Priority::Background => "background",
+    }
+  }
+
+  pub fn parse(value: &str) -> Option<Priority> {
+    match value {
+      "interactive" => Some(Priority::Interactive),
+      "background" => Some(Priority::Background),
+      _ => None,
+    }
+  }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -24,7 +62,15 @@ impl From<Error> for tonic::Status {
 
 impl Context {
   pub fn new(user_id: i64) -> Context {
-    Context { user_id }
+    Context {
+      user_id,
+      priority: Priority::default(),
+    }
+  }
+
+  pub fn with_priority(mut self, priority: Priority) -> Self {
+    self.priority = priority;
+    self
   }
 
   pub fn from_request<T>(r: &tonic::Request<T>) -> Result<Context, Error> {
@@ -41,10 +87,66 @@ impl Context {
       Error::Malformed
     })?;
 
-    Ok(Context { user_id })
+    // An unknown priority must not fail the request, it is handled in the background.
+    let priority = r
+      .metadata()
+      .get(PRIORITY_HEADER)
+      .and_then(|value| {
+        let priority = value.to_str().ok().and_then(Priority::parse);
+        if priority.is_none() {
+          tracing::debug!(message = "Ignoring unknown `x-request-priority`", ?value);
+        }
+        priority
+      })
+      .unwrap_or_default();
+
+    Ok(Context { user_id, priority })
   }
 
   pub fn user_id(&self) -> i64 {
     self.user_id
   }
+
+  pub fn priority(&self) -> Priority {
+    self.priority
+  }
+
+  /// Sets the user and priority of the context on `request`, to be read by
+  /// [`Context::from_request`].
+  pub fn attach<T>(&self, request: &mut tonic::Request<T>) -> Result<(), Error> {
+    let user_id = self
+      .user_id
+      .to_string()
+      .parse()
+      .map_err(|_| Error::Malformed)?;
+    request.metadata_mut().insert("x-user-id", user_id);
+    request.metadata_mut().insert(
+      PRIORITY_HEADER,
+      tonic::metadata::MetadataValue::from_static(self.priority.as_str()),
+    );
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn from_request_should_read_the_attached_priority() {
+    let mut request = tonic::Request::new(());
+    Context::new(7)
+      .with_priority(Priority::Interactive)
+      .attach(&mut request)
+      .unwrap();
+    let context = Context::from_request(&request).unwrap();
+    assert_eq!(context.user_id(), 7);
+    assert_eq!(context.priority(), Priority::Interactive);
+
+    request
+      .metadata_mut()
+      .insert(PRIORITY_HEADER, "urgent".parse().unwrap());
+    let context = Context::from_request(&request).unwrap();
+    assert_eq!(context.priority(), Priority::Background);
+  }
 }
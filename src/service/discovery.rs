@@ -0,0 +1,300 @@
+//! Clients following the endpoints of their service as streamed by the
+//! `rappel.system.EndpointDiscovery` control plane, so routing changes roll out to every consumer
+//! without redeploying it.
+//!
+//! ```rust,ignore
+//! let discovery = ShardedClient::try_new(control_plane_conf, EndpointDiscoveryClient::new)?;
+//! let clusters = DiscoveredClient::subscribe(
+//!   discovery.borrow("clusters")?.clone(),
+//!   ShardedClient::try_new(clusters_conf.clone(), ClustersClient::new)?,
+//!   clusters_conf,
+//!   ClustersClient::new,
+//! );
+//! let lease = clusters.current().acquire(&cluster_id).await?;
+//! ```
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::proto::system::endpoint_discovery_client::EndpointDiscoveryClient;
+use crate::proto::system::ServiceEndpoints;
+use crate::proto::system::WatchEndpointsRequest;
+use crate::util::backoff::Backoff;
+
+use super::ClientChannel;
+use super::ServiceConf;
+use super::ShardedClient;
+
+pub type DiscoverySvcClient = EndpointDiscoveryClient<ClientChannel>;
+
+/// First delay before watching the endpoints again after the control plane failed.
+const REWATCH_BACKOFF: Duration = Duration::from_millis(500);
+
+/// A [`ShardedClient`] rebuilt for every update of the endpoints of its service. Updates keep the
+/// TLS of the local configuration, and its policies where the update leaves them unset, and the
+/// [`super::ShardMap`] and locality of the client.
+///
+/// Updates without valid instances are logged and skipped, and the last endpoints keep being used
+/// while the control plane is unavailable.
+#[derive(Clone, Debug)]
+pub struct DiscoveredClient<T: Clone> {
+  snapshots: watch::Receiver<Snapshot<T>>,
+  _follower: Arc<Follower>,
+}
+
+#[derive(Clone, Debug)]
+struct Snapshot<T: Clone> {
+  version: i64,
+  client: ShardedClient<T>,
+}
+
+impl<T> DiscoveredClient<T>
+where
+  T: Clone + Send + Sync + 'static,
+{
+  /// Routes with `initial` until the first update of `discovery` for the service of `conf`, then
+  /// with a client built from `conf` and the update through `builder`.
+  pub fn subscribe<F>(
+    discovery: DiscoverySvcClient,
+    initial: ShardedClient<T>,
+    conf: ServiceConf,
+    builder: F,
+  ) -> Self
+  where
+    F: Fn(ClientChannel) -> T + Send + Sync + 'static,
+  {
+    let (sender, snapshots) = watch::channel(Snapshot {
+      version: 0,
+      client: initial,
+    });
+    let follower = tokio::spawn(follow(discovery, conf, builder, sender));
+
+    Self {
+      snapshots,
+      _follower: Arc::new(Follower(follower)),
+    }
+  }
+
+  /// The client of the latest endpoints.
+  pub fn current(&self) -> ShardedClient<T> {
+    self.snapshots.borrow().client.clone()
+  }
+
+  /// Version of the latest endpoints, zero before the first update.
+  pub fn version(&self) -> i64 {
+    self.snapshots.borrow().version
+  }
+
+  /// Waits for the next update of the endpoints.
+  pub async fn changed(&mut self) -> Result<(), watch::error::RecvError> {
+    self.snapshots.changed().await
+  }
+}
+
+/// Watches the endpoints of the service of `conf` until every [`DiscoveredClient`] is dropped,
+/// watching again with a backoff when the stream fails or ends.
+async fn follow<T, F>(
+  mut discovery: DiscoverySvcClient,
+  conf: ServiceConf,
+  builder: F,
+  sender: watch::Sender<Snapshot<T>>,
+) where
+  T: Clone,
+  F: Fn(ClientChannel) -> T,
+{
+  let mut delays = Backoff::exponential(REWATCH_BACKOFF).delays();
+
+  while !sender.is_closed() {
+    let request = WatchEndpointsRequest {
+      services: vec![conf.name.clone()],
+    };
+
+    match discovery.watch(request).await {
+      Ok(response) => {
+        let mut updates = response.into_inner();
+        loop {
+          match updates.message().await {
+            Ok(Some(endpoints)) => {
+              delays.reset();
+              apply(&conf, &builder, &sender, endpoints);
+            }
+            Ok(None) => break,
+            Err(status) => {
+              tracing::warn!(message = "Endpoint watch failed", service = %conf.name, %status);
+              break;
+            }
+          }
+        }
+      }
+      Err(status) => {
+        tracing::warn!(message = "Failed to watch endpoints", service = %conf.name, %status)
+      }
+    }
+
+    tokio::time::sleep(delays.next().unwrap_or_default()).await;
+  }
+}
+
+fn apply<T, F>(
+  conf: &ServiceConf,
+  builder: &F,
+  sender: &watch::Sender<Snapshot<T>>,
+  endpoints: ServiceEndpoints,
+) where
+  T: Clone,
+  F: Fn(ClientChannel) -> T,
+{
+  let current = sender.borrow().clone();
+  if endpoints.service != conf.name || endpoints.version <= current.version {
+    return;
+  }
+
+  match ShardedClient::try_new(conf.with_endpoints(&endpoints), builder) {
+    Ok(client) => {
+      tracing::info!(
+        message = "Routing to discovered endpoints",
+        service = %conf.name,
+        version = endpoints.version,
+        instances = endpoints.endpoints.len()
+      );
+      sender.send_replace(Snapshot {
+        version: endpoints.version,
+        client: client.with_routing_of(&current.client),
+      });
+    }
+    Err(error) => {
+      tracing::warn!(
+        message = "Ignoring invalid endpoints",
+        service = %conf.name,
+        version = endpoints.version,
+        %error
+      )
+    }
+  }
+}
+
+/// Task of [`follow`], aborted with the last [`DiscoveredClient`].
+#[derive(Debug)]
+struct Follower(JoinHandle<()>);
+
+impl Drop for Follower {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::Stream;
+  use tokio::net::TcpListener;
+  use tonic::transport::Channel;
+  use tonic::transport::Server;
+  use tower_layer::Layer;
+
+  use crate::proto::system::endpoint_discovery_server::EndpointDiscovery;
+  use crate::proto::system::endpoint_discovery_server::EndpointDiscoveryServer;
+  use crate::proto::system::Endpoint;
+
+  use super::super::ResponseLimitLayer;
+  use super::super::ServiceInstance;
+  use super::super::SlowCallLayer;
+  use super::*;
+
+  /// Sends its updates, then keeps the stream open.
+  struct FakeDiscovery(Vec<ServiceEndpoints>);
+
+  type Updates =
+    std::pin::Pin<Box<dyn Stream<Item = Result<ServiceEndpoints, tonic::Status>> + Send>>;
+
+  #[tonic::async_trait]
+  impl EndpointDiscovery for FakeDiscovery {
+    type WatchStream = Updates;
+
+    async fn watch(
+      &self,
+      _: tonic::Request<WatchEndpointsRequest>,
+    ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status> {
+      let updates = futures::stream::iter(self.0.clone().into_iter().map(Ok));
+      let updates = futures::StreamExt::chain(updates, futures::stream::pending());
+      Ok(tonic::Response::new(Box::pin(updates)))
+    }
+  }
+
+  fn endpoints(version: i64, addresses: &[&str]) -> ServiceEndpoints {
+    ServiceEndpoints {
+      service: "clusters".to_string(),
+      version,
+      endpoints: addresses
+        .iter()
+        .map(|address| Endpoint {
+          address: address.to_string(),
+          ..Default::default()
+        })
+        .collect(),
+      policy: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn subscribe_should_follow_valid_updates() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+      let stream = listener.accept().await.map(|(stream, _)| stream);
+      Some((stream, listener))
+    });
+    let discovery = FakeDiscovery(vec![
+      endpoints(1, &[]),
+      endpoints(2, &["http://10.0.0.2:50051", "http://10.0.0.3:50051"]),
+      endpoints(1, &["http://10.0.0.4:50051"]),
+    ]);
+    tokio::spawn(
+      Server::builder()
+        .add_service(EndpointDiscoveryServer::new(discovery))
+        .serve_with_incoming(incoming),
+    );
+
+    let conf = ServiceConf::builder("clusters")
+      .with_instance(ServiceInstance::new("http://10.0.0.1:50051"))
+      .build()
+      .unwrap();
+    let address = format!("http://{}", address);
+    let channel = Channel::from_shared(address.clone())
+      .unwrap()
+      .connect_lazy();
+    let channel = SlowCallLayer::client(&address, Duration::from_secs(1))
+      .layer(ResponseLimitLayer::default().layer(channel));
+    let mut client = DiscoveredClient::subscribe(
+      EndpointDiscoveryClient::new(channel),
+      ShardedClient::try_new(conf.clone(), |channel| channel).unwrap(),
+      conf,
+      |channel| channel,
+    );
+    assert_eq!(client.version(), 0);
+    assert_eq!(
+      client.current().address("key").unwrap(),
+      "http://10.0.0.1:50051"
+    );
+
+    tokio::time::timeout(Duration::from_secs(5), client.changed())
+      .await
+      .unwrap()
+      .unwrap();
+
+    // The empty and the outdated updates are skipped.
+    assert_eq!(client.version(), 2);
+    for key in ["a", "b", "c", "d"] {
+      let current = client.current();
+      let address = current.address(key).unwrap();
+      assert!(
+        ["http://10.0.0.2:50051", "http://10.0.0.3:50051"].contains(&address),
+        "{} routed to {}",
+        key,
+        address
+      );
+    }
+  }
+}
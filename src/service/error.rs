@@ -1,23 +1,105 @@
 use std::net::AddrParseError;
 use tonic::codegen::http::uri::InvalidUri;
 
+use crate::util::error_context::Contextual;
+use crate::util::error_context::ErrorContext;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
   #[error("Config Error: {0}")]
   ConfigError(#[from] config::ConfigError),
 
+  #[error("Invalid service config: {0}")]
+  InvalidConf(#[from] super::ServiceConfError),
+
   #[error("Invalid Uri: {0}")]
   InvalidUriError(#[from] InvalidUri),
 
   #[error("Cannot field client for key: {0}")]
   MissingClient(String),
 
+  #[error("Too many requests queued for: {0}")]
+  ResourceExhausted(String),
+
+  #[error("No shard map configured for service: {0}")]
+  MissingShardMap(String),
+
+  #[error("Shard Map Error: {0}")]
+  ShardMapError(#[from] redis::RedisError),
+
   #[error("Tonic Transport Error: {0}")]
   TonicTransportError(#[from] tonic::transport::Error),
 
+  #[error("Missing secret: {0}")]
+  MissingSecret(String),
+
+  #[error("TLS Error: {0}")]
+  TlsError(String),
+
+  #[error("IO Error: {0}")]
+  IoError(#[from] std::io::Error),
+
   #[error("AddrParseError: {0}")]
   AddrParseError(#[from] AddrParseError),
 
   #[error("Unknown: {0}")]
   Unknown(#[from] anyhow::Error),
+
+  /// An error with the service it is about, see [`Contextual`]. Match on [`Self::inner`].
+  #[error("{source} ({context})")]
+  Context {
+    context: ErrorContext,
+    source: Box<Error>,
+  },
+}
+
+impl Error {
+  /// The error without its context, e.g. to match on its kind.
+  pub fn inner(&self) -> &Self {
+    match self {
+      Error::Context { source, .. } => source.inner(),
+      error => error,
+    }
+  }
+
+  /// The error without its context, and its context.
+  pub fn split(self) -> (Self, Option<ErrorContext>) {
+    match self {
+      Error::Context { context, source } => (*source, Some(context)),
+      error => (error, None),
+    }
+  }
+}
+
+impl Contextual for Error {
+  fn with_context(self, context: ErrorContext) -> Self {
+    let (source, inner) = self.split();
+    let context = match inner {
+      Some(inner) => inner.or(context),
+      None => context,
+    };
+
+    Error::Context {
+      context,
+      source: Box::new(source),
+    }
+  }
+
+  fn context(&self) -> Option<&ErrorContext> {
+    match self {
+      Error::Context { context, .. } => Some(context),
+      _ => None,
+    }
+  }
+}
+
+/// The context of the error is kept in the message.
+impl From<Error> for tonic::Status {
+  fn from(error: Error) -> Self {
+    match error.inner() {
+      Error::ResourceExhausted(_) => tonic::Status::resource_exhausted(error.to_string()),
+      Error::MissingClient(_) => tonic::Status::unavailable(error.to_string()),
+      _ => tonic::Status::internal(error.to_string()),
+    }
+  }
 }
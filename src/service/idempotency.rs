@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+use prost::Message;
+use redis::AsyncCommands;
+use tonic::Code;
+use tracing_futures::Instrument;
+
+use crate::codec::protobuf::ProtobufCodec;
+use crate::codec::Codec;
+use crate::codec::Decoder;
+use crate::codec::Encoder;
+use crate::redis::Keys;
+use crate::util::redis_exec;
+
+use super::IDEMPOTENCY_KEY_HEADER;
+
+/// Time the responses are kept for replay by default.
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Claims a key for a request unless it was already claimed, in which case the stored entry is
+/// returned.
+///
+/// KEYS: idempotency entry. ARGV: TTL in milliseconds.
+const CLAIM_SCRIPT: &str = r"
+if redis.call('HSETNX', KEYS[1], 'state', 'pending') == 1 then
+  redis.call('PEXPIRE', KEYS[1], ARGV[1])
+  return {}
+end
+return redis.call('HGETALL', KEYS[1])
+";
+
+/// Makes mutation RPCs idempotent: the first response to a request with an
+/// [`IDEMPOTENCY_KEY_HEADER`] is stored, and replayed to the requests with the same key, method
+/// and user until the TTL expires. Requests without the header always run.
+///
+/// Successful responses and definitive errors are replayed. Transient errors, e.g. `UNAVAILABLE`,
+/// release the key so the client can retry. A duplicate arriving while the first request still
+/// runs fails with `ABORTED`.
+///
+/// ```rust,ignore
+/// async fn create_workspace(
+///   &self,
+///   request: Request<CreateWorkspaceRequest>,
+/// ) -> Result<Response<Workspace>, Status> {
+///   let idempotency = self.idempotency.clone();
+///   idempotency
+///     .run(&request, "CreateWorkspace", || self.create(request.get_ref()))
+///     .await
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Idempotency {
+  client: redis::Client,
+  keys: Keys,
+  ttl: Duration,
+}
+
+impl Idempotency {
+  pub fn new(client: redis::Client) -> Self {
+    Self {
+      client,
+      keys: Keys::default(),
+      ttl: DEFAULT_IDEMPOTENCY_TTL,
+    }
+  }
+
+  pub fn with_keys(mut self, keys: Keys) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Keeps the responses for `ttl`, [`DEFAULT_IDEMPOTENCY_TTL`] by default.
+  pub fn with_ttl(mut self, ttl: Duration) -> Self {
+    self.ttl = ttl;
+    self
+  }
+
+  /// Runs `handler` for the first request with a given idempotency key and replays its outcome
+  /// for the next ones.
+  pub async fn run<T, R, F, Fut>(
+    &self,
+    request: &tonic::Request<T>,
+    method: &str,
+    handler: F,
+  ) -> Result<tonic::Response<R>, tonic::Status>
+  where
+    R: Message + Default,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<R, tonic::Status>>,
+  {
+    let idempotency_key = match request.metadata().get(IDEMPOTENCY_KEY_HEADER) {
+      None => return handler().await.map(tonic::Response::new),
+      Some(value) => value.to_str().map_err(|_| {
+        tonic::Status::invalid_argument(format!("Malformed {}", IDEMPOTENCY_KEY_HEADER))
+      })?,
+    };
+
+    // Keys are scoped per user, so users cannot read each other's responses.
+    let user_id = request
+      .metadata()
+      .get("x-user-id")
+      .and_then(|value| value.to_str().ok())
+      .unwrap_or_default();
+    let key = self
+      .keys
+      .idempotency(&format!("{}:{}:{}", method, user_id, idempotency_key));
+
+    let mut conn = redis_exec::connect(&self.client).await.map_err(internal)?;
+
+    let mut entry: HashMap<String, Vec<u8>> = redis::Script::new(CLAIM_SCRIPT)
+      .key(&key)
+      .arg(self.ttl.as_millis() as u64)
+      .invoke_async(&mut conn)
+      .instrument(tracing::info_span!("redis-idempotency-claim", %method))
+      .await
+      .map_err(internal)?;
+
+    if !entry.is_empty() {
+      tracing::debug!(message = "Replaying idempotent request", %method, %idempotency_key);
+      let mut text = |field: &str| {
+        entry
+          .remove(field)
+          .map(|value| String::from_utf8_lossy(&value).into_owned())
+          .unwrap_or_default()
+      };
+
+      return match text("state").as_str() {
+        "done" => {
+          let mut payload = entry.remove("payload").unwrap_or_default();
+          ProtobufCodec::<R>::new()
+            .decoder()
+            .decode(&mut payload)
+            .map_err(internal)?
+            .map(tonic::Response::new)
+            .ok_or_else(|| tonic::Status::internal("Empty idempotent response"))
+        }
+        "error" => {
+          let code = Code::from_i32(text("code").parse().unwrap_or(Code::Unknown as i32));
+          let message = text("message");
+          let details = entry.remove("details").unwrap_or_default();
+          Err(tonic::Status::with_details(code, message, details.into()))
+        }
+        _ => Err(tonic::Status::aborted(
+          "A request with the same idempotency key is in progress",
+        )),
+      };
+    }
+
+    let result = handler().await;
+
+    let stored: Result<(), redis::RedisError> = match &result {
+      Err(status) if is_transient(status.code()) => conn.del(&key).await,
+      Err(status) => {
+        let code = (status.code() as i32).to_string();
+        let fields: [(&str, &[u8]); 4] = [
+          ("state", b"error"),
+          ("code", code.as_bytes()),
+          ("message", status.message().as_bytes()),
+          ("details", status.details()),
+        ];
+        conn.hset_multiple(&key, &fields).await
+      }
+      Ok(response) => {
+        let mut payload = Vec::default();
+        let encoded = ProtobufCodec::<R>::new()
+          .encoder()
+          .encode(response, &mut payload);
+        match encoded {
+          Ok(_) => {
+            let fields: [(&str, &[u8]); 2] = [("state", b"done"), ("payload", &payload)];
+            conn.hset_multiple(&key, &fields).await
+          }
+          Err(error) => {
+            tracing::warn!(message = "Failed to encode the idempotent response", %error);
+            conn.del(&key).await
+          }
+        }
+      }
+    };
+
+    if let Err(error) = stored {
+      tracing::warn!(message = "Failed to store the idempotent response", %method, %error);
+    }
+
+    result.map(tonic::Response::new)
+  }
+}
+
+/// Errors worth retrying with the same idempotency key.
+fn is_transient(code: Code) -> bool {
+  matches!(
+    code,
+    Code::Unavailable | Code::DeadlineExceeded | Code::Aborted | Code::Cancelled | Code::Unknown
+  )
+}
+
+fn internal(error: impl std::fmt::Display) -> tonic::Status {
+  tonic::Status::internal(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+
+  use crate::proto::longrunning::GetOperationRequest;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn run_should_replay_the_first_outcome() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let idempotency =
+      Idempotency::new(client).with_keys(Keys::new(&format!("{}:", uuid::Uuid::new_v4())));
+    let calls = AtomicUsize::default();
+
+    let request = |key: &str| {
+      let mut request = tonic::Request::new(());
+      request
+        .metadata_mut()
+        .insert(IDEMPOTENCY_KEY_HEADER, key.parse().unwrap());
+      request
+    };
+    let handler = |status: Option<tonic::Status>| {
+      let n = calls.fetch_add(1, Ordering::SeqCst);
+      async move {
+        match status {
+          Some(status) => Err(status),
+          None => Ok(GetOperationRequest {
+            operation_id: n.to_string(),
+          }),
+        }
+      }
+    };
+
+    for _ in 0..2 {
+      let response = idempotency
+        .run(&request("a"), "Create", || handler(None))
+        .await
+        .unwrap();
+      assert_eq!(response.get_ref().operation_id, "0");
+    }
+
+    for _ in 0..2 {
+      let status = idempotency
+        .run(&request("b"), "Create", || {
+          handler(Some(tonic::Status::already_exists("taken")))
+        })
+        .await
+        .unwrap_err();
+      assert_eq!(
+        (status.code(), status.message()),
+        (Code::AlreadyExists, "taken")
+      );
+    }
+
+    for _ in 0..2 {
+      let status = idempotency
+        .run(&request("c"), "Create", || {
+          handler(Some(tonic::Status::unavailable("down")))
+        })
+        .await
+        .unwrap_err();
+      assert_eq!(status.code(), Code::Unavailable);
+    }
+
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+  }
+}
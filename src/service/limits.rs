@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::Deserialize;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::codegen::Body as HttpBody;
+use tonic::codegen::BoxFuture;
+#[cfg(feature = "redis")]
+use tonic::transport::server::TcpConnectInfo;
+#[cfg(feature = "redis")]
+use tonic::transport::server::TlsConnectInfo;
+use tonic::transport::Body;
+use tower_layer::Layer;
+use tower_service::Service;
+
+#[cfg(feature = "redis")]
+use crate::quota::QuotaError;
+#[cfg(feature = "redis")]
+use crate::quota::RedisRateLimiter;
+
+#[cfg(feature = "redis")]
+use super::trace::remote_addr;
+
+type BodyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Length of the prefix of the gRPC frames: a compression flag and the length of the message.
+const FRAME_PREFIX_LEN: usize = 5;
+
+/// Limits of the requests to a method. Unset limits are not enforced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct RequestLimits {
+  /// Bytes of each message of a call, as encoded on the wire.
+  #[serde(default)]
+  pub max_message_bytes: Option<usize>,
+  /// Calls per second of a principal, the client certificate of the caller or its address. Only
+  /// enforced with the `redis` feature.
+  #[serde(default)]
+  pub requests_per_second: Option<u32>,
+}
+
+impl RequestLimits {
+  /// These limits, falling back to `defaults` for the unset ones.
+  fn or(self, defaults: RequestLimits) -> Self {
+    Self {
+      max_message_bytes: self.max_message_bytes.or(defaults.max_message_bytes),
+      requests_per_second: self.requests_per_second.or(defaults.requests_per_second),
+    }
+  }
+}
+
+/// Limits of the requests of a server, keyed by method path, e.g.
+/// `/rappel.workspace.Workspaces/CreateWorkspace`:
+///
+/// ```yaml
+/// limits:
+///   default:
+///     max_message_bytes: 4194304
+///   methods:
+///     /rappel.workspace.Workspaces/CreateWorkspace:
+///       requests_per_second: 5
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct ServerLimits {
+  /// Limits of the methods without their own, and of the limits they leave unset.
+  #[serde(default)]
+  pub default: RequestLimits,
+  #[serde(default)]
+  pub methods: HashMap<String, RequestLimits>,
+}
+
+impl ServerLimits {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_default(mut self, limits: RequestLimits) -> Self {
+    self.default = limits;
+    self
+  }
+
+  /// Sets the limits of `method`, e.g. `/rappel.workspace.Workspaces/CreateWorkspace`.
+  pub fn with_method(mut self, method: &str, limits: RequestLimits) -> Self {
+    self.methods.insert(method.to_string(), limits);
+    self
+  }
+
+  /// Limits of the requests to `method`.
+  pub fn limits(&self, method: &str) -> RequestLimits {
+    match self.methods.get(method) {
+      Some(limits) => limits.or(self.default),
+      None => self.default,
+    }
+  }
+}
+
+/// Enforces the [`ServerLimits`] of a server, see [`RequestLimit`].
+#[derive(Clone, Debug, Default)]
+pub struct RequestLimitLayer {
+  limits: Arc<ServerLimits>,
+  #[cfg(feature = "redis")]
+  rate_limiter: Option<RedisRateLimiter>,
+}
+
+impl RequestLimitLayer {
+  pub fn new(limits: ServerLimits) -> Self {
+    Self {
+      limits: Arc::new(limits),
+      #[cfg(feature = "redis")]
+      rate_limiter: None,
+    }
+  }
+
+  /// Counts the requests per second in `rate_limiter`. Rates are not limited without one.
+  #[cfg(feature = "redis")]
+  pub fn with_rate_limiter(mut self, rate_limiter: RedisRateLimiter) -> Self {
+    self.rate_limiter = Some(rate_limiter);
+    self
+  }
+}
+
+impl<S> Layer<S> for RequestLimitLayer {
+  type Service = RequestLimit<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    RequestLimit {
+      inner,
+      layer: self.clone(),
+    }
+  }
+}
+
+/// Rejects with `RESOURCE_EXHAUSTED` the calls of a principal exceeding the requests per second
+/// of their method, with a `RetryInfo` detail telling when to retry, and the calls sending a
+/// message larger than allowed. Rates are counted in Redis, so they hold across the replicas of a
+/// server; they are not enforced while Redis is unavailable, nor for the anonymous callers of a
+/// Unix domain socket, which cannot be told apart.
+#[derive(Clone, Debug)]
+pub struct RequestLimit<S> {
+  inner: S,
+  layer: RequestLimitLayer,
+}
+
+impl<S> Service<http::Request<Body>> for RequestLimit<S>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = BoxFuture<S::Response, S::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+    let method = request.uri().path().to_string();
+    let limits = self.layer.limits.limits(&method);
+
+    let request = match limits.max_message_bytes {
+      Some(max_bytes) => map_body(request, |body| limit_messages(body, max_bytes)),
+      None => request,
+    };
+
+    #[cfg(feature = "redis")]
+    if let (Some(rate_limiter), Some(per_second)) =
+      (&self.layer.rate_limiter, limits.requests_per_second)
+    {
+      return self.rate_limited(request, method, rate_limiter.clone(), per_second);
+    }
+
+    Box::pin(self.inner.call(request))
+  }
+}
+
+#[cfg(feature = "redis")]
+impl<S> RequestLimit<S>
+where
+  S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+{
+  /// Calls the inner service unless the principal of `request` exceeds `per_second` calls of
+  /// `method`.
+  fn rate_limited(
+    &mut self,
+    request: http::Request<Body>,
+    method: String,
+    rate_limiter: RedisRateLimiter,
+    per_second: u32,
+  ) -> BoxFuture<S::Response, S::Error> {
+    let principal = match principal(&request) {
+      Some(principal) => principal,
+      None => {
+        tracing::debug!(message = "Not rate limiting an anonymous request", %method);
+        return Box::pin(self.inner.call(request));
+      }
+    };
+
+    // The ready service is taken for this call, leaving a clone to be readied for the next one.
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+
+    Box::pin(async move {
+      match rate_limiter.acquire(&principal, &method, per_second).await {
+        Ok(()) => {}
+        Err(error @ QuotaError::RateLimited { .. }) => {
+          tracing::warn!(message = "Rate limited request", %method, %principal, per_second);
+          return Ok(tonic::Status::from(error).to_http());
+        }
+        Err(error) => {
+          tracing::warn!(message = "Failed to count the request rate", %method, %error);
+        }
+      }
+
+      inner.call(request).await
+    })
+  }
+}
+
+/// Rate limiting subject of the caller of `request`: the certificate it authenticated with over
+/// mutual TLS, or the IP address it connects from. Request headers such as `x-user-id` are chosen
+/// by the caller and never used. `None` for the anonymous callers of a Unix domain socket.
+#[cfg(feature = "redis")]
+fn principal(request: &http::Request<Body>) -> Option<String> {
+  let certificate = request
+    .extensions()
+    .get::<TlsConnectInfo<TcpConnectInfo>>()
+    .and_then(TlsConnectInfo::peer_certs)
+    .and_then(|certificates| certificates.first().cloned());
+  if let Some(certificate) = certificate {
+    let digest = ring::digest::digest(&ring::digest::SHA256, certificate.get_ref());
+    let fingerprint: String = digest
+      .as_ref()
+      .iter()
+      .map(|byte| format!("{:02x}", byte))
+      .collect();
+    return Some(format!("client:{}", fingerprint));
+  }
+
+  remote_addr(request).map(|address| format!("peer:{}", address.ip()))
+}
+
+/// Limits the size of the messages of the responses of a client channel, see [`ResponseLimit`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResponseLimitLayer {
+  max_message_bytes: Option<usize>,
+}
+
+impl ResponseLimitLayer {
+  /// Limits each message received to `max_message_bytes`, as encoded on the wire. Messages are
+  /// not limited when unset.
+  pub fn new(max_message_bytes: Option<usize>) -> Self {
+    Self { max_message_bytes }
+  }
+}
+
+impl<S> Layer<S> for ResponseLimitLayer {
+  type Service = ResponseLimit<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    ResponseLimit {
+      inner,
+      max_message_bytes: self.max_message_bytes,
+    }
+  }
+}
+
+/// Fails the responses of a channel with `RESOURCE_EXHAUSTED` as soon as they announce a message
+/// larger than allowed, before the message is buffered, see [`LimitedBody`].
+#[derive(Clone, Debug)]
+pub struct ResponseLimit<S> {
+  inner: S,
+  max_message_bytes: Option<usize>,
+}
+
+impl<S, B, R> Service<http::Request<B>> for ResponseLimit<S>
+where
+  S: Service<http::Request<B>, Response = http::Response<R>>,
+  S::Future: Send + 'static,
+  R: Send + 'static,
+{
+  type Response = http::Response<LimitedBody<R>>;
+  type Error = S::Error;
+  type Future = BoxFuture<Self::Response, S::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    let max_message_bytes = self.max_message_bytes;
+    let response = self.inner.call(request);
+
+    Box::pin(async move {
+      let response = response.await?;
+      Ok(response.map(|body| LimitedBody::new(body, max_message_bytes)))
+    })
+  }
+}
+
+/// A body failing with `RESOURCE_EXHAUSTED` once one of its gRPC frames announces a message of
+/// more than its limit, see [`FrameLimit`]. Unlike [`limit_messages`] it keeps the trailers, which
+/// carry the status of the responses.
+#[derive(Debug)]
+pub struct LimitedBody<B> {
+  inner: B,
+  frames: Option<FrameLimit>,
+}
+
+impl<B> LimitedBody<B> {
+  fn new(inner: B, max_bytes: Option<usize>) -> Self {
+    Self {
+      inner,
+      frames: max_bytes.map(FrameLimit::new),
+    }
+  }
+}
+
+impl<B> HttpBody for LimitedBody<B>
+where
+  B: HttpBody<Data = Bytes> + Unpin,
+  B::Error: Into<BodyError>,
+{
+  type Data = Bytes;
+  type Error = BodyError;
+
+  fn poll_data(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    let this = &mut *self;
+    let chunk = match Pin::new(&mut this.inner).poll_data(cx) {
+      Poll::Ready(Some(Ok(chunk))) => chunk,
+      Poll::Ready(Some(Err(error))) => return Poll::Ready(Some(Err(error.into()))),
+      Poll::Ready(None) => return Poll::Ready(None),
+      Poll::Pending => return Poll::Pending,
+    };
+
+    match this.frames.as_mut().map(|frames| frames.check(&chunk)) {
+      Some(Err(error)) => Poll::Ready(Some(Err(Box::new(tonic::Status::from(error))))),
+      _ => Poll::Ready(Some(Ok(chunk))),
+    }
+  }
+
+  fn poll_trailers(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+    Pin::new(&mut self.inner)
+      .poll_trailers(cx)
+      .map_err(Into::into)
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.inner.is_end_stream()
+  }
+}
+
+fn map_body<B>(request: http::Request<B>, f: impl FnOnce(B) -> B) -> http::Request<B> {
+  let (parts, body) = request.into_parts();
+  http::Request::from_parts(parts, f(body))
+}
+
+/// Fails `body` with `RESOURCE_EXHAUSTED` as soon as the prefix of one of its gRPC frames
+/// announces a message of more than `max_bytes`, before the message is buffered. Handlers then
+/// fail with the status of the body.
+pub(crate) fn limit_messages(body: Body, max_bytes: usize) -> Body {
+  let mut frames = FrameLimit::new(max_bytes);
+  let body = body.map(move |chunk| {
+    let chunk = chunk.map_err(|error| Box::new(error) as BodyError)?;
+    match frames.check(&chunk) {
+      Ok(()) => Ok(chunk),
+      Err(error) => Err(Box::new(tonic::Status::from(error)) as BodyError),
+    }
+  });
+
+  Body::wrap_stream(body)
+}
+
+/// A gRPC frame announcing a message larger than the limit of its [`FrameLimit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Message of {length} bytes exceeds the limit of {max_bytes} bytes")]
+pub(crate) struct MessageTooLarge {
+  pub length: usize,
+  pub max_bytes: usize,
+}
+
+impl From<MessageTooLarge> for tonic::Status {
+  fn from(error: MessageTooLarge) -> Self {
+    tonic::Status::resource_exhausted(error.to_string())
+  }
+}
+
+/// Follows the gRPC frames of a body chunk after chunk, checking the length in the prefix of
+/// every frame.
+#[derive(Clone, Debug)]
+pub(crate) struct FrameLimit {
+  max_bytes: usize,
+  prefix: [u8; FRAME_PREFIX_LEN],
+  prefix_len: usize,
+  /// Bytes of the message of the current frame not read yet.
+  remaining: usize,
+}
+
+impl FrameLimit {
+  pub(crate) fn new(max_bytes: usize) -> Self {
+    Self {
+      max_bytes,
+      prefix: [0; FRAME_PREFIX_LEN],
+      prefix_len: 0,
+      remaining: 0,
+    }
+  }
+
+  /// Reads the next `chunk` of the body, failing if it starts a message of more than the limit.
+  pub(crate) fn check(&mut self, mut chunk: &[u8]) -> Result<(), MessageTooLarge> {
+    while !chunk.is_empty() {
+      if self.remaining > 0 {
+        let skipped = self.remaining.min(chunk.len());
+        self.remaining -= skipped;
+        chunk = &chunk[skipped..];
+        continue;
+      }
+
+      let read = (FRAME_PREFIX_LEN - self.prefix_len).min(chunk.len());
+      self.prefix[self.prefix_len..self.prefix_len + read].copy_from_slice(&chunk[..read]);
+      self.prefix_len += read;
+      chunk = &chunk[read..];
+
+      if self.prefix_len == FRAME_PREFIX_LEN {
+        self.prefix_len = 0;
+        let length = u32::from_be_bytes([
+          self.prefix[1],
+          self.prefix[2],
+          self.prefix[3],
+          self.prefix[4],
+        ]) as usize;
+        if length > self.max_bytes {
+          return Err(MessageTooLarge {
+            length,
+            max_bytes: self.max_bytes,
+          });
+        }
+        self.remaining = length;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[cfg(feature = "redis")]
+  #[test]
+  fn principal_should_ignore_the_user_id_header() {
+    let request = http::Request::builder()
+      .header("x-user-id", "42")
+      .body(Body::empty())
+      .unwrap();
+
+    assert_eq!(principal(&request), None);
+  }
+
+  #[test]
+  fn limits_should_fall_back_to_the_defaults() {
+    let limits = ServerLimits::new()
+      .with_default(RequestLimits {
+        max_message_bytes: Some(1024),
+        requests_per_second: None,
+      })
+      .with_method(
+        "/rappel.workspace.Workspaces/CreateWorkspace",
+        RequestLimits {
+          max_message_bytes: None,
+          requests_per_second: Some(5),
+        },
+      );
+
+    assert_eq!(
+      limits.limits("/rappel.workspace.Workspaces/CreateWorkspace"),
+      RequestLimits {
+        max_message_bytes: Some(1024),
+        requests_per_second: Some(5),
+      }
+    );
+    assert_eq!(
+      limits.limits("/rappel.workspace.Workspaces/GetWorkspace"),
+      RequestLimits {
+        max_message_bytes: Some(1024),
+        requests_per_second: None,
+      }
+    );
+  }
+
+  /// Reads the whole body of the requests, failing with the status of the body if it fails.
+  #[derive(Clone)]
+  struct ReadBody;
+
+  impl Service<http::Request<Body>> for ReadBody {
+    type Response = http::Response<BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+      Box::pin(async move {
+        let mut body = request.into_body();
+        while let Some(chunk) = body.next().await {
+          if let Err(error) = chunk {
+            // Like tonic, answers with the status found in the source chain of the error.
+            let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&error);
+            while let Some(error) = source {
+              if let Some(status) = error.downcast_ref::<tonic::Status>() {
+                return Ok(tonic::Status::new(status.code(), status.message()).to_http());
+              }
+              source = error.source();
+            }
+            return Ok(tonic::Status::unknown(error.to_string()).to_http());
+          }
+        }
+        Ok(tonic::Status::new(tonic::Code::Ok, "").to_http())
+      })
+    }
+  }
+
+  async fn code(service: &mut RequestLimit<ReadBody>, request: http::Request<Body>) -> tonic::Code {
+    let response = service.call(request).await.unwrap();
+    tonic::Status::from_header_map(response.headers())
+      .map(|status| status.code())
+      .unwrap_or(tonic::Code::Ok)
+  }
+
+  /// gRPC frame of a `len` bytes message.
+  fn frame(len: usize) -> Vec<u8> {
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(len as u32).to_be_bytes());
+    frame.extend(std::iter::repeat_n(b'x', len));
+    frame
+  }
+
+  #[test]
+  fn frame_limit_should_check_each_message() {
+    let mut frames = FrameLimit::new(10);
+    let stream: Vec<u8> = (0..100).flat_map(|_| frame(10)).collect();
+    // Chunks split the prefixes and messages anywhere.
+    for chunk in stream.chunks(3) {
+      frames.check(chunk).unwrap();
+    }
+
+    frames.check(&frame(11)[..4]).unwrap();
+    assert_eq!(
+      frames.check(&[11]),
+      Err(MessageTooLarge {
+        length: 11,
+        max_bytes: 10
+      })
+    );
+  }
+
+  #[tokio::test]
+  async fn request_limit_should_reject_oversized_messages() {
+    let limits = ServerLimits::new().with_method(
+      "/Upload",
+      RequestLimits {
+        max_message_bytes: Some(10),
+        requests_per_second: None,
+      },
+    );
+    let mut service = RequestLimitLayer::new(limits).layer(ReadBody);
+    let request = |frames: Vec<Vec<u8>>| {
+      http::Request::builder()
+        .uri("/Upload")
+        .body(Body::from(frames.concat()))
+        .unwrap()
+    };
+
+    assert_eq!(
+      code(&mut service, request(vec![frame(5), frame(11)])).await,
+      tonic::Code::ResourceExhausted
+    );
+    // Small messages are allowed however many the call sends.
+    assert_eq!(
+      code(&mut service, request(vec![frame(10); 50])).await,
+      tonic::Code::Ok
+    );
+
+    let unlimited = http::Request::new(Body::from(frame(11)));
+    assert_eq!(code(&mut service, unlimited).await, tonic::Code::Ok);
+  }
+
+  /// Answers every request with the frames of its body, then `grpc-status: 0` trailers.
+  #[derive(Clone)]
+  struct Respond(Vec<Vec<u8>>);
+
+  impl Service<http::Request<()>> for Respond {
+    type Response = http::Response<Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: http::Request<()>) -> Self::Future {
+      let frames = self.0.clone();
+      Box::pin(async move {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+          for frame in frames {
+            sender.send_data(frame.into()).await.unwrap();
+          }
+          let mut trailers = http::HeaderMap::new();
+          trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+          sender.send_trailers(trailers).await.unwrap();
+        });
+        Ok(http::Response::new(body))
+      })
+    }
+  }
+
+  #[tokio::test]
+  async fn response_limit_should_reject_oversized_messages() {
+    let mut service = ResponseLimitLayer::new(Some(10)).layer(Respond(vec![frame(10); 3]));
+    let mut body = service
+      .call(http::Request::new(()))
+      .await
+      .unwrap()
+      .into_body();
+    while let Some(chunk) = body.data().await {
+      chunk.unwrap();
+    }
+    let trailers = body.trailers().await.unwrap().unwrap();
+    assert_eq!(trailers["grpc-status"], "0");
+
+    let mut service = ResponseLimitLayer::new(Some(10)).layer(Respond(vec![frame(5), frame(11)]));
+    let mut body = service
+      .call(http::Request::new(()))
+      .await
+      .unwrap()
+      .into_body();
+    body.data().await.unwrap().unwrap();
+    let error = body.data().await.unwrap().unwrap_err();
+    let status = error.downcast_ref::<tonic::Status>().unwrap();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+    let mut service = ResponseLimitLayer::default().layer(Respond(vec![frame(11)]));
+    let mut body = service
+      .call(http::Request::new(()))
+      .await
+      .unwrap()
+      .into_body();
+    assert_eq!(body.data().await.unwrap().unwrap().len(), 16);
+  }
+}
@@ -1,33 +1,54 @@
+use crate::proto::account::billing_accounts_client::BillingAccountsClient;
+use crate::proto::account::organizations_client::OrganizationsClient;
 use crate::proto::longrunning::operations_client::OperationsClient;
+use crate::proto::process::process_manager_client::ProcessManagerClient;
 use crate::proto::system::clusters_client::ClustersClient;
+use crate::proto::system::locations_client::LocationsClient;
+use crate::proto::system::Location;
+use crate::proto::workspace::ide_manager_client::IdeManagerClient;
+use crate::proto::workspace::templates_client::TemplatesClient;
+use crate::proto::workspace::workspaces_client::WorkspacesClient;
 use crate::service::ClusterSvcClient;
 use crate::service::ClusterWorkspacesClient;
 use crate::service::OperationsSvcClient;
 
 use super::client::ShardedClient;
+use super::config::ServiceConf;
+use super::process::ProcessManagerSvcClient;
+use super::readiness::Dependencies;
+use super::shard_map::ShardMap;
+use super::typed::BillingClient;
+use super::typed::BillingSvcClient;
+use super::typed::ClusterClient;
+use super::typed::ClusterWorkspaceClient;
+use super::typed::IdeClient;
+use super::typed::IdeSvcClient;
+use super::typed::LocationClient;
+use super::typed::LocationsSvcClient;
+use super::typed::OperationClient;
+use super::typed::OrganizationClient;
+use super::typed::OrganizationsSvcClient;
+use super::typed::TemplateClient;
+use super::typed::TemplatesSvcClient;
+use super::typed::WorkspaceClient;
+use super::typed::WorkspacesSvcClient;
 
 use serde_derive::Deserialize;
 
-#[allow(dead_code)]
-#[derive(Clone, Debug, Deserialize)]
-pub(crate) struct ServiceInstance {
-  pub address: String,
-  pub shard_ranges: Vec<(String, String)>,
-}
-
-#[allow(dead_code)]
-#[derive(Clone, Debug, Deserialize)]
-pub(crate) struct ServiceConf {
-  pub name: String,
-  pub instances: Vec<ServiceInstance>,
-}
-
 #[allow(dead_code, unused)]
 #[derive(Clone, Debug, Deserialize)]
 struct LocatorConfig {
   pub cluster: ServiceConf,
   pub longrunning: ServiceConf,
   pub system: ServiceConf,
+  #[serde(default)]
+  pub process: Option<ServiceConf>,
+  /// Serves the billing accounts and organizations.
+  #[serde(default)]
+  pub account: Option<ServiceConf>,
+  /// Serves the workspaces, IDEs and templates of users.
+  #[serde(default)]
+  pub workspace: Option<ServiceConf>,
   pub version: i64,
 }
 
@@ -41,18 +62,187 @@ pub struct ServiceLocator {
   clusters: ShardedClient<ClusterSvcClient>,
   operations: ShardedClient<OperationsSvcClient>,
   cluster_workspaces: ShardedClient<ClusterWorkspacesClient>,
+  processes: Option<ShardedClient<ProcessManagerSvcClient>>,
+  locations: ShardedClient<LocationsSvcClient>,
+  accounts: Option<AccountClients>,
+  workspaces: Option<WorkspaceClients>,
+}
+
+#[derive(Debug, Clone)]
+struct AccountClients {
+  billing: ShardedClient<BillingSvcClient>,
+  organizations: ShardedClient<OrganizationsSvcClient>,
+}
+
+#[derive(Debug, Clone)]
+struct WorkspaceClients {
+  workspaces: ShardedClient<WorkspacesSvcClient>,
+  ides: ShardedClient<IdeSvcClient>,
+  templates: ShardedClient<TemplatesSvcClient>,
 }
 
 impl ServiceLocator {
   pub fn try_new(conf: config::Config) -> anyhow::Result<ServiceLocator> {
     let config: LocatorConfig = conf.try_deserialize()?;
 
+    let accounts = config
+      .account
+      .map(|account| -> anyhow::Result<AccountClients> {
+        Ok(AccountClients {
+          billing: ShardedClient::try_new(account.clone(), BillingAccountsClient::new)?,
+          organizations: ShardedClient::try_new(account, OrganizationsClient::new)?,
+        })
+      })
+      .transpose()?;
+    let workspaces = config
+      .workspace
+      .map(|workspace| -> anyhow::Result<WorkspaceClients> {
+        Ok(WorkspaceClients {
+          workspaces: ShardedClient::try_new(workspace.clone(), WorkspacesClient::new)?,
+          ides: ShardedClient::try_new(workspace.clone(), IdeManagerClient::new)?,
+          templates: ShardedClient::try_new(workspace, TemplatesClient::new)?,
+        })
+      })
+      .transpose()?;
+
     Ok(ServiceLocator {
+      locations: ShardedClient::try_new(config.system.clone(), LocationsClient::new)?,
       clusters: ShardedClient::try_new(config.system, ClustersClient::new)?,
       operations: ShardedClient::try_new(config.longrunning, OperationsClient::new)?,
       cluster_workspaces: ShardedClient::try_new(config.cluster, ClusterWorkspacesClient::new)?,
+      processes: config
+        .process
+        .map(|process| ShardedClient::try_new(process, ProcessManagerClient::new))
+        .transpose()?,
+      accounts,
+      workspaces,
     })
   }
+
+  pub fn clusters(&self) -> ClusterClient {
+    ClusterClient::new(self.clusters.clone())
+  }
+
+  pub fn locations(&self) -> LocationClient {
+    LocationClient::new(self.locations.clone())
+  }
+
+  pub fn operations(&self) -> OperationClient {
+    OperationClient::new(self.operations.clone())
+  }
+
+  pub fn cluster_workspaces(&self) -> ClusterWorkspaceClient {
+    ClusterWorkspaceClient::new(self.cluster_workspaces.clone())
+  }
+
+  pub fn billing(&self) -> anyhow::Result<BillingClient> {
+    Ok(BillingClient::new(self.account_clients()?.billing.clone()))
+  }
+
+  pub fn organizations(&self) -> anyhow::Result<OrganizationClient> {
+    Ok(OrganizationClient::new(
+      self.account_clients()?.organizations.clone(),
+    ))
+  }
+
+  pub fn workspaces(&self) -> anyhow::Result<WorkspaceClient> {
+    Ok(WorkspaceClient::new(
+      self.workspace_clients()?.workspaces.clone(),
+    ))
+  }
+
+  pub fn ides(&self) -> anyhow::Result<IdeClient> {
+    Ok(IdeClient::new(self.workspace_clients()?.ides.clone()))
+  }
+
+  pub fn templates(&self) -> anyhow::Result<TemplateClient> {
+    Ok(TemplateClient::new(
+      self.workspace_clients()?.templates.clone(),
+    ))
+  }
+
+  fn account_clients(&self) -> anyhow::Result<&AccountClients> {
+    self
+      .accounts
+      .as_ref()
+      .ok_or_else(|| anyhow::anyhow!("The account service is not configured"))
+  }
+
+  fn workspace_clients(&self) -> anyhow::Result<&WorkspaceClients> {
+    self
+      .workspaces
+      .as_ref()
+      .ok_or_else(|| anyhow::anyhow!("The workspace service is not configured"))
+  }
+
+  /// Routes the keys of every service through its [`ShardMap`] stored in `client`.
+  pub fn with_shard_maps(mut self, client: redis::Client) -> Self {
+    self.clusters = with_shard_map(self.clusters, &client);
+    self.operations = with_shard_map(self.operations, &client);
+    self.cluster_workspaces = with_shard_map(self.cluster_workspaces, &client);
+    self.processes = self
+      .processes
+      .map(|processes| with_shard_map(processes, &client));
+    self.locations = with_shard_map(self.locations, &client);
+    self.accounts = self.accounts.map(|accounts| AccountClients {
+      billing: with_shard_map(accounts.billing, &client),
+      organizations: with_shard_map(accounts.organizations, &client),
+    });
+    self.workspaces = self.workspaces.map(|workspaces| WorkspaceClients {
+      workspaces: with_shard_map(workspaces.workspaces, &client),
+      ides: with_shard_map(workspaces.ides, &client),
+      templates: with_shard_map(workspaces.templates, &client),
+    });
+    self
+  }
+
+  /// Prefers the instances of every service closest to `local`, see
+  /// [`ShardedClient::with_locality`].
+  pub fn with_locality(mut self, local: &Location, locations: &[Location]) -> Self {
+    self.clusters = self.clusters.with_locality(local, locations);
+    self.operations = self.operations.with_locality(local, locations);
+    self.cluster_workspaces = self.cluster_workspaces.with_locality(local, locations);
+    self.processes = self
+      .processes
+      .map(|processes| processes.with_locality(local, locations));
+    self.locations = self.locations.with_locality(local, locations);
+    self.accounts = self.accounts.map(|accounts| AccountClients {
+      billing: accounts.billing.with_locality(local, locations),
+      organizations: accounts.organizations.with_locality(local, locations),
+    });
+    self.workspaces = self.workspaces.map(|workspaces| WorkspaceClients {
+      workspaces: workspaces.workspaces.with_locality(local, locations),
+      ides: workspaces.ides.with_locality(local, locations),
+      templates: workspaces.templates.with_locality(local, locations),
+    });
+    self
+  }
+
+  /// The services located, to wait for at startup, see [`super::readiness`].
+  pub fn dependencies(&self) -> Dependencies {
+    let dependencies = Dependencies::new()
+      .with_service(&self.clusters)
+      .with_service(&self.operations)
+      .with_service(&self.cluster_workspaces);
+
+    let dependencies = match &self.processes {
+      Some(processes) => dependencies.with_service(processes),
+      None => dependencies,
+    };
+    let dependencies = match &self.accounts {
+      Some(accounts) => dependencies.with_service(&accounts.billing),
+      None => dependencies,
+    };
+    match &self.workspaces {
+      Some(workspaces) => dependencies.with_service(&workspaces.workspaces),
+      None => dependencies,
+    }
+  }
+}
+
+fn with_shard_map<T: Clone>(client: ShardedClient<T>, redis: &redis::Client) -> ShardedClient<T> {
+  let shard_map = ShardMap::new(redis.clone(), client.name());
+  client.with_shard_map(shard_map)
 }
 
 #[async_trait::async_trait]
@@ -75,3 +265,13 @@ impl ServiceRegistry<OperationsSvcClient> for ServiceLocator {
     Ok(self.operations.clone())
   }
 }
+
+#[async_trait::async_trait]
+impl ServiceRegistry<ProcessManagerSvcClient> for ServiceLocator {
+  async fn get(&self) -> anyhow::Result<ShardedClient<ProcessManagerSvcClient>> {
+    self
+      .processes
+      .clone()
+      .ok_or_else(|| anyhow::anyhow!("The process service is not configured"))
+  }
+}
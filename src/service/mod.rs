@@ -1,28 +1,133 @@
+mod catch_panic;
 mod client;
 mod config;
 mod context;
+mod discovery;
 mod error;
+#[cfg(feature = "redis")]
+mod idempotency;
+mod limits;
 mod locator;
+mod paging;
+mod process;
+pub mod readiness;
+mod secrets;
+#[allow(clippy::module_inception)]
 mod service;
+mod shard_map;
+mod shed;
+mod shutdown;
+mod slow;
+mod tls;
+mod trace;
+mod typed;
+mod uds;
+mod watch;
 
 pub use context::Context;
+pub use context::Priority;
+pub use context::IDEMPOTENCY_KEY_HEADER;
+pub use context::PRIORITY_HEADER;
 
+pub use catch_panic::metrics as panic_metrics;
+pub use catch_panic::CatchPanic;
+pub use catch_panic::CatchPanicLayer;
+pub use catch_panic::PanicMetrics;
+pub use client::Lease;
+pub use client::Locality;
 pub use client::ShardedClient;
+pub use client::DEFAULT_CONNECT_TIMEOUT;
+pub use config::ServiceConf;
+pub use config::ServiceConfBuilder;
+pub use config::ServiceConfError;
+pub use config::ServiceInstance;
+pub use config::TlsConf;
+pub use discovery::DiscoveredClient;
+pub use discovery::DiscoverySvcClient;
 pub use error::Error;
+#[cfg(feature = "redis")]
+pub use idempotency::Idempotency;
+#[cfg(feature = "redis")]
+pub use idempotency::DEFAULT_IDEMPOTENCY_TTL;
+pub use limits::LimitedBody;
+pub use limits::RequestLimit;
+pub use limits::RequestLimitLayer;
+pub use limits::RequestLimits;
+pub use limits::ResponseLimit;
+pub use limits::ResponseLimitLayer;
+pub use limits::ServerLimits;
 pub use locator::ServiceLocator;
+pub use paging::stream_pages;
+pub use paging::PageRequest;
+pub use paging::PageResponse;
+pub use paging::PageRetry;
+pub use process::ProcessClient;
+pub use process::ProcessManagerSvcClient;
+pub use secrets::FileSecrets;
+pub use secrets::SecretProvider;
+pub use secrets::StaticSecrets;
+pub use shard_map::rendezvous;
+pub use shard_map::weighted_rendezvous;
+pub use shard_map::ShardMap;
+pub use shard_map::ShardMove;
+pub use shed::LoadShed;
+pub use shed::LoadShedConfig;
+pub use shed::LoadShedLayer;
+pub use shutdown::shutdown_signal;
+pub use shutdown::DEFAULT_SHUTDOWN_GRACE;
+pub use slow::SlowCall;
+pub use slow::SlowCallLayer;
+pub use slow::DEFAULT_SLOW_CALL_THRESHOLD;
+pub use slow::OPERATION_ID_HEADER;
+pub use tls::tls_incoming;
+pub use tls::ServerTls;
+pub use tls::DEFAULT_HANDSHAKE_TIMEOUT;
+pub use tls::DEFAULT_TLS_RELOAD;
 use tonic::transport::Channel;
+pub use trace::RequestTrace;
+pub use trace::RequestTraceLayer;
+pub use trace::REQUEST_ID_HEADER;
+pub use typed::BillingClient;
+pub use typed::BillingSvcClient;
+pub use typed::CallRetry;
+pub use typed::ClusterClient;
+pub use typed::ClusterWorkspaceClient;
+pub use typed::IdeClient;
+pub use typed::IdeSvcClient;
+pub use typed::LocationClient;
+pub use typed::LocationsSvcClient;
+pub use typed::OperationClient;
+pub use typed::OrganizationClient;
+pub use typed::OrganizationsSvcClient;
+pub use typed::TemplateClient;
+pub use typed::TemplatesSvcClient;
+pub use typed::WorkspaceClient;
+pub use typed::WorkspacesSvcClient;
+pub use uds::unix_incoming;
+pub use uds::unix_path;
+pub use uds::UnixConnector;
+pub use uds::UNIX_SCHEME;
+pub use watch::resumable_watch;
+pub use watch::WatchRequest;
+pub use watch::WatchRetry;
 
 use crate::proto::cluster::workspaces_client::WorkspacesClient;
 use crate::proto::longrunning::operations_client::OperationsClient;
 use crate::proto::system::clusters_client::ClustersClient;
 
-pub type ClusterSvcClient = ClustersClient<Channel>;
-pub type OperationsSvcClient = OperationsClient<Channel>;
-pub type ClusterWorkspacesClient = WorkspacesClient<Channel>;
+/// Channel of the service clients, logging slow calls and limiting the size of the messages
+/// received.
+pub type ClientChannel = SlowCall<ResponseLimit<Channel>>;
+
+pub type ClusterSvcClient = ClustersClient<ClientChannel>;
+pub type OperationsSvcClient = OperationsClient<ClientChannel>;
+pub type ClusterWorkspacesClient = WorkspacesClient<ClientChannel>;
 pub type ClusterWorkspacesShardedClient = ShardedClient<ClusterWorkspacesClient>;
 
 pub use locator::ServiceRegistry;
+pub use service::ServerBuilder;
 pub use service::ServerConfig;
+pub use service::ServerLayers;
 pub use service::Service;
 pub use service::ServiceConfig;
 pub use service::ServiceOptions;
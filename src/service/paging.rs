@@ -0,0 +1,243 @@
+use std::future::Future;
+use std::time::Duration;
+
+use futures::Stream;
+use futures::TryStreamExt;
+use tonic::Code;
+
+use crate::proto::cluster;
+use crate::proto::workspace;
+use crate::util::backoff;
+use crate::util::backoff::Backoff;
+
+/// Request of a paginated List RPC, following the `page_token` convention.
+pub trait PageRequest: Clone {
+  fn set_page_token(&mut self, page_token: String);
+}
+
+/// Response of a paginated List RPC, following the `next_page_token` convention. An empty
+/// `next_page_token` marks the last page.
+pub trait PageResponse {
+  type Item;
+
+  fn next_page_token(&self) -> &str;
+
+  fn into_items(self) -> Vec<Self::Item>;
+}
+
+macro_rules! paginated {
+  ($request:ty, $response:ty, $items:ident, $item:ty) => {
+    impl PageRequest for $request {
+      fn set_page_token(&mut self, page_token: String) {
+        self.page_token = page_token;
+      }
+    }
+
+    impl PageResponse for $response {
+      type Item = $item;
+
+      fn next_page_token(&self) -> &str {
+        &self.next_page_token
+      }
+
+      fn into_items(self) -> Vec<Self::Item> {
+        self.$items
+      }
+    }
+  };
+}
+
+paginated!(
+  cluster::ListWorkspaceRequest,
+  cluster::ListWorkspaceResponse,
+  workspace_server,
+  cluster::WorkspaceServer
+);
+paginated!(
+  workspace::ListWorkspaceRequest,
+  workspace::ListWorkspaceResponse,
+  workspace,
+  workspace::Workspace
+);
+paginated!(
+  workspace::ListTemplateRequest,
+  workspace::ListTemplateResponse,
+  template,
+  workspace::Template
+);
+
+/// How often a page is requested again after a transient failure (`UNAVAILABLE`,
+/// `DEADLINE_EXCEEDED`, `RESOURCE_EXHAUSTED` or `ABORTED`). The backoff doubles after every attempt.
+#[derive(Clone, Debug)]
+pub struct PageRetry {
+  pub attempts: u32,
+  pub backoff: Duration,
+}
+
+impl Default for PageRetry {
+  fn default() -> Self {
+    Self {
+      attempts: 3,
+      backoff: Duration::from_millis(100),
+    }
+  }
+}
+
+impl PageRetry {
+  pub fn none() -> Self {
+    Self {
+      attempts: 1,
+      backoff: Duration::ZERO,
+    }
+  }
+}
+
+/// Streams the items of every page of a List RPC, starting at the page of `request`. `rpc` is
+/// called once per page with `request` and the page token of the previous response. The stream
+/// ends after the first error.
+///
+/// ```rust,ignore
+/// let workspaces = stream_pages(request, PageRetry::default(), |request| {
+///   let mut client = client.clone();
+///   async move { client.list_workspaces(request).await }
+/// });
+/// ```
+pub fn stream_pages<Req, Resp, F, Fut>(
+  request: Req,
+  retry: PageRetry,
+  rpc: F,
+) -> impl Stream<Item = Result<Resp::Item, tonic::Status>>
+where
+  Req: PageRequest,
+  Resp: PageResponse,
+  F: FnMut(Req) -> Fut,
+  Fut: Future<Output = Result<tonic::Response<Resp>, tonic::Status>>,
+{
+  futures::stream::unfold((Some(request), rpc), move |(request, mut rpc)| {
+    let retry = retry.clone();
+
+    async move {
+      let mut request = request?;
+      let response = match fetch(&mut rpc, &request, &retry).await {
+        Ok(response) => response,
+        Err(status) => return Some((Err(status), (None, rpc))),
+      };
+
+      let next = match response.next_page_token() {
+        "" => None,
+        token => {
+          request.set_page_token(token.to_string());
+          Some(request)
+        }
+      };
+
+      Some((Ok(response.into_items()), (next, rpc)))
+    }
+  })
+  .map_ok(|items| futures::stream::iter(items.into_iter().map(Ok)))
+  .try_flatten()
+}
+
+async fn fetch<Req, Resp, F, Fut>(
+  rpc: &mut F,
+  request: &Req,
+  retry: &PageRetry,
+) -> Result<Resp, tonic::Status>
+where
+  Req: PageRequest,
+  F: FnMut(Req) -> Fut,
+  Fut: Future<Output = Result<tonic::Response<Resp>, tonic::Status>>,
+{
+  let retryable = |status: &tonic::Status| is_transient(status.code());
+
+  backoff::retry(
+    Backoff::exponential(retry.backoff),
+    retry.attempts,
+    retryable,
+    || rpc(request.clone()),
+  )
+  .await
+  .map(tonic::Response::into_inner)
+}
+
+fn is_transient(code: Code) -> bool {
+  matches!(
+    code,
+    Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+  use std::sync::Arc;
+
+  use futures::StreamExt;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn stream_pages_should_thread_tokens_and_retry_pages() {
+    let calls = Arc::new(AtomicUsize::default());
+
+    let rpc = |request: cluster::ListWorkspaceRequest| {
+      let calls = calls.clone();
+
+      async move {
+        // The second page fails once before succeeding.
+        if calls.fetch_add(1, Ordering::SeqCst) == 1 {
+          return Err(tonic::Status::unavailable("try again"));
+        }
+
+        let (ids, next_page_token) = match request.page_token.as_str() {
+          "" => (vec![1, 2], "2"),
+          "2" => (vec![3], ""),
+          token => panic!("unexpected page token {}", token),
+        };
+
+        Ok(tonic::Response::new(cluster::ListWorkspaceResponse {
+          workspace_server: ids
+            .into_iter()
+            .map(|workspace_id| cluster::WorkspaceServer {
+              workspace_id,
+              ..Default::default()
+            })
+            .collect(),
+          next_page_token: next_page_token.to_string(),
+        }))
+      }
+    };
+
+    let retry = PageRetry {
+      attempts: 2,
+      backoff: Duration::ZERO,
+    };
+    let ids: Vec<i64> = stream_pages(cluster::ListWorkspaceRequest::default(), retry, rpc)
+      .map_ok(|server| server.workspace_id)
+      .try_collect()
+      .await
+      .unwrap();
+
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+  }
+
+  #[tokio::test]
+  async fn stream_pages_should_end_after_an_error() {
+    let rpc = |_: cluster::ListWorkspaceRequest| async {
+      Err::<tonic::Response<cluster::ListWorkspaceResponse>, _>(tonic::Status::not_found("gone"))
+    };
+
+    let results: Vec<_> = stream_pages(
+      cluster::ListWorkspaceRequest::default(),
+      PageRetry::default(),
+      rpc,
+    )
+    .collect()
+    .await;
+
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].as_ref().unwrap_err().code(), Code::NotFound);
+  }
+}
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use futures::Stream;
+use futures::StreamExt;
+
+use crate::proto::process::process_manager_client::ProcessManagerClient;
+use crate::proto::process::Command;
+use crate::proto::process::IoChannel;
+use crate::proto::process::KillProcessRequest;
+use crate::proto::process::ListProcessRequest;
+use crate::proto::process::Process;
+use crate::proto::process::ReadRequest;
+
+use super::ClientChannel;
+use super::ShardedClient;
+
+pub type ProcessManagerSvcClient = ProcessManagerClient<ClientChannel>;
+
+/// Typed helpers around the `rappel.process.ProcessManager` service of a workspace node.
+#[derive(Clone, Debug)]
+pub struct ProcessClient {
+  client: ProcessManagerSvcClient,
+  poll_interval: Duration,
+}
+
+impl ProcessClient {
+  pub fn new(client: ProcessManagerSvcClient) -> Self {
+    Self {
+      client,
+      poll_interval: Duration::from_millis(500),
+    }
+  }
+
+  /// Talks to the instance of `clients` serving `key`, e.g. the workspace the process runs in.
+  pub fn for_key(
+    clients: &ShardedClient<ProcessManagerSvcClient>,
+    key: &str,
+  ) -> Result<Self, super::Error> {
+    Ok(Self::new(clients.borrow(key)?.clone()))
+  }
+
+  /// How often [`Self::wait`] polls the process list.
+  pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+    self.poll_interval = poll_interval;
+    self
+  }
+
+  pub async fn start(&self, command: Command) -> Result<Process, tonic::Status> {
+    let response = self.client.clone().start(command).await?;
+    Ok(response.into_inner())
+  }
+
+  /// Streams the output of `pid` on `channel` until the process closes it.
+  pub async fn output(
+    &self,
+    pid: u32,
+    channel: IoChannel,
+  ) -> Result<impl Stream<Item = Result<Vec<u8>, tonic::Status>>, tonic::Status> {
+    let request = ReadRequest {
+      pid,
+      channel: channel as i32,
+    };
+    let stream = self.client.clone().listen(request).await?.into_inner();
+
+    let output = stream
+      .scan(false, |closed, response| {
+        if *closed {
+          return futures::future::ready(None);
+        }
+
+        if let Ok(response) = &response {
+          *closed = response.is_closed;
+        }
+
+        futures::future::ready(Some(response.map(|response| response.data)))
+      })
+      .filter(|data| futures::future::ready(!matches!(data, Ok(data) if data.is_empty())));
+
+    Ok(output)
+  }
+
+  /// Waits until `pid` exits and returns it with its exit status. Fails with `DEADLINE_EXCEEDED`
+  /// if it is still running after `deadline`, and with `NOT_FOUND` if the node does not know it.
+  pub async fn wait(&self, pid: u32, deadline: Duration) -> Result<Process, tonic::Status> {
+    let poll = async {
+      loop {
+        let processes = self
+          .client
+          .clone()
+          .list(ListProcessRequest::default())
+          .await?
+          .into_inner()
+          .process;
+
+        match processes.into_iter().find(|process| process.pid == pid) {
+          Some(process) if !process.is_running => return Ok(process),
+          Some(_) => {}
+          None => {
+            return Err(tonic::Status::not_found(format!(
+              "Process {} not found",
+              pid
+            )))
+          }
+        }
+
+        tokio::time::sleep(self.poll_interval).await;
+      }
+    };
+
+    tokio::time::timeout(deadline, poll)
+      .await
+      .map_err(|_| tonic::Status::deadline_exceeded(format!("Process {} is still running", pid)))?
+  }
+
+  /// Sends `signal` to `pid`, e.g. `libc::SIGTERM`.
+  pub async fn signal(&self, pid: u32, signal: i32) -> Result<Process, tonic::Status> {
+    let request = KillProcessRequest {
+      pid,
+      flag: signal as u64,
+    };
+
+    let response = self.client.clone().kill(request).await?;
+    Ok(response.into_inner())
+  }
+}
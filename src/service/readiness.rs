@@ -0,0 +1,237 @@
+//! Gates the startup of a service on its dependencies.
+//!
+//! A service starting while Redis or an upstream service is down fails at its first request and
+//! crash-loops, which hides the dependency at fault behind restarts. Waiting for them first, with
+//! [`await_dependencies`], fails once with the dependencies still down instead:
+//!
+//! ```rust,ignore
+//! let dependencies = service
+//!   .service_locator()
+//!   .dependencies()
+//!   .with_redis("redis", redis.clone());
+//! readiness::await_dependencies(&dependencies).await?;
+//! service.serve(router).await?;
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+
+use tonic::Code;
+use tracing_futures::Instrument;
+
+use super::ClientChannel;
+use super::ShardedClient;
+use crate::proto::health::proto::health_check_response::ServingStatus;
+use crate::proto::health::proto::health_client::HealthClient;
+use crate::proto::health::proto::HealthCheckRequest;
+use crate::util::redis_exec;
+
+/// Time the dependencies get to become ready by default.
+pub const DEFAULT_READINESS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval between the checks of a dependency by default.
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadinessError {
+  #[error("Dependencies not ready after {timeout:?}: {}", join(failures))]
+  NotReady {
+    timeout: Duration,
+    failures: Vec<DependencyFailure>,
+  },
+}
+
+/// A dependency still failing its check when the timeout elapsed, with its last error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DependencyFailure {
+  pub dependency: String,
+  pub error: String,
+}
+
+impl fmt::Display for DependencyFailure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} ({})", self.dependency, self.error)
+  }
+}
+
+#[derive(Clone, Debug)]
+enum Dependency {
+  Redis(redis::Client),
+  Grpc(ClientChannel),
+}
+
+/// Dependencies of a service and how long to wait for them, see [`await_dependencies`].
+#[derive(Clone, Debug)]
+pub struct Dependencies {
+  dependencies: Vec<(String, Dependency)>,
+  timeout: Duration,
+  interval: Duration,
+}
+
+impl Default for Dependencies {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Dependencies {
+  pub fn new() -> Self {
+    Self {
+      dependencies: Vec::default(),
+      timeout: DEFAULT_READINESS_TIMEOUT,
+      interval: DEFAULT_CHECK_INTERVAL,
+    }
+  }
+
+  /// Waits for `client` to answer `PING`.
+  pub fn with_redis(mut self, name: &str, client: redis::Client) -> Self {
+    self
+      .dependencies
+      .push((name.to_string(), Dependency::Redis(client)));
+    self
+  }
+
+  /// Waits for every instance of `client` to pass the gRPC health check. Instances not serving
+  /// the health service are ready once they answer.
+  pub fn with_service<T: Clone>(mut self, client: &ShardedClient<T>) -> Self {
+    for (address, channel) in client.instances() {
+      self.dependencies.push((
+        format!("{} at {}", client.name(), address),
+        Dependency::Grpc(channel.clone()),
+      ));
+    }
+    self
+  }
+
+  /// Fails when the dependencies are not ready after `timeout`, [`DEFAULT_READINESS_TIMEOUT`] by
+  /// default.
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Checks the dependencies not ready every `interval`, [`DEFAULT_CHECK_INTERVAL`] by default.
+  pub fn with_interval(mut self, interval: Duration) -> Self {
+    self.interval = interval;
+    self
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.dependencies.iter().map(|(name, _)| name.as_str())
+  }
+}
+
+/// Waits until every dependency passes its check. Fails with the dependencies still failing,
+/// and their last error, once the timeout elapses.
+pub async fn await_dependencies(dependencies: &Dependencies) -> Result<(), ReadinessError> {
+  let deadline = Instant::now() + dependencies.timeout;
+
+  let checks = dependencies
+    .dependencies
+    .iter()
+    .map(|(name, dependency)| async move {
+      let error = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let error = match tokio::time::timeout(remaining, check(dependency)).await {
+          Ok(Ok(())) => {
+            tracing::info!(message = "Dependency ready", dependency = %name);
+            return None;
+          }
+          Ok(Err(error)) => error,
+          Err(_) => "check timed out".to_string(),
+        };
+
+        if Instant::now() + dependencies.interval >= deadline {
+          break error;
+        }
+        tracing::info!(message = "Waiting for dependency", dependency = %name, %error);
+        tokio::time::sleep(dependencies.interval).await;
+      };
+
+      tracing::error!(message = "Dependency not ready", dependency = %name, %error);
+      Some(DependencyFailure {
+        dependency: name.clone(),
+        error,
+      })
+    });
+
+  let failures: Vec<DependencyFailure> = futures::future::join_all(checks)
+    .await
+    .into_iter()
+    .flatten()
+    .collect();
+
+  if failures.is_empty() {
+    Ok(())
+  } else {
+    Err(ReadinessError::NotReady {
+      timeout: dependencies.timeout,
+      failures,
+    })
+  }
+}
+
+async fn check(dependency: &Dependency) -> Result<(), String> {
+  match dependency {
+    Dependency::Redis(client) => {
+      let mut conn = redis_exec::connect(client)
+        .await
+        .map_err(|error| error.to_string())?;
+      redis::cmd("PING")
+        .query_async::<_, ()>(&mut conn)
+        .instrument(tracing::info_span!("redis-readiness-ping"))
+        .await
+        .map_err(|error| error.to_string())
+    }
+    Dependency::Grpc(channel) => {
+      let response = HealthClient::new(channel.clone())
+        .check(HealthCheckRequest {
+          service: String::default(),
+        })
+        .await;
+
+      match response {
+        Ok(response) if response.get_ref().status == ServingStatus::Serving as i32 => Ok(()),
+        Ok(response) => Err(format!(
+          "health status {:?}",
+          ServingStatus::from_i32(response.get_ref().status).unwrap_or(ServingStatus::Unknown)
+        )),
+        Err(status) if status.code() == Code::Unimplemented => Ok(()),
+        Err(status) => Err(status.to_string()),
+      }
+    }
+  }
+}
+
+fn join(failures: &[DependencyFailure]) -> String {
+  failures
+    .iter()
+    .map(DependencyFailure::to_string)
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn await_dependencies_should_report_the_failing_dependencies() {
+    let dependencies = Dependencies::new()
+      .with_redis(
+        "redis",
+        redis::Client::open("redis://127.0.0.1:1/").unwrap(),
+      )
+      .with_timeout(Duration::from_millis(50))
+      .with_interval(Duration::from_millis(10));
+
+    let error = await_dependencies(&dependencies).await.unwrap_err();
+    let ReadinessError::NotReady { failures, .. } = &error;
+
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].dependency, "redis");
+    assert!(error.to_string().starts_with("Dependencies not ready"));
+    assert!(await_dependencies(&Dependencies::new()).await.is_ok());
+  }
+}
@@ -0,0 +1,64 @@
+//! Sources of the secrets of a service, e.g. the certificate and key of its TLS server, see
+//! [`super::ServerTls`]. Secrets are read again on every call, so rotated secrets are picked up.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+use super::Error;
+
+#[async_trait::async_trait]
+pub trait SecretProvider: Send + Sync {
+  /// Returns the current value of the secret `name`, [`Error::MissingSecret`] when there is none.
+  async fn get(&self, name: &str) -> Result<Vec<u8>, Error>;
+}
+
+/// Secrets stored as files of a directory, e.g. a mounted Kubernetes secret, named by their path
+/// relative to the directory.
+#[derive(Clone, Debug)]
+pub struct FileSecrets {
+  dir: PathBuf,
+}
+
+impl FileSecrets {
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Self { dir: dir.into() }
+  }
+}
+
+#[async_trait::async_trait]
+impl SecretProvider for FileSecrets {
+  async fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+    match tokio::fs::read(self.dir.join(name)).await {
+      Ok(secret) => Ok(secret),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => {
+        Err(Error::MissingSecret(name.to_string()))
+      }
+      Err(error) => Err(error.into()),
+    }
+  }
+}
+
+/// Secrets held in memory, e.g. fetched from a vault at startup.
+#[derive(Clone, Debug, Default)]
+pub struct StaticSecrets {
+  secrets: HashMap<String, Vec<u8>>,
+}
+
+impl StaticSecrets {
+  pub fn with_secret(mut self, name: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+    self.secrets.insert(name.into(), secret.into());
+    self
+  }
+}
+
+#[async_trait::async_trait]
+impl SecretProvider for StaticSecrets {
+  async fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+    self
+      .secrets
+      .get(name)
+      .cloned()
+      .ok_or_else(|| Error::MissingSecret(name.to_string()))
+  }
+}
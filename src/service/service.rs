@@ -1,21 +1,71 @@
+use std::future::Future;
 use std::net::AddrParseError;
 use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use super::ServiceLocator;
 use config::Config;
 use config::Environment;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use tokio::net::TcpListener;
+use tonic::transport::server::Router;
+use tonic::transport::Server;
+use tower_layer::Identity;
+use tower_layer::Stack;
 use tracing::Level;
 
+#[cfg(feature = "redis")]
+use crate::quota::RedisRateLimiter;
+
+use super::shutdown::drain;
+use super::shutdown_signal;
+use super::tls_incoming;
+use super::unix_incoming;
+use super::unix_path;
+use super::CatchPanicLayer;
 use super::Error;
+use super::FileSecrets;
+use super::LoadShedConfig;
+use super::LoadShedLayer;
+use super::RequestLimitLayer;
+use super::RequestTraceLayer;
+use super::SecretProvider;
+use super::ServerLimits;
+use super::ServerTls;
+use super::SlowCallLayer;
+use super::DEFAULT_SHUTDOWN_GRACE;
+use super::DEFAULT_SLOW_CALL_THRESHOLD;
+
+/// Layers of the servers built by [`Service::server`].
+pub type ServerLayers = Stack<
+  CatchPanicLayer,
+  Stack<
+    LoadShedLayer,
+    Stack<RequestLimitLayer, Stack<SlowCallLayer, Stack<RequestTraceLayer, Identity>>>,
+  >,
+>;
+
+/// Server builder returned by [`Service::server`].
+pub type ServerBuilder = Server<ServerLayers>;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ServerConfig {
   pub name: String,
+  /// `host:port`, or `unix:///path.sock` for a Unix domain socket.
   pub address: String,
   pub external_ip: IpAddr,
+  /// Calls served for more milliseconds than this are logged, one second when unset.
+  #[serde(default)]
+  pub slow_call_ms: Option<u64>,
+  /// Milliseconds in-flight calls get to complete on shutdown, 30 seconds when unset.
+  #[serde(default)]
+  pub shutdown_grace_ms: Option<u64>,
+  /// Terminates TLS on TCP addresses with the secrets of [`ServiceOptions::secrets`].
+  #[serde(default)]
+  pub tls: Option<ServerTls>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -25,16 +75,32 @@ pub struct LogConfig {}
 pub struct ServiceConfig {
   pub server: ServerConfig,
   pub logger: LogConfig,
+  #[serde(default)]
+  pub load_shedding: LoadShedConfig,
+  /// Per-method message size and rate limits, see [`RequestLimitLayer`].
+  #[serde(default)]
+  pub limits: ServerLimits,
 }
 
 pub struct Service {
   config: Config,
   service_config: ServiceConfig,
   service_locator: ServiceLocator,
+  secrets: Arc<dyn SecretProvider>,
+  #[cfg(feature = "redis")]
+  rate_limiter: Option<RedisRateLimiter>,
 }
 
 #[derive(Default)]
-pub struct ServiceOptions {}
+pub struct ServiceOptions {
+  /// Source of the secrets named in the config, files relative to the working directory when
+  /// unset.
+  pub secrets: Option<Arc<dyn SecretProvider>>,
+  /// Counts the requests per second limited by the `limits` of the config. Rates are not limited
+  /// when unset.
+  #[cfg(feature = "redis")]
+  pub rate_limiter: Option<RedisRateLimiter>,
+}
 
 impl Service {
   pub fn try_new(opts: ServiceOptions) -> Result<Self, anyhow::Error> {
@@ -42,12 +108,17 @@ impl Service {
     let service_locator = ServiceLocator::try_new(Self::init_service_locator(&opts)?)?;
     let service_config: ServiceConfig = config.clone().try_deserialize()?;
 
-    let _ = Self::init_logging(&opts, &service_config.logger)?;
+    Self::init_logging(&opts, &service_config.logger)?;
 
     let svc = Service {
       config,
       service_config,
       service_locator,
+      secrets: opts
+        .secrets
+        .unwrap_or_else(|| Arc::new(FileSecrets::new("."))),
+      #[cfg(feature = "redis")]
+      rate_limiter: opts.rate_limiter,
     };
 
     Ok(svc)
@@ -90,6 +161,106 @@ impl Service {
     &self.service_locator
   }
 
+  /// Returns a server builder tracing every RPC, see [`super::RequestTrace`], logging the slow
+  /// ones, see [`super::SlowCall`], rejecting the requests over their `limits`, see
+  /// [`super::RequestLimit`], shedding the requests over the `load_shedding` limit, see
+  /// [`LoadShedLayer`], and answering the panicking handlers with `INTERNAL`, see
+  /// [`super::CatchPanic`].
+  pub fn server(&self) -> ServerBuilder {
+    let slow_call_threshold = self
+      .service_config
+      .server
+      .slow_call_ms
+      .map_or(DEFAULT_SLOW_CALL_THRESHOLD, Duration::from_millis);
+
+    let limits = RequestLimitLayer::new(self.service_config.limits.clone());
+    #[cfg(feature = "redis")]
+    let limits = match &self.rate_limiter {
+      Some(rate_limiter) => limits.with_rate_limiter(rate_limiter.clone()),
+      None => limits,
+    };
+
+    Server::builder()
+      .layer(RequestTraceLayer::new())
+      .layer(SlowCallLayer::server(slow_call_threshold))
+      .layer(limits)
+      .layer(LoadShedLayer::new(
+        self.service_config.load_shedding.clone(),
+      ))
+      .layer(CatchPanicLayer::new())
+  }
+
+  /// Serves `router` on the configured address, or on a Unix domain socket for
+  /// `unix:///path.sock` addresses, e.g. for sidecars on the same node, until the process receives
+  /// SIGTERM or Ctrl-C. See [`Service::serve_with_shutdown`].
+  pub async fn serve(&self, router: Router<ServerLayers>) -> Result<(), Error> {
+    self.serve_with_shutdown(router, shutdown_signal()).await
+  }
+
+  /// Serves `router` until `signal` resolves, then drains it: new connections are refused, open
+  /// ones get a GOAWAY so clients move their new streams elsewhere, and the calls in flight get
+  /// `shutdown_grace_ms` to complete before being aborted. With `tls` configured, TCP connections
+  /// are served over TLS, see [`ServerTls`].
+  ///
+  /// Applications also running workers drain the RPCs first, as they may enqueue tasks:
+  ///
+  /// ```rust,ignore
+  /// let (drained, rpcs_drained) = tokio::sync::oneshot::channel();
+  /// let rpcs = async {
+  ///   let result = service.serve_with_shutdown(router, shutdown_signal()).await;
+  ///   drained.send(()).ok();
+  ///   result
+  /// };
+  /// let workers = runner.run_until(async { rpcs_drained.await.ok(); });
+  /// tokio::try_join!(rpcs, workers)?;
+  /// ```
+  pub async fn serve_with_shutdown(
+    &self,
+    router: Router<ServerLayers>,
+    signal: impl Future<Output = ()>,
+  ) -> Result<(), Error> {
+    let grace = self
+      .service_config
+      .server
+      .shutdown_grace_ms
+      .map_or(DEFAULT_SHUTDOWN_GRACE, Duration::from_millis);
+
+    match unix_path(&self.service_config.server.address) {
+      Some(path) => {
+        let incoming = unix_incoming(path)?;
+        drain(
+          |stop| router.serve_with_incoming_shutdown(incoming, stop),
+          signal,
+          grace,
+        )
+        .await?
+      }
+      None => match &self.service_config.server.tls {
+        Some(tls) => {
+          let listener = TcpListener::bind(self.address()?).await?;
+          let incoming = tls_incoming(listener, tls.clone(), self.secrets.clone()).await?;
+          drain(
+            |stop| router.serve_with_incoming_shutdown(incoming, stop),
+            signal,
+            grace,
+          )
+          .await?
+        }
+        None => {
+          let address = self.address()?;
+          drain(
+            |stop| router.serve_with_shutdown(address, stop),
+            signal,
+            grace,
+          )
+          .await?
+        }
+      },
+    }
+
+    Ok(())
+  }
+
   pub fn address(&self) -> Result<SocketAddr, Error> {
     self
       .service_config
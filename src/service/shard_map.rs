@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use redis::AsyncCommands;
+use tracing_futures::Instrument;
+
+use crate::util::backoff::Backoff;
+use crate::util::backoff::Jitter;
+use crate::util::redis_exec;
+
+/// A key whose shard changed during a rebalance.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShardMove {
+  pub key: String,
+  pub from: String,
+  pub to: String,
+}
+
+/// Explicit key to instance assignments of a service, persisted in the Redis hash
+/// `shardmap:{service}` and cached in memory.
+///
+/// Instances are identified by their address rather than their position in the configuration, so
+/// the routing of pinned keys survives reordering the instance list. The cache is only updated
+/// by this process and by [`ShardMap::refresh`], run [`ShardMap::watch`] to pick up assignments
+/// made elsewhere, e.g. by a rebalance.
+#[derive(Clone, Debug)]
+pub struct ShardMap {
+  client: redis::Client,
+  key: String,
+  entries: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl ShardMap {
+  pub fn new(client: redis::Client, service: &str) -> Self {
+    Self {
+      client,
+      key: format!("shardmap:{}", service),
+      entries: Arc::default(),
+    }
+  }
+
+  /// Stores the shard map under `prefix`, e.g. `rappel:{env}:`.
+  pub fn with_prefix(mut self, prefix: &str) -> Self {
+    self.key = format!("{}{}", prefix, self.key);
+    self
+  }
+
+  /// Returns the cached address `key` is pinned to.
+  pub fn get(&self, key: &str) -> Option<String> {
+    self.entries.read().unwrap().get(key).cloned()
+  }
+
+  /// Reloads the assignments from Redis.
+  pub async fn refresh(&self) -> Result<(), redis::RedisError> {
+    let entries = self.entries().await?;
+    *self.entries.write().unwrap() = entries;
+    Ok(())
+  }
+
+  /// Refreshes the cache every `interval` until the future is dropped. Failed refreshes are
+  /// retried sooner, backing off up to `interval`.
+  pub async fn watch(&self, interval: Duration) {
+    let mut failures = Backoff::default()
+      .with_max(interval)
+      .with_jitter(Jitter::Full)
+      .delays();
+
+    loop {
+      let delay = match self.refresh().await {
+        Ok(()) => {
+          failures.reset();
+          interval
+        }
+        Err(error) => {
+          tracing::warn!(message = "Failed to refresh the shard map", key = %self.key, %error);
+          failures.next().unwrap_or(interval)
+        }
+      };
+
+      tokio::time::sleep(delay).await;
+    }
+  }
+
+  /// Reads every assignment from Redis, bypassing the cache.
+  pub async fn entries(&self) -> Result<HashMap<String, String>, redis::RedisError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    conn
+      .hgetall(&self.key)
+      .instrument(tracing::info_span!("redis-shard-map-hgetall"))
+      .await
+  }
+
+  /// Pins `key` to `address`, replacing any previous assignment.
+  pub async fn assign(&self, key: &str, address: &str) -> Result<(), redis::RedisError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = conn
+      .hset(&self.key, key, address)
+      .instrument(tracing::info_span!("redis-shard-map-hset", %key))
+      .await?;
+
+    self
+      .entries
+      .write()
+      .unwrap()
+      .insert(key.to_string(), address.to_string());
+    Ok(())
+  }
+
+  /// Pins `key` to `address` unless it is already pinned, and returns the address it is pinned
+  /// to. Concurrent callers agree on a single assignment.
+  pub async fn assign_if_absent(
+    &self,
+    key: &str,
+    address: &str,
+  ) -> Result<String, redis::RedisError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let (_, assigned): (bool, String) = redis::pipe()
+      .atomic()
+      .hset_nx(&self.key, key, address)
+      .hget(&self.key, key)
+      .query_async(&mut conn)
+      .instrument(tracing::info_span!("redis-shard-map-hsetnx", %key))
+      .await?;
+
+    self
+      .entries
+      .write()
+      .unwrap()
+      .insert(key.to_string(), assigned.clone());
+    Ok(assigned)
+  }
+
+  pub async fn unassign(&self, key: &str) -> Result<(), redis::RedisError> {
+    let mut conn = redis_exec::connect(&self.client).await?;
+
+    let _: () = conn
+      .hdel(&self.key, key)
+      .instrument(tracing::info_span!("redis-shard-map-hdel", %key))
+      .await?;
+
+    self.entries.write().unwrap().remove(key);
+    Ok(())
+  }
+
+  /// Moves the keys pinned to an address missing from `addresses` to the instance
+  /// [`rendezvous`] picks for them. Keys pinned to a live instance never move.
+  pub async fn rebalance(&self, addresses: &[String]) -> Result<Vec<ShardMove>, redis::RedisError> {
+    let mut moves = Vec::default();
+
+    for (key, from) in self.entries().await? {
+      if addresses.contains(&from) {
+        continue;
+      }
+
+      if let Some(to) = rendezvous(&key, addresses) {
+        moves.push(ShardMove {
+          key,
+          from,
+          to: to.to_string(),
+        });
+      }
+    }
+
+    for ShardMove { key, from, to } in &moves {
+      self.assign(key, to).await?;
+      tracing::info!(message = "Moved pinned key", key = %self.key, %key, %from, %to);
+    }
+
+    Ok(moves)
+  }
+}
+
+/// Picks the address of `key` by rendezvous hashing: the result only depends on the set of
+/// addresses, and removing an address only moves the keys it owned.
+pub fn rendezvous<'a>(key: &str, addresses: &'a [String]) -> Option<&'a str> {
+  addresses
+    .iter()
+    .max_by_key(|address| fnv1a(&[address.as_bytes(), b"/", key.as_bytes()]))
+    .map(String::as_str)
+}
+
+/// [`rendezvous`] with weighted instances: every instance gets a share of the keys proportional to
+/// its weight, and adding or removing an instance only moves the keys it gains or loses.
+pub fn weighted_rendezvous<'a>(
+  key: &str,
+  instances: impl IntoIterator<Item = (&'a str, u32)>,
+) -> Option<&'a str> {
+  instances
+    .into_iter()
+    .map(|(address, weight)| {
+      let hash = fmix64(fnv1a(&[address.as_bytes(), b"/", key.as_bytes()]));
+      // Maps the hash into (0, 1), where the logarithmic method turns it into a score whose
+      // maximum is distributed in proportion to the weights.
+      let unit = (hash as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+      (address, weight as f64 / -unit.ln())
+    })
+    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    .map(|(address, _)| address)
+}
+
+/// Finalizer of MurmurHash3, spreading FNV-1a hashes of similar inputs uniformly, which the
+/// scores of [`weighted_rendezvous`] rely on.
+fn fmix64(mut hash: u64) -> u64 {
+  hash ^= hash >> 33;
+  hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+  hash ^= hash >> 33;
+  hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+  hash ^ (hash >> 33)
+}
+
+/// 64 bit FNV-1a, stable across processes and releases unlike `DefaultHasher`.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+  parts
+    .iter()
+    .flat_map(|part| part.iter())
+    .fold(0xcbf29ce484222325, |hash, byte| {
+      (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rendezvous_should_ignore_instance_order() {
+    let addresses = vec![
+      "http://a:50051".to_string(),
+      "http://b:50051".to_string(),
+      "http://c:50051".to_string(),
+    ];
+    let reordered: Vec<String> = addresses.iter().rev().cloned().collect();
+    let without_b = vec![addresses[0].clone(), addresses[2].clone()];
+
+    for key in (0..100).map(|i| format!("workspace-{}", i)) {
+      let owner = rendezvous(&key, &addresses).unwrap();
+
+      assert_eq!(owner, rendezvous(&key, &reordered).unwrap());
+      if owner != addresses[1] {
+        assert_eq!(owner, rendezvous(&key, &without_b).unwrap());
+      }
+    }
+
+    assert_eq!(rendezvous("key", &[]), None);
+  }
+
+  #[test]
+  fn weighted_rendezvous_should_share_keys_by_weight() {
+    let instances = [("http://a:50051", 3), ("http://b:50051", 1)];
+
+    let on_a = (0..4000)
+      .map(|i| format!("workspace-{}", i))
+      .filter(|key| weighted_rendezvous(key, instances) == Some("http://a:50051"))
+      .count();
+
+    assert!((2700..3300).contains(&on_a), "{} keys on a", on_a);
+    assert_eq!(weighted_rendezvous("key", []), None);
+  }
+
+  #[tokio::test]
+  async fn rebalance_should_only_move_keys_of_removed_instances() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let map = ShardMap::new(client, &uuid::Uuid::new_v4().to_string());
+
+    map.assign("pinned", "http://a:50051").await.unwrap();
+    map.assign("orphan", "http://gone:50051").await.unwrap();
+    assert_eq!(
+      map
+        .assign_if_absent("pinned", "http://b:50051")
+        .await
+        .unwrap(),
+      "http://a:50051"
+    );
+
+    let addresses = vec!["http://a:50051".to_string(), "http://b:50051".to_string()];
+    let moves = map.rebalance(&addresses).await.unwrap();
+
+    assert_eq!(moves.len(), 1);
+    assert_eq!(moves[0].key, "orphan");
+    assert_eq!(map.get("pinned").unwrap(), "http://a:50051");
+    assert!(addresses.contains(&map.get("orphan").unwrap()));
+  }
+}
@@ -0,0 +1,209 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::codegen::BoxFuture;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Bounds and tuning of the adaptive concurrency limit, see [`LoadShedLayer`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LoadShedConfig {
+  pub enabled: bool,
+  pub initial_limit: usize,
+  pub min_limit: usize,
+  pub max_limit: usize,
+  /// Weight of a new sample in the limit, between 0 and 1.
+  pub smoothing: f64,
+  /// Number of samples after which the no-load latency is measured again.
+  pub probe_interval: usize,
+}
+
+impl Default for LoadShedConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      initial_limit: 20,
+      min_limit: 4,
+      max_limit: 1000,
+      smoothing: 0.2,
+      probe_interval: 1000,
+    }
+  }
+}
+
+/// Gradient concurrency limit: the limit grows while the latency stays close to the lowest one
+/// observed and shrinks in proportion as it rises, i.e. as requests start queuing.
+#[derive(Debug)]
+struct Gradient {
+  config: LoadShedConfig,
+  limit: f64,
+  min_latency: Option<Duration>,
+  samples: usize,
+}
+
+impl Gradient {
+  fn new(config: LoadShedConfig) -> Self {
+    Self {
+      limit: config.initial_limit as f64,
+      config,
+      min_latency: None,
+      samples: 0,
+    }
+  }
+
+  fn limit(&self) -> usize {
+    self.limit as usize
+  }
+
+  fn record(&mut self, latency: Duration) {
+    self.samples += 1;
+    if self
+      .samples
+      .is_multiple_of(self.config.probe_interval.max(1))
+    {
+      self.min_latency = None;
+    }
+
+    let latency = latency.max(Duration::from_micros(1));
+    let min_latency = self.min_latency.map_or(latency, |min| min.min(latency));
+    self.min_latency = Some(min_latency);
+
+    let gradient = (min_latency.as_secs_f64() / latency.as_secs_f64()).clamp(0.5, 1.0);
+    let target = self.limit * gradient + self.limit.sqrt();
+    let limit = self.limit * (1.0 - self.config.smoothing) + target * self.config.smoothing;
+
+    self.limit = limit.clamp(self.config.min_limit as f64, self.config.max_limit as f64);
+  }
+}
+
+/// Sheds the requests exceeding an adaptive concurrency limit with `RESOURCE_EXHAUSTED` instead of
+/// queuing them until they time out. The limit follows the latency of the server, see
+/// [`LoadShedConfig`].
+#[derive(Clone, Debug)]
+pub struct LoadShedLayer {
+  enabled: bool,
+  in_flight: Arc<AtomicUsize>,
+  gradient: Arc<Mutex<Gradient>>,
+}
+
+impl LoadShedLayer {
+  pub fn new(config: LoadShedConfig) -> Self {
+    Self {
+      enabled: config.enabled,
+      in_flight: Arc::default(),
+      gradient: Arc::new(Mutex::new(Gradient::new(config))),
+    }
+  }
+
+  /// Returns the current concurrency limit.
+  pub fn limit(&self) -> usize {
+    self.gradient.lock().unwrap().limit()
+  }
+}
+
+impl Default for LoadShedLayer {
+  fn default() -> Self {
+    Self::new(LoadShedConfig::default())
+  }
+}
+
+impl<S> Layer<S> for LoadShedLayer {
+  type Service = LoadShed<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    LoadShed {
+      inner,
+      layer: self.clone(),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct LoadShed<S> {
+  inner: S,
+  layer: LoadShedLayer,
+}
+
+/// Counts a request in flight until dropped, so cancelled requests are released too.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+impl<S, B> Service<http::Request<B>> for LoadShed<S>
+where
+  S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = BoxFuture<S::Response, S::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    if !self.layer.enabled {
+      return Box::pin(self.inner.call(request));
+    }
+
+    let in_flight = self.layer.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+    let guard = InFlight(self.layer.in_flight.clone());
+
+    let limit = self.layer.limit();
+    if in_flight > limit {
+      drop(guard);
+      tracing::warn!(message = "Shedding request", method = %request.uri().path(), in_flight, limit);
+      let status = tonic::Status::resource_exhausted("The server is overloaded, retry later");
+      return Box::pin(futures::future::ready(Ok(status.to_http())));
+    }
+
+    let gradient = self.layer.gradient.clone();
+    let started = Instant::now();
+    let response = self.inner.call(request);
+
+    Box::pin(async move {
+      let response = response.await;
+      gradient.lock().unwrap().record(started.elapsed());
+      drop(guard);
+      response
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn gradient_should_follow_latency() {
+    let mut gradient = Gradient::new(LoadShedConfig::default());
+
+    for _ in 0..50 {
+      gradient.record(Duration::from_millis(10));
+    }
+    let unloaded = gradient.limit();
+    assert!(unloaded > 20);
+
+    for _ in 0..50 {
+      gradient.record(Duration::from_millis(100));
+    }
+    assert!(gradient.limit() < unloaded);
+    assert!(gradient.limit() >= 4);
+  }
+}
@@ -0,0 +1,98 @@
+use std::future::Future;
+use std::time::Duration;
+
+/// Time in-flight work gets to complete after a shutdown signal by default.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Resolves when the process receives SIGTERM, as sent on deploys, or Ctrl-C.
+pub async fn shutdown_signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::signal;
+    use tokio::signal::unix::SignalKind;
+
+    match signal(SignalKind::terminate()) {
+      Ok(mut terminate) => {
+        tokio::select! {
+          _ = terminate.recv() => {}
+          _ = tokio::signal::ctrl_c() => {}
+        }
+      }
+      Err(error) => {
+        tracing::warn!(message = "Failed to listen to SIGTERM", %error);
+        tokio::signal::ctrl_c().await.ok();
+      }
+    }
+  }
+
+  #[cfg(not(unix))]
+  tokio::signal::ctrl_c().await.ok();
+}
+
+/// Runs `serve` until `signal` resolves, then gives it `grace` to drain. The future returned by
+/// `serve` is dropped once the grace period is over, which aborts the work still in flight.
+pub(crate) async fn drain<S, F, E>(
+  serve: S,
+  signal: impl Future<Output = ()>,
+  grace: Duration,
+) -> Result<(), E>
+where
+  S: FnOnce(futures::future::BoxFuture<'static, ()>) -> F,
+  F: Future<Output = Result<(), E>>,
+{
+  let (draining, drained) = tokio::sync::oneshot::channel();
+  let server = serve(Box::pin(async move {
+    drained.await.ok();
+  }));
+  tokio::pin!(server);
+
+  tokio::select! {
+    result = &mut server => return result,
+    _ = signal => {}
+  }
+
+  tracing::info!(message = "Draining", grace_ms = grace.as_millis() as u64);
+  draining.send(()).ok();
+
+  match tokio::time::timeout(grace, server).await {
+    Ok(result) => result,
+    Err(_) => {
+      tracing::warn!(message = "Grace period elapsed, aborting in-flight work");
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn drain_should_wait_for_in_flight_work_up_to_the_grace_period() {
+    let (finished, mut done) = tokio::sync::mpsc::unbounded_channel();
+
+    let result: Result<(), ()> = drain(
+      |stop| async move {
+        stop.await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        finished.send("drained").ok();
+        Ok(())
+      },
+      async {},
+      Duration::from_secs(1),
+    )
+    .await;
+    assert_eq!(result, Ok(()));
+    assert_eq!(done.try_recv(), Ok("drained"));
+
+    let started = std::time::Instant::now();
+    let result: Result<(), ()> = drain(
+      |_| futures::future::pending(),
+      async {},
+      Duration::from_millis(10),
+    )
+    .await;
+    assert_eq!(result, Ok(()));
+    assert!(started.elapsed() < Duration::from_secs(1));
+  }
+}
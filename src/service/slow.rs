@@ -0,0 +1,125 @@
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use tonic::codegen::http;
+use tonic::codegen::BoxFuture;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use super::trace::remote_addr;
+use super::Locality;
+
+/// Request header naming the operation a call works on, logged with slow calls.
+pub const OPERATION_ID_HEADER: &str = "x-operation-id";
+
+/// Duration after which calls are logged as slow by default.
+pub const DEFAULT_SLOW_CALL_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Logs the calls taking longer than a threshold, see [`SlowCall`].
+#[derive(Clone, Debug)]
+pub struct SlowCallLayer {
+  threshold: Duration,
+  peer: Option<String>,
+}
+
+impl SlowCallLayer {
+  /// Logs the calls served for longer than `threshold`. The peer is the address of the caller.
+  pub fn server(threshold: Duration) -> Self {
+    Self {
+      threshold,
+      peer: None,
+    }
+  }
+
+  /// Logs the calls to `address` answered after more than `threshold`.
+  pub fn client(address: &str, threshold: Duration) -> Self {
+    Self {
+      threshold,
+      peer: Some(address.to_string()),
+    }
+  }
+}
+
+impl<S> Layer<S> for SlowCallLayer {
+  type Service = SlowCall<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    SlowCall {
+      inner,
+      layer: self.clone(),
+    }
+  }
+}
+
+/// Emits a `Slow RPC` warning with the method, duration, peer, [`OPERATION_ID_HEADER`] and
+/// [`Locality`] of the calls whose response headers take longer than the threshold, so tail
+/// latency regressions show in the logs without tracing infrastructure.
+#[derive(Clone, Debug)]
+pub struct SlowCall<S> {
+  inner: S,
+  layer: SlowCallLayer,
+}
+
+impl<S, B, R> Service<http::Request<B>> for SlowCall<S>
+where
+  S: Service<http::Request<B>, Response = http::Response<R>>,
+  S::Future: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = BoxFuture<S::Response, S::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    let method = request.uri().path().to_string();
+    let operation_id = request
+      .headers()
+      .get(OPERATION_ID_HEADER)
+      .and_then(|value| value.to_str().ok())
+      .unwrap_or_default()
+      .to_string();
+    let peer = match &self.layer.peer {
+      Some(address) => address.clone(),
+      None => remote_addr(&request)
+        .map(|address| address.to_string())
+        .unwrap_or_default(),
+    };
+    let locality = request
+      .extensions()
+      .get::<Locality>()
+      .map_or("", Locality::as_str);
+    let side = match self.layer.peer {
+      Some(_) => "client",
+      None => "server",
+    };
+
+    let threshold = self.layer.threshold;
+    let started = Instant::now();
+    let response = self.inner.call(request);
+
+    Box::pin(async move {
+      let response = response.await;
+
+      let duration = started.elapsed();
+      if duration > threshold {
+        tracing::warn!(
+          message = "Slow RPC",
+          %method,
+          duration_ms = duration.as_millis() as u64,
+          threshold_ms = threshold.as_millis() as u64,
+          %peer,
+          %operation_id,
+          locality,
+          side,
+        );
+      }
+
+      response
+    })
+  }
+}
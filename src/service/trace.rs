@@ -0,0 +1,195 @@
+use std::net::SocketAddr;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Instant;
+
+use tonic::codegen::http;
+use tonic::codegen::BoxFuture;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::transport::server::TlsConnectInfo;
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing_futures::Instrument;
+
+/// Request header correlating the logs of a request across services. Requests without one are
+/// given a new id, and the id is echoed in the response headers.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Wraps every RPC of a server in an `rpc` span, see [`RequestTrace`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestTraceLayer;
+
+impl RequestTraceLayer {
+  pub fn new() -> Self {
+    Self
+  }
+}
+
+impl<S> Layer<S> for RequestTraceLayer {
+  type Service = RequestTrace<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    RequestTrace { inner }
+  }
+}
+
+/// Opens an `rpc` span per request holding the method, peer address, request id and user id, and
+/// records the gRPC status code and latency once the response headers are sent.
+///
+/// The status of a streaming call failing after its first message is only sent in the trailers;
+/// such calls are recorded with the status of their headers, i.e. `0`.
+#[derive(Clone, Debug)]
+pub struct RequestTrace<S> {
+  inner: S,
+}
+
+impl<S, B, R> Service<http::Request<B>> for RequestTrace<S>
+where
+  S: Service<http::Request<B>, Response = http::Response<R>>,
+  S::Future: Send + 'static,
+  S::Error: std::fmt::Display,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = BoxFuture<S::Response, S::Error>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+    let request_id = match header(&request, REQUEST_ID_HEADER) {
+      Some(request_id) => request_id.to_string(),
+      None => {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        if let Ok(value) = request_id.parse() {
+          request.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+        request_id
+      }
+    };
+
+    let peer = remote_addr(&request)
+      .map(|address| address.to_string())
+      .unwrap_or_default();
+
+    let span = tracing::info_span!(
+      "rpc",
+      method = %request.uri().path(),
+      %peer,
+      %request_id,
+      user_id = header(&request, "x-user-id").unwrap_or_default(),
+      grpc_status = tracing::field::Empty,
+      latency_ms = tracing::field::Empty,
+    );
+
+    let started = Instant::now();
+    let response = span.in_scope(|| self.inner.call(request));
+
+    Box::pin(
+      async move {
+        let result = response.await;
+        let span = tracing::Span::current();
+        span.record("latency_ms", started.elapsed().as_millis() as u64);
+
+        match result {
+          Ok(mut response) => {
+            let grpc_status = response
+              .headers()
+              .get("grpc-status")
+              .and_then(|value| value.to_str().ok())
+              .and_then(|value| value.parse().ok())
+              .unwrap_or(0_i32);
+            span.record("grpc_status", grpc_status);
+            tracing::info!(message = "Finished RPC", grpc_status);
+
+            if let Ok(value) = request_id.parse() {
+              response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            Ok(response)
+          }
+          Err(error) => {
+            tracing::warn!(message = "RPC failed", %error);
+            Err(error)
+          }
+        }
+      }
+      .instrument(span),
+    )
+  }
+}
+
+/// Address of the client of a request served over TCP, with or without TLS. Requests served over
+/// a Unix domain socket have none.
+pub(crate) fn remote_addr<B>(request: &http::Request<B>) -> Option<SocketAddr> {
+  let extensions = request.extensions();
+  extensions
+    .get::<TcpConnectInfo>()
+    .and_then(TcpConnectInfo::remote_addr)
+    .or_else(|| {
+      extensions
+        .get::<TlsConnectInfo<TcpConnectInfo>>()
+        .and_then(|info| info.get_ref().remote_addr())
+    })
+}
+
+fn header<'a, B>(request: &'a http::Request<B>, name: &str) -> Option<&'a str> {
+  request
+    .headers()
+    .get(name)
+    .and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::Infallible;
+
+  use futures::future::Ready;
+
+  use super::*;
+
+  /// Echoes the request id it received and fails with `NOT_FOUND`.
+  struct NotFound;
+
+  impl Service<http::Request<()>> for NotFound {
+    type Response = http::Response<String>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<()>) -> Self::Future {
+      let request_id = header(&request, REQUEST_ID_HEADER)
+        .unwrap_or_default()
+        .to_string();
+      let response = http::Response::builder()
+        .header("grpc-status", "5")
+        .body(request_id)
+        .unwrap();
+      futures::future::ready(Ok(response))
+    }
+  }
+
+  #[tokio::test]
+  async fn request_trace_should_propagate_request_id() {
+    let mut service = RequestTraceLayer::new().layer(NotFound);
+
+    let request = http::Request::builder()
+      .uri("/rappel.workspace.Workspaces/GetWorkspace")
+      .header(REQUEST_ID_HEADER, "42")
+      .body(())
+      .unwrap();
+    let response = service.call(request).await.unwrap();
+    assert_eq!(response.body(), "42");
+    assert_eq!(response.headers()[REQUEST_ID_HEADER], "42");
+
+    let response = service.call(http::Request::new(())).await.unwrap();
+    assert!(!response.body().is_empty());
+    assert_eq!(
+      response.headers()[REQUEST_ID_HEADER].to_str().unwrap(),
+      response.body()
+    );
+  }
+}
@@ -0,0 +1,375 @@
+//! Typed clients of the rappel services, bundling the [`ShardedClient`] of a service with the
+//! caller's [`Context`] and retries, so callers neither handle tonic stubs nor request metadata.
+//!
+//! Every call takes the key routing it to an instance, e.g. the id of the workspace, and is
+//! retried on transient failures with the same idempotency key, see [`super::Idempotency`].
+//!
+//! ```rust,ignore
+//! let workspaces = locator.workspaces()?.with_context(Context::new(user_id));
+//! let workspace = workspaces
+//!   .get(&workspace_id, GetWorkspaceRequest { id: workspace_id.clone() })
+//!   .await?;
+//! ```
+
+use std::future::Future;
+use std::time::Duration;
+
+use tonic::metadata::MetadataValue;
+use tonic::Code;
+use uuid::Uuid;
+
+use crate::proto::account;
+use crate::proto::account::billing_accounts_client::BillingAccountsClient;
+use crate::proto::account::organizations_client::OrganizationsClient;
+use crate::proto::cluster;
+use crate::proto::google::protobuf::Empty;
+use crate::proto::longrunning;
+use crate::proto::system;
+use crate::proto::system::locations_client::LocationsClient;
+use crate::proto::workspace;
+use crate::proto::workspace::ide_manager_client::IdeManagerClient;
+use crate::proto::workspace::templates_client::TemplatesClient;
+use crate::proto::workspace::workspaces_client::WorkspacesClient;
+use crate::util::backoff;
+use crate::util::backoff::Backoff;
+
+use super::ClientChannel;
+use super::ClusterSvcClient;
+use super::ClusterWorkspacesClient;
+use super::Context;
+use super::OperationsSvcClient;
+use super::ShardedClient;
+use super::IDEMPOTENCY_KEY_HEADER;
+
+pub type BillingSvcClient = BillingAccountsClient<ClientChannel>;
+pub type OrganizationsSvcClient = OrganizationsClient<ClientChannel>;
+pub type LocationsSvcClient = LocationsClient<ClientChannel>;
+pub type IdeSvcClient = IdeManagerClient<ClientChannel>;
+pub type TemplatesSvcClient = TemplatesClient<ClientChannel>;
+pub type WorkspacesSvcClient = WorkspacesClient<ClientChannel>;
+
+/// How often a typed call is sent again after a transient failure (`UNAVAILABLE`,
+/// `DEADLINE_EXCEEDED`, `RESOURCE_EXHAUSTED` or `ABORTED`). The backoff doubles after every attempt.
+#[derive(Clone, Debug)]
+pub struct CallRetry {
+  pub attempts: u32,
+  pub backoff: Duration,
+}
+
+impl Default for CallRetry {
+  fn default() -> Self {
+    Self {
+      attempts: 3,
+      backoff: Duration::from_millis(100),
+    }
+  }
+}
+
+impl CallRetry {
+  pub fn none() -> Self {
+    Self {
+      attempts: 1,
+      backoff: Duration::ZERO,
+    }
+  }
+}
+
+/// Sends `request` to the instance of `clients` serving `key` through `rpc`, with the user of
+/// `ctx` and one idempotency key for every attempt.
+async fn call<T, Req, Resp, F, Fut>(
+  clients: &ShardedClient<T>,
+  ctx: Option<&Context>,
+  retry: &CallRetry,
+  key: &str,
+  request: Req,
+  rpc: F,
+) -> Result<Resp, tonic::Status>
+where
+  T: Clone,
+  Req: Clone,
+  F: Fn(T, tonic::Request<Req>) -> Fut,
+  Fut: Future<Output = Result<tonic::Response<Resp>, tonic::Status>>,
+{
+  let idempotency_key = MetadataValue::try_from(Uuid::new_v4().to_string())
+    .map_err(|error| tonic::Status::internal(error.to_string()))?;
+  let retryable = |status: &tonic::Status| is_transient(status.code());
+
+  backoff::retry(
+    Backoff::exponential(retry.backoff),
+    retry.attempts,
+    retryable,
+    || async {
+      let lease = clients.acquire(key).await?;
+      let mut request = lease.request(request.clone());
+      if let Some(ctx) = ctx {
+        ctx.attach(&mut request)?;
+      }
+      request
+        .metadata_mut()
+        .insert(IDEMPOTENCY_KEY_HEADER, idempotency_key.clone());
+
+      rpc((*lease).clone(), request).await
+    },
+  )
+  .await
+  .map(tonic::Response::into_inner)
+}
+
+fn is_transient(code: Code) -> bool {
+  matches!(
+    code,
+    Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted
+  )
+}
+
+macro_rules! typed_client {
+  (
+    $(#[$doc:meta])*
+    $name:ident($raw:ty) {
+      $($method:ident($request:ty) -> $response:ty;)*
+    }
+  ) => {
+    $(#[$doc])*
+    #[derive(Clone, Debug)]
+    pub struct $name {
+      clients: ShardedClient<$raw>,
+      ctx: Option<Context>,
+      retry: CallRetry,
+    }
+
+    impl $name {
+      pub fn new(clients: ShardedClient<$raw>) -> Self {
+        Self {
+          clients,
+          ctx: None,
+          retry: CallRetry::default(),
+        }
+      }
+
+      /// Sends the calls on behalf of the user of `ctx`.
+      pub fn with_context(mut self, ctx: Context) -> Self {
+        self.ctx = Some(ctx);
+        self
+      }
+
+      pub fn with_retry(mut self, retry: CallRetry) -> Self {
+        self.retry = retry;
+        self
+      }
+
+      pub fn clients(&self) -> &ShardedClient<$raw> {
+        &self.clients
+      }
+
+      $(
+        pub async fn $method(&self, key: &str, request: $request) -> Result<$response, tonic::Status> {
+          call(
+            &self.clients,
+            self.ctx.as_ref(),
+            &self.retry,
+            key,
+            request,
+            |mut client, request| async move { client.$method(request).await },
+          )
+          .await
+        }
+      )*
+    }
+  };
+}
+
+typed_client! {
+  /// Typed client of the `rappel.system.Clusters` service.
+  ClusterClient(ClusterSvcClient) {
+    create(system::CreateClusterRequest) -> system::Cluster;
+    get(system::GetClusterRequest) -> system::Cluster;
+    update(system::UpdateClusterRequest) -> system::Cluster;
+    select(system::SelectClusterRequest) -> system::Cluster;
+    list(system::ListClusterRequest) -> system::ListClusterResponse;
+    startup(system::StartClusterRequest) -> Empty;
+    shutdown(system::ShutdownClusterRequest) -> Empty;
+  }
+}
+
+typed_client! {
+  /// Typed client of the `rappel.system.Locations` service.
+  LocationClient(LocationsSvcClient) {
+    get(system::GetLocationRequest) -> system::Location;
+    list(system::ListLocationRequest) -> system::ListLocationResponse;
+  }
+}
+
+typed_client! {
+  /// Typed client of the `rappel.cluster.Workspaces` service, serving the workspaces of a cluster.
+  ClusterWorkspaceClient(ClusterWorkspacesClient) {
+    create(cluster::CreateWorkspaceRequest) -> cluster::WorkspaceServer;
+    get(cluster::GetWorkspaceRequest) -> cluster::WorkspaceServer;
+    list(cluster::ListWorkspaceRequest) -> cluster::ListWorkspaceResponse;
+    start(cluster::StartWorkspaceRequest) -> cluster::WorkspaceServer;
+    stop(cluster::StopWorkspaceRequest) -> cluster::WorkspaceServer;
+    restart(cluster::RestartWorkspaceRequest) -> cluster::WorkspaceServer;
+    replace(cluster::ReplaceWorkspaceRequest) -> cluster::WorkspaceServer;
+    delete(cluster::DeleteWorkspaceRequest) -> cluster::WorkspaceServer;
+  }
+}
+
+typed_client! {
+  /// Typed client of the `rappel.account.BillingAccounts` service.
+  BillingClient(BillingSvcClient) {
+    create(account::CreateBillingAccountRequest) -> account::BillingAccount;
+    get(account::GetBillingAccountRequest) -> account::BillingAccount;
+    list(account::ListBillingAccountRequest) -> account::ListBillingAccountResponse;
+  }
+}
+
+typed_client! {
+  /// Typed client of the `rappel.account.Organizations` service.
+  OrganizationClient(OrganizationsSvcClient) {
+    create(account::CreateOrganizationRequest) -> account::Organization;
+    get(account::GetOrganizationRequest) -> account::Organization;
+    list(account::ListOrganizationRequest) -> account::ListOrganizationResponse;
+  }
+}
+
+typed_client! {
+  /// Typed client of the `rappel.workspace.Workspaces` service.
+  WorkspaceClient(WorkspacesSvcClient) {
+    create(workspace::CreateWorkspaceRequest) -> workspace::Workspace;
+    delete(workspace::DeleteWorkspaceRequest) -> Empty;
+    get(workspace::GetWorkspaceRequest) -> workspace::Workspace;
+    list(workspace::ListWorkspaceRequest) -> workspace::ListWorkspaceResponse;
+    update(workspace::UpdateWorkspaceRequest) -> workspace::Workspace;
+    start(workspace::StartWorkspaceRequest) -> longrunning::Operation;
+    stop(workspace::StopWorkspaceRequest) -> longrunning::Operation;
+    restart(workspace::RestartWorkspaceRequest) -> longrunning::Operation;
+    replace(workspace::ReplaceWorkspaceRequest) -> longrunning::Operation;
+  }
+}
+
+typed_client! {
+  /// Typed client of the `rappel.workspace.IdeManager` service.
+  IdeClient(IdeSvcClient) {
+    create(workspace::CreateIdeRequest) -> workspace::Ide;
+    list(workspace::ListIdeRequest) -> workspace::ListIdeResponse;
+    get(workspace::GetIdeRequest) -> workspace::Ide;
+    delete(workspace::DeleteIdeRequest) -> workspace::Ide;
+    start(workspace::StartIdeRequest) -> workspace::Ide;
+    stop(workspace::StopIdeRequest) -> workspace::Ide;
+    restart(workspace::RestartIdeRequest) -> workspace::Ide;
+  }
+}
+
+typed_client! {
+  /// Typed client of the `rappel.workspace.Templates` service.
+  TemplateClient(TemplatesSvcClient) {
+    create(workspace::CreateTemplateRequest) -> workspace::Template;
+    list(workspace::ListTemplateRequest) -> workspace::ListTemplateResponse;
+    get(workspace::GetTemplateRequest) -> workspace::Template;
+    delete(workspace::DeleteTemplateRequest) -> Empty;
+  }
+}
+
+typed_client! {
+  /// Typed client of the unary RPCs of the `longrunning.Operations` service. Operation events are
+  /// streamed with [`super::resumable_watch`].
+  OperationClient(OperationsSvcClient) {
+    get(longrunning::GetOperationRequest) -> longrunning::Operation;
+    batch_get_operations(longrunning::BatchGetOperationsRequest) -> longrunning::BatchGetOperationsResponse;
+    cancel(longrunning::CancelOperationRequest) -> Empty;
+    get_operation_tree(longrunning::GetOperationTreeRequest) -> longrunning::OperationTree;
+    get_latency_summary(longrunning::GetLatencySummaryRequest) -> longrunning::LatencySummary;
+    get_queue_metrics(longrunning::GetQueueMetricsRequest) -> longrunning::QueueMetrics;
+    annotate_operation(longrunning::AnnotateOperationRequest) -> longrunning::Operation;
+    list_task_schemas(longrunning::ListTaskSchemasRequest) -> longrunning::ListTaskSchemasResponse;
+    submit_task(longrunning::SubmitTaskRequest) -> longrunning::Operation;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Mutex;
+
+  use super::super::config::ServiceConf;
+  use super::super::config::ServiceInstance;
+  use super::*;
+
+  fn clients() -> ShardedClient<ClientChannel> {
+    let config = ServiceConf {
+      name: "clusters".to_string(),
+      instances: vec![ServiceInstance {
+        address: "http://10.0.0.1:50051".to_string(),
+        shard_ranges: Vec::default(),
+        location: String::default(),
+        weight: None,
+      }],
+      connections: None,
+      max_in_flight: None,
+      max_queued: None,
+      slow_call_ms: None,
+      connect_timeout_ms: None,
+      call_timeout_ms: None,
+      keepalive_interval_ms: None,
+      keepalive_timeout_ms: None,
+      tcp_nodelay: None,
+      max_message_bytes: None,
+      tls: None,
+    };
+    ShardedClient::try_new(config, |channel| channel).unwrap()
+  }
+
+  #[tokio::test]
+  async fn call_should_retry_with_the_same_metadata() {
+    let clients = clients();
+    let seen = Mutex::new(Vec::new());
+
+    let retry = CallRetry {
+      attempts: 3,
+      backoff: Duration::ZERO,
+    };
+    let response = call(
+      &clients,
+      Some(&Context::new(7)),
+      &retry,
+      "1",
+      system::GetClusterRequest::default(),
+      |_, request: tonic::Request<system::GetClusterRequest>| {
+        let mut seen = seen.lock().unwrap();
+        let metadata = request.metadata();
+        seen.push((
+          metadata.get("x-user-id").cloned(),
+          metadata.get(IDEMPOTENCY_KEY_HEADER).cloned(),
+        ));
+        let result = match seen.len() {
+          1 => Err(tonic::Status::unavailable("try again")),
+          _ => Ok(tonic::Response::new(system::Cluster::default())),
+        };
+        async move { result }
+      },
+    )
+    .await;
+
+    assert!(response.is_ok());
+    let seen = seen.into_inner().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[0], seen[1]);
+    assert_eq!(seen[0].0.as_ref().unwrap(), "7");
+    assert!(seen[0].1.is_some());
+  }
+
+  #[tokio::test]
+  async fn call_should_not_retry_permanent_failures() {
+    let clients = clients();
+
+    let response = call(
+      &clients,
+      None,
+      &CallRetry::default(),
+      "1",
+      system::GetClusterRequest::default(),
+      |_, _| async { Err::<tonic::Response<system::Cluster>, _>(tonic::Status::not_found("gone")) },
+    )
+    .await;
+
+    assert_eq!(response.unwrap_err().code(), Code::NotFound);
+  }
+}
@@ -0,0 +1,93 @@
+use std::io;
+use std::path::PathBuf;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::Stream;
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+use tonic::codegen::http::Uri;
+use tonic::codegen::BoxFuture;
+use tower_service::Service;
+
+/// Prefix of the addresses of Unix domain sockets, e.g. `unix:///run/rappel/agent.sock`.
+pub const UNIX_SCHEME: &str = "unix://";
+
+/// Returns the socket path of a `unix://` address, `None` for other addresses.
+pub fn unix_path(address: &str) -> Option<&str> {
+  address.strip_prefix(UNIX_SCHEME)
+}
+
+/// Connects channels to a Unix domain socket, whatever the URI of their endpoint.
+#[derive(Clone, Debug)]
+pub struct UnixConnector {
+  path: PathBuf,
+}
+
+impl UnixConnector {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self { path: path.into() }
+  }
+}
+
+impl Service<Uri> for UnixConnector {
+  type Response = UnixStream;
+  type Error = io::Error;
+  type Future = BoxFuture<UnixStream, io::Error>;
+
+  fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, _: Uri) -> Self::Future {
+    let path = self.path.clone();
+    Box::pin(async move { UnixStream::connect(path).await })
+  }
+}
+
+/// Binds `path` and yields the accepted connections. A socket file left over by a previous
+/// process is replaced.
+pub fn unix_incoming(path: &str) -> io::Result<impl Stream<Item = io::Result<UnixStream>>> {
+  match std::fs::remove_file(path) {
+    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+    _ => {}
+  }
+
+  let listener = UnixListener::bind(path)?;
+  tracing::info!(message = "Listening on Unix domain socket", %path);
+
+  Ok(futures::stream::unfold(listener, |listener| async move {
+    let stream = listener.accept().await.map(|(stream, _)| stream);
+    Some((stream, listener))
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::StreamExt;
+  use tokio::io::AsyncReadExt;
+  use tokio::io::AsyncWriteExt;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn unix_connector_should_reach_incoming() {
+    let path = std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()));
+    let address = format!("{}{}", UNIX_SCHEME, path.display());
+    let path = unix_path(&address).unwrap();
+
+    let mut incoming = Box::pin(unix_incoming(path).unwrap());
+    let mut client = UnixConnector::new(path)
+      .call(Uri::from_static("http://localhost"))
+      .await
+      .unwrap();
+    let mut server = incoming.next().await.unwrap().unwrap();
+
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    std::fs::remove_file(path).unwrap();
+  }
+}
@@ -0,0 +1,245 @@
+use std::future::Future;
+use std::time::Duration;
+
+use futures::Stream;
+use futures::StreamExt;
+use tonic::Code;
+
+use crate::proto::longrunning::OperationEvent;
+use crate::proto::longrunning::StreamOperationsRequest;
+use crate::util::backoff::Backoff;
+
+/// Request of a server-streaming watch RPC that can resume after the last event received.
+pub trait WatchRequest: Clone {
+  type Event;
+
+  /// Moves the request past `event`, so a new call starts with the events following it.
+  fn resume_after(&mut self, event: &Self::Event);
+}
+
+/// Resumes after the publish time of the last event. The event bus does not keep events, so the
+/// ones published while disconnected are missed, but none is repeated.
+impl WatchRequest for StreamOperationsRequest {
+  type Event = OperationEvent;
+
+  fn resume_after(&mut self, event: &OperationEvent) {
+    if event.event_ts.is_some() {
+      self.after_ts = event.event_ts.clone();
+    }
+  }
+}
+
+/// How a watch reconnects after its stream fails or ends. The backoff doubles after every
+/// consecutive failure up to `max_backoff`, and is reset by the next event.
+#[derive(Clone, Debug)]
+pub struct WatchRetry {
+  /// Consecutive failures after which the watch gives up, never when unset.
+  pub attempts: Option<u32>,
+  pub backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for WatchRetry {
+  fn default() -> Self {
+    Self {
+      attempts: None,
+      backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_secs(30),
+    }
+  }
+}
+
+struct Watch<Req, F, S> {
+  request: Req,
+  rpc: F,
+  retry: WatchRetry,
+  stream: Option<S>,
+  failures: u32,
+  done: bool,
+}
+
+impl<Req, F, S> Watch<Req, F, S> {
+  /// Counts a failure, returns `false` once the watch should give up.
+  fn fail(&mut self, status: &tonic::Status) -> bool {
+    self.stream = None;
+    self.failures += 1;
+
+    let exhausted = self
+      .retry
+      .attempts
+      .is_some_and(|attempts| self.failures >= attempts);
+    !exhausted && is_transient(status.code())
+  }
+
+  fn backoff(&self) -> Duration {
+    Backoff::exponential(self.retry.backoff)
+      .with_max(self.retry.max_backoff)
+      .delay(self.failures)
+  }
+}
+
+/// Follows a server-streaming watch RPC across disconnections. `rpc` is called with `request`
+/// again whenever the stream fails with a transient error or ends, after the request was moved
+/// past the last event received, see [`WatchRequest`]. The stream ends after the first other
+/// error, or once `retry` gives up.
+///
+/// ```rust,ignore
+/// let events = resumable_watch(filter, WatchRetry::default(), |request| {
+///   let mut client = client.clone();
+///   async move { client.stream_operations(request).await }
+/// });
+/// ```
+pub fn resumable_watch<Req, F, Fut, S>(
+  request: Req,
+  retry: WatchRetry,
+  rpc: F,
+) -> impl Stream<Item = Result<Req::Event, tonic::Status>>
+where
+  Req: WatchRequest,
+  F: FnMut(Req) -> Fut,
+  Fut: Future<Output = Result<tonic::Response<S>, tonic::Status>>,
+  S: Stream<Item = Result<Req::Event, tonic::Status>> + Unpin,
+{
+  let watch = Watch {
+    request,
+    rpc,
+    retry,
+    stream: None,
+    failures: 0,
+    done: false,
+  };
+
+  futures::stream::unfold(watch, |mut watch| async move {
+    loop {
+      if watch.done {
+        return None;
+      }
+
+      let stream = match &mut watch.stream {
+        Some(stream) => stream,
+        None => {
+          if watch.failures > 0 {
+            tokio::time::sleep(watch.backoff()).await;
+          }
+
+          match (watch.rpc)(watch.request.clone()).await {
+            Ok(response) => {
+              watch.stream = Some(response.into_inner());
+              continue;
+            }
+            Err(status) => {
+              if !watch.fail(&status) {
+                watch.done = true;
+                return Some((Err(status), watch));
+              }
+              tracing::debug!(message = "Retrying watch", failures = watch.failures, %status);
+              continue;
+            }
+          }
+        }
+      };
+
+      match stream.next().await {
+        Some(Ok(event)) => {
+          watch.request.resume_after(&event);
+          watch.failures = 0;
+          return Some((Ok(event), watch));
+        }
+        Some(Err(status)) => {
+          if !watch.fail(&status) {
+            watch.done = true;
+            return Some((Err(status), watch));
+          }
+          tracing::debug!(message = "Resuming watch", failures = watch.failures, %status);
+        }
+        None => {
+          let status = tonic::Status::unavailable("Watch stream ended");
+          if !watch.fail(&status) {
+            watch.done = true;
+            return Some((Err(status), watch));
+          }
+          tracing::debug!(message = "Resuming ended watch", failures = watch.failures);
+        }
+      }
+    }
+  })
+}
+
+/// Errors worth reconnecting after, including the ones a broken connection surfaces as.
+fn is_transient(code: Code) -> bool {
+  matches!(
+    code,
+    Code::Unavailable
+      | Code::DeadlineExceeded
+      | Code::ResourceExhausted
+      | Code::Aborted
+      | Code::Unknown
+      | Code::Internal
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::sync::Mutex;
+
+  use crate::proto::google::protobuf::Timestamp;
+
+  use super::*;
+
+  fn event(seconds: i64) -> OperationEvent {
+    OperationEvent {
+      operation_id: seconds.to_string(),
+      event_ts: Some(Timestamp { seconds, nanos: 0 }),
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn resumable_watch_should_resume_after_the_last_event() {
+    let requests = Arc::new(Mutex::new(Vec::default()));
+
+    let rpc = |request: StreamOperationsRequest| {
+      let requests = requests.clone();
+
+      async move {
+        let after = request.after_ts.as_ref().map_or(0, |ts| ts.seconds);
+        requests.lock().unwrap().push(after);
+
+        let events = match after {
+          0 => vec![
+            Ok(event(1)),
+            Ok(event(2)),
+            Err(tonic::Status::unavailable("reset")),
+          ],
+          2 => vec![
+            Ok(event(3)),
+            Err(tonic::Status::permission_denied("revoked")),
+          ],
+          after => panic!("unexpected resume point {}", after),
+        };
+        Ok(tonic::Response::new(futures::stream::iter(events)))
+      }
+    };
+
+    let retry = WatchRetry {
+      backoff: Duration::ZERO,
+      ..Default::default()
+    };
+    let events: Vec<_> = resumable_watch(StreamOperationsRequest::default(), retry, rpc)
+      .collect()
+      .await;
+
+    let ids: Vec<_> = events
+      .iter()
+      .filter_map(|event| event.as_ref().ok())
+      .map(|event| event.operation_id.as_str())
+      .collect();
+    assert_eq!(ids, vec!["1", "2", "3"]);
+    assert_eq!(
+      events.last().unwrap().as_ref().unwrap_err().code(),
+      Code::PermissionDenied
+    );
+    assert_eq!(*requests.lock().unwrap(), vec![0, 2]);
+  }
+}
@@ -0,0 +1,300 @@
+//! Delays between the attempts of retry loops, shared by the retries of the crate so consumers
+//! can align their own loops with them.
+//!
+//! ```rust,ignore
+//! let budget = RetryBudget::default();
+//! let backoff = Backoff::exponential(Duration::from_millis(100)).with_jitter(Jitter::Full);
+//!
+//! let response = retry(backoff, 5, |status| budget.try_withdraw() && is_transient(status), || {
+//!   budget.deposit();
+//!   client.clone().get(request.clone())
+//! })
+//! .await?;
+//! ```
+
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use rand::Rng;
+use serde::Deserialize;
+
+/// Delay after the first failure by default.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest delay between attempts by default.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Randomization of the delays, so clients failing together do not retry together.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Jitter {
+  /// The exponential delay itself.
+  #[default]
+  None,
+  /// A random delay up to the exponential delay.
+  Full,
+  /// A random delay between the initial delay and three times the previous one, so the delays
+  /// grow without being correlated across clients.
+  Decorrelated,
+}
+
+/// Exponential backoff: the delay doubles after every consecutive failure, up to a maximum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+  initial: Duration,
+  max: Duration,
+  jitter: Jitter,
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Self::exponential(DEFAULT_INITIAL_BACKOFF)
+  }
+}
+
+impl Backoff {
+  /// Waits `initial` after the first failure, then doubles the delay up to
+  /// [`DEFAULT_MAX_BACKOFF`], without jitter.
+  pub fn exponential(initial: Duration) -> Self {
+    Self {
+      initial,
+      max: DEFAULT_MAX_BACKOFF.max(initial),
+      jitter: Jitter::None,
+    }
+  }
+
+  pub fn with_max(mut self, max: Duration) -> Self {
+    self.max = max;
+    self
+  }
+
+  pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+    self.jitter = jitter;
+    self
+  }
+
+  /// Delay after `failures` consecutive failures, before jitter.
+  pub fn delay(&self, failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(31);
+    self
+      .initial
+      .saturating_mul(2_u32.pow(exponent))
+      .min(self.max)
+  }
+
+  /// The delays after each consecutive failure, with jitter.
+  pub fn delays(&self) -> Delays {
+    Delays {
+      backoff: *self,
+      failures: 0,
+      previous: self.initial,
+    }
+  }
+}
+
+/// Delays of a [`Backoff`], one per consecutive failure. Never ends.
+#[derive(Clone, Debug)]
+pub struct Delays {
+  backoff: Backoff,
+  failures: u32,
+  previous: Duration,
+}
+
+impl Delays {
+  /// Starts over from the initial delay, e.g. after a success.
+  pub fn reset(&mut self) {
+    self.failures = 0;
+    self.previous = self.backoff.initial;
+  }
+
+  /// Consecutive failures so far.
+  pub fn failures(&self) -> u32 {
+    self.failures
+  }
+}
+
+impl Iterator for Delays {
+  type Item = Duration;
+
+  fn next(&mut self) -> Option<Duration> {
+    self.failures = self.failures.saturating_add(1);
+
+    let delay = match self.backoff.jitter {
+      Jitter::None => self.backoff.delay(self.failures),
+      Jitter::Full => random(Duration::ZERO, self.backoff.delay(self.failures)),
+      Jitter::Decorrelated => {
+        let upper = self.previous.saturating_mul(3).min(self.backoff.max);
+        random(self.backoff.initial.min(upper), upper)
+      }
+    };
+
+    self.previous = delay;
+    Some(delay)
+  }
+}
+
+fn random(low: Duration, high: Duration) -> Duration {
+  let low = u64::try_from(low.as_nanos()).unwrap_or(u64::MAX);
+  let high = u64::try_from(high.as_nanos()).unwrap_or(u64::MAX);
+  Duration::from_nanos(rand::thread_rng().gen_range(low..=high.max(low)))
+}
+
+/// Calls `attempt` until it succeeds, `retryable` rejects its error, or it was called `attempts`
+/// times, sleeping for the delays of `backoff` in between. Returns the last result.
+pub async fn retry<T, E, R, F, Fut>(
+  backoff: Backoff,
+  attempts: u32,
+  retryable: R,
+  mut attempt: F,
+) -> Result<T, E>
+where
+  R: Fn(&E) -> bool,
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T, E>>,
+{
+  let mut delays = backoff.delays();
+
+  loop {
+    match attempt().await {
+      Ok(value) => return Ok(value),
+      Err(error) if delays.failures() + 1 < attempts && retryable(&error) => {
+        let delay = delays.next().unwrap_or_default();
+        tracing::debug!(
+          message = "Retrying",
+          attempt = delays.failures(),
+          delay_ms = delay.as_millis() as u64
+        );
+        tokio::time::sleep(delay).await;
+      }
+      Err(error) => return Err(error),
+    }
+  }
+}
+
+/// Retries allowed by default, as a share of the requests.
+pub const DEFAULT_RETRY_RATIO: f64 = 0.1;
+
+/// Retries allowed per second by default, however few requests are sent.
+pub const DEFAULT_MIN_RETRIES_PER_SECOND: u32 = 10;
+
+/// Limits retries to a share of the requests, so retries do not multiply the load of a dependency
+/// that is failing already. Shared by the clones.
+#[derive(Clone, Debug)]
+pub struct RetryBudget {
+  ratio: f64,
+  min_per_second: f64,
+  capacity: f64,
+  state: Arc<Mutex<BudgetState>>,
+}
+
+#[derive(Debug)]
+struct BudgetState {
+  tokens: f64,
+  refilled: Instant,
+}
+
+impl Default for RetryBudget {
+  fn default() -> Self {
+    Self::new(DEFAULT_RETRY_RATIO, DEFAULT_MIN_RETRIES_PER_SECOND)
+  }
+}
+
+impl RetryBudget {
+  /// Allows `ratio` retries per request, plus `min_per_second` retries per second.
+  pub fn new(ratio: f64, min_per_second: u32) -> Self {
+    let min_per_second = f64::from(min_per_second);
+    let capacity = (min_per_second * 10.0).max(1.0);
+
+    Self {
+      ratio,
+      min_per_second,
+      capacity,
+      state: Arc::new(Mutex::new(BudgetState {
+        tokens: capacity,
+        refilled: Instant::now(),
+      })),
+    }
+  }
+
+  /// Counts a request, which earns `ratio` retries.
+  pub fn deposit(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.tokens = (state.tokens + self.ratio).min(self.capacity);
+  }
+
+  /// Takes a retry out of the budget. Returns `false` when the budget is spent.
+  pub fn try_withdraw(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+
+    let now = Instant::now();
+    let elapsed = now.duration_since(state.refilled).as_secs_f64();
+    state.tokens = (state.tokens + elapsed * self.min_per_second).min(self.capacity);
+    state.refilled = now;
+
+    if state.tokens >= 1.0 {
+      state.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn delays_should_grow_up_to_the_max() {
+    let backoff =
+      Backoff::exponential(Duration::from_millis(100)).with_max(Duration::from_millis(500));
+
+    let delays: Vec<u128> = backoff.delays().take(5).map(|d| d.as_millis()).collect();
+    assert_eq!(delays, vec![100, 200, 400, 500, 500]);
+
+    let mut delays = backoff.with_jitter(Jitter::Full).delays();
+    for failures in 1..10 {
+      assert!(delays.next().unwrap() <= backoff.delay(failures));
+    }
+
+    let mut delays = backoff.with_jitter(Jitter::Decorrelated).delays();
+    for _ in 0..10 {
+      let delay = delays.next().unwrap();
+      assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(500));
+    }
+    delays.reset();
+    assert_eq!(delays.failures(), 0);
+  }
+
+  #[tokio::test]
+  async fn retry_should_stop_after_the_attempts() {
+    let mut calls = 0;
+    let result: Result<(), u32> = retry(
+      Backoff::exponential(Duration::ZERO),
+      3,
+      |_| true,
+      || {
+        calls += 1;
+        futures::future::err(calls)
+      },
+    )
+    .await;
+
+    assert_eq!(result, Err(3));
+  }
+
+  #[test]
+  fn retry_budget_should_run_out() {
+    let budget = RetryBudget::new(0.5, 0);
+
+    assert!(budget.try_withdraw());
+    assert!(!budget.try_withdraw());
+
+    budget.deposit();
+    budget.deposit();
+    assert!(budget.try_withdraw());
+  }
+}
@@ -0,0 +1,131 @@
+//! Source of the current time and of delays, so time-dependent behavior such as lease expiry can
+//! be tested without waiting.
+//!
+//! ```rust,ignore
+//! let clock = TestClock::new();
+//! let queue = RedisQueue::new(client, queue, JsonCodec::new()).with_clock(Arc::new(clock.clone()));
+//!
+//! queue.pull(&ctx).await?;
+//! clock.advance(Duration::from_secs(60));
+//! assert_eq!(queue.recover_expired(Duration::from_secs(30)).await?.len(), 1);
+//! ```
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::Utc;
+use tokio::sync::watch;
+
+#[async_trait::async_trait]
+pub trait Clock: Debug + Send + Sync {
+  fn now(&self) -> DateTime<Utc>;
+
+  async fn sleep(&self, duration: Duration);
+
+  /// Nanoseconds since the Unix epoch, as stored in the `*_ts` fields of the operations.
+  fn timestamp_nanos(&self) -> i64 {
+    self.now().timestamp_nanos_opt().unwrap_or_default()
+  }
+}
+
+/// A clock shared by the components of a process.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The time of the system, and the delays of the Tokio timer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[async_trait::async_trait]
+impl Clock for SystemClock {
+  fn now(&self) -> DateTime<Utc> {
+    Utc::now()
+  }
+
+  async fn sleep(&self, duration: Duration) {
+    tokio::time::sleep(duration).await
+  }
+}
+
+/// The system clock, shared.
+pub fn system() -> SharedClock {
+  Arc::new(SystemClock)
+}
+
+/// A clock moving only when told to, see [`TestClock::advance`]. Sleeps end once the clock is
+/// advanced past their deadline. Clones share the time.
+#[derive(Clone, Debug)]
+pub struct TestClock {
+  now: Arc<watch::Sender<DateTime<Utc>>>,
+}
+
+impl Default for TestClock {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl TestClock {
+  /// Starts at the current time of the system.
+  pub fn new() -> Self {
+    Self::at(Utc::now())
+  }
+
+  pub fn at(now: DateTime<Utc>) -> Self {
+    Self {
+      now: Arc::new(watch::channel(now).0),
+    }
+  }
+
+  /// Moves the time forward by `duration`, ending the sleeps due by then.
+  pub fn advance(&self, duration: Duration) {
+    let duration = chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX);
+    self.now.send_modify(|now| *now += duration);
+  }
+
+  pub fn set(&self, now: DateTime<Utc>) {
+    self.now.send_replace(now);
+  }
+}
+
+#[async_trait::async_trait]
+impl Clock for TestClock {
+  fn now(&self) -> DateTime<Utc> {
+    *self.now.borrow()
+  }
+
+  async fn sleep(&self, duration: Duration) {
+    let deadline =
+      self.now() + chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX);
+    let mut now = self.now.subscribe();
+
+    // The sender lives as long as `self`, so `changed` does not fail.
+    while *now.borrow_and_update() < deadline {
+      if now.changed().await.is_err() {
+        return;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_clock_should_end_sleeps_once_advanced() {
+    let clock = TestClock::new();
+    let start = clock.now();
+
+    let mut sleep = clock.sleep(Duration::from_secs(60));
+    assert!(futures::poll!(&mut sleep).is_pending());
+
+    clock.advance(Duration::from_secs(30));
+    assert!(futures::poll!(&mut sleep).is_pending());
+
+    clock.advance(Duration::from_secs(30));
+    sleep.await;
+    assert_eq!(clock.now() - start, chrono::Duration::seconds(60));
+  }
+}
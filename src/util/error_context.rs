@@ -0,0 +1,127 @@
+//! Resources involved in a failure, attached to errors as they cross module boundaries so a logged
+//! error tells which queue, operation, Redis key or service it is about.
+//!
+//! ```rust,ignore
+//! let message = queue
+//!   .pull_raw(&ctx)
+//!   .await
+//!   .with_context(|| ErrorContext::new().with_queue(queue.name()))?;
+//! ```
+//!
+//! Context attached to an error already carrying some only fills the resources it lacks, so the
+//! innermost, most precise context wins.
+
+use std::fmt;
+
+/// Resources an error is about. Unset resources are left out of its display.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+  pub queue: Option<String>,
+  pub operation_id: Option<String>,
+  pub key: Option<String>,
+  pub service: Option<String>,
+}
+
+impl ErrorContext {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_queue(mut self, queue: &str) -> Self {
+    self.queue = Some(queue.to_string());
+    self
+  }
+
+  pub fn with_operation(mut self, operation_id: &str) -> Self {
+    self.operation_id = Some(operation_id.to_string());
+    self
+  }
+
+  /// Sets the Redis key involved.
+  pub fn with_key(mut self, key: &str) -> Self {
+    self.key = Some(key.to_string());
+    self
+  }
+
+  pub fn with_service(mut self, service: &str) -> Self {
+    self.service = Some(service.to_string());
+    self
+  }
+
+  /// This context, with the resources it lacks taken from `outer`.
+  pub fn or(self, outer: ErrorContext) -> Self {
+    Self {
+      queue: self.queue.or(outer.queue),
+      operation_id: self.operation_id.or(outer.operation_id),
+      key: self.key.or(outer.key),
+      service: self.service.or(outer.service),
+    }
+  }
+
+  fn fields(&self) -> impl Iterator<Item = (&'static str, &str)> {
+    [
+      ("service", &self.service),
+      ("queue", &self.queue),
+      ("operation_id", &self.operation_id),
+      ("key", &self.key),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+  }
+}
+
+/// Resources as `name=value` pairs, e.g. `queue=backups operation_id=42`.
+impl fmt::Display for ErrorContext {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (index, (name, value)) in self.fields().enumerate() {
+      if index > 0 {
+        f.write_str(" ")?;
+      }
+      write!(f, "{}={}", name, value)?;
+    }
+    Ok(())
+  }
+}
+
+/// Errors carrying an [`ErrorContext`].
+pub trait Contextual: Sized {
+  /// The error with `context` attached, merged with the context it already carries.
+  fn with_context(self, context: ErrorContext) -> Self;
+
+  /// Context attached to the error, if any.
+  fn context(&self) -> Option<&ErrorContext>;
+}
+
+/// Attaches an [`ErrorContext`] to the error of a result.
+pub trait ResultExt<T, E> {
+  /// Attaches the context built by `context` if the result is an error.
+  fn with_context<F: FnOnce() -> ErrorContext>(self, context: F) -> Result<T, E>;
+}
+
+impl<T, E: Contextual> ResultExt<T, E> for Result<T, E> {
+  fn with_context<F: FnOnce() -> ErrorContext>(self, context: F) -> Result<T, E> {
+    self.map_err(|error| error.with_context(context()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn inner_context_should_win() {
+    let inner = ErrorContext::new()
+      .with_operation("42")
+      .with_key("operation:42");
+    let outer = ErrorContext::new()
+      .with_queue("backups")
+      .with_operation("7");
+
+    let context = inner.or(outer);
+    assert_eq!(
+      context.to_string(),
+      "queue=backups operation_id=42 key=operation:42"
+    );
+    assert_eq!(ErrorContext::new().to_string(), "");
+  }
+}
@@ -0,0 +1,6 @@
+pub mod backoff;
+pub mod clock;
+pub mod error_context;
+pub mod redis_exec;
+pub mod runtime;
+pub mod shutdown;
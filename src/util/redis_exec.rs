@@ -0,0 +1,307 @@
+//! Latency and errors of the Redis commands sent by the crate, so a degraded Redis is told apart
+//! from slow application code.
+//!
+//! Connections opened with [`connect`] record every command they send, pipelines and scripts
+//! included, in the process wide [`metrics`]: a latency histogram and an error count per command,
+//! and the connections opened, failed and dropped. Every command runs in a `redis-command` span,
+//! and failures are logged with their command.
+//!
+//! ```rust,ignore
+//! let mut conn = redis_exec::connect(&client).await?;
+//! let _: () = conn.set("key", "value").await?;
+//! print!("{}", redis_exec::metrics().render());
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::FutureExt;
+use redis::aio::ConnectionLike;
+use redis::Arg;
+use redis::Cmd;
+use redis::Pipeline;
+use redis::RedisFuture;
+use redis::RedisResult;
+use redis::Value;
+use tracing::Instrument;
+
+/// Upper bounds in seconds of the buckets of the latency histograms, besides `+Inf`.
+pub const LATENCY_BUCKETS: [f64; 11] = [
+  0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Label of the latency of opening connections, see [`connect`].
+pub const CONNECT: &str = "CONNECT";
+
+/// Label of pipelines, whose commands are sent and answered together.
+pub const PIPELINE: &str = "PIPELINE";
+
+static METRICS: RedisMetrics = RedisMetrics::new();
+
+/// Metrics of every connection opened with [`connect`].
+pub fn metrics() -> &'static RedisMetrics {
+  &METRICS
+}
+
+/// Opens a connection of `client` recording its commands in [`metrics`].
+pub async fn connect(client: &redis::Client) -> RedisResult<InstrumentedConnection> {
+  let started = Instant::now();
+  let connected = client.get_async_connection().await;
+  METRICS.record(CONNECT, started.elapsed(), connected.as_ref().err());
+
+  match connected {
+    Ok(inner) => {
+      METRICS.connects.fetch_add(1, Ordering::Relaxed);
+      Ok(InstrumentedConnection { inner })
+    }
+    Err(error) => {
+      METRICS.connect_errors.fetch_add(1, Ordering::Relaxed);
+      tracing::warn!(message = "Failed to connect to Redis", %error);
+      Err(error)
+    }
+  }
+}
+
+/// A Redis connection recording the latency and errors of its commands, see [`connect`].
+pub struct InstrumentedConnection {
+  inner: redis::aio::Connection,
+}
+
+impl InstrumentedConnection {
+  /// Turns the connection into a pub/sub one, whose messages are not instrumented.
+  pub fn into_pubsub(self) -> redis::aio::PubSub {
+    self.inner.into_pubsub()
+  }
+}
+
+impl ConnectionLike for InstrumentedConnection {
+  fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+    let command = command_name(cmd);
+    let span = tracing::trace_span!("redis-command", command = %command);
+
+    async move {
+      let started = Instant::now();
+      let result = self.inner.req_packed_command(cmd).await;
+      METRICS.record(&command, started.elapsed(), result.as_ref().err());
+      result
+    }
+    .instrument(span)
+    .boxed()
+  }
+
+  fn req_packed_commands<'a>(
+    &'a mut self,
+    cmd: &'a Pipeline,
+    offset: usize,
+    count: usize,
+  ) -> RedisFuture<'a, Vec<Value>> {
+    let span = tracing::trace_span!("redis-command", command = PIPELINE, count);
+
+    async move {
+      let started = Instant::now();
+      let result = self.inner.req_packed_commands(cmd, offset, count).await;
+      METRICS.record(PIPELINE, started.elapsed(), result.as_ref().err());
+      result
+    }
+    .instrument(span)
+    .boxed()
+  }
+
+  fn get_db(&self) -> i64 {
+    self.inner.get_db()
+  }
+}
+
+/// The command of `cmd` in upper case, e.g. `HGETALL` or `EVALSHA`.
+fn command_name(cmd: &Cmd) -> String {
+  match cmd.args_iter().next() {
+    Some(Arg::Simple(name)) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+    _ => "UNKNOWN".to_string(),
+  }
+}
+
+/// Latency and errors of the commands sent through [`InstrumentedConnection`]s, rendered in the
+/// Prometheus text format.
+#[derive(Debug)]
+pub struct RedisMetrics {
+  commands: Mutex<BTreeMap<String, CommandStats>>,
+  connects: AtomicU64,
+  connect_errors: AtomicU64,
+  disconnects: AtomicU64,
+}
+
+/// Totals of one command.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandStats {
+  pub count: u64,
+  pub errors: u64,
+  pub seconds: f64,
+  /// Commands answered within each of the [`LATENCY_BUCKETS`], not cumulated.
+  pub buckets: [u64; LATENCY_BUCKETS.len()],
+}
+
+impl RedisMetrics {
+  const fn new() -> Self {
+    Self {
+      commands: Mutex::new(BTreeMap::new()),
+      connects: AtomicU64::new(0),
+      connect_errors: AtomicU64::new(0),
+      disconnects: AtomicU64::new(0),
+    }
+  }
+
+  fn record(&self, command: &str, elapsed: Duration, error: Option<&redis::RedisError>) {
+    // Scripts are loaded on their first NOSCRIPT reply, which is not a failure.
+    let error = error.filter(|error| error.kind() != redis::ErrorKind::NoScriptError);
+    if let Some(error) = error {
+      tracing::debug!(message = "Redis command failed", %command, %error);
+      if error.is_connection_dropped() || error.is_io_error() {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+
+    let seconds = elapsed.as_secs_f64();
+    let mut commands = self.commands.lock().unwrap_or_else(PoisonError::into_inner);
+    let stats = match commands.get_mut(command) {
+      Some(stats) => stats,
+      None => commands.entry(command.to_string()).or_default(),
+    };
+    stats.count += 1;
+    stats.errors += u64::from(error.is_some());
+    stats.seconds += seconds;
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+      stats.buckets[bucket] += 1;
+    }
+  }
+
+  /// Totals of `command`, e.g. `HGETALL`, [`PIPELINE`] or [`CONNECT`].
+  pub fn command(&self, command: &str) -> Option<CommandStats> {
+    self
+      .commands
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .get(command)
+      .cloned()
+  }
+
+  /// Connections opened, connections that failed to open and connections dropped mid-command.
+  pub fn connections(&self) -> (u64, u64, u64) {
+    (
+      self.connects.load(Ordering::Relaxed),
+      self.connect_errors.load(Ordering::Relaxed),
+      self.disconnects.load(Ordering::Relaxed),
+    )
+  }
+
+  pub fn render(&self) -> String {
+    let mut out = String::default();
+    let commands = self
+      .commands
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .clone();
+
+    let _ = writeln!(out, "# TYPE rappel_redis_command_seconds histogram");
+    for (command, stats) in &commands {
+      let mut cumulated = 0;
+      for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+        cumulated += count;
+        let _ = writeln!(
+          out,
+          "rappel_redis_command_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+          command, bound, cumulated
+        );
+      }
+      let _ = writeln!(
+        out,
+        "rappel_redis_command_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+        command, stats.count
+      );
+      let _ = writeln!(
+        out,
+        "rappel_redis_command_seconds_sum{{command=\"{}\"}} {}",
+        command, stats.seconds
+      );
+      let _ = writeln!(
+        out,
+        "rappel_redis_command_seconds_count{{command=\"{}\"}} {}",
+        command, stats.count
+      );
+    }
+
+    let _ = writeln!(out, "# TYPE rappel_redis_command_errors_total counter");
+    for (command, stats) in &commands {
+      let _ = writeln!(
+        out,
+        "rappel_redis_command_errors_total{{command=\"{}\"}} {}",
+        command, stats.errors
+      );
+    }
+
+    let (connects, connect_errors, disconnects) = self.connections();
+    let _ = writeln!(out, "# TYPE rappel_redis_connections_total counter");
+    let _ = writeln!(
+      out,
+      "rappel_redis_connections_total{{outcome=\"opened\"}} {}",
+      connects
+    );
+    let _ = writeln!(
+      out,
+      "rappel_redis_connections_total{{outcome=\"failed\"}} {}",
+      connect_errors
+    );
+    let _ = writeln!(out, "# TYPE rappel_redis_disconnects_total counter");
+    let _ = writeln!(out, "rappel_redis_disconnects_total {}", disconnects);
+
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn record_should_fill_the_histogram_of_the_command() {
+    let metrics = RedisMetrics::new();
+    let error = redis::RedisError::from((redis::ErrorKind::TypeError, "WRONGTYPE"));
+    let noscript = redis::RedisError::from((redis::ErrorKind::NoScriptError, "NOSCRIPT"));
+
+    metrics.record("GET", Duration::from_micros(300), None);
+    metrics.record("GET", Duration::from_millis(20), Some(&error));
+    metrics.record("GET", Duration::from_secs(2), None);
+    metrics.record("EVALSHA", Duration::from_millis(1), Some(&noscript));
+
+    let get = metrics.command("GET").unwrap();
+    assert_eq!((get.count, get.errors), (3, 1));
+    assert_eq!(get.buckets, [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0]);
+    assert_eq!(metrics.command("EVALSHA").unwrap().errors, 0);
+
+    let rendered = metrics.render();
+    assert!(
+      rendered.contains("rappel_redis_command_seconds_bucket{command=\"GET\",le=\"0.025\"} 2")
+    );
+    assert!(rendered.contains("rappel_redis_command_seconds_bucket{command=\"GET\",le=\"+Inf\"} 3"));
+    assert!(rendered.contains("rappel_redis_command_errors_total{command=\"GET\"} 1"));
+  }
+
+  #[test]
+  fn command_name_should_be_the_first_argument() {
+    assert_eq!(command_name(redis::cmd("hgetall").arg("key")), "HGETALL");
+  }
+
+  #[tokio::test]
+  async fn connect_should_count_failed_connections() {
+    let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+    let (_, failed, _) = metrics().connections();
+
+    assert!(connect(&client).await.is_err());
+    assert!(metrics().connections().1 > failed);
+  }
+}
@@ -0,0 +1,180 @@
+//! Metrics of the Tokio runtime and of the tasks polled on it, to tell whether slow operations
+//! wait on Redis, on the network or on an executor starved by blocking tasks.
+//!
+//! A [`TaskMonitor`] times every poll of the futures it instruments: long polls block a worker
+//! thread, and a long delay before the first poll means the runtime is too busy to start tasks.
+//!
+//! ```rust,ignore
+//! let monitor = TaskMonitor::new();
+//! let output = monitor.instrument(perform(task)).await;
+//! tracing::info!(stats = ?monitor.stats(), runtime = ?RuntimeStats::sample());
+//! ```
+
+use std::future::Future;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Polls longer than this are counted as slow by default.
+pub const DEFAULT_SLOW_POLL: Duration = Duration::from_millis(50);
+
+/// Snapshot of the metrics of a Tokio runtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RuntimeStats {
+  /// Worker threads of the runtime.
+  pub workers: usize,
+  /// Tasks spawned and not completed yet.
+  pub alive_tasks: usize,
+  /// Tasks scheduled from outside the runtime and not picked up by a worker yet.
+  pub global_queue_depth: usize,
+  /// Time the workers spent running tasks, summed over the workers.
+  pub busy: Duration,
+  /// Times the workers parked for lack of tasks, summed over the workers.
+  pub parks: u64,
+}
+
+impl RuntimeStats {
+  /// Samples the runtime of the current task, `None` outside of a Tokio runtime.
+  pub fn sample() -> Option<Self> {
+    let metrics = tokio::runtime::Handle::try_current().ok()?.metrics();
+    let workers = metrics.num_workers();
+
+    Some(Self {
+      workers,
+      alive_tasks: metrics.num_alive_tasks(),
+      global_queue_depth: metrics.global_queue_depth(),
+      busy: (0..workers)
+        .map(|worker| metrics.worker_total_busy_duration(worker))
+        .sum(),
+      parks: (0..workers)
+        .map(|worker| metrics.worker_park_count(worker))
+        .sum(),
+    })
+  }
+}
+
+/// Totals of the futures instrumented by a [`TaskMonitor`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TaskStats {
+  pub instrumented: u64,
+  /// Time between the creation of the futures and their first poll.
+  pub first_poll_delay: Duration,
+  pub polls: u64,
+  pub poll_time: Duration,
+  /// Polls that took at least the slow poll threshold of the monitor.
+  pub slow_polls: u64,
+}
+
+/// Times the polls of the futures it instruments. Clones share their totals.
+#[derive(Clone, Debug)]
+pub struct TaskMonitor {
+  counters: Arc<Counters>,
+  slow_poll: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+  instrumented: AtomicU64,
+  first_poll_delay_ns: AtomicU64,
+  polls: AtomicU64,
+  poll_time_ns: AtomicU64,
+  slow_polls: AtomicU64,
+}
+
+impl TaskMonitor {
+  pub fn new() -> Self {
+    Self {
+      counters: Arc::default(),
+      slow_poll: DEFAULT_SLOW_POLL,
+    }
+  }
+
+  pub fn with_slow_poll(mut self, slow_poll: Duration) -> Self {
+    self.slow_poll = slow_poll;
+    self
+  }
+
+  /// Wraps `future` to record its polls.
+  pub fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+    let counters = self.counters.clone();
+    let slow_poll = self.slow_poll;
+    let mut created = Some(Instant::now());
+    let mut future = Box::pin(future);
+
+    counters.instrumented.fetch_add(1, Ordering::Relaxed);
+
+    futures::future::poll_fn(move |cx| {
+      if let Some(created) = created.take() {
+        add(&counters.first_poll_delay_ns, created.elapsed());
+      }
+
+      let started = Instant::now();
+      let poll = future.as_mut().poll(cx);
+      let elapsed = started.elapsed();
+
+      counters.polls.fetch_add(1, Ordering::Relaxed);
+      add(&counters.poll_time_ns, elapsed);
+      if elapsed >= slow_poll {
+        counters.slow_polls.fetch_add(1, Ordering::Relaxed);
+      }
+
+      poll
+    })
+  }
+
+  pub fn stats(&self) -> TaskStats {
+    let counters = &self.counters;
+
+    TaskStats {
+      instrumented: counters.instrumented.load(Ordering::Relaxed),
+      first_poll_delay: Duration::from_nanos(counters.first_poll_delay_ns.load(Ordering::Relaxed)),
+      polls: counters.polls.load(Ordering::Relaxed),
+      poll_time: Duration::from_nanos(counters.poll_time_ns.load(Ordering::Relaxed)),
+      slow_polls: counters.slow_polls.load(Ordering::Relaxed),
+    }
+  }
+}
+
+impl Default for TaskMonitor {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn add(counter: &AtomicU64, duration: Duration) {
+  counter.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn monitor_should_time_the_polls() {
+    let monitor = TaskMonitor::new().with_slow_poll(Duration::from_millis(5));
+
+    let output = monitor
+      .instrument(async {
+        tokio::task::yield_now().await;
+        // Blocks the worker, as a misbehaving task would.
+        std::thread::sleep(Duration::from_millis(10));
+        42
+      })
+      .await;
+    assert_eq!(output, 42);
+
+    let stats = monitor.clone().stats();
+    assert_eq!(stats.instrumented, 1);
+    assert_eq!(stats.polls, 2);
+    assert_eq!(stats.slow_polls, 1);
+    assert!(stats.poll_time >= Duration::from_millis(10));
+  }
+
+  #[tokio::test]
+  async fn runtime_should_be_sampled_within_tokio() {
+    let stats = RuntimeStats::sample().unwrap();
+    assert_eq!(stats.workers, 1);
+  }
+}
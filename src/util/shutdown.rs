@@ -0,0 +1,90 @@
+//! Coordinated teardown of the brokers, workers and runners of a process through one token.
+//!
+//! ```rust,ignore
+//! let shutdown = ShutdownToken::new();
+//! let broker = RedisBroker::new(client.clone(), "exports").with_shutdown(shutdown.clone());
+//! let worker = Worker::new(queue, ctx).with_shutdown(shutdown.clone());
+//! tokio::spawn(async move { worker.run().await });
+//!
+//! shutdown_signal().await;
+//! shutdown.shutdown();
+//! ```
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Tells everything holding a clone of the token that the process is shutting down. Shutting
+/// down any clone shuts them all down, for good.
+#[derive(Clone, Debug)]
+pub struct ShutdownToken {
+  sender: Arc<watch::Sender<bool>>,
+  receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownToken {
+  pub fn new() -> Self {
+    let (sender, receiver) = watch::channel(false);
+
+    Self {
+      sender: Arc::new(sender),
+      receiver,
+    }
+  }
+
+  /// Starts the shutdown. Shutting down again does nothing.
+  pub fn shutdown(&self) {
+    // Never fails, as the token holds a receiver.
+    self.sender.send(true).ok();
+  }
+
+  pub fn is_shutdown(&self) -> bool {
+    *self.receiver.borrow()
+  }
+
+  /// Resolves once the shutdown started, right away if it already did.
+  pub async fn wait(&self) {
+    let mut receiver = self.receiver.clone();
+
+    while !*receiver.borrow() {
+      if receiver.changed().await.is_err() {
+        return;
+      }
+    }
+  }
+}
+
+impl Default for ShutdownToken {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn clones_should_observe_the_shutdown() {
+    let token = ShutdownToken::new();
+    let clone = token.clone();
+    let waiting = tokio::spawn(async move { clone.wait().await });
+
+    assert!(!token.is_shutdown());
+    token.shutdown();
+    token.shutdown();
+
+    tokio::time::timeout(Duration::from_secs(1), waiting)
+      .await
+      .expect("the clone should observe the shutdown")
+      .unwrap();
+    assert!(token.is_shutdown());
+
+    // Tokens shut down already resolve right away.
+    tokio::time::timeout(Duration::from_secs(1), token.clone().wait())
+      .await
+      .unwrap();
+  }
+}